@@ -0,0 +1,89 @@
+/*
+ * C bindings for libwebauthn.
+ *
+ * Functions returning int return LW_OK on success, or LW_ERROR, in which case
+ * lw_last_error() and lw_last_error_category() describe the error. Calls block
+ * until they complete; ceremonies can be aborted from another thread with
+ * lw_cancel_token_cancel(). Strings returned by the library must be freed with
+ * lw_string_free().
+ */
+
+#ifndef LIBWEBAUTHN_H
+#define LIBWEBAUTHN_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+#define LW_OK 0
+#define LW_ERROR -1
+
+#define LW_PIN_REASON_RELYING_PARTY_REQUEST 0
+#define LW_PIN_REASON_AUTHENTICATOR_POLICY 1
+#define LW_PIN_REASON_FALLBACK_FROM_UV 2
+
+/* Size of the buffer a PIN callback writes the PIN into, including the terminating NUL. */
+#define LW_PIN_BUFFER_SIZE 64
+
+/* The USB security keys connected when the list was created. */
+typedef struct LwDeviceList LwDeviceList;
+
+/* Aborts the ceremonies it is passed to. */
+typedef struct LwCancelToken LwCancelToken;
+
+/*
+ * Asks the user for a PIN. Writes the NUL-terminated PIN into `pin`, of `pin_len`
+ * bytes, and returns 0, or returns any other value to cancel the ceremony.
+ * `attempts_left` is -1 if unknown. Called from a library thread.
+ */
+typedef int (*LwPinCallback)(void *user_data, int reason, int32_t attempts_left,
+                             char *pin, size_t pin_len);
+
+/* Lists the connected USB security keys. Returns NULL on failure. */
+LwDeviceList *lw_device_list_new(void);
+size_t lw_device_list_len(const LwDeviceList *devices);
+/* A description of the device at `index`, to show to the user. NULL if out of range. */
+char *lw_device_list_name(const LwDeviceList *devices, size_t index);
+void lw_device_list_free(LwDeviceList *devices);
+
+/*
+ * Registers a new credential on the device at `index`. `options_json` is a
+ * PublicKeyCredentialCreationOptionsJSON; on success, `*response_json` is set to
+ * the RegistrationResponseJSON. `pin_callback` and `cancel` may be NULL.
+ */
+int lw_register(LwDeviceList *devices, size_t index, const char *origin,
+                const char *options_json, LwPinCallback pin_callback,
+                void *user_data, const LwCancelToken *cancel,
+                char **response_json);
+
+/*
+ * Gets an assertion from the device at `index`. `options_json` is a
+ * PublicKeyCredentialRequestOptionsJSON; on success, `*response_json` is set to
+ * the AuthenticationResponseJSON. `pin_callback` and `cancel` may be NULL.
+ */
+int lw_authenticate(LwDeviceList *devices, size_t index, const char *origin,
+                    const char *options_json, LwPinCallback pin_callback,
+                    void *user_data, const LwCancelToken *cancel,
+                    char **response_json);
+
+LwCancelToken *lw_cancel_token_new(void);
+/* Aborts the ceremonies using `cancel`, which then fail with PLATFORM_CANCELLED. */
+void lw_cancel_token_cancel(const LwCancelToken *cancel);
+void lw_cancel_token_free(LwCancelToken *cancel);
+
+/* The stable code of the last error on this thread, e.g. CTAP2_ERR_PIN_INVALID, or NULL.
+ * PLATFORM_INTERNAL_ERROR if the library panicked. */
+const char *lw_last_error(void);
+/* The WebAuthn error of the last error on this thread, e.g. NotAllowedError, or NULL. */
+const char *lw_last_error_category(void);
+
+void lw_string_free(char *string);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* LIBWEBAUTHN_H */
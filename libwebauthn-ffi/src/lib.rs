@@ -0,0 +1,473 @@
+//! C ABI for libwebauthn, for desktop components written in C or C++, e.g. GTK or Qt.
+//!
+//! The declarations are in `include/libwebauthn.h`. Ceremonies take the relying party's
+//! options as WebAuthn Level 3 JSON, and return the response JSON, as in
+//! `libwebauthn::simple`.
+//!
+//! Functions returning `int` return [LW_OK] on success, or [LW_ERROR], in which case
+//! [lw_last_error] and [lw_last_error_category] describe the error. Calls block until they
+//! complete, and can be aborted from another thread with [lw_cancel_token_cancel].
+//! Strings returned by the library must be freed with [lw_string_free].
+//!
+//! With the `uniffi` feature, the library also exports UniFFI bindings for Kotlin and
+//! Swift, see [uniffi_bindings].
+
+use std::cell::RefCell;
+use std::ffi::{c_char, c_int, c_void, CStr, CString};
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+
+use libwebauthn::blocking::block_on;
+use libwebauthn::pin::PinRequestReason;
+use libwebauthn::simple::{authenticate_on_channel, register_on_channel};
+use libwebauthn::transport::hid::{list_devices, HidDevice};
+use libwebauthn::transport::{CancellationToken, Channel, Device};
+use libwebauthn::webauthn::{Error, PlatformError};
+use tracing::{error, warn};
+use zeroize::Zeroizing;
+
+#[cfg(feature = "uniffi")]
+pub mod uniffi_bindings;
+
+#[cfg(feature = "uniffi")]
+uniffi::setup_scaffolding!();
+
+pub const LW_OK: c_int = 0;
+pub const LW_ERROR: c_int = -1;
+
+pub const LW_PIN_REASON_RELYING_PARTY_REQUEST: c_int = 0;
+pub const LW_PIN_REASON_AUTHENTICATOR_POLICY: c_int = 1;
+pub const LW_PIN_REASON_FALLBACK_FROM_UV: c_int = 2;
+
+/// Size of the buffer a [LwPinCallback] writes the PIN into, including the terminating NUL.
+pub const LW_PIN_BUFFER_SIZE: usize = 64;
+
+/// Asks the user for a PIN. Writes the NUL-terminated PIN into `pin`, of `pin_len` bytes,
+/// and returns 0, or returns any other value to cancel the ceremony. `attempts_left` is -1
+/// if unknown. Called from a library thread.
+pub type LwPinCallback = Option<
+    unsafe extern "C" fn(
+        user_data: *mut c_void,
+        reason: c_int,
+        attempts_left: i32,
+        pin: *mut c_char,
+        pin_len: usize,
+    ) -> c_int,
+>;
+
+/// The USB security keys connected when the list was created.
+pub struct LwDeviceList {
+    devices: Vec<HidDevice>,
+}
+
+/// Aborts the ceremonies it is passed to.
+pub struct LwCancelToken {
+    token: CancellationToken,
+}
+
+struct LastError {
+    code: CString,
+    category: CString,
+}
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<LastError>> = const { RefCell::new(None) };
+}
+
+/// Error code reported when the library panicked.
+const PANIC_ERROR_CODE: &str = "PLATFORM_INTERNAL_ERROR";
+
+fn set_last_error(code: &str, category: &str) {
+    let last_error = LastError {
+        code: CString::new(code).unwrap_or_default(),
+        category: CString::new(category).unwrap_or_default(),
+    };
+    LAST_ERROR.with(|cell| *cell.borrow_mut() = Some(last_error));
+}
+
+fn fail(error: Error) -> c_int {
+    set_last_error(error.code(), error.webauthn_category().name());
+    LW_ERROR
+}
+
+/// Runs the body of an exported function, returning `on_panic` if it panics: unwinding into
+/// C is undefined behaviour.
+fn guarded<T>(on_panic: T, body: impl FnOnce() -> T) -> T {
+    panic::catch_unwind(AssertUnwindSafe(body)).unwrap_or_else(|_| {
+        error!("Panic in exported function");
+        set_last_error(PANIC_ERROR_CODE, "UnknownError");
+        on_panic
+    })
+}
+
+fn invalid(argument: &str) -> Error {
+    warn!(argument, "Invalid argument");
+    Error::Platform(PlatformError::SyntaxError)
+}
+
+unsafe fn str_arg<'a>(arg: *const c_char, name: &str) -> Result<&'a str, Error> {
+    if arg.is_null() {
+        return Err(invalid(name));
+    }
+    CStr::from_ptr(arg).to_str().map_err(|_| invalid(name))
+}
+
+/// The C caller's PIN callback, with the pointer it is passed back.
+#[derive(Clone, Copy)]
+struct PinCallback {
+    callback: LwPinCallback,
+    user_data: *mut c_void,
+}
+
+// The caller is responsible for `user_data` being usable from library threads.
+unsafe impl Send for PinCallback {}
+unsafe impl Sync for PinCallback {}
+
+impl PinCallback {
+    fn call(
+        &self,
+        reason: PinRequestReason,
+        attempts_left: Option<u32>,
+    ) -> Option<Zeroizing<String>> {
+        let Some(callback) = self.callback else {
+            warn!("A PIN is required, but no PIN callback was given");
+            return None;
+        };
+        let reason = match reason {
+            PinRequestReason::RelyingPartyRequest => LW_PIN_REASON_RELYING_PARTY_REQUEST,
+            PinRequestReason::AuthenticatorPolicy => LW_PIN_REASON_AUTHENTICATOR_POLICY,
+            PinRequestReason::FallbackFromUV => LW_PIN_REASON_FALLBACK_FROM_UV,
+        };
+        let attempts_left = attempts_left.map_or(-1, |n| i32::try_from(n).unwrap_or(i32::MAX));
+        let mut pin = Zeroizing::new([0u8; LW_PIN_BUFFER_SIZE]);
+        let status = unsafe {
+            callback(
+                self.user_data,
+                reason,
+                attempts_left,
+                pin.as_mut_ptr() as *mut c_char,
+                pin.len(),
+            )
+        };
+        if status != 0 {
+            return None;
+        }
+        pin[LW_PIN_BUFFER_SIZE - 1] = 0;
+        let pin = CStr::from_bytes_until_nul(&pin[..]).ok()?;
+        pin.to_str().ok().map(|pin| Zeroizing::new(pin.to_owned()))
+    }
+}
+
+enum Ceremony {
+    Register,
+    Authenticate,
+}
+
+#[allow(clippy::too_many_arguments)]
+unsafe fn run_ceremony(
+    ceremony: Ceremony,
+    devices: *mut LwDeviceList,
+    index: usize,
+    origin: *const c_char,
+    options_json: *const c_char,
+    pin_callback: LwPinCallback,
+    user_data: *mut c_void,
+    cancel: *const LwCancelToken,
+    response_json: *mut *mut c_char,
+) -> c_int {
+    let result = (|| {
+        let devices = devices.as_mut().ok_or_else(|| invalid("devices"))?;
+        let device = devices
+            .devices
+            .get_mut(index)
+            .ok_or_else(|| invalid("index"))?;
+        let origin = str_arg(origin, "origin")?;
+        let options_json = str_arg(options_json, "options_json")?;
+        if response_json.is_null() {
+            return Err(invalid("response_json"));
+        }
+        let token = cancel.as_ref().map(|cancel| cancel.token.clone());
+        let pin_callback = PinCallback {
+            callback: pin_callback,
+            user_data,
+        };
+        let pin_callback = move |reason, attempts_left| pin_callback.call(reason, attempts_left);
+
+        let response = block_on(async move {
+            let mut channel = device.channel().await?;
+            channel.set_cancellation_token(token);
+            match ceremony {
+                Ceremony::Register => {
+                    register_on_channel(&mut channel, origin, options_json, pin_callback).await
+                }
+                Ceremony::Authenticate => {
+                    authenticate_on_channel(&mut channel, origin, options_json, pin_callback).await
+                }
+            }
+        })?;
+        CString::new(response).map_err(|_| Error::Platform(PlatformError::InvalidDeviceResponse))
+    })();
+
+    match result {
+        Ok(response) => {
+            *response_json = response.into_raw();
+            LW_OK
+        }
+        Err(error) => fail(error),
+    }
+}
+
+/// Lists the connected USB security keys. Returns NULL on failure.
+#[no_mangle]
+pub extern "C" fn lw_device_list_new() -> *mut LwDeviceList {
+    guarded(ptr::null_mut(), || match block_on(list_devices()) {
+        Ok(devices) => Box::into_raw(Box::new(LwDeviceList { devices })),
+        Err(error) => {
+            fail(error);
+            ptr::null_mut()
+        }
+    })
+}
+
+/// The number of devices in the list.
+///
+/// # Safety
+/// `devices` must be NULL or returned by [lw_device_list_new], and not yet freed.
+#[no_mangle]
+pub unsafe extern "C" fn lw_device_list_len(devices: *const LwDeviceList) -> usize {
+    devices.as_ref().map_or(0, |devices| devices.devices.len())
+}
+
+/// A description of the device at `index`, to show to the user. Returns NULL if out of
+/// range.
+///
+/// # Safety
+/// `devices` must be NULL or returned by [lw_device_list_new], and not yet freed.
+#[no_mangle]
+pub unsafe extern "C" fn lw_device_list_name(
+    devices: *const LwDeviceList,
+    index: usize,
+) -> *mut c_char {
+    guarded(ptr::null_mut(), || {
+        devices
+            .as_ref()
+            .and_then(|devices| devices.devices.get(index))
+            .and_then(|device| CString::new(device.to_string()).ok())
+            .map_or(ptr::null_mut(), CString::into_raw)
+    })
+}
+
+/// # Safety
+/// `devices` must be NULL or returned by [lw_device_list_new], and not yet freed.
+#[no_mangle]
+pub unsafe extern "C" fn lw_device_list_free(devices: *mut LwDeviceList) {
+    if !devices.is_null() {
+        drop(Box::from_raw(devices));
+    }
+}
+
+/// Registers a new credential on the device at `index`, see `libwebauthn::simple::register`.
+/// On success, `*response_json` is set to the `RegistrationResponseJSON`.
+///
+/// # Safety
+/// `devices` must be returned by [lw_device_list_new], and not used by another call at the
+/// same time. `origin` and `options_json` must be NUL-terminated strings. `cancel` must be
+/// NULL or returned by [lw_cancel_token_new]. `response_json` must be a valid pointer.
+#[no_mangle]
+#[allow(clippy::too_many_arguments)]
+pub unsafe extern "C" fn lw_register(
+    devices: *mut LwDeviceList,
+    index: usize,
+    origin: *const c_char,
+    options_json: *const c_char,
+    pin_callback: LwPinCallback,
+    user_data: *mut c_void,
+    cancel: *const LwCancelToken,
+    response_json: *mut *mut c_char,
+) -> c_int {
+    guarded(LW_ERROR, || {
+        run_ceremony(
+            Ceremony::Register,
+            devices,
+            index,
+            origin,
+            options_json,
+            pin_callback,
+            user_data,
+            cancel,
+            response_json,
+        )
+    })
+}
+
+/// Gets an assertion from the device at `index`, see `libwebauthn::simple::authenticate`.
+/// On success, `*response_json` is set to the `AuthenticationResponseJSON`.
+///
+/// # Safety
+/// As for [lw_register].
+#[no_mangle]
+#[allow(clippy::too_many_arguments)]
+pub unsafe extern "C" fn lw_authenticate(
+    devices: *mut LwDeviceList,
+    index: usize,
+    origin: *const c_char,
+    options_json: *const c_char,
+    pin_callback: LwPinCallback,
+    user_data: *mut c_void,
+    cancel: *const LwCancelToken,
+    response_json: *mut *mut c_char,
+) -> c_int {
+    guarded(LW_ERROR, || {
+        run_ceremony(
+            Ceremony::Authenticate,
+            devices,
+            index,
+            origin,
+            options_json,
+            pin_callback,
+            user_data,
+            cancel,
+            response_json,
+        )
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn lw_cancel_token_new() -> *mut LwCancelToken {
+    Box::into_raw(Box::new(LwCancelToken {
+        token: CancellationToken::new(),
+    }))
+}
+
+/// Aborts the ceremonies using `cancel`, which then fail with `PLATFORM_CANCELLED`.
+/// Can be called from any thread.
+///
+/// # Safety
+/// `cancel` must be NULL or returned by [lw_cancel_token_new], and not yet freed.
+#[no_mangle]
+pub unsafe extern "C" fn lw_cancel_token_cancel(cancel: *const LwCancelToken) {
+    if let Some(cancel) = cancel.as_ref() {
+        cancel.token.cancel();
+    }
+}
+
+/// # Safety
+/// `cancel` must be NULL or returned by [lw_cancel_token_new], and not yet freed.
+#[no_mangle]
+pub unsafe extern "C" fn lw_cancel_token_free(cancel: *mut LwCancelToken) {
+    if !cancel.is_null() {
+        drop(Box::from_raw(cancel));
+    }
+}
+
+/// The stable code of the last error on this thread, e.g. `CTAP2_ERR_PIN_INVALID`, or NULL.
+/// `PLATFORM_INTERNAL_ERROR` if the library panicked. Valid until the next failing call on
+/// this thread.
+#[no_mangle]
+pub extern "C" fn lw_last_error() -> *const c_char {
+    LAST_ERROR.with(|cell| {
+        cell.borrow()
+            .as_ref()
+            .map_or(ptr::null(), |error| error.code.as_ptr())
+    })
+}
+
+/// The WebAuthn error of the last error on this thread, e.g. `NotAllowedError`, or NULL.
+/// Valid until the next failing call on this thread.
+#[no_mangle]
+pub extern "C" fn lw_last_error_category() -> *const c_char {
+    LAST_ERROR.with(|cell| {
+        cell.borrow()
+            .as_ref()
+            .map_or(ptr::null(), |error| error.category.as_ptr())
+    })
+}
+
+/// # Safety
+/// `string` must be NULL or returned by this library, and not yet freed.
+#[no_mangle]
+pub unsafe extern "C" fn lw_string_free(string: *mut c_char) {
+    if !string.is_null() {
+        drop(CString::from_raw(string));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::{c_char, c_int, c_void, CStr};
+    use std::ptr;
+
+    use libwebauthn::pin::PinRequestReason;
+
+    use super::*;
+
+    #[test]
+    fn invalid_arguments_set_last_error() {
+        let mut response: *mut c_char = ptr::null_mut();
+        let status = unsafe {
+            lw_register(
+                ptr::null_mut(),
+                0,
+                c"https://example.org".as_ptr(),
+                c"{}".as_ptr(),
+                None,
+                ptr::null_mut(),
+                ptr::null(),
+                &mut response,
+            )
+        };
+        assert_eq!(status, LW_ERROR);
+        assert!(response.is_null());
+        let code = unsafe { CStr::from_ptr(lw_last_error()) };
+        assert_eq!(code.to_str().unwrap(), "PLATFORM_SYNTAX_ERROR");
+        let category = unsafe { CStr::from_ptr(lw_last_error_category()) };
+        assert_eq!(category.to_str().unwrap(), "TypeError");
+    }
+
+    #[test]
+    fn pin_callback_fills_buffer() {
+        unsafe extern "C" fn callback(
+            user_data: *mut c_void,
+            reason: c_int,
+            attempts_left: i32,
+            pin: *mut c_char,
+            pin_len: usize,
+        ) -> c_int {
+            assert_eq!(*(user_data as *const u32), 7);
+            assert_eq!(reason, LW_PIN_REASON_AUTHENTICATOR_POLICY);
+            assert_eq!(attempts_left, -1);
+            assert_eq!(pin_len, LW_PIN_BUFFER_SIZE);
+            ptr::copy_nonoverlapping(c"1234".as_ptr(), pin, 5);
+            0
+        }
+
+        let mut user_data = 7u32;
+        let pin_callback = PinCallback {
+            callback: Some(callback),
+            user_data: &mut user_data as *mut u32 as *mut c_void,
+        };
+        assert_eq!(
+            pin_callback.call(PinRequestReason::AuthenticatorPolicy, None),
+            Some(Zeroizing::new("1234".to_owned()))
+        );
+
+        let no_callback = PinCallback {
+            callback: None,
+            user_data: ptr::null_mut(),
+        };
+        assert_eq!(
+            no_callback.call(PinRequestReason::RelyingPartyRequest, Some(3)),
+            None
+        );
+    }
+
+    #[test]
+    fn panics_set_last_error() {
+        let status = guarded(LW_ERROR, || -> c_int { panic!("test panic") });
+        assert_eq!(status, LW_ERROR);
+        let code = unsafe { CStr::from_ptr(lw_last_error()) };
+        assert_eq!(code.to_str().unwrap(), PANIC_ERROR_CODE);
+        let category = unsafe { CStr::from_ptr(lw_last_error_category()) };
+        assert_eq!(category.to_str().unwrap(), "UnknownError");
+    }
+}
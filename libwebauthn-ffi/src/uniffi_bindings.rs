@@ -0,0 +1,260 @@
+//! UniFFI bindings, for Kotlin and Swift.
+//!
+//! Generate them from the built library, e.g.:
+//! ```text
+//! $ cargo build -p libwebauthn-ffi --features uniffi
+//! $ cargo run -p libwebauthn-ffi --features uniffi --bin uniffi-bindgen -- \
+//!     generate --library target/debug/libwebauthn_ffi.so --language kotlin --out-dir out
+//! ```
+//!
+//! As with the C ABI, ceremonies take and return WebAuthn Level 3 JSON, and block until
+//! they complete; progress and PIN requests are reported to a [UxListener] implemented by
+//! the app.
+
+use std::sync::{Arc, Mutex};
+
+use libwebauthn::blocking::block_on;
+use libwebauthn::pin::PinRequestReason;
+use libwebauthn::simple::{authenticate_on_channel, register_on_channel};
+use libwebauthn::transport::hid::{list_devices, HidDevice};
+use libwebauthn::transport::{CancellationToken, Channel, Device};
+use libwebauthn::webauthn::Error;
+use libwebauthn::UvUpdate;
+use zeroize::Zeroizing;
+
+#[derive(Debug, thiserror::Error, uniffi::Error)]
+pub enum WebAuthnError {
+    /// `code` is the stable code of the error, e.g. `CTAP2_ERR_PIN_INVALID`, and
+    /// `category` the WebAuthn error, e.g. `NotAllowedError`.
+    #[error("{code} ({category})")]
+    Failed { code: String, category: String },
+}
+
+impl From<Error> for WebAuthnError {
+    fn from(error: Error) -> Self {
+        Self::Failed {
+            code: error.code().to_owned(),
+            category: error.webauthn_category().name().to_owned(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, uniffi::Enum)]
+pub enum PinReason {
+    RelyingPartyRequest,
+    AuthenticatorPolicy,
+    FallbackFromUv,
+}
+
+impl From<PinRequestReason> for PinReason {
+    fn from(reason: PinRequestReason) -> Self {
+        match reason {
+            PinRequestReason::RelyingPartyRequest => Self::RelyingPartyRequest,
+            PinRequestReason::AuthenticatorPolicy => Self::AuthenticatorPolicy,
+            PinRequestReason::FallbackFromUV => Self::FallbackFromUv,
+        }
+    }
+}
+
+/// Progress of a ceremony, see `libwebauthn::UvUpdate`.
+#[derive(Debug, Clone, uniffi::Enum)]
+pub enum UxUpdate {
+    UvRetry {
+        attempts_left: Option<u32>,
+    },
+    PresenceRequired,
+    Processing,
+    AlwaysUvEnforced,
+    DeviceRemoved,
+    OperationTimeout,
+    SessionLocked,
+    Cancelled,
+    /// The user is verified again, for the given reason.
+    AuthTokenMismatch {
+        reason: String,
+    },
+}
+
+impl UxUpdate {
+    fn from_uv_update(update: UvUpdate) -> Option<Self> {
+        Some(match update {
+            UvUpdate::UvRetry { attempts_left } => Self::UvRetry { attempts_left },
+            // Answered through UxListener::request_pin instead.
+            UvUpdate::PinRequired(_) => return None,
+            UvUpdate::PresenceRequired => Self::PresenceRequired,
+            UvUpdate::Processing => Self::Processing,
+            UvUpdate::AlwaysUvEnforced => Self::AlwaysUvEnforced,
+            UvUpdate::DeviceRemoved => Self::DeviceRemoved,
+            UvUpdate::OperationTimeout => Self::OperationTimeout,
+            UvUpdate::SessionLocked => Self::SessionLocked,
+            UvUpdate::Cancelled => Self::Cancelled,
+            UvUpdate::AuthTokenMismatch(mismatch) => Self::AuthTokenMismatch {
+                reason: mismatch.to_string(),
+            },
+            _ => return None,
+        })
+    }
+}
+
+/// Implemented by the app, to prompt the user during a ceremony. Called from library
+/// threads.
+#[uniffi::export(with_foreign)]
+pub trait UxListener: Send + Sync {
+    /// Asks the user for a PIN. Returning `None` cancels the ceremony.
+    fn request_pin(&self, reason: PinReason, attempts_left: Option<u32>) -> Option<String>;
+    /// Reports the progress of the ceremony, e.g. that the user has to touch the device.
+    fn on_update(&self, update: UxUpdate);
+}
+
+/// Aborts the ceremonies it is passed to.
+#[derive(uniffi::Object)]
+pub struct CancelToken {
+    token: CancellationToken,
+}
+
+#[uniffi::export]
+impl CancelToken {
+    #[uniffi::constructor]
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self {
+            token: CancellationToken::new(),
+        })
+    }
+
+    /// Aborts the ceremonies using this token, which then fail with `PLATFORM_CANCELLED`.
+    pub fn cancel(&self) {
+        self.token.cancel();
+    }
+}
+
+/// A connected USB security key.
+#[derive(uniffi::Object)]
+pub struct SecurityKey {
+    name: String,
+    device: Mutex<HidDevice>,
+}
+
+enum Ceremony {
+    Register,
+    Authenticate,
+}
+
+impl SecurityKey {
+    fn run(
+        &self,
+        ceremony: Ceremony,
+        origin: &str,
+        options_json: &str,
+        listener: Arc<dyn UxListener>,
+        cancel: Option<Arc<CancelToken>>,
+    ) -> Result<String, WebAuthnError> {
+        let mut device = self.device.lock().unwrap_or_else(|e| e.into_inner());
+        let pin_listener = Arc::clone(&listener);
+        let pin_callback = move |reason: PinRequestReason, attempts_left| {
+            pin_listener
+                .request_pin(reason.into(), attempts_left)
+                .map(Zeroizing::new)
+        };
+
+        let response = block_on(async move {
+            let mut channel = device.channel().await?;
+            channel.set_cancellation_token(cancel.map(|cancel| cancel.token.clone()));
+            let mut updates = channel.get_ux_update_receiver();
+            let forwarder = tokio::spawn(async move {
+                while let Ok(update) = updates.recv().await {
+                    if let Some(update) = UxUpdate::from_uv_update(update) {
+                        listener.on_update(update);
+                    }
+                }
+            });
+            let response = match ceremony {
+                Ceremony::Register => {
+                    register_on_channel(&mut channel, origin, options_json, pin_callback).await
+                }
+                Ceremony::Authenticate => {
+                    authenticate_on_channel(&mut channel, origin, options_json, pin_callback).await
+                }
+            };
+            forwarder.abort();
+            response
+        })?;
+        Ok(response)
+    }
+}
+
+#[uniffi::export]
+impl SecurityKey {
+    /// A description of the device, to show to the user.
+    pub fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    /// Registers a new credential, see `libwebauthn::simple::register`.
+    pub fn register(
+        &self,
+        origin: String,
+        options_json: String,
+        listener: Arc<dyn UxListener>,
+        cancel: Option<Arc<CancelToken>>,
+    ) -> Result<String, WebAuthnError> {
+        self.run(Ceremony::Register, &origin, &options_json, listener, cancel)
+    }
+
+    /// Gets an assertion, see `libwebauthn::simple::authenticate`.
+    pub fn authenticate(
+        &self,
+        origin: String,
+        options_json: String,
+        listener: Arc<dyn UxListener>,
+        cancel: Option<Arc<CancelToken>>,
+    ) -> Result<String, WebAuthnError> {
+        self.run(
+            Ceremony::Authenticate,
+            &origin,
+            &options_json,
+            listener,
+            cancel,
+        )
+    }
+}
+
+/// Lists the connected USB security keys.
+#[uniffi::export]
+pub fn list_security_keys() -> Result<Vec<Arc<SecurityKey>>, WebAuthnError> {
+    let devices = block_on(list_devices())?;
+    Ok(devices
+        .into_iter()
+        .map(|device| {
+            Arc::new(SecurityKey {
+                name: device.to_string(),
+                device: Mutex::new(device),
+            })
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use libwebauthn::webauthn::{Error, PlatformError};
+
+    use super::{UxUpdate, WebAuthnError};
+    use libwebauthn::UvUpdate;
+
+    #[test]
+    fn errors_carry_code_and_category() {
+        let error = WebAuthnError::from(Error::Platform(PlatformError::Cancelled));
+        assert_eq!(error.to_string(), "PLATFORM_CANCELLED (AbortError)");
+    }
+
+    #[test]
+    fn uv_updates_are_forwarded() {
+        assert!(matches!(
+            UxUpdate::from_uv_update(UvUpdate::UvRetry {
+                attempts_left: Some(2)
+            }),
+            Some(UxUpdate::UvRetry {
+                attempts_left: Some(2)
+            })
+        ));
+    }
+}
@@ -27,6 +27,15 @@ async fn handle_updates(mut state_recv: Receiver<UvUpdate>) {
     while let Ok(update) = state_recv.recv().await {
         match update {
             UvUpdate::PresenceRequired => println!("Please touch your device!"),
+            UvUpdate::AlwaysUvEnforced => {
+                println!("Your device always requires user verification.")
+            }
+            UvUpdate::DeviceRemoved => println!("Device removed! Plug it back in to continue."),
+            UvUpdate::OperationTimeout => println!("Timed out, please try again."),
+            UvUpdate::SessionLocked => println!("Unlock your session to continue."),
+            UvUpdate::Cancelled => println!("Operation cancelled."),
+            UvUpdate::AuthTokenMismatch(reason) => println!("Verifying you again: {reason}."),
+            UvUpdate::Processing => println!("Your device is busy, please wait."),
             UvUpdate::UvRetry { attempts_left } => {
                 print!("UV failed.");
                 if let Some(attempts_left) = attempts_left {
@@ -59,6 +68,7 @@ async fn handle_updates(mut state_recv: Receiver<UvUpdate>) {
                     let _ = update.send_pin(&pin_raw);
                 }
             }
+            _ => {}
         }
     }
 }
@@ -1,3 +1,4 @@
+use futures::StreamExt;
 use libwebauthn::UvUpdate;
 use std::error::Error;
 use std::fmt::Display;
@@ -7,12 +8,12 @@ use text_io::read;
 use tokio::sync::broadcast::Receiver;
 use tracing_subscriber::{self, EnvFilter};
 
-use libwebauthn::management::BioEnrollment;
+use libwebauthn::management::{truncate_friendly_name, BioEnrollment, FingerprintEnrollmentUpdate};
 use libwebauthn::pin::PinRequestReason;
 use libwebauthn::proto::ctap2::{Ctap2, Ctap2GetInfoResponse, Ctap2LastEnrollmentSampleStatus};
 use libwebauthn::transport::hid::list_devices;
 use libwebauthn::transport::{Channel as _, Device};
-use libwebauthn::webauthn::Error as WebAuthnError;
+use libwebauthn::webauthn::{CtapError, Error as WebAuthnError};
 
 const TIMEOUT: Duration = Duration::from_secs(10);
 
@@ -27,6 +28,15 @@ async fn handle_updates(mut state_recv: Receiver<UvUpdate>) {
     while let Ok(update) = state_recv.recv().await {
         match update {
             UvUpdate::PresenceRequired => println!("Please touch your device!"),
+            UvUpdate::AlwaysUvEnforced => {
+                println!("Your device always requires user verification.")
+            }
+            UvUpdate::DeviceRemoved => println!("Device removed! Plug it back in to continue."),
+            UvUpdate::OperationTimeout => println!("Timed out, please try again."),
+            UvUpdate::SessionLocked => println!("Unlock your session to continue."),
+            UvUpdate::Cancelled => println!("Operation cancelled."),
+            UvUpdate::AuthTokenMismatch(reason) => println!("Verifying you again: {reason}."),
+            UvUpdate::Processing => println!("Your device is busy, please wait."),
             UvUpdate::UvRetry { attempts_left } => {
                 print!("UV failed.");
                 if let Some(attempts_left) = attempts_left {
@@ -59,6 +69,7 @@ async fn handle_updates(mut state_recv: Receiver<UvUpdate>) {
                     let _ = update.send_pin(&pin_raw);
                 }
             }
+            _ => {}
         }
     }
 }
@@ -180,7 +191,7 @@ pub async fn main() -> Result<(), Box<dyn Error>> {
                     .map(|x| format!("{x:?}")),
                 Operation::RemoveEnrollment => {
                     let enrollments = loop {
-                        match channel.get_bio_enrollments(TIMEOUT).await {
+                        match channel.list_fingerprints(TIMEOUT).await {
                             Ok(r) => break r,
                             Err(WebAuthnError::Ctap(ctap_error)) => {
                                 if ctap_error.is_retryable_user_error() {
@@ -198,16 +209,13 @@ pub async fn main() -> Result<(), Box<dyn Error>> {
                     }
                     let idx = ask_for_user_input(enrollments.len());
                     channel
-                        .remove_bio_enrollment(
-                            &enrollments[idx].template_id.as_ref().unwrap(),
-                            TIMEOUT,
-                        )
+                        .remove_bio_enrollment(&enrollments[idx].template_id, TIMEOUT)
                         .await
                         .map(|x| format!("{x:?}"))
                 }
                 Operation::RenameEnrollment => {
                     let enrollments = loop {
-                        match channel.get_bio_enrollments(TIMEOUT).await {
+                        match channel.list_fingerprints(TIMEOUT).await {
                             Ok(r) => break r,
                             Err(WebAuthnError::Ctap(ctap_error)) => {
                                 if ctap_error.is_retryable_user_error() {
@@ -226,47 +234,40 @@ pub async fn main() -> Result<(), Box<dyn Error>> {
                     let idx = ask_for_user_input(enrollments.len());
                     print!("New name: ");
                     io::stdout().flush().expect("Failed to flush stdout!");
-                    let new_name: String = read!("{}\n");
+                    let mut new_name: String = read!("{}\n");
+                    if let Ok(Some(max)) = channel.get_max_template_friendly_name(TIMEOUT).await {
+                        let truncated = truncate_friendly_name(&new_name, max);
+                        if truncated.len() < new_name.len() {
+                            println!("Name too long for this device, using \"{truncated}\"");
+                            new_name = truncated.to_owned();
+                        }
+                    }
                     channel
-                        .rename_bio_enrollment(
-                            &enrollments[idx].template_id.as_ref().unwrap(),
-                            &new_name,
-                            TIMEOUT,
-                        )
+                        .rename_bio_enrollment(&enrollments[idx].template_id, &new_name, TIMEOUT)
                         .await
                         .map(|x| format!("{x:?}"))
                 }
                 Operation::AddNewEnrollment => {
-                    let (template_id, mut sample_status, mut remaining_samples) =
-                        match channel.start_new_bio_enrollment(None, TIMEOUT).await {
-                            Ok(r) => r,
-                            Err(WebAuthnError::Ctap(ctap_error)) => {
-                                if ctap_error.is_retryable_user_error() {
-                                    println!("Oops, try again! Error: {}", ctap_error);
-                                    continue;
-                                }
-                                break Err(WebAuthnError::Ctap(ctap_error));
+                    let mut updates = channel.enroll_fingerprint(None, TIMEOUT);
+                    let mut result = Err(WebAuthnError::Ctap(CtapError::Other));
+                    while let Some(update) = updates.next().await {
+                        match update {
+                            Ok(FingerprintEnrollmentUpdate::Progress {
+                                last_sample_status,
+                                remaining_samples,
+                            }) => print_status_update(last_sample_status, remaining_samples),
+                            Ok(FingerprintEnrollmentUpdate::Completed { template_id }) => {
+                                result = Ok(format!("Enrolled template {template_id:?}"));
                             }
-                            Err(err) => break Err(err),
-                        };
-                    while remaining_samples > 0 {
-                        print_status_update(sample_status, remaining_samples);
-                        (sample_status, remaining_samples) = match channel
-                            .capture_next_bio_enrollment_sample(&template_id, None, TIMEOUT)
-                            .await
-                        {
-                            Ok(r) => r,
-                            Err(WebAuthnError::Ctap(ctap_error)) => {
-                                if ctap_error.is_retryable_user_error() {
-                                    println!("Oops, try again! Error: {}", ctap_error);
-                                    continue;
-                                }
-                                break 'outer Err(WebAuthnError::Ctap(ctap_error));
+                            Err(WebAuthnError::Ctap(ctap_error))
+                                if ctap_error.is_retryable_user_error() =>
+                            {
+                                println!("Oops, try again! Error: {}", ctap_error);
                             }
-                            Err(err) => break 'outer Err(err),
-                        };
+                            Err(err) => result = Err(err),
+                        }
                     }
-                    Ok(format!("Success!"))
+                    result
                 }
             };
             match action {
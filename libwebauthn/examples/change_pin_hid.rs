@@ -14,6 +14,7 @@ use libwebauthn::transport::Device;
 use libwebauthn::webauthn::Error as WebAuthnError;
 use std::io::{self, Write};
 use text_io::read;
+use zeroize::Zeroizing;
 
 const TIMEOUT: Duration = Duration::from_secs(10);
 
@@ -28,6 +29,15 @@ async fn handle_updates(mut state_recv: Receiver<UvUpdate>) {
     while let Ok(update) = state_recv.recv().await {
         match update {
             UvUpdate::PresenceRequired => println!("Please touch your device!"),
+            UvUpdate::AlwaysUvEnforced => {
+                println!("Your device always requires user verification.")
+            }
+            UvUpdate::DeviceRemoved => println!("Device removed! Plug it back in to continue."),
+            UvUpdate::OperationTimeout => println!("Timed out, please try again."),
+            UvUpdate::SessionLocked => println!("Unlock your session to continue."),
+            UvUpdate::Cancelled => println!("Operation cancelled."),
+            UvUpdate::AuthTokenMismatch(reason) => println!("Verifying you again: {reason}."),
+            UvUpdate::Processing => println!("Your device is busy, please wait."),
             UvUpdate::UvRetry { attempts_left } => {
                 print!("UV failed.");
                 if let Some(attempts_left) = attempts_left {
@@ -60,6 +70,7 @@ async fn handle_updates(mut state_recv: Receiver<UvUpdate>) {
                     let _ = update.send_pin(&pin_raw);
                 }
             }
+            _ => {}
         }
     }
 }
@@ -76,11 +87,16 @@ pub async fn main() -> Result<(), Box<dyn Error>> {
         let mut channel = device.channel().await?;
         channel.wink(TIMEOUT).await?;
 
+        let retries = channel.get_retries(TIMEOUT).await?;
+        if let Some(pin_retries) = retries.pin_retries {
+            println!("PIN: {} attempts left.", pin_retries);
+        }
+
         print!("PIN: Please enter the _new_ PIN: ");
         io::stdout().flush().unwrap();
-        let new_pin: String = read!("{}\n");
+        let new_pin: Zeroizing<String> = Zeroizing::new(read!("{}\n"));
 
-        if &new_pin == "" {
+        if new_pin.is_empty() {
             println!("PIN: No PIN provided, cancelling operation.");
             return Ok(());
         }
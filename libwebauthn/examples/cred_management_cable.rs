@@ -0,0 +1,172 @@
+use std::error::Error;
+use std::io::{self, Write};
+use std::time::Duration;
+
+use futures::stream::TryStreamExt;
+use libwebauthn::management::CredentialManagement;
+use libwebauthn::pin::PinRequestReason;
+use libwebauthn::proto::ctap2::{Ctap2, Ctap2CredentialData, Ctap2RPData};
+use libwebauthn::transport::cable::channel::{CableUpdate, CableUxUpdate};
+use libwebauthn::transport::cable::qr_code_device::{CableQrCodeDevice, QrCodeOperationHint};
+use libwebauthn::transport::{Channel as _, Device};
+use libwebauthn::UvUpdate;
+use qrcode::render::unicode;
+use qrcode::QrCode;
+use text_io::read;
+use tokio::sync::broadcast::Receiver;
+use tracing_subscriber::{self, EnvFilter};
+
+const TIMEOUT: Duration = Duration::from_secs(120);
+
+fn setup_logging() {
+    tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::from_default_env())
+        .without_time()
+        .init();
+}
+
+async fn handle_updates(mut state_recv: Receiver<CableUxUpdate>) {
+    while let Ok(update) = state_recv.recv().await {
+        match update {
+            CableUxUpdate::UvUpdate(uv_update) => match uv_update {
+                UvUpdate::PresenceRequired => println!("Please touch your device!"),
+                UvUpdate::AlwaysUvEnforced => {
+                    println!("Your device always requires user verification.")
+                }
+                UvUpdate::DeviceRemoved => println!("Device removed!"),
+                UvUpdate::OperationTimeout => println!("Timed out, please try again."),
+                UvUpdate::SessionLocked => println!("Unlock your session to continue."),
+                UvUpdate::Cancelled => println!("Operation cancelled."),
+                UvUpdate::AuthTokenMismatch(reason) => println!("Verifying you again: {reason}."),
+                UvUpdate::Processing => println!("Your device is busy, please wait."),
+                UvUpdate::UvRetry { attempts_left } => {
+                    print!("UV failed.");
+                    if let Some(attempts_left) = attempts_left {
+                        print!(" You have {attempts_left} attempts left.");
+                    }
+                }
+                UvUpdate::PinRequired(update) => {
+                    let mut attempts_str = String::new();
+                    if let Some(attempts) = update.attempts_left {
+                        attempts_str = format!(". You have {attempts} attempts left!");
+                    };
+
+                    match update.reason {
+                        PinRequestReason::RelyingPartyRequest => println!("RP required a PIN."),
+                        PinRequestReason::AuthenticatorPolicy => {
+                            println!("Your device requires a PIN.")
+                        }
+                        PinRequestReason::FallbackFromUV => {
+                            println!("UV failed too often and is blocked. Falling back to PIN.")
+                        }
+                    }
+                    print!("PIN: Please enter the PIN for your authenticator{attempts_str}: ");
+                    io::stdout().flush().unwrap();
+                    let pin_raw: String = read!("{}\n");
+
+                    if pin_raw.is_empty() {
+                        println!("PIN: No PIN provided, cancelling operation.");
+                        update.cancel();
+                    } else {
+                        let _ = update.send_pin(&pin_raw);
+                    }
+                }
+                _ => {}
+            },
+            CableUxUpdate::CableUpdate(cable_update) => match cable_update {
+                CableUpdate::ProximityCheck => println!("Proximity check in progress..."),
+                CableUpdate::AdvertReceived { elapsed } => {
+                    println!("Device found nearby after {:?}.", elapsed)
+                }
+                CableUpdate::Connecting => println!("Connecting to the device..."),
+                CableUpdate::Reconnecting { attempt, .. } => {
+                    println!("Connection failed, retrying (attempt {})...", attempt)
+                }
+                CableUpdate::TunnelConnected { elapsed } => {
+                    println!("Connected to the tunnel server after {:?}.", elapsed)
+                }
+                CableUpdate::Authenticating => println!("Authenticating with the device..."),
+                CableUpdate::Connected => println!("Tunnel established successfully!"),
+                CableUpdate::SessionEstablished { elapsed, .. } => {
+                    println!("Device ready after {:?}.", elapsed)
+                }
+                CableUpdate::Linking(status) => {
+                    println!("Device sent linking info, stored: {}.", status.stored)
+                }
+                CableUpdate::Error(err) => println!("Error during connection: {}", err),
+            },
+        }
+    }
+}
+
+fn format_credential(cred: &Ctap2CredentialData) -> String {
+    cred.user
+        .display_name
+        .clone()
+        .unwrap_or(cred.user.name.clone().unwrap_or("<No username>".into()))
+}
+
+#[tokio::main]
+pub async fn main() -> Result<(), Box<dyn Error>> {
+    setup_logging();
+
+    // Management flows start with authenticatorGetInfo, so hint a getAssertion.
+    let mut device: CableQrCodeDevice =
+        CableQrCodeDevice::new_transient(QrCodeOperationHint::GetAssertionRequest);
+
+    println!("Created QR code, awaiting for advertisement.");
+    let qr_code = QrCode::new(device.qr_code.to_string()).unwrap();
+    let image = qr_code
+        .render::<unicode::Dense1x2>()
+        .dark_color(unicode::Dense1x2::Light)
+        .light_color(unicode::Dense1x2::Dark)
+        .build();
+    println!("{}", image);
+
+    let mut channel = device.channel().await?;
+    println!("Tunnel established {:?}", channel);
+
+    let state_recv = channel.get_ux_update_receiver();
+    tokio::spawn(handle_updates(state_recv));
+
+    let info = channel.ctap2_get_info().await?;
+    if !info.supports_credential_management() {
+        println!("The authenticator does not support credential management.");
+        return Ok(());
+    }
+
+    let rps: Vec<Ctap2RPData> = channel.enumerate_rps(TIMEOUT).try_collect().await?;
+    let mut credentials = vec![];
+    for rp in rps {
+        let creds: Vec<Ctap2CredentialData> = channel
+            .enumerate_credentials(&rp.rp_id_hash, TIMEOUT)
+            .try_collect()
+            .await?;
+        credentials.extend(creds.into_iter().map(|cred| (rp.rp.id.clone(), cred)));
+    }
+    if credentials.is_empty() {
+        println!("No passkeys stored on the authenticator.");
+        return Ok(());
+    }
+
+    for (idx, (rp_id, cred)) in credentials.iter().enumerate() {
+        println!("({idx}) {rp_id}: {}", format_credential(cred));
+    }
+    print!("Passkey to delete (empty to keep all): ");
+    io::stdout().flush().unwrap();
+    let input: String = read!("{}\n");
+    let Some((rp_id, cred)) = input
+        .trim()
+        .parse::<usize>()
+        .ok()
+        .and_then(|idx| credentials.get(idx))
+    else {
+        return Ok(());
+    };
+
+    channel
+        .delete_credential(&cred.credential_id, TIMEOUT)
+        .await?;
+    println!("Deleted {rp_id}: {}", format_credential(cred));
+    Ok(())
+}
@@ -0,0 +1,42 @@
+use std::env;
+use std::error::Error;
+
+use tracing_subscriber::{self, EnvFilter};
+
+use libwebauthn::transport::daemon::{list_devices, run_daemon};
+
+const DEFAULT_SOCKET_PATH: &str = "/tmp/libwebauthn.sock";
+
+fn setup_logging() {
+    tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::from_default_env())
+        .without_time()
+        .init();
+}
+
+/// Usage: `hid_daemon serve [SOCKET]` to share local HID devices,
+/// or `hid_daemon list [SOCKET]` to list the devices shared by a running daemon.
+#[tokio::main]
+pub async fn main() -> Result<(), Box<dyn Error>> {
+    setup_logging();
+
+    let args: Vec<String> = env::args().collect();
+    let socket_path = args
+        .get(2)
+        .map(String::as_str)
+        .unwrap_or(DEFAULT_SOCKET_PATH);
+    match args.get(1).map(String::as_str) {
+        Some("serve") => {
+            println!("Sharing HID devices on {}", socket_path);
+            run_daemon(socket_path).await?;
+        }
+        _ => {
+            let devices = list_devices(socket_path).await?;
+            println!("Devices shared by daemon: {}", devices.len());
+            for device in devices {
+                println!("  {} [{}]", device, device.id);
+            }
+        }
+    }
+    Ok(())
+}
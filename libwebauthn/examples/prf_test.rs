@@ -35,6 +35,15 @@ async fn handle_updates(mut state_recv: Receiver<UvUpdate>) {
     while let Ok(update) = state_recv.recv().await {
         match update {
             UvUpdate::PresenceRequired => println!("Please touch your device!"),
+            UvUpdate::AlwaysUvEnforced => {
+                println!("Your device always requires user verification.")
+            }
+            UvUpdate::DeviceRemoved => println!("Device removed! Plug it back in to continue."),
+            UvUpdate::OperationTimeout => println!("Timed out, please try again."),
+            UvUpdate::SessionLocked => println!("Unlock your session to continue."),
+            UvUpdate::Cancelled => println!("Operation cancelled."),
+            UvUpdate::AuthTokenMismatch(reason) => println!("Verifying you again: {reason}."),
+            UvUpdate::Processing => println!("Your device is busy, please wait."),
             UvUpdate::UvRetry { attempts_left } => {
                 print!("UV failed.");
                 if let Some(attempts_left) = attempts_left {
@@ -67,6 +76,7 @@ async fn handle_updates(mut state_recv: Receiver<UvUpdate>) {
                     let _ = update.send_pin(&pin_raw);
                 }
             }
+            _ => {}
         }
     }
 }
@@ -149,17 +159,18 @@ async fn run_success_test(
     hmac_or_prf: GetAssertionHmacOrPrfInput,
     printoutput: &str,
 ) {
-    let get_assertion = GetAssertionRequest {
-        relying_party_id: "demo.yubico.com".to_owned(),
-        hash: Vec::from(challenge),
-        allow: vec![credential.clone()],
-        user_verification: UserVerificationRequirement::Preferred,
-        extensions: Some(GetAssertionRequestExtensions {
+    let get_assertion = GetAssertionRequest::builder()
+        .rp_id("demo.yubico.com")
+        .client_data_hash(challenge)
+        .allow_credential(credential.clone())
+        .user_verification(UserVerificationRequirement::Preferred)
+        .extensions(GetAssertionRequestExtensions {
             hmac_or_prf,
             ..Default::default()
-        }),
-        timeout: TIMEOUT,
-    };
+        })
+        .timeout(TIMEOUT)
+        .build()
+        .unwrap();
 
     let response = loop {
         match channel.webauthn_get_assertion(&get_assertion).await {
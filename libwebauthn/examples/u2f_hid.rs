@@ -5,7 +5,7 @@ use libwebauthn::UvUpdate;
 use tokio::sync::broadcast::Receiver;
 use tracing_subscriber::{self, EnvFilter};
 
-use libwebauthn::ops::u2f::{RegisterRequest, SignRequest};
+use libwebauthn::ops::u2f::{AuthenticateRequest, RegisterRequest};
 use libwebauthn::transport::hid::list_devices;
 use libwebauthn::transport::{Channel as _, Device};
 use libwebauthn::u2f::U2F;
@@ -58,9 +58,9 @@ pub async fn main() -> Result<(), Box<dyn Error>> {
         // Signature ceremony
         println!("Signature request sent (timeout: {:?} seconds).", TIMEOUT);
         let new_key = response.as_registered_key()?;
-        let sign_request =
-            SignRequest::new(&APP_ID, &challenge, &new_key.key_handle, TIMEOUT, true);
-        let response = channel.u2f_sign(&sign_request).await?;
+        let authenticate_request =
+            AuthenticateRequest::new(&APP_ID, &challenge, vec![new_key], TIMEOUT);
+        let response = channel.u2f_authenticate(&authenticate_request).await?;
         println!("Response: {:?}", response);
     }
 
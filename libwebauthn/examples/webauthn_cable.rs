@@ -8,7 +8,7 @@ use libwebauthn::transport::cable::channel::{CableUpdate, CableUxUpdate};
 use libwebauthn::transport::cable::known_devices::{
     CableKnownDevice, ClientPayloadHint, EphemeralDeviceInfoStore,
 };
-use libwebauthn::transport::cable::qr_code_device::{CableQrCodeDevice, QrCodeOperationHint};
+use libwebauthn::transport::cable::qr_code_device::CableQrCodeDevice;
 use libwebauthn::UvUpdate;
 use qrcode::render::unicode;
 use qrcode::QrCode;
@@ -22,7 +22,7 @@ use libwebauthn::ops::webauthn::{
     GetAssertionRequest, MakeCredentialRequest, ResidentKeyRequirement, UserVerificationRequirement,
 };
 use libwebauthn::proto::ctap2::{
-    Ctap2CredentialType, Ctap2PublicKeyCredentialDescriptor, Ctap2PublicKeyCredentialRpEntity,
+    Ctap2PublicKeyCredentialDescriptor, Ctap2PublicKeyCredentialRpEntity,
     Ctap2PublicKeyCredentialUserEntity,
 };
 use libwebauthn::transport::{Channel as _, Device};
@@ -42,6 +42,15 @@ async fn handle_updates(mut state_recv: Receiver<CableUxUpdate>) {
         match update {
             CableUxUpdate::UvUpdate(uv_update) => match uv_update {
                 UvUpdate::PresenceRequired => println!("Please touch your device!"),
+                UvUpdate::AlwaysUvEnforced => {
+                    println!("Your device always requires user verification.")
+                }
+                UvUpdate::DeviceRemoved => println!("Device removed! Plug it back in to continue."),
+                UvUpdate::OperationTimeout => println!("Timed out, please try again."),
+                UvUpdate::SessionLocked => println!("Unlock your session to continue."),
+                UvUpdate::Cancelled => println!("Operation cancelled."),
+                UvUpdate::AuthTokenMismatch(reason) => println!("Verifying you again: {reason}."),
+                UvUpdate::Processing => println!("Your device is busy, please wait."),
                 UvUpdate::UvRetry { attempts_left } => {
                     print!("UV failed.");
                     if let Some(attempts_left) = attempts_left {
@@ -74,12 +83,28 @@ async fn handle_updates(mut state_recv: Receiver<CableUxUpdate>) {
                         let _ = update.send_pin(&pin_raw);
                     }
                 }
+                _ => {}
             },
             CableUxUpdate::CableUpdate(cable_update) => match cable_update {
                 CableUpdate::ProximityCheck => println!("Proximity check in progress..."),
+                CableUpdate::AdvertReceived { elapsed } => {
+                    println!("Device found nearby after {:?}.", elapsed)
+                }
                 CableUpdate::Connecting => println!("Connecting to the device..."),
+                CableUpdate::Reconnecting { attempt, .. } => {
+                    println!("Connection failed, retrying (attempt {})...", attempt)
+                }
+                CableUpdate::TunnelConnected { elapsed } => {
+                    println!("Connected to the tunnel server after {:?}.", elapsed)
+                }
                 CableUpdate::Authenticating => println!("Authenticating with the device..."),
                 CableUpdate::Connected => println!("Tunnel established successfully!"),
+                CableUpdate::SessionEstablished { elapsed, .. } => {
+                    println!("Device ready after {:?}.", elapsed)
+                }
+                CableUpdate::Linking(status) => {
+                    println!("Device sent linking info, stored: {}.", status.stored)
+                }
                 CableUpdate::Error(err) => println!("Error during connection: {}", err),
             },
         }
@@ -95,9 +120,27 @@ pub async fn main() -> Result<(), Box<dyn Error>> {
     let challenge: [u8; 32] = thread_rng().gen();
 
     let credential: Ctap2PublicKeyCredentialDescriptor = {
+        // Make Credentials ceremony
+        let make_credentials_request = MakeCredentialRequest::builder()
+            .origin("example.org")
+            .client_data_hash(&challenge)
+            .rp(Ctap2PublicKeyCredentialRpEntity::new(
+                "example.org",
+                "example.org",
+            ))
+            .user(Ctap2PublicKeyCredentialUserEntity::new(
+                &user_id,
+                "mario.rossi",
+                "Mario Rossi",
+            ))
+            .resident_key(ResidentKeyRequirement::Discouraged)
+            .user_verification(UserVerificationRequirement::Preferred)
+            .timeout(TIMEOUT)
+            .build()?;
+
         // Create QR code
         let mut device: CableQrCodeDevice = CableQrCodeDevice::new_persistent(
-            QrCodeOperationHint::MakeCredential,
+            ClientPayloadHint::from(&make_credentials_request),
             device_info_store.clone(),
         );
 
@@ -117,20 +160,6 @@ pub async fn main() -> Result<(), Box<dyn Error>> {
         let state_recv = channel.get_ux_update_receiver();
         tokio::spawn(handle_updates(state_recv));
 
-        // Make Credentials ceremony
-        let make_credentials_request = MakeCredentialRequest {
-            origin: "example.org".to_owned(),
-            hash: Vec::from(challenge),
-            relying_party: Ctap2PublicKeyCredentialRpEntity::new("example.org", "example.org"),
-            user: Ctap2PublicKeyCredentialUserEntity::new(&user_id, "mario.rossi", "Mario Rossi"),
-            resident_key: Some(ResidentKeyRequirement::Discouraged),
-            user_verification: UserVerificationRequirement::Preferred,
-            algorithms: vec![Ctap2CredentialType::default()],
-            exclude: None,
-            extensions: None,
-            timeout: TIMEOUT,
-        };
-
         let response = loop {
             match channel
                 .webauthn_make_credential(&make_credentials_request)
@@ -156,21 +185,20 @@ pub async fn main() -> Result<(), Box<dyn Error>> {
     println!("Waiting for 5 seconds before contacting the device...");
     sleep(Duration::from_secs(5)).await;
 
-    let get_assertion = GetAssertionRequest {
-        relying_party_id: "example.org".to_owned(),
-        hash: Vec::from(challenge),
-        allow: vec![credential],
-        user_verification: UserVerificationRequirement::Discouraged,
-        extensions: None,
-        timeout: TIMEOUT,
-    };
+    let get_assertion = GetAssertionRequest::builder()
+        .rp_id("example.org")
+        .client_data_hash(&challenge)
+        .allow_credential(credential)
+        .user_verification(UserVerificationRequirement::Discouraged)
+        .timeout(TIMEOUT)
+        .build()?;
 
     let all_devices = device_info_store.list_all().await;
     let (_known_device_id, known_device_info) =
         all_devices.first().expect("No known devices found");
 
     let mut known_device: CableKnownDevice = CableKnownDevice::new(
-        ClientPayloadHint::GetAssertion,
+        ClientPayloadHint::from(&get_assertion),
         known_device_info,
         device_info_store.clone(),
     )
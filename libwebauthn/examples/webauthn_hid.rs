@@ -14,7 +14,7 @@ use libwebauthn::ops::webauthn::{
 };
 use libwebauthn::pin::PinRequestReason;
 use libwebauthn::proto::ctap2::{
-    Ctap2CredentialType, Ctap2PublicKeyCredentialDescriptor, Ctap2PublicKeyCredentialRpEntity,
+    Ctap2PublicKeyCredentialDescriptor, Ctap2PublicKeyCredentialRpEntity,
     Ctap2PublicKeyCredentialUserEntity,
 };
 use libwebauthn::transport::hid::list_devices;
@@ -34,6 +34,15 @@ async fn handle_updates(mut state_recv: Receiver<UvUpdate>) {
     while let Ok(update) = state_recv.recv().await {
         match update {
             UvUpdate::PresenceRequired => println!("Please touch your device!"),
+            UvUpdate::AlwaysUvEnforced => {
+                println!("Your device always requires user verification.")
+            }
+            UvUpdate::DeviceRemoved => println!("Device removed! Plug it back in to continue."),
+            UvUpdate::OperationTimeout => println!("Timed out, please try again."),
+            UvUpdate::SessionLocked => println!("Unlock your session to continue."),
+            UvUpdate::Cancelled => println!("Operation cancelled."),
+            UvUpdate::AuthTokenMismatch(reason) => println!("Verifying you again: {reason}."),
+            UvUpdate::Processing => println!("Your device is busy, please wait."),
             UvUpdate::UvRetry { attempts_left } => {
                 print!("UV failed.");
                 if let Some(attempts_left) = attempts_left {
@@ -66,6 +75,7 @@ async fn handle_updates(mut state_recv: Receiver<UvUpdate>) {
                     let _ = update.send_pin(&pin_raw);
                 }
             }
+            _ => {}
         }
     }
 }
@@ -86,18 +96,22 @@ pub async fn main() -> Result<(), Box<dyn Error>> {
         channel.wink(TIMEOUT).await?;
 
         // Make Credentials ceremony
-        let make_credentials_request = MakeCredentialRequest {
-            origin: "example.org".to_owned(),
-            hash: Vec::from(challenge),
-            relying_party: Ctap2PublicKeyCredentialRpEntity::new("example.org", "example.org"),
-            user: Ctap2PublicKeyCredentialUserEntity::new(&user_id, "mario.rossi", "Mario Rossi"),
-            resident_key: Some(ResidentKeyRequirement::Discouraged),
-            user_verification: UserVerificationRequirement::Preferred,
-            algorithms: vec![Ctap2CredentialType::default()],
-            exclude: None,
-            extensions: None,
-            timeout: TIMEOUT,
-        };
+        let make_credentials_request = MakeCredentialRequest::builder()
+            .origin("example.org")
+            .client_data_hash(&challenge)
+            .rp(Ctap2PublicKeyCredentialRpEntity::new(
+                "example.org",
+                "example.org",
+            ))
+            .user(Ctap2PublicKeyCredentialUserEntity::new(
+                &user_id,
+                "mario.rossi",
+                "Mario Rossi",
+            ))
+            .resident_key(ResidentKeyRequirement::Discouraged)
+            .user_verification(UserVerificationRequirement::Preferred)
+            .timeout(TIMEOUT)
+            .build()?;
 
         let state_recv = channel.get_ux_update_receiver();
         tokio::spawn(handle_updates(state_recv));
@@ -123,14 +137,13 @@ pub async fn main() -> Result<(), Box<dyn Error>> {
 
         let credential: Ctap2PublicKeyCredentialDescriptor =
             (&response.authenticator_data).try_into().unwrap();
-        let get_assertion = GetAssertionRequest {
-            relying_party_id: "example.org".to_owned(),
-            hash: Vec::from(challenge),
-            allow: vec![credential],
-            user_verification: UserVerificationRequirement::Discouraged,
-            extensions: None,
-            timeout: TIMEOUT,
-        };
+        let get_assertion = GetAssertionRequest::builder()
+            .rp_id("example.org")
+            .client_data_hash(&challenge)
+            .allow_credential(credential)
+            .user_verification(UserVerificationRequirement::Discouraged)
+            .timeout(TIMEOUT)
+            .build()?;
 
         let response = loop {
             match channel.webauthn_get_assertion(&get_assertion).await {
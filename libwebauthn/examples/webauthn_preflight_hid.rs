@@ -17,7 +17,7 @@ use libwebauthn::ops::webauthn::{
 };
 use libwebauthn::pin::PinRequestReason;
 use libwebauthn::proto::ctap2::{
-    Ctap2CredentialType, Ctap2PublicKeyCredentialDescriptor, Ctap2PublicKeyCredentialRpEntity,
+    Ctap2PublicKeyCredentialDescriptor, Ctap2PublicKeyCredentialRpEntity,
     Ctap2PublicKeyCredentialType, Ctap2PublicKeyCredentialUserEntity,
 };
 use libwebauthn::transport::hid::list_devices;
@@ -37,6 +37,15 @@ async fn handle_updates(mut state_recv: Receiver<UvUpdate>) {
     while let Ok(update) = state_recv.recv().await {
         match update {
             UvUpdate::PresenceRequired => println!("Please touch your device!"),
+            UvUpdate::AlwaysUvEnforced => {
+                println!("Your device always requires user verification.")
+            }
+            UvUpdate::DeviceRemoved => println!("Device removed! Plug it back in to continue."),
+            UvUpdate::OperationTimeout => println!("Timed out, please try again."),
+            UvUpdate::SessionLocked => println!("Unlock your session to continue."),
+            UvUpdate::Cancelled => println!("Operation cancelled."),
+            UvUpdate::AuthTokenMismatch(reason) => println!("Verifying you again: {reason}."),
+            UvUpdate::Processing => println!("Your device is busy, please wait."),
             UvUpdate::UvRetry { attempts_left } => {
                 print!("UV failed.");
                 if let Some(attempts_left) = attempts_left {
@@ -69,6 +78,7 @@ async fn handle_updates(mut state_recv: Receiver<UvUpdate>) {
                     let _ = update.send_pin(&pin_raw);
                 }
             }
+            _ => {}
         }
     }
 }
@@ -161,18 +171,25 @@ async fn make_credential_call(
     exclude_list: Option<Vec<Ctap2PublicKeyCredentialDescriptor>>,
 ) -> Result<Ctap2PublicKeyCredentialDescriptor, WebAuthnError> {
     let challenge: [u8; 32] = thread_rng().gen();
-    let make_credentials_request = MakeCredentialRequest {
-        origin: "example.org".to_owned(),
-        hash: Vec::from(challenge),
-        relying_party: Ctap2PublicKeyCredentialRpEntity::new("example.org", "example.org"),
-        user: Ctap2PublicKeyCredentialUserEntity::new(&user_id, "mario.rossi", "Mario Rossi"),
-        resident_key: Some(ResidentKeyRequirement::Discouraged),
-        user_verification: UserVerificationRequirement::Preferred,
-        algorithms: vec![Ctap2CredentialType::default()],
-        exclude: exclude_list,
-        extensions: None,
-        timeout: TIMEOUT,
-    };
+    let mut builder = MakeCredentialRequest::builder()
+        .origin("example.org")
+        .client_data_hash(&challenge)
+        .rp(Ctap2PublicKeyCredentialRpEntity::new(
+            "example.org",
+            "example.org",
+        ))
+        .user(Ctap2PublicKeyCredentialUserEntity::new(
+            &user_id,
+            "mario.rossi",
+            "Mario Rossi",
+        ))
+        .resident_key(ResidentKeyRequirement::Discouraged)
+        .user_verification(UserVerificationRequirement::Preferred)
+        .timeout(TIMEOUT);
+    for credential in exclude_list.into_iter().flatten() {
+        builder = builder.exclude_credential(credential);
+    }
+    let make_credentials_request = builder.build()?;
 
     let response = loop {
         match channel
@@ -198,14 +215,15 @@ async fn get_assertion_call(
     allow_list: Vec<Ctap2PublicKeyCredentialDescriptor>,
 ) -> Result<GetAssertionResponse, WebAuthnError> {
     let challenge: [u8; 32] = thread_rng().gen();
-    let get_assertion = GetAssertionRequest {
-        relying_party_id: "example.org".to_owned(),
-        hash: Vec::from(challenge),
-        allow: allow_list,
-        user_verification: UserVerificationRequirement::Discouraged,
-        extensions: None,
-        timeout: TIMEOUT,
-    };
+    let mut builder = GetAssertionRequest::builder()
+        .rp_id("example.org")
+        .client_data_hash(&challenge)
+        .user_verification(UserVerificationRequirement::Discouraged)
+        .timeout(TIMEOUT);
+    for credential in allow_list {
+        builder = builder.allow_credential(credential);
+    }
+    let get_assertion = builder.build()?;
 
     let response = loop {
         match channel.webauthn_get_assertion(&get_assertion).await {
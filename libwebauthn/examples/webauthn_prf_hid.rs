@@ -18,7 +18,7 @@ use libwebauthn::ops::webauthn::{
 };
 use libwebauthn::pin::PinRequestReason;
 use libwebauthn::proto::ctap2::{
-    Ctap2CredentialType, Ctap2PublicKeyCredentialDescriptor, Ctap2PublicKeyCredentialRpEntity,
+    Ctap2PublicKeyCredentialDescriptor, Ctap2PublicKeyCredentialRpEntity,
     Ctap2PublicKeyCredentialUserEntity,
 };
 use libwebauthn::transport::hid::list_devices;
@@ -38,6 +38,15 @@ async fn handle_updates(mut state_recv: Receiver<UvUpdate>) {
     while let Ok(update) = state_recv.recv().await {
         match update {
             UvUpdate::PresenceRequired => println!("Please touch your device!"),
+            UvUpdate::AlwaysUvEnforced => {
+                println!("Your device always requires user verification.")
+            }
+            UvUpdate::DeviceRemoved => println!("Device removed! Plug it back in to continue."),
+            UvUpdate::OperationTimeout => println!("Timed out, please try again."),
+            UvUpdate::SessionLocked => println!("Unlock your session to continue."),
+            UvUpdate::Cancelled => println!("Operation cancelled."),
+            UvUpdate::AuthTokenMismatch(reason) => println!("Verifying you again: {reason}."),
+            UvUpdate::Processing => println!("Your device is busy, please wait."),
             UvUpdate::UvRetry { attempts_left } => {
                 print!("UV failed.");
                 if let Some(attempts_left) = attempts_left {
@@ -70,6 +79,7 @@ async fn handle_updates(mut state_recv: Receiver<UvUpdate>) {
                     let _ = update.send_pin(&pin_raw);
                 }
             }
+            _ => {}
         }
     }
 }
@@ -98,18 +108,23 @@ pub async fn main() -> Result<(), Box<dyn Error>> {
         tokio::spawn(handle_updates(state_recv));
 
         // Make Credentials ceremony
-        let make_credentials_request = MakeCredentialRequest {
-            origin: "example.org".to_owned(),
-            hash: Vec::from(challenge),
-            relying_party: Ctap2PublicKeyCredentialRpEntity::new("example.org", "example.org"),
-            user: Ctap2PublicKeyCredentialUserEntity::new(&user_id, "mario.rossi", "Mario Rossi"),
-            resident_key: Some(ResidentKeyRequirement::Required),
-            user_verification: UserVerificationRequirement::Preferred,
-            algorithms: vec![Ctap2CredentialType::default()],
-            exclude: None,
-            extensions: Some(extensions.clone()),
-            timeout: TIMEOUT,
-        };
+        let make_credentials_request = MakeCredentialRequest::builder()
+            .origin("example.org")
+            .client_data_hash(&challenge)
+            .rp(Ctap2PublicKeyCredentialRpEntity::new(
+                "example.org",
+                "example.org",
+            ))
+            .user(Ctap2PublicKeyCredentialUserEntity::new(
+                &user_id,
+                "mario.rossi",
+                "Mario Rossi",
+            ))
+            .resident_key(ResidentKeyRequirement::Required)
+            .user_verification(UserVerificationRequirement::Preferred)
+            .extensions(extensions.clone())
+            .timeout(TIMEOUT)
+            .build()?;
 
         let response = loop {
             match channel
@@ -421,17 +436,18 @@ async fn run_success_test(
     hmac_or_prf: GetAssertionHmacOrPrfInput,
     printoutput: &str,
 ) {
-    let get_assertion = GetAssertionRequest {
-        relying_party_id: "example.org".to_owned(),
-        hash: Vec::from(challenge),
-        allow: vec![credential.clone()],
-        user_verification: UserVerificationRequirement::Discouraged,
-        extensions: Some(GetAssertionRequestExtensions {
+    let get_assertion = GetAssertionRequest::builder()
+        .rp_id("example.org")
+        .client_data_hash(challenge)
+        .allow_credential(credential.clone())
+        .user_verification(UserVerificationRequirement::Discouraged)
+        .extensions(GetAssertionRequestExtensions {
             hmac_or_prf,
             ..Default::default()
-        }),
-        timeout: TIMEOUT,
-    };
+        })
+        .timeout(TIMEOUT)
+        .build()
+        .unwrap();
 
     let response = loop {
         match channel.webauthn_get_assertion(&get_assertion).await {
@@ -463,17 +479,19 @@ async fn run_failed_test(
     printoutput: &str,
     expected_error: WebAuthnError,
 ) {
-    let get_assertion = GetAssertionRequest {
-        relying_party_id: "example.org".to_owned(),
-        hash: Vec::from(challenge),
-        allow: credential.map(|x| vec![x.clone()]).unwrap_or_default(),
-        user_verification: UserVerificationRequirement::Discouraged,
-        extensions: Some(GetAssertionRequestExtensions {
+    let mut builder = GetAssertionRequest::builder()
+        .rp_id("example.org")
+        .client_data_hash(challenge)
+        .user_verification(UserVerificationRequirement::Discouraged)
+        .extensions(GetAssertionRequestExtensions {
             hmac_or_prf,
             ..Default::default()
-        }),
-        timeout: TIMEOUT,
-    };
+        })
+        .timeout(TIMEOUT);
+    if let Some(credential) = credential {
+        builder = builder.allow_credential(credential.clone());
+    }
+    let get_assertion = builder.build().unwrap();
 
     let response: Result<(), libwebauthn::webauthn::Error> = loop {
         match channel.webauthn_get_assertion(&get_assertion).await {
@@ -0,0 +1,9 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use libwebauthn::parse;
+
+fuzz_target!(|frame: &[u8]| {
+    let _ = parse::parse_cable_initial_message(frame);
+    let _ = parse::parse_cable_frame(frame);
+});
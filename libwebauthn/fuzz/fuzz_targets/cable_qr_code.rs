@@ -0,0 +1,8 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use libwebauthn::parse;
+
+fuzz_target!(|uri: &str| {
+    let _ = parse::parse_cable_qr_code(uri);
+});
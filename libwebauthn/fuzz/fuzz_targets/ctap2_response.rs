@@ -0,0 +1,15 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use libwebauthn::parse;
+
+// A response frame, parsed as each of the responses the platform decodes.
+fuzz_target!(|frame: &[u8]| {
+    let Ok(response) = parse::parse_ctap2_response(frame) else {
+        return;
+    };
+    let data = response.data.unwrap_or_default();
+    let _ = parse::parse_get_info(&data);
+    let _ = parse::parse_make_credential_response(&data);
+    let _ = parse::parse_get_assertion_response(&data);
+});
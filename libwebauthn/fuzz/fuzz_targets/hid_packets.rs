@@ -0,0 +1,9 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use libwebauthn::parse;
+
+// Split into full-speed HID reports, the last one possibly short.
+fuzz_target!(|data: &[u8]| {
+    let _ = parse::parse_hid_message(data.chunks(64));
+});
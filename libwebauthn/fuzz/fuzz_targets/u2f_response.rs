@@ -0,0 +1,9 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use libwebauthn::parse;
+
+fuzz_target!(|apdu: &[u8]| {
+    let _ = parse::parse_u2f_register_response(apdu);
+    let _ = parse::parse_u2f_sign_response(apdu);
+});
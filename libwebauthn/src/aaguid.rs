@@ -0,0 +1,54 @@
+//! Human-readable product names for authenticator AAGUIDs.
+//!
+//! Names are taken from the community-maintained registry at
+//! <https://github.com/passkeydeveloper/passkey-authenticator-aaguids>, bundled in
+//! `data/aaguid.json` using the same format (icons are not bundled). The bundled table is a
+//! subset of the registry, covering common platform authenticators, password managers and
+//! security keys, copied on 2026-10-16; update it by copying entries from the registry's
+//! `aaguid.json`. For authenticators missing here, the `metadata` feature's
+//! `MetadataService::product_name` looks up the description from the FIDO Metadata Service.
+
+use std::collections::HashMap;
+use std::sync::OnceLock;
+
+use serde::Deserialize;
+use uuid::Uuid;
+
+const REGISTRY: &str = include_str!("../data/aaguid.json");
+
+#[derive(Debug, Deserialize)]
+struct RegistryEntry {
+    name: String,
+}
+
+fn registry() -> &'static HashMap<Uuid, RegistryEntry> {
+    static PARSED: OnceLock<HashMap<Uuid, RegistryEntry>> = OnceLock::new();
+    PARSED.get_or_init(|| serde_json::from_str(REGISTRY).expect("bundled AAGUID registry is valid"))
+}
+
+/// Looks up the product name for an AAGUID, e.g. "YubiKey 5 Series with NFC".
+pub fn product_name(aaguid: &Uuid) -> Option<&'static str> {
+    registry().get(aaguid).map(|entry| entry.name.as_str())
+}
+
+/// Same as [product_name], for a raw 16-byte AAGUID as found in getInfo or authenticator data.
+pub fn product_name_from_bytes(aaguid: &[u8]) -> Option<&'static str> {
+    product_name(&Uuid::from_slice(aaguid).ok()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn look_up_known_aaguids() {
+        let aaguid = Uuid::parse_str("2fc0579f-8113-47ea-b116-bb5a8db9202a").unwrap();
+        assert_eq!(product_name(&aaguid), Some("YubiKey 5 Series with NFC"));
+        assert_eq!(
+            product_name_from_bytes(aaguid.as_bytes()),
+            Some("YubiKey 5 Series with NFC")
+        );
+        assert_eq!(product_name(&Uuid::nil()), None);
+        assert_eq!(product_name_from_bytes(&[0; 4]), None);
+    }
+}
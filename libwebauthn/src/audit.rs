@@ -0,0 +1,266 @@
+//! Structured audit trail of ceremonies.
+//!
+//! Environments that must log authentication events install an [AuditTrail] on a channel with
+//! `Channel::set_audit_trail`. Each high-level ceremony on that channel then produces one
+//! [AuditRecord], successful or not, e.g. to be written to a SIEM as JSON lines. [AuditOptions]
+//! controls what identifying data the records contain. Nothing is recorded without a trail.
+
+use std::fmt;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Serialize, Serializer};
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+use crate::correlation::CorrelationId;
+use crate::fido::{AuthenticatorData, AuthenticatorDataFlags};
+use crate::metrics::Operation;
+use crate::ops::webauthn::{GetAssertionRequest, GetAssertionResponse};
+use crate::ops::webauthn::{MakeCredentialRequest, MakeCredentialResponse};
+use crate::proto::ctap2::Ctap2UserVerificationOperation;
+use crate::sources;
+use crate::transport::{AuthTokenData, Channel, DeviceAaguid};
+use crate::webauthn::error::Error;
+use crate::webauthn::pin_uv_auth_token::UsedPinUvAuthToken;
+
+pub trait AuditSink: Send + Sync {
+    /// Called once a ceremony completed. Implementations should hand the record off quickly.
+    fn record(&self, record: &AuditRecord);
+}
+
+impl fmt::Debug for dyn AuditSink {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("AuditSink")
+    }
+}
+
+/// Where a channel's audit records go, and what they contain.
+#[derive(Debug, Clone)]
+pub struct AuditTrail {
+    pub sink: Arc<dyn AuditSink>,
+    pub options: AuditOptions,
+}
+
+impl AuditTrail {
+    pub fn new(sink: Arc<dyn AuditSink>, options: AuditOptions) -> Self {
+        Self { sink, options }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct AuditOptions {
+    pub rp_id: RpIdPolicy,
+    /// Whether to include the AAGUID, which identifies the authenticator model.
+    pub include_aaguid: bool,
+}
+
+impl Default for AuditOptions {
+    fn default() -> Self {
+        Self {
+            rp_id: RpIdPolicy::Plain,
+            include_aaguid: true,
+        }
+    }
+}
+
+/// How the relying party ID appears in records.
+#[derive(Debug, Clone)]
+pub enum RpIdPolicy {
+    Plain,
+    /// Hex-encoded SHA-256 of `salt` followed by the RP ID. Records of the same RP can be
+    /// correlated, but a secret salt keeps the RP from being looked up in a dictionary.
+    Hashed {
+        salt: Vec<u8>,
+    },
+    Omitted,
+}
+
+impl RpIdPolicy {
+    fn apply(&self, rp_id: &str) -> Option<String> {
+        match self {
+            Self::Plain => Some(rp_id.to_string()),
+            Self::Hashed { salt } => {
+                let mut hasher = Sha256::new();
+                hasher.update(salt);
+                hasher.update(rp_id.as_bytes());
+                Some(hex::encode(hasher.finalize()))
+            }
+            Self::Omitted => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditUvMethod {
+    /// The user wasn't verified, only their presence may have been checked.
+    #[default]
+    None,
+    Pin,
+    /// The authenticator's built-in UV, e.g. a fingerprint.
+    BuiltInUv,
+}
+
+impl AuditUvMethod {
+    /// How the user was verified in a ceremony, which used `auth_token` if `uv_auth_used` says
+    /// a token was sent with the request.
+    pub(crate) fn from_ceremony<T>(
+        authenticator_data: &AuthenticatorData<T>,
+        uv_auth_used: UsedPinUvAuthToken,
+        auth_token: Option<&AuthTokenData>,
+    ) -> Self {
+        if !authenticator_data
+            .flags
+            .contains(AuthenticatorDataFlags::USER_VERIFIED)
+        {
+            return Self::None;
+        }
+        let token_operation = match uv_auth_used {
+            UsedPinUvAuthToken::FromStorage | UsedPinUvAuthToken::NewlyCalculated => {
+                auth_token.map(|token| token.uv_operation)
+            }
+            UsedPinUvAuthToken::LegacyUV | UsedPinUvAuthToken::None => None,
+        };
+        match token_operation {
+            Some(Ctap2UserVerificationOperation::GetPinToken)
+            | Some(Ctap2UserVerificationOperation::GetPinUvAuthTokenUsingPinWithPermissions) => {
+                Self::Pin
+            }
+            // Either a token obtained through UV, or the authenticator verified on its own.
+            _ => Self::BuiltInUv,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct AuditRecord {
+    /// When the ceremony completed, serialized as milliseconds since the Unix epoch.
+    #[serde(serialize_with = "serialize_epoch_millis")]
+    pub timestamp: SystemTime,
+    pub correlation_id: Uuid,
+    pub operation: Operation,
+    /// The channel's transport, see `Channel::transport_name`.
+    pub transport: &'static str,
+    /// The relying party ID, as allowed by [RpIdPolicy].
+    pub rp_id: Option<String>,
+    pub aaguid: Option<Uuid>,
+    /// How the user was verified, `None` if the ceremony failed.
+    pub uv_method: Option<AuditUvMethod>,
+    /// The error code the ceremony failed with, e.g. `CTAP2_ERR_PIN_INVALID`, see `Error::code`.
+    pub error: Option<&'static str>,
+}
+
+fn serialize_epoch_millis<S: Serializer>(
+    timestamp: &SystemTime,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    let millis = timestamp
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since_epoch| since_epoch.as_millis());
+    serializer.serialize_u64(millis as u64)
+}
+
+pub(crate) fn record_make_credential<C: Channel>(
+    channel: &C,
+    correlation_id: CorrelationId,
+    op: &MakeCredentialRequest,
+    result: &Result<MakeCredentialResponse, Error>,
+) {
+    let Some(AuditTrail { sink, options }) = channel.get_audit_trail() else {
+        return;
+    };
+    let aaguid = result
+        .as_ref()
+        .ok()
+        .and_then(|r| r.authenticator_data.attested_credential.as_ref())
+        .map(|credential| Uuid::from_bytes(credential.aaguid));
+    let uv_method = result.as_ref().ok().map(|r| r.uv_method);
+    sink.record(&AuditRecord {
+        timestamp: sources::now(),
+        correlation_id: *correlation_id.as_uuid(),
+        operation: Operation::MakeCredential,
+        transport: channel.transport_name(),
+        rp_id: options.rp_id.apply(&op.relying_party.id),
+        aaguid: aaguid.filter(|_| options.include_aaguid),
+        uv_method,
+        error: result.as_ref().err().map(Error::code),
+    });
+}
+
+pub(crate) fn record_get_assertion<C: Channel>(
+    channel: &C,
+    correlation_id: CorrelationId,
+    op: &GetAssertionRequest,
+    result: &Result<GetAssertionResponse, Error>,
+) {
+    let Some(AuditTrail { sink, options }) = channel.get_audit_trail() else {
+        return;
+    };
+    // Assertions don't carry the AAGUID, so it's taken from the getInfo response of the
+    // ceremony. U2F devices have none.
+    let aaguid = channel
+        .device_aaguid()
+        .and_then(DeviceAaguid::get)
+        .filter(|_| options.include_aaguid);
+    let uv_method = result.as_ref().ok().map(|r| r.uv_method);
+    sink.record(&AuditRecord {
+        timestamp: sources::now(),
+        correlation_id: *correlation_id.as_uuid(),
+        operation: Operation::GetAssertion,
+        transport: channel.transport_name(),
+        rp_id: options.rp_id.apply(&op.relying_party_id),
+        aaguid,
+        uv_method,
+        error: result.as_ref().err().map(Error::code),
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use uuid::Uuid;
+
+    use super::{AuditRecord, AuditUvMethod, RpIdPolicy};
+    use crate::metrics::Operation;
+
+    #[test]
+    fn record_is_serialized() {
+        let record = AuditRecord {
+            timestamp: UNIX_EPOCH + Duration::from_millis(1_700_000_000_123),
+            correlation_id: Uuid::nil(),
+            operation: Operation::GetAssertion,
+            transport: "hid",
+            rp_id: Some("example.org".to_owned()),
+            aaguid: None,
+            uv_method: Some(AuditUvMethod::BuiltInUv),
+            error: None,
+        };
+        let json = serde_json::to_value(&record).unwrap();
+        assert_eq!(json["timestamp"], 1_700_000_000_123u64);
+        assert_eq!(json["operation"], "get_assertion");
+        assert_eq!(json["uv_method"], "built_in_uv");
+    }
+
+    #[test]
+    fn rp_id_policy() {
+        assert_eq!(
+            RpIdPolicy::Plain.apply("example.org").as_deref(),
+            Some("example.org")
+        );
+        assert_eq!(RpIdPolicy::Omitted.apply("example.org"), None);
+
+        let salted = RpIdPolicy::Hashed { salt: vec![1, 2] };
+        let hashed = salted.apply("example.org").unwrap();
+        assert_eq!(hashed.len(), 64);
+        assert_eq!(salted.apply("example.org").unwrap(), hashed);
+        assert_ne!(salted.apply("example.com").unwrap(), hashed);
+        assert_ne!(
+            RpIdPolicy::Hashed { salt: vec![3] }
+                .apply("example.org")
+                .unwrap(),
+            hashed
+        );
+    }
+}
@@ -0,0 +1,118 @@
+//! A blocking facade over the async API, for CLI tools and plugins that can't adopt async.
+//!
+//! As with `reqwest::blocking`, every call runs to completion on an internal multi-threaded
+//! runtime, started on first use and kept for the lifetime of the process: channels spawn
+//! background tasks, e.g. for UX updates, which must outlive a single call.
+//!
+//! These functions block the calling thread, and panic if called from within an async
+//! runtime. UX updates can be read from another thread, with `blocking_recv()` on the
+//! channel's [`Channel::get_ux_update_receiver`].
+//!
+//! Operations without a dedicated wrapper, e.g. credential management, can be driven
+//! through [`block_on`].
+
+use std::future::Future;
+use std::sync::OnceLock;
+
+use tokio::runtime::{Builder, Runtime};
+use zeroize::Zeroizing;
+
+use crate::ops::webauthn::{
+    GetAssertionRequest, GetAssertionResponse, MakeCredentialRequest, MakeCredentialResponse,
+};
+use crate::pin::PinRequestReason;
+use crate::transport::hid::{self, HidDevice};
+use crate::transport::{Channel, Device, Transport};
+use crate::webauthn::{Error, WebAuthn};
+
+fn runtime() -> &'static Runtime {
+    static RUNTIME: OnceLock<Runtime> = OnceLock::new();
+    RUNTIME.get_or_init(|| {
+        Builder::new_multi_thread()
+            .thread_name("libwebauthn-blocking")
+            .enable_all()
+            .build()
+            .expect("Failed to start the runtime of the blocking API")
+    })
+}
+
+/// Runs `future` to completion on the internal runtime, blocking the calling thread.
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    runtime().block_on(future)
+}
+
+/// Blocking version of [`hid::list_devices`].
+pub fn list_devices_blocking() -> Result<Vec<HidDevice>, Error> {
+    block_on(hid::list_devices())
+}
+
+/// Blocking version of [`Device::channel`].
+pub fn channel_blocking<'d, T, C, D>(device: &'d mut D) -> Result<C, Error>
+where
+    T: Transport,
+    C: Channel + 'd,
+    D: Device<'d, T, C>,
+{
+    block_on(device.channel())
+}
+
+/// Blocking version of [`WebAuthn::webauthn_make_credential`].
+pub fn make_credential_blocking<C: WebAuthn + Send>(
+    channel: &mut C,
+    request: &MakeCredentialRequest,
+) -> Result<MakeCredentialResponse, Error> {
+    block_on(channel.webauthn_make_credential(request))
+}
+
+/// Blocking version of [`WebAuthn::webauthn_get_assertion`].
+pub fn get_assertion_blocking<C: WebAuthn + Send>(
+    channel: &mut C,
+    request: &GetAssertionRequest,
+) -> Result<GetAssertionResponse, Error> {
+    block_on(channel.webauthn_get_assertion(request))
+}
+
+/// Blocking version of [`crate::simple::register`].
+pub fn register_blocking<F>(
+    origin: &str,
+    options_json: &str,
+    pin_callback: F,
+) -> Result<String, Error>
+where
+    F: Fn(PinRequestReason, Option<u32>) -> Option<Zeroizing<String>> + Send + Sync + 'static,
+{
+    block_on(crate::simple::register(origin, options_json, pin_callback))
+}
+
+/// Blocking version of [`crate::simple::authenticate`].
+pub fn authenticate_blocking<F>(
+    origin: &str,
+    options_json: &str,
+    pin_callback: F,
+) -> Result<String, Error>
+where
+    F: Fn(PinRequestReason, Option<u32>) -> Option<Zeroizing<String>> + Send + Sync + 'static,
+{
+    block_on(crate::simple::authenticate(
+        origin,
+        options_json,
+        pin_callback,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::oneshot;
+
+    use super::block_on;
+
+    #[test]
+    fn runs_futures_from_sync_code() {
+        let (sender, receiver) = oneshot::channel();
+        block_on(async {
+            tokio::spawn(async move { sender.send(21 * 2) });
+        });
+        // Spawned tasks keep running between calls.
+        assert_eq!(block_on(receiver).unwrap(), 42);
+    }
+}
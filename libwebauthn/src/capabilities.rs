@@ -0,0 +1,114 @@
+//! Client capabilities, to answer `PublicKeyCredential.getClientCapabilities()`.
+//!
+//! [client_capabilities] reports what this library supports, given the enabled features and
+//! the hardware found. Embedders adjust the result for what they add on top, e.g. conditional
+//! mediation, which needs their UI.
+//!
+//! See <https://w3c.github.io/webauthn/#sctn-getClientCapabilities>
+
+use std::collections::BTreeMap;
+
+use tracing::debug;
+
+use crate::transport::ble::btleplug;
+
+/// Extensions processed by the library, by their WebAuthn identifiers.
+pub const SUPPORTED_EXTENSIONS: &[&str] = &[
+    "appid",
+    "credBlob",
+    "credProps",
+    "credProtect",
+    "hmacCreateSecret",
+    "largeBlob",
+    "minPinLength",
+    "prf",
+];
+
+/// The capabilities of WebAuthn L3, see the [module documentation](self).
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ClientCapabilities {
+    pub conditional_create: bool,
+    pub conditional_get: bool,
+    /// Phones can be used through caBLE, as there is a Bluetooth adapter.
+    pub hybrid_transport: bool,
+    pub passkey_platform_authenticator: bool,
+    pub user_verifying_platform_authenticator: bool,
+    pub related_origins: bool,
+    pub signal_all_accepted_credentials: bool,
+    pub signal_current_user_details: bool,
+    pub signal_unknown_credential: bool,
+    /// WebAuthn identifiers of the supported extensions, e.g. "prf".
+    pub extensions: Vec<&'static str>,
+}
+
+impl ClientCapabilities {
+    /// The capabilities as returned by `getClientCapabilities()`, with extensions prefixed by
+    /// "extension:".
+    pub fn to_map(&self) -> BTreeMap<String, bool> {
+        let mut map: BTreeMap<String, bool> = [
+            ("conditionalCreate", self.conditional_create),
+            ("conditionalGet", self.conditional_get),
+            ("hybridTransport", self.hybrid_transport),
+            (
+                "passkeyPlatformAuthenticator",
+                self.passkey_platform_authenticator,
+            ),
+            (
+                "userVerifyingPlatformAuthenticator",
+                self.user_verifying_platform_authenticator,
+            ),
+            ("relatedOrigins", self.related_origins),
+            (
+                "signalAllAcceptedCredentials",
+                self.signal_all_accepted_credentials,
+            ),
+            ("signalCurrentUserDetails", self.signal_current_user_details),
+            ("signalUnknownCredential", self.signal_unknown_credential),
+        ]
+        .into_iter()
+        .map(|(name, supported)| (name.to_owned(), supported))
+        .collect();
+        for extension in &self.extensions {
+            map.insert(format!("extension:{extension}"), true);
+        }
+        map
+    }
+}
+
+/// Detects the client capabilities, by looking for a Bluetooth adapter.
+///
+/// There is no platform authenticator performing user verification, so
+/// userVerifyingPlatformAuthenticator is always false.
+pub async fn client_capabilities() -> ClientCapabilities {
+    let hybrid_transport = btleplug::adapter_available().await;
+    let capabilities = ClientCapabilities {
+        hybrid_transport,
+        // Discoverable credentials can only be stored on a phone.
+        passkey_platform_authenticator: hybrid_transport,
+        user_verifying_platform_authenticator: false,
+        extensions: SUPPORTED_EXTENSIONS.to_vec(),
+        ..Default::default()
+    };
+    debug!(?capabilities, "Detected client capabilities");
+    capabilities
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ClientCapabilities, SUPPORTED_EXTENSIONS};
+
+    #[test]
+    fn map_uses_webauthn_names() {
+        let capabilities = ClientCapabilities {
+            hybrid_transport: true,
+            extensions: SUPPORTED_EXTENSIONS.to_vec(),
+            ..Default::default()
+        };
+        let map = capabilities.to_map();
+        assert_eq!(map.get("hybridTransport"), Some(&true));
+        assert_eq!(map.get("conditionalGet"), Some(&false));
+        assert_eq!(map.get("extension:prf"), Some(&true));
+        assert_eq!(map.get("extension:appidExclude"), None);
+        assert_eq!(map.len(), 9 + SUPPORTED_EXTENSIONS.len());
+    }
+}
@@ -0,0 +1,58 @@
+//! CTAP2 canonical CBOR, as the library encodes it on the wire.
+//!
+//! Authenticators may reject requests whose maps aren't in canonical order, and signatures over
+//! CBOR only verify on the exact encoding. Tools building CTAP messages, e.g. attestation
+//! statements or test vectors, can use these instead of encoding with a CBOR crate directly.
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::proto::ctap2::cbor;
+pub use crate::proto::ctap2::cbor::CborError;
+
+/// Encodes `value` with map keys in CTAP2 canonical order: by major type, then by length, then
+/// bytewise. Structs are encoded as maps keyed by their field names, or by their indices.
+pub fn to_vec_canonical<T: Serialize>(value: &T) -> Result<Vec<u8>, CborError> {
+    cbor::to_vec_canonical(value)
+}
+
+/// Decodes a single value, failing on trailing data.
+pub fn from_slice<T: DeserializeOwned>(slice: &[u8]) -> Result<T, CborError> {
+    cbor::from_slice(slice)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use serde::Serialize;
+
+    use super::{from_slice, to_vec_canonical};
+
+    #[derive(Serialize)]
+    struct Extensions {
+        #[serde(rename = "hmac-secret")]
+        hmac_secret: bool,
+        #[serde(rename = "credProtect")]
+        cred_protect: u8,
+    }
+
+    #[test]
+    fn canonical_order() {
+        let keys: BTreeMap<i32, u8> = [(-1, 0), (1, 0), (24, 0)].into();
+        assert_eq!(
+            to_vec_canonical(&keys).unwrap(),
+            [0xa3, 0x01, 0x00, 0x18, 0x18, 0x00, 0x20, 0x00]
+        );
+
+        let extensions = Extensions {
+            hmac_secret: true,
+            cred_protect: 2,
+        };
+        let encoded = to_vec_canonical(&extensions).unwrap();
+        let decoded: BTreeMap<String, serde_cbor_2::Value> = from_slice(&encoded).unwrap();
+        assert_eq!(decoded.len(), 2);
+        // Keys of the same length sort bytewise, regardless of the field order.
+        assert_eq!(&encoded[1..13], b"\x6bcredProtect");
+    }
+}
@@ -0,0 +1,340 @@
+//! Registration and assertion ceremonies as explicit state machines.
+//!
+//! [`WebAuthn`] runs a ceremony as a single async call, reporting progress through the
+//! channel's UX updates. A [`Ceremony`] instead runs in the background, and reports each
+//! step as a [`CeremonyState`]: states waiting for the user are resumed by injecting their
+//! answer, e.g. with [`Ceremony::send_pin`]. This suits a credentials portal mediating
+//! between a UI process and the device, where the UI only sees states and sends answers.
+
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinHandle;
+use tracing::{debug, instrument, warn};
+use zeroize::Zeroizing;
+
+use crate::ops::webauthn::{
+    GetAssertionRequest, GetAssertionResponse, MakeCredentialRequest, MakeCredentialResponse,
+};
+use crate::pin::{PinProvider, PinRequestContext, PinRequestReason};
+use crate::proto::ctap2::Ctap2PublicKeyCredentialUserEntity;
+use crate::transport::any::{AnyDevice, AnyUxUpdate};
+use crate::transport::error::TransportError;
+use crate::transport::{CancellationToken, Channel, Device};
+use crate::webauthn::{Error, PlatformError, WebAuthn};
+use crate::UvUpdate;
+
+#[derive(Debug, Clone)]
+pub enum CeremonyRequest {
+    MakeCredential(MakeCredentialRequest),
+    GetAssertion(GetAssertionRequest),
+}
+
+#[derive(Debug, Clone)]
+pub enum CeremonyResponse {
+    MakeCredential(Box<MakeCredentialResponse>),
+    /// Holds the single assertion of the account the user selected.
+    GetAssertion(GetAssertionResponse),
+}
+
+#[derive(Debug)]
+pub enum CeremonyState {
+    /// Several devices are available. Resume with [`Ceremony::select_device`].
+    SelectDevice { devices: Vec<String> },
+    /// The device waits for the user's touch, or built-in user verification.
+    WaitingForTouch,
+    /// The device needs its PIN. Resume with [`Ceremony::send_pin`].
+    NeedsPin {
+        reason: PinRequestReason,
+        attempts_left: Option<u32>,
+    },
+    /// Several discoverable credentials match. Resume with [`Ceremony::select_account`].
+    NeedsAccountSelection {
+        accounts: Vec<Option<Ctap2PublicKeyCredentialUserEntity>>,
+    },
+    /// The ceremony is over. No other state follows.
+    Done(Result<CeremonyResponse, Error>),
+}
+
+/// The answer a ceremony currently waits for.
+#[derive(Default)]
+enum Pending {
+    #[default]
+    None,
+    Device(usize, oneshot::Sender<usize>),
+    Pin(oneshot::Sender<Option<Zeroizing<String>>>),
+    Account(usize, oneshot::Sender<usize>),
+}
+
+type SharedPending = Arc<Mutex<Pending>>;
+
+/// A ceremony running in the background, see the [module documentation](self).
+pub struct Ceremony {
+    states: mpsc::UnboundedReceiver<CeremonyState>,
+    pending: SharedPending,
+    token: CancellationToken,
+}
+
+impl fmt::Debug for Ceremony {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Ceremony")
+            .field("cancelled", &self.token.is_cancelled())
+            .finish()
+    }
+}
+
+impl Ceremony {
+    /// Starts `request` on one of `devices`, asking the user to select one if there are
+    /// several.
+    pub fn start(devices: Vec<AnyDevice>, request: CeremonyRequest) -> Self {
+        let (state_sender, states) = mpsc::unbounded_channel();
+        let pending = SharedPending::default();
+        let token = CancellationToken::new();
+        let runner = Runner {
+            states: state_sender,
+            pending: Arc::clone(&pending),
+            token: token.clone(),
+        };
+        tokio::spawn(async move {
+            let result = runner.run(devices, request).await;
+            runner.emit(CeremonyState::Done(result));
+        });
+        Self {
+            states,
+            pending,
+            token,
+        }
+    }
+
+    /// The next state of the ceremony, or `None` once [`CeremonyState::Done`] was returned.
+    pub async fn next_state(&mut self) -> Option<CeremonyState> {
+        self.states.recv().await
+    }
+
+    /// Resumes [`CeremonyState::SelectDevice`] with the device at `index`.
+    pub fn select_device(&self, index: usize) -> Result<(), Error> {
+        match self.take_pending() {
+            Pending::Device(count, sender) if index < count => sender
+                .send(index)
+                .or(Err(Error::Platform(PlatformError::Cancelled))),
+            pending => self.restore_pending(pending, "select_device"),
+        }
+    }
+
+    /// Resumes [`CeremonyState::NeedsPin`] with the user's PIN.
+    pub fn send_pin(&self, pin: &str) -> Result<(), Error> {
+        match self.take_pending() {
+            Pending::Pin(sender) => sender
+                .send(Some(Zeroizing::new(pin.to_owned())))
+                .or(Err(Error::Platform(PlatformError::Cancelled))),
+            pending => self.restore_pending(pending, "send_pin"),
+        }
+    }
+
+    /// Resumes [`CeremonyState::NeedsAccountSelection`] with the account at `index`.
+    pub fn select_account(&self, index: usize) -> Result<(), Error> {
+        match self.take_pending() {
+            Pending::Account(count, sender) if index < count => sender
+                .send(index)
+                .or(Err(Error::Platform(PlatformError::Cancelled))),
+            pending => self.restore_pending(pending, "select_account"),
+        }
+    }
+
+    /// Aborts the ceremony, which then ends with `PlatformError::Cancelled`.
+    pub fn cancel(&self) {
+        self.token.cancel();
+    }
+
+    fn take_pending(&self) -> Pending {
+        std::mem::take(&mut *self.pending.lock().unwrap())
+    }
+
+    fn restore_pending(&self, pending: Pending, answer: &str) -> Result<(), Error> {
+        *self.pending.lock().unwrap() = pending;
+        warn!(answer, "Ceremony isn't waiting for this answer");
+        Err(Error::Platform(PlatformError::SyntaxError))
+    }
+}
+
+impl Drop for Ceremony {
+    fn drop(&mut self) {
+        // The background task winds down, cancelling the device's pending request.
+        self.token.cancel();
+    }
+}
+
+#[derive(Clone)]
+struct Runner {
+    states: mpsc::UnboundedSender<CeremonyState>,
+    pending: SharedPending,
+    token: CancellationToken,
+}
+
+impl Runner {
+    fn emit(&self, state: CeremonyState) {
+        debug!(?state, "Ceremony state");
+        if self.states.send(state).is_err() {
+            // The ceremony was dropped, so nobody is interested anymore.
+            debug!("Ceremony dropped, discarding state");
+        }
+    }
+
+    /// Emits `state`, and waits for its answer.
+    async fn wait_for<T>(
+        &self,
+        state: CeremonyState,
+        pending: impl FnOnce(oneshot::Sender<T>) -> Pending,
+    ) -> Result<T, Error> {
+        let (sender, receiver) = oneshot::channel();
+        *self.pending.lock().unwrap() = pending(sender);
+        self.emit(state);
+        let answer = tokio::select! {
+            answer = receiver => answer.ok(),
+            _ = self.token.cancelled() => None,
+        };
+        *self.pending.lock().unwrap() = Pending::None;
+        answer.ok_or(Error::Platform(PlatformError::Cancelled))
+    }
+
+    #[instrument(skip_all, fields(devices = devices.len()))]
+    async fn run(
+        &self,
+        mut devices: Vec<AnyDevice>,
+        request: CeremonyRequest,
+    ) -> Result<CeremonyResponse, Error> {
+        let index = match devices.len() {
+            0 => {
+                warn!("No devices to run the ceremony on");
+                return Err(Error::Transport(TransportError::TransportUnavailable));
+            }
+            1 => 0,
+            count => {
+                let state = CeremonyState::SelectDevice {
+                    devices: devices.iter().map(|device| device.to_string()).collect(),
+                };
+                self.wait_for(state, |sender| Pending::Device(count, sender))
+                    .await?
+            }
+        };
+
+        let mut channel = devices[index].channel().await?;
+        channel.set_cancellation_token(Some(self.token.clone()));
+        channel.set_pin_provider(Some(Arc::new(self.clone())));
+        let forwarder = self.forward_ux_updates(channel.get_ux_update_receiver());
+        let response = match request {
+            CeremonyRequest::MakeCredential(request) => channel
+                .webauthn_make_credential(&request)
+                .await
+                .map(|response| CeremonyResponse::MakeCredential(Box::new(response))),
+            CeremonyRequest::GetAssertion(request) => {
+                match channel.webauthn_get_assertion(&request).await {
+                    Ok(response) => self.select_assertion(response).await,
+                    Err(error) => Err(error),
+                }
+            }
+        };
+        forwarder.abort();
+        response
+    }
+
+    async fn select_assertion(
+        &self,
+        mut response: GetAssertionResponse,
+    ) -> Result<CeremonyResponse, Error> {
+        if response.assertions.len() > 1 {
+            let count = response.assertions.len();
+            let state = CeremonyState::NeedsAccountSelection {
+                accounts: response
+                    .assertions
+                    .iter()
+                    .map(|assertion| assertion.user.clone())
+                    .collect(),
+            };
+            let index = self
+                .wait_for(state, |sender| Pending::Account(count, sender))
+                .await?;
+            response.assertions = vec![response.assertions.swap_remove(index)];
+        }
+        Ok(CeremonyResponse::GetAssertion(response))
+    }
+
+    fn forward_ux_updates(
+        &self,
+        mut updates: tokio::sync::broadcast::Receiver<AnyUxUpdate>,
+    ) -> JoinHandle<()> {
+        let runner = self.clone();
+        tokio::spawn(async move {
+            while let Ok(update) = updates.recv().await {
+                if let AnyUxUpdate::UvUpdate(
+                    UvUpdate::PresenceRequired | UvUpdate::UvRetry { .. },
+                ) = update
+                {
+                    runner.emit(CeremonyState::WaitingForTouch);
+                }
+            }
+        })
+    }
+}
+
+#[async_trait]
+impl PinProvider for Runner {
+    async fn provide_pin(&self, context: &PinRequestContext) -> Option<Zeroizing<String>> {
+        let state = CeremonyState::NeedsPin {
+            reason: context.reason,
+            attempts_left: context.attempts_left,
+        };
+        self.wait_for(state, Pending::Pin).await.ok().flatten()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{Ceremony, CeremonyRequest, CeremonyState, Pending};
+    use crate::ops::webauthn::GetAssertionRequest;
+    use crate::transport::error::TransportError;
+    use crate::webauthn::{Error, PlatformError};
+
+    fn request() -> CeremonyRequest {
+        CeremonyRequest::GetAssertion(
+            GetAssertionRequest::builder()
+                .rp_id("example.org")
+                .client_data_hash(&[0; 32])
+                .timeout(Duration::from_secs(1))
+                .build()
+                .unwrap(),
+        )
+    }
+
+    #[tokio::test]
+    async fn ends_without_devices() {
+        let mut ceremony = Ceremony::start(vec![], request());
+        assert!(matches!(
+            ceremony.next_state().await,
+            Some(CeremonyState::Done(Err(Error::Transport(
+                TransportError::TransportUnavailable
+            ))))
+        ));
+        assert!(ceremony.next_state().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn rejects_unexpected_answers() {
+        let ceremony = Ceremony::start(vec![], request());
+        assert_eq!(
+            ceremony.send_pin("1234"),
+            Err(Error::Platform(PlatformError::SyntaxError))
+        );
+
+        let (sender, _receiver) = tokio::sync::oneshot::channel();
+        *ceremony.pending.lock().unwrap() = Pending::Account(2, sender);
+        assert!(ceremony.select_device(0).is_err());
+        assert!(ceremony.select_account(2).is_err());
+        assert_eq!(ceremony.select_account(1), Ok(()));
+        assert!(ceremony.select_account(1).is_err());
+    }
+}
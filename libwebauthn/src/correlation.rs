@@ -0,0 +1,83 @@
+//! Correlation IDs for WebAuthn and U2F ceremonies.
+//!
+//! The ceremonies of the [crate::webauthn::WebAuthn] and [crate::u2f::U2F] traits (e.g.
+//! `webauthn_make_credential`, `u2f_sign`) run inside a tracing span carrying a
+//! `correlation_id` field, so that interleaved logs from multiple devices and tasks can be
+//! told apart. The same ID is attached to PIN requests and to the error logged when the
+//! ceremony fails.
+//!
+//! Ceremonies generate a fresh ID, unless the caller runs them inside [CorrelationId::scope],
+//! which lets the caller know the ID up front and match it against its own records.
+//! Management operations, such as credential management or bio enrollment, don't generate
+//! IDs; run them inside [CorrelationId::scope] for their PIN requests to carry one.
+
+use std::fmt::{self, Display};
+use std::future::Future;
+
+use uuid::Uuid;
+
+tokio::task_local! {
+    static CURRENT: CorrelationId;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct CorrelationId(Uuid);
+
+impl CorrelationId {
+    pub fn new() -> Self {
+        Self(Uuid::new_v4())
+    }
+
+    /// The ID of the operation running on the current task, if any.
+    pub fn current() -> Option<Self> {
+        CURRENT.try_with(|id| *id).ok()
+    }
+
+    /// Runs `f` with this ID, operations started within it will use it rather than generating their own.
+    pub async fn scope<F: Future>(self, f: F) -> F::Output {
+        CURRENT.scope(self, f).await
+    }
+
+    fn current_or_new() -> Self {
+        Self::current().unwrap_or_default()
+    }
+
+    /// Picks the ID for a new operation and records it on the current span.
+    pub(crate) fn for_operation() -> Self {
+        let id = Self::current_or_new();
+        tracing::Span::current().record("correlation_id", tracing::field::display(id));
+        id
+    }
+
+    pub fn as_uuid(&self) -> &Uuid {
+        &self.0
+    }
+}
+
+impl Default for CorrelationId {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Display for CorrelationId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0.as_simple())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CorrelationId;
+
+    #[tokio::test]
+    async fn scope_sets_current_id() {
+        assert_eq!(CorrelationId::current(), None);
+        let id = CorrelationId::new();
+        let inner = id
+            .scope(async { (CorrelationId::current(), CorrelationId::for_operation()) })
+            .await;
+        assert_eq!(inner, (Some(id), id));
+        assert_ne!(CorrelationId::for_operation(), id);
+    }
+}
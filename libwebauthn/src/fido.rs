@@ -2,7 +2,8 @@ use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
 use cosey::PublicKey;
 use serde::{
     de::{DeserializeOwned, Error as DesError, Visitor},
-    Deserialize, Deserializer, Serialize,
+    ser::Error as SerError,
+    Deserialize, Deserializer, Serialize, Serializer,
 };
 use serde_bytes::ByteBuf;
 use std::{
@@ -21,7 +22,7 @@ use crate::{
     webauthn::{Error, PlatformError},
 };
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
 pub enum FidoProtocol {
     FIDO2,
     U2F,
@@ -50,8 +51,8 @@ bitflags! {
         const USER_PRESENT = 0x01;
         const RFU_1 = 0x02;
         const USER_VERIFIED = 0x04;
-        const RFU_2_1 = 0x08;
-        const RFU_2_2 = 0x10;
+        const BACKUP_ELIGIBLE = 0x08;
+        const BACKUP_STATE = 0x10;
         const RFU_2_3 = 0x20;
         const ATTESTED_CREDENTIALS = 0x40;
         const EXTENSION_DATA = 0x80;
@@ -75,6 +76,28 @@ impl From<&AttestedCredentialData> for Ctap2PublicKeyCredentialDescriptor {
     }
 }
 
+/// Whether a credential may be backed up, e.g. synced as a passkey, and whether it currently
+/// is, from the BE and BS flags of the authenticator data.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CredentialBackupState {
+    pub backup_eligible: bool,
+    pub backed_up: bool,
+}
+
+impl From<&AuthenticatorDataFlags> for CredentialBackupState {
+    fn from(flags: &AuthenticatorDataFlags) -> Self {
+        let state = Self {
+            backup_eligible: flags.contains(AuthenticatorDataFlags::BACKUP_ELIGIBLE),
+            backed_up: flags.contains(AuthenticatorDataFlags::BACKUP_STATE),
+        };
+        if state.backed_up && !state.backup_eligible {
+            warn!("Authenticator reports a backed up credential that isn't backup eligible");
+        }
+        state
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct AuthenticatorData<T> {
     pub rp_id_hash: [u8; 32],
@@ -84,6 +107,12 @@ pub struct AuthenticatorData<T> {
     pub extensions: Option<T>,
 }
 
+impl<T> AuthenticatorData<T> {
+    pub fn backup_state(&self) -> CredentialBackupState {
+        (&self.flags).into()
+    }
+}
+
 impl<T> AuthenticatorData<T>
 where
     T: Clone + Serialize,
@@ -121,7 +150,7 @@ where
             })?;
             res.extend(&att_data.credential_id);
             let cose_encoded_public_key =
-                cbor::to_vec(&att_data.credential_public_key)
+                cbor::to_vec_canonical(&att_data.credential_public_key)
             .map_err(|e| {
                 error!(
                     %e,
@@ -134,7 +163,7 @@ where
 
         if self.extensions.is_some() || self.flags.contains(AuthenticatorDataFlags::EXTENSION_DATA)
         {
-            res.extend(cbor::to_vec(&self.extensions).map_err(|e| {
+            res.extend(cbor::to_vec_canonical(&self.extensions).map_err(|e| {
                 error!(%e, "Failed to create AuthenticatorData output vec at extensions");
                 Error::Platform(PlatformError::InvalidDeviceResponse)
             })?);
@@ -156,6 +185,20 @@ impl<T> TryFrom<&AuthenticatorData<T>> for Ctap2PublicKeyCredentialDescriptor {
     }
 }
 
+/// Serializes to the raw authenticator data, as it was signed.
+impl<T> Serialize for AuthenticatorData<T>
+where
+    T: Clone + Serialize,
+{
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let bytes = self.to_response_bytes().map_err(SerError::custom)?;
+        serializer.serialize_bytes(&bytes)
+    }
+}
+
 impl<'de, T: DeserializeOwned> Deserialize<'de> for AuthenticatorData<T> {
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
     where
@@ -262,7 +305,29 @@ mod tests {
 
     use crate::proto::ctap2::cbor;
 
-    use super::{AttestedCredentialData, AuthenticatorData, AuthenticatorDataFlags};
+    use super::{
+        AttestedCredentialData, AuthenticatorData, AuthenticatorDataFlags, CredentialBackupState,
+    };
+
+    #[test]
+    fn backup_state_from_flags() {
+        let flags = AuthenticatorDataFlags::from_bits_truncate(0b0001_1101);
+        assert_eq!(
+            CredentialBackupState::from(&flags),
+            CredentialBackupState {
+                backup_eligible: true,
+                backed_up: true,
+            }
+        );
+        let flags = AuthenticatorDataFlags::USER_PRESENT | AuthenticatorDataFlags::BACKUP_ELIGIBLE;
+        assert_eq!(
+            CredentialBackupState::from(&flags),
+            CredentialBackupState {
+                backup_eligible: true,
+                backed_up: false,
+            }
+        );
+    }
 
     #[test]
     fn test_serialize_auth_data() {
@@ -273,11 +338,10 @@ mod tests {
             0x86, 0xce, 0x19, 0x47,
         ];
         let flag_bits = 0b1100_0101;
-        let flags = 
-            AuthenticatorDataFlags::USER_PRESENT |
-            AuthenticatorDataFlags::USER_VERIFIED |
-            AuthenticatorDataFlags::ATTESTED_CREDENTIALS |
-            AuthenticatorDataFlags::EXTENSION_DATA;
+        let flags = AuthenticatorDataFlags::USER_PRESENT
+            | AuthenticatorDataFlags::USER_VERIFIED
+            | AuthenticatorDataFlags::ATTESTED_CREDENTIALS
+            | AuthenticatorDataFlags::EXTENSION_DATA;
         assert_eq!(flag_bits, flags.bits());
         let signature_count = 0;
         let aaguid = [
@@ -316,7 +380,7 @@ mod tests {
             flags,
             signature_count,
             attested_credential: Some(attested_credential.clone()),
-            extensions: Some(extensions.clone())
+            extensions: Some(extensions.clone()),
         };
         let webauthn_auth_data = auth_data.to_response_bytes().unwrap();
         assert_eq!(rp_id_hash, &webauthn_auth_data[..32]);
@@ -341,14 +405,8 @@ mod tests {
         let authdata_wrapped = cbor::to_vec(&ByteBuf::from(webauthn_auth_data)).unwrap();
         let auth_data_reparsed: AuthenticatorData<T> =
             cbor::from_slice(authdata_wrapped.as_slice()).unwrap();
-        assert_eq!(
-            auth_data.rp_id_hash,
-            auth_data_reparsed.rp_id_hash
-        );
-        assert_eq!(
-            auth_data.flags.bits(),
-            auth_data_reparsed.flags.bits()
-        );
+        assert_eq!(auth_data.rp_id_hash, auth_data_reparsed.rp_id_hash);
+        assert_eq!(auth_data.flags.bits(), auth_data_reparsed.flags.bits());
         assert_eq!(
             auth_data.signature_count,
             auth_data_reparsed.signature_count
@@ -366,9 +424,6 @@ mod tests {
             attested_credential.credential_public_key,
             attested_credential_reparsed.credential_public_key
         );
-        assert_eq!(
-            extensions,
-            auth_data_reparsed.extensions.unwrap()
-        );
+        assert_eq!(extensions, auth_data_reparsed.extensions.unwrap());
     }
 }
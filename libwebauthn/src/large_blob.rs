@@ -0,0 +1,646 @@
+//! Per-credential large blobs, as stored in the entries of an authenticator's large-blob array.
+//!
+//! Each entry is encrypted with the largeBlobKey of its credential, returned by makeCredential
+//! and getAssertion with the largeBlob extension. As the specification requires, the blob is
+//! compressed with DEFLATE (RFC 1951) before encryption and `origSize` records its length, so
+//! entries written here can be read by browsers and other platforms, and the other way around.
+//!
+//! [LargeBlobs] reads and writes the array with authenticatorLargeBlobs, in fragments that fit
+//! the device's maxMsgSize, and checks the truncated SHA-256 hash trailing it. Entries it can't
+//! decode, e.g. those of other platforms, are written back as they were read.
+//!
+//! See <https://fidoalliance.org/specs/fido-v2.1-ps-20210615/fido-client-to-authenticator-protocol-v2.1-ps-20210615.html#large-blob>
+
+use std::time::Duration;
+
+use aes_gcm::aead::{Aead, Payload};
+use aes_gcm::{Aes256Gcm, KeyInit, Nonce};
+use async_trait::async_trait;
+use miniz_oxide::deflate::compress_to_vec;
+use miniz_oxide::inflate::decompress_to_vec_with_limit;
+use serde_bytes::ByteBuf;
+use serde_cbor_2 as serde_cbor;
+use serde_indexed::{DeserializeIndexed, SerializeIndexed};
+use sha2::{Digest, Sha256};
+use tracing::{debug, info, warn};
+
+use crate::ops::webauthn::UserVerificationRequirement;
+use crate::pin::PinUvAuthProtocol;
+use crate::proto::ctap2::cbor::{self, Value};
+use crate::proto::ctap2::{
+    Ctap2, Ctap2AuthTokenPermissionRole, Ctap2ClientPinRequest, Ctap2GetInfoResponse,
+    Ctap2LargeBlobsRequest, Ctap2UserVerifiableRequest,
+};
+use crate::sources;
+use crate::transport::Channel;
+use crate::webauthn::error::CtapError;
+use crate::webauthn::handle_errors;
+use crate::webauthn::pin_uv_auth_token::{user_verification, UsedPinUvAuthToken, UvOptions};
+use crate::webauthn::{Error, PlatformError};
+use crate::UvUpdate;
+
+/// A generous bound on the size of a decompressed blob. Devices store a few kilobytes at most,
+/// but DEFLATE expands by up to three orders of magnitude, so a hostile entry must not be
+/// inflated without one.
+pub const MAX_LARGE_BLOB_SIZE: usize = 64 * 1024;
+
+const COMPRESSION_LEVEL: u8 = 6;
+
+/// The serialized array is followed by the first 16 bytes of its SHA-256 hash.
+const TRAILER_SIZE: usize = 16;
+
+/// Defaults for devices which don't report maxMsgSize or maxSerializedLargeBlobArray, the
+/// smallest values the specification allows.
+const DEFAULT_MAX_MSG_SIZE: u32 = 1024;
+const DEFAULT_MAX_BLOB_ARRAY: u32 = 1024;
+
+/// An entry of the large-blob array.
+#[derive(Debug, Clone, PartialEq, Eq, SerializeIndexed, DeserializeIndexed)]
+pub struct LargeBlobEntry {
+    /// The AES-256-GCM encryption of the compressed blob, followed by the tag.
+    #[serde(index = 0x01)]
+    pub ciphertext: ByteBuf,
+
+    #[serde(index = 0x02)]
+    pub nonce: ByteBuf,
+
+    /// The length of the blob before compression.
+    #[serde(index = 0x03)]
+    pub orig_size: u64,
+}
+
+impl LargeBlobEntry {
+    /// Compresses and encrypts `blob` for the credential whose largeBlobKey is `key`.
+    pub fn seal(key: &[u8; 32], blob: &[u8]) -> Self {
+        let orig_size = blob.len() as u64;
+        let compressed = compress_to_vec(blob, COMPRESSION_LEVEL);
+        let nonce: [u8; 12] = sources::random();
+        let ciphertext = cipher(key)
+            .encrypt(
+                Nonce::from_slice(&nonce),
+                Payload {
+                    msg: &compressed,
+                    aad: &associated_data(orig_size),
+                },
+            )
+            .expect("AES-GCM encrypts messages of any size a device can store");
+        Self {
+            ciphertext: ByteBuf::from(ciphertext),
+            nonce: ByteBuf::from(nonce.to_vec()),
+            orig_size,
+        }
+    }
+
+    /// Decrypts and decompresses the blob, if the entry belongs to the credential whose
+    /// largeBlobKey is `key`. Entries of other credentials return `None`. Blobs larger than
+    /// `max_size` are rejected without being decompressed.
+    pub fn open(&self, key: &[u8; 32], max_size: usize) -> Result<Option<Vec<u8>>, Error> {
+        if self.nonce.len() != 12 {
+            warn!(len = self.nonce.len(), "Invalid large blob nonce");
+            return Err(Error::Platform(PlatformError::InvalidDeviceResponse));
+        }
+        let Some(compressed) = self.decrypt(key) else {
+            debug!("Large blob entry belongs to another credential");
+            return Ok(None);
+        };
+        if self.orig_size > max_size as u64 {
+            warn!(
+                self.orig_size,
+                max_size, "Large blob exceeds the size limit"
+            );
+            return Err(Error::Platform(PlatformError::LargeBlobTooLarge(
+                self.orig_size,
+            )));
+        }
+        let blob =
+            decompress_to_vec_with_limit(&compressed, self.orig_size as usize).map_err(|err| {
+                warn!(?err, "Failed to decompress large blob");
+                Error::Platform(PlatformError::InvalidDeviceResponse)
+            })?;
+        if blob.len() as u64 != self.orig_size {
+            warn!(
+                len = blob.len(),
+                self.orig_size, "Large blob length doesn't match origSize"
+            );
+            return Err(Error::Platform(PlatformError::InvalidDeviceResponse));
+        }
+        Ok(Some(blob))
+    }
+
+    /// The compressed blob, if the entry belongs to the credential whose largeBlobKey is `key`.
+    fn decrypt(&self, key: &[u8; 32]) -> Option<Vec<u8>> {
+        if self.nonce.len() != 12 {
+            return None;
+        }
+        cipher(key)
+            .decrypt(
+                Nonce::from_slice(&self.nonce),
+                Payload {
+                    msg: &self.ciphertext,
+                    aad: &associated_data(self.orig_size),
+                },
+            )
+            .ok()
+    }
+}
+
+/// Finds the blob of the credential whose largeBlobKey is `key` among `entries`. Malformed
+/// entries, which may well belong to other credentials, are skipped.
+pub fn find_large_blob(
+    entries: &[LargeBlobEntry],
+    key: &[u8; 32],
+    max_size: usize,
+) -> Result<Option<Vec<u8>>, Error> {
+    for entry in entries {
+        match entry.open(key, max_size) {
+            Ok(Some(blob)) => return Ok(Some(blob)),
+            Ok(None) => (),
+            // Only reported once the entry decrypted, so it is this credential's.
+            Err(error @ Error::Platform(PlatformError::LargeBlobTooLarge(_))) => return Err(error),
+            Err(error) => warn!(?error, "Skipping malformed large blob entry"),
+        }
+    }
+    Ok(None)
+}
+
+#[async_trait]
+pub trait LargeBlobs {
+    /// Reads the blob of the credential whose largeBlobKey is `key`, if it has one.
+    async fn get_large_blob(
+        &mut self,
+        key: &[u8; 32],
+        timeout: Duration,
+    ) -> Result<Option<Vec<u8>>, Error>;
+
+    /// Stores `blob` for the credential whose largeBlobKey is `key`, replacing its previous one.
+    async fn put_large_blob(
+        &mut self,
+        key: &[u8; 32],
+        blob: &[u8],
+        timeout: Duration,
+    ) -> Result<(), Error>;
+
+    /// Removes the blob of the credential whose largeBlobKey is `key`. Returns whether it had
+    /// one.
+    async fn delete_large_blob(&mut self, key: &[u8; 32], timeout: Duration)
+        -> Result<bool, Error>;
+}
+
+#[async_trait]
+impl<C> LargeBlobs for C
+where
+    C: Channel,
+{
+    async fn get_large_blob(
+        &mut self,
+        key: &[u8; 32],
+        timeout: Duration,
+    ) -> Result<Option<Vec<u8>>, Error> {
+        let info = large_blobs_info(self).await?;
+        let entries: Vec<LargeBlobEntry> = read_array(self, &info, timeout)
+            .await?
+            .iter()
+            .filter_map(decode_entry)
+            .collect();
+        find_large_blob(&entries, key, MAX_LARGE_BLOB_SIZE)
+    }
+
+    async fn put_large_blob(
+        &mut self,
+        key: &[u8; 32],
+        blob: &[u8],
+        timeout: Duration,
+    ) -> Result<(), Error> {
+        let info = large_blobs_info(self).await?;
+        let mut array = read_array(self, &info, timeout).await?;
+        array.retain(|value| !belongs_to(value, key));
+        let entry = serde_cbor::value::to_value(LargeBlobEntry::seal(key, blob))
+            .map_err(cbor::CborError::from)?;
+        array.push(entry);
+        write_array(self, &info, &array, timeout).await
+    }
+
+    async fn delete_large_blob(
+        &mut self,
+        key: &[u8; 32],
+        timeout: Duration,
+    ) -> Result<bool, Error> {
+        let info = large_blobs_info(self).await?;
+        let mut array = read_array(self, &info, timeout).await?;
+        let len = array.len();
+        array.retain(|value| !belongs_to(value, key));
+        if array.len() == len {
+            debug!("No large blob to delete");
+            return Ok(false);
+        }
+        write_array(self, &info, &array, timeout).await?;
+        Ok(true)
+    }
+}
+
+async fn large_blobs_info<C: Channel>(channel: &mut C) -> Result<Ctap2GetInfoResponse, Error> {
+    let info = channel.ctap2_get_info().await?;
+    if !info.option_enabled("largeBlobs") {
+        warn!("Device doesn't support large blobs");
+        return Err(Error::Platform(PlatformError::NotSupported));
+    }
+    Ok(info)
+}
+
+/// Room for the command and the other parameters of a request, as the specification suggests.
+fn max_fragment_length(info: &Ctap2GetInfoResponse) -> u32 {
+    info.max_msg_size
+        .unwrap_or(DEFAULT_MAX_MSG_SIZE)
+        .max(DEFAULT_MAX_MSG_SIZE)
+        - 64
+}
+
+/// Reads the serialized array, one fragment after the other until a short one.
+async fn read_array<C: Channel>(
+    channel: &mut C,
+    info: &Ctap2GetInfoResponse,
+    timeout: Duration,
+) -> Result<Vec<Value>, Error> {
+    let max_fragment = max_fragment_length(info);
+    let max_array = info.max_blob_array.unwrap_or(DEFAULT_MAX_BLOB_ARRAY) as usize;
+    let mut serialized = Vec::new();
+    loop {
+        let request = Ctap2LargeBlobsRequest::new_get(serialized.len() as u32, max_fragment);
+        let response = channel.ctap2_large_blobs(&request, timeout).await?;
+        let Some(fragment) = response.config else {
+            warn!("Large blob read returned no fragment");
+            return Err(Error::Platform(PlatformError::MissingResponseField(
+                "config",
+            )));
+        };
+        if fragment.len() > max_fragment as usize || serialized.len() + fragment.len() > max_array {
+            warn!(len = fragment.len(), "Large blob fragment is too long");
+            return Err(Error::Platform(PlatformError::InvalidDeviceResponse));
+        }
+        serialized.extend_from_slice(&fragment);
+        if fragment.len() < max_fragment as usize {
+            break;
+        }
+    }
+    debug!(len = serialized.len(), "Read large blob array");
+    Ok(decode_array(&serialized))
+}
+
+/// Writes `array` with its hash, in fragments. A pinUvAuthToken with the largeBlobWrite
+/// permission authenticates them, if the device is protected by a PIN or built-in UV.
+async fn write_array<C: Channel>(
+    channel: &mut C,
+    info: &Ctap2GetInfoResponse,
+    array: &[Value],
+    timeout: Duration,
+) -> Result<(), Error> {
+    let serialized = encode_array(array)?;
+    let max_array = info.max_blob_array.unwrap_or(DEFAULT_MAX_BLOB_ARRAY);
+    if serialized.len() > max_array as usize {
+        warn!(
+            len = serialized.len(),
+            max_array, "Large blob array exceeds maxSerializedLargeBlobArray"
+        );
+        return Err(Error::Platform(PlatformError::LargeBlobTooLarge(
+            serialized.len() as u64,
+        )));
+    }
+    let max_fragment = max_fragment_length(info) as usize;
+    let length = serialized.len() as u32;
+    for (index, fragment) in serialized.chunks(max_fragment).enumerate() {
+        let offset = (index * max_fragment) as u32;
+        let mut req =
+            Ctap2LargeBlobsRequest::new_set(offset, fragment, (offset == 0).then_some(length));
+        loop {
+            let uv_auth_used = user_verification(
+                channel,
+                UvOptions::new(UserVerificationRequirement::Discouraged),
+                &mut req,
+                timeout,
+            )
+            .await?;
+            // On success, this is an empty Ctap2LargeBlobsResponse
+            handle_errors!(
+                channel,
+                channel.ctap2_large_blobs(&req, timeout).await,
+                uv_auth_used,
+                timeout
+            )
+        }?;
+    }
+    debug!(len = serialized.len(), "Wrote large blob array");
+    Ok(())
+}
+
+/// The entries of a serialized array. If it doesn't match its hash, the specification has
+/// platforms treat it as empty.
+fn decode_array(serialized: &[u8]) -> Vec<Value> {
+    let Some(split) = serialized.len().checked_sub(TRAILER_SIZE) else {
+        warn!(len = serialized.len(), "Large blob array lacks its hash");
+        return vec![];
+    };
+    let (array, trailer) = serialized.split_at(split);
+    if trailer != &Sha256::digest(array)[..TRAILER_SIZE] {
+        warn!("Large blob array doesn't match its hash, treating it as empty");
+        return vec![];
+    }
+    cbor::from_slice(array).unwrap_or_else(|err| {
+        warn!(
+            ?err,
+            "Failed to decode large blob array, treating it as empty"
+        );
+        vec![]
+    })
+}
+
+fn encode_array(array: &[Value]) -> Result<Vec<u8>, Error> {
+    let mut serialized = cbor::to_vec(&array)?;
+    let hash = Sha256::digest(&serialized);
+    serialized.extend_from_slice(&hash[..TRAILER_SIZE]);
+    Ok(serialized)
+}
+
+/// Entries of other formats are kept in the array, but never opened.
+fn decode_entry(value: &Value) -> Option<LargeBlobEntry> {
+    serde_cbor::value::from_value(value.clone())
+        .inspect_err(|err| debug!(?err, "Large blob entry of an unknown format"))
+        .ok()
+}
+
+fn belongs_to(value: &Value, key: &[u8; 32]) -> bool {
+    decode_entry(value).is_some_and(|entry| entry.decrypt(key).is_some())
+}
+
+impl Ctap2UserVerifiableRequest for Ctap2LargeBlobsRequest {
+    fn ensure_uv_set(&mut self) {
+        // No-op
+    }
+
+    fn calculate_and_set_uv_auth(
+        &mut self,
+        uv_proto: &Box<dyn PinUvAuthProtocol>,
+        uv_auth_token: &[u8],
+    ) {
+        // pinUvAuthParam (0x05): the result of calling
+        // authenticate(pinUvAuthToken, 32×0xff || h'0c00' || uint32LittleEndian(offset) || SHA-256(set)).
+        let mut data = vec![0xff; 32];
+        data.extend([0x0C, 0x00]);
+        data.extend(self.offset.to_le_bytes());
+        data.extend(Sha256::digest(
+            self.set
+                .as_ref()
+                .map(|set| set.as_slice())
+                .unwrap_or_default(),
+        ));
+        let uv_auth_param = uv_proto.authenticate(uv_auth_token, &data);
+        self.protocol = Some(uv_proto.version());
+        self.uv_auth_param = Some(ByteBuf::from(uv_auth_param));
+    }
+
+    fn client_data_hash(&self) -> &[u8] {
+        unreachable!()
+    }
+
+    fn permissions(&self) -> Ctap2AuthTokenPermissionRole {
+        Ctap2AuthTokenPermissionRole::LARGE_BLOB_WRITE
+    }
+
+    fn permissions_rpid(&self) -> Option<&str> {
+        None
+    }
+
+    fn can_use_uv(&self, _info: &Ctap2GetInfoResponse) -> bool {
+        true
+    }
+
+    fn handle_legacy_preview(&mut self, _info: &Ctap2GetInfoResponse) {
+        // No-op
+    }
+}
+
+fn cipher(key: &[u8; 32]) -> Aes256Gcm {
+    Aes256Gcm::new(key.into())
+}
+
+/// "blob" followed by origSize as a 64-bit little-endian integer.
+fn associated_data(orig_size: u64) -> Vec<u8> {
+    let mut data = b"blob".to_vec();
+    data.extend_from_slice(&orig_size.to_le_bytes());
+    data
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::time::Duration;
+
+    use aes_gcm::aead::{Aead, Payload};
+    use aes_gcm::Nonce;
+    use miniz_oxide::inflate::decompress_to_vec;
+    use serde_bytes::ByteBuf;
+    use sha2::{Digest, Sha256};
+
+    use super::{
+        decode_array, encode_array, find_large_blob, LargeBlobEntry, LargeBlobs,
+        MAX_LARGE_BLOB_SIZE,
+    };
+    use crate::proto::ctap2::cbor::{self, CborRequest, CborResponse, Value};
+    use crate::proto::ctap2::{Ctap2CommandCode, Ctap2GetInfoResponse, Ctap2LargeBlobsResponse};
+    use crate::testing::MockChannel;
+    use crate::webauthn::{Error, PlatformError};
+
+    const KEY: [u8; 32] = [0x42; 32];
+
+    #[test]
+    fn round_trip_is_compressed() {
+        let blob = b"certificate ".repeat(100);
+        let entry = LargeBlobEntry::seal(&KEY, &blob);
+        assert_eq!(entry.orig_size, blob.len() as u64);
+        // The ciphertext is the size of the compressed blob, plus the 16-byte tag.
+        assert!(entry.ciphertext.len() < blob.len() / 4);
+
+        let encoded = cbor::to_vec(&entry).unwrap();
+        let decoded: LargeBlobEntry = cbor::from_slice(&encoded).unwrap();
+        assert_eq!(
+            decoded.open(&KEY, MAX_LARGE_BLOB_SIZE).unwrap(),
+            Some(blob.clone())
+        );
+        assert_eq!(decoded.open(&[0; 32], MAX_LARGE_BLOB_SIZE).unwrap(), None);
+
+        let other = LargeBlobEntry::seal(&[0; 32], b"other");
+        assert_eq!(
+            find_large_blob(&[other, entry], &KEY, MAX_LARGE_BLOB_SIZE).unwrap(),
+            Some(blob)
+        );
+    }
+
+    #[test]
+    fn plaintext_is_raw_deflate() {
+        let entry = LargeBlobEntry::seal(&KEY, b"hello hello hello");
+        let compressed = super::cipher(&KEY)
+            .decrypt(
+                Nonce::from_slice(&entry.nonce),
+                Payload {
+                    msg: &entry.ciphertext,
+                    aad: &super::associated_data(entry.orig_size),
+                },
+            )
+            .unwrap();
+        assert_eq!(
+            decompress_to_vec(&compressed).unwrap(),
+            b"hello hello hello"
+        );
+    }
+
+    #[test]
+    fn oversized_blob_is_rejected() {
+        let entry = LargeBlobEntry::seal(&KEY, &[0; 4096]);
+        assert_eq!(
+            entry.open(&KEY, 1024),
+            Err(Error::Platform(PlatformError::LargeBlobTooLarge(4096)))
+        );
+    }
+
+    #[test]
+    fn malformed_entries_are_skipped() {
+        let mut malformed = LargeBlobEntry::seal(&KEY, b"stale");
+        malformed.nonce = ByteBuf::from(vec![0; 8]);
+        let entry = LargeBlobEntry::seal(&KEY, b"blob");
+        assert_eq!(
+            find_large_blob(&[malformed, entry], &KEY, MAX_LARGE_BLOB_SIZE).unwrap(),
+            Some(b"blob".to_vec())
+        );
+    }
+
+    #[test]
+    fn array_is_checked_against_its_hash() {
+        // The initial value of the array: an empty CBOR array, and its truncated hash.
+        let initial = hex::decode("80").unwrap();
+        let mut serialized = initial.clone();
+        serialized.extend_from_slice(&Sha256::digest(&initial)[..16]);
+        assert_eq!(encode_array(&[]).unwrap(), serialized);
+
+        let array = vec![Value::Text("other platform".to_owned())];
+        let mut serialized = encode_array(&array).unwrap();
+        assert_eq!(decode_array(&serialized), array);
+        *serialized.last_mut().unwrap() ^= 0x01;
+        assert!(decode_array(&serialized).is_empty());
+        assert!(decode_array(&[0x80]).is_empty());
+    }
+
+    fn large_blobs_info() -> Ctap2GetInfoResponse {
+        Ctap2GetInfoResponse {
+            versions: vec!["FIDO_2_1".to_owned()],
+            options: Some(HashMap::from([("largeBlobs".to_owned(), true)])),
+            max_msg_size: Some(1024),
+            max_blob_array: Some(4096),
+            ..Default::default()
+        }
+    }
+
+    /// Hardly compressible, so that the array needs several fragments.
+    fn incompressible_blob(len: usize) -> Vec<u8> {
+        let mut state: u32 = 1;
+        (0..len)
+            .map(|_| {
+                state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                (state >> 16) as u8
+            })
+            .collect()
+    }
+
+    /// The offset, length and fragment of a write.
+    fn written_fragment(request: &CborRequest) -> (u32, Option<u32>, Vec<u8>) {
+        let Value::Map(params) = cbor::from_slice(&request.encoded_data).unwrap() else {
+            panic!("Request isn't a map");
+        };
+        let integer = |key| match params.get(&Value::Integer(key)) {
+            Some(Value::Integer(value)) => Some(*value as u32),
+            _ => None,
+        };
+        let Some(Value::Bytes(fragment)) = params.get(&Value::Integer(0x02)) else {
+            panic!("Request isn't a write");
+        };
+        (integer(0x03).unwrap(), integer(0x04), fragment.clone())
+    }
+
+    #[tokio::test]
+    async fn blob_is_written_and_read_in_fragments() {
+        let info = large_blobs_info();
+        let blob = incompressible_blob(1200);
+        let other = Value::Text("other platform".to_owned());
+        let mut channel = MockChannel::new();
+        channel
+            .expect(Ctap2CommandCode::AuthenticatorGetInfo, &info)
+            .expect(
+                Ctap2CommandCode::AuthenticatorLargeBlobs,
+                &Ctap2LargeBlobsResponse {
+                    config: Some(ByteBuf::from(
+                        encode_array(std::slice::from_ref(&other)).unwrap(),
+                    )),
+                },
+            );
+        // Each fragment checks whether the device is protected by a PIN or built-in UV.
+        for _ in 0..2 {
+            channel
+                .expect(Ctap2CommandCode::AuthenticatorGetInfo, &info)
+                .expect_response(
+                    Ctap2CommandCode::AuthenticatorLargeBlobs,
+                    CborResponse::new_success_from_slice(&[]),
+                );
+        }
+        channel
+            .put_large_blob(&KEY, &blob, Duration::from_secs(1))
+            .await
+            .unwrap();
+        channel.assert_done();
+
+        // The array is read first, then written in two fragments.
+        let writes: Vec<_> = channel
+            .requests()
+            .iter()
+            .filter(|request| request.command == Ctap2CommandCode::AuthenticatorLargeBlobs)
+            .skip(1)
+            .map(written_fragment)
+            .collect();
+        let [(0, length, first), (960, None, second)] = &writes[..] else {
+            panic!("Unexpected fragments: {writes:?}");
+        };
+        assert_eq!(first.len(), 960);
+        let serialized = [&first[..], &second[..]].concat();
+        assert_eq!(*length, Some(serialized.len() as u32));
+        let array = decode_array(&serialized);
+        assert_eq!(array[0], other);
+
+        let mut channel = MockChannel::new();
+        channel.expect(Ctap2CommandCode::AuthenticatorGetInfo, &info);
+        for fragment in serialized.chunks(960) {
+            channel.expect(
+                Ctap2CommandCode::AuthenticatorLargeBlobs,
+                &Ctap2LargeBlobsResponse {
+                    config: Some(ByteBuf::from(fragment)),
+                },
+            );
+        }
+        let read = channel
+            .get_large_blob(&KEY, Duration::from_secs(1))
+            .await
+            .unwrap();
+        assert_eq!(read, Some(blob));
+        channel.assert_done();
+    }
+
+    #[tokio::test]
+    async fn unsupported_device_is_rejected() {
+        let mut channel = MockChannel::new();
+        channel.expect(
+            Ctap2CommandCode::AuthenticatorGetInfo,
+            &Ctap2GetInfoResponse::default(),
+        );
+        assert_eq!(
+            channel.get_large_blob(&KEY, Duration::from_secs(1)).await,
+            Err(Error::Platform(PlatformError::NotSupported))
+        );
+        channel.assert_done();
+    }
+}
@@ -1,15 +1,46 @@
+pub mod audit;
+pub mod blocking;
+pub mod capabilities;
+pub mod cbor;
+pub mod ceremony;
+pub mod correlation;
 pub mod fido;
+pub mod large_blob;
 pub mod management;
+pub mod metrics;
+pub mod offline_secret;
 pub mod ops;
+pub mod parse;
 pub mod pin;
 pub mod proto;
+pub mod quirks;
+mod redact;
+pub mod session_gate;
+pub mod simple;
+pub mod sources;
+pub mod timeout;
 pub mod transport;
 pub mod u2f;
 pub mod webauthn;
 
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+
+#[cfg(feature = "verify")]
+pub mod verify;
+
+#[cfg(feature = "metadata")]
+pub mod metadata;
+
+#[cfg(feature = "aaguid-names")]
+pub mod aaguid;
+
 use std::sync::Arc;
 
 use tokio::sync::oneshot;
+use zeroize::Zeroizing;
+
+use correlation::CorrelationId;
 
 #[macro_use]
 extern crate num_derive;
@@ -26,7 +57,9 @@ macro_rules! unwrap_field {
                 "Device response did not contain expected field: {}",
                 stringify!($field)
             );
-            return Err(Error::Platform(PlatformError::InvalidDeviceResponse));
+            return Err(Error::Platform(PlatformError::MissingResponseField(
+                stringify!($field),
+            )));
         }
     }};
 }
@@ -40,6 +73,7 @@ pub enum Transport {
 }
 
 #[derive(Debug, Clone)]
+#[non_exhaustive]
 pub enum UvUpdate {
     /// UV failed, but we can still retry. `attempts_left` optionally shows how many tries _in total_ are left.
     /// Builtin UV may still temporarily be blocked.
@@ -50,15 +84,40 @@ pub enum UvUpdate {
     /// The ongoing operation may run into a timeout, no answer is provided in time.
     PinRequired(PinRequiredUpdate),
     PresenceRequired,
+    /// The device is busy with the request, e.g. generating a key, and doesn't need the user's
+    /// presence right now. Any touch prompt can be hidden until `PresenceRequired` is sent again.
+    Processing,
+    /// The RP discouraged user verification, but the device's alwaysUv option enforces it.
+    /// Only sent if the request's `AlwaysUvPolicy` is `Warn`.
+    AlwaysUvEnforced,
+    /// The device disappeared mid-operation. The operation resumes if it's plugged back in
+    /// in time, otherwise it fails with `TransportError::DeviceRemoved`.
+    DeviceRemoved,
+    /// The user didn't touch or verify on the device in time. The operation fails with a
+    /// timeout error, and any touch prompt can be dismissed.
+    OperationTimeout,
+    /// The embedder's `SessionGate` is closed, e.g. because the screen is locked.
+    /// The operation resumes once it opens.
+    SessionLocked,
+    /// The operation was aborted through the channel's cancellation token. Any prompt still
+    /// shown, e.g. for a PIN or presence, can be dismissed.
+    Cancelled,
+    /// The pinUvAuthToken of an earlier operation can't be reused, e.g. as it is bound to
+    /// another RP, so the user is verified again. Sent before the PIN or UV prompt, to
+    /// explain it.
+    AuthTokenMismatch(transport::AuthTokenMismatch),
 }
 
 #[derive(Debug, Clone)]
 pub struct PinRequiredUpdate {
-    reply_to: Arc<oneshot::Sender<String>>,
+    reply_to: Arc<oneshot::Sender<Zeroizing<String>>>,
     /// What caused the PIN request.
     pub reason: PinRequestReason,
     /// Optionally, how many PIN attempts are left _in total_.
     pub attempts_left: Option<u32>,
+    /// The ceremony asking for the PIN, see [correlation]. `None` for management operations
+    /// run outside of [CorrelationId::scope].
+    pub correlation_id: Option<CorrelationId>,
 }
 
 impl PinRequiredUpdate {
@@ -66,7 +125,7 @@ impl PinRequiredUpdate {
     pub fn send_pin(self, pin: &str) -> Result<(), String> {
         match Arc::into_inner(self.reply_to) {
             Some(sender) => sender
-                .send(pin.to_string())
+                .send(Zeroizing::new(pin.to_string()))
                 .map_err(|_| "Failed to send PIN".to_string()),
             None => Err("Multiple references to reply_to exist; cannot send PIN".to_string()),
         }
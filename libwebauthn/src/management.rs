@@ -1,8 +1,14 @@
 mod bio_enrollment;
-pub use bio_enrollment::BioEnrollment;
+pub use bio_enrollment::{
+    truncate_friendly_name, BioEnrollment, Ctap2BioEnrollmentFingerprintSensorInfo, Fingerprint,
+    FingerprintEnrollmentUpdate,
+};
 
 mod authenticator_config;
-pub use authenticator_config::AuthenticatorConfig;
+pub use authenticator_config::{AuthenticatorConfig, EnterpriseAttestationState};
 
 mod credential_management;
 pub use credential_management::CredentialManagement;
+
+mod credential_inventory;
+pub use credential_inventory::{CredentialInventory, RelyingPartyInventory, StoredCredential};
@@ -1,9 +1,9 @@
 use crate::proto::ctap2::cbor;
 use crate::proto::ctap2::Ctap2ClientPinRequest;
 use crate::transport::Channel;
-pub use crate::webauthn::error::{CtapError, Error};
+pub use crate::webauthn::error::{CtapError, Error, PlatformError};
 use crate::webauthn::handle_errors;
-use crate::webauthn::pin_uv_auth_token::{user_verification, UsedPinUvAuthToken};
+use crate::webauthn::pin_uv_auth_token::{user_verification, UsedPinUvAuthToken, UvOptions};
 use crate::{
     ops::webauthn::UserVerificationRequirement,
     pin::PinUvAuthProtocol,
@@ -16,7 +16,44 @@ use crate::{
 use async_trait::async_trait;
 use serde_bytes::ByteBuf;
 use std::time::Duration;
-use tracing::info;
+use tracing::{info, warn};
+
+/// Whether the device's enterprise attestation is enabled, from its `ep` option.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EnterpriseAttestationState {
+    Unsupported,
+    Disabled,
+    Enabled,
+}
+
+impl From<&Ctap2GetInfoResponse> for EnterpriseAttestationState {
+    fn from(info: &Ctap2GetInfoResponse) -> Self {
+        match info.options.as_ref().and_then(|o| o.get("ep")) {
+            None => Self::Unsupported,
+            Some(false) => Self::Disabled,
+            Some(true) => Self::Enabled,
+        }
+    }
+}
+
+/// Fails before sending setMinPINLength with `count` RP IDs, if the device doesn't support the
+/// subcommand or can't store that many.
+fn check_min_pin_length_rpids(info: &Ctap2GetInfoResponse, count: usize) -> Result<(), Error> {
+    if !info.option_enabled("setMinPINLength") {
+        warn!("Device doesn't support setMinPINLength");
+        return Err(Error::Platform(PlatformError::NotSupported));
+    }
+    // Absent if the device doesn't allow adding RP IDs after setup.
+    let max = info.max_rpids_for_setminpinlength.unwrap_or(0);
+    if count > max as usize {
+        warn!(count, max, "Too many RP IDs for setMinPINLength");
+        return Err(Error::Platform(PlatformError::TooManyMinPinLengthRpIds {
+            count,
+            max,
+        }));
+    }
+    Ok(())
+}
 
 #[async_trait]
 pub trait AuthenticatorConfig {
@@ -24,6 +61,13 @@ pub trait AuthenticatorConfig {
 
     async fn enable_enterprise_attestation(&mut self, timeout: Duration) -> Result<(), Error>;
 
+    /// Makes sure enterprise attestation is disabled. authenticatorConfig has no subcommand
+    /// to disable it once enabled, only authenticatorReset does: on devices where it's
+    /// enabled, this fails with `PlatformError::NotSupported`.
+    async fn disable_enterprise_attestation(&mut self) -> Result<(), Error>;
+
+    async fn enterprise_attestation_state(&mut self) -> Result<EnterpriseAttestationState, Error>;
+
     async fn set_min_pin_length(
         &mut self,
         new_pin_length: u64,
@@ -50,7 +94,7 @@ where
         loop {
             let uv_auth_used = user_verification(
                 self,
-                UserVerificationRequirement::Required,
+                UvOptions::new(UserVerificationRequirement::Required),
                 &mut req,
                 timeout,
             )
@@ -71,7 +115,7 @@ where
         loop {
             let uv_auth_used = user_verification(
                 self,
-                UserVerificationRequirement::Required,
+                UvOptions::new(UserVerificationRequirement::Required),
                 &mut req,
                 timeout,
             )
@@ -86,6 +130,23 @@ where
         }
     }
 
+    async fn disable_enterprise_attestation(&mut self) -> Result<(), Error> {
+        match self.enterprise_attestation_state().await? {
+            EnterpriseAttestationState::Unsupported | EnterpriseAttestationState::Disabled => {
+                Ok(())
+            }
+            EnterpriseAttestationState::Enabled => {
+                warn!("Enterprise attestation can only be disabled by resetting the device");
+                Err(Error::Platform(PlatformError::NotSupported))
+            }
+        }
+    }
+
+    async fn enterprise_attestation_state(&mut self) -> Result<EnterpriseAttestationState, Error> {
+        let info = self.ctap2_get_info().await?;
+        Ok(EnterpriseAttestationState::from(&info))
+    }
+
     async fn set_min_pin_length(
         &mut self,
         new_pin_length: u64,
@@ -96,7 +157,7 @@ where
         loop {
             let uv_auth_used = user_verification(
                 self,
-                UserVerificationRequirement::Required,
+                UvOptions::new(UserVerificationRequirement::Required),
                 &mut req,
                 timeout,
             )
@@ -117,7 +178,7 @@ where
         loop {
             let uv_auth_used = user_verification(
                 self,
-                UserVerificationRequirement::Required,
+                UvOptions::new(UserVerificationRequirement::Required),
                 &mut req,
                 timeout,
             )
@@ -137,11 +198,13 @@ where
         rpids: Vec<String>,
         timeout: Duration,
     ) -> Result<(), Error> {
+        let info = self.ctap2_get_info().await?;
+        check_min_pin_length_rpids(&info, rpids.len())?;
         let mut req = Ctap2AuthenticatorConfigRequest::new_set_min_pin_length_rpids(rpids);
         loop {
             let uv_auth_used = user_verification(
                 self,
-                UserVerificationRequirement::Required,
+                UvOptions::new(UserVerificationRequirement::Required),
                 &mut req,
                 timeout,
             )
@@ -173,7 +236,7 @@ impl Ctap2UserVerifiableRequest for Ctap2AuthenticatorConfigRequest {
         data.push(0x0D);
         data.push(self.subcommand as u8);
         if self.subcommand == Ctap2AuthenticatorConfigCommand::SetMinPINLength {
-            data.extend(cbor::to_vec(&self.subcommand_params).unwrap());
+            data.extend(cbor::to_vec_canonical(&self.subcommand_params).unwrap());
         }
         let uv_auth_param = uv_proto.authenticate(uv_auth_token, &data);
         self.protocol = Some(uv_proto.version());
@@ -200,3 +263,67 @@ impl Ctap2UserVerifiableRequest for Ctap2AuthenticatorConfigRequest {
         // No-op
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::{check_min_pin_length_rpids, EnterpriseAttestationState};
+    use crate::proto::ctap2::Ctap2GetInfoResponse;
+    use crate::webauthn::{Error, PlatformError};
+
+    fn info(options: &[(&str, bool)], max_rpids: Option<u32>) -> Ctap2GetInfoResponse {
+        let options: HashMap<String, bool> = options
+            .iter()
+            .map(|&(name, enabled)| (name.to_string(), enabled))
+            .collect();
+        Ctap2GetInfoResponse {
+            options: Some(options),
+            max_rpids_for_setminpinlength: max_rpids,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn enterprise_attestation_state_from_ep() {
+        for (options, state) in [
+            (&[][..], EnterpriseAttestationState::Unsupported),
+            (&[("ep", false)][..], EnterpriseAttestationState::Disabled),
+            (&[("ep", true)][..], EnterpriseAttestationState::Enabled),
+        ] {
+            assert_eq!(
+                EnterpriseAttestationState::from(&info(options, None)),
+                state
+            );
+        }
+    }
+
+    #[test]
+    fn min_pin_length_rpids_within_device_limit() {
+        let not_supported = info(&[("setMinPINLength", false)], Some(4));
+        assert_eq!(
+            check_min_pin_length_rpids(&not_supported, 1),
+            Err(Error::Platform(PlatformError::NotSupported))
+        );
+
+        let fixed_list = info(&[("setMinPINLength", true)], None);
+        assert_eq!(check_min_pin_length_rpids(&fixed_list, 0), Ok(()));
+        assert_eq!(
+            check_min_pin_length_rpids(&fixed_list, 1),
+            Err(Error::Platform(PlatformError::TooManyMinPinLengthRpIds {
+                count: 1,
+                max: 0
+            }))
+        );
+
+        let up_to_two = info(&[("setMinPINLength", true)], Some(2));
+        assert_eq!(check_min_pin_length_rpids(&up_to_two, 2), Ok(()));
+        assert_eq!(
+            check_min_pin_length_rpids(&up_to_two, 3),
+            Err(Error::Platform(PlatformError::TooManyMinPinLengthRpIds {
+                count: 3,
+                max: 2
+            }))
+        );
+    }
+}
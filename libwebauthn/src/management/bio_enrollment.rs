@@ -4,23 +4,24 @@ use crate::{
     pin::PinUvAuthProtocol,
     proto::ctap2::{
         Ctap2, Ctap2AuthTokenPermissionRole, Ctap2BioEnrollmentFingerprintKind,
-        Ctap2BioEnrollmentModality, Ctap2BioEnrollmentRequest, Ctap2BioEnrollmentTemplateId,
-        Ctap2ClientPinRequest, Ctap2GetInfoResponse, Ctap2LastEnrollmentSampleStatus,
-        Ctap2UserVerifiableRequest,
+        Ctap2BioEnrollmentModality, Ctap2BioEnrollmentRequest, Ctap2BioEnrollmentResponse,
+        Ctap2BioEnrollmentTemplateId, Ctap2ClientPinRequest, Ctap2GetInfoResponse,
+        Ctap2LastEnrollmentSampleStatus, Ctap2UserVerifiableRequest,
     },
-    transport::Channel,
+    transport::{Channel, MAX_DEVICE_LOCK_DURATION},
     unwrap_field,
     webauthn::{
         error::{CtapError, Error, PlatformError},
         handle_errors,
-        pin_uv_auth_token::{user_verification, UsedPinUvAuthToken},
+        pin_uv_auth_token::{user_verification, UsedPinUvAuthToken, UvOptions},
     },
     UvUpdate,
 };
 use async_trait::async_trait;
+use futures::stream::{self, BoxStream, StreamExt};
 use serde_bytes::ByteBuf;
 use std::time::Duration;
-use tracing::info;
+use tracing::{debug, info, warn};
 
 #[async_trait]
 pub trait BioEnrollment {
@@ -41,6 +42,13 @@ pub trait BioEnrollment {
         template_id: &[u8],
         timeout: Duration,
     ) -> Result<(), Error>;
+    /// Maximum length in bytes of a template friendly name, if the device reports one.
+    async fn get_max_template_friendly_name(
+        &mut self,
+        timeout: Duration,
+    ) -> Result<Option<usize>, Error>;
+    /// Renames an enrollment. Fails with [PlatformError::FriendlyNameTooLong] if the name
+    /// exceeds the device limit, see [truncate_friendly_name].
     async fn rename_bio_enrollment(
         &mut self,
         template_id: &[u8],
@@ -58,10 +66,79 @@ pub trait BioEnrollment {
         enrollment_timeout: Option<Duration>,
         timeout: Duration,
     ) -> Result<(Ctap2LastEnrollmentSampleStatus, u64), Error>;
+    /// Cancels an ongoing fingerprint enrollment, disarming the sensor. Succeeds if there
+    /// is no enrollment in progress.
     async fn cancel_current_bio_enrollment(&mut self, timeout: Duration) -> Result<(), Error>;
+    /// Lists the enrolled fingerprints. Returns an empty list if there are none, rather than
+    /// the authenticator's `CTAP2_ERR_INVALID_OPTION`.
+    async fn list_fingerprints(&mut self, timeout: Duration) -> Result<Vec<Fingerprint>, Error>;
+    /// Enrolls a new fingerprint, driving enrollBegin and enrollCaptureNextSample until no
+    /// samples remain. Yields a [FingerprintEnrollmentUpdate::Progress] per captured sample,
+    /// then [FingerprintEnrollmentUpdate::Completed] with the new template ID.
+    ///
+    /// Retryable user errors (e.g. the user didn't touch the sensor in time) are yielded and
+    /// capturing continues; any other error ends the stream.
+    ///
+    /// If the pending sample capture is cancelled on the transport (e.g. through
+    /// `HidChannelHandle::cancel_ongoing_operation`), the enrollment is cancelled on the
+    /// authenticator as well and the stream ends with [PlatformError::Cancelled]. When dropping
+    /// the stream early instead, call [BioEnrollment::cancel_current_bio_enrollment].
+    fn enroll_fingerprint(
+        &mut self,
+        enrollment_timeout: Option<Duration>,
+        timeout: Duration,
+    ) -> BoxStream<'_, Result<FingerprintEnrollmentUpdate, Error>>;
+}
+
+/// An enrolled fingerprint template.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Fingerprint {
+    pub template_id: Vec<u8>,
+    pub friendly_name: Option<String>,
+}
+
+impl TryFrom<Ctap2BioEnrollmentTemplateId> for Fingerprint {
+    type Error = Error;
+
+    fn try_from(template: Ctap2BioEnrollmentTemplateId) -> Result<Self, Error> {
+        let Some(template_id) = template.template_id else {
+            warn!("Enrollment is missing its template ID");
+            return Err(Error::Ctap(CtapError::Other));
+        };
+        Ok(Self {
+            template_id: template_id.into_vec(),
+            friendly_name: template.template_friendly_name,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum FingerprintEnrollmentUpdate {
+    /// A sample was captured, or rejected with the given status.
+    Progress {
+        last_sample_status: Ctap2LastEnrollmentSampleStatus,
+        remaining_samples: u64,
+    },
+    /// All samples were captured and the enrollment is stored on the device.
+    Completed { template_id: Vec<u8> },
+}
+
+enum EnrollmentState {
+    Begin,
+    Capturing { template_id: Vec<u8> },
+    Completed { template_id: Vec<u8> },
+    Done,
 }
 
-#[derive(Debug, Clone)]
+fn next_enrollment_state(template_id: Vec<u8>, remaining_samples: u64) -> EnrollmentState {
+    if remaining_samples > 0 {
+        EnrollmentState::Capturing { template_id }
+    } else {
+        EnrollmentState::Completed { template_id }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Ctap2BioEnrollmentFingerprintSensorInfo {
     pub fingerprint_kind: Ctap2BioEnrollmentFingerprintKind,
     pub max_capture_samples_required_for_enroll: Option<u64>,
@@ -69,6 +146,113 @@ pub struct Ctap2BioEnrollmentFingerprintSensorInfo {
     pub max_template_friendly_name: Option<u64>,
 }
 
+impl Ctap2BioEnrollmentFingerprintSensorInfo {
+    /// Whether the user swipes their finger across the sensor, rather than placing it on it.
+    pub fn is_swipe_sensor(&self) -> bool {
+        self.fingerprint_kind == Ctap2BioEnrollmentFingerprintKind::Swipe
+    }
+}
+
+impl TryFrom<Ctap2BioEnrollmentResponse> for Ctap2BioEnrollmentFingerprintSensorInfo {
+    type Error = Error;
+
+    fn try_from(resp: Ctap2BioEnrollmentResponse) -> Result<Self, Error> {
+        let Some(fingerprint_kind) = resp.fingerprint_kind else {
+            warn!("Sensor info is missing the fingerprint kind");
+            return Err(Error::Ctap(CtapError::Other));
+        };
+        Ok(Self {
+            fingerprint_kind,
+            max_capture_samples_required_for_enroll: resp.max_capture_samples_required_for_enroll,
+            max_template_friendly_name: resp.max_template_friendly_name,
+        })
+    }
+}
+
+/// Truncates `name` to at most `max_bytes` bytes, without splitting a UTF-8 character.
+pub fn truncate_friendly_name(name: &str, max_bytes: usize) -> &str {
+    if name.len() <= max_bytes {
+        return name;
+    }
+    let mut end = max_bytes;
+    while !name.is_char_boundary(end) {
+        end -= 1;
+    }
+    &name[..end]
+}
+
+/// Sends a request starting or continuing an enrollment. The device stays locked until the
+/// enrollment completes, since any other request in between would abort it.
+async fn enrollment_step<C: Channel>(
+    channel: &mut C,
+    req: &mut Ctap2BioEnrollmentRequest,
+    timeout: Duration,
+) -> Result<Ctap2BioEnrollmentResponse, Error> {
+    if let Err(err) = channel.lock_device(MAX_DEVICE_LOCK_DURATION).await {
+        debug!(?err, "Failed to lock device, enrolling without a lock");
+    }
+    // Unlocks below even if user verification fails.
+    let resp = async {
+        loop {
+            let uv_auth_used = user_verification(
+                channel,
+                UvOptions::new(UserVerificationRequirement::Preferred),
+                req,
+                timeout,
+            )
+            .await?;
+
+            handle_errors!(
+                channel,
+                channel.ctap2_bio_enrollment(req, timeout).await,
+                uv_auth_used,
+                timeout
+            )
+        }
+    }
+    .await;
+    if !matches!(&resp, Ok(resp) if resp.remaining_samples.unwrap_or_default() > 0) {
+        if let Err(err) = channel.unlock_device().await {
+            debug!(?err, "Failed to unlock device");
+        }
+    }
+    resp
+}
+
+/// Sends a single cancelCurrentEnrollment request.
+async fn cancel_current_enrollment<C: Channel>(
+    channel: &mut C,
+    timeout: Duration,
+) -> Result<(), Error> {
+    let mut req = Ctap2BioEnrollmentRequest::new_cancel_current_enrollment();
+
+    let result = loop {
+        let uv_auth_used = user_verification(
+            channel,
+            UvOptions::new(UserVerificationRequirement::Preferred),
+            &mut req,
+            timeout,
+        )
+        .await?;
+
+        // On success, this is an all-empty Ctap2AuthenticatorConfigResponse
+        handle_errors!(
+            channel,
+            channel.ctap2_bio_enrollment(&req, timeout).await,
+            uv_auth_used,
+            timeout
+        )
+    };
+    if let Err(err) = channel.unlock_device().await {
+        debug!(?err, "Failed to unlock device");
+    }
+    result?;
+
+    // "Authenticator on receiving such command, cancels current ongoing enrollment, if any, and returns CTAP2_OK."
+    // So, the resulting Response will be empty on success.
+    Ok(())
+}
+
 #[async_trait]
 impl<C> BioEnrollment for C
 where
@@ -91,13 +275,7 @@ where
         let req = Ctap2BioEnrollmentRequest::new_fingerprint_sensor_info();
         // No UV needed
         let resp = self.ctap2_bio_enrollment(&req, timeout).await?;
-        Ok(Ctap2BioEnrollmentFingerprintSensorInfo {
-            fingerprint_kind: resp.fingerprint_kind.ok_or(Error::Ctap(CtapError::Other))?,
-            max_capture_samples_required_for_enroll: resp
-                .max_capture_samples_required_for_enroll
-                .clone(),
-            max_template_friendly_name: resp.max_template_friendly_name,
-        })
+        Ctap2BioEnrollmentFingerprintSensorInfo::try_from(resp)
     }
 
     async fn get_bio_enrollments(
@@ -109,7 +287,7 @@ where
         let resp = loop {
             let uv_auth_used = user_verification(
                 self,
-                UserVerificationRequirement::Preferred,
+                UvOptions::new(UserVerificationRequirement::Preferred),
                 &mut req,
                 timeout,
             )
@@ -136,7 +314,7 @@ where
         loop {
             let uv_auth_used = user_verification(
                 self,
-                UserVerificationRequirement::Preferred,
+                UvOptions::new(UserVerificationRequirement::Preferred),
                 &mut req,
                 timeout,
             )
@@ -156,18 +334,36 @@ where
         Ok(())
     }
 
+    async fn get_max_template_friendly_name(
+        &mut self,
+        timeout: Duration,
+    ) -> Result<Option<usize>, Error> {
+        let info = self.get_fingerprint_sensor_info(timeout).await?;
+        Ok(info.max_template_friendly_name.map(|max| max as usize))
+    }
+
     async fn rename_bio_enrollment(
         &mut self,
         template_id: &[u8],
         template_friendly_name: &str,
         timeout: Duration,
     ) -> Result<(), Error> {
+        // Devices without a reported limit are left to reject overlong names themselves.
+        if let Ok(Some(max)) = self.get_max_template_friendly_name(timeout).await {
+            if template_friendly_name.len() > max {
+                warn!(
+                    { len = template_friendly_name.len(), max },
+                    "Template friendly name exceeds device limit"
+                );
+                return Err(Error::Platform(PlatformError::FriendlyNameTooLong(max)));
+            }
+        }
         let mut req =
             Ctap2BioEnrollmentRequest::new_rename_enrollment(template_id, template_friendly_name);
         loop {
             let uv_auth_used = user_verification(
                 self,
-                UserVerificationRequirement::Preferred,
+                UvOptions::new(UserVerificationRequirement::Preferred),
                 &mut req,
                 timeout,
             )
@@ -192,24 +388,7 @@ where
         timeout: Duration,
     ) -> Result<(Vec<u8>, Ctap2LastEnrollmentSampleStatus, u64), Error> {
         let mut req = Ctap2BioEnrollmentRequest::new_start_new_enrollment(enrollment_timeout);
-
-        let resp = loop {
-            let uv_auth_used = user_verification(
-                self,
-                UserVerificationRequirement::Preferred,
-                &mut req,
-                timeout,
-            )
-            .await?;
-
-            // On success, this is an all-empty Ctap2AuthenticatorConfigResponse
-            handle_errors!(
-                self,
-                self.ctap2_bio_enrollment(&req, timeout).await,
-                uv_auth_used,
-                timeout
-            )
-        }?;
+        let resp = enrollment_step(self, &mut req, timeout).await?;
 
         let remaining_samples = unwrap_field!(resp.remaining_samples);
         let template_id = unwrap_field!(resp.template_id).clone();
@@ -229,24 +408,7 @@ where
     ) -> Result<(Ctap2LastEnrollmentSampleStatus, u64), Error> {
         let mut req =
             Ctap2BioEnrollmentRequest::new_next_enrollment(template_id, enrollment_timeout);
-
-        let resp = loop {
-            let uv_auth_used = user_verification(
-                self,
-                UserVerificationRequirement::Preferred,
-                &mut req,
-                timeout,
-            )
-            .await?;
-
-            // On success, this is an all-empty Ctap2AuthenticatorConfigResponse
-            handle_errors!(
-                self,
-                self.ctap2_bio_enrollment(&req, timeout).await,
-                uv_auth_used,
-                timeout
-            )
-        }?;
+        let resp = enrollment_step(self, &mut req, timeout).await?;
 
         let remaining_samples = unwrap_field!(resp.remaining_samples);
         let sample_status = unwrap_field!(resp.last_enroll_sample_status);
@@ -254,29 +416,105 @@ where
     }
 
     async fn cancel_current_bio_enrollment(&mut self, timeout: Duration) -> Result<(), Error> {
-        let mut req = Ctap2BioEnrollmentRequest::new_cancel_current_enrollment();
-
-        loop {
-            let uv_auth_used = user_verification(
-                self,
-                UserVerificationRequirement::Preferred,
-                &mut req,
-                timeout,
-            )
-            .await?;
+        match cancel_current_enrollment(self, timeout).await {
+            // A capture cancelled on the transport may still leave its CTAP2_ERR_KEEPALIVE_CANCEL
+            // response to be read, in which case the cancel request itself needs to be repeated.
+            Err(Error::Ctap(CtapError::KeepAliveCancel)) => {
+                debug!("Read stale response of a cancelled capture, retrying");
+                cancel_current_enrollment(self, timeout).await
+            }
+            result => result,
+        }
+    }
 
-            // On success, this is an all-empty Ctap2AuthenticatorConfigResponse
-            handle_errors!(
-                self,
-                self.ctap2_bio_enrollment(&req, timeout).await,
-                uv_auth_used,
-                timeout
-            )
-        }?;
+    async fn list_fingerprints(&mut self, timeout: Duration) -> Result<Vec<Fingerprint>, Error> {
+        match self.get_bio_enrollments(timeout).await {
+            Ok(templates) => templates.into_iter().map(Fingerprint::try_from).collect(),
+            // "If there are no enrollments existing on authenticator, it returns CTAP2_ERR_INVALID_OPTION."
+            Err(Error::Ctap(CtapError::InvalidOption)) => {
+                debug!("No fingerprints enrolled");
+                Ok(vec![])
+            }
+            Err(err) => Err(err),
+        }
+    }
 
-        // "Authenticator on receiving such command, cancels current ongoing enrollment, if any, and returns CTAP2_OK."
-        // So, the resulting Response will be empty on success.
-        Ok(())
+    fn enroll_fingerprint(
+        &mut self,
+        enrollment_timeout: Option<Duration>,
+        timeout: Duration,
+    ) -> BoxStream<'_, Result<FingerprintEnrollmentUpdate, Error>> {
+        stream::unfold(
+            (self, EnrollmentState::Begin),
+            move |(channel, state)| async move {
+                let (result, next_state) = match state {
+                    EnrollmentState::Done => return None,
+                    EnrollmentState::Completed { template_id } => {
+                        info!("Fingerprint enrollment completed");
+                        (
+                            Ok(FingerprintEnrollmentUpdate::Completed { template_id }),
+                            EnrollmentState::Done,
+                        )
+                    }
+                    EnrollmentState::Begin => {
+                        match channel
+                            .start_new_bio_enrollment(enrollment_timeout, timeout)
+                            .await
+                        {
+                            Ok((template_id, last_sample_status, remaining_samples)) => (
+                                Ok(FingerprintEnrollmentUpdate::Progress {
+                                    last_sample_status,
+                                    remaining_samples,
+                                }),
+                                next_enrollment_state(template_id, remaining_samples),
+                            ),
+                            Err(err) => (Err(err), EnrollmentState::Done),
+                        }
+                    }
+                    EnrollmentState::Capturing { template_id } => {
+                        match channel
+                            .capture_next_bio_enrollment_sample(
+                                &template_id,
+                                enrollment_timeout,
+                                timeout,
+                            )
+                            .await
+                        {
+                            Ok((last_sample_status, remaining_samples)) => (
+                                Ok(FingerprintEnrollmentUpdate::Progress {
+                                    last_sample_status,
+                                    remaining_samples,
+                                }),
+                                next_enrollment_state(template_id, remaining_samples),
+                            ),
+                            Err(Error::Ctap(ctap_error))
+                                if ctap_error.is_retryable_user_error() =>
+                            {
+                                debug!(?ctap_error, "Retryable error while capturing sample");
+                                (
+                                    Err(Error::Ctap(ctap_error)),
+                                    EnrollmentState::Capturing { template_id },
+                                )
+                            }
+                            Err(err) => (Err(err), EnrollmentState::Done),
+                        }
+                    }
+                };
+                let result = match result {
+                    Err(Error::Platform(PlatformError::Cancelled))
+                    | Err(Error::Ctap(CtapError::KeepAliveCancel)) => {
+                        info!("Fingerprint enrollment cancelled");
+                        if let Err(err) = channel.cancel_current_bio_enrollment(timeout).await {
+                            warn!(?err, "Failed to cancel enrollment on the authenticator");
+                        }
+                        Err(Error::Platform(PlatformError::Cancelled))
+                    }
+                    result => result,
+                };
+                Some((result, (channel, next_state)))
+            },
+        )
+        .boxed()
     }
 }
 
@@ -300,7 +538,7 @@ impl Ctap2UserVerifiableRequest for Ctap2BioEnrollmentRequest {
         };
         // e.g. "Authenticator calls verify(pinUvAuthToken, fingerprint (0x01) || removeEnrollment (0x06) || subCommandParams, pinUvAuthParam)"
         if let Some(params) = &self.subcommand_params {
-            data.extend(cbor::to_vec(&params).unwrap());
+            data.extend(cbor::to_vec_canonical(&params).unwrap());
         }
         let uv_auth_param = uv_proto.authenticate(uv_auth_token, &data);
         self.protocol = Some(uv_proto.version());
@@ -336,3 +574,79 @@ impl Ctap2UserVerifiableRequest for Ctap2BioEnrollmentRequest {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use serde_bytes::ByteBuf;
+
+    use super::{
+        enrollment_step, truncate_friendly_name, Ctap2BioEnrollmentFingerprintSensorInfo,
+        Fingerprint,
+    };
+    use crate::proto::ctap2::{
+        Ctap2BioEnrollmentFingerprintKind, Ctap2BioEnrollmentRequest, Ctap2BioEnrollmentResponse,
+        Ctap2BioEnrollmentTemplateId, Ctap2CommandCode,
+    };
+    use crate::proto::CtapError;
+    use crate::testing::MockChannel;
+    use crate::webauthn::Error;
+
+    #[test]
+    fn truncate_friendly_name_on_char_boundary() {
+        assert_eq!(truncate_friendly_name("thumb", 16), "thumb");
+        assert_eq!(truncate_friendly_name("left thumb", 4), "left");
+        // "ü" is two bytes, so it's dropped rather than split
+        assert_eq!(truncate_friendly_name("Daumen grün", 10), "Daumen gr");
+        assert_eq!(truncate_friendly_name("指纹", 4), "指");
+        assert_eq!(truncate_friendly_name("指纹", 0), "");
+    }
+
+    #[test]
+    fn fingerprint_from_template() {
+        let template = Ctap2BioEnrollmentTemplateId {
+            template_id: Some(ByteBuf::from(vec![0x01, 0x02])),
+            template_friendly_name: Some("left thumb".to_owned()),
+        };
+        assert_eq!(
+            Fingerprint::try_from(template).unwrap(),
+            Fingerprint {
+                template_id: vec![0x01, 0x02],
+                friendly_name: Some("left thumb".to_owned()),
+            }
+        );
+
+        let template = Ctap2BioEnrollmentTemplateId {
+            template_id: None,
+            template_friendly_name: None,
+        };
+        assert!(Fingerprint::try_from(template).is_err());
+    }
+
+    #[test]
+    fn sensor_info_from_response() {
+        let resp = Ctap2BioEnrollmentResponse {
+            fingerprint_kind: Some(Ctap2BioEnrollmentFingerprintKind::Swipe),
+            max_capture_samples_required_for_enroll: Some(8),
+            ..Default::default()
+        };
+        let info = Ctap2BioEnrollmentFingerprintSensorInfo::try_from(resp).unwrap();
+        assert!(info.is_swipe_sensor());
+        assert_eq!(info.max_capture_samples_required_for_enroll, Some(8));
+        assert_eq!(info.max_template_friendly_name, None);
+
+        let resp = Ctap2BioEnrollmentResponse::default();
+        assert!(Ctap2BioEnrollmentFingerprintSensorInfo::try_from(resp).is_err());
+    }
+
+    #[tokio::test]
+    async fn failed_enrollment_step_unlocks_device() {
+        let mut channel = MockChannel::new();
+        channel.expect_error(Ctap2CommandCode::AuthenticatorGetInfo, CtapError::Other);
+        let mut req = Ctap2BioEnrollmentRequest::new_start_new_enrollment(None);
+        let result = enrollment_step(&mut channel, &mut req, Duration::from_secs(1)).await;
+        assert_eq!(result.unwrap_err(), Error::Ctap(CtapError::Other));
+        assert!(!channel.is_locked());
+    }
+}
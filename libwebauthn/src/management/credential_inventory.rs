@@ -0,0 +1,167 @@
+use serde::Serialize;
+use tracing::warn;
+
+use crate::ops::webauthn::CredentialProtectionPolicy;
+use crate::proto::ctap2::{Ctap2CredentialData, Ctap2CredentialManagementMetadata, Ctap2RPData};
+use crate::webauthn::error::{Error, PlatformError};
+
+/// Snapshot of all discoverable credentials stored on a device, as returned by
+/// [super::CredentialManagement::get_credential_inventory]. Binary values are base64url
+/// encoded, so that the JSON output of two snapshots can be diffed.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CredentialInventory {
+    pub existing_resident_credentials_count: u64,
+    pub max_possible_remaining_resident_credentials_count: u64,
+    pub relying_parties: Vec<RelyingPartyInventory>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RelyingPartyInventory {
+    pub id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    pub credentials: Vec<StoredCredential>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StoredCredential {
+    pub credential_id: String,
+    pub user_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user_name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user_display_name: Option<String>,
+    /// `None` if the device reported an unknown credProtect level.
+    pub cred_protect: Option<CredentialProtectionPolicy>,
+    pub has_large_blob_key: bool,
+}
+
+impl CredentialInventory {
+    pub(crate) fn new(
+        metadata: Ctap2CredentialManagementMetadata,
+        relying_parties: Vec<RelyingPartyInventory>,
+    ) -> Self {
+        Self {
+            existing_resident_credentials_count: metadata.existing_resident_credentials_count,
+            max_possible_remaining_resident_credentials_count: metadata
+                .max_possible_remaining_resident_credentials_count,
+            relying_parties,
+        }
+    }
+
+    /// Pretty-printed JSON representation of the inventory.
+    pub fn to_json(&self) -> Result<String, Error> {
+        serde_json::to_string_pretty(self).map_err(|e| {
+            warn!(%e, "Failed to serialize credential inventory");
+            Error::Platform(PlatformError::InvalidDeviceResponse)
+        })
+    }
+}
+
+impl RelyingPartyInventory {
+    pub(crate) fn new(rp: Ctap2RPData, credentials: Vec<Ctap2CredentialData>) -> Self {
+        Self {
+            id: rp.rp.id,
+            name: rp.rp.name,
+            credentials: credentials
+                .into_iter()
+                .map(StoredCredential::from)
+                .collect(),
+        }
+    }
+}
+
+impl From<Ctap2CredentialData> for StoredCredential {
+    fn from(cred: Ctap2CredentialData) -> Self {
+        let cred_protect = match cred.cred_protect {
+            1 => Some(CredentialProtectionPolicy::UserVerificationOptional),
+            2 => Some(CredentialProtectionPolicy::UserVerificationOptionalWithCredentialIDList),
+            3 => Some(CredentialProtectionPolicy::UserVerificationRequired),
+            _ => None,
+        };
+        Self {
+            credential_id: base64_url::encode(&cred.credential_id.id),
+            user_id: base64_url::encode(&cred.user.id),
+            user_name: cred.user.name,
+            user_display_name: cred.user.display_name,
+            cred_protect,
+            has_large_blob_key: cred.large_blob_key.is_some(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use cosey::{P256PublicKey, PublicKey};
+    use serde_bytes::ByteBuf;
+
+    use super::*;
+    use crate::proto::ctap2::{
+        Ctap2PublicKeyCredentialDescriptor, Ctap2PublicKeyCredentialRpEntity,
+        Ctap2PublicKeyCredentialType, Ctap2PublicKeyCredentialUserEntity,
+    };
+
+    fn credential(cred_protect: u64, large_blob_key: Option<Vec<u8>>) -> Ctap2CredentialData {
+        Ctap2CredentialData::new(
+            Ctap2PublicKeyCredentialUserEntity::new(&[0x01, 0x02], "alice", "Alice"),
+            Ctap2PublicKeyCredentialDescriptor {
+                id: ByteBuf::from([0xAA, 0xBB]),
+                r#type: Ctap2PublicKeyCredentialType::PublicKey,
+                transports: None,
+            },
+            PublicKey::P256Key(P256PublicKey {
+                x: Default::default(),
+                y: Default::default(),
+            }),
+            cred_protect,
+            large_blob_key,
+        )
+    }
+
+    #[test]
+    fn inventory_to_json() {
+        let rp = Ctap2RPData::new(
+            Ctap2PublicKeyCredentialRpEntity {
+                id: String::from("example.org"),
+                name: None,
+            },
+            vec![],
+        );
+        let inventory = CredentialInventory::new(
+            Ctap2CredentialManagementMetadata::new(2, 23),
+            vec![RelyingPartyInventory::new(
+                rp,
+                vec![credential(3, Some(vec![0; 32])), credential(7, None)],
+            )],
+        );
+        let json: serde_json::Value = serde_json::from_str(&inventory.to_json().unwrap()).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "existingResidentCredentialsCount": 2,
+                "maxPossibleRemainingResidentCredentialsCount": 23,
+                "relyingParties": [{
+                    "id": "example.org",
+                    "credentials": [{
+                        "credentialId": "qrs",
+                        "userId": "AQI",
+                        "userName": "alice",
+                        "userDisplayName": "Alice",
+                        "credProtect": "userVerificationRequired",
+                        "hasLargeBlobKey": true,
+                    }, {
+                        "credentialId": "qrs",
+                        "userId": "AQI",
+                        "userName": "alice",
+                        "userDisplayName": "Alice",
+                        "credProtect": null,
+                        "hasLargeBlobKey": false,
+                    }],
+                }],
+            })
+        );
+    }
+}
@@ -1,3 +1,4 @@
+use super::credential_inventory::{CredentialInventory, RelyingPartyInventory};
 use crate::proto::ctap2::cbor;
 use crate::{
     ops::webauthn::UserVerificationRequirement,
@@ -13,14 +14,15 @@ use crate::{
     webauthn::{
         error::{CtapError, Error, PlatformError},
         handle_errors,
-        pin_uv_auth_token::{user_verification, UsedPinUvAuthToken},
+        pin_uv_auth_token::{user_verification, UsedPinUvAuthToken, UvOptions},
     },
     UvUpdate,
 };
 use async_trait::async_trait;
+use futures::stream::{self, BoxStream, StreamExt, TryStreamExt};
 use serde_bytes::ByteBuf;
 use std::time::Duration;
-use tracing::info;
+use tracing::{debug, info, warn};
 
 #[async_trait]
 pub trait CredentialManagement {
@@ -40,17 +42,48 @@ pub trait CredentialManagement {
         &mut self,
         timeout: Duration,
     ) -> Result<Ctap2CredentialData, Error>;
+    /// Enumerates the RPs with discoverable credentials on the device, driving
+    /// enumerateRPsBegin and enumerateRPsGetNextRP until totalRPs have been returned.
+    /// The stream is empty if no discoverable credentials are stored, and ends after the
+    /// first error.
+    fn enumerate_rps(&mut self, timeout: Duration) -> BoxStream<'_, Result<Ctap2RPData, Error>>;
+    /// Enumerates the discoverable credentials of the RP with the given `rpid_hash`, driving
+    /// enumerateCredentialsBegin and enumerateCredentialsGetNextCredential until
+    /// totalCredentials have been returned. Ends after the first error.
+    fn enumerate_credentials(
+        &mut self,
+        rpid_hash: &[u8],
+        timeout: Duration,
+    ) -> BoxStream<'_, Result<Ctap2CredentialData, Error>>;
+    /// Deletes a discoverable credential, e.g. one returned by
+    /// [CredentialManagement::enumerate_credentials]. Fails with
+    /// [PlatformError::CredentialNotFound] if the device does not hold the credential.
     async fn delete_credential(
         &mut self,
         credential_id: &Ctap2PublicKeyCredentialDescriptor,
         timeout: Duration,
     ) -> Result<(), Error>;
+    /// Replaces the name and displayName of a discoverable credential's user. `user.id` must
+    /// match the stored user ID; omitted fields are removed. Not supported by devices only
+    /// implementing credentialMgmtPreview.
     async fn update_user_info(
         &mut self,
         credential_id: &Ctap2PublicKeyCredentialDescriptor,
         user: &Ctap2PublicKeyCredentialUserEntity,
         timeout: Duration,
     ) -> Result<(), Error>;
+    /// Collects the credential metadata, all RPs and all their discoverable credentials into
+    /// a serializable [CredentialInventory].
+    async fn get_credential_inventory(
+        &mut self,
+        timeout: Duration,
+    ) -> Result<CredentialInventory, Error>;
+}
+
+enum EnumerationState {
+    Begin,
+    Next { remaining: u64 },
+    Done,
 }
 
 #[async_trait]
@@ -66,7 +99,7 @@ where
         let resp = loop {
             let uv_auth_used = user_verification(
                 self,
-                UserVerificationRequirement::Preferred,
+                UvOptions::new(UserVerificationRequirement::Preferred),
                 &mut req,
                 timeout,
             )
@@ -95,7 +128,7 @@ where
         let resp = loop {
             let uv_auth_used = user_verification(
                 self,
-                UserVerificationRequirement::Preferred,
+                UvOptions::new(UserVerificationRequirement::Preferred),
                 &mut req,
                 timeout,
             )
@@ -123,7 +156,7 @@ where
         let resp = loop {
             let uv_auth_used = user_verification(
                 self,
-                UserVerificationRequirement::Preferred,
+                UvOptions::new(UserVerificationRequirement::Preferred),
                 &mut req,
                 timeout,
             )
@@ -152,7 +185,7 @@ where
         let resp = loop {
             let uv_auth_used = user_verification(
                 self,
-                UserVerificationRequirement::Preferred,
+                UvOptions::new(UserVerificationRequirement::Preferred),
                 &mut req,
                 timeout,
             )
@@ -185,7 +218,7 @@ where
         let resp = loop {
             let uv_auth_used = user_verification(
                 self,
-                UserVerificationRequirement::Preferred,
+                UvOptions::new(UserVerificationRequirement::Preferred),
                 &mut req,
                 timeout,
             )
@@ -209,16 +242,101 @@ where
         Ok(cred)
     }
 
+    fn enumerate_rps(&mut self, timeout: Duration) -> BoxStream<'_, Result<Ctap2RPData, Error>> {
+        stream::unfold(
+            (self, EnumerationState::Begin),
+            move |(channel, state)| async move {
+                let (result, next_state) = match state {
+                    EnumerationState::Done | EnumerationState::Next { remaining: 0 } => {
+                        return None
+                    }
+                    EnumerationState::Begin => match channel.enumerate_rps_begin(timeout).await {
+                        Ok((rp, total_rps)) => (
+                            Ok(rp),
+                            EnumerationState::Next {
+                                remaining: total_rps.saturating_sub(1),
+                            },
+                        ),
+                        // "If no discoverable credentials exist on this authenticator, return CTAP2_ERR_NO_CREDENTIALS."
+                        Err(Error::Ctap(CtapError::NoCredentials)) => {
+                            debug!("No discoverable credentials stored");
+                            return None;
+                        }
+                        Err(err) => (Err(err), EnumerationState::Done),
+                    },
+                    EnumerationState::Next { remaining } => {
+                        match channel.enumerate_rps_next_rp(timeout).await {
+                            Ok(rp) => (
+                                Ok(rp),
+                                EnumerationState::Next {
+                                    remaining: remaining - 1,
+                                },
+                            ),
+                            Err(err) => (Err(err), EnumerationState::Done),
+                        }
+                    }
+                };
+                Some((result, (channel, next_state)))
+            },
+        )
+        .boxed()
+    }
+
+    fn enumerate_credentials(
+        &mut self,
+        rpid_hash: &[u8],
+        timeout: Duration,
+    ) -> BoxStream<'_, Result<Ctap2CredentialData, Error>> {
+        let rpid_hash = rpid_hash.to_vec();
+        stream::unfold((self, EnumerationState::Begin), move |(channel, state)| {
+            let rpid_hash = rpid_hash.clone();
+            async move {
+                let (result, next_state) = match state {
+                    EnumerationState::Done | EnumerationState::Next { remaining: 0 } => {
+                        return None
+                    }
+                    EnumerationState::Begin => {
+                        match channel
+                            .enumerate_credentials_begin(&rpid_hash, timeout)
+                            .await
+                        {
+                            Ok((cred, total_credentials)) => (
+                                Ok(cred),
+                                EnumerationState::Next {
+                                    remaining: total_credentials.saturating_sub(1),
+                                },
+                            ),
+                            Err(err) => (Err(err), EnumerationState::Done),
+                        }
+                    }
+                    EnumerationState::Next { remaining } => {
+                        match channel.enumerate_credentials_next(timeout).await {
+                            Ok(cred) => (
+                                Ok(cred),
+                                EnumerationState::Next {
+                                    remaining: remaining - 1,
+                                },
+                            ),
+                            Err(err) => (Err(err), EnumerationState::Done),
+                        }
+                    }
+                };
+                Some((result, (channel, next_state)))
+            }
+        })
+        .boxed()
+    }
+
     async fn delete_credential(
         &mut self,
         credential_id: &Ctap2PublicKeyCredentialDescriptor,
         timeout: Duration,
     ) -> Result<(), Error> {
         let mut req = Ctap2CredentialManagementRequest::new_delete_credential(credential_id);
-        loop {
+        let result = loop {
             let uv_auth_used = user_verification(
                 self,
-                UserVerificationRequirement::Preferred,
+                UvOptions::new(UserVerificationRequirement::Preferred),
                 &mut req,
                 timeout,
             )
@@ -231,8 +349,16 @@ where
                 uv_auth_used,
                 timeout
             )
-        }?;
-        Ok(())
+        };
+        match result {
+            Ok(_) => Ok(()),
+            // "If there is no credential matching credentialId, return CTAP2_ERR_NO_CREDENTIALS."
+            Err(Error::Ctap(CtapError::NoCredentials)) => {
+                debug!("Credential to delete not found on the device");
+                Err(Error::Platform(PlatformError::CredentialNotFound))
+            }
+            Err(err) => Err(err),
+        }
     }
 
     async fn update_user_info(
@@ -243,20 +369,23 @@ where
     ) -> Result<(), Error> {
         let mut req =
             Ctap2CredentialManagementRequest::new_update_user_information(credential_id, user);
+        // Preview mode does not support "updateUserInfo" subcommand. Check this before
+        // user verification, so the user is not prompted for a PIN in vain.
+        let info = self.ctap2_get_info().await?;
+        req.handle_legacy_preview(&info);
+        if req.use_legacy_preview {
+            warn!("Device only supports credentialMgmtPreview, which lacks updateUserInformation");
+            return Err(Error::Ctap(CtapError::InvalidCommand));
+        }
         loop {
             let uv_auth_used = user_verification(
                 self,
-                UserVerificationRequirement::Preferred,
+                UvOptions::new(UserVerificationRequirement::Preferred),
                 &mut req,
                 timeout,
             )
             .await?;
 
-            // Preview mode does not support "updateUserInfo" subcommand
-            if req.use_legacy_preview {
-                return Err(Error::Ctap(CtapError::InvalidCommand));
-            }
-
             // On success, this is an all-empty Ctap2AuthenticatorConfigResponse
             handle_errors!(
                 self,
@@ -267,6 +396,40 @@ where
         }?;
         Ok(())
     }
+
+    async fn get_credential_inventory(
+        &mut self,
+        timeout: Duration,
+    ) -> Result<CredentialInventory, Error> {
+        let metadata = self.get_credential_metadata(timeout).await?;
+        let rps: Vec<Ctap2RPData> = self.enumerate_rps(timeout).try_collect().await?;
+        let mut relying_parties = Vec::with_capacity(rps.len());
+        for rp in rps {
+            let credentials = self
+                .enumerate_credentials(&rp.rp_id_hash, timeout)
+                .try_collect()
+                .await?;
+            relying_parties.push(RelyingPartyInventory::new(rp, credentials));
+        }
+        info!(
+            rps = relying_parties.len(),
+            "Collected credential inventory"
+        );
+        Ok(CredentialInventory::new(metadata, relying_parties))
+    }
+}
+
+impl Ctap2CredentialManagementRequest {
+    /// The message authenticated by pinUvAuthParam.
+    fn uv_auth_message(&self) -> Vec<u8> {
+        let mut data = vec![self.subcommand.unwrap() as u8];
+
+        // e.g. pinUvAuthParam (0x04): authenticate(pinUvAuthToken, enumerateCredentialsBegin (0x04) || subCommandParams).
+        if let Some(params) = &self.subcommand_params {
+            data.extend(cbor::to_vec_canonical(&params).unwrap());
+        }
+        data
+    }
 }
 
 impl Ctap2UserVerifiableRequest for Ctap2CredentialManagementRequest {
@@ -279,12 +442,7 @@ impl Ctap2UserVerifiableRequest for Ctap2CredentialManagementRequest {
         uv_proto: &Box<dyn PinUvAuthProtocol>,
         uv_auth_token: &[u8],
     ) {
-        let mut data = vec![self.subcommand.unwrap() as u8];
-
-        // e.g. pinUvAuthParam (0x04): authenticate(pinUvAuthToken, enumerateCredentialsBegin (0x04) || subCommandParams).
-        if let Some(params) = &self.subcommand_params {
-            data.extend(cbor::to_vec(&params).unwrap());
-        }
+        let data = self.uv_auth_message();
         let uv_auth_param = uv_proto.authenticate(uv_auth_token, &data);
         self.protocol = Some(uv_proto.version());
         self.uv_auth_param = Some(ByteBuf::from(uv_auth_param));
@@ -319,3 +477,185 @@ impl Ctap2UserVerifiableRequest for Ctap2CredentialManagementRequest {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::time::Duration;
+
+    use cosey::{P256PublicKey, PublicKey};
+    use futures::StreamExt;
+    use serde_bytes::ByteBuf;
+
+    use super::CredentialManagement;
+    use crate::proto::ctap2::{
+        Ctap2CommandCode, Ctap2CredentialManagementRequest, Ctap2CredentialManagementResponse,
+        Ctap2GetInfoResponse, Ctap2PublicKeyCredentialDescriptor, Ctap2PublicKeyCredentialRpEntity,
+        Ctap2PublicKeyCredentialType, Ctap2PublicKeyCredentialUserEntity,
+    };
+    use crate::proto::CtapError;
+    use crate::testing::MockChannel;
+    use crate::webauthn::Error;
+
+    const TIMEOUT: Duration = Duration::from_secs(1);
+
+    fn cred_mgmt_info() -> Ctap2GetInfoResponse {
+        Ctap2GetInfoResponse {
+            versions: vec!["FIDO_2_1".to_owned()],
+            options: Some(HashMap::from([("credMgmt".to_owned(), true)])),
+            ..Default::default()
+        }
+    }
+
+    fn rp_response(id: &str, total_rps: Option<u64>) -> Ctap2CredentialManagementResponse {
+        Ctap2CredentialManagementResponse {
+            rp: Some(Ctap2PublicKeyCredentialRpEntity::new(id, id)),
+            rp_id_hash: Some(ByteBuf::from(vec![id.len() as u8; 32])),
+            total_rps,
+            ..Default::default()
+        }
+    }
+
+    fn credential_response(
+        user_id: u8,
+        total_credentials: Option<u64>,
+    ) -> Ctap2CredentialManagementResponse {
+        Ctap2CredentialManagementResponse {
+            user: Some(Ctap2PublicKeyCredentialUserEntity::new(
+                &[user_id],
+                "alice",
+                "Alice",
+            )),
+            credential_id: Some(Ctap2PublicKeyCredentialDescriptor {
+                id: ByteBuf::from([user_id, 0xAA]),
+                r#type: Ctap2PublicKeyCredentialType::PublicKey,
+                transports: None,
+            }),
+            public_key: Some(PublicKey::P256Key(P256PublicKey {
+                x: Default::default(),
+                y: Default::default(),
+            })),
+            cred_protect: Some(1),
+            total_credentials,
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn rps_are_enumerated_until_total() {
+        let info = cred_mgmt_info();
+        let mut channel = MockChannel::new();
+        for response in [
+            rp_response("example.org", Some(2)),
+            rp_response("example.com", None),
+        ] {
+            channel
+                .expect(Ctap2CommandCode::AuthenticatorGetInfo, &info)
+                .expect(
+                    Ctap2CommandCode::AuthenticatorCredentialManagement,
+                    &response,
+                );
+        }
+        let rps: Vec<_> = channel.enumerate_rps(TIMEOUT).collect().await;
+        let ids: Vec<_> = rps.into_iter().map(|rp| rp.unwrap().rp.id).collect();
+        assert_eq!(ids, ["example.org", "example.com"]);
+        channel.assert_done();
+    }
+
+    #[tokio::test]
+    async fn no_credentials_is_an_empty_stream() {
+        let mut channel = MockChannel::new();
+        channel
+            .expect(Ctap2CommandCode::AuthenticatorGetInfo, &cred_mgmt_info())
+            .expect_error(
+                Ctap2CommandCode::AuthenticatorCredentialManagement,
+                CtapError::NoCredentials,
+            );
+        let rps: Vec<_> = channel.enumerate_rps(TIMEOUT).collect().await;
+        assert!(rps.is_empty());
+        channel.assert_done();
+    }
+
+    #[tokio::test]
+    async fn credentials_are_enumerated_until_total() {
+        let info = cred_mgmt_info();
+        let mut channel = MockChannel::new();
+        for response in [
+            credential_response(1, Some(3)),
+            credential_response(2, None),
+            credential_response(3, None),
+        ] {
+            channel
+                .expect(Ctap2CommandCode::AuthenticatorGetInfo, &info)
+                .expect(
+                    Ctap2CommandCode::AuthenticatorCredentialManagement,
+                    &response,
+                );
+        }
+        let credentials: Vec<_> = channel
+            .enumerate_credentials(&[0x11; 32], TIMEOUT)
+            .collect()
+            .await;
+        let user_ids: Vec<_> = credentials
+            .into_iter()
+            .map(|credential| credential.unwrap().user.id.into_vec())
+            .collect();
+        assert_eq!(user_ids, [[1], [2], [3]]);
+        channel.assert_done();
+    }
+
+    #[tokio::test]
+    async fn failed_credential_ends_stream() {
+        let info = cred_mgmt_info();
+        let mut channel = MockChannel::new();
+        channel
+            .expect(Ctap2CommandCode::AuthenticatorGetInfo, &info)
+            .expect(
+                Ctap2CommandCode::AuthenticatorCredentialManagement,
+                &credential_response(1, Some(3)),
+            )
+            .expect(Ctap2CommandCode::AuthenticatorGetInfo, &info)
+            .expect_error(
+                Ctap2CommandCode::AuthenticatorCredentialManagement,
+                CtapError::Other,
+            );
+        let credentials: Vec<_> = channel
+            .enumerate_credentials(&[0x11; 32], TIMEOUT)
+            .collect()
+            .await;
+        // The third credential isn't requested once the second one failed.
+        assert_eq!(credentials.len(), 2);
+        assert!(credentials[0].is_ok());
+        assert_eq!(
+            credentials[1].as_ref().unwrap_err(),
+            &Error::Ctap(CtapError::Other)
+        );
+        channel.assert_done();
+    }
+
+    #[test]
+    fn update_user_information_uv_auth_message() {
+        let credential_id = Ctap2PublicKeyCredentialDescriptor {
+            id: ByteBuf::from([0xAA]),
+            r#type: Ctap2PublicKeyCredentialType::PublicKey,
+            transports: None,
+        };
+        let user = Ctap2PublicKeyCredentialUserEntity::new(&[0x01], "a", "b");
+        let req =
+            Ctap2CredentialManagementRequest::new_update_user_information(&credential_id, &user);
+        let mut expected = vec![
+            0x07, // updateUserInformation
+            0xA2, // map(2)
+            0x02, // credentialID
+            0xA2, 0x62, b'i', b'd', 0x41, 0xAA, 0x64, b't', b'y', b'p', b'e', 0x6A,
+        ];
+        expected.extend(b"public-key");
+        expected.extend([
+            0x03, // user
+            0xA3, 0x62, b'i', b'd', 0x41, 0x01, 0x64, b'n', b'a', b'm', b'e', 0x61, b'a', 0x6B,
+        ]);
+        expected.extend(b"displayName");
+        expected.extend([0x61, b'b']);
+        assert_eq!(req.uv_auth_message(), expected);
+    }
+}
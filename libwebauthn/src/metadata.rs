@@ -0,0 +1,560 @@
+//! FIDO Metadata Service (MDS3) support.
+//!
+//! Downloads or accepts the MDS3 BLOB (<https://fidoalliance.org/specs/mds/fido-metadata-service-v3.0-ps-20210518.html>),
+//! verifies its JWS signature and certificate chain against a trusted root, and indexes the
+//! metadata statements by AAGUID and attestation certificate key identifier.
+//!
+//! The BLOB is signed by a certificate chaining up to GlobalSign Root CA - R3, which callers
+//! must provide, e.g. from <https://secure.globalsign.com/cacert/root-r3.crt>.
+//! Revocation of the BLOB signing certificates through CRLs is not checked.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use base64_url::base64::engine::general_purpose::STANDARD;
+use base64_url::base64::Engine;
+use http::header::{HOST, USER_AGENT};
+use http::uri::Scheme;
+use http::{Request, StatusCode, Uri, Version};
+use rustls::pki_types::ServerName;
+use rustls::{ClientConfig, RootCertStore};
+use serde::Deserialize;
+use time::{Date, Month, OffsetDateTime};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio_rustls::TlsConnector;
+use tracing::{debug, info, instrument, warn};
+use uuid::Uuid;
+use x509_parser::prelude::{FromDer, X509Certificate};
+
+use crate::verify::certificate::parse_chain;
+use crate::verify::jws::Jws;
+use crate::verify::VerificationError;
+
+pub const MDS3_URL: &str = "https://mds3.fidoalliance.org/";
+
+#[derive(thiserror::Error, Debug, PartialEq)]
+pub enum MetadataError {
+    #[error("failed to download metadata: {0}")]
+    Download(String),
+    #[error("malformed metadata BLOB")]
+    Malformed,
+    #[error("metadata verification failed: {0}")]
+    Verification(#[from] VerificationError),
+    #[error("certificate chain does not lead to a trusted root")]
+    UntrustedRoot,
+    #[error("certificate is not valid at the current time")]
+    CertificateExpired,
+    /// The BLOB's nextUpdate date has passed, a newer BLOB should be downloaded.
+    #[error("metadata BLOB is stale, a new one was due on {0}")]
+    Stale(String),
+    #[error("authenticator has a compromised status: {0:?}")]
+    Compromised(AuthenticatorStatus),
+    #[error("no metadata for this authenticator")]
+    UnknownAuthenticator,
+}
+
+/// Authenticator status, see <https://fidoalliance.org/specs/mds/fido-metadata-service-v3.0-ps-20210518.html#authenticatorstatus-enum>
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum AuthenticatorStatus {
+    NotFidoCertified,
+    FidoCertified,
+    UserVerificationBypass,
+    AttestationKeyCompromise,
+    UserKeyRemoteCompromise,
+    UserKeyPhysicalCompromise,
+    UpdateAvailable,
+    Revoked,
+    SelfAssertionSubmitted,
+    #[serde(rename = "FIDO_CERTIFIED_L1")]
+    FidoCertifiedL1,
+    #[serde(rename = "FIDO_CERTIFIED_L1plus")]
+    FidoCertifiedL1Plus,
+    #[serde(rename = "FIDO_CERTIFIED_L2")]
+    FidoCertifiedL2,
+    #[serde(rename = "FIDO_CERTIFIED_L2plus")]
+    FidoCertifiedL2Plus,
+    #[serde(rename = "FIDO_CERTIFIED_L3")]
+    FidoCertifiedL3,
+    #[serde(rename = "FIDO_CERTIFIED_L3plus")]
+    FidoCertifiedL3Plus,
+    #[serde(other)]
+    Unknown,
+}
+
+impl AuthenticatorStatus {
+    /// Whether credentials from authenticators with this status should no longer be trusted.
+    pub fn is_compromised(&self) -> bool {
+        matches!(
+            self,
+            Self::UserVerificationBypass
+                | Self::AttestationKeyCompromise
+                | Self::UserKeyRemoteCompromise
+                | Self::UserKeyPhysicalCompromise
+                | Self::Revoked
+        )
+    }
+
+    pub fn is_certified(&self) -> bool {
+        matches!(
+            self,
+            Self::FidoCertified
+                | Self::FidoCertifiedL1
+                | Self::FidoCertifiedL1Plus
+                | Self::FidoCertifiedL2
+                | Self::FidoCertifiedL2Plus
+                | Self::FidoCertifiedL3
+                | Self::FidoCertifiedL3Plus
+        )
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StatusReport {
+    pub status: AuthenticatorStatus,
+    pub effective_date: Option<String>,
+    pub certificate: Option<String>,
+    pub url: Option<String>,
+}
+
+/// The parts of a metadata statement relevant to attestation verification.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MetadataStatement {
+    pub description: String,
+    pub authenticator_version: u32,
+    pub protocol_family: String,
+    #[serde(default)]
+    pub attestation_types: Vec<String>,
+    /// Base64 DER certificates.
+    #[serde(default)]
+    pub attestation_root_certificates: Vec<String>,
+    pub icon: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MetadataEntry {
+    pub aaid: Option<String>,
+    pub aaguid: Option<Uuid>,
+    pub attestation_certificate_key_identifiers: Option<Vec<String>>,
+    pub metadata_statement: Option<MetadataStatement>,
+    #[serde(default)]
+    pub status_reports: Vec<StatusReport>,
+    pub time_of_last_status_change: String,
+}
+
+impl MetadataEntry {
+    /// The most recent status report, if any.
+    pub fn latest_status(&self) -> Option<AuthenticatorStatus> {
+        self.status_reports
+            .iter()
+            .max_by(|a, b| a.effective_date.cmp(&b.effective_date))
+            .map(|report| report.status)
+    }
+
+    pub fn is_certified(&self) -> bool {
+        self.status_reports.iter().any(|r| r.status.is_certified())
+    }
+
+    /// The first compromised status reported for this authenticator, if any.
+    pub fn compromised_status(&self) -> Option<AuthenticatorStatus> {
+        self.status_reports
+            .iter()
+            .map(|r| r.status)
+            .find(AuthenticatorStatus::is_compromised)
+    }
+
+    /// DER attestation root certificates from the metadata statement.
+    pub fn attestation_roots(&self) -> Vec<Vec<u8>> {
+        let Some(statement) = &self.metadata_statement else {
+            return vec![];
+        };
+        statement
+            .attestation_root_certificates
+            .iter()
+            .filter_map(|c| STANDARD.decode(c).ok())
+            .collect()
+    }
+
+    /// Checks a leaf-first attestation certificate chain leads to one of this authenticator's
+    /// attestation roots, and that the authenticator hasn't been reported as compromised.
+    pub fn verify_attestation_trust(&self, certificates: &[Vec<u8>]) -> Result<(), MetadataError> {
+        if let Some(status) = self.compromised_status() {
+            warn!(?status, "Authenticator has a compromised status");
+            return Err(MetadataError::Compromised(status));
+        }
+        let chain = parse_chain(certificates)?;
+        let last = chain.last().expect("chains are never empty");
+        for root in self.attestation_roots() {
+            // The attestation chain may or may not include the root itself.
+            if certificates.last() == Some(&root) {
+                return Ok(());
+            }
+            let Ok((_, root)) = X509Certificate::from_der(&root) else {
+                continue;
+            };
+            if last.verify_signature(Some(root.public_key())).is_ok() {
+                return Ok(());
+            }
+        }
+        Err(MetadataError::UntrustedRoot)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct MetadataBlobPayload {
+    legal_header: Option<String>,
+    no: u64,
+    next_update: String,
+    entries: Vec<MetadataEntry>,
+}
+
+/// A verified MDS3 BLOB, indexed for lookups.
+#[derive(Debug, Clone)]
+pub struct MetadataService {
+    /// Serial number of the BLOB, increases with each update.
+    pub serial: u64,
+    /// Date (YYYY-MM-DD) by which a newer BLOB will be available.
+    pub next_update: String,
+    pub legal_header: Option<String>,
+    entries: Vec<MetadataEntry>,
+    by_aaguid: HashMap<Uuid, usize>,
+    by_key_identifier: HashMap<String, usize>,
+}
+
+impl MetadataService {
+    /// Downloads the BLOB from `url` (e.g. [MDS3_URL]) and verifies it against `root_certificate` (DER).
+    #[instrument(skip(root_certificate))]
+    pub async fn download(url: &str, root_certificate: &[u8]) -> Result<Self, MetadataError> {
+        let blob = https_get(url).await?;
+        info!({ len = blob.len() }, "Downloaded metadata BLOB");
+        Self::from_blob(&blob, root_certificate)
+    }
+
+    /// Verifies a BLOB obtained by other means against `root_certificate` (DER). BLOBs past
+    /// their nextUpdate date are rejected as stale.
+    pub fn from_blob(blob: &[u8], root_certificate: &[u8]) -> Result<Self, MetadataError> {
+        let jws = std::str::from_utf8(blob)
+            .ok()
+            .and_then(|blob| Jws::parse(blob.trim()))
+            .ok_or(MetadataError::Malformed)?;
+        let certificates = jws.certificates().ok_or(MetadataError::Malformed)?;
+        let chain = parse_chain(&certificates)?;
+        let (_, root) = X509Certificate::from_der(root_certificate)
+            .or(Err(VerificationError::MalformedCertificate))?;
+        let last = chain.last().expect("chains are never empty");
+        if certificates.last().map(Vec::as_slice) != Some(root_certificate)
+            && last.verify_signature(Some(root.public_key())).is_err()
+        {
+            return Err(MetadataError::UntrustedRoot);
+        }
+        if !chain.iter().chain([&root]).all(|c| c.validity().is_valid()) {
+            return Err(MetadataError::CertificateExpired);
+        }
+        jws.verify(&chain[0])?;
+        let service = Self::from_payload(&jws.payload)?;
+        service.check_fresh(OffsetDateTime::now_utc().date())?;
+        Ok(service)
+    }
+
+    /// Fails if `today` is past the BLOB's nextUpdate date.
+    fn check_fresh(&self, today: Date) -> Result<(), MetadataError> {
+        let next_update = parse_date(&self.next_update).ok_or_else(|| {
+            warn!(next_update = self.next_update, "Malformed nextUpdate date");
+            MetadataError::Malformed
+        })?;
+        if today > next_update {
+            warn!(next_update = self.next_update, "Metadata BLOB is stale");
+            return Err(MetadataError::Stale(self.next_update.clone()));
+        }
+        Ok(())
+    }
+
+    fn from_payload(payload: &[u8]) -> Result<Self, MetadataError> {
+        let payload: MetadataBlobPayload = serde_json::from_slice(payload).map_err(|e| {
+            warn!(%e, "Failed to parse metadata BLOB payload");
+            MetadataError::Malformed
+        })?;
+        let mut by_aaguid = HashMap::new();
+        let mut by_key_identifier = HashMap::new();
+        for (i, entry) in payload.entries.iter().enumerate() {
+            if let Some(aaguid) = entry.aaguid {
+                by_aaguid.insert(aaguid, i);
+            }
+            for key_id in entry
+                .attestation_certificate_key_identifiers
+                .iter()
+                .flatten()
+            {
+                by_key_identifier.insert(key_id.to_lowercase(), i);
+            }
+        }
+        debug!(
+            { serial = payload.no, entries = payload.entries.len() },
+            "Loaded metadata BLOB"
+        );
+        Ok(Self {
+            serial: payload.no,
+            next_update: payload.next_update,
+            legal_header: payload.legal_header,
+            entries: payload.entries,
+            by_aaguid,
+            by_key_identifier,
+        })
+    }
+
+    pub fn entries(&self) -> &[MetadataEntry] {
+        &self.entries
+    }
+
+    /// Looks up a FIDO2 authenticator.
+    pub fn entry(&self, aaguid: &Uuid) -> Option<&MetadataEntry> {
+        self.by_aaguid.get(aaguid).map(|&i| &self.entries[i])
+    }
+
+    /// The description of a FIDO2 authenticator in its metadata statement, e.g.
+    /// "YubiKey 5 Series with NFC".
+    pub fn product_name(&self, aaguid: &Uuid) -> Option<&str> {
+        let statement = self.entry(aaguid)?.metadata_statement.as_ref()?;
+        Some(statement.description.as_str())
+    }
+
+    /// Looks up a U2F authenticator by the hex SHA-1 of its attestation certificate's public key.
+    pub fn entry_for_key_identifier(&self, key_identifier: &str) -> Option<&MetadataEntry> {
+        self.by_key_identifier
+            .get(&key_identifier.to_lowercase())
+            .map(|&i| &self.entries[i])
+    }
+
+    /// Checks the attestation certificate chain of an authenticator against its metadata.
+    pub fn verify_attestation_trust(
+        &self,
+        aaguid: &Uuid,
+        certificates: &[Vec<u8>],
+    ) -> Result<&MetadataEntry, MetadataError> {
+        let entry = self
+            .entry(aaguid)
+            .ok_or(MetadataError::UnknownAuthenticator)?;
+        entry.verify_attestation_trust(certificates)?;
+        Ok(entry)
+    }
+}
+
+/// Parses a date in the YYYY-MM-DD format used by MDS3.
+fn parse_date(date: &str) -> Option<Date> {
+    let mut parts = date.splitn(3, '-');
+    let year = parts.next()?.parse().ok()?;
+    let month: u8 = parts.next()?.parse().ok()?;
+    let day = parts.next()?.parse().ok()?;
+    Date::from_calendar_date(year, Month::try_from(month).ok()?, day).ok()
+}
+
+async fn https_get(url: &str) -> Result<Vec<u8>, MetadataError> {
+    let download_error = |e: &dyn std::fmt::Display| MetadataError::Download(e.to_string());
+    let uri: Uri = url.parse().map_err(|e| download_error(&e))?;
+    if uri.scheme() != Some(&Scheme::HTTPS) {
+        return Err(MetadataError::Download(
+            "only https URLs are supported".into(),
+        ));
+    }
+    let (Some(host), Some(authority)) = (uri.host(), uri.authority()) else {
+        return Err(MetadataError::Download("URL has no host".into()));
+    };
+    // HTTP/1.0, so the body is never chunked and ends when the server closes the connection.
+    let request = Request::get(&uri)
+        .version(Version::HTTP_10)
+        .header(HOST, authority.as_str())
+        .header(USER_AGENT, "libwebauthn")
+        .body(())
+        .map_err(|e| download_error(&e))?;
+
+    let mut roots = RootCertStore::empty();
+    roots.add_parsable_certificates(rustls_native_certs::load_native_certs().certs);
+    let config =
+        ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .map_err(|e| download_error(&e))?
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+    let server_name = ServerName::try_from(host.to_owned()).map_err(|e| download_error(&e))?;
+    let tcp = TcpStream::connect((host, uri.port_u16().unwrap_or(443)))
+        .await
+        .map_err(|e| download_error(&e))?;
+    let mut stream = TlsConnector::from(Arc::new(config))
+        .connect(server_name, tcp)
+        .await
+        .map_err(|e| download_error(&e))?;
+
+    stream
+        .write_all(&encode_request(&request))
+        .await
+        .map_err(|e| download_error(&e))?;
+    let mut response = vec![];
+    if let Err(e) = stream.read_to_end(&mut response).await {
+        // Some servers close the connection without a TLS close_notify.
+        if e.kind() != std::io::ErrorKind::UnexpectedEof || response.is_empty() {
+            return Err(download_error(&e));
+        }
+    }
+
+    let (status, body_start) = parse_response(&response)?;
+    if !status.is_success() {
+        return Err(MetadataError::Download(status.to_string()));
+    }
+    Ok(response.split_off(body_start))
+}
+
+fn encode_request(request: &Request<()>) -> Vec<u8> {
+    let target = request
+        .uri()
+        .path_and_query()
+        .map_or("/", |path| path.as_str());
+    let mut encoded =
+        format!("{} {target} {:?}\r\n", request.method(), request.version()).into_bytes();
+    for (name, value) in request.headers() {
+        encoded.extend_from_slice(name.as_str().as_bytes());
+        encoded.extend_from_slice(b": ");
+        encoded.extend_from_slice(value.as_bytes());
+        encoded.extend_from_slice(b"\r\n");
+    }
+    encoded.extend_from_slice(b"\r\n");
+    encoded
+}
+
+/// Returns the status of a complete response, and where its body starts.
+fn parse_response(response: &[u8]) -> Result<(StatusCode, usize), MetadataError> {
+    let malformed = || MetadataError::Download("malformed HTTP response".into());
+    let mut headers = [httparse::EMPTY_HEADER; 64];
+    let mut parsed = httparse::Response::new(&mut headers);
+    let httparse::Status::Complete(body_start) = parsed.parse(response).map_err(|_| malformed())?
+    else {
+        return Err(malformed());
+    };
+    let status = parsed
+        .code
+        .and_then(|code| StatusCode::from_u16(code).ok())
+        .ok_or_else(malformed)?;
+    Ok((status, body_start))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const AAGUID: &str = "ee882879-721c-4913-9775-3dfcce97072a";
+
+    fn payload() -> String {
+        format!(
+            r#"{{
+                "legalHeader": "Retrieval and use of this BLOB indicates acceptance of the appropriate agreement.",
+                "no": 42,
+                "nextUpdate": "2024-06-01",
+                "entries": [
+                    {{
+                        "aaguid": "{AAGUID}",
+                        "metadataStatement": {{
+                            "description": "Test Key",
+                            "authenticatorVersion": 2,
+                            "protocolFamily": "fido2",
+                            "attestationTypes": ["basic_full"],
+                            "attestationRootCertificates": ["AQID"],
+                            "upv": [{{"major": 1, "minor": 1}}]
+                        }},
+                        "statusReports": [
+                            {{"status": "FIDO_CERTIFIED_L1", "effectiveDate": "2020-01-01"}},
+                            {{"status": "UPDATE_AVAILABLE", "effectiveDate": "2021-01-01"}}
+                        ],
+                        "timeOfLastStatusChange": "2021-01-01"
+                    }},
+                    {{
+                        "attestationCertificateKeyIdentifiers": ["AB12CD"],
+                        "statusReports": [
+                            {{"status": "ATTESTATION_KEY_COMPROMISE", "effectiveDate": "2022-01-01"}}
+                        ],
+                        "timeOfLastStatusChange": "2022-01-01"
+                    }}
+                ]
+            }}"#
+        )
+    }
+
+    #[test]
+    fn parse_and_look_up_entries() {
+        let service = MetadataService::from_payload(payload().as_bytes()).unwrap();
+        assert_eq!(service.serial, 42);
+        assert_eq!(service.next_update, "2024-06-01");
+
+        let entry = service.entry(&Uuid::parse_str(AAGUID).unwrap()).unwrap();
+        assert_eq!(
+            service.product_name(&Uuid::parse_str(AAGUID).unwrap()),
+            Some("Test Key")
+        );
+        assert!(entry.is_certified());
+        assert_eq!(
+            entry.latest_status(),
+            Some(AuthenticatorStatus::UpdateAvailable)
+        );
+        assert_eq!(entry.compromised_status(), None);
+        assert_eq!(entry.attestation_roots(), vec![vec![1, 2, 3]]);
+
+        let u2f = service.entry_for_key_identifier("ab12cd").unwrap();
+        assert!(!u2f.is_certified());
+        assert_eq!(
+            u2f.verify_attestation_trust(&[]),
+            Err(MetadataError::Compromised(
+                AuthenticatorStatus::AttestationKeyCompromise
+            ))
+        );
+        assert!(service.entry(&Uuid::nil()).is_none());
+    }
+
+    #[test]
+    fn reject_stale_blob() {
+        let service = MetadataService::from_payload(payload().as_bytes()).unwrap();
+        let date = |day| Date::from_calendar_date(2024, Month::June, day).unwrap();
+        assert_eq!(service.check_fresh(date(1)), Ok(()));
+        assert_eq!(
+            service.check_fresh(date(2)),
+            Err(MetadataError::Stale("2024-06-01".into()))
+        );
+    }
+
+    #[test]
+    fn http_roundtrip() {
+        let request = Request::get(MDS3_URL)
+            .version(Version::HTTP_10)
+            .header(HOST, "mds3.fidoalliance.org")
+            .body(())
+            .unwrap();
+        assert_eq!(
+            encode_request(&request),
+            b"GET / HTTP/1.0\r\nhost: mds3.fidoalliance.org\r\n\r\n"
+        );
+
+        let response = b"HTTP/1.1 200 OK\r\nContent-Type: application/jwt\r\n\r\nblob";
+        let (status, body_start) = parse_response(response).unwrap();
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(&response[body_start..], b"blob");
+        assert_eq!(
+            parse_response(b"HTTP/1.1 404 Not Found\r\n\r\n").unwrap().0,
+            StatusCode::NOT_FOUND
+        );
+        assert!(parse_response(b"HTTP/1.1 200 OK\r\n").is_err());
+    }
+
+    #[test]
+    fn reject_malformed_blob() {
+        assert_eq!(
+            MetadataService::from_blob(b"not-a-jws", &[]).unwrap_err(),
+            MetadataError::Malformed
+        );
+        assert_eq!(
+            MetadataService::from_payload(b"{}").unwrap_err(),
+            MetadataError::Malformed
+        );
+    }
+}
@@ -0,0 +1,148 @@
+//! Metrics hooks for monitoring authenticators at scale.
+//!
+//! Embedders install a [MetricsRecorder] on a channel with `Channel::set_metrics_recorder`,
+//! e.g. one forwarding to the `metrics` crate or to Prometheus. The library then reports the
+//! duration and outcome of every high-level ceremony on that channel, and its PIN and built-in
+//! UV attempts. caBLE devices also report tunnel connect times to the recorder set with their
+//! `with_metrics_recorder`, and pass it on to their channels. Without a recorder, nothing is
+//! recorded.
+
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+
+use crate::webauthn::error::{CtapError, Error};
+
+/// Receives the library's metrics. All methods default to doing nothing, so implementations
+/// only need to override what they're interested in.
+///
+/// Methods are called inline from ceremonies, and should hand the data off quickly.
+pub trait MetricsRecorder: Send + Sync {
+    /// A high-level ceremony completed, successfully or not.
+    fn record_operation(&self, _metrics: &OperationMetrics<'_>) {}
+
+    /// The user verified with a PIN or built-in UV, successfully or not.
+    fn record_uv_attempt(&self, _method: UvAttemptMethod, _success: bool) {}
+
+    /// The caBLE tunnel server accepted the connection, `elapsed` after connecting started.
+    fn record_tunnel_connect(&self, _tunnel_domain: &str, _elapsed: Duration) {}
+}
+
+impl fmt::Debug for dyn MetricsRecorder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("MetricsRecorder")
+    }
+}
+
+/// Records the outcome of a ceremony started at `started`.
+pub(crate) fn record_operation<T>(
+    recorder: &dyn MetricsRecorder,
+    operation: Operation,
+    transport: &'static str,
+    started: Instant,
+    result: &Result<T, Error>,
+) {
+    recorder.record_operation(&OperationMetrics {
+        operation,
+        transport,
+        duration: started.elapsed(),
+        error: result.as_ref().err(),
+    });
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Operation {
+    MakeCredential,
+    GetAssertion,
+}
+
+impl Operation {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::MakeCredential => "make_credential",
+            Self::GetAssertion => "get_assertion",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum UvAttemptMethod {
+    Pin,
+    BuiltInUv,
+}
+
+#[derive(Debug)]
+pub struct OperationMetrics<'a> {
+    pub operation: Operation,
+    /// The channel's transport, see `Channel::transport_name`.
+    pub transport: &'static str,
+    /// Time from the start of the ceremony until it completed, including user interaction.
+    pub duration: Duration,
+    /// Why the ceremony failed, if it did.
+    pub error: Option<&'a Error>,
+}
+
+impl OperationMetrics<'_> {
+    pub fn succeeded(&self) -> bool {
+        self.error.is_none()
+    }
+
+    /// The CTAP status code the ceremony failed with, if the authenticator reported one.
+    pub fn ctap_error(&self) -> Option<CtapError> {
+        match self.error {
+            Some(Error::Ctap(error)) => Some(*error),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+
+    #[derive(Default)]
+    struct TestRecorder {
+        operations: Mutex<Vec<(Operation, &'static str, Option<CtapError>)>>,
+    }
+
+    impl MetricsRecorder for TestRecorder {
+        fn record_operation(&self, metrics: &OperationMetrics<'_>) {
+            self.operations.lock().unwrap().push((
+                metrics.operation,
+                metrics.transport,
+                metrics.ctap_error(),
+            ));
+        }
+    }
+
+    #[test]
+    fn records_operation_outcome() {
+        let recorder = TestRecorder::default();
+        let result: Result<(), Error> = Err(Error::Ctap(CtapError::PINInvalid));
+        record_operation(
+            &recorder,
+            Operation::GetAssertion,
+            "hid",
+            Instant::now(),
+            &result,
+        );
+        record_operation(
+            &recorder,
+            Operation::MakeCredential,
+            "cable",
+            Instant::now(),
+            &Ok(()),
+        );
+        assert_eq!(
+            *recorder.operations.lock().unwrap(),
+            vec![
+                (Operation::GetAssertion, "hid", Some(CtapError::PINInvalid)),
+                (Operation::MakeCredential, "cable", None),
+            ]
+        );
+    }
+}
@@ -0,0 +1,278 @@
+//! Offline login secrets, derived with the hmac-secret extension.
+//!
+//! As in pam-u2f's HMAC mode, a PAM module or screen locker can check a security key without
+//! any relying party: the device computes an HMAC of a random salt, with a key bound to the
+//! credential, and only the salt and a hash of the result are stored. Each successful
+//! verification rotates the salt, fetching the next secret in the same request, so that a
+//! recorded device response can't be replayed.
+//!
+//! The credential must have been created with the hmac-secret extension, see
+//! `MakeCredentialHmacOrPrfInput::HmacGetSecret`.
+
+use std::fmt;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+use sha2::{Digest, Sha256};
+use tracing::{instrument, warn};
+
+use crate::ops::webauthn::{
+    GetAssertionHmacOrPrfInput, GetAssertionRequest, GetAssertionRequestExtensions,
+    HMACGetSecretInput, HMACGetSecretOutput, UserVerificationRequirement,
+};
+use crate::proto::ctap2::{Ctap2PublicKeyCredentialDescriptor, Ctap2PublicKeyCredentialType};
+use crate::redact::REDACTED;
+use crate::sources;
+use crate::transport::Channel;
+use crate::webauthn::{Error, PlatformError, WebAuthn};
+
+/// Version of the storage format written by [OfflineSecret::to_json].
+pub const OFFLINE_SECRET_FORMAT_VERSION: u32 = 1;
+
+/// What is stored about an enrolled credential, to verify it offline.
+#[derive(Clone, PartialEq, Eq)]
+pub struct OfflineSecret {
+    pub rp_id: String,
+    pub credential_id: Vec<u8>,
+    /// Chosen on enrollment, and required again on every verification.
+    pub user_verification: UserVerificationRequirement,
+    /// The salt to send on the next verification.
+    salt: [u8; 32],
+    /// SHA-256 of the device's HMAC of `salt`.
+    secret_hash: [u8; 32],
+}
+
+impl fmt::Debug for OfflineSecret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("OfflineSecret")
+            .field("rp_id", &self.rp_id)
+            .field("credential_id", &self.credential_id)
+            .field("user_verification", &self.user_verification)
+            .field("salt", &REDACTED)
+            .field("secret_hash", &REDACTED)
+            .finish()
+    }
+}
+
+/// The storage format, with binary fields in base64url.
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct StoredOfflineSecret {
+    version: u32,
+    rp_id: String,
+    credential_id: String,
+    user_verification: UserVerificationRequirement,
+    salt: String,
+    secret_hash: String,
+}
+
+impl OfflineSecret {
+    /// Enrolls `credential_id`, which must support hmac-secret, fetching the secret of a
+    /// first random salt. Later verifications use the same `user_verification`.
+    #[instrument(skip(channel, credential_id))]
+    pub async fn enroll<C: Channel>(
+        channel: &mut C,
+        rp_id: &str,
+        credential_id: &[u8],
+        user_verification: UserVerificationRequirement,
+        timeout: Duration,
+    ) -> Result<Self, Error> {
+        let salt = random_bytes();
+        let output = get_secret(
+            channel,
+            rp_id,
+            credential_id,
+            HMACGetSecretInput {
+                salt1: salt,
+                salt2: None,
+            },
+            user_verification,
+            timeout,
+        )
+        .await?;
+        Ok(Self {
+            rp_id: rp_id.to_owned(),
+            credential_id: credential_id.to_vec(),
+            user_verification,
+            salt,
+            secret_hash: Sha256::digest(output.output1).into(),
+        })
+    }
+
+    /// Checks that the device holds the enrolled credential, and rotates the salt. On
+    /// success, `self` must be stored again, replacing the previous version.
+    #[instrument(skip_all, fields(rp_id = self.rp_id))]
+    pub async fn verify_and_rotate<C: Channel>(
+        &mut self,
+        channel: &mut C,
+        timeout: Duration,
+    ) -> Result<(), Error> {
+        let next_salt = random_bytes();
+        let output = get_secret(
+            channel,
+            &self.rp_id,
+            &self.credential_id,
+            HMACGetSecretInput {
+                salt1: self.salt,
+                salt2: Some(next_salt),
+            },
+            self.user_verification,
+            timeout,
+        )
+        .await?;
+        self.check(&output.output1)?;
+        let Some(next_secret) = output.output2 else {
+            warn!("Device did not return the secret of the second salt");
+            return Err(Error::Platform(PlatformError::InvalidDeviceResponse));
+        };
+        self.salt = next_salt;
+        self.secret_hash = Sha256::digest(next_secret).into();
+        Ok(())
+    }
+
+    fn check(&self, secret: &[u8; 32]) -> Result<(), Error> {
+        let hash: [u8; 32] = Sha256::digest(secret).into();
+        // Compares in constant time.
+        let difference = hash
+            .iter()
+            .zip(self.secret_hash.iter())
+            .fold(0, |acc, (a, b)| acc | (a ^ b));
+        if difference != 0 {
+            warn!("Device's secret does not match the enrolled one");
+            return Err(Error::Platform(PlatformError::SecretMismatch));
+        }
+        Ok(())
+    }
+
+    pub fn to_json(&self) -> String {
+        let stored = StoredOfflineSecret {
+            version: OFFLINE_SECRET_FORMAT_VERSION,
+            rp_id: self.rp_id.clone(),
+            credential_id: base64_url::encode(&self.credential_id),
+            user_verification: self.user_verification,
+            salt: base64_url::encode(&self.salt),
+            secret_hash: base64_url::encode(&self.secret_hash),
+        };
+        serde_json::to_string(&stored).expect("Serializing strings can't fail")
+    }
+
+    pub fn from_json(json: &str) -> Result<Self, Error> {
+        let stored: StoredOfflineSecret = serde_json::from_str(json).map_err(|e| {
+            warn!(%e, "Invalid stored offline secret");
+            Error::Platform(PlatformError::SyntaxError)
+        })?;
+        if stored.version != OFFLINE_SECRET_FORMAT_VERSION {
+            warn!(
+                stored.version,
+                "Unsupported version of stored offline secret"
+            );
+            return Err(Error::Platform(PlatformError::SyntaxError));
+        }
+        Ok(Self {
+            rp_id: stored.rp_id,
+            credential_id: decode(&stored.credential_id)?,
+            user_verification: stored.user_verification,
+            salt: decode_32(&stored.salt)?,
+            secret_hash: decode_32(&stored.secret_hash)?,
+        })
+    }
+}
+
+fn random_bytes() -> [u8; 32] {
+    sources::random()
+}
+
+fn decode(encoded: &str) -> Result<Vec<u8>, Error> {
+    base64_url::decode(encoded).map_err(|e| {
+        warn!(%e, "Invalid base64url value in stored offline secret");
+        Error::Platform(PlatformError::SyntaxError)
+    })
+}
+
+fn decode_32(encoded: &str) -> Result<[u8; 32], Error> {
+    decode(encoded)?.try_into().map_err(|_| {
+        warn!("Stored salt or hash is not 32 bytes long");
+        Error::Platform(PlatformError::SyntaxError)
+    })
+}
+
+async fn get_secret<C: Channel>(
+    channel: &mut C,
+    rp_id: &str,
+    credential_id: &[u8],
+    salts: HMACGetSecretInput,
+    user_verification: UserVerificationRequirement,
+    timeout: Duration,
+) -> Result<HMACGetSecretOutput, Error> {
+    // Nothing is signed for a relying party, the challenge only has to be fresh.
+    let request = GetAssertionRequest::builder()
+        .rp_id(rp_id)
+        .client_data_hash(&random_bytes())
+        .allow_credential(Ctap2PublicKeyCredentialDescriptor {
+            id: ByteBuf::from(credential_id),
+            r#type: Ctap2PublicKeyCredentialType::PublicKey,
+            transports: None,
+        })
+        .extensions(GetAssertionRequestExtensions {
+            hmac_or_prf: GetAssertionHmacOrPrfInput::HmacGetSecret(salts),
+            ..Default::default()
+        })
+        .user_verification(user_verification)
+        .timeout(timeout)
+        .build()?;
+    let response = channel.webauthn_get_assertion(&request).await?;
+    let output = response
+        .assertions
+        .into_iter()
+        .next()
+        .and_then(|assertion| assertion.unsigned_extensions_output)
+        .and_then(|extensions| extensions.hmac_get_secret);
+    output.ok_or_else(|| {
+        warn!("Device returned no hmac-secret output, the credential may not support it");
+        Error::Platform(PlatformError::NotSupported)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use sha2::{Digest, Sha256};
+
+    use super::OfflineSecret;
+    use crate::ops::webauthn::UserVerificationRequirement;
+    use crate::webauthn::{Error, PlatformError};
+
+    fn enrolled() -> OfflineSecret {
+        OfflineSecret {
+            rp_id: "pam://localhost".to_owned(),
+            credential_id: vec![1, 2, 3],
+            user_verification: UserVerificationRequirement::Required,
+            salt: [7; 32],
+            secret_hash: Sha256::digest([9; 32]).into(),
+        }
+    }
+
+    #[test]
+    fn stored_format_round_trips() {
+        let secret = enrolled();
+        let json = secret.to_json();
+        assert!(json.starts_with(r#"{"version":1,"rpId":"pam://localhost","credentialId":"AQID","userVerification":"required""#));
+        assert_eq!(OfflineSecret::from_json(&json).unwrap(), secret);
+
+        let future = json.replace(r#""version":1"#, r#""version":2"#);
+        assert_eq!(
+            OfflineSecret::from_json(&future),
+            Err(Error::Platform(PlatformError::SyntaxError))
+        );
+    }
+
+    #[test]
+    fn only_the_enrolled_secret_matches() {
+        let secret = enrolled();
+        assert_eq!(secret.check(&[9; 32]), Ok(()));
+        assert_eq!(
+            secret.check(&[8; 32]),
+            Err(Error::Platform(PlatformError::SecretMismatch))
+        );
+    }
+}
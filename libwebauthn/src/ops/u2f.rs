@@ -7,11 +7,14 @@ use tracing::{error, trace};
 use x509_parser::nom::AsBytes;
 
 use super::webauthn::MakeCredentialRequest;
-use crate::fido::{AttestedCredentialData, AuthenticatorData, AuthenticatorDataFlags};
+use crate::fido::{
+    AttestedCredentialData, AuthenticatorData, AuthenticatorDataFlags, FidoProtocol,
+};
 use crate::ops::webauthn::{
-    GetAssertionRequest, GetAssertionResponse, MakeCredentialResponse, UserVerificationRequirement,
+    AlwaysUvPolicy, GetAssertionRequest, GetAssertionResponse, MakeCredentialResponse,
+    UserVerificationRequirement, UvMethodPreference,
 };
-use crate::proto::ctap1::{Ctap1RegisterRequest, Ctap1SignRequest};
+use crate::proto::ctap1::{Ctap1RegisterRequest, Ctap1RegisteredKey, Ctap1SignRequest};
 use crate::proto::ctap1::{Ctap1RegisterResponse, Ctap1SignResponse};
 use crate::proto::ctap2::cbor;
 use crate::proto::ctap2::{
@@ -43,6 +46,61 @@ impl SignRequest {
     }
 }
 
+/// A U2F authentication with any of several registered keys, as in the U2F JavaScript API's
+/// `sign()`: the first key handle the device knows is used.
+#[derive(Debug, Clone)]
+pub struct AuthenticateRequest {
+    /// The hash of the AppID, for registered keys without their own.
+    pub app_id_hash: Vec<u8>,
+    /// The challenge parameter, i.e. the SHA-256 hash of the client data.
+    pub challenge: Vec<u8>,
+    pub registered_keys: Vec<Ctap1RegisteredKey>,
+    pub timeout: Duration,
+}
+
+impl AuthenticateRequest {
+    pub fn new(
+        app_id: &str,
+        challenge: &[u8],
+        registered_keys: Vec<Ctap1RegisteredKey>,
+        timeout: Duration,
+    ) -> Self {
+        Self {
+            app_id_hash: Sha256::digest(app_id).to_vec(),
+            challenge: Vec::from(challenge),
+            registered_keys,
+            timeout,
+        }
+    }
+
+    /// A sign request for each registered key, in order, hashing their own AppID if any.
+    pub fn sign_requests(&self) -> Vec<SignRequest> {
+        self.registered_keys
+            .iter()
+            .map(|key| SignRequest {
+                app_id_hash: key
+                    .app_id
+                    .as_ref()
+                    .map(|app_id| Sha256::digest(app_id).to_vec())
+                    .unwrap_or_else(|| self.app_id_hash.clone()),
+                challenge: self.challenge.clone(),
+                key_handle: key.key_handle.clone(),
+                timeout: self.timeout,
+                require_user_presence: true,
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct AuthenticateResponse {
+    /// The key handle the device signed with.
+    pub key_handle: Vec<u8>,
+    /// The hash of the AppID it was used with.
+    pub app_id_hash: Vec<u8>,
+    pub response: SignResponse,
+}
+
 pub trait UpgradableResponse<T, R> {
     fn try_upgrade(&self, request: &R) -> Result<T, Error>;
 }
@@ -78,7 +136,7 @@ impl UpgradableResponse<MakeCredentialResponse, MakeCredentialRequest> for Regis
             x: x.into(),
             y: y.into(),
         });
-        let cose_encoded_public_key = cbor::to_vec(&cose_public_key)?;
+        let cose_encoded_public_key = cbor::to_vec_canonical(&cose_public_key)?;
         assert!(cose_encoded_public_key.len() == 77);
 
         // Let attestedCredData be a byte string with following structure:
@@ -147,7 +205,9 @@ impl UpgradableResponse<MakeCredentialResponse, MakeCredentialRequest> for Regis
             enterprise_attestation: None,
             large_blob_key: None,
         };
-        Ok(resp.into_make_credential_output(request, None))
+        let mut upgraded_response = resp.into_make_credential_output(request, None);
+        upgraded_response.protocol = FidoProtocol::U2F;
+        Ok(upgraded_response)
     }
 }
 
@@ -213,12 +273,54 @@ impl UpgradableResponse<GetAssertionResponse, SignRequest> for SignResponse {
                 UserVerificationRequirement::Preferred
             },
             timeout: request.timeout.clone(),
+            always_uv_policy: AlwaysUvPolicy::default(),
+            uv_method_preference: UvMethodPreference::default(),
+            platform_uv_attempts: None,
+            additional_permissions: Default::default(),
+            ctap1_fallback: Default::default(),
         };
-        let upgraded_response = [response.into_assertion_output(&orig_request, None)]
-            .as_slice()
-            .into();
+        let mut upgraded_response: GetAssertionResponse =
+            [response.into_assertion_output(&orig_request, None)]
+                .as_slice()
+                .into();
+        upgraded_response.protocol = FidoProtocol::U2F;
 
         trace!(?upgraded_response);
         Ok(upgraded_response)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use sha2::{Digest, Sha256};
+
+    use super::AuthenticateRequest;
+    use crate::proto::ctap1::Ctap1RegisteredKey;
+
+    #[test]
+    fn sign_requests_use_each_key_app_id() {
+        let mut legacy_key = Ctap1RegisteredKey::new_u2f_v2(&[2; 64]);
+        legacy_key.app_id = Some("https://legacy.example.org".to_owned());
+        let request = AuthenticateRequest::new(
+            "https://example.org",
+            &[0; 32],
+            vec![Ctap1RegisteredKey::new_u2f_v2(&[1; 64]), legacy_key],
+            Duration::from_secs(10),
+        );
+
+        let sign_requests = request.sign_requests();
+        assert_eq!(sign_requests.len(), 2);
+        assert_eq!(
+            sign_requests[0].app_id_hash,
+            Sha256::digest("https://example.org").to_vec()
+        );
+        assert_eq!(
+            sign_requests[1].app_id_hash,
+            Sha256::digest("https://legacy.example.org").to_vec()
+        );
+        assert_eq!(sign_requests[1].key_handle, [2; 64]);
+        assert!(sign_requests.iter().all(|r| r.require_user_presence));
+    }
+}
@@ -1,24 +1,30 @@
 mod get_assertion;
 mod make_credential;
 
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
 use super::u2f::{RegisterRequest, SignRequest};
+use crate::webauthn::error::{Error, PlatformError};
 use crate::webauthn::CtapError;
 pub use get_assertion::{
     Assertion, Ctap2HMACGetSecretOutput, GetAssertionHmacOrPrfInput,
     GetAssertionLargeBlobExtension, GetAssertionLargeBlobExtensionOutput, GetAssertionPrfOutput,
-    GetAssertionRequest, GetAssertionRequestExtensions, GetAssertionResponse,
-    GetAssertionResponseExtensions, GetAssertionResponseUnsignedExtensions, HMACGetSecretInput,
-    HMACGetSecretOutput, PRFValue,
+    GetAssertionRequest, GetAssertionRequestBuilder, GetAssertionRequestExtensions,
+    GetAssertionResponse, GetAssertionResponseExtensions, GetAssertionResponseUnsignedExtensions,
+    HMACGetSecretInput, HMACGetSecretOutput, PRFValue, SignCountCheck,
 };
 pub use make_credential::{
     CredentialPropsExtension, CredentialProtectionExtension, CredentialProtectionPolicy,
     MakeCredentialHmacOrPrfInput, MakeCredentialLargeBlobExtension,
     MakeCredentialLargeBlobExtensionOutput, MakeCredentialPrfOutput, MakeCredentialRequest,
-    MakeCredentialResponse, MakeCredentialsRequestExtensions, MakeCredentialsResponseExtensions,
-    MakeCredentialsResponseUnsignedExtensions, ResidentKeyRequirement,
+    MakeCredentialRequestBuilder, MakeCredentialResponse, MakeCredentialsRequestExtensions,
+    MakeCredentialsResponseExtensions, MakeCredentialsResponseUnsignedExtensions,
+    ResidentKeyRequirement,
 };
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
 pub enum UserVerificationRequirement {
     Required,
     Preferred,
@@ -43,11 +49,58 @@ impl UserVerificationRequirement {
     }
 }
 
+/// What to do when the RP discourages user verification, but the device enforces
+/// it anyway because its `alwaysUv` option is enabled.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum AlwaysUvPolicy {
+    /// Perform user verification as required by the device.
+    #[default]
+    Proceed,
+    /// Perform user verification, but send `UvUpdate::AlwaysUvEnforced` first.
+    Warn,
+    /// Fail with `PlatformError::AlwaysUvEnforced` instead of verifying the user.
+    Abort,
+}
+
+/// Which user verification method to attempt, when a device supports both built-in UV
+/// (e.g. a fingerprint) and a PIN.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum UvMethodPreference {
+    /// Use built-in UV, falling back to the PIN if it's blocked.
+    #[default]
+    PreferBio,
+    /// Use the PIN if one is set, otherwise built-in UV.
+    PreferPin,
+    /// Only ever ask for the PIN.
+    PinOnly,
+    /// Only ever use built-in UV, even if it's blocked.
+    BioOnly,
+}
+
+/// When to fall back from FIDO2 to CTAP1/U2F. Requests U2F can't fulfill, e.g. requiring UV
+/// or a discoverable credential, never fall back.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Ctap1Fallback {
+    /// Only use FIDO2, failing on U2F-only devices.
+    Never,
+    /// Use U2F if the device doesn't support FIDO2, or if its PIN is blocked.
+    #[default]
+    WhenRequired,
+    /// Use U2F whenever the device supports it, e.g. to exercise legacy credentials.
+    Always,
+}
+
 pub trait DowngradableRequest<T> {
     fn is_downgradable(&self) -> bool;
     fn try_downgrade(&self) -> Result<T, CtapError>;
 }
 
+/// Rejects a request built with an invalid option, see the request builders.
+fn invalid_request(operation: &str, reason: &str) -> Error {
+    warn!(operation, reason, "Invalid request");
+    Error::Platform(PlatformError::SyntaxError)
+}
+
 #[cfg(test)]
 mod tests {
     use crate::ops::webauthn::make_credential::ResidentKeyRequirement;
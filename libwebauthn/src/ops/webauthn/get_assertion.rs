@@ -5,18 +5,24 @@ use sha2::{Digest, Sha256};
 use tracing::{debug, error, trace};
 
 use crate::{
-    fido::AuthenticatorData,
+    audit::AuditUvMethod,
+    fido::{AuthenticatorData, CredentialBackupState, FidoProtocol},
     pin::PinUvAuthProtocol,
     proto::ctap2::{
-        Ctap2AttestationStatement, Ctap2GetAssertionResponseExtensions,
-        Ctap2PublicKeyCredentialDescriptor, Ctap2PublicKeyCredentialUserEntity,
+        Ctap2AttestationStatement, Ctap2AuthTokenPermissionRole,
+        Ctap2GetAssertionResponseExtensions, Ctap2PublicKeyCredentialDescriptor,
+        Ctap2PublicKeyCredentialUserEntity,
     },
-    webauthn::CtapError,
+    redact::{redact, REDACTED},
+    webauthn::error::{CtapError, Error},
 };
 
-use super::{DowngradableRequest, SignRequest, UserVerificationRequirement};
+use super::{
+    invalid_request, AlwaysUvPolicy, Ctap1Fallback, DowngradableRequest, SignRequest,
+    UserVerificationRequirement, UvMethodPreference,
+};
 
-#[derive(Debug, Default, Clone, Serialize)]
+#[derive(Default, Clone, Serialize)]
 pub struct PRFValue {
     #[serde(with = "serde_bytes")]
     pub first: [u8; 32],
@@ -24,6 +30,15 @@ pub struct PRFValue {
     pub second: Option<[u8; 32]>,
 }
 
+impl std::fmt::Debug for PRFValue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PRFValue")
+            .field("first", &REDACTED)
+            .field("second", &redact(&self.second))
+            .finish()
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct GetAssertionRequest {
     pub relying_party_id: String,
@@ -32,6 +47,178 @@ pub struct GetAssertionRequest {
     pub extensions: Option<GetAssertionRequestExtensions>,
     pub user_verification: UserVerificationRequirement,
     pub timeout: Duration,
+    /// Handling of alwaysUv devices when `user_verification` is discouraged
+    pub always_uv_policy: AlwaysUvPolicy,
+    /// Which user verification method to attempt first
+    pub uv_method_preference: UvMethodPreference,
+    /// Built-in UV failures to allow before falling back to PIN, overriding the
+    /// authenticator's `preferredPlatformUvAttempts`
+    pub platform_uv_attempts: Option<u32>,
+    /// Permissions to request for the pinUvAuthToken besides GetAssertion's own, so that
+    /// follow-up operations reuse it instead of prompting for the PIN again
+    pub additional_permissions: Ctap2AuthTokenPermissionRole,
+    /// When to fall back to CTAP1/U2F
+    pub ctap1_fallback: Ctap1Fallback,
+}
+
+impl GetAssertionRequest {
+    pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(60);
+
+    /// Starts building a request. The RP ID and client data hash are required, other
+    /// options default as in WebAuthn.
+    pub fn builder() -> GetAssertionRequestBuilder {
+        GetAssertionRequestBuilder::default()
+    }
+
+    /// The AppID of the appid extension, if requested.
+    pub fn app_id(&self) -> Option<&str> {
+        self.extensions.as_ref()?.app_id.as_deref()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct GetAssertionRequestBuilder {
+    relying_party_id: Option<String>,
+    hash: Option<Vec<u8>>,
+    allow: Vec<Ctap2PublicKeyCredentialDescriptor>,
+    extensions: Option<GetAssertionRequestExtensions>,
+    user_verification: UserVerificationRequirement,
+    timeout: Duration,
+    always_uv_policy: AlwaysUvPolicy,
+    uv_method_preference: UvMethodPreference,
+    platform_uv_attempts: Option<u32>,
+    additional_permissions: Ctap2AuthTokenPermissionRole,
+    ctap1_fallback: Ctap1Fallback,
+}
+
+impl Default for GetAssertionRequestBuilder {
+    fn default() -> Self {
+        Self {
+            relying_party_id: None,
+            hash: None,
+            allow: vec![],
+            extensions: None,
+            user_verification: UserVerificationRequirement::Preferred,
+            timeout: GetAssertionRequest::DEFAULT_TIMEOUT,
+            always_uv_policy: AlwaysUvPolicy::default(),
+            uv_method_preference: UvMethodPreference::default(),
+            platform_uv_attempts: None,
+            additional_permissions: Ctap2AuthTokenPermissionRole::empty(),
+            ctap1_fallback: Ctap1Fallback::default(),
+        }
+    }
+}
+
+impl GetAssertionRequestBuilder {
+    pub fn rp_id(mut self, relying_party_id: &str) -> Self {
+        self.relying_party_id = Some(relying_party_id.to_owned());
+        self
+    }
+
+    /// The SHA-256 hash of the client data.
+    pub fn client_data_hash(mut self, hash: &[u8]) -> Self {
+        self.hash = Some(hash.to_vec());
+        self
+    }
+
+    /// Adds a credential which may be asserted. Without any, discoverable credentials
+    /// are used.
+    pub fn allow_credential(mut self, credential: Ctap2PublicKeyCredentialDescriptor) -> Self {
+        self.allow.push(credential);
+        self
+    }
+
+    pub fn extensions(mut self, extensions: GetAssertionRequestExtensions) -> Self {
+        self.extensions = Some(extensions);
+        self
+    }
+
+    /// Defaults to preferred.
+    pub fn user_verification(mut self, requirement: UserVerificationRequirement) -> Self {
+        self.user_verification = requirement;
+        self
+    }
+
+    /// Bounds the whole ceremony, defaults to [GetAssertionRequest::DEFAULT_TIMEOUT].
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn always_uv_policy(mut self, policy: AlwaysUvPolicy) -> Self {
+        self.always_uv_policy = policy;
+        self
+    }
+
+    pub fn uv_method_preference(mut self, preference: UvMethodPreference) -> Self {
+        self.uv_method_preference = preference;
+        self
+    }
+
+    pub fn ctap1_fallback(mut self, fallback: Ctap1Fallback) -> Self {
+        self.ctap1_fallback = fallback;
+        self
+    }
+
+    pub fn platform_uv_attempts(mut self, attempts: u32) -> Self {
+        self.platform_uv_attempts = Some(attempts);
+        self
+    }
+
+    /// Adds permissions to request for the pinUvAuthToken, see
+    /// [GetAssertionRequest::additional_permissions].
+    pub fn additional_permissions(mut self, permissions: Ctap2AuthTokenPermissionRole) -> Self {
+        self.additional_permissions |= permissions;
+        self
+    }
+
+    /// Fails with `PlatformError::SyntaxError` if a required option is missing, or an
+    /// option is invalid.
+    pub fn build(self) -> Result<GetAssertionRequest, Error> {
+        let Some(relying_party_id) = self.relying_party_id else {
+            return Err(invalid_request(
+                "GetAssertion",
+                "relying party ID is missing",
+            ));
+        };
+        if relying_party_id.is_empty() {
+            return Err(invalid_request("GetAssertion", "relying party ID is empty"));
+        }
+        let Some(hash) = self.hash else {
+            return Err(invalid_request(
+                "GetAssertion",
+                "client data hash is missing",
+            ));
+        };
+        if hash.len() != 32 {
+            return Err(invalid_request(
+                "GetAssertion",
+                "client data hash is not a SHA-256 hash",
+            ));
+        }
+        if self.allow.iter().any(|credential| credential.id.is_empty()) {
+            return Err(invalid_request(
+                "GetAssertion",
+                "allowed credential ID is empty",
+            ));
+        }
+        if self.timeout.is_zero() {
+            return Err(invalid_request("GetAssertion", "timeout is zero"));
+        }
+        Ok(GetAssertionRequest {
+            relying_party_id,
+            hash,
+            allow: self.allow,
+            extensions: self.extensions,
+            user_verification: self.user_verification,
+            timeout: self.timeout,
+            always_uv_policy: self.always_uv_policy,
+            uv_method_preference: self.uv_method_preference,
+            platform_uv_attempts: self.platform_uv_attempts,
+            additional_permissions: self.additional_permissions,
+            ctap1_fallback: self.ctap1_fallback,
+        })
+    }
 }
 
 #[derive(Debug, Default, Clone)]
@@ -77,12 +264,15 @@ pub struct GetAssertionLargeBlobExtensionOutput {
 
 #[derive(Debug, Default, Clone)]
 pub struct GetAssertionRequestExtensions {
+    /// appid: the AppID credentials in the allow list may have been registered with over U2F.
+    /// They are asserted with the RP ID first, then with the AppID.
+    pub app_id: Option<String>,
     pub cred_blob: Option<bool>,
     pub hmac_or_prf: GetAssertionHmacOrPrfInput,
     pub large_blob: GetAssertionLargeBlobExtension,
 }
 
-#[derive(Clone, Debug, Default, Serialize)]
+#[derive(Clone, Default, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct HMACGetSecretOutput {
     pub output1: [u8; 32],
@@ -90,7 +280,16 @@ pub struct HMACGetSecretOutput {
     pub output2: Option<[u8; 32]>,
 }
 
-#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+impl std::fmt::Debug for HMACGetSecretOutput {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("HMACGetSecretOutput")
+            .field("output1", &REDACTED)
+            .field("output2", &redact(&self.output2))
+            .finish()
+    }
+}
+
+#[derive(Clone, Default, Serialize, Deserialize)]
 #[serde(transparent)]
 pub struct Ctap2HMACGetSecretOutput {
     // We get this from the device, but have to decrypt it, and
@@ -99,6 +298,14 @@ pub struct Ctap2HMACGetSecretOutput {
     pub(crate) encrypted_output: Vec<u8>,
 }
 
+impl std::fmt::Debug for Ctap2HMACGetSecretOutput {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Ctap2HMACGetSecretOutput")
+            .field("encrypted_output", &REDACTED)
+            .finish()
+    }
+}
+
 impl Ctap2HMACGetSecretOutput {
     pub(crate) fn decrypt_output(
         &self,
@@ -133,6 +340,9 @@ pub type GetAssertionResponseExtensions = Ctap2GetAssertionResponseExtensions;
 #[derive(Debug, Default, Clone, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct GetAssertionResponseUnsignedExtensions {
+    /// Whether the AppID was used instead of the RP ID, if the appid extension was requested.
+    #[serde(rename = "appid", skip_serializing_if = "Option::is_none")]
+    pub app_id: Option<bool>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub hmac_get_secret: Option<HMACGetSecretOutput>,
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -141,12 +351,18 @@ pub struct GetAssertionResponseUnsignedExtensions {
     pub prf: Option<GetAssertionPrfOutput>,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize)]
 pub struct GetAssertionResponse {
     pub assertions: Vec<Assertion>,
+    /// True if the RP discouraged UV, but the device's alwaysUv option enforced it.
+    pub always_uv_enforced: bool,
+    /// The protocol the assertions were made with, U2F if the request fell back to it.
+    pub protocol: FidoProtocol,
+    /// How the user was verified in this ceremony.
+    pub uv_method: AuditUvMethod,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize)]
 pub struct Assertion {
     pub credential_id: Option<Ctap2PublicKeyCredentialDescriptor>,
     pub authenticator_data: AuthenticatorData<GetAssertionResponseExtensions>,
@@ -160,10 +376,63 @@ pub struct Assertion {
     pub attestation_statement: Option<Ctap2AttestationStatement>,
 }
 
+/// How an assertion's signature counter compares to the one stored by the relying party, as
+/// in step 22 of WebAuthn's assertion verification.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SignCountCheck {
+    /// The counter increased.
+    Increased,
+    /// Both counters are zero: the authenticator doesn't implement a signature counter.
+    NotSupported,
+    /// The counter didn't increase, a sign that the authenticator may have been cloned.
+    Regressed,
+}
+
+impl SignCountCheck {
+    pub fn new(previous: u32, current: u32) -> Self {
+        if previous == 0 && current == 0 {
+            Self::NotSupported
+        } else if current > previous {
+            Self::Increased
+        } else {
+            Self::Regressed
+        }
+    }
+}
+
+impl Assertion {
+    /// The BE and BS flags of `authenticator_data`.
+    pub fn backup_state(&self) -> CredentialBackupState {
+        self.authenticator_data.backup_state()
+    }
+
+    /// Compares the signature counter with `previous`, the one stored for the credential.
+    /// Relying parties should store the new counter, unless it
+    /// [regressed](SignCountCheck::Regressed).
+    pub fn check_sign_count(&self, previous: u32) -> SignCountCheck {
+        SignCountCheck::new(previous, self.authenticator_data.signature_count)
+    }
+
+    /// Sets the appid extension's output, if requested: whether the assertion was made with
+    /// the AppID, rather than the RP ID.
+    pub(crate) fn set_app_id_output(&mut self, request: &GetAssertionRequest) {
+        let Some(app_id) = request.app_id() else {
+            return;
+        };
+        let used = self.authenticator_data.rp_id_hash[..] == Sha256::digest(app_id)[..];
+        self.unsigned_extensions_output
+            .get_or_insert_with(Default::default)
+            .app_id = Some(used);
+    }
+}
+
 impl From<&[Assertion]> for GetAssertionResponse {
     fn from(assertions: &[Assertion]) -> Self {
         Self {
             assertions: assertions.to_owned(),
+            always_uv_enforced: false,
+            protocol: FidoProtocol::FIDO2,
+            uv_method: AuditUvMethod::None,
         }
     }
 }
@@ -172,6 +441,9 @@ impl From<Assertion> for GetAssertionResponse {
     fn from(assertion: Assertion) -> Self {
         Self {
             assertions: vec![assertion],
+            always_uv_enforced: false,
+            protocol: FidoProtocol::FIDO2,
+            uv_method: AuditUvMethod::None,
         }
     }
 }
@@ -195,7 +467,7 @@ impl DowngradableRequest<Vec<SignRequest>> for GetAssertionRequest {
 
     fn try_downgrade(&self) -> Result<Vec<SignRequest>, CtapError> {
         trace!(?self);
-        let downgraded_requests: Vec<SignRequest> = self
+        let mut downgraded_requests: Vec<SignRequest> = self
             .allow
             .iter()
             .map(|credential| {
@@ -223,7 +495,115 @@ impl DowngradableRequest<Vec<SignRequest>> for GetAssertionRequest {
                 SignRequest::new_upgraded(&rp_id_hash, challenge, credential_id, self.timeout)
             })
             .collect();
+        // With the appid extension, credentials registered over U2F are tried with the AppID
+        // after the RP ID.
+        let app_id_requests = self.app_id().map(|app_id| {
+            let app_id_hash = Sha256::digest(app_id);
+            self.allow.iter().map(move |credential| {
+                SignRequest::new_upgraded(&app_id_hash, &self.hash, &credential.id, self.timeout)
+            })
+        });
+        downgraded_requests.extend(app_id_requests.into_iter().flatten());
         trace!(?downgraded_requests);
         Ok(downgraded_requests)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use serde_bytes::ByteBuf;
+    use sha2::{Digest, Sha256};
+
+    use super::{GetAssertionRequest, GetAssertionRequestExtensions, SignCountCheck};
+    use crate::ops::webauthn::{DowngradableRequest, UserVerificationRequirement};
+    use crate::proto::ctap2::{Ctap2PublicKeyCredentialDescriptor, Ctap2PublicKeyCredentialType};
+    use crate::webauthn::error::{Error, PlatformError};
+
+    fn credential(id: &[u8]) -> Ctap2PublicKeyCredentialDescriptor {
+        Ctap2PublicKeyCredentialDescriptor {
+            id: ByteBuf::from(id),
+            r#type: Ctap2PublicKeyCredentialType::PublicKey,
+            transports: None,
+        }
+    }
+
+    #[test]
+    fn builder_applies_webauthn_defaults() {
+        let request = GetAssertionRequest::builder()
+            .rp_id("example.org")
+            .client_data_hash(&[1; 32])
+            .allow_credential(credential(b"credential"))
+            .build()
+            .unwrap();
+        assert_eq!(request.relying_party_id, "example.org");
+        assert_eq!(request.allow[0].id.as_slice(), b"credential");
+        assert!(request.extensions.is_none());
+        assert!(matches!(
+            request.user_verification,
+            UserVerificationRequirement::Preferred
+        ));
+        assert_eq!(request.timeout, GetAssertionRequest::DEFAULT_TIMEOUT);
+    }
+
+    #[test]
+    fn builder_rejects_invalid_requests() {
+        let valid = GetAssertionRequest::builder()
+            .rp_id("example.org")
+            .client_data_hash(&[1; 32]);
+        assert!(valid.clone().build().is_ok());
+
+        for invalid in [
+            GetAssertionRequest::builder().client_data_hash(&[1; 32]),
+            valid.clone().rp_id(""),
+            valid.clone().client_data_hash(&[]),
+            valid.clone().allow_credential(credential(b"")),
+        ] {
+            assert!(matches!(
+                invalid.build(),
+                Err(Error::Platform(PlatformError::SyntaxError))
+            ));
+        }
+    }
+
+    #[test]
+    fn sign_count_regressions() {
+        assert_eq!(SignCountCheck::new(0, 0), SignCountCheck::NotSupported);
+        assert_eq!(SignCountCheck::new(0, 1), SignCountCheck::Increased);
+        assert_eq!(SignCountCheck::new(41, 42), SignCountCheck::Increased);
+        assert_eq!(SignCountCheck::new(42, 42), SignCountCheck::Regressed);
+        assert_eq!(SignCountCheck::new(42, 7), SignCountCheck::Regressed);
+        // A counter reset to zero is a regression too.
+        assert_eq!(SignCountCheck::new(42, 0), SignCountCheck::Regressed);
+    }
+
+    #[test]
+    fn appid_is_tried_after_rp_id() {
+        let request = GetAssertionRequest::builder()
+            .rp_id("example.org")
+            .client_data_hash(&[1; 32])
+            .allow_credential(credential(b"first"))
+            .allow_credential(credential(b"second"))
+            .extensions(GetAssertionRequestExtensions {
+                app_id: Some("https://example.org/appid.json".to_owned()),
+                ..Default::default()
+            })
+            .build()
+            .unwrap();
+
+        let sign_requests = request.try_downgrade().unwrap();
+        let app_id_hash = Sha256::digest("https://example.org/appid.json").to_vec();
+        let app_ids: Vec<_> = sign_requests
+            .iter()
+            .map(|r| (r.key_handle.as_slice(), r.app_id_hash == app_id_hash))
+            .collect();
+        assert_eq!(
+            app_ids,
+            [
+                (&b"first"[..], false),
+                (&b"second"[..], false),
+                (&b"first"[..], true),
+                (&b"second"[..], true)
+            ]
+        );
+    }
+}
@@ -3,24 +3,29 @@ use std::time::Duration;
 use ctap_types::ctap2::credential_management::CredentialProtectionPolicy as Ctap2CredentialProtectionPolicy;
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
-use tracing::{debug, instrument, trace};
+use tracing::{debug, instrument, trace, warn};
 
 use crate::{
-    fido::AuthenticatorData,
+    audit::AuditUvMethod,
+    fido::{AuthenticatorData, CredentialBackupState, FidoProtocol},
     proto::{
         ctap1::{Ctap1RegisteredKey, Ctap1Version},
         ctap2::{
-            Ctap2AttestationStatement, Ctap2COSEAlgorithmIdentifier, Ctap2CredentialType,
-            Ctap2GetInfoResponse, Ctap2MakeCredentialsResponseExtensions,
+            Ctap2AttestationStatement, Ctap2AuthTokenPermissionRole, Ctap2COSEAlgorithmIdentifier,
+            Ctap2CredentialType, Ctap2GetInfoResponse, Ctap2MakeCredentialsResponseExtensions,
             Ctap2PublicKeyCredentialDescriptor, Ctap2PublicKeyCredentialRpEntity,
             Ctap2PublicKeyCredentialUserEntity,
         },
     },
+    webauthn::error::Error,
 };
 
-use super::{DowngradableRequest, RegisterRequest, UserVerificationRequirement};
+use super::{
+    invalid_request, AlwaysUvPolicy, Ctap1Fallback, DowngradableRequest, RegisterRequest,
+    UserVerificationRequirement, UvMethodPreference,
+};
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize)]
 pub struct MakeCredentialResponse {
     pub format: String,
     pub authenticator_data: AuthenticatorData<MakeCredentialsResponseExtensions>,
@@ -28,6 +33,14 @@ pub struct MakeCredentialResponse {
     pub enterprise_attestation: Option<bool>,
     pub large_blob_key: Option<Vec<u8>>,
     pub unsigned_extensions_output: MakeCredentialsResponseUnsignedExtensions,
+    /// True if the RP discouraged UV, but the device's alwaysUv option enforced it.
+    pub always_uv_enforced: bool,
+    /// The protocol the credential was created with, U2F if the request fell back to it.
+    pub protocol: FidoProtocol,
+    /// The BE and BS flags of `authenticator_data`.
+    pub backup_state: CredentialBackupState,
+    /// How the user was verified in this ceremony.
+    pub uv_method: AuditUvMethod,
 }
 
 #[derive(Debug, Default, Clone, Serialize)]
@@ -173,6 +186,23 @@ pub struct MakeCredentialRequest {
     /// extensions
     pub extensions: Option<MakeCredentialsRequestExtensions>,
     pub timeout: Duration,
+    /// Handling of alwaysUv devices when `user_verification` is discouraged
+    pub always_uv_policy: AlwaysUvPolicy,
+    /// Which user verification method to attempt first
+    pub uv_method_preference: UvMethodPreference,
+    /// Built-in UV failures to allow before falling back to PIN, overriding the
+    /// authenticator's `preferredPlatformUvAttempts`
+    pub platform_uv_attempts: Option<u32>,
+    /// Permissions to request for the pinUvAuthToken besides MakeCredential's own, so that
+    /// follow-up operations, e.g. credential management, reuse it instead of prompting for
+    /// the PIN again
+    pub additional_permissions: Ctap2AuthTokenPermissionRole,
+    /// attestationFormats: the RP's preferred attestation statement formats, most preferred
+    /// first. Only sent to authenticators listing their formats in getInfo, others use their
+    /// default format.
+    pub attestation_formats: Vec<String>,
+    /// When to fall back to CTAP1/U2F
+    pub ctap1_fallback: Ctap1Fallback,
 }
 
 #[derive(Debug, Default, Clone)]
@@ -294,10 +324,227 @@ impl MakeCredentialRequest {
             resident_key: None,
             user_verification: UserVerificationRequirement::Discouraged,
             timeout: Duration::from_secs(10),
+            always_uv_policy: AlwaysUvPolicy::default(),
+            uv_method_preference: UvMethodPreference::default(),
+            platform_uv_attempts: None,
+            additional_permissions: Ctap2AuthTokenPermissionRole::empty(),
+            attestation_formats: vec![],
+            ctap1_fallback: Ctap1Fallback::default(),
         }
     }
 }
 
+impl MakeCredentialRequest {
+    /// Longest WebAuthn user handle, in bytes.
+    pub const MAX_USER_ID_LENGTH: usize = 64;
+    pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(60);
+
+    /// Starts building a request. The client data hash, RP and user are required, other
+    /// options default as in WebAuthn.
+    pub fn builder() -> MakeCredentialRequestBuilder {
+        MakeCredentialRequestBuilder::default()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct MakeCredentialRequestBuilder {
+    hash: Option<Vec<u8>>,
+    origin: String,
+    relying_party: Option<Ctap2PublicKeyCredentialRpEntity>,
+    user: Option<Ctap2PublicKeyCredentialUserEntity>,
+    resident_key: Option<ResidentKeyRequirement>,
+    user_verification: UserVerificationRequirement,
+    algorithms: Vec<Ctap2CredentialType>,
+    exclude: Vec<Ctap2PublicKeyCredentialDescriptor>,
+    extensions: Option<MakeCredentialsRequestExtensions>,
+    timeout: Duration,
+    always_uv_policy: AlwaysUvPolicy,
+    uv_method_preference: UvMethodPreference,
+    platform_uv_attempts: Option<u32>,
+    additional_permissions: Ctap2AuthTokenPermissionRole,
+    attestation_formats: Vec<String>,
+    ctap1_fallback: Ctap1Fallback,
+}
+
+impl Default for MakeCredentialRequestBuilder {
+    fn default() -> Self {
+        Self {
+            hash: None,
+            origin: String::new(),
+            relying_party: None,
+            user: None,
+            resident_key: None,
+            user_verification: UserVerificationRequirement::Preferred,
+            algorithms: vec![],
+            exclude: vec![],
+            extensions: None,
+            timeout: MakeCredentialRequest::DEFAULT_TIMEOUT,
+            always_uv_policy: AlwaysUvPolicy::default(),
+            uv_method_preference: UvMethodPreference::default(),
+            platform_uv_attempts: None,
+            additional_permissions: Ctap2AuthTokenPermissionRole::empty(),
+            attestation_formats: vec![],
+            ctap1_fallback: Ctap1Fallback::default(),
+        }
+    }
+}
+
+impl MakeCredentialRequestBuilder {
+    /// The SHA-256 hash of the client data.
+    pub fn client_data_hash(mut self, hash: &[u8]) -> Self {
+        self.hash = Some(hash.to_vec());
+        self
+    }
+
+    pub fn origin(mut self, origin: &str) -> Self {
+        self.origin = origin.to_owned();
+        self
+    }
+
+    pub fn rp(mut self, relying_party: Ctap2PublicKeyCredentialRpEntity) -> Self {
+        self.relying_party = Some(relying_party);
+        self
+    }
+
+    pub fn user(mut self, user: Ctap2PublicKeyCredentialUserEntity) -> Self {
+        self.user = Some(user);
+        self
+    }
+
+    pub fn resident_key(mut self, requirement: ResidentKeyRequirement) -> Self {
+        self.resident_key = Some(requirement);
+        self
+    }
+
+    /// Defaults to preferred.
+    pub fn user_verification(mut self, requirement: UserVerificationRequirement) -> Self {
+        self.user_verification = requirement;
+        self
+    }
+
+    /// Adds an acceptable credential type, in order of preference. Without any, ES256 is used.
+    pub fn algorithm(mut self, algorithm: Ctap2COSEAlgorithmIdentifier) -> Self {
+        self.algorithms.push(Ctap2CredentialType {
+            algorithm,
+            ..Default::default()
+        });
+        self
+    }
+
+    /// Adds a credential which must not already be stored on the authenticator.
+    pub fn exclude_credential(mut self, credential: Ctap2PublicKeyCredentialDescriptor) -> Self {
+        self.exclude.push(credential);
+        self
+    }
+
+    pub fn extensions(mut self, extensions: MakeCredentialsRequestExtensions) -> Self {
+        self.extensions = Some(extensions);
+        self
+    }
+
+    /// Bounds the whole ceremony, defaults to [MakeCredentialRequest::DEFAULT_TIMEOUT].
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn always_uv_policy(mut self, policy: AlwaysUvPolicy) -> Self {
+        self.always_uv_policy = policy;
+        self
+    }
+
+    pub fn uv_method_preference(mut self, preference: UvMethodPreference) -> Self {
+        self.uv_method_preference = preference;
+        self
+    }
+
+    pub fn ctap1_fallback(mut self, fallback: Ctap1Fallback) -> Self {
+        self.ctap1_fallback = fallback;
+        self
+    }
+
+    pub fn platform_uv_attempts(mut self, attempts: u32) -> Self {
+        self.platform_uv_attempts = Some(attempts);
+        self
+    }
+
+    /// Adds permissions to request for the pinUvAuthToken, see
+    /// [MakeCredentialRequest::additional_permissions].
+    pub fn additional_permissions(mut self, permissions: Ctap2AuthTokenPermissionRole) -> Self {
+        self.additional_permissions |= permissions;
+        self
+    }
+
+    /// Adds an attestation statement format, e.g. "packed", in order of preference.
+    pub fn attestation_format(mut self, format: &str) -> Self {
+        self.attestation_formats.push(format.to_owned());
+        self
+    }
+
+    /// Fails with `PlatformError::SyntaxError` if a required option is missing, or an
+    /// option is invalid, as WebAuthn clients reject such requests with a TypeError.
+    pub fn build(self) -> Result<MakeCredentialRequest, Error> {
+        let Some(hash) = self.hash else {
+            return Err(invalid_request(
+                "MakeCredential",
+                "client data hash is missing",
+            ));
+        };
+        if hash.len() != 32 {
+            return Err(invalid_request(
+                "MakeCredential",
+                "client data hash is not a SHA-256 hash",
+            ));
+        }
+        let Some(relying_party) = self.relying_party else {
+            return Err(invalid_request(
+                "MakeCredential",
+                "relying party is missing",
+            ));
+        };
+        if relying_party.id.is_empty() {
+            return Err(invalid_request(
+                "MakeCredential",
+                "relying party ID is empty",
+            ));
+        }
+        let Some(user) = self.user else {
+            return Err(invalid_request("MakeCredential", "user is missing"));
+        };
+        if user.id.is_empty() || user.id.len() > MakeCredentialRequest::MAX_USER_ID_LENGTH {
+            return Err(invalid_request(
+                "MakeCredential",
+                "user ID must be 1 to 64 bytes long",
+            ));
+        }
+        if self.timeout.is_zero() {
+            return Err(invalid_request("MakeCredential", "timeout is zero"));
+        }
+        let algorithms = match self.algorithms.is_empty() {
+            true => vec![Ctap2CredentialType::default()],
+            false => self.algorithms,
+        };
+        Ok(MakeCredentialRequest {
+            hash,
+            origin: self.origin,
+            relying_party,
+            user,
+            resident_key: self.resident_key,
+            user_verification: self.user_verification,
+            algorithms,
+            exclude: (!self.exclude.is_empty()).then_some(self.exclude),
+            extensions: self.extensions,
+            timeout: self.timeout,
+            always_uv_policy: self.always_uv_policy,
+            uv_method_preference: self.uv_method_preference,
+            platform_uv_attempts: self.platform_uv_attempts,
+            additional_permissions: self.additional_permissions,
+            attestation_formats: self.attestation_formats,
+            ctap1_fallback: self.ctap1_fallback,
+        })
+    }
+}
+
 impl DowngradableRequest<RegisterRequest> for MakeCredentialRequest {
     #[instrument(skip_all)]
     fn is_downgradable(&self) -> bool {
@@ -315,10 +562,7 @@ impl DowngradableRequest<RegisterRequest> for MakeCredentialRequest {
         }
 
         // Options must not include "rk" set to true.
-        if matches!(
-            self.resident_key,
-            Some(ResidentKeyRequirement::Required)
-        ) {
+        if matches!(self.resident_key, Some(ResidentKeyRequirement::Required)) {
             debug!("Not downgradable: request requires resident key");
             return false;
         }
@@ -370,3 +614,71 @@ impl DowngradableRequest<RegisterRequest> for MakeCredentialRequest {
         Ok(downgraded)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{MakeCredentialRequest, ResidentKeyRequirement};
+    use crate::proto::ctap2::{
+        Ctap2COSEAlgorithmIdentifier, Ctap2PublicKeyCredentialRpEntity,
+        Ctap2PublicKeyCredentialUserEntity,
+    };
+    use crate::webauthn::error::{Error, PlatformError};
+
+    fn rp() -> Ctap2PublicKeyCredentialRpEntity {
+        Ctap2PublicKeyCredentialRpEntity::new("example.org", "Example")
+    }
+
+    fn user(id: &[u8]) -> Ctap2PublicKeyCredentialUserEntity {
+        Ctap2PublicKeyCredentialUserEntity::new(id, "user", "User")
+    }
+
+    #[test]
+    fn builder_applies_webauthn_defaults() {
+        let request = MakeCredentialRequest::builder()
+            .client_data_hash(&[1; 32])
+            .rp(rp())
+            .user(user(b"user"))
+            .resident_key(ResidentKeyRequirement::Required)
+            .build()
+            .unwrap();
+        assert_eq!(request.hash, vec![1; 32]);
+        assert_eq!(request.relying_party.id, "example.org");
+        assert!(matches!(
+            request.resident_key,
+            Some(ResidentKeyRequirement::Required)
+        ));
+        assert!(request.user_verification.is_preferred());
+        assert_eq!(
+            request.algorithms[0].algorithm,
+            Ctap2COSEAlgorithmIdentifier::ES256
+        );
+        assert!(request.exclude.is_none());
+        assert_eq!(request.timeout, MakeCredentialRequest::DEFAULT_TIMEOUT);
+    }
+
+    #[test]
+    fn builder_rejects_invalid_requests() {
+        let valid = MakeCredentialRequest::builder()
+            .client_data_hash(&[1; 32])
+            .rp(rp())
+            .user(user(b"user"));
+        assert!(valid.clone().build().is_ok());
+
+        for invalid in [
+            MakeCredentialRequest::builder()
+                .rp(rp())
+                .user(user(b"user")),
+            valid.clone().client_data_hash(&[1; 20]),
+            valid.clone().user(user(b"")),
+            valid.clone().user(user(&[1; 65])),
+            valid
+                .clone()
+                .rp(Ctap2PublicKeyCredentialRpEntity::new("", "Example")),
+        ] {
+            assert!(matches!(
+                invalid.build(),
+                Err(Error::Platform(PlatformError::SyntaxError))
+            ));
+        }
+    }
+}
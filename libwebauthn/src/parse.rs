@@ -0,0 +1,124 @@
+//! Entry points for every wire format received from devices and authenticators.
+//!
+//! These parse what the transports and protocol implementations would, without a device, so
+//! that malformed input can be fuzzed: see the targets under `fuzz/`. They never panic, any
+//! input either parses or returns an error.
+
+use serde::de::DeserializeOwned;
+
+use crate::proto::ctap1::apdu::ApduResponse;
+use crate::proto::ctap1::{Ctap1RegisterResponse, Ctap1SignResponse};
+use crate::proto::ctap2::cbor::{self, CborResponse};
+use crate::proto::ctap2::{
+    Ctap2GetAssertionResponse, Ctap2GetInfoResponse, Ctap2MakeCredentialResponse,
+};
+use crate::transport::cable::qr_code_device::{CableQrCode, QrCodeParseError};
+use crate::transport::cable::tunnel;
+use crate::transport::error::TransportError;
+use crate::transport::hid::framing::{HidMessage, HidMessageParser, HidMessageParserState};
+use crate::webauthn::error::{CtapError, Error, PlatformError};
+
+fn parse_cbor<T: DeserializeOwned>(data: &[u8], response: &'static str) -> Result<T, Error> {
+    cbor::from_slice(data)
+        .map_err(|source| Error::Platform(PlatformError::InvalidResponse { response, source }))
+}
+
+/// A CTAP2 response frame: the status code, followed by the CBOR-encoded response, if any.
+pub fn parse_ctap2_response(frame: &[u8]) -> Result<CborResponse, Error> {
+    CborResponse::try_from(&frame.to_vec())
+        .or(Err(Error::Transport(TransportError::InvalidFraming)))
+}
+
+/// The CBOR-encoded authenticatorGetInfo response, without status code.
+pub fn parse_get_info(data: &[u8]) -> Result<Ctap2GetInfoResponse, Error> {
+    parse_cbor(data, "Ctap2GetInfoResponse")
+}
+
+/// The CBOR-encoded authenticatorMakeCredential response, without status code.
+pub fn parse_make_credential_response(data: &[u8]) -> Result<Ctap2MakeCredentialResponse, Error> {
+    parse_cbor(data, "Ctap2MakeCredentialResponse")
+}
+
+/// The CBOR-encoded authenticatorGetAssertion or authenticatorGetNextAssertion response,
+/// without status code.
+pub fn parse_get_assertion_response(data: &[u8]) -> Result<Ctap2GetAssertionResponse, Error> {
+    parse_cbor(data, "Ctap2GetAssertionResponse")
+}
+
+/// A U2F registration response APDU, including the status words.
+pub fn parse_u2f_register_response(apdu: &[u8]) -> Result<Ctap1RegisterResponse, Error> {
+    let apdu = ApduResponse::try_from(&apdu.to_vec()).or(Err(CtapError::Other))?;
+    apdu.try_into().or(Err(Error::Ctap(CtapError::Other)))
+}
+
+/// A U2F authentication response APDU, including the status words.
+pub fn parse_u2f_sign_response(apdu: &[u8]) -> Result<Ctap1SignResponse, Error> {
+    let apdu = ApduResponse::try_from(&apdu.to_vec()).or(Err(CtapError::Other))?;
+    apdu.try_into().or(Err(Error::Ctap(CtapError::Other)))
+}
+
+/// A CTAPHID message from its packets, as received from the device. Packets after the message
+/// is complete are rejected.
+pub fn parse_hid_message<'a>(
+    packets: impl IntoIterator<Item = &'a [u8]>,
+) -> Result<HidMessage, Error> {
+    let mut parser = HidMessageParser::new();
+    let mut state = HidMessageParserState::MorePacketsExpected;
+    for packet in packets {
+        if state == HidMessageParserState::Done {
+            return Err(Error::Transport(TransportError::InvalidFraming));
+        }
+        state = parser
+            .update(packet)
+            .or(Err(Error::Transport(TransportError::InvalidFraming)))?;
+    }
+    parser
+        .into_message()
+        .or(Err(Error::Transport(TransportError::InvalidFraming)))
+}
+
+/// A scanned `FIDO:/` URI, see [CableQrCode]'s `FromStr`.
+pub fn parse_cable_qr_code(uri: &str) -> Result<CableQrCode, QrCodeParseError> {
+    uri.parse()
+}
+
+/// The initial caBLE message sent by the authenticator after the handshake, decrypted and
+/// unpadded. Returns the getInfo response it carries.
+pub fn parse_cable_initial_message(frame: &[u8]) -> Result<Ctap2GetInfoResponse, Error> {
+    let (info, _features) = tunnel::parse_initial_message(frame)?;
+    parse_get_info(&info)
+}
+
+/// Any later caBLE message sent by the authenticator, decrypted but still padded: CTAP
+/// responses, linking info updates, and shutdown or JSON messages.
+pub fn parse_cable_frame(frame: &[u8]) -> Result<(), Error> {
+    tunnel::parse_frame(frame.to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_cable_frame, parse_get_info, parse_hid_message, parse_u2f_sign_response};
+    use crate::transport::hid::framing::HidCommand;
+
+    #[test]
+    fn malformed_input_is_rejected() {
+        assert!(parse_get_info(&[0xa1, 0x01]).is_err());
+        assert!(parse_u2f_sign_response(&[0x01, 0x90, 0x00]).is_err());
+        assert!(parse_cable_frame(&[]).is_err());
+        assert!(parse_cable_frame(&[0x01, 0xff]).is_err());
+        assert!(parse_hid_message([&[0xff; 4][..]]).is_err());
+        assert!(parse_hid_message([]).is_err());
+    }
+
+    #[test]
+    fn hid_message() {
+        let packets: [&[u8]; 2] = [
+            &[0xC0, 0xC1, 0xC2, 0xC3, 0x90, 0x00, 0x03, 0x0A],
+            &[0xC0, 0xC1, 0xC2, 0xC3, 0x00, 0x0B, 0x0C],
+        ];
+        let message = parse_hid_message(packets).unwrap();
+        assert_eq!(message.cmd, HidCommand::Cbor);
+        assert_eq!(message.payload, vec![0x0A, 0x0B, 0x0C]);
+        assert!(parse_hid_message([packets[0], packets[1], packets[1]]).is_err());
+    }
+}
@@ -1,3 +1,5 @@
+use std::fmt::{self, Debug};
+use std::sync::Arc;
 use std::time::Duration;
 
 use aes::cipher::{block_padding::NoPadding, BlockDecryptMut};
@@ -10,16 +12,18 @@ use p256::{
     ecdh::EphemeralSecret, elliptic_curve::sec1::FromEncodedPoint, EncodedPoint,
     PublicKey as P256PublicKey,
 };
-use rand::{rngs::OsRng, thread_rng, Rng};
 use sha2::{Digest, Sha256};
-use tracing::{error, instrument, warn};
+use tracing::{debug, error, instrument, warn};
 use x509_parser::nom::AsBytes;
+use zeroize::Zeroizing;
 
 use crate::{
+    correlation::CorrelationId,
     proto::{
-        ctap2::{Ctap2, Ctap2ClientPinRequest, Ctap2PinUvAuthProtocol},
+        ctap2::{Ctap2, Ctap2ClientPinRequest, Ctap2GetInfoResponse, Ctap2PinUvAuthProtocol},
         CtapError,
     },
+    sources::{self, CurrentRng},
     transport::Channel,
     webauthn::{
         error::{Error, PlatformError},
@@ -49,6 +53,146 @@ pub enum PinRequestReason {
     // Passkey
 }
 
+/// Details of a PIN request, passed to a [PinProvider].
+#[derive(Debug, Clone)]
+pub struct PinRequestContext {
+    /// What caused the PIN request.
+    pub reason: PinRequestReason,
+    /// Optionally, how many PIN attempts are left _in total_.
+    pub attempts_left: Option<u32>,
+    /// The operation asking for the PIN.
+    pub correlation_id: Option<CorrelationId>,
+}
+
+/// Supplies PINs directly to the PIN ceremony, as an alternative to answering
+/// `UvUpdate::PinRequired` updates. Install it with `Channel::set_pin_provider()`.
+#[async_trait]
+pub trait PinProvider: Send + Sync {
+    /// Returns the PIN, or `None` if the user cancelled the PIN entry.
+    async fn provide_pin(&self, context: &PinRequestContext) -> Option<Zeroizing<String>>;
+}
+
+impl Debug for dyn PinProvider {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("PinProvider")
+    }
+}
+
+/// The PIN requirements advertised by a device in its getInfo response.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PinPolicy {
+    /// Minimum PIN length, in Unicode code points.
+    pub min_length: usize,
+    /// Maximum PIN length, in Unicode code points.
+    pub max_length: usize,
+    /// The device enforces additional complexity rules, which can't be checked up front.
+    pub complexity_policy: bool,
+    /// Where the device's PIN complexity policy is described.
+    pub policy_url: Option<String>,
+    /// The current PIN must be changed before the device can be used.
+    pub force_change: bool,
+}
+
+/// Maximum UTF-8 length of a PIN, which is padded to 64 bytes including a terminating zero byte.
+const MAX_PIN_BYTES: usize = 63;
+
+impl PinPolicy {
+    pub fn from_get_info(get_info_response: &Ctap2GetInfoResponse) -> Self {
+        Self {
+            // If the minPINLength member of the authenticatorGetInfo response is absent, then let platformMinPINLengthInCodePoints be 4.
+            min_length: get_info_response.min_pin_length.unwrap_or(4) as usize,
+            max_length: get_info_response
+                .max_pin_length
+                .map_or(MAX_PIN_BYTES, |max| max as usize),
+            complexity_policy: get_info_response.pin_complexity_policy == Some(true),
+            policy_url: get_info_response
+                .pin_complexity_policy_url
+                .as_ref()
+                .map(|url| String::from_utf8_lossy(url).into_owned()),
+            force_change: get_info_response.force_pin_change == Some(true),
+        }
+    }
+
+    /// Checks the parts of the policy that can be verified without the device.
+    pub fn validate(&self, new_pin: &str) -> Result<(), PinPolicyViolation> {
+        let code_points = new_pin.chars().count();
+        if code_points < self.min_length {
+            return Err(PinPolicyViolation::TooShort {
+                min_length: self.min_length,
+            });
+        }
+        if code_points > self.max_length || new_pin.len() > MAX_PIN_BYTES {
+            return Err(PinPolicyViolation::TooLong {
+                max_length: self.max_length.min(MAX_PIN_BYTES),
+            });
+        }
+        Ok(())
+    }
+
+    fn violation(&self, violation: PinPolicyViolation) -> Error {
+        warn!(%violation, "New PIN violates the device's PIN policy");
+        Error::Platform(PlatformError::PinPolicyViolation {
+            violation,
+            policy_url: self.policy_url.clone(),
+        })
+    }
+}
+
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+pub enum PinPolicyViolation {
+    #[error("pin must be at least {min_length} characters long")]
+    TooShort { min_length: usize },
+    #[error("pin must be at most {max_length} characters long")]
+    TooLong { max_length: usize },
+    #[error("the device requires a pin change, but the new pin is the same as the current one")]
+    Unchanged,
+    #[error("pin does not meet the device's complexity policy")]
+    Complexity,
+}
+
+/// Creates a fresh protocol instance, with a new key pair, for each token acquisition.
+pub type PinUvAuthProtocolFactory = Arc<dyn Fn() -> Box<dyn PinUvAuthProtocol> + Send + Sync>;
+
+/// The PIN/UV auth protocol implementations used on a channel, see
+/// [Channel::set_pin_uv_auth_protocols]. Versions without a custom implementation use the
+/// builtin one.
+#[derive(Clone, Default)]
+pub struct PinUvAuthProtocols {
+    custom: Vec<(Ctap2PinUvAuthProtocol, PinUvAuthProtocolFactory)>,
+}
+
+impl PinUvAuthProtocols {
+    /// Uses a custom implementation for a PIN/UV auth protocol version, e.g. one performing
+    /// ECDH on an HSM. Only versions known to [Ctap2PinUvAuthProtocol] can be negotiated with
+    /// devices.
+    pub fn with_custom<F>(mut self, version: Ctap2PinUvAuthProtocol, factory: F) -> Self
+    where
+        F: Fn() -> Box<dyn PinUvAuthProtocol> + Send + Sync + 'static,
+    {
+        self.custom.retain(|(v, _)| *v != version);
+        self.custom.push((version, Arc::new(factory)));
+        self
+    }
+
+    pub(crate) fn create(&self, version: Ctap2PinUvAuthProtocol) -> Box<dyn PinUvAuthProtocol> {
+        match self.custom.iter().find(|(v, _)| *v == version) {
+            Some((_, factory)) => factory(),
+            None => version.create_protocol_object(),
+        }
+    }
+}
+
+impl Debug for PinUvAuthProtocols {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PinUvAuthProtocols")
+            .field(
+                "custom",
+                &self.custom.iter().map(|(v, _)| v).collect::<Vec<_>>(),
+            )
+            .finish()
+    }
+}
+
 pub trait PinUvAuthProtocol: Send + Sync {
     fn version(&self) -> Ctap2PinUvAuthProtocol;
 
@@ -58,7 +202,7 @@ pub trait PinUvAuthProtocol: Send + Sync {
     fn encapsulate(
         &self,
         peer_public_key: &cose::PublicKey,
-    ) -> Result<(cose::PublicKey, Vec<u8>), Error>;
+    ) -> Result<(cose::PublicKey, Zeroizing<Vec<u8>>), Error>;
 
     // encrypt(key, demPlaintext) → ciphertext
     //   Encrypts a plaintext to produce a ciphertext, which may be longer than the plaintext.
@@ -67,7 +211,7 @@ pub trait PinUvAuthProtocol: Send + Sync {
 
     // decrypt(key, ciphertext) → plaintext | error
     //   Decrypts a ciphertext and returns the plaintext.
-    fn decrypt(&self, key: &[u8], ciphertext: &[u8]) -> Result<Vec<u8>, Error>;
+    fn decrypt(&self, key: &[u8], ciphertext: &[u8]) -> Result<Zeroizing<Vec<u8>>, Error>;
 
     // authenticate(key, message) → signature
     //   Computes a MAC of the given message.
@@ -82,11 +226,11 @@ trait ECPrivateKeyPinUvAuthProtocol {
 
 /// Common functionality between ECDH-based PIN/UV auth protocols (1 & 2)
 trait ECDHPinUvAuthProtocol {
-    fn ecdh(&self, peer_public_key: &cose::PublicKey) -> Result<Vec<u8>, Error>;
+    fn ecdh(&self, peer_public_key: &cose::PublicKey) -> Result<Zeroizing<Vec<u8>>, Error>;
     fn encapsulate(
         &self,
         peer_public_key: &cose::PublicKey,
-    ) -> Result<(cose::PublicKey, Vec<u8>), Error>;
+    ) -> Result<(cose::PublicKey, Zeroizing<Vec<u8>>), Error>;
     fn get_public_key(&self) -> cose::PublicKey;
 }
 
@@ -97,13 +241,18 @@ pub struct PinUvAuthProtocolOne {
 
 impl PinUvAuthProtocolOne {
     pub fn new() -> Self {
-        let private_key = EphemeralSecret::random(&mut OsRng);
+        let private_key = EphemeralSecret::random(&mut CurrentRng);
         let public_key = private_key.public_key();
         Self {
             private_key,
             public_key,
         }
     }
+
+    /// The public key agreement key, as returned by authenticators for getKeyAgreement.
+    pub(crate) fn key_agreement(&self) -> cose::PublicKey {
+        self.get_public_key()
+    }
 }
 
 impl ECPrivateKeyPinUvAuthProtocol for PinUvAuthProtocolOne {
@@ -131,7 +280,7 @@ where
     fn encapsulate(
         &self,
         peer_public_key: &cose::PublicKey,
-    ) -> Result<(cose::PublicKey, Vec<u8>), Error> {
+    ) -> Result<(cose::PublicKey, Zeroizing<Vec<u8>>), Error> {
         // Let sharedSecret be the result of calling ecdh(peerCoseKey). Return any resulting error.
         let shared_secret = self.ecdh(peer_public_key)?;
 
@@ -140,7 +289,7 @@ where
     }
 
     /// ecdh(peerCoseKey) → sharedSecret | error
-    fn ecdh(&self, peer_public_key: &cose::PublicKey) -> Result<Vec<u8>, Error> {
+    fn ecdh(&self, peer_public_key: &cose::PublicKey) -> Result<Zeroizing<Vec<u8>>, Error> {
         // Parse peerCoseKey as specified for getPublicKey, below, and produce a P-256 point, Y.
         // If unsuccessful, or if the resulting point is not on the curve, return error.
         let cose::PublicKey::EcdhEsHkdf256Key(peer_public_key) = peer_public_key else {
@@ -165,7 +314,9 @@ where
         let shared = self.private_key().diffie_hellman(&peer_public_key);
 
         // Return kdf(Z).
-        Ok(self.kdf(shared.raw_secret_bytes().as_bytes()))
+        Ok(Zeroizing::new(
+            self.kdf(shared.raw_secret_bytes().as_bytes()),
+        ))
     }
 
     /// getPublicKey()
@@ -209,7 +360,7 @@ impl PinUvAuthProtocol for PinUvAuthProtocolOne {
     }
 
     #[instrument(skip_all)]
-    fn decrypt(&self, key: &[u8], ciphertext: &[u8]) -> Result<Vec<u8>, Error> {
+    fn decrypt(&self, key: &[u8], ciphertext: &[u8]) -> Result<Zeroizing<Vec<u8>>, Error> {
         // If the size of demCiphertext is not a multiple of the AES block length, return error.
         // Otherwise return the AES-256-CBC decryption of demCiphertext using an all-zero IV.
         if ciphertext.len() % 16 != 0 {
@@ -229,13 +380,13 @@ impl PinUvAuthProtocol for PinUvAuthProtocolOne {
             error!("Unpad error while decrypting");
             return Err(Error::Ctap(CtapError::Other));
         };
-        Ok(plaintext)
+        Ok(Zeroizing::new(plaintext))
     }
 
     fn encapsulate(
         &self,
         peer_public_key: &cose::PublicKey,
-    ) -> Result<(cose::PublicKey, Vec<u8>), Error> {
+    ) -> Result<(cose::PublicKey, Zeroizing<Vec<u8>>), Error> {
         <Self as ECDHPinUvAuthProtocol>::encapsulate(self, peer_public_key)
     }
 }
@@ -247,13 +398,18 @@ pub struct PinUvAuthProtocolTwo {
 
 impl PinUvAuthProtocolTwo {
     pub fn new() -> Self {
-        let private_key = EphemeralSecret::random(&mut OsRng);
+        let private_key = EphemeralSecret::random(&mut CurrentRng);
         let public_key = private_key.public_key();
         Self {
             private_key,
             public_key,
         }
     }
+
+    /// The public key agreement key, as returned by authenticators for getKeyAgreement.
+    pub(crate) fn key_agreement(&self) -> cose::PublicKey {
+        self.get_public_key()
+    }
 }
 
 impl ECPrivateKeyPinUvAuthProtocol for PinUvAuthProtocolTwo {
@@ -286,7 +442,7 @@ impl PinUvAuthProtocol for PinUvAuthProtocolTwo {
     fn encapsulate(
         &self,
         peer_public_key: &cose::PublicKey,
-    ) -> Result<(cose::PublicKey, Vec<u8>), Error> {
+    ) -> Result<(cose::PublicKey, Zeroizing<Vec<u8>>), Error> {
         <Self as ECDHPinUvAuthProtocol>::encapsulate(self, peer_public_key)
     }
 
@@ -295,7 +451,7 @@ impl PinUvAuthProtocol for PinUvAuthProtocolTwo {
         let key = &key[32..];
 
         // Let iv be a 16-byte, random bytestring.
-        let iv: [u8; 16] = thread_rng().gen();
+        let iv: [u8; 16] = sources::random();
 
         // Let ct be the AES-256-CBC encryption of demPlaintext using key and iv.
         // (No padding is performed as the size of demPlaintext is required to be a multiple of the AES block length.)
@@ -311,7 +467,7 @@ impl PinUvAuthProtocol for PinUvAuthProtocolTwo {
         Ok(out)
     }
 
-    fn decrypt(&self, key: &[u8], ciphertext: &[u8]) -> Result<Vec<u8>, Error> {
+    fn decrypt(&self, key: &[u8], ciphertext: &[u8]) -> Result<Zeroizing<Vec<u8>>, Error> {
         // Discard the first 32 bytes of key. (This selects the AES-key portion of the shared secret.)
         let key = &key[32..];
 
@@ -333,7 +489,7 @@ impl PinUvAuthProtocol for PinUvAuthProtocolTwo {
             error!("Unpad error while decrypting");
             return Err(Error::Ctap(CtapError::Other));
         };
-        Ok(plaintext)
+        Ok(Zeroizing::new(plaintext))
     }
 
     fn authenticate(&self, key: &[u8], message: &[u8]) -> Vec<u8> {
@@ -347,11 +503,11 @@ impl PinUvAuthProtocol for PinUvAuthProtocolTwo {
 }
 
 /// hash(pin) -> LEFT(SHA-256(pin), 16)
-pub fn pin_hash(pin: &[u8]) -> Vec<u8> {
+pub fn pin_hash(pin: &[u8]) -> Zeroizing<Vec<u8>> {
     let mut hasher = Sha256::default();
     hasher.update(pin);
-    let hashed = hasher.finalize().to_vec();
-    Vec::from(&hashed[..16])
+    let hashed = Zeroizing::new(hasher.finalize().to_vec());
+    Zeroizing::new(Vec::from(&hashed[..16]))
 }
 
 pub fn hmac_sha256(key: &[u8], message: &[u8]) -> Vec<u8> {
@@ -368,9 +524,25 @@ pub fn hkdf_sha256(salt: Option<&[u8]>, ikm: &[u8], info: &[u8]) -> Vec<u8> {
     Vec::from(okm)
 }
 
+/// Remaining PIN and built-in UV attempts of a device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Retries {
+    /// PIN attempts left, if the device supports a PIN.
+    pub pin_retries: Option<u32>,
+    /// The device must be power-cycled before the PIN can be tried again.
+    pub power_cycle_required: bool,
+    /// Built-in UV attempts left, if the device supports built-in UV.
+    pub uv_retries: Option<u32>,
+}
+
 #[async_trait]
 pub trait PinManagement {
-    async fn change_pin(&mut self, new_pin: String, timeout: Duration) -> Result<(), Error>;
+    async fn change_pin(
+        &mut self,
+        new_pin: Zeroizing<String>,
+        timeout: Duration,
+    ) -> Result<(), Error>;
+    async fn get_retries(&mut self, timeout: Duration) -> Result<Retries, Error>;
 }
 
 #[async_trait]
@@ -378,21 +550,23 @@ impl<C> PinManagement for C
 where
     C: Channel,
 {
-    async fn change_pin(&mut self, new_pin: String, timeout: Duration) -> Result<(), Error> {
+    async fn change_pin(
+        &mut self,
+        new_pin: Zeroizing<String>,
+        timeout: Duration,
+    ) -> Result<(), Error> {
         let get_info_response = self.ctap2_get_info().await?;
 
-        // If the minPINLength member of the authenticatorGetInfo response is absent, then let platformMinPINLengthInCodePoints be 4.
-        if new_pin.as_bytes().len() < get_info_response.min_pin_length.unwrap_or(4) as usize {
-            // If platformCollectedPinLengthInCodePoints is less than platformMinPINLengthInCodePoints then the platform SHOULD display a "PIN too short" error message to the user.
-            return Err(Error::Platform(PlatformError::PinTooShort));
-        }
-
+        // If platformCollectedPinLengthInCodePoints is less than platformMinPINLengthInCodePoints then the platform SHOULD display a "PIN too short" error message to the user.
         // If the byte length of "newPin" is greater than the max UTF-8 representation limit of 63 bytes, then the platform SHOULD display a "PIN too long" error message to the user.
-        if new_pin.as_bytes().len() >= 64 {
-            return Err(Error::Platform(PlatformError::PinTooLong));
+        let policy = PinPolicy::from_get_info(&get_info_response);
+        if let Err(violation) = policy.validate(&new_pin) {
+            return Err(policy.violation(violation));
         }
 
-        let Some(uv_proto) = select_uv_proto(&get_info_response).await else {
+        let Some(uv_proto) =
+            select_uv_proto(&get_info_response, &self.get_pin_uv_auth_protocols()).await
+        else {
             error!("No supported PIN/UV auth protocols found");
             return Err(Error::Ctap(CtapError::Other));
         };
@@ -419,12 +593,19 @@ where
             }
         };
 
+        // The authenticator rejects changing a PIN to itself, while forcePINChange is set.
+        if policy.force_change
+            && current_pin.as_deref().map(Vec::as_slice) == Some(new_pin.as_bytes())
+        {
+            return Err(policy.violation(PinPolicyViolation::Unchanged));
+        }
+
         // In preparation for obtaining pinUvAuthToken, the platform:
         // * Obtains a shared secret.
         let (public_key, shared_secret) = obtain_shared_secret(self, &uv_proto, timeout).await?;
 
         // paddedPin is newPin padded on the right with 0x00 bytes to make it 64 bytes long. (Since the maximum length of newPin is 63 bytes, there is always at least one byte of padding.)
-        let mut padded_new_pin = new_pin.as_bytes().to_vec();
+        let mut padded_new_pin = Zeroizing::new(new_pin.as_bytes().to_vec());
         padded_new_pin.resize(64, 0x00);
 
         // newPinEnc: the result of calling encrypt(shared secret, paddedPin) where
@@ -464,7 +645,140 @@ where
         };
 
         // On success, this is an all-empty Ctap2ClientPinResponse
-        let _ = self.ctap2_client_pin(&req, timeout).await?;
-        Ok(())
+        match self.ctap2_client_pin(&req, timeout).await {
+            Ok(_) => {
+                // Changing the PIN invalidates all pinUvAuthTokens issued by the device.
+                self.clear_uv_auth_token_store();
+                Ok(())
+            }
+            Err(Error::Ctap(CtapError::PINPolicyViolation)) if policy.complexity_policy => {
+                Err(policy.violation(PinPolicyViolation::Complexity))
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    #[instrument(skip_all)]
+    async fn get_retries(&mut self, timeout: Duration) -> Result<Retries, Error> {
+        let get_info_response = self.ctap2_get_info().await?;
+        let options = get_info_response.options.as_ref();
+        let mut retries = Retries {
+            pin_retries: None,
+            power_cycle_required: false,
+            uv_retries: None,
+        };
+
+        if options.is_some_and(|o| o.contains_key("clientPin")) {
+            // FIDO 2.0 requires PIN protocol, 2.1 does not anymore
+            let pin_protocol = if get_info_response.supports_fido_2_1() {
+                None
+            } else {
+                select_uv_proto(&get_info_response, &self.get_pin_uv_auth_protocols())
+                    .await
+                    .map(|proto| proto.version())
+            };
+            let response = self
+                .ctap2_client_pin(
+                    &Ctap2ClientPinRequest::new_get_pin_retries(pin_protocol),
+                    timeout,
+                )
+                .await?;
+            retries.pin_retries = response.pin_retries;
+            retries.power_cycle_required = response.power_cycle_state == Some(true);
+        }
+
+        if options.is_some_and(|o| o.contains_key("uv")) {
+            let response = self
+                .ctap2_client_pin(&Ctap2ClientPinRequest::new_get_uv_retries(), timeout)
+                .await?;
+            retries.uv_retries = response.uv_retries;
+        }
+
+        debug!(?retries, "Retrieved retry counters");
+        Ok(retries)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        PinPolicy, PinPolicyViolation, PinUvAuthProtocol, PinUvAuthProtocolOne, PinUvAuthProtocols,
+    };
+    use crate::proto::ctap2::Ctap2PinUvAuthProtocol;
+    use crate::webauthn::error::Error;
+    use cosey as cose;
+    use zeroize::Zeroizing;
+
+    fn policy(min_length: usize, max_length: usize) -> PinPolicy {
+        PinPolicy {
+            min_length,
+            max_length,
+            complexity_policy: false,
+            policy_url: None,
+            force_change: false,
+        }
+    }
+
+    #[test]
+    fn validate_pin_length() {
+        assert_eq!(
+            policy(4, 63).validate("123"),
+            Err(PinPolicyViolation::TooShort { min_length: 4 })
+        );
+        assert_eq!(policy(4, 63).validate("1234"), Ok(()));
+        assert_eq!(
+            policy(4, 6).validate("1234567"),
+            Err(PinPolicyViolation::TooLong { max_length: 6 })
+        );
+        // Lengths are counted in code points, but the PIN must still fit into 63 bytes.
+        assert_eq!(policy(4, 63).validate("äöüß"), Ok(()));
+        assert_eq!(
+            policy(4, 63).validate(&"ä".repeat(32)),
+            Err(PinPolicyViolation::TooLong { max_length: 63 })
+        );
+    }
+
+    struct FixedMacProtocol(PinUvAuthProtocolOne);
+
+    impl PinUvAuthProtocol for FixedMacProtocol {
+        fn version(&self) -> Ctap2PinUvAuthProtocol {
+            self.0.version()
+        }
+
+        fn encapsulate(
+            &self,
+            peer_public_key: &cose::PublicKey,
+        ) -> Result<(cose::PublicKey, Zeroizing<Vec<u8>>), Error> {
+            self.0.encapsulate(peer_public_key)
+        }
+
+        fn encrypt(&self, key: &[u8], plaintext: &[u8]) -> Result<Vec<u8>, Error> {
+            self.0.encrypt(key, plaintext)
+        }
+
+        fn decrypt(&self, key: &[u8], ciphertext: &[u8]) -> Result<Zeroizing<Vec<u8>>, Error> {
+            self.0.decrypt(key, ciphertext)
+        }
+
+        fn authenticate(&self, _key: &[u8], _message: &[u8]) -> Vec<u8> {
+            vec![0x42; 16]
+        }
+    }
+
+    #[test]
+    fn custom_protocol_replaces_builtin() {
+        let version = Ctap2PinUvAuthProtocol::One;
+        let protocols = PinUvAuthProtocols::default().with_custom(version, || {
+            Box::new(FixedMacProtocol(PinUvAuthProtocolOne::new()))
+        });
+        let custom = protocols.create(version);
+        let builtin = PinUvAuthProtocols::default().create(version);
+
+        assert_eq!(custom.authenticate(&[0; 32], b"message"), vec![0x42; 16]);
+        assert_ne!(builtin.authenticate(&[0; 32], b"message"), vec![0x42; 16]);
+        assert_eq!(
+            protocols.create(Ctap2PinUvAuthProtocol::Two).version(),
+            Ctap2PinUvAuthProtocol::Two
+        );
     }
 }
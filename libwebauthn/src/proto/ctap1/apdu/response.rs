@@ -31,6 +31,13 @@ impl ApduResponse {
         }
     }
 
+    /// The response as received from the device: its data, followed by the status words.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = self.data.clone().unwrap_or_default();
+        bytes.extend([self.sw1, self.sw2]);
+        bytes
+    }
+
     pub fn status(&self) -> Result<ApduResponseStatus, IOError> {
         let mut cursor = IOCursor::new(vec![self.sw1, self.sw2]);
         let code = cursor.read_u16::<BigEndian>().unwrap() as u16;
@@ -158,6 +158,19 @@ impl Ctap1RegisterResponse {
     pub fn as_registered_key(&self) -> Result<Ctap1RegisteredKey, IOError> {
         Ok(Ctap1RegisteredKey::new_u2f_v2(&self.key_handle))
     }
+
+    /// The raw registration data, as U2F relying parties verify it: the reserved byte, the
+    /// public key, the key handle with its length, the attestation certificate and the
+    /// signature.
+    pub fn registration_data(&self) -> Vec<u8> {
+        let mut data = vec![0x05];
+        data.extend_from_slice(&self.public_key);
+        data.push(self.key_handle.len() as u8);
+        data.extend_from_slice(&self.key_handle);
+        data.extend_from_slice(&self.attestation);
+        data.extend_from_slice(&self.signature);
+        data
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -296,6 +309,17 @@ impl TryFrom<ApduResponse> for Ctap1SignResponse {
     }
 }
 
+impl Ctap1SignResponse {
+    /// The raw signature data, as U2F relying parties verify it: the user presence byte, the
+    /// counter and the signature.
+    pub fn signature_data(&self) -> Vec<u8> {
+        let mut data = vec![self.user_presence_verified as u8];
+        data.extend_from_slice(&self.counter.to_be_bytes());
+        data.extend_from_slice(&self.signature);
+        data
+    }
+}
+
 pub trait Preflight<P>: Sized {
     /// Modify request in place, removing items in exclusion list, in favour of generating new pre-fligh requests.
     fn preflight(&self) -> Result<(Self, Vec<P>), CtapError>;
@@ -325,18 +349,29 @@ impl Preflight<Ctap1SignRequest> for Ctap1RegisterRequest {
 #[cfg(test)]
 mod tests {
     use crate::proto::ctap1::apdu::ApduResponse;
-    use crate::proto::ctap1::Ctap1RegisterResponse;
+    use crate::proto::ctap1::{Ctap1RegisterResponse, Ctap1SignResponse};
     use std::convert::TryInto;
 
     #[test]
     fn register_response_apdu_to_ctap1() {
         let apdu = hex::decode("05046DDBE3C25D974C9A403D6C648ED41C219D44734C43986B4053B325BE01C31E28F146731E5C21BA0E0E1938DA4C1FECAD650A2971A13CF6076BF52B52C19F8D0E40602CFD267868E84D4852BD5B008BC6CE0211D4858C8A647328A13B7D5C0A42B3893D63A58FCA7BD3EBB74F55CE537195DFF0113D4C561BBB7DFAC0C0ECD1AFB53082015930820100A003020102020102300A06082A8648CE3D0403023028311530130603550403130C5365637572697479204B6579310F300D060355040A1306476F6F676C653022180F32303030303130313030303030305A180F32303939313233313233353935395A3028311530130603550403130C5365637572697479204B6579310F300D060355040A1306476F6F676C653059301306072A8648CE3D020106082A8648CE3D030107034200040393AF897BE858E88C1953876A1A538477C4DA6E6EA14ACF0A2FD89A4DCCF95878A8CD2929029CC1D794BFFB9C37547CBBB5BB31AB3A6756ACF74F123CECD45CA31730153013060B2B0601040182E51C020101040403020470300A06082A8648CE3D040302034700304402207F958ABE6CF08CB2E9A03774D52DF8C0EA261E1AC0C283409FEDD8D36DFAF09302204EEB7501C720428D206E1B092D8D26CA8536B70F5F09AEA99562390BEF1BA7EC3044022031413D6E238A5F998B26B3931655C411847D99776B6E5CF15AA2E11BFAF325F00220098745DA82C11BB242934BAC6AE95155EAAD68520D695D46982DA9B2C94F94E3").unwrap();
-        let apdu = ApduResponse::new_success(&apdu);
-        let decoded: Ctap1RegisterResponse = apdu.try_into().unwrap();
+        let decoded: Ctap1RegisterResponse = ApduResponse::new_success(&apdu).try_into().unwrap();
 
         assert_eq!(decoded.public_key, hex::decode("046DDBE3C25D974C9A403D6C648ED41C219D44734C43986B4053B325BE01C31E28F146731E5C21BA0E0E1938DA4C1FECAD650A2971A13CF6076BF52B52C19F8D0E").unwrap());
         assert_eq!(decoded.key_handle, hex::decode("602CFD267868E84D4852BD5B008BC6CE0211D4858C8A647328A13B7D5C0A42B3893D63A58FCA7BD3EBB74F55CE537195DFF0113D4C561BBB7DFAC0C0ECD1AFB5").unwrap());
         assert_eq!(decoded.attestation, hex::decode("3082015930820100A003020102020102300A06082A8648CE3D0403023028311530130603550403130C5365637572697479204B6579310F300D060355040A1306476F6F676C653022180F32303030303130313030303030305A180F32303939313233313233353935395A3028311530130603550403130C5365637572697479204B6579310F300D060355040A1306476F6F676C653059301306072A8648CE3D020106082A8648CE3D030107034200040393AF897BE858E88C1953876A1A538477C4DA6E6EA14ACF0A2FD89A4DCCF95878A8CD2929029CC1D794BFFB9C37547CBBB5BB31AB3A6756ACF74F123CECD45CA31730153013060B2B0601040182E51C020101040403020470300A06082A8648CE3D040302034700304402207F958ABE6CF08CB2E9A03774D52DF8C0EA261E1AC0C283409FEDD8D36DFAF09302204EEB7501C720428D206E1B092D8D26CA8536B70F5F09AEA99562390BEF1BA7EC").unwrap());
         assert_eq!(decoded.signature, hex::decode("3044022031413D6E238A5F998B26B3931655C411847D99776B6E5CF15AA2E11BFAF325F00220098745DA82C11BB242934BAC6AE95155EAAD68520D695D46982DA9B2C94F94E3").unwrap());
+        assert_eq!(decoded.registration_data(), apdu);
+    }
+
+    #[test]
+    fn sign_response_apdu_to_ctap1() {
+        let data = hex::decode("0100000005304402").unwrap();
+        let decoded: Ctap1SignResponse = ApduResponse::new_success(&data).try_into().unwrap();
+
+        assert!(decoded.user_presence_verified);
+        assert_eq!(decoded.counter, 5);
+        assert_eq!(decoded.signature, [0x30, 0x44, 0x02]);
+        assert_eq!(decoded.signature_data(), data);
     }
 }
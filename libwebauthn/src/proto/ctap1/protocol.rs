@@ -79,7 +79,7 @@ where
             return Err(Error::Ctap(CtapError::from(status)));
         }
 
-        let response: Ctap1RegisterResponse = apdu_response.try_into().unwrap();
+        let response: Ctap1RegisterResponse = apdu_response.try_into().or(Err(CtapError::Other))?;
         debug!("CTAP1 register response");
         trace!(?response);
         Ok(response)
@@ -98,7 +98,7 @@ where
             return Err(Error::Ctap(CtapError::from(status)));
         }
 
-        let response: Ctap1SignResponse = apdu_response.try_into().unwrap();
+        let response: Ctap1SignResponse = apdu_response.try_into().or(Err(CtapError::Other))?;
         debug!({ ?response.user_presence_verified }, "CTAP1 sign response received");
         trace!(?response);
         Ok(response)
@@ -112,8 +112,18 @@ async fn send_apdu_request_wait_uv<'c, C: Channel>(
 ) -> Result<ApduResponse, Error> {
     tokio_timeout(timeout, async {
         loop {
-            channel.apdu_send(request, timeout).await?;
-            let apdu_response = channel.apdu_recv(timeout).await?;
+            let result = match channel.apdu_send(request, timeout).await {
+                Ok(()) => channel.apdu_recv(timeout).await,
+                Err(err) => Err(err),
+            };
+            let apdu_response = match result {
+                Err(Error::Transport(TransportError::DeviceReconnected)) => {
+                    // U2F requests carry no session state, so they can simply be resent.
+                    debug!("Device reconnected, resending APDU request");
+                    continue;
+                }
+                result => result?,
+            };
             let apdu_status = apdu_response
                 .status()
                 .or(Err(Error::Transport(TransportError::InvalidFraming)))?;
@@ -4,4 +4,5 @@ mod serde;
 
 pub use request::CborRequest;
 pub use response::CborResponse;
-pub(crate) use serde::{from_cursor, from_slice, to_vec, CborError, Value};
+pub use serde::CborError;
+pub(crate) use serde::{from_cursor, from_slice, to_vec, to_vec_canonical, Value};
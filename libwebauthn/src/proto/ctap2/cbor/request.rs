@@ -1,20 +1,32 @@
+use std::fmt;
 use std::io::Error as IOError;
 
 use crate::proto::ctap2::cbor;
 use crate::proto::ctap2::model::Ctap2ClientPinRequest;
 use crate::proto::ctap2::model::Ctap2CommandCode;
 use crate::proto::ctap2::model::Ctap2GetAssertionRequest;
+use crate::proto::ctap2::model::Ctap2LargeBlobsRequest;
 use crate::proto::ctap2::model::Ctap2MakeCredentialRequest;
 use crate::proto::ctap2::Ctap2AuthenticatorConfigRequest;
 use crate::proto::ctap2::Ctap2BioEnrollmentRequest;
 use crate::proto::ctap2::Ctap2CredentialManagementRequest;
 
-#[derive(Debug, Clone)]
+#[derive(Clone)]
 pub struct CborRequest {
     pub command: Ctap2CommandCode,
     pub encoded_data: Vec<u8>,
 }
 
+/// Only the command and length: the encoded request may carry PINs and pinUvAuthParams.
+impl fmt::Debug for CborRequest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CborRequest")
+            .field("command", &self.command)
+            .field("len", &self.encoded_data.len())
+            .finish()
+    }
+}
+
 impl CborRequest {
     pub fn new(command: Ctap2CommandCode) -> Self {
         Self {
@@ -24,7 +36,8 @@ impl CborRequest {
     }
 
     pub fn ctap_hid_data(&self) -> Vec<u8> {
-        let mut data = vec![self.command as u8];
+        let mut data = Vec::with_capacity(1 + self.encoded_data.len());
+        data.push(self.command as u8);
         data.extend(&self.encoded_data);
         data
     }
@@ -40,7 +53,7 @@ impl From<&Ctap2MakeCredentialRequest> for CborRequest {
     fn from(request: &Ctap2MakeCredentialRequest) -> CborRequest {
         CborRequest {
             command: Ctap2CommandCode::AuthenticatorMakeCredential,
-            encoded_data: cbor::to_vec(&request).unwrap(),
+            encoded_data: cbor::to_vec_canonical(&request).unwrap(),
         }
     }
 }
@@ -49,7 +62,7 @@ impl From<&Ctap2GetAssertionRequest> for CborRequest {
     fn from(request: &Ctap2GetAssertionRequest) -> CborRequest {
         CborRequest {
             command: Ctap2CommandCode::AuthenticatorGetAssertion,
-            encoded_data: cbor::to_vec(&request).unwrap(),
+            encoded_data: cbor::to_vec_canonical(&request).unwrap(),
         }
     }
 }
@@ -58,7 +71,7 @@ impl From<&Ctap2ClientPinRequest> for CborRequest {
     fn from(request: &Ctap2ClientPinRequest) -> CborRequest {
         CborRequest {
             command: Ctap2CommandCode::AuthenticatorClientPin,
-            encoded_data: cbor::to_vec(&request).unwrap(),
+            encoded_data: cbor::to_vec_canonical(&request).unwrap(),
         }
     }
 }
@@ -67,7 +80,16 @@ impl From<&Ctap2AuthenticatorConfigRequest> for CborRequest {
     fn from(request: &Ctap2AuthenticatorConfigRequest) -> CborRequest {
         CborRequest {
             command: Ctap2CommandCode::AuthenticatorConfig,
-            encoded_data: cbor::to_vec(&request).unwrap(),
+            encoded_data: cbor::to_vec_canonical(&request).unwrap(),
+        }
+    }
+}
+
+impl From<&Ctap2LargeBlobsRequest> for CborRequest {
+    fn from(request: &Ctap2LargeBlobsRequest) -> CborRequest {
+        CborRequest {
+            command: Ctap2CommandCode::AuthenticatorLargeBlobs,
+            encoded_data: cbor::to_vec_canonical(&request).unwrap(),
         }
     }
 }
@@ -81,7 +103,7 @@ impl From<&Ctap2BioEnrollmentRequest> for CborRequest {
         };
         CborRequest {
             command,
-            encoded_data: cbor::to_vec(&request).unwrap(),
+            encoded_data: cbor::to_vec_canonical(&request).unwrap(),
         }
     }
 }
@@ -95,7 +117,70 @@ impl From<&Ctap2CredentialManagementRequest> for CborRequest {
         };
         CborRequest {
             command,
-            encoded_data: cbor::to_vec(&request).unwrap(),
+            encoded_data: cbor::to_vec_canonical(&request).unwrap(),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use serde_bytes::ByteBuf;
+
+    use super::CborRequest;
+    use crate::proto::ctap2::{
+        Ctap2CommandCode, Ctap2CredentialType, Ctap2MakeCredentialOptions,
+        Ctap2MakeCredentialRequest, Ctap2MakeCredentialsRequestExtensions,
+        Ctap2PublicKeyCredentialRpEntity, Ctap2PublicKeyCredentialUserEntity,
+    };
+
+    #[test]
+    /// Requests go out in CTAP2 canonical CBOR, whatever the field order of their structs.
+    fn make_credential_wire_format() {
+        let request = Ctap2MakeCredentialRequest {
+            hash: ByteBuf::from(vec![0x01, 0x02, 0x03, 0x04]),
+            relying_party: Ctap2PublicKeyCredentialRpEntity {
+                id: "example.com".to_owned(),
+                name: None,
+            },
+            user: Ctap2PublicKeyCredentialUserEntity {
+                id: ByteBuf::from(vec![0x01]),
+                name: Some("u".to_owned()),
+                display_name: Some("U".to_owned()),
+            },
+            algorithms: vec![Ctap2CredentialType::default()],
+            exclude: None,
+            extensions: Some(Ctap2MakeCredentialsRequestExtensions {
+                large_blob_key: Some(true),
+                hmac_secret: Some(true),
+                ..Default::default()
+            }),
+            options: Some(Ctap2MakeCredentialOptions {
+                require_resident_key: Some(true),
+                deprecated_require_user_verification: None,
+            }),
+            pin_auth_param: None,
+            pin_auth_proto: None,
+            enterprise_attestation: None,
+            attestation_formats_preference: None,
+        };
+        let request = CborRequest::from(&request);
+        assert_eq!(
+            request.command,
+            Ctap2CommandCode::AuthenticatorMakeCredential
+        );
+        // Known good, verified by hand with cbor.me playground. hmac-secret sorts before
+        // largeBlobKey, as it is shorter.
+        let expected = hex::decode(concat!(
+            "a6",
+            "014401020304",
+            "02a1626964",
+            "6b6578616d706c652e636f6d",
+            "03a36269644101646e616d6561756b646973706c61794e616d656155",
+            "0481a263616c672664747970656a7075626c69632d6b6579",
+            "06a26b686d61632d736563726574f56c6c61726765426c6f624b6579f5",
+            "07a162726bf5",
+        ))
+        .unwrap();
+        assert_eq!(hex::encode(&request.encoded_data), hex::encode(expected));
+    }
+}
@@ -1,15 +1,26 @@
 use crate::proto::error::CtapError;
 
 use std::convert::{TryFrom, TryInto};
+use std::fmt;
 use std::io::{Error as IOError, ErrorKind as IOErrorKind};
 use tracing::error;
 
-#[derive(Debug, Clone)]
+#[derive(Clone)]
 pub struct CborResponse {
     pub status_code: CtapError,
     pub data: Option<Vec<u8>>,
 }
 
+/// Only the status and length: the encoded response may carry tokens and user identities.
+impl fmt::Debug for CborResponse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CborResponse")
+            .field("status_code", &self.status_code)
+            .field("len", &self.data.as_ref().map(Vec::len))
+            .finish()
+    }
+}
+
 impl CborResponse {
     pub fn new_success_from_slice(slice: &[u8]) -> Self {
         Self {
@@ -26,6 +26,17 @@ where
     serde_cbor::ser::to_vec(serializable).map_err(CborError::from)
 }
 
+/// Encodes a value in CTAP2 canonical CBOR. Maps, including structs, go through [Value], whose
+/// ordering is the canonical one: keys sort by major type, then length, then bytewise.
+/// Used for requests to authenticators, and for data that is signed or authenticated.
+pub(crate) fn to_vec_canonical<T>(serializable: &T) -> Result<Vec<u8>, CborError>
+where
+    T: Serialize,
+{
+    let value = serde_cbor::value::to_value(serializable)?;
+    serde_cbor::ser::to_vec(&value).map_err(CborError::from)
+}
+
 /// Decodes a value from CBOR data in a reader without checking that there is no trailing data
 pub(crate) fn from_cursor<T, R>(reader: R) -> Result<T, CborError>
 where
@@ -149,4 +160,29 @@ mod tests {
         let expected = serde_cbor::to_vec(&nested_struct).unwrap();
         assert_eq!(serialized, expected);
     }
+
+    #[derive(Serialize)]
+    struct UnorderedStruct {
+        #[serde(rename = "largeBlobKey")]
+        large_blob_key: bool,
+        #[serde(rename = "hmac-secret")]
+        hmac_secret: bool,
+    }
+
+    #[test]
+    fn test_serialize_canonical() {
+        let unordered = UnorderedStruct {
+            large_blob_key: true,
+            hmac_secret: true,
+        };
+        // Plain encoding keeps the field order, canonical encoding puts shorter keys first.
+        assert_eq!(
+            hex::encode(to_vec(&unordered).unwrap()),
+            "a26c6c61726765426c6f624b6579f56b686d61632d736563726574f5"
+        );
+        assert_eq!(
+            hex::encode(to_vec_canonical(&unordered).unwrap()),
+            "a26b686d61632d736563726574f56c6c61726765426c6f624b6579f5"
+        );
+    }
 }
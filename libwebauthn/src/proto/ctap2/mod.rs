@@ -9,9 +9,11 @@ pub use model::Ctap2GetInfoResponse;
 pub use model::{
     Ctap2AttestationStatement, Ctap2AuthTokenPermissionRole, Ctap2COSEAlgorithmIdentifier,
     Ctap2ClientPinRequest, Ctap2CommandCode, Ctap2CredentialType, Ctap2MakeCredentialOptions,
-    Ctap2PinUvAuthProtocol, Ctap2PublicKeyCredentialDescriptor, Ctap2PublicKeyCredentialRpEntity,
-    Ctap2PublicKeyCredentialType, Ctap2PublicKeyCredentialUserEntity, Ctap2Transport,
-    Ctap2UserVerifiableRequest, Ctap2UserVerificationOperation, FidoU2fAttestationStmt,
+    Ctap2PinUvAuthProtocol, Ctap2PinUvAuthProtocolCommand, Ctap2PublicKeyCredentialDescriptor,
+    Ctap2PublicKeyCredentialRpEntity, Ctap2PublicKeyCredentialType,
+    Ctap2PublicKeyCredentialUserEntity, Ctap2Transport, Ctap2UserVerifiableRequest,
+    Ctap2UserVerificationOperation, FidoU2fAttestationStmt, PackedAttestationStmt,
+    SafetyNetAttestationStmt,
 };
 pub use model::{
     Ctap2AuthenticatorConfigCommand, Ctap2AuthenticatorConfigParams,
@@ -23,13 +25,16 @@ pub use model::{
 };
 pub use model::{
     Ctap2CredentialData, Ctap2CredentialManagementMetadata, Ctap2CredentialManagementRequest,
-    Ctap2CredentialManagementResponse, Ctap2RPData,
+    Ctap2CredentialManagementResponse, Ctap2CredentialManagementSubcommand, Ctap2RPData,
 };
 pub use model::{
     Ctap2GetAssertionRequest, Ctap2GetAssertionResponse, Ctap2GetAssertionResponseExtensions,
 };
+pub use model::{Ctap2LargeBlobsRequest, Ctap2LargeBlobsResponse};
 pub use model::{
-    Ctap2MakeCredentialRequest, Ctap2MakeCredentialResponse, Ctap2MakeCredentialsResponseExtensions,
+    Ctap2MakeCredentialRequest, Ctap2MakeCredentialResponse, Ctap2MakeCredentialsRequestExtensions,
+    Ctap2MakeCredentialsResponseExtensions,
 };
 pub mod preflight;
 pub use protocol::Ctap2;
+pub(crate) use protocol::{check_max_msg_size, HMAC_SECRET_ALLOWANCE, PIN_UV_AUTH_PARAM_ALLOWANCE};
@@ -1,5 +1,6 @@
 use crate::pin::PinUvAuthProtocol;
 use crate::proto::ctap1::Ctap1Transport;
+use crate::redact::{redact, REDACTED};
 
 use num_enum::{IntoPrimitive, TryFromPrimitive};
 use serde_bytes::ByteBuf;
@@ -11,7 +12,8 @@ pub use get_info::Ctap2GetInfoResponse;
 mod bio_enrollment;
 pub use bio_enrollment::{
     Ctap2BioEnrollmentFingerprintKind, Ctap2BioEnrollmentModality, Ctap2BioEnrollmentRequest,
-    Ctap2BioEnrollmentResponse, Ctap2BioEnrollmentTemplateId, Ctap2LastEnrollmentSampleStatus,
+    Ctap2BioEnrollmentResponse, Ctap2BioEnrollmentSubcommand, Ctap2BioEnrollmentTemplateId,
+    Ctap2LastEnrollmentSampleStatus,
 };
 mod authenticator_config;
 pub use authenticator_config::{
@@ -21,22 +23,25 @@ pub use authenticator_config::{
 mod client_pin;
 pub use client_pin::{
     Ctap2AuthTokenPermissionRole, Ctap2ClientPinRequest, Ctap2ClientPinResponse,
-    Ctap2PinUvAuthProtocol,
+    Ctap2PinUvAuthProtocol, Ctap2PinUvAuthProtocolCommand,
 };
+mod large_blobs;
+pub use large_blobs::{Ctap2LargeBlobsRequest, Ctap2LargeBlobsResponse};
 mod make_credential;
 pub use make_credential::{
     Ctap2MakeCredentialOptions, Ctap2MakeCredentialRequest, Ctap2MakeCredentialResponse,
-    Ctap2MakeCredentialsResponseExtensions,
+    Ctap2MakeCredentialsRequestExtensions, Ctap2MakeCredentialsResponseExtensions,
 };
 mod get_assertion;
 pub use get_assertion::{
     Ctap2AttestationStatement, Ctap2GetAssertionOptions, Ctap2GetAssertionRequest,
     Ctap2GetAssertionResponse, Ctap2GetAssertionResponseExtensions, FidoU2fAttestationStmt,
+    PackedAttestationStmt, SafetyNetAttestationStmt,
 };
 mod credential_management;
 pub use credential_management::{
     Ctap2CredentialData, Ctap2CredentialManagementMetadata, Ctap2CredentialManagementRequest,
-    Ctap2CredentialManagementResponse, Ctap2RPData,
+    Ctap2CredentialManagementResponse, Ctap2CredentialManagementSubcommand, Ctap2RPData,
 };
 
 #[derive(Debug, IntoPrimitive, TryFromPrimitive, Copy, Clone, PartialEq, Serialize_repr)]
@@ -52,6 +57,7 @@ pub enum Ctap2CommandCode {
     AuthenticatorCredentialManagement = 0x0A,
     AuthenticatorCredentialManagementPreview = 0x41,
     AuthenticatorSelection = 0x0B,
+    AuthenticatorLargeBlobs = 0x0C,
     AuthenticatorConfig = 0x0D,
 }
 
@@ -80,7 +86,7 @@ impl Ctap2PublicKeyCredentialRpEntity {
     }
 }
 
-#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
 pub struct Ctap2PublicKeyCredentialUserEntity {
     pub id: ByteBuf,
 
@@ -93,6 +99,16 @@ pub struct Ctap2PublicKeyCredentialUserEntity {
     pub display_name: Option<String>,
 }
 
+impl std::fmt::Debug for Ctap2PublicKeyCredentialUserEntity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Ctap2PublicKeyCredentialUserEntity")
+            .field("id", &REDACTED)
+            .field("name", &redact(&self.name))
+            .field("display_name", &redact(&self.display_name))
+            .finish()
+    }
+}
+
 impl Ctap2PublicKeyCredentialUserEntity {
     pub fn dummy() -> Self {
         Self {
@@ -236,7 +252,7 @@ mod tests {
             algorithm: Ctap2COSEAlgorithmIdentifier::ES256,
             public_key_type: Ctap2PublicKeyCredentialType::PublicKey,
         };
-        let serialized = cbor::to_vec(&credential_type).unwrap();
+        let serialized = cbor::to_vec_canonical(&credential_type).unwrap();
         // Known good, verified by hand with cbor.me playground
         let expected = hex::decode("a263616c672664747970656a7075626c69632d6b6579").unwrap();
         assert_eq!(serialized, expected);
@@ -250,7 +266,7 @@ mod tests {
             r#type: Ctap2PublicKeyCredentialType::PublicKey,
             transports: None,
         };
-        let serialized = cbor::to_vec(&credential_descriptor).unwrap();
+        let serialized = cbor::to_vec_canonical(&credential_descriptor).unwrap();
         // Known good, verified by hand with cbor.me playground
         let expected = hex::decode("a2626964414264747970656a7075626c69632d6b6579").unwrap();
         assert_eq!(serialized, expected);
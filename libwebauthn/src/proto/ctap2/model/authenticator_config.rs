@@ -4,8 +4,9 @@ use serde_indexed::SerializeIndexed;
 use serde_repr::{Deserialize_repr, Serialize_repr};
 
 use super::Ctap2PinUvAuthProtocol;
+use crate::redact::redact;
 
-#[derive(Debug, Clone, SerializeIndexed)]
+#[derive(Clone, SerializeIndexed)]
 pub struct Ctap2AuthenticatorConfigRequest {
     // subCommand (0x01)
     #[serde(index = 0x01)]
@@ -27,6 +28,17 @@ pub struct Ctap2AuthenticatorConfigRequest {
     pub uv_auth_param: Option<ByteBuf>,
 }
 
+impl std::fmt::Debug for Ctap2AuthenticatorConfigRequest {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Ctap2AuthenticatorConfigRequest")
+            .field("subcommand", &self.subcommand)
+            .field("subcommand_params", &self.subcommand_params)
+            .field("protocol", &self.protocol)
+            .field("uv_auth_param", &redact(&self.uv_auth_param))
+            .finish()
+    }
+}
+
 impl Ctap2AuthenticatorConfigRequest {
     pub(crate) fn new_toggle_always_uv() -> Self {
         Ctap2AuthenticatorConfigRequest {
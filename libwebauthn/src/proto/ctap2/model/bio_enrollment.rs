@@ -1,10 +1,11 @@
 use super::Ctap2PinUvAuthProtocol;
+use crate::redact::redact;
 use serde_bytes::ByteBuf;
 use serde_indexed::{DeserializeIndexed, SerializeIndexed};
 use serde_repr::{Deserialize_repr, Serialize_repr};
 use std::time::Duration;
 
-#[derive(Debug, Clone, SerializeIndexed)]
+#[derive(Clone, SerializeIndexed)]
 pub struct Ctap2BioEnrollmentRequest {
     // modality (0x01) 	Unsigned Integer 	Optional 	The user verification modality being requested
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -67,7 +68,7 @@ pub struct Ctap2BioEnrollmentParams {
     timeout_milliseconds: Option<u64>,
 }
 
-#[derive(Debug, Default, Clone, DeserializeIndexed)]
+#[derive(Debug, Default, Clone, SerializeIndexed, DeserializeIndexed)]
 pub struct Ctap2BioEnrollmentResponse {
     // modality (0x01) 	Unsigned Integer 	Optional 	The user verification modality.
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -110,7 +111,7 @@ pub struct Ctap2BioEnrollmentResponse {
     pub max_template_friendly_name: Option<u64>,
 }
 
-#[derive(Debug, Clone, DeserializeIndexed)]
+#[derive(Debug, Clone, SerializeIndexed, DeserializeIndexed)]
 pub struct Ctap2BioEnrollmentTemplateId {
     // templateId (0x01) 	Byte String 	Required 	Template Identifier.
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -150,12 +151,26 @@ pub enum Ctap2BioEnrollmentModality {
 }
 
 #[repr(u64)]
-#[derive(Debug, Clone, FromPrimitive, PartialEq, Serialize_repr, Deserialize_repr)]
+#[derive(Debug, Clone, Copy, FromPrimitive, PartialEq, Eq, Serialize_repr, Deserialize_repr)]
 pub enum Ctap2BioEnrollmentFingerprintKind {
     Touch = 0x01,
     Swipe = 0x02,
 }
 
+impl std::fmt::Debug for Ctap2BioEnrollmentRequest {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Ctap2BioEnrollmentRequest")
+            .field("modality", &self.modality)
+            .field("subcommand", &self.subcommand)
+            .field("subcommand_params", &self.subcommand_params)
+            .field("protocol", &self.protocol)
+            .field("uv_auth_param", &redact(&self.uv_auth_param))
+            .field("get_modality", &self.get_modality)
+            .field("use_legacy_preview", &self.use_legacy_preview)
+            .finish()
+    }
+}
+
 impl Ctap2BioEnrollmentRequest {
     pub fn new_get_modality() -> Self {
         Ctap2BioEnrollmentRequest {
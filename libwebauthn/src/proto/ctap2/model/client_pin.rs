@@ -1,11 +1,15 @@
 use cosey::PublicKey;
+use serde::ser::SerializeStruct;
+use serde::{Serialize, Serializer};
 use serde_bytes::ByteBuf;
 use serde_indexed::{DeserializeIndexed, SerializeIndexed};
 use serde_repr::{Deserialize_repr, Serialize_repr};
+use zeroize::Zeroize;
 
 use crate::pin::{PinUvAuthProtocol, PinUvAuthProtocolOne, PinUvAuthProtocolTwo};
+use crate::redact::redact;
 
-#[derive(Debug, Clone, SerializeIndexed)]
+#[derive(Clone, SerializeIndexed)]
 pub struct Ctap2ClientPinRequest {
     ///pinUvAuthProtocol (0x01)
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -47,6 +51,21 @@ pub struct Ctap2ClientPinRequest {
     pub permissions_rpid: Option<String>,
 }
 
+impl std::fmt::Debug for Ctap2ClientPinRequest {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Ctap2ClientPinRequest")
+            .field("protocol", &self.protocol)
+            .field("command", &self.command)
+            .field("key_agreement", &self.key_agreement)
+            .field("uv_auth_param", &redact(&self.uv_auth_param))
+            .field("new_pin_encrypted", &redact(&self.new_pin_encrypted))
+            .field("pin_hash_encrypted", &redact(&self.pin_hash_encrypted))
+            .field("permissions", &self.permissions)
+            .field("permissions_rpid", &self.permissions_rpid)
+            .finish()
+    }
+}
+
 impl Ctap2ClientPinRequest {
     pub fn new_get_key_agreement(protocol: Ctap2PinUvAuthProtocol) -> Self {
         Self {
@@ -181,7 +200,7 @@ impl Ctap2ClientPinRequest {
 }
 
 bitflags! {
-    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    #[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
     pub struct Ctap2AuthTokenPermissionRole: u32 {
         const MAKE_CREDENTIAL = 0x01;
         const GET_ASSERTION = 0x02;
@@ -208,6 +227,17 @@ impl Ctap2PinUvAuthProtocol {
     }
 }
 
+impl Drop for Ctap2ClientPinRequest {
+    fn drop(&mut self) {
+        for encrypted in [&mut self.new_pin_encrypted, &mut self.pin_hash_encrypted]
+            .into_iter()
+            .flatten()
+        {
+            encrypted.zeroize();
+        }
+    }
+}
+
 #[repr(u32)]
 #[derive(Debug, Clone, FromPrimitive, PartialEq, Serialize_repr, Deserialize_repr)]
 pub enum Ctap2PinUvAuthProtocolCommand {
@@ -221,7 +251,7 @@ pub enum Ctap2PinUvAuthProtocolCommand {
     GetPinUvAuthTokenUsingPinWithPermissions = 0x09,
 }
 
-#[derive(Debug, Clone, Default, DeserializeIndexed)]
+#[derive(Clone, Default, DeserializeIndexed)]
 pub struct Ctap2ClientPinResponse {
     /// keyAgreement (0x01)
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -248,3 +278,66 @@ pub struct Ctap2ClientPinResponse {
     #[serde(index = 0x05)]
     pub uv_retries: Option<u32>,
 }
+
+impl std::fmt::Debug for Ctap2ClientPinResponse {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Ctap2ClientPinResponse")
+            .field("key_agreement", &self.key_agreement)
+            .field("pin_uv_auth_token", &redact(&self.pin_uv_auth_token))
+            .field("pin_retries", &self.pin_retries)
+            .field("power_cycle_state", &self.power_cycle_state)
+            .field("uv_retries", &self.uv_retries)
+            .finish()
+    }
+}
+
+/// Serializes with named keys, for diagnostics. The pinUvAuthToken is left out, as it is a
+/// secret, like in the [Debug] output.
+impl Serialize for Ctap2ClientPinResponse {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut state = serializer.serialize_struct("Ctap2ClientPinResponse", 4)?;
+        if let Some(key_agreement) = &self.key_agreement {
+            state.serialize_field("keyAgreement", key_agreement)?;
+        }
+        if let Some(pin_retries) = self.pin_retries {
+            state.serialize_field("pinRetries", &pin_retries)?;
+        }
+        if let Some(power_cycle_state) = self.power_cycle_state {
+            state.serialize_field("powerCycleState", &power_cycle_state)?;
+        }
+        if let Some(uv_retries) = self.uv_retries {
+            state.serialize_field("uvRetries", &uv_retries)?;
+        }
+        state.end()
+    }
+}
+
+impl Drop for Ctap2ClientPinResponse {
+    fn drop(&mut self) {
+        if let Some(token) = self.pin_uv_auth_token.as_mut() {
+            token.zeroize();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_bytes::ByteBuf;
+    use serde_json::json;
+
+    use super::Ctap2ClientPinResponse;
+
+    #[test]
+    fn serialized_response_omits_token() {
+        let mut response = Ctap2ClientPinResponse::default();
+        response.pin_uv_auth_token = Some(ByteBuf::from([0xab; 32]));
+        response.pin_retries = Some(3);
+        assert_eq!(
+            serde_json::to_value(&response).unwrap(),
+            json!({ "pinRetries": 3 })
+        );
+    }
+}
@@ -2,12 +2,14 @@ use super::{
     Ctap2PinUvAuthProtocol, Ctap2PublicKeyCredentialDescriptor, Ctap2PublicKeyCredentialRpEntity,
     Ctap2PublicKeyCredentialUserEntity,
 };
+use crate::redact::redact;
 use cosey::PublicKey;
+use serde::Serialize;
 use serde_bytes::ByteBuf;
 use serde_indexed::{DeserializeIndexed, SerializeIndexed};
 use serde_repr::{Deserialize_repr, Serialize_repr};
 
-#[derive(Debug, Clone, SerializeIndexed)]
+#[derive(Clone, SerializeIndexed)]
 pub struct Ctap2CredentialManagementRequest {
     //subCommand (0x01) 	Unsigned Integer 	subCommand currently being requested
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -63,7 +65,7 @@ pub struct Ctap2CredentialManagementParams {
     user: Option<Ctap2PublicKeyCredentialUserEntity>,
 }
 
-#[derive(Debug, Default, Clone, DeserializeIndexed)]
+#[derive(Debug, Default, Clone, SerializeIndexed, DeserializeIndexed)]
 pub struct Ctap2CredentialManagementResponse {
     // existingResidentCredentialsCount (0x01) 	Unsigned Integer 	Number of existing discoverable credentials present on the authenticator.
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -121,6 +123,18 @@ pub struct Ctap2CredentialManagementResponse {
     pub large_blob_key: Option<ByteBuf>,
 }
 
+impl std::fmt::Debug for Ctap2CredentialManagementRequest {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Ctap2CredentialManagementRequest")
+            .field("subcommand", &self.subcommand)
+            .field("subcommand_params", &self.subcommand_params)
+            .field("protocol", &self.protocol)
+            .field("uv_auth_param", &redact(&self.uv_auth_param))
+            .field("use_legacy_preview", &self.use_legacy_preview)
+            .finish()
+    }
+}
+
 impl Ctap2CredentialManagementRequest {
     pub fn new_get_credential_metadata() -> Self {
         Ctap2CredentialManagementRequest {
@@ -210,7 +224,7 @@ impl Ctap2CredentialManagementRequest {
     }
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize)]
 pub struct Ctap2CredentialManagementMetadata {
     pub existing_resident_credentials_count: u64,
     pub max_possible_remaining_resident_credentials_count: u64,
@@ -228,7 +242,7 @@ impl Ctap2CredentialManagementMetadata {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize)]
 pub struct Ctap2CredentialData {
     pub user: Ctap2PublicKeyCredentialUserEntity,
     pub credential_id: Ctap2PublicKeyCredentialDescriptor,
@@ -256,7 +270,7 @@ impl Ctap2CredentialData {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize)]
 pub struct Ctap2RPData {
     pub rp: Ctap2PublicKeyCredentialRpEntity,
     pub rp_id_hash: Vec<u8>,
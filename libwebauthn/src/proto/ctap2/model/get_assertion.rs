@@ -7,6 +7,7 @@ use crate::{
         GetAssertionResponseUnsignedExtensions, HMACGetSecretInput, PRFValue,
     },
     pin::PinUvAuthProtocol,
+    redact::{redact, REDACTED},
     transport::AuthTokenData,
     webauthn::{Error, PlatformError},
 };
@@ -43,7 +44,7 @@ impl Ctap2GetAssertionOptions {
     }
 }
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct PackedAttestationStmt {
     #[serde(rename = "alg")]
     pub algorithm: Ctap2COSEAlgorithmIdentifier,
@@ -55,7 +56,7 @@ pub struct PackedAttestationStmt {
     pub certificates: Vec<ByteBuf>,
 }
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct FidoU2fAttestationStmt {
     #[serde(rename = "sig")]
     pub signature: ByteBuf,
@@ -64,7 +65,7 @@ pub struct FidoU2fAttestationStmt {
     pub certificate: ByteBuf,
 }
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct TpmAttestationStmt {
     #[serde(rename = "ver")]
     pub version: String,
@@ -85,24 +86,34 @@ pub struct TpmAttestationStmt {
     pub public_area: ByteBuf,
 }
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SafetyNetAttestationStmt {
+    #[serde(rename = "ver")]
+    pub version: String,
+
+    /// The SafetyNet API response, a compact JWS.
+    pub response: ByteBuf,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct AppleAnonymousAttestationStmt {
     #[serde(rename = "x5c")]
     pub certificates: Vec<ByteBuf>,
 }
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(untagged)]
 pub enum Ctap2AttestationStatement {
     PackedOrAndroid(PackedAttestationStmt),
     Tpm(TpmAttestationStmt),
     FidoU2F(FidoU2fAttestationStmt),
     AppleAnonymous(AppleAnonymousAttestationStmt),
+    AndroidSafetyNet(SafetyNetAttestationStmt),
     None(BTreeMap<Value, Value>),
 }
 
 // https://www.w3.org/TR/webauthn/#op-get-assertion
-#[derive(Debug, Clone, SerializeIndexed)]
+#[derive(Clone, SerializeIndexed)]
 pub struct Ctap2GetAssertionRequest {
     /// rpId (0x01)
     #[serde(index = 0x01)]
@@ -138,6 +149,20 @@ pub struct Ctap2GetAssertionRequest {
     pub pin_auth_proto: Option<u32>,
 }
 
+impl std::fmt::Debug for Ctap2GetAssertionRequest {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Ctap2GetAssertionRequest")
+            .field("relying_party_id", &self.relying_party_id)
+            .field("client_data_hash", &self.client_data_hash)
+            .field("allow", &self.allow)
+            .field("extensions", &self.extensions)
+            .field("options", &self.options)
+            .field("pin_auth_param", &redact(&self.pin_auth_param))
+            .field("pin_auth_proto", &self.pin_auth_proto)
+            .finish()
+    }
+}
+
 impl Ctap2GetAssertionRequest {
     pub fn skip_serializing_extensions(
         extensions: &Option<Ctap2GetAssertionRequestExtensions>,
@@ -351,7 +376,7 @@ impl Ctap2GetAssertionRequestExtensions {
     }
 }
 
-#[derive(Debug, Clone, SerializeIndexed)]
+#[derive(Clone, SerializeIndexed)]
 pub struct CalculatedHMACGetSecretInput {
     // keyAgreement(0x01): public key of platform key-agreement key.
     #[serde(index = 0x01)]
@@ -368,7 +393,18 @@ pub struct CalculatedHMACGetSecretInput {
     pub pin_auth_proto: Option<u32>,
 }
 
-#[derive(Debug, Clone, DeserializeIndexed)]
+impl std::fmt::Debug for CalculatedHMACGetSecretInput {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CalculatedHMACGetSecretInput")
+            .field("public_key", &self.public_key)
+            .field("salt_enc", &REDACTED)
+            .field("salt_auth", &REDACTED)
+            .field("pin_auth_proto", &self.pin_auth_proto)
+            .finish()
+    }
+}
+
+#[derive(Debug, Clone, SerializeIndexed, DeserializeIndexed)]
 pub struct Ctap2GetAssertionResponse {
     #[serde(skip_serializing_if = "Option::is_none")]
     #[serde(index = 0x01)]
@@ -543,6 +579,7 @@ impl Ctap2GetAssertionResponseExtensions {
             .unwrap_or_default();
 
         GetAssertionResponseUnsignedExtensions {
+            app_id: None,
             hmac_get_secret,
             large_blob,
             prf,
@@ -1,12 +1,13 @@
 use std::collections::HashMap;
 
 use serde_bytes::ByteBuf;
-use serde_indexed::DeserializeIndexed;
+use serde_indexed::{DeserializeIndexed, SerializeIndexed};
 use tracing::debug;
 
 use super::{Ctap2CredentialType, Ctap2UserVerificationOperation};
+use crate::ops::webauthn::UvMethodPreference;
 
-#[derive(Debug, Clone, DeserializeIndexed)]
+#[derive(Debug, Clone, Default, SerializeIndexed, DeserializeIndexed)]
 pub struct Ctap2GetInfoResponse {
     /// versions (0x01)
     #[serde(index = 0x01)]
@@ -161,6 +162,12 @@ impl Ctap2GetInfoResponse {
         options.get(name) == Some(&true)
     }
 
+    /// Product name of the device, e.g. "YubiKey 5 Series with NFC", if its AAGUID is known.
+    #[cfg(feature = "aaguid-names")]
+    pub fn product_name(&self) -> Option<&'static str> {
+        crate::aaguid::product_name_from_bytes(&self.aaguid)
+    }
+
     pub fn supports_fido_2_1(&self) -> bool {
         self.versions.iter().any(|v| v == "FIDO_2_1")
     }
@@ -195,33 +202,137 @@ impl Ctap2GetInfoResponse {
             (self.option_enabled("pinUvAuthToken") && self.option_enabled("uv"))
     }
 
+    /// Built-in UV attempts the platform should make before falling back to PIN.
+    /// Defaults to a single attempt if the authenticator doesn't state a preference.
+    pub fn platform_uv_attempts(&self) -> u32 {
+        self.preferred_platform_uv_attempts
+            .filter(|&attempts| attempts > 0)
+            .unwrap_or(1)
+    }
+
     pub fn uv_operation(&self, uv_blocked: bool) -> Option<Ctap2UserVerificationOperation> {
-        if self.option_enabled("uv") && !uv_blocked {
+        self.uv_operation_with_preference(uv_blocked, UvMethodPreference::default())
+    }
+
+    pub fn uv_operation_with_preference(
+        &self,
+        uv_blocked: bool,
+        preference: UvMethodPreference,
+    ) -> Option<Ctap2UserVerificationOperation> {
+        let builtin_uv = self.option_enabled("uv") && !uv_blocked;
+        let use_builtin_uv = match preference {
+            UvMethodPreference::PreferBio => builtin_uv,
+            UvMethodPreference::PreferPin => builtin_uv && !self.option_enabled("clientPin"),
+            UvMethodPreference::PinOnly => false,
+            UvMethodPreference::BioOnly => {
+                if !builtin_uv {
+                    debug!("Built-in UV unavailable or blocked, and PIN disallowed by preference");
+                    return None;
+                }
+                true
+            }
+        };
+        if use_builtin_uv {
             if self.option_enabled("pinUvAuthToken") {
                 debug!("getPinUvAuthTokenUsingUvWithPermissions");
-                return Some(
-                    Ctap2UserVerificationOperation::GetPinUvAuthTokenUsingUvWithPermissions,
-                );
+                Some(Ctap2UserVerificationOperation::GetPinUvAuthTokenUsingUvWithPermissions)
             } else {
                 debug!("Deprecated FIDO 2.0 behaviour: populating 'uv' flag");
-                return Some(Ctap2UserVerificationOperation::None);
+                Some(Ctap2UserVerificationOperation::None)
             }
+        } else if !self.option_enabled("clientPin") {
+            debug!("No UV and no PIN (e.g. maybe UV was blocked and no PIN available)");
+            None
+        } else if self.option_enabled("pinUvAuthToken") {
+            debug!("getPinUvAuthTokenUsingPinWithPermissions");
+            Some(Ctap2UserVerificationOperation::GetPinUvAuthTokenUsingPinWithPermissions)
         } else {
-            // !uv
-            if self.option_enabled("pinUvAuthToken") {
-                assert!(self.option_enabled("clientPin"));
-                debug!("getPinUvAuthTokenUsingPinWithPermissions");
-                return Some(
-                    Ctap2UserVerificationOperation::GetPinUvAuthTokenUsingPinWithPermissions,
-                );
-            } else if self.option_enabled("clientPin") {
-                // !pinUvAuthToken
-                debug!("getPinToken");
-                return Some(Ctap2UserVerificationOperation::GetPinToken);
-            } else {
-                debug!("No UV and no PIN (e.g. maybe UV was blocked and no PIN available)");
-                return None;
-            }
+            // !pinUvAuthToken
+            debug!("getPinToken");
+            Some(Ctap2UserVerificationOperation::GetPinToken)
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use serde_bytes::ByteBuf;
+
+    use super::Ctap2GetInfoResponse;
+    use crate::ops::webauthn::UvMethodPreference;
+    use crate::proto::ctap2::{cbor, Ctap2UserVerificationOperation};
+
+    fn info(options: &[(&str, bool)]) -> Ctap2GetInfoResponse {
+        let options: HashMap<String, bool> = options
+            .iter()
+            .map(|&(name, enabled)| (name.to_string(), enabled))
+            .collect();
+        Ctap2GetInfoResponse {
+            options: Some(options),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn uv_operation_honors_preference() {
+        let bio_and_pin = info(&[("uv", true), ("clientPin", true), ("pinUvAuthToken", true)]);
+        let using_uv =
+            Some(Ctap2UserVerificationOperation::GetPinUvAuthTokenUsingUvWithPermissions);
+        let using_pin =
+            Some(Ctap2UserVerificationOperation::GetPinUvAuthTokenUsingPinWithPermissions);
+
+        let op = |uv_blocked, preference| {
+            bio_and_pin.uv_operation_with_preference(uv_blocked, preference)
+        };
+        assert_eq!(op(false, UvMethodPreference::PreferBio), using_uv);
+        assert_eq!(op(true, UvMethodPreference::PreferBio), using_pin);
+        assert_eq!(op(false, UvMethodPreference::PreferPin), using_pin);
+        assert_eq!(op(false, UvMethodPreference::PinOnly), using_pin);
+        assert_eq!(op(false, UvMethodPreference::BioOnly), using_uv);
+        assert_eq!(op(true, UvMethodPreference::BioOnly), None);
+
+        let bio_only = info(&[("uv", true), ("clientPin", false), ("pinUvAuthToken", true)]);
+        assert_eq!(
+            bio_only.uv_operation_with_preference(false, UvMethodPreference::PreferPin),
+            using_uv
+        );
+        assert_eq!(
+            bio_only.uv_operation_with_preference(false, UvMethodPreference::PinOnly),
+            None
+        );
+    }
+
+    #[test]
+    fn platform_uv_attempts_defaults_to_one() {
+        let info_with_attempts = |attempts| Ctap2GetInfoResponse {
+            preferred_platform_uv_attempts: attempts,
+            ..Default::default()
+        };
+        assert_eq!(info_with_attempts(Some(3)).platform_uv_attempts(), 3);
+        assert_eq!(info_with_attempts(Some(0)).platform_uv_attempts(), 1);
+        assert_eq!(info_with_attempts(None).platform_uv_attempts(), 1);
+    }
+
+    #[test]
+    fn serialized_info_round_trips() {
+        let info = Ctap2GetInfoResponse {
+            versions: vec!["FIDO_2_1".to_string()],
+            aaguid: ByteBuf::from([7; 16]),
+            max_msg_size: Some(1200),
+            ..info(&[("rk", true)])
+        };
+        let reparsed: Ctap2GetInfoResponse =
+            cbor::from_slice(&cbor::to_vec(&info).unwrap()).unwrap();
+        assert_eq!(reparsed.versions, info.versions);
+        assert_eq!(reparsed.aaguid, info.aaguid);
+        assert_eq!(reparsed.max_msg_size, Some(1200));
+        assert_eq!(reparsed.options, info.options);
+        assert!(reparsed.extensions.is_none());
+
+        let json = serde_json::to_value(&info).unwrap();
+        assert_eq!(json["1"][0], "FIDO_2_1");
+        assert_eq!(json["5"], 1200);
+    }
+}
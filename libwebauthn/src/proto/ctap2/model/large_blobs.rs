@@ -0,0 +1,84 @@
+use serde_bytes::ByteBuf;
+use serde_indexed::{DeserializeIndexed, SerializeIndexed};
+
+use super::Ctap2PinUvAuthProtocol;
+use crate::redact::redact;
+
+#[derive(Clone, SerializeIndexed)]
+pub struct Ctap2LargeBlobsRequest {
+    // get (0x01) 	Unsigned Integer 	The number of bytes requested to read.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(index = 0x01)]
+    pub get: Option<u32>,
+
+    // set (0x02) 	Byte String 	A fragment to write.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(index = 0x02)]
+    pub set: Option<ByteBuf>,
+
+    // offset (0x03) 	Unsigned Integer 	The byte offset at which to read/write.
+    #[serde(index = 0x03)]
+    pub offset: u32,
+
+    // length (0x04) 	Unsigned Integer 	The total length of a write operation, on its first fragment.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(index = 0x04)]
+    pub length: Option<u32>,
+
+    // pinUvAuthParam (0x05) 	Byte String
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(index = 0x05)]
+    pub uv_auth_param: Option<ByteBuf>,
+
+    // pinUvAuthProtocol (0x06) 	Unsigned Integer
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(index = 0x06)]
+    pub protocol: Option<Ctap2PinUvAuthProtocol>,
+}
+
+impl std::fmt::Debug for Ctap2LargeBlobsRequest {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Ctap2LargeBlobsRequest")
+            .field("get", &self.get)
+            .field("set", &self.set.as_ref().map(|fragment| fragment.len()))
+            .field("offset", &self.offset)
+            .field("length", &self.length)
+            .field("uv_auth_param", &redact(&self.uv_auth_param))
+            .field("protocol", &self.protocol)
+            .finish()
+    }
+}
+
+impl Ctap2LargeBlobsRequest {
+    pub(crate) fn new_get(offset: u32, count: u32) -> Self {
+        Ctap2LargeBlobsRequest {
+            get: Some(count),
+            set: None,
+            offset,
+            length: None,
+            uv_auth_param: None,
+            protocol: None,
+        }
+    }
+
+    /// Writes `fragment` at `offset`. The first fragment, at offset 0, carries the `length`
+    /// of the whole serialized array.
+    pub(crate) fn new_set(offset: u32, fragment: &[u8], length: Option<u32>) -> Self {
+        Ctap2LargeBlobsRequest {
+            get: None,
+            set: Some(ByteBuf::from(fragment)),
+            offset,
+            length,
+            uv_auth_param: None, // Will be filled out later by user_verification()
+            protocol: None,      // Will be filled out later by user_verification()
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, SerializeIndexed, DeserializeIndexed)]
+pub struct Ctap2LargeBlobsResponse {
+    // config (0x01) 	Byte String 	The fragment read, in response to get.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(index = 0x01)]
+    pub config: Option<ByteBuf>,
+}
@@ -5,22 +5,23 @@ use super::{
     Ctap2UserVerifiableRequest,
 };
 use crate::{
-    fido::AuthenticatorData,
+    audit::AuditUvMethod,
+    fido::{AuthenticatorData, FidoProtocol},
     ops::webauthn::{
-        CredentialProtectionPolicy, ResidentKeyRequirement,
-        MakeCredentialHmacOrPrfInput, MakeCredentialLargeBlobExtension, MakeCredentialRequest,
-        MakeCredentialResponse, MakeCredentialsRequestExtensions,
-        MakeCredentialsResponseUnsignedExtensions,
+        CredentialProtectionPolicy, MakeCredentialHmacOrPrfInput, MakeCredentialLargeBlobExtension,
+        MakeCredentialRequest, MakeCredentialResponse, MakeCredentialsRequestExtensions,
+        MakeCredentialsResponseUnsignedExtensions, ResidentKeyRequirement,
     },
     pin::PinUvAuthProtocol,
     proto::CtapError,
+    redact::redact,
     webauthn::Error,
 };
 use ctap_types::ctap2::credential_management::CredentialProtectionPolicy as Ctap2CredentialProtectionPolicy;
 use serde::{Deserialize, Serialize};
 use serde_bytes::ByteBuf;
 use serde_indexed::{DeserializeIndexed, SerializeIndexed};
-use tracing::warn;
+use tracing::{debug, warn};
 
 #[derive(Debug, Default, Clone, Copy, Serialize)]
 pub struct Ctap2MakeCredentialOptions {
@@ -40,7 +41,7 @@ impl Ctap2MakeCredentialOptions {
 }
 
 // https://www.w3.org/TR/webauthn/#authenticatormakecredential
-#[derive(Debug, Clone, SerializeIndexed)]
+#[derive(Clone, SerializeIndexed)]
 pub struct Ctap2MakeCredentialRequest {
     /// clientDataHash (0x01)
     #[serde(index = 0x01)]
@@ -87,6 +88,32 @@ pub struct Ctap2MakeCredentialRequest {
     #[serde(skip_serializing_if = "Option::is_none")]
     #[serde(index = 0x0A)]
     pub enterprise_attestation: Option<u32>,
+
+    /// attestationFormatsPreference (0x0B)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(index = 0x0B)]
+    pub attestation_formats_preference: Option<Vec<String>>,
+}
+
+impl std::fmt::Debug for Ctap2MakeCredentialRequest {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Ctap2MakeCredentialRequest")
+            .field("hash", &self.hash)
+            .field("relying_party", &self.relying_party)
+            .field("user", &self.user)
+            .field("algorithms", &self.algorithms)
+            .field("exclude", &self.exclude)
+            .field("extensions", &self.extensions)
+            .field("options", &self.options)
+            .field("pin_auth_param", &redact(&self.pin_auth_param))
+            .field("pin_auth_proto", &self.pin_auth_proto)
+            .field("enterprise_attestation", &self.enterprise_attestation)
+            .field(
+                "attestation_formats_preference",
+                &self.attestation_formats_preference,
+            )
+            .finish()
+    }
 }
 
 impl Ctap2MakeCredentialRequest {
@@ -106,6 +133,7 @@ impl Ctap2MakeCredentialRequest {
             pin_auth_param: Some(ByteBuf::from(Vec::new())),
             pin_auth_proto: Some(Ctap2PinUvAuthProtocol::One as u32),
             enterprise_attestation: None,
+            attestation_formats_preference: None,
         }
     }
 
@@ -158,6 +186,20 @@ impl Ctap2MakeCredentialRequest {
             None => None,
         };
 
+        // Authenticators before CTAP 2.2 may reject the unknown parameter, and don't list
+        // their formats.
+        let attestation_formats_preference = match (
+            req.attestation_formats.is_empty(),
+            &info.attestation_formats,
+        ) {
+            (true, _) => None,
+            (false, Some(_)) => Some(req.attestation_formats.clone()),
+            (false, None) => {
+                debug!("Device doesn't support attestationFormatsPreference, ignoring it");
+                None
+            }
+        };
+
         Ok(Ctap2MakeCredentialRequest {
             hash: ByteBuf::from(req.hash.clone()),
             relying_party: req.relying_party.clone(),
@@ -172,6 +214,7 @@ impl Ctap2MakeCredentialRequest {
             pin_auth_param: None,
             pin_auth_proto: None,
             enterprise_attestation: None,
+            attestation_formats_preference,
         })
     }
 }
@@ -269,7 +312,7 @@ impl Ctap2MakeCredentialsRequestExtensions {
     }
 }
 
-#[derive(Debug, Clone, DeserializeIndexed)]
+#[derive(Debug, Clone, SerializeIndexed, DeserializeIndexed)]
 pub struct Ctap2MakeCredentialResponse {
     #[serde(index = 0x01)]
     pub format: String,
@@ -301,6 +344,7 @@ impl Ctap2MakeCredentialResponse {
                 request,
                 info,
             );
+        let backup_state = self.authenticator_data.backup_state();
         MakeCredentialResponse {
             format: self.format,
             authenticator_data: self.authenticator_data,
@@ -308,6 +352,10 @@ impl Ctap2MakeCredentialResponse {
             enterprise_attestation: self.enterprise_attestation,
             large_blob_key: self.large_blob_key.map(|x| x.into_vec()),
             unsigned_extensions_output,
+            always_uv_enforced: false,
+            protocol: FidoProtocol::FIDO2,
+            backup_state,
+            uv_method: AuditUvMethod::None,
         }
     }
 }
@@ -371,3 +419,27 @@ pub struct Ctap2MakeCredentialsResponseExtensions {
     #[serde(default, skip_serializing_if = "Option::is_none")]
     pub min_pin_length: Option<u32>,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::Ctap2MakeCredentialRequest;
+    use crate::ops::webauthn::MakeCredentialRequest;
+    use crate::proto::ctap2::Ctap2GetInfoResponse;
+
+    #[test]
+    fn attestation_formats_only_sent_when_advertised() {
+        let mut request = MakeCredentialRequest::dummy();
+        request.attestation_formats = vec!["packed".to_owned(), "none".to_owned()];
+
+        let mut info = Ctap2GetInfoResponse::default();
+        let ctap2 = Ctap2MakeCredentialRequest::from_webauthn_request(&request, &info).unwrap();
+        assert_eq!(ctap2.attestation_formats_preference, None);
+
+        info.attestation_formats = Some(vec!["packed".to_owned()]);
+        let ctap2 = Ctap2MakeCredentialRequest::from_webauthn_request(&request, &info).unwrap();
+        assert_eq!(
+            ctap2.attestation_formats_preference,
+            Some(request.attestation_formats)
+        );
+    }
+}
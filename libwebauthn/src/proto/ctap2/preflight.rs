@@ -27,18 +27,8 @@ pub(crate) async fn ctap2_preflight<C: Channel>(
     info!("Credential list BEFORE preflight: {credentials:?}");
     let mut filtered_list = Vec::new();
     for credential in credentials {
-        let preflight_request = Ctap2GetAssertionRequest {
-            relying_party_id: rp.to_string(),
-            client_data_hash: ByteBuf::from(client_data_hash),
-            allow: vec![credential.clone()],
-            extensions: None,
-            options: Some(Ctap2GetAssertionOptions {
-                require_user_presence: false,
-                require_user_verification: false,
-            }),
-            pin_auth_param: None,
-            pin_auth_proto: None,
-        };
+        let preflight_request =
+            silent_request(rp, client_data_hash, std::slice::from_ref(credential));
         match channel
             .ctap2_get_assertion(&preflight_request, Duration::from_secs(2))
             .await
@@ -71,3 +61,45 @@ pub(crate) async fn ctap2_preflight<C: Channel>(
     info!("Credential list AFTER preflight: {filtered_list:?}");
     filtered_list
 }
+
+/// Whether any of `credentials` is present under `rp`, asked with a single silent request. For
+/// channels without pre-flight, which may not find credentials one by one as quickly.
+pub(crate) async fn ctap2_probe<C: Channel>(
+    channel: &mut C,
+    credentials: &[Ctap2PublicKeyCredentialDescriptor],
+    client_data_hash: &[u8],
+    rp: &str,
+) -> bool {
+    let probe_request = silent_request(rp, client_data_hash, credentials);
+    match channel
+        .ctap2_get_assertion(&probe_request, Duration::from_secs(2))
+        .await
+    {
+        Ok(_) => true,
+        Err(e) => {
+            debug!(?e, rp, "Probe found no credentials");
+            false
+        }
+    }
+}
+
+/// A getAssertion request with up=false, which the authenticator answers without asking the
+/// user, if it has one of the `credentials`.
+fn silent_request(
+    rp: &str,
+    client_data_hash: &[u8],
+    credentials: &[Ctap2PublicKeyCredentialDescriptor],
+) -> Ctap2GetAssertionRequest {
+    Ctap2GetAssertionRequest {
+        relying_party_id: rp.to_string(),
+        client_data_hash: ByteBuf::from(client_data_hash),
+        allow: credentials.to_vec(),
+        extensions: None,
+        options: Some(Ctap2GetAssertionOptions {
+            require_user_presence: false,
+            require_user_verification: false,
+        }),
+        pin_auth_param: None,
+        pin_auth_proto: None,
+    }
+}
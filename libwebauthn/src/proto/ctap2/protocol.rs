@@ -3,18 +3,23 @@ use std::time::Duration;
 use async_trait::async_trait;
 use tracing::{debug, instrument, trace, warn};
 
-use crate::proto::ctap2::cbor::{self, CborRequest};
+use crate::proto::ctap2::cbor::{self, CborRequest, CborResponse};
 use crate::proto::ctap2::{Ctap2BioEnrollmentResponse, Ctap2CommandCode};
+use crate::quirks;
+use crate::timeout::OperationDeadline;
+use crate::transport::error::TransportError;
 use crate::transport::Channel;
 use crate::unwrap_field;
 use crate::webauthn::error::{CtapError, Error, PlatformError};
+use crate::UvUpdate;
 
-use super::model::Ctap2ClientPinResponse;
+use super::model::{Ctap2BioEnrollmentSubcommand, Ctap2ClientPinResponse};
 use super::{
     Ctap2AuthenticatorConfigRequest, Ctap2BioEnrollmentRequest, Ctap2ClientPinRequest,
     Ctap2CredentialManagementRequest, Ctap2CredentialManagementResponse, Ctap2GetAssertionRequest,
-    Ctap2GetAssertionResponse, Ctap2GetInfoResponse, Ctap2MakeCredentialRequest,
-    Ctap2MakeCredentialResponse,
+    Ctap2GetAssertionResponse, Ctap2GetInfoResponse, Ctap2LargeBlobsRequest,
+    Ctap2LargeBlobsResponse, Ctap2MakeCredentialRequest, Ctap2MakeCredentialResponse,
+    Ctap2PinUvAuthProtocolCommand,
 };
 
 const TIMEOUT_GET_INFO: Duration = Duration::from_millis(250);
@@ -29,50 +34,120 @@ macro_rules! parse_cbor {
                     stringify!($type),
                     e
                 );
-                return Err(Error::Platform(PlatformError::InvalidDeviceResponse));
+                return Err(Error::Platform(PlatformError::InvalidResponse {
+                    response: stringify!($type),
+                    source: e,
+                }));
             }
         }
     }};
 }
 
+/// Space taken by the pinUvAuthParam and pinUvAuthProtocol members user verification may add
+/// to a request: their keys, a byte string of up to 32 bytes, and the protocol number.
+pub(crate) const PIN_UV_AUTH_PARAM_ALLOWANCE: usize = 1 + 2 + 32 + 1 + 1;
+
+/// Space taken by an hmac-secret extension calculated after user verification: its name, the
+/// platform's COSE key agreement key, up to two encrypted salts with their IV, the salt
+/// authentication and the protocol number.
+pub(crate) const HMAC_SECRET_ALLOWANCE: usize = 14 + 1 + 79 + 83 + 35 + 2;
+
+/// Rejects `request` if it, grown by `allowance` bytes, exceeds the maxMsgSize advertised in
+/// `info`. Devices fail oversized messages with an unhelpful error, or drop them altogether, so
+/// this is checked before asking the user for anything. `allowance` covers what user
+/// verification adds to the request later on. Long credential lists are normally trimmed by
+/// pre-flight first, see [super::preflight].
+pub(crate) fn check_max_msg_size(
+    request: &CborRequest,
+    allowance: usize,
+    info: &Ctap2GetInfoResponse,
+) -> Result<(), Error> {
+    let Some(max_msg_size) = info.max_msg_size else {
+        return Ok(());
+    };
+    // The command byte counts towards the message size.
+    let size = request.encoded_data.len() + 1 + allowance;
+    if size > max_msg_size as usize {
+        warn!(size, max_msg_size, "Request exceeds maxMsgSize");
+        return Err(Error::Platform(PlatformError::RequestTooLarge {
+            size,
+            max_msg_size,
+        }));
+    }
+    Ok(())
+}
+
+/// Exchanges a request waiting for the user's presence or built-in UV. If the user doesn't act
+/// in time, sends `UvUpdate::OperationTimeout`, so the UI can take down its prompt.
+async fn user_presence_exchange<C: Channel>(
+    channel: &mut C,
+    request: &CborRequest,
+    timeout: Duration,
+) -> Result<CborResponse, Error> {
+    channel.cbor_send(request, timeout).await?;
+    let response = channel.cbor_recv(timeout).await;
+    let timed_out = match &response {
+        Ok(response) => matches!(
+            response.status_code,
+            CtapError::UserActionTimeout | CtapError::ActionTimeout
+        ),
+        Err(error) => *error == Error::Transport(TransportError::Timeout),
+    };
+    if timed_out {
+        warn!("User didn't act before the timeout");
+        channel
+            .send_ux_update(UvUpdate::OperationTimeout.into())
+            .await;
+    }
+    response
+}
+
 #[async_trait]
 pub trait Ctap2 {
     async fn ctap2_get_info(&mut self) -> Result<Ctap2GetInfoResponse, Error>;
     async fn ctap2_make_credential(
         &mut self,
         request: &Ctap2MakeCredentialRequest,
-        timeout: Duration,
+        timeout: impl Into<OperationDeadline> + Send,
     ) -> Result<Ctap2MakeCredentialResponse, Error>;
     async fn ctap2_client_pin(
         &mut self,
         request: &Ctap2ClientPinRequest,
-        timeout: Duration,
+        timeout: impl Into<OperationDeadline> + Send,
     ) -> Result<Ctap2ClientPinResponse, Error>;
     async fn ctap2_get_assertion(
         &mut self,
         request: &Ctap2GetAssertionRequest,
-        timeout: Duration,
+        timeout: impl Into<OperationDeadline> + Send,
     ) -> Result<Ctap2GetAssertionResponse, Error>;
     async fn ctap2_get_next_assertion(
         &mut self,
-        timeout: Duration,
+        timeout: impl Into<OperationDeadline> + Send,
     ) -> Result<Ctap2GetAssertionResponse, Error>;
-    async fn ctap2_selection(&mut self, timeout: Duration) -> Result<(), Error>;
+    async fn ctap2_selection(
+        &mut self,
+        timeout: impl Into<OperationDeadline> + Send,
+    ) -> Result<(), Error>;
     async fn ctap2_authenticator_config(
         &mut self,
         request: &Ctap2AuthenticatorConfigRequest,
-        timeout: Duration,
+        timeout: impl Into<OperationDeadline> + Send,
     ) -> Result<(), Error>;
     async fn ctap2_bio_enrollment(
         &mut self,
         request: &Ctap2BioEnrollmentRequest,
-        timeout: Duration,
+        timeout: impl Into<OperationDeadline> + Send,
     ) -> Result<Ctap2BioEnrollmentResponse, Error>;
     async fn ctap2_credential_management(
         &mut self,
         request: &Ctap2CredentialManagementRequest,
-        timeout: Duration,
+        timeout: impl Into<OperationDeadline> + Send,
     ) -> Result<Ctap2CredentialManagementResponse, Error>;
+    async fn ctap2_large_blobs(
+        &mut self,
+        request: &Ctap2LargeBlobsRequest,
+        timeout: impl Into<OperationDeadline> + Send,
+    ) -> Result<Ctap2LargeBlobsResponse, Error>;
 }
 
 #[async_trait]
@@ -83,14 +158,19 @@ where
     #[instrument(skip_all)]
     async fn ctap2_get_info(&mut self) -> Result<Ctap2GetInfoResponse, Error> {
         let cbor_request = CborRequest::new(Ctap2CommandCode::AuthenticatorGetInfo);
-        self.cbor_send(&cbor_request, TIMEOUT_GET_INFO).await?;
-        let cbor_response = self.cbor_recv(TIMEOUT_GET_INFO).await?;
+        let timeout = quirks::adjust_timeout(self.usb_id(), TIMEOUT_GET_INFO);
+        self.cbor_send(&cbor_request, timeout).await?;
+        let cbor_response = self.cbor_recv(timeout).await?;
         match cbor_response.status_code {
             CtapError::Ok => (),
             error => return Err(Error::Ctap(error)),
         };
         let data = unwrap_field!(cbor_response.data);
-        let ctap_response = parse_cbor!(Ctap2GetInfoResponse, &data);
+        let mut ctap_response = parse_cbor!(Ctap2GetInfoResponse, &data);
+        quirks::apply_to_get_info(self.usb_id(), &mut ctap_response);
+        if let Some(aaguid) = self.device_aaguid() {
+            aaguid.set(&ctap_response.aaguid);
+        }
         debug!("CTAP2 GetInfo successful");
         trace!(?ctap_response);
         Ok(ctap_response)
@@ -100,11 +180,12 @@ where
     async fn ctap2_make_credential(
         &mut self,
         request: &Ctap2MakeCredentialRequest,
-        timeout: Duration,
+        timeout: impl Into<OperationDeadline> + Send,
     ) -> Result<Ctap2MakeCredentialResponse, Error> {
+        let timeout =
+            quirks::adjust_timeout(self.usb_id(), timeout.into().user_presence_timeout()?);
         trace!(?request);
-        self.cbor_send(&request.into(), timeout).await?;
-        let cbor_response = self.cbor_recv(timeout).await?;
+        let cbor_response = user_presence_exchange(self, &request.into(), timeout).await?;
         match cbor_response.status_code {
             CtapError::Ok => (),
             error => return Err(Error::Ctap(error)),
@@ -121,11 +202,12 @@ where
     async fn ctap2_get_assertion(
         &mut self,
         request: &Ctap2GetAssertionRequest,
-        timeout: Duration,
+        timeout: impl Into<OperationDeadline> + Send,
     ) -> Result<Ctap2GetAssertionResponse, Error> {
+        let timeout =
+            quirks::adjust_timeout(self.usb_id(), timeout.into().user_presence_timeout()?);
         trace!(?request);
-        self.cbor_send(&request.into(), timeout).await?;
-        let cbor_response = self.cbor_recv(timeout).await?;
+        let cbor_response = user_presence_exchange(self, &request.into(), timeout).await?;
         match cbor_response.status_code {
             CtapError::Ok => (),
             error => return Err(Error::Ctap(error)),
@@ -141,8 +223,9 @@ where
     #[instrument(skip_all)]
     async fn ctap2_get_next_assertion(
         &mut self,
-        timeout: Duration,
+        timeout: impl Into<OperationDeadline> + Send,
     ) -> Result<Ctap2GetAssertionResponse, Error> {
+        let timeout = quirks::adjust_timeout(self.usb_id(), timeout.into().io_timeout()?);
         debug!("CTAP2 GetNextAssertion request");
         let cbor_request = CborRequest::new(Ctap2CommandCode::AuthenticatorGetNextAssertion);
         self.cbor_send(&cbor_request, timeout).await?;
@@ -155,12 +238,16 @@ where
     }
 
     #[instrument(skip_all)]
-    async fn ctap2_selection(&mut self, timeout: Duration) -> Result<(), Error> {
+    async fn ctap2_selection(
+        &mut self,
+        timeout: impl Into<OperationDeadline> + Send,
+    ) -> Result<(), Error> {
+        let timeout =
+            quirks::adjust_timeout(self.usb_id(), timeout.into().user_presence_timeout()?);
         debug!("CTAP2 Authenticator Selection request");
         let cbor_request = CborRequest::new(Ctap2CommandCode::AuthenticatorSelection);
 
-        self.cbor_send(&cbor_request, timeout).await?;
-        let cbor_response = self.cbor_recv(timeout).await?;
+        let cbor_response = user_presence_exchange(self, &cbor_request, timeout).await?;
         match cbor_response.status_code {
             CtapError::Ok => {
                 return Ok(());
@@ -176,11 +263,26 @@ where
     async fn ctap2_client_pin(
         &mut self,
         request: &Ctap2ClientPinRequest,
-        timeout: Duration,
+        timeout: impl Into<OperationDeadline> + Send,
     ) -> Result<Ctap2ClientPinResponse, Error> {
+        // Waits for the user's built-in UV.
+        let waits_for_user = request.command
+            == Ctap2PinUvAuthProtocolCommand::GetPinUvAuthTokenUsingUvWithPermissions;
+        let timeout = quirks::adjust_timeout(
+            self.usb_id(),
+            if waits_for_user {
+                timeout.into().user_presence_timeout()?
+            } else {
+                timeout.into().io_timeout()?
+            },
+        );
         trace!(?request);
-        self.cbor_send(&request.into(), timeout).await?;
-        let cbor_response = self.cbor_recv(timeout).await?;
+        let cbor_response = if waits_for_user {
+            user_presence_exchange(self, &request.into(), timeout).await?
+        } else {
+            self.cbor_send(&request.into(), timeout).await?;
+            self.cbor_recv(timeout).await?
+        };
         match cbor_response.status_code {
             CtapError::Ok => (),
             error => return Err(Error::Ctap(error)),
@@ -202,8 +304,9 @@ where
     async fn ctap2_authenticator_config(
         &mut self,
         request: &Ctap2AuthenticatorConfigRequest,
-        timeout: Duration,
+        timeout: impl Into<OperationDeadline> + Send,
     ) -> Result<(), Error> {
+        let timeout = quirks::adjust_timeout(self.usb_id(), timeout.into().io_timeout()?);
         trace!(?request);
         self.cbor_send(&request.into(), timeout).await?;
         let cbor_response = self.cbor_recv(timeout).await?;
@@ -225,8 +328,19 @@ where
     async fn ctap2_bio_enrollment(
         &mut self,
         request: &Ctap2BioEnrollmentRequest,
-        timeout: Duration,
+        timeout: impl Into<OperationDeadline> + Send,
     ) -> Result<Ctap2BioEnrollmentResponse, Error> {
+        let timeout = quirks::adjust_timeout(
+            self.usb_id(),
+            match request.subcommand {
+                // Waits for the user to touch the fingerprint sensor.
+                Some(Ctap2BioEnrollmentSubcommand::EnrollBegin)
+                | Some(Ctap2BioEnrollmentSubcommand::EnrollCaptureNextSample) => {
+                    timeout.into().user_presence_timeout()?
+                }
+                _ => timeout.into().io_timeout()?,
+            },
+        );
         trace!(?request);
         self.cbor_send(&request.into(), timeout).await?;
         let cbor_response = self.cbor_recv(timeout).await?;
@@ -251,8 +365,9 @@ where
     async fn ctap2_credential_management(
         &mut self,
         request: &Ctap2CredentialManagementRequest,
-        timeout: Duration,
+        timeout: impl Into<OperationDeadline> + Send,
     ) -> Result<Ctap2CredentialManagementResponse, Error> {
+        let timeout = quirks::adjust_timeout(self.usb_id(), timeout.into().io_timeout()?);
         trace!(?request);
         self.cbor_send(&request.into(), timeout).await?;
         let cbor_response = self.cbor_recv(timeout).await?;
@@ -272,4 +387,29 @@ where
             Ok(Ctap2CredentialManagementResponse::default())
         }
     }
+
+    #[instrument(skip_all)]
+    async fn ctap2_large_blobs(
+        &mut self,
+        request: &Ctap2LargeBlobsRequest,
+        timeout: impl Into<OperationDeadline> + Send,
+    ) -> Result<Ctap2LargeBlobsResponse, Error> {
+        let timeout = quirks::adjust_timeout(self.usb_id(), timeout.into().io_timeout()?);
+        trace!(?request);
+        self.cbor_send(&request.into(), timeout).await?;
+        let cbor_response = self.cbor_recv(timeout).await?;
+        match cbor_response.status_code {
+            CtapError::Ok => (),
+            error => return Err(Error::Ctap(error)),
+        };
+        if let Some(data) = cbor_response.data {
+            let ctap_response = parse_cbor!(Ctap2LargeBlobsResponse, &data);
+            debug!("CTAP2 LargeBlobs successful");
+            trace!(?ctap_response);
+            Ok(ctap_response)
+        } else {
+            // Writes are answered without any data.
+            Ok(Ctap2LargeBlobsResponse::default())
+        }
+    }
 }
@@ -1,6 +1,7 @@
 use num_enum::{IntoPrimitive, TryFromPrimitive};
 
 use crate::proto::ctap1::apdu::ApduResponseStatus;
+use crate::webauthn::error::RecommendedAction;
 
 // https://fidoalliance.org/specs/fido-v2.0-ps-20190130/fido-client-to-authenticator-protocol-v2.0-ps-20190130.html#error-responses
 
@@ -57,6 +58,84 @@ pub enum CtapError {
 }
 
 impl CtapError {
+    /// The error's name in the CTAP specification, e.g. `CTAP2_ERR_PIN_INVALID`.
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::Ok => "CTAP2_OK",
+            Self::InvalidCommand => "CTAP1_ERR_INVALID_COMMAND",
+            Self::InvalidParameter => "CTAP1_ERR_INVALID_PARAMETER",
+            Self::InvalidLength => "CTAP1_ERR_INVALID_LENGTH",
+            Self::InvalidSeq => "CTAP1_ERR_INVALID_SEQ",
+            Self::Timeout => "CTAP1_ERR_TIMEOUT",
+            Self::ChannelBusy => "CTAP1_ERR_CHANNEL_BUSY",
+            Self::LockRequired => "CTAP1_ERR_LOCK_REQUIRED",
+            Self::InvalidChannel => "CTAP1_ERR_INVALID_CHANNEL",
+            Self::InvalidCborType => "CTAP2_ERR_CBOR_UNEXPECTED_TYPE",
+            Self::InvalidCbor => "CTAP2_ERR_INVALID_CBOR",
+            Self::MissingParameter => "CTAP2_ERR_MISSING_PARAMETER",
+            Self::LimitExceeded => "CTAP2_ERR_LIMIT_EXCEEDED",
+            Self::UnsupportedExtension => "CTAP2_ERR_UNSUPPORTED_EXTENSION",
+            Self::CredentialExcluded => "CTAP2_ERR_CREDENTIAL_EXCLUDED",
+            Self::Processing => "CTAP2_ERR_PROCESSING",
+            Self::InvalidCredential => "CTAP2_ERR_INVALID_CREDENTIAL",
+            Self::UserActionPending => "CTAP2_ERR_USER_ACTION_PENDING",
+            Self::OperationPending => "CTAP2_ERR_OPERATION_PENDING",
+            Self::NoOperations => "CTAP2_ERR_NO_OPERATIONS",
+            Self::UnsupportedAlgorithm => "CTAP2_ERR_UNSUPPORTED_ALGORITHM",
+            Self::OperationDenied => "CTAP2_ERR_OPERATION_DENIED",
+            Self::KeyStoreFull => "CTAP2_ERR_KEY_STORE_FULL",
+            Self::NoOperationPending => "CTAP2_ERR_NO_OPERATION_PENDING",
+            Self::UnsupportedOption => "CTAP2_ERR_UNSUPPORTED_OPTION",
+            Self::InvalidOption => "CTAP2_ERR_INVALID_OPTION",
+            Self::KeepAliveCancel => "CTAP2_ERR_KEEPALIVE_CANCEL",
+            Self::NoCredentials => "CTAP2_ERR_NO_CREDENTIALS",
+            Self::UserActionTimeout => "CTAP2_ERR_USER_ACTION_TIMEOUT",
+            Self::NotAllowed => "CTAP2_ERR_NOT_ALLOWED",
+            Self::PINInvalid => "CTAP2_ERR_PIN_INVALID",
+            Self::PINBlocked => "CTAP2_ERR_PIN_BLOCKED",
+            Self::PINAuthInvalid => "CTAP2_ERR_PIN_AUTH_INVALID",
+            Self::PINAuthBlocked => "CTAP2_ERR_PIN_AUTH_BLOCKED",
+            Self::PINNotSet => "CTAP2_ERR_PIN_NOT_SET",
+            Self::PINRequired => "CTAP2_ERR_PIN_REQUIRED",
+            Self::PINPolicyViolation => "CTAP2_ERR_PIN_POLICY_VIOLATION",
+            Self::PINTokenExpired => "CTAP2_ERR_PIN_TOKEN_EXPIRED",
+            Self::RequestTooLarge => "CTAP2_ERR_REQUEST_TOO_LARGE",
+            Self::ActionTimeout => "CTAP2_ERR_ACTION_TIMEOUT",
+            Self::UserPresenceRequired => "CTAP2_ERR_UP_REQUIRED",
+            Self::UvBlocked => "CTAP2_ERR_UV_BLOCKED",
+            Self::IntegrityFailure => "CTAP2_ERR_INTEGRITY_FAILURE",
+            Self::InvalidSubcommand => "CTAP2_ERR_INVALID_SUBCOMMAND",
+            Self::UVInvalid => "CTAP2_ERR_UV_INVALID",
+            Self::UnauthorizedPermission => "CTAP2_ERR_UNAUTHORIZED_PERMISSION",
+            Self::Other => "CTAP1_ERR_OTHER",
+        }
+    }
+
+    pub fn recommended_action(&self) -> RecommendedAction {
+        if self.is_retryable_user_error() {
+            return RecommendedAction::RetryAfterUserAction;
+        }
+        match self {
+            // Transient states of the device or the transport.
+            Self::InvalidSeq
+            | Self::Timeout
+            | Self::ChannelBusy
+            | Self::LockRequired
+            | Self::InvalidChannel
+            | Self::Processing
+            | Self::UserActionPending
+            | Self::OperationPending
+            | Self::PINTokenExpired => RecommendedAction::Retry,
+            // The user didn't act in time, or can pick a different PIN.
+            Self::ActionTimeout | Self::UserPresenceRequired | Self::PINPolicyViolation => {
+                RecommendedAction::RetryAfterUserAction
+            }
+            // PIN attempts are blocked until the device is power-cycled.
+            Self::PINAuthBlocked => RecommendedAction::ReplugDevice,
+            _ => RecommendedAction::Fatal,
+        }
+    }
+
     pub fn is_retryable_user_error(&self) -> bool {
         match &self {
             Self::PINInvalid | Self::UVInvalid => true, // PIN or biometric auth failed
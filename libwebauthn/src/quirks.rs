@@ -0,0 +1,247 @@
+//! Workarounds for misbehaving authenticators.
+//!
+//! Some devices report wrong getInfo fields, e.g. a `maxMsgSize` larger than they can actually
+//! receive, advertise features they don't implement correctly, or need longer than usual to
+//! answer. A [`Quirk`] describes the fixes for one such device, matched either by its USB
+//! vendor and product ID or by its AAGUID.
+//!
+//! The library ships no quirks of its own: applications add those of the devices they support
+//! through [`register`]. The protocol layer consults them: getInfo responses are patched before
+//! being used, and so are the timeouts of all CTAP2 requests. Since the AAGUID is only known from getInfo, timeouts can only be adjusted for
+//! quirks matched by USB ID.
+
+use std::sync::RwLock;
+use std::time::Duration;
+
+use tracing::{debug, info};
+use uuid::Uuid;
+
+use crate::proto::ctap2::Ctap2GetInfoResponse;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct UsbId {
+    pub vendor_id: u16,
+    pub product_id: u16,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum QuirkMatch {
+    Usb(UsbId),
+    Aaguid(Uuid),
+}
+
+/// Fixes for a single device model.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Quirk {
+    pub matches: QuirkMatch,
+    /// Why the quirk is needed, logged when it is applied.
+    pub reason: String,
+    /// Replaces the reported maxMsgSize.
+    pub max_msg_size: Option<u32>,
+    /// Replaces the reported transports.
+    pub transports: Option<Vec<String>>,
+    /// Options removed from getInfo, e.g. `credentialMgmtPreview`, so that they are not used.
+    pub disabled_options: Vec<String>,
+    /// Extensions removed from getInfo.
+    pub disabled_extensions: Vec<String>,
+    /// Versions removed from getInfo, e.g. `FIDO_2_1_PRE`.
+    pub disabled_versions: Vec<String>,
+    /// Added to the timeout of every CTAP2 request.
+    pub extra_timeout: Duration,
+}
+
+impl Quirk {
+    pub fn usb(vendor_id: u16, product_id: u16, reason: &str) -> Self {
+        Self::new(
+            QuirkMatch::Usb(UsbId {
+                vendor_id,
+                product_id,
+            }),
+            reason,
+        )
+    }
+
+    pub fn aaguid(aaguid: Uuid, reason: &str) -> Self {
+        Self::new(QuirkMatch::Aaguid(aaguid), reason)
+    }
+
+    fn new(matches: QuirkMatch, reason: &str) -> Self {
+        Self {
+            matches,
+            reason: reason.to_owned(),
+            max_msg_size: None,
+            transports: None,
+            disabled_options: vec![],
+            disabled_extensions: vec![],
+            disabled_versions: vec![],
+            extra_timeout: Duration::ZERO,
+        }
+    }
+
+    pub fn with_max_msg_size(mut self, max_msg_size: u32) -> Self {
+        self.max_msg_size = Some(max_msg_size);
+        self
+    }
+
+    pub fn with_transports(mut self, transports: &[&str]) -> Self {
+        self.transports = Some(transports.iter().map(|t| t.to_string()).collect());
+        self
+    }
+
+    pub fn without_option(mut self, option: &str) -> Self {
+        self.disabled_options.push(option.to_owned());
+        self
+    }
+
+    pub fn without_extension(mut self, extension: &str) -> Self {
+        self.disabled_extensions.push(extension.to_owned());
+        self
+    }
+
+    pub fn without_version(mut self, version: &str) -> Self {
+        self.disabled_versions.push(version.to_owned());
+        self
+    }
+
+    pub fn with_extra_timeout(mut self, extra_timeout: Duration) -> Self {
+        self.extra_timeout = extra_timeout;
+        self
+    }
+
+    fn applies_to(&self, usb_id: Option<UsbId>, aaguid: Option<&[u8]>) -> bool {
+        match &self.matches {
+            QuirkMatch::Usb(id) => usb_id == Some(*id),
+            QuirkMatch::Aaguid(id) => aaguid == Some(id.as_bytes().as_slice()),
+        }
+    }
+
+    fn apply_to_get_info(&self, info: &mut Ctap2GetInfoResponse) {
+        info!(matches = ?self.matches, reason = %self.reason, "Applying device quirk");
+        if let Some(max_msg_size) = self.max_msg_size {
+            info.max_msg_size = Some(max_msg_size);
+        }
+        if let Some(transports) = &self.transports {
+            info.transports = Some(transports.clone());
+        }
+        if let Some(options) = info.options.as_mut() {
+            options.retain(|option, _| !self.disabled_options.contains(option));
+        }
+        if let Some(extensions) = info.extensions.as_mut() {
+            extensions.retain(|extension| !self.disabled_extensions.contains(extension));
+        }
+        info.versions
+            .retain(|version| !self.disabled_versions.contains(version));
+    }
+}
+
+static REGISTERED: RwLock<Vec<Quirk>> = RwLock::new(Vec::new());
+
+/// Adds a quirk. Quirks are applied in the order they were registered, so later quirks win
+/// where they overlap.
+pub fn register(quirk: Quirk) {
+    debug!(?quirk, "Registering device quirk");
+    REGISTERED
+        .write()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .push(quirk);
+}
+
+/// All quirks applying to a device with the given USB ID and AAGUID, if known.
+pub fn lookup(usb_id: Option<UsbId>, aaguid: Option<&[u8]>) -> Vec<Quirk> {
+    let registered = REGISTERED
+        .read()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    registered
+        .iter()
+        .filter(|quirk| quirk.applies_to(usb_id, aaguid))
+        .cloned()
+        .collect()
+}
+
+/// Patches a getInfo response received from the device with the given USB ID, if known.
+pub fn apply_to_get_info(usb_id: Option<UsbId>, info: &mut Ctap2GetInfoResponse) {
+    for quirk in lookup(usb_id, Some(&info.aaguid)) {
+        quirk.apply_to_get_info(info);
+    }
+}
+
+/// Extends a request timeout for the device with the given USB ID, if known.
+pub fn adjust_timeout(usb_id: Option<UsbId>, timeout: Duration) -> Duration {
+    if usb_id.is_none() {
+        return timeout;
+    }
+    lookup(usb_id, None)
+        .iter()
+        .fold(timeout, |timeout, quirk| timeout + quirk.extra_timeout)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::time::Duration;
+
+    use serde_bytes::ByteBuf;
+    use uuid::Uuid;
+
+    use super::*;
+
+    fn get_info(aaguid: Uuid) -> Ctap2GetInfoResponse {
+        Ctap2GetInfoResponse {
+            versions: vec!["FIDO_2_0".to_owned(), "FIDO_2_1_PRE".to_owned()],
+            extensions: Some(vec!["hmac-secret".to_owned(), "credProtect".to_owned()]),
+            aaguid: ByteBuf::from(aaguid.as_bytes().to_vec()),
+            options: Some(HashMap::from([
+                ("rk".to_owned(), true),
+                ("credentialMgmtPreview".to_owned(), true),
+            ])),
+            max_msg_size: Some(2048),
+            transports: Some(vec!["nfc".to_owned()]),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn registered_quirks_patch_get_info_and_timeouts() {
+        // Test IDs, not used by any real device, since the registry is process-wide.
+        let aaguid = Uuid::parse_str("a8a3c4e1-0d4b-4f4e-9c44-3f6ad2a6f001").unwrap();
+        register(
+            Quirk::aaguid(aaguid, "Broken preview commands")
+                .without_option("credentialMgmtPreview")
+                .without_version("FIDO_2_1_PRE")
+                .without_extension("credProtect"),
+        );
+        register(
+            Quirk::usb(0xfffe, 0xfffe, "Fragmented messages over 1024 bytes")
+                .with_max_msg_size(1024)
+                .with_transports(&["usb"])
+                .with_extra_timeout(Duration::from_secs(1)),
+        );
+        let usb_id = UsbId {
+            vendor_id: 0xfffe,
+            product_id: 0xfffe,
+        };
+
+        let mut info = get_info(aaguid);
+        apply_to_get_info(Some(usb_id), &mut info);
+        assert_eq!(info.versions, vec!["FIDO_2_0".to_owned()]);
+        assert_eq!(info.extensions, Some(vec!["hmac-secret".to_owned()]));
+        assert_eq!(info.options, Some(HashMap::from([("rk".to_owned(), true)])));
+        assert_eq!(info.max_msg_size, Some(1024));
+        assert_eq!(info.transports, Some(vec!["usb".to_owned()]));
+        assert_eq!(
+            adjust_timeout(Some(usb_id), Duration::from_secs(2)),
+            Duration::from_secs(3)
+        );
+
+        // Other devices are left alone.
+        let mut info = get_info(Uuid::nil());
+        apply_to_get_info(None, &mut info);
+        assert_eq!(info.versions.len(), 2);
+        assert_eq!(info.options.unwrap().len(), 2);
+        assert_eq!(info.max_msg_size, Some(2048));
+        assert_eq!(
+            adjust_timeout(None, Duration::from_secs(2)),
+            Duration::from_secs(2)
+        );
+    }
+}
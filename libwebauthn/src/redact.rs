@@ -0,0 +1,61 @@
+//! Redaction of secrets and personal data in `Debug` output.
+//!
+//! Requests and responses are logged with `trace!(?request)`. Types carrying PINs,
+//! pinUvAuthParams, shared-secret-encrypted blobs, PRF outputs or user identities format
+//! those fields as `<redacted>`, so that tracing can be enabled in production.
+
+pub(crate) const REDACTED: &str = "<redacted>";
+
+/// Formats a set optional field as `<redacted>`, keeping whether it was set.
+pub(crate) fn redact<T>(value: &Option<T>) -> Option<&'static str> {
+    value.as_ref().map(|_| REDACTED)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ops::webauthn::{HMACGetSecretOutput, PRFValue};
+    use crate::proto::ctap2::cbor::{CborRequest, CborResponse};
+    use crate::proto::ctap2::{Ctap2CommandCode, Ctap2PublicKeyCredentialUserEntity};
+
+    #[test]
+    fn secrets_and_identities_are_redacted() {
+        let user = Ctap2PublicKeyCredentialUserEntity::new(b"user-handle", "alice", "Alice Doe");
+        let debug = format!("{user:?}");
+        assert!(!debug.contains("alice"));
+        assert!(!debug.contains("Alice Doe"));
+        assert!(debug.contains("<redacted>"));
+
+        let output = HMACGetSecretOutput {
+            output1: [0xab; 32],
+            output2: Some([0xab; 32]),
+        };
+        assert!(!format!("{output:?}").contains("171"));
+
+        let prf = PRFValue {
+            first: [0xab; 32],
+            second: None,
+        };
+        assert_eq!(
+            format!("{prf:?}"),
+            r#"PRFValue { first: "<redacted>", second: None }"#
+        );
+    }
+
+    #[test]
+    fn encoded_messages_are_redacted() {
+        let request = CborRequest {
+            command: Ctap2CommandCode::AuthenticatorClientPin,
+            encoded_data: vec![0xab; 3],
+        };
+        assert_eq!(
+            format!("{request:?}"),
+            "CborRequest { command: AuthenticatorClientPin, len: 3 }"
+        );
+
+        let response = CborResponse::new_success_from_slice(&[0xab; 5]);
+        assert_eq!(
+            format!("{response:?}"),
+            "CborResponse { status_code: Ok, len: Some(5) }"
+        );
+    }
+}
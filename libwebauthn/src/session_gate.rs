@@ -0,0 +1,161 @@
+//! Gating ceremonies on the state of the user's desktop session.
+//!
+//! Kiosk and enterprise deployments may require that the session is unlocked (or that the
+//! user re-authenticated to the OS) before a PIN is collected, a credential is registered, or
+//! an assertion is made.
+//! Embedders implement [SessionGate] on top of their platform's screen-lock or idle APIs, and
+//! run operations inside [scope]. While the gate is closed, the operation sends
+//! `UvUpdate::SessionLocked` and pauses until the gate opens, or the operation times out.
+
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use tracing::{debug, info, warn};
+
+use crate::transport::{until_cancelled, Channel};
+use crate::webauthn::error::{Error, PlatformError};
+use crate::UvUpdate;
+
+tokio::task_local! {
+    static CURRENT: Arc<dyn SessionGate>;
+}
+
+#[async_trait]
+pub trait SessionGate: Send + Sync {
+    /// Whether ceremonies may currently proceed.
+    async fn is_open(&self) -> bool;
+
+    /// Resolves once the gate opens, e.g. after the user unlocked the screen.
+    async fn wait_open(&self);
+}
+
+/// Runs `f` with `gate`; operations started within it pause while the gate is closed.
+pub async fn scope<F: Future>(gate: Arc<dyn SessionGate>, f: F) -> F::Output {
+    CURRENT.scope(gate, f).await
+}
+
+/// Waits for the current gate, if any, to open before PIN entry or registration proceeds.
+pub(crate) async fn wait_for_open<C>(channel: &mut C, timeout: Duration) -> Result<(), Error>
+where
+    C: Channel,
+{
+    let Ok(gate) = CURRENT.try_with(Arc::clone) else {
+        return Ok(());
+    };
+    if gate.is_open().await {
+        return Ok(());
+    }
+    info!("Session gate is closed, pausing operation");
+    channel.send_ux_update(UvUpdate::SessionLocked.into()).await;
+    let opened = async {
+        match tokio::time::timeout(timeout, gate.wait_open()).await {
+            Ok(()) => {
+                debug!("Session gate opened, resuming operation");
+                Ok(())
+            }
+            Err(_) => {
+                warn!("Session gate did not open before the operation timed out");
+                Err(Error::Platform(PlatformError::SessionLocked))
+            }
+        }
+    };
+    let token = channel.get_cancellation_token();
+    until_cancelled(token, channel.get_ux_update_sender(), opened).await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    use tokio::sync::Notify;
+    use tokio_util::sync::CancellationToken;
+
+    use super::*;
+    use crate::testing::MockChannel;
+
+    #[derive(Default)]
+    struct TestGate {
+        open: AtomicBool,
+        notify: Notify,
+    }
+
+    #[async_trait]
+    impl SessionGate for TestGate {
+        async fn is_open(&self) -> bool {
+            self.open.load(Ordering::SeqCst)
+        }
+
+        async fn wait_open(&self) {
+            while !self.open.load(Ordering::SeqCst) {
+                self.notify.notified().await;
+            }
+        }
+    }
+
+    impl TestGate {
+        fn unlock(&self) {
+            self.open.store(true, Ordering::SeqCst);
+            self.notify.notify_one();
+        }
+    }
+
+    const TIMEOUT: Duration = Duration::from_secs(30);
+
+    #[tokio::test(start_paused = true)]
+    async fn closed_gate_pauses_until_unlocked() {
+        let gate = Arc::new(TestGate::default());
+        let mut channel = MockChannel::new();
+        let mut updates = channel.get_ux_update_sender().subscribe();
+        let unlocker = gate.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_secs(10)).await;
+            unlocker.unlock();
+        });
+
+        let result = scope(gate, wait_for_open(&mut channel, TIMEOUT)).await;
+        assert_eq!(result, Ok(()));
+        assert!(matches!(updates.try_recv(), Ok(UvUpdate::SessionLocked)));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn open_gate_and_no_gate_do_not_pause() {
+        let mut channel = MockChannel::new();
+        let mut updates = channel.get_ux_update_sender().subscribe();
+        assert_eq!(wait_for_open(&mut channel, TIMEOUT).await, Ok(()));
+
+        let gate = Arc::new(TestGate::default());
+        gate.unlock();
+        assert_eq!(
+            scope(gate, wait_for_open(&mut channel, TIMEOUT)).await,
+            Ok(())
+        );
+        assert!(updates.try_recv().is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn gate_closed_until_timeout_is_session_locked() {
+        let gate = Arc::new(TestGate::default());
+        let mut channel = MockChannel::new();
+        let result = scope(gate, wait_for_open(&mut channel, TIMEOUT)).await;
+        assert_eq!(result, Err(Error::Platform(PlatformError::SessionLocked)));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn waiting_is_cancelled_through_the_channel() {
+        let gate = Arc::new(TestGate::default());
+        let mut channel = MockChannel::new();
+        let token = CancellationToken::new();
+        channel.set_cancellation_token(Some(token.clone()));
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_secs(10)).await;
+            token.cancel();
+        });
+
+        let started = tokio::time::Instant::now();
+        let result = scope(gate, wait_for_open(&mut channel, TIMEOUT)).await;
+        assert_eq!(result, Err(Error::Platform(PlatformError::Cancelled)));
+        assert_eq!(started.elapsed(), Duration::from_secs(10));
+    }
+}
@@ -0,0 +1,880 @@
+//! One-shot helpers for the simplest WebAuthn flows.
+//!
+//! [`register`] and [`authenticate`] take the relying party's options as
+//! WebAuthn Level 3 JSON (`PublicKeyCredentialCreationOptionsJSON` and
+//! `PublicKeyCredentialRequestOptionsJSON`), pick the first available USB
+//! security key, run the ceremony and return the `RegistrationResponseJSON` or
+//! `AuthenticationResponseJSON` that can be handed back to the relying party
+//! unchanged.
+//!
+//! A ceremony failing because the user mistyped their PIN or failed built-in UV is
+//! tried again, up to [`MAX_ATTEMPTS`] times within the options' `timeout`, but not once
+//! the PIN has only [`MIN_PIN_RETRIES`] attempts left.
+//!
+//! Applications that need device selection, other transports, or fine-grained
+//! UX updates should use the [`WebAuthn`] trait directly instead.
+
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use cosey::PublicKey;
+use http::Uri;
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+use sha2::{Digest, Sha256};
+use tracing::{debug, instrument, warn};
+use zeroize::Zeroizing;
+
+use crate::ops::webauthn::{
+    GetAssertionRequest, MakeCredentialRequest, MakeCredentialsRequestExtensions,
+    ResidentKeyRequirement, UserVerificationRequirement,
+};
+use crate::pin::{PinManagement, PinProvider, PinRequestContext, PinRequestReason, Retries};
+use crate::proto::ctap2::cbor;
+use crate::proto::ctap2::{
+    Ctap2AttestationStatement, Ctap2CredentialType, Ctap2PublicKeyCredentialDescriptor,
+    Ctap2PublicKeyCredentialRpEntity, Ctap2PublicKeyCredentialType,
+    Ctap2PublicKeyCredentialUserEntity, Ctap2Transport,
+};
+use crate::proto::CtapError;
+use crate::timeout::OperationDeadline;
+use crate::transport::error::TransportError;
+use crate::transport::hid::list_devices;
+use crate::transport::{Channel, Device};
+use crate::webauthn::{Error, PlatformError, WebAuthn};
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Attempts at a ceremony, including the first one.
+pub const MAX_ATTEMPTS: u32 = 3;
+/// PIN attempts left to the user, rather than spent on retries with a wrong PIN.
+pub const MIN_PIN_RETRIES: u32 = 3;
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RpEntityJson {
+    id: Option<String>,
+    name: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct UserEntityJson {
+    id: String,
+    name: String,
+    display_name: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct CredentialDescriptorJson {
+    id: String,
+    #[serde(rename = "type")]
+    r#type: Ctap2PublicKeyCredentialType,
+    transports: Option<Vec<String>>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct AuthenticatorSelectionJson {
+    resident_key: Option<String>,
+    #[serde(default)]
+    require_resident_key: bool,
+    user_verification: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct CreationExtensionsJson {
+    cred_props: Option<bool>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct CreationOptionsJson {
+    rp: RpEntityJson,
+    user: UserEntityJson,
+    challenge: String,
+    pub_key_cred_params: Vec<Ctap2CredentialType>,
+    timeout: Option<u64>,
+    #[serde(default)]
+    exclude_credentials: Vec<CredentialDescriptorJson>,
+    #[serde(default)]
+    authenticator_selection: AuthenticatorSelectionJson,
+    #[serde(default)]
+    extensions: CreationExtensionsJson,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RequestOptionsJson {
+    challenge: String,
+    timeout: Option<u64>,
+    rp_id: Option<String>,
+    #[serde(default)]
+    allow_credentials: Vec<CredentialDescriptorJson>,
+    user_verification: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct CollectedClientData<'a> {
+    #[serde(rename = "type")]
+    r#type: &'a str,
+    challenge: &'a str,
+    origin: &'a str,
+    cross_origin: bool,
+}
+
+#[derive(Debug, Serialize)]
+struct AttestationObject<'a> {
+    fmt: &'a str,
+    #[serde(rename = "attStmt")]
+    attestation_statement: &'a Ctap2AttestationStatement,
+    #[serde(rename = "authData", with = "serde_bytes")]
+    authenticator_data: &'a [u8],
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct AuthenticatorAttestationResponseJson {
+    #[serde(rename = "clientDataJSON")]
+    client_data_json: String,
+    authenticator_data: String,
+    transports: Vec<Ctap2Transport>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    public_key_algorithm: Option<i32>,
+    attestation_object: String,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct AuthenticatorAssertionResponseJson {
+    #[serde(rename = "clientDataJSON")]
+    client_data_json: String,
+    authenticator_data: String,
+    signature: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    user_handle: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct PublicKeyCredentialJson<R, E> {
+    id: String,
+    raw_id: String,
+    #[serde(rename = "type")]
+    r#type: Ctap2PublicKeyCredentialType,
+    response: R,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    authenticator_attachment: Option<&'static str>,
+    client_extension_results: E,
+}
+
+/// Registers a new credential on the first available USB security key.
+///
+/// `options_json` is a `PublicKeyCredentialCreationOptionsJSON` as produced by the
+/// relying party. Whenever the device asks for a PIN, `pin_callback` is invoked with
+/// the reason and the number of attempts left; returning `None` cancels the operation.
+#[instrument(skip(options_json, pin_callback))]
+pub async fn register<F>(origin: &str, options_json: &str, pin_callback: F) -> Result<String, Error>
+where
+    F: Fn(PinRequestReason, Option<u32>) -> Option<Zeroizing<String>> + Send + Sync + 'static,
+{
+    let (request, client_data_json) = registration_request(origin, options_json)?;
+    let mut device = first_device().await?;
+    let mut channel = device.channel().await?;
+    run_registration(&mut channel, &request, &client_data_json, pin_callback).await
+}
+
+/// Like [`register`], on a device of the caller's choice.
+#[instrument(skip(channel, options_json, pin_callback))]
+pub async fn register_on_channel<C, F>(
+    channel: &mut C,
+    origin: &str,
+    options_json: &str,
+    pin_callback: F,
+) -> Result<String, Error>
+where
+    C: Channel,
+    F: Fn(PinRequestReason, Option<u32>) -> Option<Zeroizing<String>> + Send + Sync + 'static,
+{
+    let (request, client_data_json) = registration_request(origin, options_json)?;
+    run_registration(channel, &request, &client_data_json, pin_callback).await
+}
+
+fn registration_request(
+    origin: &str,
+    options_json: &str,
+) -> Result<(MakeCredentialRequest, Vec<u8>), Error> {
+    let options: CreationOptionsJson = from_json(options_json)?;
+    let rp_id = rp_id_for_origin(origin, options.rp.id.as_deref())?;
+    let client_data_json = client_data_json("webauthn.create", &options.challenge, origin)?;
+    let selection = &options.authenticator_selection;
+    let mut builder = MakeCredentialRequest::builder()
+        .client_data_hash(&Sha256::digest(&client_data_json))
+        .origin(origin)
+        .rp(Ctap2PublicKeyCredentialRpEntity::new(
+            &rp_id,
+            &options.rp.name,
+        ))
+        .user(Ctap2PublicKeyCredentialUserEntity::new(
+            &decode_base64url(&options.user.id)?,
+            &options.user.name,
+            &options.user.display_name,
+        ))
+        .user_verification(user_verification(selection.user_verification.as_deref()))
+        .extensions(MakeCredentialsRequestExtensions {
+            cred_props: options.extensions.cred_props,
+            ..Default::default()
+        })
+        .timeout(timeout(options.timeout));
+    match selection.resident_key.as_deref() {
+        Some("required") => builder = builder.resident_key(ResidentKeyRequirement::Required),
+        Some("preferred") => builder = builder.resident_key(ResidentKeyRequirement::Preferred),
+        Some("discouraged") => builder = builder.resident_key(ResidentKeyRequirement::Discouraged),
+        _ if selection.require_resident_key => {
+            builder = builder.resident_key(ResidentKeyRequirement::Required)
+        }
+        _ => {}
+    }
+    // Parameters of unknown credential types are skipped, as in WebAuthn.
+    for param in &options.pub_key_cred_params {
+        if param.public_key_type == Ctap2PublicKeyCredentialType::PublicKey {
+            builder = builder.algorithm(param.algorithm);
+        }
+    }
+    for credential in descriptors(&options.exclude_credentials)? {
+        builder = builder.exclude_credential(credential);
+    }
+    let request = builder.build()?;
+    Ok((request, client_data_json))
+}
+
+async fn run_registration<C, F>(
+    channel: &mut C,
+    request: &MakeCredentialRequest,
+    client_data_json: &[u8],
+    pin_callback: F,
+) -> Result<String, Error>
+where
+    C: Channel,
+    F: Fn(PinRequestReason, Option<u32>) -> Option<Zeroizing<String>> + Send + Sync + 'static,
+{
+    let previous_provider = channel.get_pin_provider();
+    channel.set_pin_provider(Some(Arc::new(CallbackPinProvider(Arc::new(pin_callback)))));
+    let deadline = OperationDeadline::new(channel.get_timeout_policy(), request.timeout);
+    let mut request = request.clone();
+    let mut attempts = 0;
+    let response = loop {
+        attempts += 1;
+        request.timeout = deadline.remaining();
+        let ctap_error = match channel.webauthn_make_credential(&request).await {
+            Err(Error::Ctap(ctap_error)) => ctap_error,
+            result => break result,
+        };
+        if !should_retry(channel, ctap_error, attempts, &deadline).await {
+            break Err(Error::Ctap(ctap_error));
+        }
+        warn!(%ctap_error, attempts, "Retrying MakeCredential after user error");
+    };
+    channel.set_pin_provider(previous_provider);
+    let response = response?;
+
+    let Some(attested_credential) = &response.authenticator_data.attested_credential else {
+        warn!("MakeCredential response did not contain attested credential data");
+        return Err(Error::Platform(PlatformError::InvalidDeviceResponse));
+    };
+    let credential_id = base64_url::encode(&attested_credential.credential_id);
+    let (transport, authenticator_attachment) = attachment(channel.transport_name());
+    let public_key_algorithm = match attested_credential.credential_public_key {
+        PublicKey::P256Key(_) => Some(-7),
+        PublicKey::Ed25519Key(_) => Some(-8),
+        PublicKey::EcdhEsHkdf256Key(_) | PublicKey::TotpKey(_) => None,
+    };
+    let authenticator_data = response.authenticator_data.to_response_bytes()?;
+    let attestation_object = cbor::to_vec_canonical(&AttestationObject {
+        fmt: &response.format,
+        attestation_statement: &response.attestation_statement,
+        authenticator_data: &authenticator_data,
+    })?;
+
+    to_json(&PublicKeyCredentialJson {
+        id: credential_id.clone(),
+        raw_id: credential_id,
+        r#type: Ctap2PublicKeyCredentialType::PublicKey,
+        response: AuthenticatorAttestationResponseJson {
+            client_data_json: base64_url::encode(client_data_json),
+            authenticator_data: base64_url::encode(&authenticator_data),
+            transports: transport.into_iter().collect(),
+            public_key_algorithm,
+            attestation_object: base64_url::encode(&attestation_object),
+        },
+        authenticator_attachment,
+        client_extension_results: response.unsigned_extensions_output,
+    })
+}
+
+/// Gets an assertion from the first available USB security key.
+///
+/// `options_json` is a `PublicKeyCredentialRequestOptionsJSON` as produced by the
+/// relying party. If it does not specify an `rpId`, the effective domain of `origin`
+/// is used. PIN handling works as in [`register`].
+#[instrument(skip(options_json, pin_callback))]
+pub async fn authenticate<F>(
+    origin: &str,
+    options_json: &str,
+    pin_callback: F,
+) -> Result<String, Error>
+where
+    F: Fn(PinRequestReason, Option<u32>) -> Option<Zeroizing<String>> + Send + Sync + 'static,
+{
+    let (request, client_data_json) = assertion_request(origin, options_json)?;
+    let mut device = first_device().await?;
+    let mut channel = device.channel().await?;
+    run_assertion(&mut channel, &request, &client_data_json, pin_callback).await
+}
+
+/// Like [`authenticate`], on a device of the caller's choice.
+#[instrument(skip(channel, options_json, pin_callback))]
+pub async fn authenticate_on_channel<C, F>(
+    channel: &mut C,
+    origin: &str,
+    options_json: &str,
+    pin_callback: F,
+) -> Result<String, Error>
+where
+    C: Channel,
+    F: Fn(PinRequestReason, Option<u32>) -> Option<Zeroizing<String>> + Send + Sync + 'static,
+{
+    let (request, client_data_json) = assertion_request(origin, options_json)?;
+    run_assertion(channel, &request, &client_data_json, pin_callback).await
+}
+
+fn assertion_request(
+    origin: &str,
+    options_json: &str,
+) -> Result<(GetAssertionRequest, Vec<u8>), Error> {
+    let options: RequestOptionsJson = from_json(options_json)?;
+    let relying_party_id = rp_id_for_origin(origin, options.rp_id.as_deref())?;
+    let client_data_json = client_data_json("webauthn.get", &options.challenge, origin)?;
+    let mut builder = GetAssertionRequest::builder()
+        .rp_id(&relying_party_id)
+        .client_data_hash(&Sha256::digest(&client_data_json))
+        .user_verification(user_verification(options.user_verification.as_deref()))
+        .timeout(timeout(options.timeout));
+    for credential in descriptors(&options.allow_credentials)? {
+        builder = builder.allow_credential(credential);
+    }
+    let request = builder.build()?;
+    Ok((request, client_data_json))
+}
+
+async fn run_assertion<C, F>(
+    channel: &mut C,
+    request: &GetAssertionRequest,
+    client_data_json: &[u8],
+    pin_callback: F,
+) -> Result<String, Error>
+where
+    C: Channel,
+    F: Fn(PinRequestReason, Option<u32>) -> Option<Zeroizing<String>> + Send + Sync + 'static,
+{
+    let previous_provider = channel.get_pin_provider();
+    channel.set_pin_provider(Some(Arc::new(CallbackPinProvider(Arc::new(pin_callback)))));
+    let deadline = OperationDeadline::new(channel.get_timeout_policy(), request.timeout);
+    let mut retried = request.clone();
+    let mut attempts = 0;
+    let response = loop {
+        attempts += 1;
+        retried.timeout = deadline.remaining();
+        let ctap_error = match channel.webauthn_get_assertion(&retried).await {
+            Err(Error::Ctap(ctap_error)) => ctap_error,
+            result => break result,
+        };
+        if !should_retry(channel, ctap_error, attempts, &deadline).await {
+            break Err(Error::Ctap(ctap_error));
+        }
+        warn!(%ctap_error, attempts, "Retrying GetAssertion after user error");
+    };
+    channel.set_pin_provider(previous_provider);
+    let mut response = response?;
+
+    if response.assertions.is_empty() {
+        warn!("GetAssertion response did not contain any assertions");
+        return Err(Error::Platform(PlatformError::InvalidDeviceResponse));
+    }
+    // Without a way to ask the user, we go with the first discoverable credential.
+    let assertion = response.assertions.swap_remove(0);
+    let Some(credential) = assertion.credential_id.as_ref().or(request.allow.first()) else {
+        warn!("GetAssertion response did not identify the credential used");
+        return Err(Error::Platform(PlatformError::InvalidDeviceResponse));
+    };
+    let credential_id = base64_url::encode(&credential.id);
+
+    to_json(&PublicKeyCredentialJson {
+        id: credential_id.clone(),
+        raw_id: credential_id,
+        r#type: Ctap2PublicKeyCredentialType::PublicKey,
+        response: AuthenticatorAssertionResponseJson {
+            client_data_json: base64_url::encode(client_data_json),
+            authenticator_data: base64_url::encode(
+                &assertion.authenticator_data.to_response_bytes()?,
+            ),
+            signature: base64_url::encode(&assertion.signature),
+            user_handle: assertion.user.map(|user| base64_url::encode(&user.id)),
+        },
+        authenticator_attachment: attachment(channel.transport_name()).1,
+        client_extension_results: assertion.unsigned_extensions_output.unwrap_or_default(),
+    })
+}
+
+/// Whether to try a ceremony again after it failed with `error`: only if the user may get
+/// it right next time, there is time left, and the PIN keeps enough attempts.
+async fn should_retry<C: Channel>(
+    channel: &mut C,
+    error: CtapError,
+    attempts: u32,
+    deadline: &OperationDeadline,
+) -> bool {
+    // Not after CTAP2_ERR_USER_ACTION_TIMEOUT: the user walked away.
+    if !matches!(error, CtapError::PINInvalid | CtapError::UVInvalid) {
+        return false;
+    }
+    if attempts >= MAX_ATTEMPTS {
+        warn!(attempts, "Giving up after too many failed attempts");
+        return false;
+    }
+    let Ok(timeout) = deadline.io_timeout() else {
+        return false;
+    };
+    if error == CtapError::PINInvalid {
+        match channel.get_retries(timeout).await {
+            Ok(Retries {
+                pin_retries: Some(pin_retries),
+                power_cycle_required: false,
+                ..
+            }) if pin_retries > MIN_PIN_RETRIES => {}
+            Ok(retries) => {
+                warn!(
+                    ?retries,
+                    "Not retrying, to leave the user enough PIN attempts"
+                );
+                return false;
+            }
+            Err(err) => {
+                warn!(?err, "Failed to get PIN retries, not retrying");
+                return false;
+            }
+        }
+    }
+    true
+}
+
+/// The transport and authenticatorAttachment reported to the RP for a channel's transport.
+/// Both are left out where the channel doesn't know them, e.g. for remote devices.
+fn attachment(transport_name: &str) -> (Option<Ctap2Transport>, Option<&'static str>) {
+    match transport_name {
+        "hid" | "daemon" => (Some(Ctap2Transport::Usb), Some("cross-platform")),
+        "ble" => (Some(Ctap2Transport::Ble), Some("cross-platform")),
+        "cable" => (Some(Ctap2Transport::Hybrid), Some("cross-platform")),
+        "local" => (Some(Ctap2Transport::Internal), Some("platform")),
+        _ => (None, None),
+    }
+}
+
+async fn first_device() -> Result<crate::transport::hid::HidDevice, Error> {
+    let Some(device) = list_devices().await?.into_iter().next() else {
+        warn!("No HID authenticators found");
+        return Err(Error::Transport(TransportError::TransportUnavailable));
+    };
+    debug!(%device, "Selected first available HID authenticator");
+    Ok(device)
+}
+
+/// Answers PIN requests with the callback passed to [`register`] or [`authenticate`].
+struct CallbackPinProvider<F>(Arc<F>);
+
+#[async_trait]
+impl<F> PinProvider for CallbackPinProvider<F>
+where
+    F: Fn(PinRequestReason, Option<u32>) -> Option<Zeroizing<String>> + Send + Sync + 'static,
+{
+    async fn provide_pin(&self, context: &PinRequestContext) -> Option<Zeroizing<String>> {
+        // The callback is allowed to block, e.g. while reading from a terminal.
+        let pin_callback = Arc::clone(&self.0);
+        let (reason, attempts_left) = (context.reason, context.attempts_left);
+        tokio::task::spawn_blocking(move || pin_callback(reason, attempts_left))
+            .await
+            .unwrap_or_default()
+    }
+}
+
+fn from_json<'a, T: Deserialize<'a>>(json: &'a str) -> Result<T, Error> {
+    serde_json::from_str(json).map_err(|e| {
+        warn!(%e, "Failed to parse options JSON");
+        Error::Platform(PlatformError::SyntaxError)
+    })
+}
+
+fn to_json<T: Serialize>(value: &T) -> Result<String, Error> {
+    serde_json::to_string(value).map_err(|e| {
+        warn!(%e, "Failed to serialize response JSON");
+        Error::Platform(PlatformError::InvalidDeviceResponse)
+    })
+}
+
+fn decode_base64url(encoded: &str) -> Result<Vec<u8>, Error> {
+    base64_url::decode(encoded).map_err(|e| {
+        warn!(%e, "Invalid base64url value in options JSON");
+        Error::Platform(PlatformError::SyntaxError)
+    })
+}
+
+fn client_data_json(r#type: &str, challenge: &str, origin: &str) -> Result<Vec<u8>, Error> {
+    serde_json::to_vec(&CollectedClientData {
+        r#type,
+        challenge,
+        origin,
+        cross_origin: false,
+    })
+    .or(Err(Error::Platform(PlatformError::SyntaxError)))
+}
+
+fn descriptors(
+    credentials: &[CredentialDescriptorJson],
+) -> Result<Vec<Ctap2PublicKeyCredentialDescriptor>, Error> {
+    credentials
+        .iter()
+        .map(|credential| {
+            Ok(Ctap2PublicKeyCredentialDescriptor {
+                id: ByteBuf::from(decode_base64url(&credential.id)?),
+                r#type: credential.r#type,
+                transports: credential.transports.as_ref().map(|transports| {
+                    transports
+                        .iter()
+                        .filter_map(|t| serde_json::from_value(t.as_str().into()).ok())
+                        .collect()
+                }),
+            })
+        })
+        .collect()
+}
+
+fn user_verification(requirement: Option<&str>) -> UserVerificationRequirement {
+    match requirement {
+        Some("required") => UserVerificationRequirement::Required,
+        Some("discouraged") => UserVerificationRequirement::Discouraged,
+        // "preferred" is the default, and unknown values are to be ignored
+        _ => UserVerificationRequirement::Preferred,
+    }
+}
+
+fn timeout(timeout_ms: Option<u64>) -> Duration {
+    timeout_ms
+        .map(Duration::from_millis)
+        .unwrap_or(DEFAULT_TIMEOUT)
+}
+
+/// Returns the host part of an origin, e.g. `example.org` for `https://example.org:8443`, if
+/// the origin is a secure context: https, or http on localhost.
+fn effective_domain(origin: &str) -> Result<String, Error> {
+    let uri: Uri = origin.parse().map_err(|err| {
+        warn!(?err, origin, "Invalid origin");
+        Error::Platform(PlatformError::SecurityError)
+    })?;
+    let Some(host) = uri.host() else {
+        warn!(origin, "Origin has no host");
+        return Err(Error::Platform(PlatformError::SecurityError));
+    };
+    // IPv6 hosts keep their brackets in URIs.
+    let host = host
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .to_ascii_lowercase();
+    let secure = match uri.scheme_str() {
+        Some("https") => true,
+        Some("http") => host == "localhost" || host.ends_with(".localhost"),
+        _ => false,
+    };
+    if !secure {
+        warn!(origin, "Origin is not a secure context");
+        return Err(Error::Platform(PlatformError::SecurityError));
+    }
+    Ok(host)
+}
+
+/// Returns the RP ID to use for `origin`: `rp_id` if it's the effective domain of the origin
+/// or a registrable suffix of it, or the effective domain if the relying party didn't set one.
+///
+/// Without the public suffix list, only single-label suffixes such as `com` are rejected.
+fn rp_id_for_origin(origin: &str, rp_id: Option<&str>) -> Result<String, Error> {
+    let domain = effective_domain(origin)?;
+    if domain.parse::<IpAddr>().is_ok() {
+        warn!(origin, "IP addresses can't be used as RP IDs");
+        return Err(Error::Platform(PlatformError::SecurityError));
+    }
+    let Some(rp_id) = rp_id else {
+        return Ok(domain);
+    };
+    let rp_id = rp_id.to_ascii_lowercase();
+    let is_suffix = rp_id.contains('.') && domain.ends_with(&format!(".{rp_id}"));
+    if rp_id != domain && !is_suffix {
+        warn!(
+            origin,
+            rp_id, "RP ID is not a registrable suffix of the origin"
+        );
+        return Err(Error::Platform(PlatformError::SecurityError));
+    }
+    Ok(rp_id)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use serde_json::Value;
+
+    use super::*;
+    use crate::proto::ctap2::cbor::CborResponse;
+    use crate::proto::ctap2::{Ctap2CommandCode, Ctap2GetInfoResponse};
+    use crate::testing::MockChannel;
+    use crate::transport::local::VirtualDevice;
+
+    const ORIGIN: &str = "https://example.org";
+    const REQUEST_OPTIONS: &str = r#"{
+        "challenge": "AAAA",
+        "allowCredentials": [{"type": "public-key", "id": "BAUG"}],
+        "userVerification": "discouraged"
+    }"#;
+
+    fn no_pin(_: PinRequestReason, _: Option<u32>) -> Option<Zeroizing<String>> {
+        None
+    }
+
+    fn pin_not_set_info() -> Ctap2GetInfoResponse {
+        Ctap2GetInfoResponse {
+            versions: vec!["FIDO_2_1".to_owned()],
+            options: Some([("clientPin".to_owned(), false)].into()),
+            ..Default::default()
+        }
+    }
+
+    fn pin_retries(retries: u32) -> CborResponse {
+        let data = cbor::to_vec(&BTreeMap::from([(0x03, retries)])).unwrap();
+        CborResponse::new_success_from_slice(&data)
+    }
+
+    #[test]
+    fn effective_domain_strips_scheme_and_port() {
+        assert_eq!(
+            effective_domain("https://example.org").unwrap(),
+            "example.org"
+        );
+        assert_eq!(
+            effective_domain("https://Login.Example.org:8443/").unwrap(),
+            "login.example.org"
+        );
+        assert_eq!(effective_domain("https://[::1]:8443").unwrap(), "::1");
+        assert_eq!(
+            effective_domain("http://localhost:8080").unwrap(),
+            "localhost"
+        );
+        for insecure in ["example.org", "http://example.org", "ftp://example.org"] {
+            assert_eq!(
+                effective_domain(insecure),
+                Err(Error::Platform(PlatformError::SecurityError))
+            );
+        }
+    }
+
+    #[test]
+    fn rp_id_must_be_registrable_suffix() {
+        let origin = "https://login.example.org";
+        assert_eq!(rp_id_for_origin(origin, None).unwrap(), "login.example.org");
+        assert_eq!(
+            rp_id_for_origin(origin, Some("example.org")).unwrap(),
+            "example.org"
+        );
+        for rp_id in ["evil.com", "ample.org", "org", "other.login.example.org"] {
+            assert_eq!(
+                rp_id_for_origin(origin, Some(rp_id)),
+                Err(Error::Platform(PlatformError::SecurityError))
+            );
+        }
+        assert_eq!(
+            rp_id_for_origin("https://[::1]:8443", None),
+            Err(Error::Platform(PlatformError::SecurityError))
+        );
+    }
+
+    #[test]
+    fn parse_creation_options() {
+        let json = r#"{
+            "rp": {"id": "example.org", "name": "Example"},
+            "user": {"id": "AQID", "name": "mario.rossi", "displayName": "Mario Rossi"},
+            "challenge": "AAAA",
+            "pubKeyCredParams": [{"type": "public-key", "alg": -7}, {"type": "public-key", "alg": -257}],
+            "excludeCredentials": [{"type": "public-key", "id": "BAUG", "transports": ["usb", "cable"]}],
+            "authenticatorSelection": {"residentKey": "required", "userVerification": "required"},
+            "attestation": "none"
+        }"#;
+        let options: CreationOptionsJson = from_json(json).unwrap();
+        assert_eq!(options.rp.id.as_deref(), Some("example.org"));
+        assert_eq!(options.pub_key_cred_params.len(), 2);
+        assert!(options.pub_key_cred_params[0].is_known());
+        assert!(!options.pub_key_cred_params[1].is_known());
+
+        let exclude = descriptors(&options.exclude_credentials).unwrap();
+        assert_eq!(exclude[0].id.as_slice(), &[4, 5, 6]);
+        assert_eq!(exclude[0].transports, Some(vec![Ctap2Transport::Usb]));
+    }
+
+    #[test]
+    fn attachment_follows_transport() {
+        assert_eq!(
+            attachment("hid"),
+            (Some(Ctap2Transport::Usb), Some("cross-platform"))
+        );
+        assert_eq!(
+            attachment("cable"),
+            (Some(Ctap2Transport::Hybrid), Some("cross-platform"))
+        );
+        assert_eq!(
+            attachment("local"),
+            (Some(Ctap2Transport::Internal), Some("platform"))
+        );
+        assert_eq!(attachment("remote"), (None, None));
+    }
+
+    #[tokio::test]
+    async fn platform_authenticators_are_reported_as_such() {
+        let mut device = VirtualDevice::new_virtual();
+        let mut channel = device.channel().await.unwrap();
+        let options = r#"{
+            "rp": {"name": "Example"},
+            "user": {"id": "AQID", "name": "mario.rossi", "displayName": "Mario Rossi"},
+            "challenge": "AAAA",
+            "pubKeyCredParams": [{"type": "public-key", "alg": -7}],
+            "authenticatorSelection": {"userVerification": "discouraged"}
+        }"#;
+        let registration = register_on_channel(&mut channel, ORIGIN, options, no_pin)
+            .await
+            .unwrap();
+        let registration: Value = serde_json::from_str(&registration).unwrap();
+        assert_eq!(registration["authenticatorAttachment"], "platform");
+        assert_eq!(
+            registration["response"]["transports"],
+            serde_json::json!(["internal"])
+        );
+
+        let options = format!(
+            r#"{{"challenge": "AAAA", "allowCredentials": [{{"type": "public-key", "id": {}}}]}}"#,
+            registration["id"]
+        );
+        let assertion = authenticate_on_channel(&mut channel, ORIGIN, &options, no_pin)
+            .await
+            .unwrap();
+        let assertion: Value = serde_json::from_str(&assertion).unwrap();
+        assert_eq!(assertion["authenticatorAttachment"], "platform");
+    }
+
+    #[tokio::test]
+    async fn user_action_timeouts_are_not_retried() {
+        let mut channel = MockChannel::new();
+        channel
+            .without_preflight()
+            .expect(Ctap2CommandCode::AuthenticatorGetInfo, &pin_not_set_info())
+            .expect(Ctap2CommandCode::AuthenticatorGetInfo, &pin_not_set_info())
+            .expect_error(
+                Ctap2CommandCode::AuthenticatorGetAssertion,
+                CtapError::UserActionTimeout,
+            );
+        let result = authenticate_on_channel(&mut channel, ORIGIN, REQUEST_OPTIONS, no_pin).await;
+        assert_eq!(result, Err(Error::Ctap(CtapError::UserActionTimeout)));
+        channel.assert_done();
+    }
+
+    #[tokio::test]
+    async fn uv_failures_are_retried_a_few_times() {
+        let mut channel = MockChannel::new();
+        channel.without_preflight();
+        for _ in 0..MAX_ATTEMPTS {
+            channel
+                .expect(Ctap2CommandCode::AuthenticatorGetInfo, &pin_not_set_info())
+                .expect(Ctap2CommandCode::AuthenticatorGetInfo, &pin_not_set_info())
+                .expect_error(
+                    Ctap2CommandCode::AuthenticatorGetAssertion,
+                    CtapError::UVInvalid,
+                )
+                // The ceremony reports the UV attempts left.
+                .expect_error(
+                    Ctap2CommandCode::AuthenticatorClientPin,
+                    CtapError::InvalidCommand,
+                );
+        }
+        let result = authenticate_on_channel(&mut channel, ORIGIN, REQUEST_OPTIONS, no_pin).await;
+        assert_eq!(result, Err(Error::Ctap(CtapError::UVInvalid)));
+        channel.assert_done();
+    }
+
+    #[tokio::test]
+    async fn pin_failures_are_retried_while_enough_attempts_are_left() {
+        let mut channel = MockChannel::new();
+        channel
+            .without_preflight()
+            .expect(Ctap2CommandCode::AuthenticatorGetInfo, &pin_not_set_info())
+            .expect(Ctap2CommandCode::AuthenticatorGetInfo, &pin_not_set_info())
+            .expect_error(
+                Ctap2CommandCode::AuthenticatorGetAssertion,
+                CtapError::PINInvalid,
+            )
+            .expect(Ctap2CommandCode::AuthenticatorGetInfo, &pin_not_set_info())
+            .expect_response(
+                Ctap2CommandCode::AuthenticatorClientPin,
+                pin_retries(MIN_PIN_RETRIES + 1),
+            )
+            .expect(Ctap2CommandCode::AuthenticatorGetInfo, &pin_not_set_info())
+            .expect(Ctap2CommandCode::AuthenticatorGetInfo, &pin_not_set_info())
+            .expect_error(
+                Ctap2CommandCode::AuthenticatorGetAssertion,
+                CtapError::PINInvalid,
+            )
+            .expect(Ctap2CommandCode::AuthenticatorGetInfo, &pin_not_set_info())
+            .expect_response(
+                Ctap2CommandCode::AuthenticatorClientPin,
+                pin_retries(MIN_PIN_RETRIES),
+            );
+        let result = authenticate_on_channel(&mut channel, ORIGIN, REQUEST_OPTIONS, no_pin).await;
+        assert_eq!(result, Err(Error::Ctap(CtapError::PINInvalid)));
+        channel.assert_done();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn retries_stop_at_the_deadline() {
+        let mut channel = MockChannel::new();
+        let deadline = OperationDeadline::new(channel.get_timeout_policy(), Duration::from_secs(1));
+        assert!(should_retry(&mut channel, CtapError::UVInvalid, 1, &deadline).await);
+        tokio::time::advance(Duration::from_secs(1)).await;
+        assert!(!should_retry(&mut channel, CtapError::UVInvalid, 1, &deadline).await);
+    }
+
+    #[test]
+    fn parse_request_options_rejects_invalid_json() {
+        assert_eq!(
+            from_json::<RequestOptionsJson>(r#"{"rpId": "example.org"}"#).unwrap_err(),
+            Error::Platform(PlatformError::SyntaxError)
+        );
+    }
+
+    #[test]
+    fn client_data_json_is_ordered() {
+        let client_data = client_data_json("webauthn.get", "AAAA", "https://example.org").unwrap();
+        assert_eq!(
+            String::from_utf8(client_data).unwrap(),
+            r#"{"type":"webauthn.get","challenge":"AAAA","origin":"https://example.org","crossOrigin":false}"#
+        );
+    }
+}
@@ -0,0 +1,240 @@
+//! The randomness and time used by the library, replaceable for reproducible tests.
+//!
+//! PIN protocol key agreements, caBLE QR secrets and client nonces, Noise handshakes, CTAPHID
+//! nonces and challenges draw from the operating system's RNG, and timestamps from the system
+//! clock. With the `testing` feature, code running inside [scope] uses the given [Sources]
+//! instead, e.g. a [SeededRandom] and a [FixedClock], so that handshakes and token ceremonies
+//! come out the same on every run. Tasks spawned by the library, e.g. for caBLE connections,
+//! inherit the sources.
+
+use std::future::Future;
+#[cfg(any(test, feature = "testing"))]
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use rand::rngs::OsRng;
+#[cfg(any(test, feature = "testing"))]
+use rand::{rngs::StdRng, SeedableRng};
+use rand::{CryptoRng, RngCore};
+use snow::params::{CipherChoice, DHChoice, HashChoice};
+use snow::resolvers::{BoxedCryptoResolver, CryptoResolver, DefaultResolver, FallbackResolver};
+use snow::types::{Cipher, Dh, Hash, Random};
+
+#[cfg(any(test, feature = "testing"))]
+tokio::task_local! {
+    static CURRENT: Sources;
+}
+
+#[cfg(any(test, feature = "testing"))]
+pub trait RandomSource: Send + Sync {
+    fn fill_bytes(&self, dest: &mut [u8]);
+}
+
+#[cfg(any(test, feature = "testing"))]
+pub trait Clock: Send + Sync {
+    fn now(&self) -> SystemTime;
+}
+
+#[cfg(any(test, feature = "testing"))]
+#[derive(Clone)]
+pub struct Sources {
+    pub random: Arc<dyn RandomSource>,
+    pub clock: Arc<dyn Clock>,
+}
+
+#[cfg(any(test, feature = "testing"))]
+impl Sources {
+    /// Deterministic randomness from `seed`, and a clock stopped at `now`.
+    pub fn deterministic(seed: u64, now: SystemTime) -> Self {
+        Self {
+            random: Arc::new(SeededRandom::new(seed)),
+            clock: Arc::new(FixedClock(now)),
+        }
+    }
+}
+
+/// Runs `f` with `sources`, operations started within it use them rather than the system's.
+#[cfg(any(test, feature = "testing"))]
+pub async fn scope<F: Future>(sources: Sources, f: F) -> F::Output {
+    CURRENT.scope(sources, f).await
+}
+
+/// Runs `f` with the current task's sources, if any, e.g. before spawning it.
+#[cfg(any(test, feature = "testing"))]
+pub(crate) fn inherit<F: Future>(f: F) -> impl Future<Output = F::Output> {
+    let sources = CURRENT.try_with(Clone::clone).ok();
+    async move {
+        match sources {
+            Some(sources) => CURRENT.scope(sources, f).await,
+            None => f.await,
+        }
+    }
+}
+
+#[cfg(not(any(test, feature = "testing")))]
+pub(crate) fn inherit<F: Future>(f: F) -> F {
+    f
+}
+
+/// Fills `dest` from the current random source, or the operating system's RNG.
+pub(crate) fn fill_bytes(dest: &mut [u8]) {
+    #[cfg(any(test, feature = "testing"))]
+    if let Ok(random) = CURRENT.try_with(|sources| sources.random.clone()) {
+        return random.fill_bytes(dest);
+    }
+    OsRng.fill_bytes(dest)
+}
+
+pub(crate) fn random<const N: usize>() -> [u8; N] {
+    let mut bytes = [0; N];
+    fill_bytes(&mut bytes);
+    bytes
+}
+
+/// The current time of the current clock, or the system's.
+pub(crate) fn now() -> SystemTime {
+    #[cfg(any(test, feature = "testing"))]
+    if let Ok(clock) = CURRENT.try_with(|sources| sources.clock.clone()) {
+        return clock.now();
+    }
+    SystemTime::now()
+}
+
+/// An RNG drawing from the current random source, for generating keys.
+pub(crate) struct CurrentRng;
+
+impl RngCore for CurrentRng {
+    fn next_u32(&mut self) -> u32 {
+        u32::from_le_bytes(random())
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        u64::from_le_bytes(random())
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        fill_bytes(dest)
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand::Error> {
+        fill_bytes(dest);
+        Ok(())
+    }
+}
+
+// The operating system's RNG, unless replaced in a test build.
+impl CryptoRng for CurrentRng {}
+
+impl Random for CurrentRng {
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), snow::Error> {
+        fill_bytes(dest);
+        Ok(())
+    }
+}
+
+/// Resolves the Noise primitives as snow does by default, with the ephemeral keys drawn from
+/// the current random source.
+pub(crate) fn noise_resolver() -> BoxedCryptoResolver {
+    Box::new(FallbackResolver::new(
+        Box::new(CurrentRngResolver),
+        Box::new(DefaultResolver),
+    ))
+}
+
+struct CurrentRngResolver;
+
+impl CryptoResolver for CurrentRngResolver {
+    fn resolve_rng(&self) -> Option<Box<dyn Random>> {
+        Some(Box::new(CurrentRng))
+    }
+
+    fn resolve_dh(&self, _choice: &DHChoice) -> Option<Box<dyn Dh>> {
+        None
+    }
+
+    fn resolve_hash(&self, _choice: &HashChoice) -> Option<Box<dyn Hash>> {
+        None
+    }
+
+    fn resolve_cipher(&self, _choice: &CipherChoice) -> Option<Box<dyn Cipher>> {
+        None
+    }
+}
+
+/// Randomness derived from a seed. Not suitable outside of tests.
+#[cfg(any(test, feature = "testing"))]
+pub struct SeededRandom(Mutex<StdRng>);
+
+#[cfg(any(test, feature = "testing"))]
+impl SeededRandom {
+    pub fn new(seed: u64) -> Self {
+        Self(Mutex::new(StdRng::seed_from_u64(seed)))
+    }
+}
+
+#[cfg(any(test, feature = "testing"))]
+impl RandomSource for SeededRandom {
+    fn fill_bytes(&self, dest: &mut [u8]) {
+        self.0.lock().unwrap().fill_bytes(dest)
+    }
+}
+
+#[cfg(any(test, feature = "testing"))]
+pub struct FixedClock(pub SystemTime);
+
+#[cfg(any(test, feature = "testing"))]
+impl Clock for FixedClock {
+    fn now(&self) -> SystemTime {
+        self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime};
+
+    use snow::Builder;
+
+    use super::{inherit, noise_resolver, now, random, scope, Sources};
+
+    #[tokio::test]
+    async fn deterministic_sources_repeat() {
+        let at = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let run = || {
+            scope(Sources::deterministic(7, at), async {
+                (random::<16>(), now())
+            })
+        };
+        let (first, second) = (run().await, run().await);
+        assert_eq!(first, second);
+        assert_eq!(first.1, at);
+        assert_ne!(random::<16>(), first.0);
+
+        let spawned = scope(Sources::deterministic(7, at), async {
+            tokio::spawn(inherit(async { random::<16>() }))
+                .await
+                .unwrap()
+        })
+        .await;
+        assert_eq!(spawned, first.0);
+    }
+
+    #[tokio::test]
+    async fn noise_handshake_is_deterministic() {
+        let handshake = || {
+            scope(Sources::deterministic(7, SystemTime::now()), async {
+                let params = "Noise_NNpsk0_25519_ChaChaPoly_SHA256".parse().unwrap();
+                let mut initiator = Builder::with_resolver(params, noise_resolver())
+                    .psk(0, &[1; 32])
+                    .unwrap()
+                    .build_initiator()
+                    .unwrap();
+                let mut message = vec![0; 128];
+                let len = initiator.write_message(&[], &mut message).unwrap();
+                message.truncate(len);
+                message
+            })
+        };
+        assert_eq!(handshake().await, handshake().await);
+    }
+}
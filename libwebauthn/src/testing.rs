@@ -0,0 +1,331 @@
+//! Utilities for testing WebAuthn flows without devices.
+//!
+//! [MockChannel] answers CBOR requests from a script of expectations, so that applications
+//! can unit-test how they drive ceremonies, and how they handle authenticator errors:
+//!
+//! ```
+//! # use libwebauthn::proto::ctap2::{Ctap2CommandCode, Ctap2GetInfoResponse};
+//! # use libwebauthn::proto::CtapError;
+//! # use libwebauthn::testing::MockChannel;
+//! let mut channel = MockChannel::new();
+//! channel
+//!     .expect(Ctap2CommandCode::AuthenticatorGetInfo, &Ctap2GetInfoResponse::default())
+//!     .expect_error(
+//!         Ctap2CommandCode::AuthenticatorMakeCredential,
+//!         CtapError::OperationDenied,
+//!     );
+//! ```
+//!
+//! Requests arriving out of script panic, failing the test. Only available with the `testing`
+//! feature.
+
+use std::collections::VecDeque;
+use std::fmt::{Display, Formatter};
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use serde::Serialize;
+use tokio::sync::broadcast;
+use tokio_util::sync::CancellationToken;
+use tracing::debug;
+
+use crate::audit::AuditTrail;
+use crate::metrics::MetricsRecorder;
+use crate::pin::{PinProvider, PinUvAuthProtocols};
+use crate::proto::ctap1::apdu::{ApduRequest, ApduResponse};
+use crate::proto::ctap2::cbor::{self, CborRequest, CborResponse};
+use crate::proto::ctap2::Ctap2CommandCode;
+use crate::proto::CtapError;
+use crate::timeout::TimeoutPolicy;
+use crate::transport::device::SupportedProtocols;
+use crate::transport::error::TransportError;
+use crate::transport::{AuthTokenData, Channel, ChannelStatus, Ctap2AuthTokenStore};
+use crate::webauthn::error::Error;
+use crate::UvUpdate;
+
+struct Expectation {
+    command: Ctap2CommandCode,
+    response: CborResponse,
+}
+
+/// A CTAP2 channel answering requests from a script of expectations, in order.
+pub struct MockChannel {
+    protocols: SupportedProtocols,
+    expectations: VecDeque<Expectation>,
+    requests: Vec<CborRequest>,
+    response: Option<CborResponse>,
+    auth_token_data: Option<AuthTokenData>,
+    pin_provider: Option<Arc<dyn PinProvider>>,
+    cancellation_token: Option<CancellationToken>,
+    timeout_policy: TimeoutPolicy,
+    audit_trail: Option<AuditTrail>,
+    metrics_recorder: Option<Arc<dyn MetricsRecorder>>,
+    pin_uv_auth_protocols: PinUvAuthProtocols,
+    ux_update_sender: broadcast::Sender<UvUpdate>,
+    locked: bool,
+    preflight: bool,
+}
+
+impl MockChannel {
+    pub fn new() -> Self {
+        let (ux_update_sender, _) = broadcast::channel(16);
+        Self {
+            protocols: SupportedProtocols::fido2_only(),
+            expectations: VecDeque::new(),
+            requests: vec![],
+            response: None,
+            auth_token_data: None,
+            pin_provider: None,
+            cancellation_token: None,
+            timeout_policy: TimeoutPolicy::default(),
+            audit_trail: None,
+            metrics_recorder: None,
+            pin_uv_auth_protocols: PinUvAuthProtocols::default(),
+            ux_update_sender,
+            locked: false,
+            preflight: true,
+        }
+    }
+
+    /// Expects a `command` request, answered successfully with `response` encoded as CBOR.
+    pub fn expect<T: Serialize>(&mut self, command: Ctap2CommandCode, response: &T) -> &mut Self {
+        let data = cbor::to_vec_canonical(response).expect("Mock response must serialize as CBOR");
+        self.expect_response(command, CborResponse::new_success_from_slice(&data))
+    }
+
+    /// Expects a `command` request, answered with the status code `error`.
+    pub fn expect_error(&mut self, command: Ctap2CommandCode, error: CtapError) -> &mut Self {
+        let response = CborResponse {
+            status_code: error,
+            data: None,
+        };
+        self.expect_response(command, response)
+    }
+
+    /// Expects a `command` request, answered with `response` as is.
+    pub fn expect_response(
+        &mut self,
+        command: Ctap2CommandCode,
+        response: CborResponse,
+    ) -> &mut Self {
+        self.expectations
+            .push_back(Expectation { command, response });
+        self
+    }
+
+    /// Reports no support for pre-flight requests, as caBLE channels do.
+    pub fn without_preflight(&mut self) -> &mut Self {
+        self.preflight = false;
+        self
+    }
+
+    /// The requests received so far, e.g. to decode and check their parameters.
+    pub fn requests(&self) -> &[CborRequest] {
+        &self.requests
+    }
+
+    /// Whether the channel was locked with [Channel::lock_device], and not unlocked since.
+    pub fn is_locked(&self) -> bool {
+        self.locked
+    }
+
+    /// Panics unless all expected requests were received.
+    pub fn assert_done(&self) {
+        let remaining: Vec<_> = self.expectations.iter().map(|e| e.command).collect();
+        assert!(
+            remaining.is_empty(),
+            "Expected requests not received: {remaining:?}"
+        );
+    }
+}
+
+impl Default for MockChannel {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Display for MockChannel {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Mock")
+    }
+}
+
+#[async_trait]
+impl Channel for MockChannel {
+    type UxUpdate = UvUpdate;
+
+    fn transport_name(&self) -> &'static str {
+        "mock"
+    }
+
+    fn get_ux_update_sender(&self) -> &broadcast::Sender<UvUpdate> {
+        &self.ux_update_sender
+    }
+
+    fn get_pin_provider(&self) -> Option<Arc<dyn PinProvider>> {
+        self.pin_provider.clone()
+    }
+
+    fn set_pin_provider(&mut self, provider: Option<Arc<dyn PinProvider>>) {
+        self.pin_provider = provider;
+    }
+
+    fn get_cancellation_token(&self) -> Option<CancellationToken> {
+        self.cancellation_token.clone()
+    }
+
+    fn set_cancellation_token(&mut self, token: Option<CancellationToken>) {
+        self.cancellation_token = token;
+    }
+
+    fn get_timeout_policy(&self) -> TimeoutPolicy {
+        self.timeout_policy
+    }
+
+    fn set_timeout_policy(&mut self, policy: TimeoutPolicy) {
+        self.timeout_policy = policy;
+    }
+
+    fn get_audit_trail(&self) -> Option<AuditTrail> {
+        self.audit_trail.clone()
+    }
+
+    fn set_audit_trail(&mut self, trail: Option<AuditTrail>) {
+        self.audit_trail = trail;
+    }
+
+    fn get_metrics_recorder(&self) -> Option<Arc<dyn MetricsRecorder>> {
+        self.metrics_recorder.clone()
+    }
+
+    fn set_metrics_recorder(&mut self, recorder: Option<Arc<dyn MetricsRecorder>>) {
+        self.metrics_recorder = recorder;
+    }
+
+    fn get_pin_uv_auth_protocols(&self) -> PinUvAuthProtocols {
+        self.pin_uv_auth_protocols.clone()
+    }
+
+    fn set_pin_uv_auth_protocols(&mut self, protocols: PinUvAuthProtocols) {
+        self.pin_uv_auth_protocols = protocols;
+    }
+
+    fn supports_preflight(&self) -> bool {
+        self.preflight
+    }
+
+    async fn supported_protocols(&self) -> Result<SupportedProtocols, Error> {
+        Ok(self.protocols)
+    }
+
+    async fn status(&self) -> ChannelStatus {
+        ChannelStatus::Ready
+    }
+
+    async fn close(&mut self) {}
+
+    async fn lock_device(&mut self, _duration: Duration) -> Result<(), Error> {
+        self.locked = true;
+        Ok(())
+    }
+
+    async fn unlock_device(&mut self) -> Result<(), Error> {
+        self.locked = false;
+        Ok(())
+    }
+
+    async fn apdu_send(&self, _request: &ApduRequest, _timeout: Duration) -> Result<(), Error> {
+        Err(Error::Transport(TransportError::TransportUnavailable))
+    }
+
+    async fn apdu_recv(&self, _timeout: Duration) -> Result<ApduResponse, Error> {
+        Err(Error::Transport(TransportError::TransportUnavailable))
+    }
+
+    async fn cbor_send(&mut self, request: &CborRequest, _timeout: Duration) -> Result<(), Error> {
+        let Some(expectation) = self.expectations.pop_front() else {
+            panic!("Unexpected {:?} request", request.command);
+        };
+        assert_eq!(
+            expectation.command, request.command,
+            "Expected {:?} request, received {:?}",
+            expectation.command, request.command
+        );
+        debug!(command = ?request.command, "Answering mocked request");
+        self.requests.push(request.clone());
+        self.response = Some(expectation.response);
+        Ok(())
+    }
+
+    async fn cbor_recv(&mut self, _timeout: Duration) -> Result<CborResponse, Error> {
+        self.response
+            .take()
+            .ok_or(Error::Transport(TransportError::InvalidFraming))
+    }
+}
+
+impl Ctap2AuthTokenStore for MockChannel {
+    fn store_auth_data(&mut self, auth_token_data: AuthTokenData) {
+        self.auth_token_data = Some(auth_token_data);
+    }
+
+    fn get_auth_data(&self) -> Option<&AuthTokenData> {
+        self.auth_token_data.as_ref()
+    }
+
+    fn clear_uv_auth_token_store(&mut self) {
+        self.auth_token_data = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::MockChannel;
+    use crate::ops::webauthn::{Ctap1Fallback, MakeCredentialRequest};
+    use crate::proto::ctap2::{Ctap2CommandCode, Ctap2GetInfoResponse};
+    use crate::proto::CtapError;
+    use crate::transport::device::SupportedProtocols;
+    use crate::transport::error::TransportError;
+    use crate::webauthn::{Error, WebAuthn};
+
+    #[tokio::test]
+    async fn scripted_error_is_returned() {
+        let info = Ctap2GetInfoResponse {
+            versions: vec!["FIDO_2_0".to_owned()],
+            ..Default::default()
+        };
+        let mut channel = MockChannel::new();
+        // Queried once for the request, and once for user verification.
+        channel
+            .expect(Ctap2CommandCode::AuthenticatorGetInfo, &info)
+            .expect(Ctap2CommandCode::AuthenticatorGetInfo, &info)
+            .expect_error(
+                Ctap2CommandCode::AuthenticatorMakeCredential,
+                CtapError::OperationDenied,
+            );
+
+        let result = channel
+            .webauthn_make_credential(&MakeCredentialRequest::dummy())
+            .await;
+        assert_eq!(result.unwrap_err(), Error::Ctap(CtapError::OperationDenied));
+        assert_eq!(channel.requests().len(), 3);
+        channel.assert_done();
+    }
+
+    #[tokio::test]
+    async fn ctap1_fallback_never_fails_on_u2f_devices() {
+        let mut channel = MockChannel::new();
+        channel.protocols = SupportedProtocols::u2f_only();
+        let mut request = MakeCredentialRequest::dummy();
+        request.ctap1_fallback = Ctap1Fallback::Never;
+
+        let result = channel.webauthn_make_credential(&request).await;
+        assert_eq!(
+            result.unwrap_err(),
+            Error::Transport(TransportError::NegotiationFailed)
+        );
+        assert!(channel.requests().is_empty());
+    }
+}
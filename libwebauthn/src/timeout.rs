@@ -0,0 +1,149 @@
+//! Timeouts of high-level operations.
+//!
+//! A ceremony such as `webauthn_make_credential` sends several requests to the device: some
+//! are answered right away (e.g. getInfo, clientPin), others wait for the user to touch the
+//! device or verify. A [TimeoutPolicy], set once per channel, bounds each kind of request,
+//! while the ceremony's own timeout bounds all of them together, through an
+//! [OperationDeadline].
+
+use std::time::Duration;
+
+use tokio::time::Instant;
+use tracing::warn;
+
+use crate::transport::error::TransportError;
+use crate::webauthn::error::Error;
+
+/// Per-request timeouts, distinguishing requests waiting for the user from plain I/O.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimeoutPolicy {
+    /// Longest wait for a request not needing the user, e.g. getInfo or getKeyAgreement.
+    pub io: Duration,
+    /// Longest wait for a request needing the user's presence or built-in UV, e.g.
+    /// makeCredential or selection.
+    pub user_presence: Duration,
+}
+
+impl TimeoutPolicy {
+    /// Authenticators answer plain requests within milliseconds, well below this.
+    pub const DEFAULT_IO: Duration = Duration::from_secs(5);
+    /// Authenticators give up on the user after about 30 seconds, and answer with
+    /// CTAP2_ERR_USER_ACTION_TIMEOUT; this leaves them time to do so.
+    pub const DEFAULT_USER_PRESENCE: Duration = Duration::from_secs(35);
+
+    pub fn with_io(mut self, timeout: Duration) -> Self {
+        self.io = timeout;
+        self
+    }
+
+    pub fn with_user_presence(mut self, timeout: Duration) -> Self {
+        self.user_presence = timeout;
+        self
+    }
+}
+
+impl Default for TimeoutPolicy {
+    fn default() -> Self {
+        Self {
+            io: Self::DEFAULT_IO,
+            user_presence: Self::DEFAULT_USER_PRESENCE,
+        }
+    }
+}
+
+/// The deadline of a ceremony, capping the timeouts of its requests.
+///
+/// A plain [Duration] converts into a deadline starting now, with that duration for every
+/// kind of request, as timeouts worked before policies.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OperationDeadline {
+    policy: TimeoutPolicy,
+    deadline: Instant,
+}
+
+impl OperationDeadline {
+    /// Starts a ceremony which must complete within `timeout`.
+    pub fn new(policy: TimeoutPolicy, timeout: Duration) -> Self {
+        Self {
+            policy,
+            deadline: Instant::now() + timeout,
+        }
+    }
+
+    /// Time left until the ceremony times out.
+    pub fn remaining(&self) -> Duration {
+        self.deadline.saturating_duration_since(Instant::now())
+    }
+
+    /// Timeout for the next request not needing the user.
+    pub fn io_timeout(&self) -> Result<Duration, Error> {
+        self.cap(self.policy.io)
+    }
+
+    /// Timeout for the next request needing the user's presence or built-in UV.
+    pub fn user_presence_timeout(&self) -> Result<Duration, Error> {
+        self.cap(self.policy.user_presence)
+    }
+
+    fn cap(&self, timeout: Duration) -> Result<Duration, Error> {
+        let remaining = self.remaining();
+        if remaining.is_zero() {
+            warn!("Operation deadline passed");
+            return Err(Error::Transport(TransportError::Timeout));
+        }
+        Ok(timeout.min(remaining))
+    }
+}
+
+impl From<Duration> for OperationDeadline {
+    fn from(timeout: Duration) -> Self {
+        let policy = TimeoutPolicy {
+            io: timeout,
+            user_presence: timeout,
+        };
+        Self::new(policy, timeout)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{OperationDeadline, TimeoutPolicy};
+    use crate::transport::error::TransportError;
+    use crate::webauthn::error::Error;
+
+    #[tokio::test(start_paused = true)]
+    async fn requests_are_capped_by_deadline() {
+        let policy = TimeoutPolicy::default();
+        let deadline = OperationDeadline::new(policy, Duration::from_secs(60));
+        assert_eq!(deadline.io_timeout().unwrap(), TimeoutPolicy::DEFAULT_IO);
+        assert_eq!(
+            deadline.user_presence_timeout().unwrap(),
+            TimeoutPolicy::DEFAULT_USER_PRESENCE
+        );
+
+        tokio::time::advance(Duration::from_secs(50)).await;
+        assert_eq!(deadline.io_timeout().unwrap(), TimeoutPolicy::DEFAULT_IO);
+        assert_eq!(
+            deadline.user_presence_timeout().unwrap(),
+            Duration::from_secs(10)
+        );
+
+        tokio::time::advance(Duration::from_secs(10)).await;
+        assert!(matches!(
+            deadline.io_timeout(),
+            Err(Error::Transport(TransportError::Timeout))
+        ));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn duration_applies_to_every_request() {
+        let deadline = OperationDeadline::from(Duration::from_secs(2));
+        assert_eq!(deadline.io_timeout().unwrap(), Duration::from_secs(2));
+        assert_eq!(
+            deadline.user_presence_timeout().unwrap(),
+            Duration::from_secs(2)
+        );
+    }
+}
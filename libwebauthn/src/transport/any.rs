@@ -0,0 +1,479 @@
+//! Devices and channels of any transport, behind a single type.
+//!
+//! [`Channel`] and [`Device`] are generic over the transport, which makes it hard to keep
+//! devices of different transports together, e.g. in a `Vec`. [`AnyDevice`] and [`AnyChannel`]
+//! wrap the concrete types, and can be used anywhere a device or channel is expected.
+
+use std::fmt::{self, Display, Formatter};
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::task::JoinHandle;
+use tracing::{trace, warn};
+
+use crate::audit::AuditTrail;
+use crate::metrics::MetricsRecorder;
+use crate::pin::{PinProvider, PinUvAuthProtocols};
+use crate::proto::ctap1::apdu::{ApduRequest, ApduResponse};
+use crate::proto::ctap2::cbor::{CborRequest, CborResponse};
+use crate::quirks::UsbId;
+use crate::timeout::TimeoutPolicy;
+use crate::transport::ble::channel::BleChannel;
+use crate::transport::ble::BleDevice;
+use crate::transport::cable::channel::{CableChannel, CableUpdate, CableUxUpdate};
+use crate::transport::cable::known_devices::{CableKnownDevice, ClientPayloadHint};
+use crate::transport::cable::qr_code_device::CableQrCodeDevice;
+use crate::transport::channel::{AuthTokenData, ChannelStatus, DeviceAaguid};
+#[cfg(feature = "daemon")]
+use crate::transport::daemon::{DaemonChannel, DaemonDevice};
+use crate::transport::device::SupportedProtocols;
+use crate::transport::hid::channel::HidChannel;
+use crate::transport::hid::HidDevice;
+use crate::transport::local::{CredentialStore, LocalChannel, LocalDevice};
+#[cfg(feature = "remote")]
+use crate::transport::remote::{RemoteChannel, RemoteDevice};
+use crate::transport::{CancellationToken, Channel, Ctap2AuthTokenStore, Device, Transport};
+use crate::webauthn::error::Error;
+use crate::UvUpdate;
+
+pub struct AnyTransport {}
+impl Transport for AnyTransport {}
+unsafe impl Send for AnyTransport {}
+unsafe impl Sync for AnyTransport {}
+
+impl Display for AnyTransport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "Any")
+    }
+}
+
+/// A device on any of the supported transports.
+pub enum AnyDevice {
+    Hid(HidDevice),
+    Ble(BleDevice),
+    /// A previously linked caBLE authenticator. WebAuthn ceremonies on its channel send the
+    /// authenticator a hint derived from their request. The device's own hint is only used for
+    /// pre-connecting, and for channels whose first request is something else, e.g. management.
+    CableKnown(CableKnownDevice),
+    CableQrCode(CableQrCodeDevice),
+    /// An authenticator running in this process. Its store is boxed, so that devices with
+    /// different stores can be kept together.
+    Local(LocalDevice<Box<dyn CredentialStore>>),
+    #[cfg(feature = "remote")]
+    Remote(RemoteDevice),
+    #[cfg(feature = "daemon")]
+    Daemon(DaemonDevice),
+}
+
+impl fmt::Debug for AnyDevice {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            AnyDevice::Hid(device) => f.debug_tuple("Hid").field(device).finish(),
+            AnyDevice::Ble(device) => f.debug_tuple("Ble").field(device).finish(),
+            AnyDevice::CableKnown(device) => f.debug_tuple("CableKnown").field(device).finish(),
+            // Holds the QR code's private key.
+            AnyDevice::CableQrCode(device) => write!(f, "CableQrCode({})", device),
+            AnyDevice::Local(device) => write!(f, "Local({})", device),
+            #[cfg(feature = "remote")]
+            AnyDevice::Remote(device) => f.debug_tuple("Remote").field(device).finish(),
+            #[cfg(feature = "daemon")]
+            AnyDevice::Daemon(device) => f.debug_tuple("Daemon").field(device).finish(),
+        }
+    }
+}
+
+impl Display for AnyDevice {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            AnyDevice::Hid(device) => device.fmt(f),
+            AnyDevice::Ble(device) => device.fmt(f),
+            AnyDevice::CableKnown(device) => device.fmt(f),
+            AnyDevice::CableQrCode(device) => device.fmt(f),
+            AnyDevice::Local(device) => device.fmt(f),
+            #[cfg(feature = "remote")]
+            AnyDevice::Remote(device) => device.fmt(f),
+            #[cfg(feature = "daemon")]
+            AnyDevice::Daemon(device) => device.fmt(f),
+        }
+    }
+}
+
+#[async_trait]
+impl<'d> Device<'d, AnyTransport, AnyChannel<'d>> for AnyDevice {
+    async fn channel(&'d mut self) -> Result<AnyChannel<'d>, Error> {
+        Ok(match self {
+            AnyDevice::Hid(device) => device.channel().await?.into(),
+            AnyDevice::Ble(device) => device.channel().await?.into(),
+            AnyDevice::CableKnown(device) => device.channel().await?.into(),
+            AnyDevice::CableQrCode(device) => device.channel().await?.into(),
+            AnyDevice::Local(device) => device.channel().await?.into(),
+            #[cfg(feature = "remote")]
+            AnyDevice::Remote(device) => device.channel().await?.into(),
+            #[cfg(feature = "daemon")]
+            AnyDevice::Daemon(device) => device.channel().await?.into(),
+        })
+    }
+}
+
+/// UX updates of an [`AnyChannel`], covering those of all transports.
+#[derive(Debug, Clone)]
+pub enum AnyUxUpdate {
+    UvUpdate(UvUpdate),
+    CableUpdate(CableUpdate),
+}
+
+impl From<UvUpdate> for AnyUxUpdate {
+    fn from(update: UvUpdate) -> Self {
+        AnyUxUpdate::UvUpdate(update)
+    }
+}
+
+impl From<CableUxUpdate> for AnyUxUpdate {
+    fn from(update: CableUxUpdate) -> Self {
+        match update {
+            CableUxUpdate::UvUpdate(update) => AnyUxUpdate::UvUpdate(update),
+            CableUxUpdate::CableUpdate(update) => AnyUxUpdate::CableUpdate(update),
+        }
+    }
+}
+
+enum AnyChannelInner<'d> {
+    Hid(HidChannel<'d>),
+    // Much larger than the others.
+    Ble(Box<BleChannel<'d>>),
+    Cable(CableChannel),
+    Local(LocalChannel<'d, Box<dyn CredentialStore>>),
+    #[cfg(feature = "remote")]
+    Remote(RemoteChannel<'d>),
+    #[cfg(feature = "daemon")]
+    Daemon(DaemonChannel<'d>),
+}
+
+/// A channel to a device on any transport.
+pub struct AnyChannel<'d> {
+    inner: AnyChannelInner<'d>,
+    ux_update_sender: broadcast::Sender<AnyUxUpdate>,
+    // Forwards the updates sent by the wrapped channel itself.
+    forward_task: JoinHandle<()>,
+}
+
+macro_rules! delegate {
+    ($inner:expr, $channel:ident => $call:expr) => {
+        match $inner {
+            AnyChannelInner::Hid($channel) => $call,
+            AnyChannelInner::Ble($channel) => {
+                let $channel = $channel.as_ref();
+                $call
+            }
+            AnyChannelInner::Cable($channel) => $call,
+            AnyChannelInner::Local($channel) => $call,
+            #[cfg(feature = "remote")]
+            AnyChannelInner::Remote($channel) => $call,
+            #[cfg(feature = "daemon")]
+            AnyChannelInner::Daemon($channel) => $call,
+        }
+    };
+}
+
+macro_rules! delegate_mut {
+    ($inner:expr, $channel:ident => $call:expr) => {
+        match $inner {
+            AnyChannelInner::Hid($channel) => $call,
+            AnyChannelInner::Ble($channel) => {
+                let $channel = $channel.as_mut();
+                $call
+            }
+            AnyChannelInner::Cable($channel) => $call,
+            AnyChannelInner::Local($channel) => $call,
+            #[cfg(feature = "remote")]
+            AnyChannelInner::Remote($channel) => $call,
+            #[cfg(feature = "daemon")]
+            AnyChannelInner::Daemon($channel) => $call,
+        }
+    };
+}
+
+impl<'d> AnyChannel<'d> {
+    fn new(inner: AnyChannelInner<'d>) -> Self {
+        let (ux_update_sender, _) = broadcast::channel(16);
+        let forward_task =
+            delegate!(&inner, channel => forward_ux_updates(channel, &ux_update_sender));
+        Self {
+            inner,
+            ux_update_sender,
+            forward_task,
+        }
+    }
+
+    /// The wrapped HID channel, e.g. to cancel an ongoing operation through its handle.
+    pub fn as_hid(&self) -> Option<&HidChannel<'d>> {
+        match &self.inner {
+            AnyChannelInner::Hid(channel) => Some(channel),
+            _ => None,
+        }
+    }
+}
+
+fn forward_ux_updates<C>(channel: &C, sender: &broadcast::Sender<AnyUxUpdate>) -> JoinHandle<()>
+where
+    C: Channel,
+    C::UxUpdate: Clone + Into<AnyUxUpdate> + 'static,
+{
+    let mut receiver = channel.get_ux_update_receiver();
+    let sender = sender.clone();
+    tokio::spawn(async move {
+        loop {
+            match receiver.recv().await {
+                Ok(update) => {
+                    if sender.send(update.into()).is_err() {
+                        trace!("No receivers for UX update");
+                    }
+                }
+                Err(RecvError::Lagged(skipped)) => warn!(skipped, "Dropped UX updates"),
+                Err(RecvError::Closed) => return,
+            }
+        }
+    })
+}
+
+impl<'d> From<HidChannel<'d>> for AnyChannel<'d> {
+    fn from(channel: HidChannel<'d>) -> Self {
+        Self::new(AnyChannelInner::Hid(channel))
+    }
+}
+
+impl<'d> From<BleChannel<'d>> for AnyChannel<'d> {
+    fn from(channel: BleChannel<'d>) -> Self {
+        Self::new(AnyChannelInner::Ble(Box::new(channel)))
+    }
+}
+
+impl From<CableChannel> for AnyChannel<'_> {
+    fn from(channel: CableChannel) -> Self {
+        Self::new(AnyChannelInner::Cable(channel))
+    }
+}
+
+impl<'d> From<LocalChannel<'d, Box<dyn CredentialStore>>> for AnyChannel<'d> {
+    fn from(channel: LocalChannel<'d, Box<dyn CredentialStore>>) -> Self {
+        Self::new(AnyChannelInner::Local(channel))
+    }
+}
+
+#[cfg(feature = "remote")]
+impl<'d> From<RemoteChannel<'d>> for AnyChannel<'d> {
+    fn from(channel: RemoteChannel<'d>) -> Self {
+        Self::new(AnyChannelInner::Remote(channel))
+    }
+}
+
+#[cfg(feature = "daemon")]
+impl<'d> From<DaemonChannel<'d>> for AnyChannel<'d> {
+    fn from(channel: DaemonChannel<'d>) -> Self {
+        Self::new(AnyChannelInner::Daemon(channel))
+    }
+}
+
+impl Drop for AnyChannel<'_> {
+    fn drop(&mut self) {
+        self.forward_task.abort();
+    }
+}
+
+impl Display for AnyChannel<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        delegate!(&self.inner, channel => channel.fmt(f))
+    }
+}
+
+#[async_trait]
+impl Channel for AnyChannel<'_> {
+    type UxUpdate = AnyUxUpdate;
+
+    fn get_ux_update_sender(&self) -> &broadcast::Sender<AnyUxUpdate> {
+        &self.ux_update_sender
+    }
+
+    fn get_pin_provider(&self) -> Option<Arc<dyn PinProvider>> {
+        delegate!(&self.inner, channel => channel.get_pin_provider())
+    }
+
+    fn set_pin_provider(&mut self, provider: Option<Arc<dyn PinProvider>>) {
+        delegate_mut!(&mut self.inner, channel => channel.set_pin_provider(provider))
+    }
+
+    fn get_cancellation_token(&self) -> Option<CancellationToken> {
+        delegate!(&self.inner, channel => channel.get_cancellation_token())
+    }
+
+    fn set_cancellation_token(&mut self, token: Option<CancellationToken>) {
+        delegate_mut!(&mut self.inner, channel => channel.set_cancellation_token(token))
+    }
+
+    fn get_timeout_policy(&self) -> TimeoutPolicy {
+        delegate!(&self.inner, channel => channel.get_timeout_policy())
+    }
+
+    fn set_timeout_policy(&mut self, policy: TimeoutPolicy) {
+        delegate_mut!(&mut self.inner, channel => channel.set_timeout_policy(policy))
+    }
+
+    fn get_audit_trail(&self) -> Option<AuditTrail> {
+        delegate!(&self.inner, channel => channel.get_audit_trail())
+    }
+
+    fn set_audit_trail(&mut self, trail: Option<AuditTrail>) {
+        delegate_mut!(&mut self.inner, channel => channel.set_audit_trail(trail))
+    }
+
+    fn get_metrics_recorder(&self) -> Option<Arc<dyn MetricsRecorder>> {
+        delegate!(&self.inner, channel => channel.get_metrics_recorder())
+    }
+
+    fn set_metrics_recorder(&mut self, recorder: Option<Arc<dyn MetricsRecorder>>) {
+        delegate_mut!(&mut self.inner, channel => channel.set_metrics_recorder(recorder))
+    }
+
+    fn get_pin_uv_auth_protocols(&self) -> PinUvAuthProtocols {
+        delegate!(&self.inner, channel => channel.get_pin_uv_auth_protocols())
+    }
+
+    fn set_pin_uv_auth_protocols(&mut self, protocols: PinUvAuthProtocols) {
+        delegate_mut!(&mut self.inner, channel => channel.set_pin_uv_auth_protocols(protocols))
+    }
+
+    async fn supported_protocols(&self) -> Result<SupportedProtocols, Error> {
+        delegate!(&self.inner, channel => channel.supported_protocols().await)
+    }
+
+    async fn status(&self) -> ChannelStatus {
+        delegate!(&self.inner, channel => channel.status().await)
+    }
+
+    async fn close(&mut self) {
+        delegate_mut!(&mut self.inner, channel => channel.close().await)
+    }
+
+    async fn apdu_send(&self, request: &ApduRequest, timeout: Duration) -> Result<(), Error> {
+        delegate!(&self.inner, channel => channel.apdu_send(request, timeout).await)
+    }
+
+    async fn apdu_recv(&self, timeout: Duration) -> Result<ApduResponse, Error> {
+        delegate!(&self.inner, channel => channel.apdu_recv(timeout).await)
+    }
+
+    async fn cbor_send(&mut self, request: &CborRequest, timeout: Duration) -> Result<(), Error> {
+        delegate_mut!(&mut self.inner, channel => channel.cbor_send(request, timeout).await)
+    }
+
+    async fn cbor_recv(&mut self, timeout: Duration) -> Result<CborResponse, Error> {
+        delegate_mut!(&mut self.inner, channel => channel.cbor_recv(timeout).await)
+    }
+
+    async fn lock_device(&mut self, duration: Duration) -> Result<(), Error> {
+        delegate_mut!(&mut self.inner, channel => channel.lock_device(duration).await)
+    }
+
+    async fn unlock_device(&mut self) -> Result<(), Error> {
+        delegate_mut!(&mut self.inner, channel => channel.unlock_device().await)
+    }
+
+    fn usb_id(&self) -> Option<UsbId> {
+        delegate!(&self.inner, channel => channel.usb_id())
+    }
+
+    fn transport_name(&self) -> &'static str {
+        delegate!(&self.inner, channel => channel.transport_name())
+    }
+
+    fn device_aaguid(&self) -> Option<&DeviceAaguid> {
+        delegate!(&self.inner, channel => channel.device_aaguid())
+    }
+
+    fn supports_preflight(&self) -> bool {
+        delegate!(&self.inner, channel => channel.supports_preflight())
+    }
+
+    fn announce_operation(&mut self, hint: ClientPayloadHint) {
+        delegate_mut!(&mut self.inner, channel => channel.announce_operation(hint))
+    }
+}
+
+impl Ctap2AuthTokenStore for AnyChannel<'_> {
+    fn store_auth_data(&mut self, auth_token_data: AuthTokenData) {
+        delegate_mut!(&mut self.inner, channel => channel.store_auth_data(auth_token_data))
+    }
+
+    fn get_auth_data(&self) -> Option<&AuthTokenData> {
+        delegate!(&self.inner, channel => channel.get_auth_data())
+    }
+
+    fn clear_uv_auth_token_store(&mut self) {
+        delegate_mut!(&mut self.inner, channel => channel.clear_uv_auth_token_store())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::{broadcast, mpsc, watch};
+
+    use super::{AnyChannel, AnyDevice, AnyUxUpdate};
+    use crate::proto::ctap2::Ctap2;
+    use crate::transport::cable::channel::{
+        CableChannel, CableLinkingStatus, CableUpdate, CableUxUpdate, ConnectionState,
+    };
+    use crate::transport::local::memory::VIRTUAL_AAGUID;
+    use crate::transport::local::{CredentialStore, LocalDevice, MemoryCredentialStore};
+    use crate::transport::{Channel, Device};
+
+    #[tokio::test]
+    async fn local_device_is_reached_through_any_channel() {
+        let store: Box<dyn CredentialStore> = Box::new(MemoryCredentialStore::default());
+        let mut device = AnyDevice::Local(LocalDevice::new("local", VIRTUAL_AAGUID, store));
+        assert_eq!(format!("{device:?}"), "Local(local)");
+
+        let mut channel = device.channel().await.unwrap();
+        assert_eq!(channel.transport_name(), "local");
+        let info = channel.ctap2_get_info().await.unwrap();
+        assert_eq!(&info.aaguid[..], &VIRTUAL_AAGUID[..]);
+    }
+
+    #[tokio::test]
+    async fn forwards_ux_updates_of_wrapped_channel() {
+        let (cbor_sender, _) = mpsc::channel(1);
+        let (_, cbor_receiver) = mpsc::channel(1);
+        let (ux_update_sender, _) = broadcast::channel(16);
+        let (_, connection_state_receiver) = watch::channel(ConnectionState::Connecting);
+        let cable = CableChannel {
+            handle_connection: tokio::spawn(async {}),
+            cbor_sender,
+            cbor_receiver,
+            ux_update_sender: ux_update_sender.clone(),
+            connection_state_receiver,
+            linking_status_receiver: watch::channel(CableLinkingStatus::default()).1,
+            pending_hint: None,
+            pin_provider: None,
+            cancellation_token: None,
+            timeout_policy: Default::default(),
+            audit_trail: None,
+            metrics_recorder: None,
+            pin_uv_auth_protocols: Default::default(),
+            auth_token_data: None,
+            aaguid: Default::default(),
+        };
+
+        let channel = AnyChannel::from(cable);
+        assert!(!channel.supports_preflight());
+        let mut updates = channel.get_ux_update_receiver();
+        ux_update_sender
+            .send(CableUxUpdate::CableUpdate(CableUpdate::Connected))
+            .unwrap();
+        assert!(matches!(
+            updates.recv().await.unwrap(),
+            AnyUxUpdate::CableUpdate(CableUpdate::Connected)
+        ));
+    }
+}
@@ -1,8 +1,10 @@
+use std::future::Future;
 use std::io::Cursor as IOCursor;
 
 use btleplug::api::{Peripheral as _, WriteType};
 use btleplug::platform::Peripheral;
 use byteorder::{BigEndian, ReadBytesExt};
+use tokio::time::{self, Instant};
 use tracing::{debug, info, instrument, trace, warn};
 
 use super::device::FidoEndpoints;
@@ -92,45 +94,12 @@ impl Connection {
         Ok(())
     }
 
+    /// Receives the next response frame, skipping keepalives and pings. All of its fragments
+    /// must arrive by `deadline`, so that neither keepalives nor a device trickling fragments
+    /// extend the wait.
     #[instrument(skip_all)]
-    pub async fn frame_recv(&self) -> Result<Frame, Error> {
-        let mut parser = BleFrameParser::new();
-
-        loop {
-            let fragment = self.receive_fragment().await?;
-            debug!("Received fragment");
-            trace!(?fragment);
-
-            let status = parser.update(&fragment).or(Err(Error::InvalidFraming))?;
-            match status {
-                BleFrameParserResult::Done => {
-                    let frame = parser.frame().unwrap();
-                    trace!(?frame, "Received frame");
-                    match frame.cmd {
-                        BleCommand::Keepalive => {
-                            debug!("Received keep-alive from authenticator");
-                            parser.reset();
-                        }
-                        BleCommand::Cancel => {
-                            info!("Device canceled operation");
-                            return Err(Error::Canceled);
-                        }
-                        BleCommand::Error => {
-                            warn!("Received error frame");
-                            return Err(Error::OperationFailed);
-                        }
-                        BleCommand::Ping => {
-                            debug!("Ignoring ping from device");
-                        }
-                        BleCommand::Msg => {
-                            debug!("Received operation response");
-                            return Ok(frame);
-                        }
-                    }
-                }
-                BleFrameParserResult::MoreFragmentsExpected => {}
-            }
-        }
+    pub async fn frame_recv(&self, deadline: Instant) -> Result<Frame, Error> {
+        receive_frame(deadline, || self.receive_fragment()).await
     }
 
     async fn receive_fragment(&self) -> Result<Vec<u8>, Error> {
@@ -147,3 +116,107 @@ impl Connection {
             .or(Err(Error::OperationFailed))
     }
 }
+
+async fn receive_frame<F, Fut>(deadline: Instant, mut receive_fragment: F) -> Result<Frame, Error>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<Vec<u8>, Error>>,
+{
+    let mut parser = BleFrameParser::new();
+
+    loop {
+        let Ok(fragment) = time::timeout_at(deadline, receive_fragment()).await else {
+            warn!("Timed out waiting for BLE response");
+            return Err(Error::Timeout);
+        };
+        let fragment = fragment?;
+        debug!("Received fragment");
+        trace!(?fragment);
+
+        let status = parser.update(&fragment).or(Err(Error::InvalidFraming))?;
+        match status {
+            BleFrameParserResult::Done => {
+                let frame = parser.frame().unwrap();
+                trace!(?frame, "Received frame");
+                match frame.cmd {
+                    BleCommand::Keepalive => {
+                        debug!("Received keep-alive from authenticator");
+                        parser.reset();
+                    }
+                    BleCommand::Cancel => {
+                        info!("Device canceled operation");
+                        return Err(Error::Canceled);
+                    }
+                    BleCommand::Error => {
+                        warn!("Received error frame");
+                        return Err(Error::OperationFailed);
+                    }
+                    BleCommand::Ping => {
+                        debug!("Ignoring ping from device");
+                    }
+                    BleCommand::Msg => {
+                        debug!("Received operation response");
+                        return Ok(frame);
+                    }
+                }
+            }
+            BleFrameParserResult::MoreFragmentsExpected => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use futures::future::{BoxFuture, FutureExt};
+    use tokio::time::{self, Instant};
+
+    use super::{receive_frame, Error};
+    use crate::transport::ble::framing::{BleCommand, BleFrame};
+
+    /// Yields `fragments` one after the other, each `delay` after the previous one.
+    fn trickle(
+        fragments: Vec<Vec<u8>>,
+        delay: Duration,
+    ) -> impl FnMut() -> BoxFuture<'static, Result<Vec<u8>, Error>> {
+        let mut fragments = fragments.into_iter();
+        move || {
+            let fragment = fragments.next();
+            async move {
+                time::sleep(delay).await;
+                fragment.ok_or(Error::OperationFailed)
+            }
+            .boxed()
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn keepalives_do_not_extend_deadline() {
+        let keepalive = BleFrame::new(BleCommand::Keepalive, &[0x01]);
+        let fragments = vec![keepalive.fragments(20).unwrap().remove(0); 10];
+        let deadline = Instant::now() + Duration::from_secs(1);
+        let result = receive_frame(deadline, trickle(fragments, Duration::from_millis(300))).await;
+        assert_eq!(result.unwrap_err(), Error::Timeout);
+        assert_eq!(Instant::now(), deadline);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn trickled_fragments_share_one_deadline() {
+        let frame = BleFrame::new(BleCommand::Msg, &[0xAB; 64]);
+        let fragments = frame.fragments(20).unwrap();
+        assert_eq!(fragments.len(), 4);
+
+        let deadline = Instant::now() + Duration::from_secs(1);
+        let result = receive_frame(
+            deadline,
+            trickle(fragments.clone(), Duration::from_millis(300)),
+        )
+        .await;
+        assert_eq!(result.unwrap_err(), Error::Timeout);
+
+        let deadline = Instant::now() + Duration::from_secs(1);
+        let result = receive_frame(deadline, trickle(fragments, Duration::from_millis(200))).await;
+        assert_eq!(result.unwrap().data, frame.data);
+    }
+}
@@ -1,4 +1,5 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
 
 use btleplug::api::bleuuid::uuid_from_u16;
 use btleplug::api::{
@@ -111,6 +112,11 @@ pub async fn start_discovery_for_service_data(
     Ok(stream)
 }
 
+/// Whether the system has a Bluetooth adapter, e.g. for caBLE's proximity check.
+pub async fn adapter_available() -> bool {
+    get_adapter().await.is_ok()
+}
+
 /// TODO(#86): Support multiple adapters.
 async fn get_adapter() -> Result<Adapter, Error> {
     let manager = Manager::new().await.or(Err(Error::Unavailable))?;
@@ -166,6 +172,69 @@ pub async fn list_fido_devices() -> Result<Vec<FidoDevice>, Error> {
     Ok(with_properties)
 }
 
+/// Actively scans for devices advertising the FIDO service for the given duration.
+/// Unlike [list_fido_devices], this also finds devices that were never connected
+/// before, e.g. authenticators in pairing mode.
+#[instrument(level = Level::DEBUG, skip_all)]
+pub async fn scan_fido_devices(duration: Duration) -> Result<Vec<FidoDevice>, Error> {
+    let adapter = get_adapter().await?;
+    let mut events = adapter.events().await.or(Err(Error::Unavailable))?;
+
+    let scan_filter = ScanFilter {
+        services: vec![FIDO_PROFILE_UUID],
+    };
+    adapter
+        .start_scan(scan_filter)
+        .await
+        .or(Err(Error::ConnectionFailed))?;
+
+    let mut seen: HashSet<PeripheralId> = HashSet::new();
+    let deadline = tokio::time::sleep(duration);
+    tokio::pin!(deadline);
+    loop {
+        tokio::select! {
+            _ = &mut deadline => break,
+            event = events.next() => match event {
+                Some(CentralEvent::DeviceDiscovered(id))
+                | Some(CentralEvent::DeviceUpdated(id))
+                | Some(CentralEvent::ServiceDataAdvertisement { id, .. }) => {
+                    if seen.insert(id.clone()) {
+                        trace!(?id, "Discovered peripheral");
+                    }
+                }
+                Some(_) => {}
+                None => break,
+            },
+        }
+    }
+
+    if let Err(e) = adapter.stop_scan().await {
+        warn!(?e, "Failed to stop BLE scan");
+    }
+
+    let mut devices = vec![];
+    for id in seen {
+        let Ok(peripheral) = adapter.peripheral(&id).await else {
+            warn!(?id, "Could not get peripheral");
+            continue;
+        };
+        let Some(device) = get_device(peripheral).await? else {
+            continue;
+        };
+        // Not all platforms apply the scan filter, so check the advertisement ourselves.
+        if device.properties.services.contains(&FIDO_PROFILE_UUID)
+            || device
+                .properties
+                .service_data
+                .contains_key(&FIDO_PROFILE_UUID)
+        {
+            devices.push(device);
+        }
+    }
+    info!({ count = devices.len() }, "Found advertising FIDO devices");
+    Ok(devices)
+}
+
 pub async fn get_device(peripheral: Peripheral) -> Result<Option<FidoDevice>, Error> {
     let Some(properties) = peripheral
         .properties()
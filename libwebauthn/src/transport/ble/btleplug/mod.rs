@@ -8,5 +8,6 @@ pub use connection::Connection;
 pub use device::FidoDevice;
 pub use error::Error;
 pub use manager::{
-    connect, list_fido_devices, start_discovery_for_service_data, supported_fido_revisions,
+    adapter_available, connect, list_fido_devices, scan_fido_devices,
+    start_discovery_for_service_data, supported_fido_revisions,
 };
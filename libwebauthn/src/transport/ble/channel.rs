@@ -1,16 +1,24 @@
 use std::convert::TryInto;
 use std::fmt::{Display, Formatter};
+use std::sync::Arc;
 use std::time::Duration;
 
+use crate::audit::AuditTrail;
 use crate::fido::{FidoProtocol, FidoRevision};
+use crate::metrics::MetricsRecorder;
+use crate::pin::{PinProvider, PinUvAuthProtocols};
 use crate::proto::ctap1::apdu::{ApduRequest, ApduResponse};
 use crate::proto::ctap2::cbor::{CborRequest, CborResponse};
 use crate::proto::CtapError;
+use crate::timeout::TimeoutPolicy;
 use crate::transport::ble::btleplug;
-use crate::transport::channel::{AuthTokenData, Channel, ChannelStatus, Ctap2AuthTokenStore};
+use crate::transport::channel::{
+    ensure_not_cancelled, until_cancelled, AuthTokenData, Channel, ChannelStatus,
+    Ctap2AuthTokenStore, DeviceAaguid,
+};
 use crate::transport::device::SupportedProtocols;
 use crate::transport::error::TransportError;
-use crate::webauthn::error::Error;
+use crate::webauthn::error::{Error, PlatformError};
 use crate::UvUpdate;
 
 use super::btleplug::manager::SupportedRevisions;
@@ -20,7 +28,9 @@ use super::BleDevice;
 
 use async_trait::async_trait;
 use tokio::sync::broadcast;
-use tracing::{debug, instrument, trace, Level};
+use tokio::time::Instant;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, instrument, trace, warn, Level};
 
 #[derive(Debug)]
 pub struct BleChannel<'a> {
@@ -29,6 +39,13 @@ pub struct BleChannel<'a> {
     connection: Connection,
     revision: FidoRevision,
     auth_token_data: Option<AuthTokenData>,
+    aaguid: DeviceAaguid,
+    pin_provider: Option<Arc<dyn PinProvider>>,
+    cancellation_token: Option<CancellationToken>,
+    timeout_policy: TimeoutPolicy,
+    audit_trail: Option<AuditTrail>,
+    metrics_recorder: Option<Arc<dyn MetricsRecorder>>,
+    pin_uv_auth_protocols: PinUvAuthProtocols,
     ux_update_sender: broadcast::Sender<UvUpdate>,
 }
 
@@ -39,8 +56,10 @@ impl<'a> BleChannel<'a> {
     ) -> Result<BleChannel<'a>, Error> {
         let (ux_update_sender, _) = broadcast::channel(16);
 
+        // Prefer CTAP2 when the authenticator supports it, as with HID.
         let revision = revisions
-            .select_protocol(FidoProtocol::U2F)
+            .select_protocol(FidoProtocol::FIDO2)
+            .or_else(|| revisions.select_protocol(FidoProtocol::U2F))
             .ok_or(Error::Transport(TransportError::NegotiationFailed))?;
         let connection = btleplug::connect(&device.btleplug_device.peripheral, &revision)
             .await
@@ -51,6 +70,13 @@ impl<'a> BleChannel<'a> {
             connection,
             revision,
             auth_token_data: None,
+            aaguid: DeviceAaguid::default(),
+            pin_provider: None,
+            cancellation_token: None,
+            timeout_policy: TimeoutPolicy::default(),
+            audit_trail: None,
+            metrics_recorder: None,
+            pin_uv_auth_protocols: PinUvAuthProtocols::default(),
             ux_update_sender,
         };
         channel
@@ -62,6 +88,49 @@ impl<'a> BleChannel<'a> {
     }
 }
 
+impl BleChannel<'_> {
+    /// BLE links aren't re-established mid-operation, so a dropped link fails with
+    /// `DeviceRemoved` right away, rather than waiting for the device to come back.
+    async fn check_removed<T, E>(&self, result: Result<T, E>) -> Result<T, Error> {
+        let Err(_) = result else {
+            return result.or(Err(Error::Transport(TransportError::ConnectionFailed)));
+        };
+        if self.device.is_connected().await {
+            return Err(Error::Transport(TransportError::ConnectionFailed));
+        }
+        warn!("Device disconnected mid-operation");
+        if self.ux_update_sender.send(UvUpdate::DeviceRemoved).is_err() {
+            debug!("No receivers for UX update");
+        }
+        Err(Error::Transport(TransportError::DeviceRemoved))
+    }
+
+    /// Receives the next response frame. The whole message, keepalives included, must arrive
+    /// within `timeout`. If the operation is cancelled, the pending request is aborted with a
+    /// CANCEL frame.
+    async fn frame_recv(&self, timeout: Duration) -> Result<BleFrame, Error> {
+        let deadline = Instant::now() + timeout;
+        let recv = async {
+            match self.connection.frame_recv(deadline).await {
+                Err(btleplug::Error::Timeout) => {
+                    warn!(?timeout, "Timed out waiting for BLE response");
+                    Err(Error::Transport(TransportError::Timeout))
+                }
+                result => self.check_removed(result).await,
+            }
+        };
+        let token = self.cancellation_token.clone();
+        let result = until_cancelled(token, &self.ux_update_sender, recv).await;
+        if matches!(result, Err(Error::Platform(PlatformError::Cancelled))) {
+            let cancel = BleFrame::new(BleCommand::Cancel, &[]);
+            if let Err(err) = self.connection.frame_send(&cancel).await {
+                warn!(?err, "Failed to send CANCEL frame");
+            }
+        }
+        result
+    }
+}
+
 impl Display for BleChannel<'_> {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         self.device.fmt(f)
@@ -72,6 +141,14 @@ impl Display for BleChannel<'_> {
 impl<'a> Channel for BleChannel<'a> {
     type UxUpdate = UvUpdate;
 
+    fn transport_name(&self) -> &'static str {
+        "ble"
+    }
+
+    fn device_aaguid(&self) -> Option<&DeviceAaguid> {
+        Some(&self.aaguid)
+    }
+
     async fn supported_protocols(&self) -> Result<SupportedProtocols, Error> {
         Ok(self.revision.into())
     }
@@ -87,25 +164,20 @@ impl<'a> Channel for BleChannel<'a> {
 
     #[instrument(level = Level::DEBUG, skip_all)]
     async fn apdu_send(&self, request: &ApduRequest, _timeout: Duration) -> Result<(), Error> {
+        ensure_not_cancelled(self.cancellation_token.as_ref(), &self.ux_update_sender)?;
         debug!({rev = ?self.revision}, "Sending APDU request");
         trace!(?request);
 
         let request_apdu_packet = request.raw_long().or(Err(TransportError::InvalidFraming))?;
         let request_frame = BleFrame::new(BleCommand::Msg, &request_apdu_packet);
-        self.connection
-            .frame_send(&request_frame)
-            .await
-            .or(Err(Error::Transport(TransportError::ConnectionFailed)))?;
+        let result = self.connection.frame_send(&request_frame).await;
+        self.check_removed(result).await?;
         Ok(())
     }
 
     #[instrument(level = Level::DEBUG, skip_all)]
-    async fn apdu_recv(&self, _timeout: Duration) -> Result<ApduResponse, Error> {
-        let response_frame = self
-            .connection
-            .frame_recv()
-            .await
-            .or(Err(Error::Transport(TransportError::ConnectionFailed)))?;
+    async fn apdu_recv(&self, timeout: Duration) -> Result<ApduResponse, Error> {
+        let response_frame = self.frame_recv(timeout).await?;
         match response_frame.cmd {
             BleCommand::Error => return Err(Error::Transport(TransportError::InvalidFraming)), // Encapsulation layer error
             BleCommand::Cancel => return Err(Error::Ctap(CtapError::KeepAliveCancel)),
@@ -128,6 +200,7 @@ impl<'a> Channel for BleChannel<'a> {
         request: &CborRequest,
         _timeout: std::time::Duration,
     ) -> Result<(), Error> {
+        ensure_not_cancelled(self.cancellation_token.as_ref(), &self.ux_update_sender)?;
         debug!("Sending CBOR request");
         trace!(?request);
 
@@ -135,20 +208,14 @@ impl<'a> Channel for BleChannel<'a> {
             .raw_long()
             .map_err(|e| TransportError::IoError(e.kind()))?;
         let request_frame = BleFrame::new(BleCommand::Msg, &cbor_request);
-        self.connection
-            .frame_send(&request_frame)
-            .await
-            .or(Err(Error::Transport(TransportError::ConnectionFailed)))?;
+        let result = self.connection.frame_send(&request_frame).await;
+        self.check_removed(result).await?;
         Ok(())
     }
 
     #[instrument(level = Level::DEBUG, skip_all)]
-    async fn cbor_recv(&mut self, _timeout: std::time::Duration) -> Result<CborResponse, Error> {
-        let response_frame = self
-            .connection
-            .frame_recv()
-            .await
-            .or(Err(Error::Transport(TransportError::ConnectionFailed)))?;
+    async fn cbor_recv(&mut self, timeout: std::time::Duration) -> Result<CborResponse, Error> {
+        let response_frame = self.frame_recv(timeout).await?;
         match response_frame.cmd {
             BleCommand::Error => return Err(Error::Transport(TransportError::InvalidFraming)), // Encapsulation layer error
             BleCommand::Cancel => return Err(Error::Ctap(CtapError::KeepAliveCancel)),
@@ -168,6 +235,54 @@ impl<'a> Channel for BleChannel<'a> {
     fn get_ux_update_sender(&self) -> &broadcast::Sender<Self::UxUpdate> {
         &self.ux_update_sender
     }
+
+    fn get_pin_provider(&self) -> Option<Arc<dyn PinProvider>> {
+        self.pin_provider.clone()
+    }
+
+    fn set_pin_provider(&mut self, provider: Option<Arc<dyn PinProvider>>) {
+        self.pin_provider = provider;
+    }
+
+    fn get_cancellation_token(&self) -> Option<CancellationToken> {
+        self.cancellation_token.clone()
+    }
+
+    fn set_cancellation_token(&mut self, token: Option<CancellationToken>) {
+        self.cancellation_token = token;
+    }
+
+    fn get_timeout_policy(&self) -> TimeoutPolicy {
+        self.timeout_policy
+    }
+
+    fn set_timeout_policy(&mut self, policy: TimeoutPolicy) {
+        self.timeout_policy = policy;
+    }
+
+    fn get_audit_trail(&self) -> Option<AuditTrail> {
+        self.audit_trail.clone()
+    }
+
+    fn set_audit_trail(&mut self, trail: Option<AuditTrail>) {
+        self.audit_trail = trail;
+    }
+
+    fn get_metrics_recorder(&self) -> Option<Arc<dyn MetricsRecorder>> {
+        self.metrics_recorder.clone()
+    }
+
+    fn set_metrics_recorder(&mut self, recorder: Option<Arc<dyn MetricsRecorder>>) {
+        self.metrics_recorder = recorder;
+    }
+
+    fn get_pin_uv_auth_protocols(&self) -> PinUvAuthProtocols {
+        self.pin_uv_auth_protocols.clone()
+    }
+
+    fn set_pin_uv_auth_protocols(&mut self, protocols: PinUvAuthProtocols) {
+        self.pin_uv_auth_protocols = protocols;
+    }
 }
 
 impl Ctap2AuthTokenStore for BleChannel<'_> {
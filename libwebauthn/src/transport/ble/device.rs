@@ -1,4 +1,5 @@
 use std::fmt;
+use std::time::Duration;
 
 use ::btleplug::api::Peripheral;
 use async_trait::async_trait;
@@ -9,7 +10,7 @@ use crate::transport::device::Device;
 use crate::transport::error::TransportError;
 use crate::webauthn::error::Error;
 
-use super::btleplug::manager::SupportedRevisions;
+use super::btleplug::manager::{SupportedRevisions, FIDO_PROFILE_UUID};
 use super::btleplug::{supported_fido_revisions, FidoDevice as BtleplugFidoDevice};
 
 use super::channel::BleChannel;
@@ -27,10 +28,48 @@ pub async fn list_devices() -> Result<Vec<BleDevice>, Error> {
     Ok(devices)
 }
 
+/// Scans for authenticators advertising the FIDO service, including ones which
+/// aren't paired yet.
+#[instrument]
+pub async fn scan_devices(duration: Duration) -> Result<Vec<BleDevice>, Error> {
+    let devices: Vec<_> = btleplug::scan_fido_devices(duration)
+        .await
+        .or(Err(Error::Transport(TransportError::TransportUnavailable)))?
+        .iter()
+        .map(|bluez_device| bluez_device.into())
+        .collect();
+    info!({ count = devices.len() }, "Scanned for BLE devices");
+    Ok(devices)
+}
+
+/// Flags advertised by the authenticator in the FIDO service data.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct FidoServiceData {
+    /// The device is in pairing mode, and can be bonded with.
+    pub pairing_mode: bool,
+    /// The device requires the passkey entry pairing method.
+    pub passkey_entry: bool,
+}
+
+impl FidoServiceData {
+    const PAIRING_MODE: u8 = 0x80;
+    const PASSKEY_ENTRY: u8 = 0x40;
+
+    pub fn parse(service_data: &[u8]) -> Option<Self> {
+        let flags = service_data.first()?;
+        Some(Self {
+            pairing_mode: flags & Self::PAIRING_MODE != 0,
+            passkey_entry: flags & Self::PASSKEY_ENTRY != 0,
+        })
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct BleDevice {
     pub btleplug_device: BtleplugFidoDevice,
     pub revisions: Option<SupportedRevisions>,
+    /// Flags from the last advertisement seen, if the device sent any service data.
+    pub service_data: Option<FidoServiceData>,
 }
 
 impl BleDevice {
@@ -55,6 +94,11 @@ impl From<&BtleplugFidoDevice> for BleDevice {
         Self {
             btleplug_device: btleplug_device.clone(),
             revisions: None,
+            service_data: btleplug_device
+                .properties
+                .service_data
+                .get(&FIDO_PROFILE_UUID)
+                .and_then(|data| FidoServiceData::parse(data)),
         }
     }
 }
@@ -101,3 +145,27 @@ impl BleDevice {
         Ok(revisions)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::FidoServiceData;
+
+    #[test]
+    fn parse_service_data() {
+        assert_eq!(FidoServiceData::parse(&[]), None);
+        assert_eq!(
+            FidoServiceData::parse(&[0xC0]),
+            Some(FidoServiceData {
+                pairing_mode: true,
+                passkey_entry: true,
+            })
+        );
+        assert_eq!(
+            FidoServiceData::parse(&[0x80, 0x00]),
+            Some(FidoServiceData {
+                pairing_mode: true,
+                passkey_entry: false,
+            })
+        );
+    }
+}
@@ -9,6 +9,9 @@ const INITIAL_FRAGMENT_MIN_LENGTH: usize = INITIAL_FRAGMENT_HEADER_LENGTH;
 const CONT_FRAGMENT_HEADER_LENGTH: usize = 1;
 const CONT_FRAGMENT_MIN_LENGTH: usize = CONT_FRAGMENT_HEADER_LENGTH; // 1B header, 1B data
 
+// Continuation fragment sequence numbers wrap around to 0 after 0x7F.
+const MAX_SEQ: u8 = 0x7F;
+
 // https://fidoalliance.org/specs/fido-v2.0-ps-20190130/fido-client-to-authenticator-protocol-v2.0-ps-20190130.html#ble-constants
 #[derive(Debug, IntoPrimitive, TryFromPrimitive, Copy, Clone, PartialEq)]
 #[repr(u8)]
@@ -71,7 +74,7 @@ impl BleFrame {
                 .collect();
             fragment.append(&mut chunk);
             fragments.push(fragment);
-            seq += 1;
+            seq = if seq == MAX_SEQ { 0 } else { seq + 1 };
         }
 
         Ok(fragments)
@@ -104,6 +107,19 @@ impl BleFrameParser {
             ));
         }
 
+        if !self.fragments.is_empty() {
+            let expected_seq = ((self.fragments.len() - 1) % (MAX_SEQ as usize + 1)) as u8;
+            if fragment[0] != expected_seq {
+                return Err(IOError::new(
+                    IOErrorKind::InvalidData,
+                    format!(
+                        "Unexpected continuation fragment sequence number: {:x}, expected {:x}",
+                        fragment[0], expected_seq
+                    ),
+                ));
+            }
+        }
+
         self.fragments.push(Vec::from(fragment));
         return if self.more_fragments_needed() {
             Ok(BleFrameParserResult::MoreFragmentsExpected)
@@ -197,6 +213,29 @@ mod tests {
         assert_eq!(frame.fragments(4).unwrap(), expected)
     }
 
+    #[test]
+    fn encode_wraps_sequence_number() {
+        let frame = BleFrame::new(BleCommand::Msg, &[0xAA; 1 + 3 * 0x81]);
+        let fragments = frame.fragments(4).unwrap();
+        // One byte in the initial fragment, then 0x81 continuation fragments with three each.
+        assert_eq!(fragments.len(), 1 + 0x81);
+        assert_eq!(fragments[0x80][0], 0x7F);
+        assert_eq!(fragments[0x81][0], 0x00);
+
+        let mut parser = BleFrameParser::new();
+        for fragment in fragments.iter() {
+            parser.update(fragment).unwrap();
+        }
+        assert_eq!(parser.frame().unwrap().data, vec![0xAA; 1 + 3 * 0x81]);
+    }
+
+    #[test]
+    fn parse_out_of_order_fragment() {
+        let mut parser = BleFrameParser::new();
+        parser.update(&[0x83, 0x00, 0x05, 0x0A]).unwrap();
+        assert!(parser.update(&[0x01, 0x0B, 0x0C, 0x0D]).is_err());
+    }
+
     #[test]
     fn parse_single_fragment() {
         let mut parser = BleFrameParser::new();
@@ -4,9 +4,12 @@ pub mod btleplug;
 pub mod channel;
 pub mod device;
 pub mod framing;
+pub mod pairing;
 
 pub use device::list_devices;
-pub use device::BleDevice;
+pub use device::scan_devices;
+pub use device::{BleDevice, FidoServiceData};
+pub use pairing::{BlePairingUpdate, PasskeyRequiredUpdate};
 
 use super::Transport;
 
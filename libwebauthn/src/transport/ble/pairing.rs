@@ -0,0 +1,283 @@
+//! Bonding with BLE authenticators through BlueZ, which is required before the FIDO
+//! GATT service can be used.
+
+use std::ffi::CString;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use ::btleplug::api::Peripheral as _;
+use dbus::blocking::stdintf::org_freedesktop_dbus::Properties;
+use dbus::blocking::Connection;
+use dbus::channel::{MatchingReceiver, Sender};
+use dbus::message::{MatchRule, MessageType};
+use dbus::strings::ErrorName;
+use dbus::{Message, Path};
+use tokio::sync::{broadcast, oneshot};
+use tracing::{debug, info, instrument, warn};
+
+use crate::transport::error::TransportError;
+use crate::webauthn::error::{Error, PlatformError};
+
+use super::BleDevice;
+
+const BLUEZ_SERVICE: &str = "org.bluez";
+const BLUEZ_PATH: &str = "/org/bluez";
+const AGENT_MANAGER_INTERFACE: &str = "org.bluez.AgentManager1";
+const AGENT_INTERFACE: &str = "org.bluez.Agent1";
+const DEVICE_INTERFACE: &str = "org.bluez.Device1";
+const AGENT_PATH: &str = "/org/libwebauthn/pairing_agent";
+const AGENT_CAPABILITY: &str = "KeyboardDisplay";
+const DBUS_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone)]
+pub enum BlePairingUpdate {
+    /// The authenticator displays a passkey, which the user needs to type in.
+    PasskeyRequired(PasskeyRequiredUpdate),
+    /// The passkey to be typed into, or confirmed on, the authenticator.
+    DisplayPasskey { passkey: u32 },
+    /// The device is now bonded.
+    Paired,
+}
+
+#[derive(Debug, Clone)]
+pub struct PasskeyRequiredUpdate {
+    reply_to: Arc<oneshot::Sender<u32>>,
+}
+
+impl PasskeyRequiredUpdate {
+    /// This consumes `self`, because we should only ever send exactly one answer back.
+    pub fn send_passkey(self, passkey: u32) -> Result<(), String> {
+        match Arc::into_inner(self.reply_to) {
+            Some(sender) => sender
+                .send(passkey)
+                .map_err(|_| "Failed to send passkey".to_string()),
+            None => Err("Multiple references to reply_to exist; cannot send passkey".to_string()),
+        }
+    }
+
+    /// The user cancels the passkey entry, which aborts pairing.
+    pub fn cancel(self) {
+        // We hang up to signal an abort
+        drop(self.reply_to)
+    }
+}
+
+impl BleDevice {
+    fn object_path(&self) -> Path<'static> {
+        Path::from(format!(
+            "{}/{}",
+            BLUEZ_PATH,
+            self.btleplug_device.peripheral.id()
+        ))
+    }
+
+    /// Whether BlueZ has bonded with this device.
+    pub async fn is_paired(&self) -> Result<bool, Error> {
+        let path = self.object_path();
+        tokio::task::spawn_blocking(move || {
+            let connection = system_bus()?;
+            let proxy = connection.with_proxy(BLUEZ_SERVICE, path, DBUS_TIMEOUT);
+            proxy.get::<bool>(DEVICE_INTERFACE, "Paired").map_err(|e| {
+                warn!(?e, "Failed to read pairing state");
+                Error::Transport(TransportError::UnknownDevice)
+            })
+        })
+        .await
+        .or(Err(Error::Platform(PlatformError::InvalidDeviceResponse)))?
+    }
+
+    /// Bonds with the device, registering a temporary BlueZ agent to handle passkey
+    /// entry. Passkey prompts are sent as [BlePairingUpdate]s, and need to be answered
+    /// before `timeout` expires. Does nothing if the device is already paired.
+    #[instrument(skip_all, fields(device = %self))]
+    pub async fn pair(
+        &self,
+        ux_update_sender: &broadcast::Sender<BlePairingUpdate>,
+        timeout: Duration,
+    ) -> Result<(), Error> {
+        if self.is_paired().await? {
+            debug!("Device is already paired");
+            return Ok(());
+        }
+
+        let path = self.object_path();
+        let sender = ux_update_sender.clone();
+        tokio::task::spawn_blocking(move || pair_blocking(path, sender, timeout))
+            .await
+            .or(Err(Error::Platform(PlatformError::InvalidDeviceResponse)))??;
+
+        info!("Device paired");
+        if ux_update_sender.send(BlePairingUpdate::Paired).is_err() {
+            debug!("No receivers for UX update");
+        }
+        Ok(())
+    }
+}
+
+fn system_bus() -> Result<Connection, Error> {
+    Connection::new_system().map_err(|e| {
+        warn!(?e, "Failed to connect to the system bus");
+        Error::Transport(TransportError::TransportUnavailable)
+    })
+}
+
+fn pair_blocking(
+    path: Path<'static>,
+    ux_update_sender: broadcast::Sender<BlePairingUpdate>,
+    timeout: Duration,
+) -> Result<(), Error> {
+    let connection = system_bus()?;
+    let agent_manager = connection.with_proxy(BLUEZ_SERVICE, BLUEZ_PATH, DBUS_TIMEOUT);
+    agent_manager
+        .method_call::<(), _, _, _>(
+            AGENT_MANAGER_INTERFACE,
+            "RegisterAgent",
+            (Path::from(AGENT_PATH), AGENT_CAPABILITY),
+        )
+        .map_err(|e| {
+            warn!(?e, "Failed to register pairing agent");
+            Error::Transport(TransportError::TransportUnavailable)
+        })?;
+
+    let cancelled = Arc::new(AtomicBool::new(false));
+    connection.start_receive(
+        MatchRule::new_method_call()
+            .with_path(AGENT_PATH)
+            .with_interface(AGENT_INTERFACE),
+        Box::new({
+            let cancelled = cancelled.clone();
+            move |call, connection| {
+                let reply = on_agent_call(&call, &ux_update_sender, &cancelled);
+                if connection.send(reply).is_err() {
+                    warn!("Failed to reply to pairing agent call");
+                }
+                true
+            }
+        }),
+    );
+
+    let result = run_pairing(&connection, &path, timeout);
+
+    if let Err(e) = agent_manager.method_call::<(), _, _, _>(
+        AGENT_MANAGER_INTERFACE,
+        "UnregisterAgent",
+        (Path::from(AGENT_PATH),),
+    ) {
+        warn!(?e, "Failed to unregister pairing agent");
+    }
+
+    match result {
+        Err(_) if cancelled.load(Ordering::Acquire) => {
+            Err(Error::Platform(PlatformError::Cancelled))
+        }
+        result => result,
+    }
+}
+
+fn run_pairing(
+    connection: &Connection,
+    path: &Path<'static>,
+    timeout: Duration,
+) -> Result<(), Error> {
+    let pair = Message::new_method_call(BLUEZ_SERVICE, path, DEVICE_INTERFACE, "Pair").unwrap();
+    let Ok(serial) = connection.send(pair) else {
+        return Err(Error::Transport(TransportError::ConnectionFailed));
+    };
+
+    let reply: Arc<Mutex<Option<Result<(), Error>>>> = Arc::new(Mutex::new(None));
+    connection.start_receive(
+        MatchRule::new(),
+        Box::new({
+            let reply = reply.clone();
+            move |message, _| {
+                if message.get_reply_serial() != Some(serial) {
+                    return true;
+                }
+                let result = match message.msg_type() {
+                    MessageType::MethodReturn => Ok(()),
+                    _ => {
+                        warn!(error = ?message.read1::<&str>().ok(), "Pairing failed");
+                        Err(Error::Transport(TransportError::ConnectionFailed))
+                    }
+                };
+                *reply.lock().unwrap() = Some(result);
+                false
+            }
+        }),
+    );
+
+    let deadline = Instant::now() + timeout;
+    loop {
+        if let Some(result) = reply.lock().unwrap().take() {
+            return result;
+        }
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            break;
+        }
+        if let Err(e) = connection.process(remaining) {
+            warn!(?e, "Failed to process D-Bus messages");
+            return Err(Error::Transport(TransportError::ConnectionFailed));
+        }
+    }
+
+    warn!("Pairing timed out, cancelling");
+    let proxy = connection.with_proxy(BLUEZ_SERVICE, path, DBUS_TIMEOUT);
+    if let Err(e) = proxy.method_call::<(), _, _, _>(DEVICE_INTERFACE, "CancelPairing", ()) {
+        warn!(?e, "Failed to cancel pairing");
+    }
+    Err(Error::Transport(TransportError::Timeout))
+}
+
+fn on_agent_call(
+    call: &Message,
+    ux_update_sender: &broadcast::Sender<BlePairingUpdate>,
+    cancelled: &AtomicBool,
+) -> Message {
+    let member = call.member().map(|m| m.to_string()).unwrap_or_default();
+    debug!(%member, "Received pairing agent call");
+    match member.as_str() {
+        "RequestPasskey" => {
+            let (tx, rx) = oneshot::channel();
+            let update = PasskeyRequiredUpdate {
+                reply_to: Arc::new(tx),
+            };
+            if ux_update_sender
+                .send(BlePairingUpdate::PasskeyRequired(update))
+                .is_err()
+            {
+                warn!("No receiver for passkey request, rejecting");
+                return agent_error(call, "org.bluez.Error.Rejected");
+            }
+            match rx.blocking_recv() {
+                Ok(passkey) => call.method_return().append1(passkey),
+                Err(_) => {
+                    info!("User cancelled passkey entry");
+                    cancelled.store(true, Ordering::Release);
+                    agent_error(call, "org.bluez.Error.Canceled")
+                }
+            }
+        }
+        // Numeric comparison: the user confirms the passkey on the authenticator.
+        "DisplayPasskey" | "RequestConfirmation" => match call.read2::<Path, u32>() {
+            Ok((_, passkey)) => {
+                if ux_update_sender
+                    .send(BlePairingUpdate::DisplayPasskey { passkey })
+                    .is_err()
+                {
+                    debug!("No receivers for UX update");
+                }
+                call.method_return()
+            }
+            Err(_) => agent_error(call, "org.bluez.Error.Rejected"),
+        },
+        // We initiated pairing, so there's nothing left to authorize.
+        "RequestAuthorization" | "Release" | "Cancel" => call.method_return(),
+        _ => agent_error(call, "org.bluez.Error.Rejected"),
+    }
+}
+
+fn agent_error(call: &Message, name: &'static str) -> Message {
+    call.error(&ErrorName::from(name), &CString::new(name).unwrap())
+}
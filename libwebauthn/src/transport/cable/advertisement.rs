@@ -1,10 +1,12 @@
 use ::btleplug::api::Central;
+use async_trait::async_trait;
 use futures::StreamExt;
+use std::fmt::Debug;
 use std::pin::pin;
 use tracing::{debug, instrument, trace, warn};
 use uuid::Uuid;
 
-use crate::transport::ble::btleplug::{self, FidoDevice};
+use crate::transport::ble::btleplug;
 use crate::transport::cable::crypto::trial_decrypt_advert;
 use crate::transport::error::TransportError;
 
@@ -37,55 +39,175 @@ impl From<[u8; 16]> for DecryptedAdvert {
     }
 }
 
+/// A BLE stack able to scan for the service data of caBLE adverts.
+///
+/// [BtleplugScanner] is used by default, and covers BlueZ, CoreBluetooth and WinRT. Other stacks,
+/// or [MockAdvertScanner] in tests, can be set on a device with `with_advert_scanner`.
+#[async_trait]
+pub trait AdvertScanner: Debug + Send + Sync {
+    /// Scans for service data advertised under any of `uuids`, until `accept` returns true for
+    /// one of them. Scanning is stopped before returning it.
+    async fn find_service_data(
+        &self,
+        uuids: &[Uuid],
+        accept: &(dyn for<'a> Fn(&'a [u8]) -> bool + Send + Sync),
+    ) -> Result<Vec<u8>, TransportError>;
+}
+
+/// Scans through [btleplug], using the first adapter.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BtleplugScanner;
+
+#[async_trait]
+impl AdvertScanner for BtleplugScanner {
+    #[instrument(skip_all, err)]
+    async fn find_service_data(
+        &self,
+        uuids: &[Uuid],
+        accept: &(dyn for<'a> Fn(&'a [u8]) -> bool + Send + Sync),
+    ) -> Result<Vec<u8>, TransportError> {
+        let stream = btleplug::manager::start_discovery_for_service_data(uuids)
+            .await
+            .or(Err(TransportError::TransportUnavailable))?;
+
+        let mut stream = pin!(stream);
+        while let Some((adapter, peripheral, data)) = stream.as_mut().next().await {
+            debug!({ ?peripheral, ?data }, "Found device with service data");
+
+            let Some(device) = btleplug::manager::get_device(peripheral.clone())
+                .await
+                .or(Err(TransportError::TransportUnavailable))?
+            else {
+                warn!(
+                    ?peripheral,
+                    "Unable to fetch peripheral properties, ignoring"
+                );
+                continue;
+            };
+
+            if !accept(&data) {
+                warn!(?device, "Advert not accepted, ignoring");
+                continue;
+            }
+            debug!(?device, "Accepted advert from device");
+
+            adapter
+                .stop_scan()
+                .await
+                .or(Err(TransportError::TransportUnavailable))?;
+
+            return Ok(data);
+        }
+
+        warn!("BLE advertisement discovery stream terminated");
+        Err(TransportError::TransportUnavailable)
+    }
+}
+
+/// Replays a fixed list of adverts, as if they had been received in order.
+#[derive(Debug, Clone, Default)]
+pub struct MockAdvertScanner {
+    adverts: Vec<Vec<u8>>,
+}
+
+impl MockAdvertScanner {
+    pub fn new(adverts: Vec<Vec<u8>>) -> Self {
+        Self { adverts }
+    }
+}
+
+#[async_trait]
+impl AdvertScanner for MockAdvertScanner {
+    async fn find_service_data(
+        &self,
+        _uuids: &[Uuid],
+        accept: &(dyn for<'a> Fn(&'a [u8]) -> bool + Send + Sync),
+    ) -> Result<Vec<u8>, TransportError> {
+        self.adverts
+            .iter()
+            .find(|advert| accept(advert))
+            .cloned()
+            .ok_or(TransportError::TransportUnavailable)
+    }
+}
+
 #[instrument(skip_all, err)]
 pub(crate) async fn await_advertisement(
     eid_key: &[u8],
-) -> Result<(FidoDevice, DecryptedAdvert), TransportError> {
+    scanner: &dyn AdvertScanner,
+) -> Result<DecryptedAdvert, TransportError> {
     let uuids = &[
         Uuid::parse_str(CABLE_UUID_FIDO).unwrap(),
         Uuid::parse_str(CABLE_UUID_GOOGLE).unwrap(), // Deprecated, but may still be in use.
     ];
-    let stream = btleplug::manager::start_discovery_for_service_data(uuids)
-        .await
-        .or(Err(TransportError::TransportUnavailable))?;
+    let accept = |data: &[u8]| {
+        trace!(?data, ?eid_key);
+        trial_decrypt_advert(eid_key, data).is_some()
+    };
+    let data = scanner.find_service_data(uuids, &accept).await?;
 
-    let mut stream = pin!(stream);
-    while let Some((adapter, peripheral, data)) = stream.as_mut().next().await {
-        debug!({ ?peripheral, ?data }, "Found device with service data");
+    // Scanners are meant to return accepted adverts only, but may be implemented elsewhere.
+    let Some(decrypted) = trial_decrypt_advert(eid_key, &data) else {
+        warn!("Scanner returned an advert which doesn't decrypt, ignoring it");
+        return Err(TransportError::InvalidFraming);
+    };
+    debug!(?decrypted, "Successfully decrypted advertisement");
+    Ok(DecryptedAdvert::from(decrypted))
+}
 
-        let Some(device) = btleplug::manager::get_device(peripheral.clone())
-            .await
-            .or(Err(TransportError::TransportUnavailable))?
-        else {
-            warn!(
-                ?peripheral,
-                "Unable to fetch peripheral properties, ignoring"
-            );
-            continue;
-        };
-
-        trace!(?device, ?data, ?eid_key);
-        let Some(decrypted) = trial_decrypt_advert(&eid_key, &data) else {
-            warn!(?device, "Trial decrypt failed, ignoring");
-            continue;
-        };
-        trace!(?decrypted);
-
-        let advert = DecryptedAdvert::from(decrypted);
-        debug!(
-            ?device,
-            ?decrypted,
-            "Successfully decrypted advertisement from device"
-        );
+#[cfg(test)]
+mod tests {
+    use async_trait::async_trait;
+    use uuid::Uuid;
 
-        adapter
-            .stop_scan()
-            .await
-            .or(Err(TransportError::TransportUnavailable))?;
+    use super::{await_advertisement, AdvertScanner, MockAdvertScanner};
+    use crate::transport::cable::crypto::{derive, encrypt_advert, KeyPurpose};
+    use crate::transport::error::TransportError;
+
+    /// Returns its advert without asking whether it's accepted.
+    #[derive(Debug)]
+    struct CarelessScanner(Vec<u8>);
 
-        return Ok((device, advert));
+    #[async_trait]
+    impl AdvertScanner for CarelessScanner {
+        async fn find_service_data(
+            &self,
+            _uuids: &[Uuid],
+            _accept: &(dyn for<'a> Fn(&'a [u8]) -> bool + Send + Sync),
+        ) -> Result<Vec<u8>, TransportError> {
+            Ok(self.0.clone())
+        }
     }
 
-    warn!("BLE advertisement discovery stream terminated");
-    Err(TransportError::TransportUnavailable)
+    #[tokio::test]
+    async fn advert_is_found_by_mock_scanner() {
+        let eid_key = derive(&[1u8; 16], None, KeyPurpose::EIDKey);
+        let other_key = derive(&[2u8; 16], None, KeyPurpose::EIDKey);
+        let mut plaintext = [0u8; 16];
+        plaintext[11..14].copy_from_slice(&[1, 2, 3]);
+        let scanner = MockAdvertScanner::new(vec![
+            vec![0u8; 4],
+            encrypt_advert(&other_key, &[0u8; 16]).to_vec(),
+            encrypt_advert(&eid_key, &plaintext).to_vec(),
+        ]);
+
+        let advert = await_advertisement(&eid_key, &scanner).await.unwrap();
+        assert_eq!(advert.routing_id, [1, 2, 3]);
+        assert!(
+            await_advertisement(&other_key, &MockAdvertScanner::default())
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn undecryptable_advert_is_rejected() {
+        let eid_key = derive(&[1u8; 16], None, KeyPurpose::EIDKey);
+        let other_key = derive(&[2u8; 16], None, KeyPurpose::EIDKey);
+        let scanner = CarelessScanner(encrypt_advert(&other_key, &[0u8; 16]).to_vec());
+        assert_eq!(
+            await_advertisement(&eid_key, &scanner).await.unwrap_err(),
+            TransportError::InvalidFraming
+        );
+    }
 }
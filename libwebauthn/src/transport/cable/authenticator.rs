@@ -0,0 +1,205 @@
+//! The authenticator side of hybrid transport, letting this device act as the phone.
+//!
+//! After scanning a QR code shown by a platform (e.g. a browser), the authenticator opens a
+//! new tunnel, announces its routing ID through an encrypted BLE advert, and completes the
+//! Noise handshake as responder. CTAP requests received over the tunnel are then answered by
+//! a local [`Channel`], e.g. one to the software authenticator.
+
+use async_trait::async_trait;
+use tracing::{debug, error, info, instrument};
+
+use super::connection_stages::derive_psk;
+use super::crypto::{derive, encrypt_advert, KeyPurpose};
+use super::qr_code_device::CableQrCode;
+use super::tunnel::{self, TunnelConnector, TunnelHandshake, KNOWN_TUNNEL_DOMAINS};
+use crate::sources;
+use crate::transport::error::TransportError;
+use crate::transport::Channel;
+use crate::webauthn::error::Error;
+
+/// Emits the BLE advert proving proximity to the platform.
+///
+/// Advertising isn't supported by the BLE stack used for scanning, so it is provided by the
+/// caller, e.g. through BlueZ's `LEAdvertisingManager1`. The advert is sent as service data for
+/// the FIDO service UUID `0xfff9`.
+#[async_trait]
+pub trait CableAdvertiser: Send + Sync {
+    async fn start_advertising(&self, service_data: &[u8; 20]) -> Result<(), TransportError>;
+    async fn stop_advertising(&self);
+}
+
+/// Serves the platform which displayed `qr_code`, forwarding its requests to `channel` until
+/// it shuts the tunnel down. The tunnel is opened through `connector`, usually a
+/// [WssTunnelConnector](super::tunnel::WssTunnelConnector), and secured with `handshake`,
+/// usually a [NoiseHandshake](super::tunnel::NoiseHandshake).
+#[instrument(skip_all, err)]
+pub async fn serve_qr_code<C: Channel>(
+    qr_code: &CableQrCode,
+    advertiser: &dyn CableAdvertiser,
+    connector: &dyn TunnelConnector,
+    handshake: &dyn TunnelHandshake,
+    channel: &mut C,
+) -> Result<(), Error> {
+    // The platform only knows the first few assigned domains; any of them will do.
+    if qr_code.known_tunnel_domains_count == 0 {
+        error!("Platform does not know any tunnel server domain");
+        return Err(Error::Transport(TransportError::TransportUnavailable));
+    }
+    let encoded_tunnel_domain: u16 = 0;
+    let tunnel_domain = KNOWN_TUNNEL_DOMAINS[encoded_tunnel_domain as usize];
+
+    let qr_secret = qr_code.qr_secret.as_ref();
+    let tunnel_id = hex::encode(&derive(qr_secret, None, KeyPurpose::TunnelID)[..16]);
+    let (mut ws_stream, routing_id) =
+        tunnel::connect_new(connector, tunnel_domain, &tunnel_id).await?;
+
+    let plaintext = advert_plaintext(&routing_id, encoded_tunnel_domain);
+    let advert = encrypt_advert(&qr_code.eid_key(), &plaintext);
+    debug!("Advertising to the platform");
+    advertiser.start_advertising(&advert).await?;
+
+    let psk = derive_psk(qr_secret, &plaintext);
+    let handshake = handshake
+        .respond(&mut ws_stream, &psk, qr_code.public_key.as_ref())
+        .await;
+    advertiser.stop_advertising().await;
+    let cipher = handshake?;
+
+    info!("Platform connected, serving requests");
+    tunnel::serve(ws_stream, cipher, channel).await
+}
+
+/// The advert: a reserved zero byte, a random nonce, the routing ID, and the tunnel domain.
+pub(crate) fn advert_plaintext(routing_id: &[u8; 3], encoded_tunnel_domain: u16) -> [u8; 16] {
+    let mut plaintext = [0u8; 16];
+    sources::fill_bytes(&mut plaintext[1..11]);
+    plaintext[11..14].copy_from_slice(routing_id);
+    plaintext[14..].copy_from_slice(&encoded_tunnel_domain.to_le_bytes());
+    plaintext
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+    use tokio::sync::{broadcast, mpsc, watch};
+    use tokio_tungstenite::{accept_async, connect_async, MaybeTlsStream};
+
+    use super::advert_plaintext;
+    use crate::ops::webauthn::MakeCredentialRequest;
+    use crate::transport::cable::advertisement::DecryptedAdvert;
+    use crate::transport::cable::channel::{
+        CableChannel, CableLinkingStatus, CableUpdate, CableUxUpdate, ConnectionState,
+    };
+    use crate::transport::cable::connection_stages::{
+        derive_psk, MpscUxUpdateSender, TunnelConnectionInput,
+    };
+    use crate::transport::cable::crypto::{encrypt_advert, trial_decrypt_advert};
+    use crate::transport::cable::qr_code_device::{CableQrCode, QrCodeOperationHint};
+    use crate::transport::cable::tunnel::{
+        self, CableTunnelConnectionType, NoiseHandshake, TunnelHandshake,
+    };
+    use crate::transport::error::TransportError;
+    use crate::transport::local::VirtualDevice;
+    use crate::transport::Device;
+    use crate::webauthn::error::Error;
+    use crate::webauthn::WebAuthn;
+
+    #[test]
+    fn advert_is_found_by_platform() {
+        let (qr_code, _) = CableQrCode::generate(QrCodeOperationHint::GetAssertionRequest, false);
+        let plaintext = advert_plaintext(&[1, 2, 3], 1);
+        let advert = encrypt_advert(&qr_code.eid_key(), &plaintext);
+
+        let decrypted = trial_decrypt_advert(&qr_code.eid_key(), &advert).unwrap();
+        let decrypted = DecryptedAdvert::from(decrypted);
+        assert_eq!(decrypted.routing_id, [1, 2, 3]);
+        assert_eq!(decrypted.encoded_tunnel_server_domain, 1);
+    }
+
+    #[tokio::test]
+    async fn platform_registers_through_tunnel() {
+        let (qr_code, private_key) =
+            CableQrCode::generate(QrCodeOperationHint::MakeCredential, false);
+        let plaintext = advert_plaintext(&[1, 2, 3], 0);
+        let psk = derive_psk(qr_code.qr_secret.as_ref(), &plaintext);
+        // Stands in for the tunnel server, which just relays the messages.
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+
+        let mut device = VirtualDevice::new_virtual();
+        let mut served = device.channel().await.unwrap();
+        let authenticator = async {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut ws_stream = accept_async(MaybeTlsStream::Plain(stream)).await.unwrap();
+            let cipher = NoiseHandshake
+                .respond(&mut ws_stream, &psk, qr_code.public_key.as_ref())
+                .await
+                .unwrap();
+            let result = tunnel::serve(ws_stream, cipher, &mut served).await;
+            // The platform drops the tunnel without closing it.
+            assert!(matches!(
+                result,
+                Ok(()) | Err(Error::Transport(TransportError::ConnectionLost))
+            ));
+        };
+        let platform = async {
+            let (mut ws_stream, _) = connect_async(url).await.unwrap();
+            let connection_type = CableTunnelConnectionType::QrCode {
+                routing_id: String::new(),
+                tunnel_id: String::new(),
+                private_key,
+            };
+            let cipher = NoiseHandshake
+                .initiate(&mut ws_stream, &psk, (&connection_type).into())
+                .await
+                .unwrap();
+            let (cbor_sender, cbor_tx_recv) = mpsc::channel(16);
+            let (cbor_rx_send, cbor_receiver) = mpsc::channel(16);
+            let input = TunnelConnectionInput {
+                connection_type,
+                tunnel_domain: String::new(),
+                known_device_store: None,
+                ws_stream,
+                cipher,
+                cbor_tx_recv,
+                cbor_rx_send,
+                linking_status: watch::channel(CableLinkingStatus::default()).0,
+            };
+            let (connection_state_sender, connection_state_receiver) =
+                watch::channel(ConnectionState::Connected);
+            let (ux_update_sender, mut ux_updates) = broadcast::channel(16);
+            let ux_sender =
+                MpscUxUpdateSender::new(ux_update_sender.clone(), connection_state_sender);
+            let mut channel = CableChannel {
+                handle_connection: tokio::spawn(async move {
+                    tunnel::connection(input, &ux_sender).await
+                }),
+                cbor_sender,
+                cbor_receiver,
+                ux_update_sender,
+                connection_state_receiver,
+                linking_status_receiver: watch::channel(CableLinkingStatus::default()).1,
+                pending_hint: None,
+                pin_provider: None,
+                cancellation_token: None,
+                timeout_policy: Default::default(),
+                audit_trail: None,
+                metrics_recorder: None,
+                pin_uv_auth_protocols: Default::default(),
+                auth_token_data: None,
+                aaguid: Default::default(),
+            };
+
+            let response = channel
+                .webauthn_make_credential(&MakeCredentialRequest::dummy())
+                .await
+                .unwrap();
+            assert!(response.authenticator_data.attested_credential.is_some());
+            assert!(matches!(
+                ux_updates.try_recv().unwrap(),
+                CableUxUpdate::CableUpdate(CableUpdate::SessionEstablished { .. })
+            ));
+        };
+        tokio::join!(authenticator, platform);
+    }
+}
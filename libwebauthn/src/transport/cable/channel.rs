@@ -1,24 +1,32 @@
 use std::fmt::{Display, Formatter};
+use std::sync::Arc;
 use std::time::Duration;
 
 use async_trait::async_trait;
-use tokio::sync::{broadcast, mpsc, watch};
+use tokio::sync::{broadcast, mpsc, oneshot, watch};
 use tokio::{task, time};
-use tracing::error;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, error};
 
+use crate::audit::AuditTrail;
+use crate::metrics::MetricsRecorder;
+use crate::pin::{PinProvider, PinUvAuthProtocols};
 use crate::proto::{
     ctap1::apdu::{ApduRequest, ApduResponse},
     ctap2::cbor::{CborRequest, CborResponse},
 };
+use crate::timeout::TimeoutPolicy;
 use crate::transport::error::TransportError;
 use crate::transport::AuthTokenData;
 use crate::transport::{
-    channel::ChannelStatus, device::SupportedProtocols, Channel, Ctap2AuthTokenStore,
+    channel::{until_cancelled, ChannelStatus},
+    device::SupportedProtocols,
+    Channel, Ctap2AuthTokenStore, DeviceAaguid,
 };
-use crate::webauthn::error::Error;
+use crate::webauthn::error::{Error, PlatformError};
 use crate::UvUpdate;
 
-use super::known_devices::CableKnownDevice;
+use super::known_devices::{CableKnownDevice, ClientPayloadHint};
 use super::qr_code_device::CableQrCodeDevice;
 
 #[derive(Debug, Clone, PartialEq)]
@@ -37,6 +45,14 @@ pub enum CableChannelDevice<'d> {
     Known(&'d CableKnownDevice),
 }
 
+/// The hint a known device's connection waits for, as it is sent when contacting the
+/// authenticator. WebAuthn ceremonies announce theirs, any other first request sends `default`.
+#[derive(Debug)]
+pub(crate) struct PendingHint {
+    pub(crate) sender: oneshot::Sender<ClientPayloadHint>,
+    pub(crate) default: ClientPayloadHint,
+}
+
 #[derive(Debug)]
 pub struct CableChannel {
     pub(crate) handle_connection: task::JoinHandle<()>,
@@ -44,12 +60,28 @@ pub struct CableChannel {
     pub(crate) cbor_receiver: mpsc::Receiver<CborResponse>,
     pub(crate) ux_update_sender: broadcast::Sender<CableUxUpdate>,
     pub(crate) connection_state_receiver: watch::Receiver<ConnectionState>,
+    pub(crate) linking_status_receiver: watch::Receiver<CableLinkingStatus>,
+    pub(crate) pending_hint: Option<PendingHint>,
+    pub(crate) pin_provider: Option<Arc<dyn PinProvider>>,
+    pub(crate) cancellation_token: Option<CancellationToken>,
+    pub(crate) timeout_policy: TimeoutPolicy,
+    pub(crate) audit_trail: Option<AuditTrail>,
+    pub(crate) metrics_recorder: Option<Arc<dyn MetricsRecorder>>,
+    pub(crate) pin_uv_auth_protocols: PinUvAuthProtocols,
+    /// Kept for the lifetime of the tunnel, so multi-step management flows
+    /// (e.g. enumerating credentials) don't prompt for UV on every subcommand.
+    pub(crate) auth_token_data: Option<AuthTokenData>,
+    pub(crate) aaguid: DeviceAaguid,
 }
 
 impl CableChannel {
-    async fn wait_for_connection(&self) -> Result<(), Error> {
-        let mut rx = self.connection_state_receiver.clone();
+    /// Whether the authenticator has been linked so far, i.e. remembered as a known device.
+    /// Authenticators send linking info at any time during the connection, if at all.
+    pub fn linking_status(&self) -> CableLinkingStatus {
+        *self.linking_status_receiver.borrow()
+    }
 
+    async fn wait_for_connection(mut rx: watch::Receiver<ConnectionState>) -> Result<(), Error> {
         // If already connected, return immediately
         if *rx.borrow() == ConnectionState::Connected {
             return Ok(());
@@ -74,6 +106,16 @@ impl CableChannel {
         // If the sender was dropped, consider it a failure
         Err(Error::Transport(TransportError::ConnectionLost))
     }
+
+    /// Closes the tunnel once the operation was cancelled, which the authenticator sees as the
+    /// platform going away.
+    fn close_if_cancelled<T>(&self, result: Result<T, Error>) -> Result<T, Error> {
+        if matches!(result, Err(Error::Platform(PlatformError::Cancelled))) {
+            debug!("Closing tunnel of cancelled operation");
+            self.handle_connection.abort();
+        }
+        result
+    }
 }
 
 impl Display for CableChannel {
@@ -94,20 +136,70 @@ pub enum CableUxUpdate {
     CableUpdate(CableUpdate),
 }
 
+/// Progress of a caBLE connection. Stages with an `elapsed` field carry the time since the
+/// channel started connecting.
 #[derive(Debug, Clone)]
 pub enum CableUpdate {
     /// Waiting for proximity check user interaction (eg. scan a QR code, or confirm on the device).
     ProximityCheck,
+    /// The authenticator's BLE advert was received, so it is nearby.
+    AdvertReceived { elapsed: Duration },
     /// Connecting to the tunnel server.
     Connecting,
+    /// Connected to the tunnel server, which may still be waiting for the authenticator.
+    TunnelConnected { elapsed: Duration },
+    /// Connecting to the tunnel server failed, trying again after `delay`.
+    Reconnecting {
+        attempt: u32,
+        tunnel_domain: String,
+        delay: Duration,
+    },
     /// Connected to the tunnel server, authenticating the channel.
     Authenticating,
-    /// Connected to the authenticator device via the tunnel server.
+    /// Connected to the authenticator device via the tunnel server, i.e. the handshake completed.
     Connected,
+    /// The authenticator sent its getInfo response, and is ready for CTAP requests.
+    SessionEstablished {
+        elapsed: Duration,
+        features: CableFeatures,
+    },
+    /// The authenticator sent linking info.
+    Linking(CableLinkingStatus),
     /// The connection to the authenticator device has failed.
     Error(TransportError),
 }
 
+/// Optional protocol features announced by the authenticator in its initial message. Older
+/// authenticators don't announce any.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct CableFeatures {
+    /// The authenticator may send linking info (`"linking"`).
+    pub linking: bool,
+    /// The authenticator accepts JSON-encoded digital credential requests (`"dc"`).
+    pub digital_credentials: bool,
+}
+
+impl CableFeatures {
+    pub(crate) fn from_names(names: &[String]) -> Self {
+        Self {
+            linking: names.iter().any(|name| name == "linking"),
+            digital_credentials: names.iter().any(|name| name == "dc"),
+        }
+    }
+}
+
+/// Linking of an authenticator connected through a QR code, which lets later connections skip
+/// scanning a QR code.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct CableLinkingStatus {
+    /// The QR code asked the authenticator for linking info.
+    pub requested: bool,
+    /// The authenticator sent linking info.
+    pub received: bool,
+    /// The linking info was valid, and persisted to the known-device store.
+    pub stored: bool,
+}
+
 impl From<UvUpdate> for CableUxUpdate {
     fn from(update: UvUpdate) -> Self {
         CableUxUpdate::UvUpdate(update)
@@ -118,6 +210,14 @@ impl From<UvUpdate> for CableUxUpdate {
 impl<'d> Channel for CableChannel {
     type UxUpdate = CableUxUpdate;
 
+    fn transport_name(&self) -> &'static str {
+        "cable"
+    }
+
+    fn device_aaguid(&self) -> Option<&DeviceAaguid> {
+        Some(&self.aaguid)
+    }
+
     async fn supported_protocols(&self) -> Result<SupportedProtocols, Error> {
         Ok(SupportedProtocols::fido2_only())
     }
@@ -144,54 +244,132 @@ impl<'d> Channel for CableChannel {
     }
 
     async fn cbor_send(&mut self, request: &CborRequest, timeout: Duration) -> Result<(), Error> {
-        // First, wait for connection to be established (no timeout for handshake)
-        self.wait_for_connection().await?;
-
-        // Now apply timeout only to the actual CBOR operation
-        match time::timeout(timeout, self.cbor_sender.send(request.clone())).await {
-            Ok(Ok(_)) => Ok(()),
-            Ok(Err(error)) => {
-                error!(%error, "CBOR request send failure");
-                Err(Error::Transport(TransportError::TransportUnavailable))
-            }
-            Err(elapsed) => {
-                error!({ %elapsed, ?timeout }, "CBOR request send timeout");
-                Err(Error::Transport(TransportError::Timeout))
+        if let Some(pending) = self.pending_hint.take() {
+            if pending.sender.send(pending.default).is_err() {
+                debug!("Connection closed before the hint was sent");
             }
         }
+        let send = async {
+            // First, wait for connection to be established (no timeout for handshake)
+            Self::wait_for_connection(self.connection_state_receiver.clone()).await?;
+
+            // Now apply timeout only to the actual CBOR operation
+            match time::timeout(timeout, self.cbor_sender.send(request.clone())).await {
+                Ok(Ok(_)) => Ok(()),
+                Ok(Err(error)) => {
+                    error!(%error, "CBOR request send failure");
+                    Err(Error::Transport(TransportError::TransportUnavailable))
+                }
+                Err(elapsed) => {
+                    error!({ %elapsed, ?timeout }, "CBOR request send timeout");
+                    Err(Error::Transport(TransportError::Timeout))
+                }
+            }
+        };
+        let token = self.cancellation_token.clone();
+        let result = until_cancelled(token, &self.ux_update_sender, send).await;
+        self.close_if_cancelled(result)
     }
 
     async fn cbor_recv(&mut self, timeout: Duration) -> Result<CborResponse, Error> {
-        // First, wait for connection to be established (no timeout for handshake)
-        self.wait_for_connection().await?;
-
-        // Now apply timeout only to the actual CBOR operation
-        match time::timeout(timeout, self.cbor_receiver.recv()).await {
-            Ok(Some(response)) => Ok(response),
-            Ok(None) => Err(Error::Transport(TransportError::TransportUnavailable)),
-            Err(elapsed) => {
-                error!({ %elapsed, ?timeout }, "CBOR response recv timeout");
-                Err(Error::Transport(TransportError::Timeout))
+        let connection_state_receiver = self.connection_state_receiver.clone();
+        let cbor_receiver = &mut self.cbor_receiver;
+        let recv = async {
+            // First, wait for connection to be established (no timeout for handshake)
+            Self::wait_for_connection(connection_state_receiver).await?;
+
+            // Now apply timeout only to the actual CBOR operation
+            match time::timeout(timeout, cbor_receiver.recv()).await {
+                Ok(Some(response)) => Ok(response),
+                Ok(None) => Err(Error::Transport(TransportError::TransportUnavailable)),
+                Err(elapsed) => {
+                    error!({ %elapsed, ?timeout }, "CBOR response recv timeout");
+                    Err(Error::Transport(TransportError::Timeout))
+                }
             }
-        }
+        };
+        let token = self.cancellation_token.clone();
+        let result = until_cancelled(token, &self.ux_update_sender, recv).await;
+        self.close_if_cancelled(result)
     }
 
     fn get_ux_update_sender(&self) -> &broadcast::Sender<CableUxUpdate> {
         &self.ux_update_sender
     }
 
-    fn supports_preflight() -> bool {
+    fn get_pin_provider(&self) -> Option<Arc<dyn PinProvider>> {
+        self.pin_provider.clone()
+    }
+
+    fn set_pin_provider(&mut self, provider: Option<Arc<dyn PinProvider>>) {
+        self.pin_provider = provider;
+    }
+
+    fn get_cancellation_token(&self) -> Option<CancellationToken> {
+        self.cancellation_token.clone()
+    }
+
+    fn set_cancellation_token(&mut self, token: Option<CancellationToken>) {
+        self.cancellation_token = token;
+    }
+
+    fn get_timeout_policy(&self) -> TimeoutPolicy {
+        self.timeout_policy
+    }
+
+    fn set_timeout_policy(&mut self, policy: TimeoutPolicy) {
+        self.timeout_policy = policy;
+    }
+
+    fn get_audit_trail(&self) -> Option<AuditTrail> {
+        self.audit_trail.clone()
+    }
+
+    fn set_audit_trail(&mut self, trail: Option<AuditTrail>) {
+        self.audit_trail = trail;
+    }
+
+    fn get_metrics_recorder(&self) -> Option<Arc<dyn MetricsRecorder>> {
+        self.metrics_recorder.clone()
+    }
+
+    fn set_metrics_recorder(&mut self, recorder: Option<Arc<dyn MetricsRecorder>>) {
+        self.metrics_recorder = recorder;
+    }
+
+    fn get_pin_uv_auth_protocols(&self) -> PinUvAuthProtocols {
+        self.pin_uv_auth_protocols.clone()
+    }
+
+    fn set_pin_uv_auth_protocols(&mut self, protocols: PinUvAuthProtocols) {
+        self.pin_uv_auth_protocols = protocols;
+    }
+
+    fn supports_preflight(&self) -> bool {
         // Disable pre-flight requests, as hybrid transport authenticators do not support silent requests.
         false
     }
+
+    fn announce_operation(&mut self, hint: ClientPayloadHint) {
+        if let Some(pending) = self.pending_hint.take() {
+            debug!(?hint, "Contacting known device");
+            if pending.sender.send(hint).is_err() {
+                debug!("Connection closed before the hint was sent");
+            }
+        }
+    }
 }
 
 impl<'d> Ctap2AuthTokenStore for CableChannel {
-    fn store_auth_data(&mut self, _auth_token_data: AuthTokenData) {}
+    fn store_auth_data(&mut self, auth_token_data: AuthTokenData) {
+        self.auth_token_data = Some(auth_token_data);
+    }
 
     fn get_auth_data(&self) -> Option<&AuthTokenData> {
-        None
+        self.auth_token_data.as_ref()
     }
 
-    fn clear_uv_auth_token_store(&mut self) {}
+    fn clear_uv_auth_token_store(&mut self) {
+        self.auth_token_data = None;
+    }
 }
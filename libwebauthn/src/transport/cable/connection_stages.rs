@@ -1,32 +1,42 @@
 use async_trait::async_trait;
 use tokio::sync::{broadcast, mpsc, watch};
+use tokio::time::{sleep, Instant};
 use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
 use tracing::{debug, error, instrument, trace, warn};
+use zeroize::Zeroizing;
 
-use super::advertisement::{await_advertisement, DecryptedAdvert};
-use super::channel::{CableUpdate, CableUxUpdate, ConnectionState};
+use super::advertisement::{await_advertisement, AdvertScanner, DecryptedAdvert};
+use super::channel::{CableLinkingStatus, CableUpdate, CableUxUpdate, ConnectionState};
 use super::crypto::{derive, KeyPurpose};
 use super::known_devices::{CableKnownDevice, CableKnownDeviceInfoStore, ClientNonce};
 use super::qr_code_device::CableQrCodeDevice;
-use super::tunnel::{self, CableTunnelConnectionType, TunnelNoiseState};
+use super::tunnel::{
+    self, CableTunnelConnectionType, TunnelCipher, TunnelConnector, TunnelDomains, TunnelHandshake,
+};
+use crate::metrics::MetricsRecorder;
 use crate::proto::ctap2::cbor::{CborRequest, CborResponse};
-use crate::transport::ble::btleplug::FidoDevice;
 use crate::transport::error::TransportError;
 use std::sync::Arc;
+use std::time::Duration;
+
+/// Connection attempts made to the tunnel server before giving up. The routing and contact
+/// IDs are only valid on the server which issued them, so there is no other domain to try.
+const CONNECT_ATTEMPTS: u32 = 3;
+/// Delay before the first retry, doubled after every further attempt.
+const CONNECT_INITIAL_BACKOFF: Duration = Duration::from_millis(500);
 
 #[derive(Debug)]
 pub(crate) struct ProximityCheckInput {
     pub eid_key: [u8; 64],
+    pub advert_scanner: Arc<dyn AdvertScanner>,
 }
 
 impl ProximityCheckInput {
     pub fn new_for_qr_code(qr_device: &CableQrCodeDevice) -> Self {
-        let eid_key: [u8; 64] = derive(
-            qr_device.qr_code.qr_secret.as_ref(),
-            None,
-            KeyPurpose::EIDKey,
-        );
-        Self { eid_key }
+        Self {
+            eid_key: qr_device.qr_code.eid_key(),
+            advert_scanner: qr_device.advert_scanner.clone(),
+        }
     }
 
     pub fn new_for_known_device(
@@ -38,13 +48,15 @@ impl ProximityCheckInput {
             Some(client_nonce),
             KeyPurpose::EIDKey,
         );
-        Self { eid_key }
+        Self {
+            eid_key,
+            advert_scanner: known_device.advert_scanner.clone(),
+        }
     }
 }
 
 #[derive(Debug)]
 pub(crate) struct ProximityCheckOutput {
-    pub _device: FidoDevice,
     pub advert: DecryptedAdvert,
 }
 
@@ -52,6 +64,8 @@ pub(crate) struct ProximityCheckOutput {
 pub(crate) struct ConnectionInput {
     pub tunnel_domain: String,
     pub connection_type: CableTunnelConnectionType,
+    pub tunnel_connector: Arc<dyn TunnelConnector>,
+    pub metrics_recorder: Option<Arc<dyn MetricsRecorder>>,
 }
 
 impl ConnectionInput {
@@ -60,7 +74,8 @@ impl ConnectionInput {
         qr_device: &CableQrCodeDevice,
         proximity_output: &ProximityCheckOutput,
     ) -> Result<Self, TransportError> {
-        let tunnel_domain = decode_tunnel_domain_from_advert(&proximity_output.advert)?;
+        let tunnel_domain =
+            decode_tunnel_domain_from_advert(&proximity_output.advert, &qr_device.tunnel_domains)?;
 
         let routing_id_str = hex::encode(&proximity_output.advert.routing_id);
         let tunnel_id = &derive(
@@ -78,6 +93,8 @@ impl ConnectionInput {
         Ok(Self {
             tunnel_domain,
             connection_type,
+            tunnel_connector: qr_device.tunnel_connector.clone(),
+            metrics_recorder: qr_device.metrics_recorder.clone(),
         })
     }
 
@@ -103,6 +120,8 @@ impl ConnectionInput {
         Self {
             tunnel_domain: known_device.device_info.tunnel_domain.clone(),
             connection_type,
+            tunnel_connector: known_device.tunnel_connector.clone(),
+            metrics_recorder: known_device.metrics_recorder.clone(),
         }
     }
 }
@@ -116,9 +135,10 @@ pub(crate) struct ConnectionOutput {
 
 pub(crate) struct HandshakeInput {
     pub ws_stream: WebSocketStream<MaybeTlsStream<tokio::net::TcpStream>>,
-    pub psk: [u8; 32],
+    pub psk: Zeroizing<[u8; 32]>,
     pub connection_type: CableTunnelConnectionType,
     pub tunnel_domain: String,
+    pub tunnel_handshake: Arc<dyn TunnelHandshake>,
 }
 
 impl HandshakeInput {
@@ -134,6 +154,7 @@ impl HandshakeInput {
             psk,
             connection_type: connection_output.connection_type,
             tunnel_domain: connection_output.tunnel_domain,
+            tunnel_handshake: qr_device.tunnel_handshake.clone(),
         }
     }
 
@@ -142,21 +163,22 @@ impl HandshakeInput {
         connection_output: ConnectionOutput,
         proximity_output: ProximityCheckOutput,
     ) -> Self {
-        let link_secret = known_device.device_info.link_secret;
+        let link_secret = &known_device.device_info.link_secret;
         let advert_plaintext = proximity_output.advert.plaintext;
-        let psk = derive_psk(&link_secret, &advert_plaintext);
+        let psk = derive_psk(link_secret, &advert_plaintext);
         Self {
             ws_stream: connection_output.ws_stream,
             psk,
             connection_type: connection_output.connection_type,
             tunnel_domain: connection_output.tunnel_domain,
+            tunnel_handshake: known_device.tunnel_handshake.clone(),
         }
     }
 }
 
 pub(crate) struct HandshakeOutput {
     pub ws_stream: WebSocketStream<MaybeTlsStream<tokio::net::TcpStream>>,
-    pub noise_state: TunnelNoiseState,
+    pub cipher: Box<dyn TunnelCipher>,
     pub connection_type: CableTunnelConnectionType,
     pub tunnel_domain: String,
 }
@@ -166,9 +188,10 @@ pub(crate) struct TunnelConnectionInput {
     pub tunnel_domain: String,
     pub known_device_store: Option<Arc<dyn CableKnownDeviceInfoStore>>,
     pub ws_stream: WebSocketStream<MaybeTlsStream<tokio::net::TcpStream>>,
-    pub noise_state: TunnelNoiseState,
+    pub cipher: Box<dyn TunnelCipher>,
     pub cbor_tx_recv: mpsc::Receiver<CborRequest>,
     pub cbor_rx_send: mpsc::Sender<CborResponse>,
+    pub linking_status: watch::Sender<CableLinkingStatus>,
 }
 
 impl TunnelConnectionInput {
@@ -177,15 +200,17 @@ impl TunnelConnectionInput {
         known_device_store: Option<Arc<dyn CableKnownDeviceInfoStore>>,
         cbor_tx_recv: mpsc::Receiver<CborRequest>,
         cbor_rx_send: mpsc::Sender<CborResponse>,
+        linking_status: watch::Sender<CableLinkingStatus>,
     ) -> Self {
         Self {
             connection_type: handshake_output.connection_type,
             tunnel_domain: handshake_output.tunnel_domain,
             known_device_store,
             ws_stream: handshake_output.ws_stream,
-            noise_state: handshake_output.noise_state,
+            cipher: handshake_output.cipher,
             cbor_tx_recv,
             cbor_rx_send,
+            linking_status,
         }
     }
 }
@@ -195,11 +220,14 @@ pub(crate) trait UxUpdateSender: Send + Sync {
     async fn send_update(&self, update: CableUxUpdate);
     async fn send_error(&self, error: TransportError);
     async fn set_connection_state(&self, state: ConnectionState);
+    /// Time since the channel started connecting.
+    fn elapsed(&self) -> Duration;
 }
 
 pub(crate) struct MpscUxUpdateSender {
     sender: broadcast::Sender<CableUxUpdate>,
     connection_state_tx: watch::Sender<ConnectionState>,
+    started: Instant,
 }
 
 impl MpscUxUpdateSender {
@@ -210,6 +238,7 @@ impl MpscUxUpdateSender {
         Self {
             sender,
             connection_state_tx,
+            started: Instant::now(),
         }
     }
 }
@@ -227,11 +256,39 @@ impl UxUpdateSender for MpscUxUpdateSender {
     async fn send_error(&self, error: TransportError) {
         self.send_update(CableUxUpdate::CableUpdate(CableUpdate::Error(error)))
             .await;
-        let _ = self.connection_state_tx.send(ConnectionState::Terminated);
+        if self
+            .connection_state_tx
+            .send(ConnectionState::Terminated)
+            .is_err()
+        {
+            debug!("No receivers for connection state");
+        }
     }
 
     async fn set_connection_state(&self, state: ConnectionState) {
-        let _ = self.connection_state_tx.send(state);
+        if self.connection_state_tx.send(state).is_err() {
+            debug!("No receivers for connection state");
+        }
+    }
+
+    fn elapsed(&self) -> Duration {
+        self.started.elapsed()
+    }
+}
+
+/// Drops all updates, for stages run before a channel exists.
+pub(crate) struct DiscardingUxUpdateSender;
+
+#[async_trait]
+impl UxUpdateSender for DiscardingUxUpdateSender {
+    async fn send_update(&self, _update: CableUxUpdate) {}
+
+    async fn send_error(&self, _error: TransportError) {}
+
+    async fn set_connection_state(&self, _state: ConnectionState) {}
+
+    fn elapsed(&self) -> Duration {
+        Duration::ZERO
     }
 }
 
@@ -246,13 +303,15 @@ pub(crate) async fn proximity_check_stage(
         .send_update(CableUxUpdate::CableUpdate(CableUpdate::ProximityCheck))
         .await;
 
-    let (device, advert) = await_advertisement(&input.eid_key).await?;
+    let advert = await_advertisement(&input.eid_key, input.advert_scanner.as_ref()).await?;
+    ux_sender
+        .send_update(CableUxUpdate::CableUpdate(CableUpdate::AdvertReceived {
+            elapsed: ux_sender.elapsed(),
+        }))
+        .await;
 
     debug!("Proximity check completed successfully");
-    Ok(ProximityCheckOutput {
-        _device: device,
-        advert,
-    })
+    Ok(ProximityCheckOutput { advert })
 }
 
 #[instrument(skip_all, err)]
@@ -266,14 +325,48 @@ pub(crate) async fn connection_stage(
         .send_update(CableUxUpdate::CableUpdate(CableUpdate::Connecting))
         .await;
 
-    let ws_stream = tunnel::connect(&input.tunnel_domain, &input.connection_type).await?;
-
-    debug!("Connection stage completed successfully");
-    Ok(ConnectionOutput {
-        ws_stream,
-        connection_type: input.connection_type,
-        tunnel_domain: input.tunnel_domain,
-    })
+    let tunnel_domain = &input.tunnel_domain;
+    let connector = input.tunnel_connector.as_ref();
+    let mut delay = CONNECT_INITIAL_BACKOFF;
+    let mut attempt = 1;
+    loop {
+        match tunnel::connect(connector, tunnel_domain, &input.connection_type).await {
+            Ok(ws_stream) => {
+                debug!("Connection stage completed successfully");
+                if let Some(recorder) = &input.metrics_recorder {
+                    recorder.record_tunnel_connect(tunnel_domain, ux_sender.elapsed());
+                }
+                ux_sender
+                    .send_update(CableUxUpdate::CableUpdate(CableUpdate::TunnelConnected {
+                        elapsed: ux_sender.elapsed(),
+                    }))
+                    .await;
+                return Ok(ConnectionOutput {
+                    ws_stream,
+                    connection_type: input.connection_type,
+                    tunnel_domain: tunnel_domain.clone(),
+                });
+            }
+            // Only failures to reach the tunnel server are worth retrying.
+            Err(TransportError::ConnectionFailed) if attempt < CONNECT_ATTEMPTS => {
+                warn!(attempt, tunnel_domain, ?delay, "Retrying tunnel connection");
+                ux_sender
+                    .send_update(CableUxUpdate::CableUpdate(CableUpdate::Reconnecting {
+                        attempt,
+                        tunnel_domain: tunnel_domain.clone(),
+                        delay,
+                    }))
+                    .await;
+                sleep(delay).await;
+                delay *= 2;
+                attempt += 1;
+            }
+            Err(error) => {
+                error!(attempt, ?error, "Failed to connect to the tunnel server");
+                return Err(error);
+            }
+        }
+    }
 }
 
 #[instrument(skip_all, err)]
@@ -288,8 +381,10 @@ pub(crate) async fn handshake_stage(
         .await;
 
     let mut ws_stream = input.ws_stream;
-    let noise_state =
-        tunnel::do_handshake(&mut ws_stream, input.psk, &input.connection_type).await?;
+    let cipher = input
+        .tunnel_handshake
+        .initiate(&mut ws_stream, &input.psk, (&input.connection_type).into())
+        .await?;
 
     debug!("Handshake stage completed successfully");
     ux_sender
@@ -302,24 +397,102 @@ pub(crate) async fn handshake_stage(
 
     Ok(HandshakeOutput {
         ws_stream,
-        noise_state,
+        cipher,
         connection_type: input.connection_type,
         tunnel_domain: input.tunnel_domain,
     })
 }
 
-fn derive_psk(secret: &[u8], advert_plaintext: &[u8]) -> [u8; 32] {
-    let mut psk: [u8; 32] = [0u8; 32];
-    psk.copy_from_slice(&derive(secret, Some(advert_plaintext), KeyPurpose::PSK)[..32]);
+pub(crate) fn derive_psk(secret: &[u8], advert_plaintext: &[u8]) -> Zeroizing<[u8; 32]> {
+    let mut psk = Zeroizing::new([0u8; 32]);
+    let derived = Zeroizing::new(derive(secret, Some(advert_plaintext), KeyPurpose::PSK));
+    psk.copy_from_slice(&derived[..32]);
     psk
 }
 
 pub(crate) fn decode_tunnel_domain_from_advert(
     advert: &DecryptedAdvert,
+    tunnel_domains: &TunnelDomains,
 ) -> Result<String, TransportError> {
-    tunnel::decode_tunnel_server_domain(advert.encoded_tunnel_server_domain)
+    tunnel_domains
+        .decode(advert.encoded_tunnel_server_domain)
         .ok_or_else(|| {
             error!({ encoded = %advert.encoded_tunnel_server_domain }, "Failed to decode tunnel server domain");
             TransportError::InvalidFraming
         })
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    use async_trait::async_trait;
+    use p256::NonZeroScalar;
+    use rand::rngs::OsRng;
+
+    use super::{connection_stage, ConnectionInput, UxUpdateSender, CONNECT_ATTEMPTS};
+    use crate::transport::cable::channel::{CableUpdate, CableUxUpdate, ConnectionState};
+    use crate::transport::cable::tunnel::{CableTunnelConnectionType, WssTunnelConnector};
+    use crate::transport::error::TransportError;
+
+    #[derive(Default)]
+    struct RecordingUxUpdateSender {
+        updates: Mutex<Vec<CableUpdate>>,
+    }
+
+    #[async_trait]
+    impl UxUpdateSender for RecordingUxUpdateSender {
+        async fn send_update(&self, update: CableUxUpdate) {
+            if let CableUxUpdate::CableUpdate(update) = update {
+                self.updates.lock().unwrap().push(update);
+            }
+        }
+
+        async fn send_error(&self, error: TransportError) {
+            self.send_update(CableUxUpdate::CableUpdate(CableUpdate::Error(error)))
+                .await;
+        }
+
+        async fn set_connection_state(&self, _state: ConnectionState) {}
+
+        fn elapsed(&self) -> Duration {
+            Duration::ZERO
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn connection_is_retried_with_backoff() {
+        // Nothing listens on this port, so every attempt fails.
+        let input = ConnectionInput {
+            tunnel_domain: "127.0.0.1:1".to_owned(),
+            connection_type: CableTunnelConnectionType::QrCode {
+                routing_id: "000000".to_owned(),
+                tunnel_id: "00".to_owned(),
+                private_key: NonZeroScalar::random(&mut OsRng),
+            },
+            tunnel_connector: Arc::new(WssTunnelConnector),
+            metrics_recorder: None,
+        };
+        let ux_sender = RecordingUxUpdateSender::default();
+
+        let result = connection_stage(input, &ux_sender).await;
+        assert_eq!(result.unwrap_err(), TransportError::ConnectionFailed);
+
+        let updates = ux_sender.updates.into_inner().unwrap();
+        assert!(matches!(updates[0], CableUpdate::Connecting));
+        let retries: Vec<_> = updates[1..]
+            .iter()
+            .map(|update| match update {
+                CableUpdate::Reconnecting {
+                    tunnel_domain,
+                    delay,
+                    ..
+                } => (tunnel_domain.as_str(), delay.as_millis()),
+                update => panic!("Unexpected update: {update:?}"),
+            })
+            .collect();
+        assert_eq!(retries.len() as u32, CONNECT_ATTEMPTS - 1);
+        assert_eq!(retries, vec![("127.0.0.1:1", 500), ("127.0.0.1:1", 1000)]);
+    }
+}
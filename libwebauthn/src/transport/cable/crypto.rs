@@ -1,4 +1,4 @@
-use aes::cipher::{BlockDecrypt, KeyInit};
+use aes::cipher::{BlockDecrypt, BlockEncrypt, KeyInit};
 use aes::{Aes256, Block};
 use hkdf::Hkdf;
 use sha2::Sha256;
@@ -59,10 +59,23 @@ pub fn trial_decrypt_advert(eid_key: &[u8], candidate_advert: &[u8]) -> Option<[
     Some(plaintext)
 }
 
+/// Encrypts the advert emitted by the authenticator, the inverse of [trial_decrypt_advert].
+pub fn encrypt_advert(eid_key: &[u8; 64], plaintext: &[u8; 16]) -> [u8; 20] {
+    let cipher = Aes256::new_from_slice(&eid_key[..32]).unwrap();
+    let mut block = Block::clone_from_slice(plaintext);
+    cipher.encrypt_block(&mut block);
+
+    let mut advert = [0u8; 20];
+    advert[..16].copy_from_slice(&block);
+    let tag = hmac_sha256(&eid_key[32..], &advert[..16]);
+    advert[16..].copy_from_slice(&tag[..4]);
+    advert
+}
+
 #[cfg(test)]
 mod tests {
-    use super::derive;
     use super::KeyPurpose;
+    use super::{derive, encrypt_advert, trial_decrypt_advert};
 
     #[test]
     fn derive_eidkey_nosalt() {
@@ -86,4 +99,16 @@ mod tests {
         let expected = hex::decode("168cf3dd220a7907f8bac30f559be92a3b6d937fe5594beeaf1e50e35976b7d654dd550e22ae4c801b9d1cdbf0d2b1472daa1328661eb889acae3023b7ffa509").unwrap();
         assert_eq!(output, expected);
     }
+
+    #[test]
+    fn encrypted_advert_decrypts() {
+        let eid_key = derive(&[1u8; 16], None, KeyPurpose::EIDKey);
+        let mut plaintext = [0x42u8; 16];
+        plaintext[0] = 0;
+        let advert = encrypt_advert(&eid_key, &plaintext);
+        assert_eq!(trial_decrypt_advert(&eid_key, &advert), Some(plaintext));
+
+        let other_key = derive(&[2u8; 16], None, KeyPurpose::EIDKey);
+        assert_eq!(trial_decrypt_advert(&other_key, &advert), None);
+    }
 }
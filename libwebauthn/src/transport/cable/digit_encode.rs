@@ -30,12 +30,53 @@ pub fn digit_encode(input: &[u8]) -> String {
     output
 }
 
+/// Inverse of [digit_encode]. Returns `None` if `input` isn't a valid encoding.
+pub fn digit_decode(input: &str) -> Option<Vec<u8>> {
+    if !input.bytes().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    let mut output = Vec::new();
+    let mut input = input;
+    while !input.is_empty() {
+        let digits = input.len().min(CHUNK_DIGITS);
+        let len = if digits == CHUNK_DIGITS {
+            CHUNK_SIZE
+        } else {
+            (1..CHUNK_SIZE).find(|len| 0x0F & (PARTIAL_CHUNK_DIGITS >> (4 * len)) == digits)?
+        };
+        let v: u64 = input[..digits].parse().ok()?;
+        if v >> (8 * len) != 0 {
+            return None;
+        }
+        output.extend_from_slice(&v.to_le_bytes()[..len]);
+        input = &input[digits..];
+    }
+    Some(output)
+}
+
 #[cfg(test)]
 mod tests {
-    use super::digit_encode;
+    use super::{digit_decode, digit_encode};
 
     #[test]
     fn test_digit_encode() {
         assert_eq!(digit_encode(b"hello world"), "335311851610699281684828783")
     }
+
+    #[test]
+    fn test_digit_decode() {
+        assert_eq!(
+            digit_decode("335311851610699281684828783").unwrap(),
+            b"hello world"
+        );
+        for len in 0..=15u8 {
+            let input: Vec<u8> = (0..len).map(|i| 0xF0 | i).collect();
+            assert_eq!(digit_decode(&digit_encode(&input)).unwrap(), input);
+        }
+        // No number of bytes is encoded with 4 digits.
+        assert_eq!(digit_decode("1234"), None);
+        // Doesn't fit in 7 bytes.
+        assert_eq!(digit_decode("99999999999999999"), None);
+        assert_eq!(digit_decode("12a"), None);
+    }
 }
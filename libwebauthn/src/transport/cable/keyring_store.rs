@@ -0,0 +1,252 @@
+//! A known-device store keeping caBLE devices in the OS keyring.
+//!
+//! The link secret of a known device lets anyone holding it contact the phone, so it shouldn't
+//! be written to disk in the clear. [KeyringDeviceInfoStore] keeps each device in its own
+//! keyring entry instead: the Secret Service (`org.freedesktop.secrets`) on Linux, the Keychain
+//! on macOS, and the Credential Manager (DPAPI) on Windows.
+
+use std::sync::{Mutex, PoisonError};
+
+use async_trait::async_trait;
+use keyring::Entry;
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+use tokio::task;
+use tracing::{debug, error, trace, warn};
+use zeroize::{Zeroize, Zeroizing};
+
+use super::known_devices::{CableKnownDeviceId, CableKnownDeviceInfo, CableKnownDeviceInfoStore};
+use crate::proto::ctap2::cbor;
+
+const DEFAULT_SERVICE: &str = "libwebauthn-cable";
+/// Entry listing the IDs of all stored devices, as keyrings can't be enumerated portably.
+const INDEX_USER: &str = "known-devices";
+
+/// Serializes updates of the index, which the keyring can only read or replace as a whole.
+/// Held across the read and the write, so that concurrent puts and deletes don't drop each
+/// other's changes. Other processes using the same service aren't covered.
+static INDEX_LOCK: Mutex<()> = Mutex::new(());
+
+#[derive(Debug, Clone)]
+pub struct KeyringDeviceInfoStore {
+    service: String,
+}
+
+impl Default for KeyringDeviceInfoStore {
+    fn default() -> Self {
+        Self::new(DEFAULT_SERVICE)
+    }
+}
+
+impl KeyringDeviceInfoStore {
+    /// Stores devices under the given keyring service name, e.g. to separate applications.
+    pub fn new(service: &str) -> Self {
+        Self {
+            service: service.to_owned(),
+        }
+    }
+
+    fn entry(&self, user: &str) -> keyring::Result<Entry> {
+        Entry::new(&self.service, user)
+    }
+
+    fn get_index(&self) -> keyring::Result<Vec<CableKnownDeviceId>> {
+        match self.entry(INDEX_USER)?.get_secret() {
+            Ok(index) => Ok(cbor::from_slice(&index).unwrap_or_else(|err| {
+                warn!(?err, "Ignoring corrupt known-device index");
+                vec![]
+            })),
+            Err(keyring::Error::NoEntry) => Ok(vec![]),
+            Err(err) => Err(err),
+        }
+    }
+
+    fn set_index(&self, index: &[CableKnownDeviceId]) -> keyring::Result<()> {
+        let index = cbor::to_vec(&index).expect("Device IDs serialize");
+        self.entry(INDEX_USER)?.set_secret(&index)
+    }
+
+    /// Applies `update` to the index, writing it back if `update` returns true.
+    fn update_index<F>(&self, update: F) -> keyring::Result<()>
+    where
+        F: FnOnce(&mut Vec<CableKnownDeviceId>) -> bool,
+    {
+        let _guard = INDEX_LOCK.lock().unwrap_or_else(PoisonError::into_inner);
+        let mut index = self.get_index()?;
+        if update(&mut index) {
+            self.set_index(&index)?;
+        }
+        Ok(())
+    }
+
+    fn put(
+        &self,
+        device_id: &CableKnownDeviceId,
+        device: &CableKnownDeviceInfo,
+    ) -> keyring::Result<()> {
+        let serialized = Zeroizing::new(
+            cbor::to_vec(&StoredDeviceInfo::from(device)).expect("Device info serializes"),
+        );
+        self.entry(device_id)?.set_secret(&serialized)?;
+
+        self.update_index(|index| {
+            if index.contains(device_id) {
+                return false;
+            }
+            index.push(device_id.clone());
+            true
+        })
+    }
+
+    fn delete(&self, device_id: &CableKnownDeviceId) -> keyring::Result<()> {
+        match self.entry(device_id)?.delete_credential() {
+            Ok(()) | Err(keyring::Error::NoEntry) => (),
+            Err(err) => return Err(err),
+        }
+
+        self.update_index(|index| {
+            let len = index.len();
+            index.retain(|id| id != device_id);
+            index.len() != len
+        })
+    }
+
+    fn list(&self) -> keyring::Result<Vec<(CableKnownDeviceId, CableKnownDeviceInfo)>> {
+        let mut devices = vec![];
+        for device_id in self.get_index()? {
+            let serialized = match self.entry(&device_id)?.get_secret() {
+                Ok(serialized) => Zeroizing::new(serialized),
+                Err(keyring::Error::NoEntry) => {
+                    warn!(?device_id, "Known device missing from keyring, skipping");
+                    continue;
+                }
+                Err(err) => return Err(err),
+            };
+            let device = cbor::from_slice::<StoredDeviceInfo>(&serialized)
+                .ok()
+                .and_then(|stored| CableKnownDeviceInfo::try_from(stored).ok());
+            match device {
+                Some(device) => devices.push((device_id, device)),
+                None => warn!(?device_id, "Ignoring corrupt known device"),
+            }
+        }
+        Ok(devices)
+    }
+}
+
+#[async_trait]
+impl CableKnownDeviceInfoStore for KeyringDeviceInfoStore {
+    async fn put_known_device(
+        &self,
+        device_id: &CableKnownDeviceId,
+        device: &CableKnownDeviceInfo,
+    ) {
+        debug!(?device_id, "Inserting or updating known device in keyring");
+        trace!(?device);
+        let (store, device_id, device) = (self.clone(), device_id.clone(), device.clone());
+        // Keyring backends block, e.g. on D-Bus calls.
+        match task::spawn_blocking(move || store.put(&device_id, &device)).await {
+            Ok(Ok(())) => (),
+            Ok(Err(err)) => error!(?err, "Failed to store known device in keyring"),
+            Err(err) => error!(?err, "Keyring task failed"),
+        }
+    }
+
+    async fn delete_known_device(&self, device_id: &CableKnownDeviceId) {
+        debug!(?device_id, "Deleting known device from keyring");
+        let (store, device_id) = (self.clone(), device_id.clone());
+        match task::spawn_blocking(move || store.delete(&device_id)).await {
+            Ok(Ok(())) => (),
+            Ok(Err(err)) => error!(?err, "Failed to delete known device from keyring"),
+            Err(err) => error!(?err, "Keyring task failed"),
+        }
+    }
+
+    async fn list_known_devices(&self) -> Vec<(CableKnownDeviceId, CableKnownDeviceInfo)> {
+        let store = self.clone();
+        match task::spawn_blocking(move || store.list()).await {
+            Ok(Ok(devices)) => devices,
+            Ok(Err(err)) => {
+                error!(?err, "Failed to list known devices in keyring");
+                vec![]
+            }
+            Err(err) => {
+                error!(?err, "Keyring task failed");
+                vec![]
+            }
+        }
+    }
+}
+
+/// The keyring representation of [CableKnownDeviceInfo].
+#[derive(Serialize, Deserialize)]
+struct StoredDeviceInfo {
+    contact_id: ByteBuf,
+    link_id: ByteBuf,
+    link_secret: ByteBuf,
+    public_key: ByteBuf,
+    name: String,
+    tunnel_domain: String,
+}
+
+impl From<&CableKnownDeviceInfo> for StoredDeviceInfo {
+    fn from(device: &CableKnownDeviceInfo) -> Self {
+        Self {
+            contact_id: ByteBuf::from(device.contact_id.clone()),
+            link_id: ByteBuf::from(device.link_id.to_vec()),
+            link_secret: ByteBuf::from(device.link_secret.to_vec()),
+            public_key: ByteBuf::from(device.public_key.to_vec()),
+            name: device.name.clone(),
+            tunnel_domain: device.tunnel_domain.clone(),
+        }
+    }
+}
+
+impl TryFrom<StoredDeviceInfo> for CableKnownDeviceInfo {
+    type Error = ();
+
+    fn try_from(stored: StoredDeviceInfo) -> Result<Self, ()> {
+        Ok(Self {
+            contact_id: stored.contact_id.to_vec(),
+            link_id: stored.link_id.as_slice().try_into().map_err(|_| ())?,
+            link_secret: stored.link_secret.as_slice().try_into().map_err(|_| ())?,
+            public_key: stored.public_key.as_slice().try_into().map_err(|_| ())?,
+            name: stored.name.clone(),
+            tunnel_domain: stored.tunnel_domain.clone(),
+        })
+    }
+}
+
+impl Drop for StoredDeviceInfo {
+    fn drop(&mut self) {
+        self.link_secret.as_mut_slice().zeroize();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::StoredDeviceInfo;
+    use crate::proto::ctap2::cbor;
+    use crate::transport::cable::known_devices::CableKnownDeviceInfo;
+
+    #[test]
+    fn stored_device_info_roundtrips() {
+        let device = CableKnownDeviceInfo {
+            contact_id: vec![1, 2, 3],
+            link_id: [4; 8],
+            link_secret: [5; 32],
+            public_key: [6; 65],
+            name: "Phone".to_owned(),
+            tunnel_domain: "cable.ua5v.com".to_owned(),
+        };
+        let serialized = cbor::to_vec(&StoredDeviceInfo::from(&device)).unwrap();
+        let stored: StoredDeviceInfo = cbor::from_slice(&serialized).unwrap();
+        let parsed = CableKnownDeviceInfo::try_from(stored).unwrap();
+        assert_eq!(parsed.contact_id, device.contact_id);
+        assert_eq!(parsed.link_id, device.link_id);
+        assert_eq!(parsed.link_secret, device.link_secret);
+        assert_eq!(parsed.public_key, device.public_key);
+        assert_eq!(parsed.name, device.name);
+        assert_eq!(parsed.tunnel_domain, device.tunnel_domain);
+    }
+}
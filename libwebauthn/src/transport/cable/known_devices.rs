@@ -1,15 +1,22 @@
 use std::collections::HashMap;
 use std::fmt::{Debug, Display};
 use std::sync::Arc;
-
-use crate::transport::cable::channel::ConnectionState;
+use std::time::Duration;
+
+use crate::metrics::MetricsRecorder;
+use crate::ops::webauthn::{GetAssertionRequest, MakeCredentialRequest, ResidentKeyRequirement};
+use crate::pin::PinUvAuthProtocols;
+use crate::sources;
+use crate::timeout::TimeoutPolicy;
+use crate::transport::cable::channel::{CableLinkingStatus, ConnectionState};
+use crate::transport::cable::channel::{CableUpdate, CableUxUpdate};
 use crate::transport::cable::connection_stages::{
-    connection_stage, handshake_stage, proximity_check_stage, ConnectionInput, HandshakeInput,
-    HandshakeOutput, MpscUxUpdateSender, ProximityCheckInput, TunnelConnectionInput,
-    UxUpdateSender,
+    connection_stage, handshake_stage, proximity_check_stage, ConnectionInput, ConnectionOutput,
+    DiscardingUxUpdateSender, HandshakeInput, HandshakeOutput, MpscUxUpdateSender,
+    ProximityCheckInput, TunnelConnectionInput, UxUpdateSender,
 };
 
-use crate::transport::error::TransportError;
+use crate::transport::error::{CableStage, TransportError};
 use crate::transport::Device;
 use crate::webauthn::error::Error;
 
@@ -18,12 +25,19 @@ use futures::lock::Mutex;
 use serde::Serialize;
 use serde_bytes::ByteBuf;
 use serde_indexed::SerializeIndexed;
-use tokio::sync::{broadcast, mpsc, watch};
-use tokio::task;
-use tracing::{debug, instrument, trace};
-
-use super::channel::CableChannel;
-use super::tunnel::{self, CableLinkingInfo};
+use tokio::runtime::Handle;
+use tokio::sync::{broadcast, mpsc, oneshot, watch};
+use tokio::task::{self, JoinHandle};
+use tokio::time::Instant;
+use tracing::{debug, instrument, trace, warn};
+use zeroize::Zeroize;
+
+use super::advertisement::{AdvertScanner, BtleplugScanner};
+use super::channel::{CableChannel, PendingHint};
+use super::tunnel::{
+    self, CableLinkingInfo, NoiseHandshake, TunnelConnector, TunnelDomains, TunnelHandshake,
+    WssTunnelConnector,
+};
 use super::Cable;
 
 #[async_trait]
@@ -32,6 +46,10 @@ pub trait CableKnownDeviceInfoStore: Debug + Send + Sync {
     async fn put_known_device(&self, device_id: &CableKnownDeviceId, device: &CableKnownDeviceInfo);
     /// Called whenever a known device becomes permanently unavailable.
     async fn delete_known_device(&self, device_id: &CableKnownDeviceId);
+    /// All known devices, for discovery. Stores which can't be enumerated return none.
+    async fn list_known_devices(&self) -> Vec<(CableKnownDeviceId, CableKnownDeviceInfo)> {
+        vec![]
+    }
 }
 
 /// An in-memory store for testing purposes.
@@ -77,10 +95,18 @@ impl CableKnownDeviceInfoStore for EphemeralDeviceInfoStore {
         let mut known_devices = self.known_devices.lock().await;
         known_devices.remove(device_id);
     }
+
+    async fn list_known_devices(&self) -> Vec<(CableKnownDeviceId, CableKnownDeviceInfo)> {
+        self.list_all().await
+    }
 }
 
 pub type CableKnownDeviceId = String;
 
+/// Age after which a pre-established tunnel connection is no longer used, as the authenticator
+/// may have stopped waiting for the handshake.
+const PRECONNECTION_MAX_AGE: Duration = Duration::from_secs(30);
+
 #[derive(Debug, Clone)]
 pub struct CableKnownDeviceInfo {
     pub contact_id: Vec<u8>,
@@ -91,6 +117,12 @@ pub struct CableKnownDeviceInfo {
     pub tunnel_domain: String,
 }
 
+impl Drop for CableKnownDeviceInfo {
+    fn drop(&mut self) {
+        self.link_secret.zeroize();
+    }
+}
+
 impl From<&CableLinkingInfo> for CableKnownDeviceId {
     fn from(linking_info: &CableLinkingInfo) -> Self {
         hex::encode(&linking_info.authenticator_public_key)
@@ -128,6 +160,27 @@ pub struct CableKnownDevice {
     pub hint: ClientPayloadHint,
     pub device_info: CableKnownDeviceInfo,
     pub(crate) store: Arc<dyn CableKnownDeviceInfoStore>,
+    pub(crate) advert_scanner: Arc<dyn AdvertScanner>,
+    pub(crate) tunnel_connector: Arc<dyn TunnelConnector>,
+    pub(crate) tunnel_handshake: Arc<dyn TunnelHandshake>,
+    pub(crate) tunnel_domains: TunnelDomains,
+    pub(crate) metrics_recorder: Option<Arc<dyn MetricsRecorder>>,
+    preconnection: Arc<std::sync::Mutex<Option<Preconnection>>>,
+}
+
+/// A tunnel connection opened ahead of `channel()`, see [CableKnownDevice::preconnect].
+#[derive(Debug)]
+struct Preconnection {
+    client_nonce: ClientNonce,
+    hint: ClientPayloadHint,
+    started: Instant,
+    connection: JoinHandle<Result<ConnectionOutput, TransportError>>,
+}
+
+impl Drop for Preconnection {
+    fn drop(&mut self) {
+        self.connection.abort();
+    }
 }
 
 impl Display for CableKnownDevice {
@@ -154,30 +207,139 @@ impl CableKnownDevice {
             hint,
             device_info: device_info.clone(),
             store: store,
+            advert_scanner: Arc::new(BtleplugScanner),
+            tunnel_connector: Arc::new(WssTunnelConnector),
+            tunnel_handshake: Arc::new(NoiseHandshake),
+            tunnel_domains: TunnelDomains::default(),
+            metrics_recorder: None,
+            preconnection: Arc::default(),
         };
         Ok(device)
     }
 
+    /// Replaces the BLE stack used to scan for the device's advert.
+    pub fn with_advert_scanner(mut self, advert_scanner: Arc<dyn AdvertScanner>) -> Self {
+        self.advert_scanner = advert_scanner;
+        self
+    }
+
+    /// Replaces how WebSocket connections to the tunnel server are opened.
+    pub fn with_tunnel_connector(mut self, tunnel_connector: Arc<dyn TunnelConnector>) -> Self {
+        self.tunnel_connector = tunnel_connector;
+        self
+    }
+
+    /// Replaces the handshake performed over the tunnel.
+    pub fn with_tunnel_handshake(mut self, tunnel_handshake: Arc<dyn TunnelHandshake>) -> Self {
+        self.tunnel_handshake = tunnel_handshake;
+        self
+    }
+
+    /// Replaces the tunnel server domains known in addition to the assigned ones.
+    pub fn with_tunnel_domains(mut self, tunnel_domains: TunnelDomains) -> Self {
+        self.tunnel_domains = tunnel_domains;
+        self
+    }
+
+    /// Reports tunnel connect times to `recorder`, which channels to this device also start with.
+    pub fn with_metrics_recorder(mut self, recorder: Arc<dyn MetricsRecorder>) -> Self {
+        self.metrics_recorder = Some(recorder);
+        self
+    }
+
+    /// Connects to the tunnel server right away, which wakes up the authenticator, e.g. as soon
+    /// as a UI offers to use this device. The next `channel()` call continues on this
+    /// connection, if it is less than [PRECONNECTION_MAX_AGE] old and its first request is for
+    /// the operation in the device's `hint`, saving the latency of contacting the authenticator.
+    ///
+    /// The connection is made on `runtime`, so that this can be called from outside of one, e.g.
+    /// from a UI thread. Within a runtime, pass [Handle::current].
+    pub fn preconnect(&self, runtime: &Handle) {
+        let client_nonce: ClientNonce = sources::random();
+        let connection_input = ConnectionInput::new_for_known_device(self, &client_nonce);
+        debug!(?self.device_info.tunnel_domain, "Pre-connecting to tunnel server");
+        let connection = runtime.spawn(sources::inherit(async move {
+            connection_stage(connection_input, &DiscardingUxUpdateSender).await
+        }));
+        let preconnection = Preconnection {
+            client_nonce,
+            hint: self.hint,
+            started: Instant::now(),
+            connection,
+        };
+        *self.preconnection.lock().unwrap() = Some(preconnection);
+    }
+
+    /// Waits for the pre-established connection, if there is a recent one made with `hint`.
+    async fn take_preconnection(
+        preconnection: Option<Preconnection>,
+        hint: ClientPayloadHint,
+    ) -> Option<(ClientNonce, ConnectionOutput)> {
+        let mut preconnection = preconnection?;
+        if preconnection.started.elapsed() > PRECONNECTION_MAX_AGE {
+            debug!("Discarding stale pre-established connection");
+            return None;
+        }
+        if preconnection.hint != hint {
+            // The authenticator already prepared its UI for the other operation.
+            debug!(?preconnection.hint, ?hint, "Discarding pre-connection with another hint");
+            return None;
+        }
+        match (&mut preconnection.connection).await {
+            Ok(Ok(connection_output)) => Some((preconnection.client_nonce, connection_output)),
+            Ok(Err(error)) => {
+                warn!(?error, "Pre-connecting failed, connecting again");
+                None
+            }
+            Err(error) => {
+                warn!(?error, "Pre-connecting task failed, connecting again");
+                None
+            }
+        }
+    }
+
     #[instrument(skip_all, err)]
     async fn connection(
         known_device: &CableKnownDevice,
+        preconnection: Option<Preconnection>,
         ux_sender: &super::connection_stages::MpscUxUpdateSender,
     ) -> Result<HandshakeOutput, TransportError> {
-        let client_nonce = rand::random::<ClientNonce>();
-
         // Stage 1: Connection (no proximity check needed for known devices)
-        let connection_input = ConnectionInput::new_for_known_device(known_device, &client_nonce);
-        let connection_output = connection_stage(connection_input.clone(), ux_sender).await?;
+        let preconnected = Self::take_preconnection(preconnection, known_device.hint).await;
+        let (client_nonce, connection_output) = match preconnected {
+            Some(preconnected) => {
+                debug!("Continuing on pre-established connection");
+                ux_sender
+                    .send_update(CableUxUpdate::CableUpdate(CableUpdate::TunnelConnected {
+                        elapsed: ux_sender.elapsed(),
+                    }))
+                    .await;
+                preconnected
+            }
+            None => {
+                let client_nonce: ClientNonce = sources::random();
+                let connection_input =
+                    ConnectionInput::new_for_known_device(known_device, &client_nonce);
+                let connection_output = connection_stage(connection_input, ux_sender)
+                    .await
+                    .map_err(|e| e.at_cable_stage(CableStage::Connection))?;
+                (client_nonce, connection_output)
+            }
+        };
 
         // Stage 2: Proximity check (after connection for known devices)
         let proximity_input =
             ProximityCheckInput::new_for_known_device(known_device, &client_nonce);
-        let proximity_output = proximity_check_stage(proximity_input, ux_sender).await?;
+        let proximity_output = proximity_check_stage(proximity_input, ux_sender)
+            .await
+            .map_err(|e| e.at_cable_stage(CableStage::ProximityCheck))?;
 
         // Stage 3: Handshake
         let handshake_input =
             HandshakeInput::new_for_known_device(known_device, connection_output, proximity_output);
-        let handshake_output = handshake_stage(handshake_input, ux_sender).await?;
+        let handshake_output = handshake_stage(handshake_input, ux_sender)
+            .await
+            .map_err(|e| e.at_cable_stage(CableStage::Handshake))?;
 
         Ok(handshake_output)
     }
@@ -193,34 +355,45 @@ impl<'d> Device<'d, Cable, CableChannel> for CableKnownDevice {
         let (cbor_rx_send, cbor_rx_recv) = mpsc::channel(16);
         let (connection_state_sender, connection_state_receiver) =
             watch::channel(ConnectionState::Connecting);
+        let (linking_status_sender, linking_status_receiver) =
+            watch::channel(CableLinkingStatus::default());
+
+        let (hint_sender, hint_receiver) = oneshot::channel();
 
         let ux_update_sender_clone = ux_update_sender.clone();
-        let known_device: CableKnownDevice = self.clone();
+        let mut known_device: CableKnownDevice = self.clone();
+        let preconnection = self.preconnection.lock().unwrap().take();
 
-        let handle_connection = task::spawn(async move {
+        let handle_connection = task::spawn(sources::inherit(async move {
             let ux_sender =
                 MpscUxUpdateSender::new(ux_update_sender_clone, connection_state_sender);
-
-            let handshake_output = match Self::connection(&known_device, &ux_sender).await {
-                Ok(handshake_output) => handshake_output,
-                Err(e) => {
-                    ux_sender.send_error(e).await;
-                    return;
-                }
-            };
+            // The authenticator is only contacted once the first request tells what it's for.
+            if let Ok(hint) = hint_receiver.await {
+                known_device.hint = hint;
+            }
+
+            let handshake_output =
+                match Self::connection(&known_device, preconnection, &ux_sender).await {
+                    Ok(handshake_output) => handshake_output,
+                    Err(e) => {
+                        ux_sender.send_error(e).await;
+                        return;
+                    }
+                };
 
             let tunnel_input = TunnelConnectionInput::from_handshake_output(
                 handshake_output,
                 Some(known_device.store),
                 cbor_tx_recv,
                 cbor_rx_send,
+                linking_status_sender,
             );
 
-            tunnel::connection(tunnel_input).await;
+            tunnel::connection(tunnel_input, &ux_sender).await;
             ux_sender
                 .set_connection_state(ConnectionState::Terminated)
                 .await;
-        });
+        }));
 
         Ok(CableChannel {
             handle_connection,
@@ -228,6 +401,19 @@ impl<'d> Device<'d, Cable, CableChannel> for CableKnownDevice {
             cbor_receiver: cbor_rx_recv,
             ux_update_sender,
             connection_state_receiver,
+            linking_status_receiver,
+            pending_hint: Some(PendingHint {
+                sender: hint_sender,
+                default: self.hint,
+            }),
+            pin_provider: None,
+            cancellation_token: None,
+            timeout_policy: TimeoutPolicy::default(),
+            audit_trail: None,
+            metrics_recorder: self.metrics_recorder.clone(),
+            pin_uv_auth_protocols: PinUvAuthProtocols::default(),
+            auth_token_data: None,
+            aaguid: Default::default(),
         })
     }
 }
@@ -247,17 +433,92 @@ pub struct ClientPayload {
     pub hint: ClientPayloadHint,
 }
 
+/// Which operation the authenticator should prepare its UI for. Ceremonies set it from their
+/// request, see the `From` implementations.
 #[derive(Debug, Copy, Clone, Serialize, PartialEq)]
 pub enum ClientPayloadHint {
     #[serde(rename = "ga")]
     GetAssertion,
+    /// A makeCredential for a non-discoverable credential, or one that may be.
     #[serde(rename = "mc")]
     MakeCredential,
+    /// A makeCredential for a discoverable credential, i.e. a passkey.
+    #[serde(rename = "d.mc")]
+    DiscoverableMakeCredential,
+}
+
+impl From<&MakeCredentialRequest> for ClientPayloadHint {
+    fn from(request: &MakeCredentialRequest) -> Self {
+        match request.resident_key {
+            Some(ResidentKeyRequirement::Required | ResidentKeyRequirement::Preferred) => {
+                ClientPayloadHint::DiscoverableMakeCredential
+            }
+            Some(ResidentKeyRequirement::Discouraged) | None => ClientPayloadHint::MakeCredential,
+        }
+    }
+}
+
+impl From<&GetAssertionRequest> for ClientPayloadHint {
+    fn from(_: &GetAssertionRequest) -> Self {
+        ClientPayloadHint::GetAssertion
+    }
 }
 
 #[cfg(test)]
 mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use tokio::runtime::{Handle, Runtime};
+    use tokio::time::timeout;
+
+    use super::{
+        CableKnownDevice, CableKnownDeviceInfo, ClientPayloadHint, EphemeralDeviceInfoStore,
+    };
+    use crate::ops::webauthn::{
+        GetAssertionRequest, MakeCredentialRequest, ResidentKeyRequirement,
+    };
+    use crate::proto::ctap2::cbor;
+    use crate::transport::cable::advertisement::MockAdvertScanner;
+    use crate::transport::cable::channel::{CableUpdate, CableUxUpdate};
+    use crate::transport::cable::mock_tunnel::MockTunnelServer;
+    use crate::transport::cable::qr_code_device::QrCodeOperationHint;
     use crate::transport::cable::tunnel::KNOWN_TUNNEL_DOMAINS;
+    use crate::transport::{Channel, Device};
+
+    #[test]
+    fn hint_from_request() {
+        let mut request = MakeCredentialRequest::dummy();
+        request.resident_key = Some(ResidentKeyRequirement::Discouraged);
+        assert_eq!(
+            ClientPayloadHint::from(&request),
+            ClientPayloadHint::MakeCredential
+        );
+        request.resident_key = Some(ResidentKeyRequirement::Preferred);
+        assert_eq!(
+            ClientPayloadHint::from(&request),
+            ClientPayloadHint::DiscoverableMakeCredential
+        );
+        assert_eq!(
+            ClientPayloadHint::from(
+                &GetAssertionRequest::builder()
+                    .rp_id("example.org")
+                    .client_data_hash(&[0; 32])
+                    .build()
+                    .unwrap()
+            ),
+            ClientPayloadHint::GetAssertion
+        );
+
+        assert_eq!(
+            cbor::to_vec(&ClientPayloadHint::DiscoverableMakeCredential).unwrap(),
+            b"\x64d.mc"
+        );
+        assert_eq!(
+            QrCodeOperationHint::from(ClientPayloadHint::DiscoverableMakeCredential),
+            QrCodeOperationHint::MakeCredential
+        );
+    }
 
     #[test]
     fn known_tunnels_domains_count() {
@@ -266,4 +527,73 @@ mod tests {
             "KNOWN_TUNNEL_DOMAINS must be encoded as a single byte."
         )
     }
+
+    fn device_info() -> CableKnownDeviceInfo {
+        CableKnownDeviceInfo {
+            contact_id: vec![1, 2, 3],
+            link_id: [4; 8],
+            link_secret: [5; 32],
+            public_key: [6; 65],
+            name: "Phone".to_owned(),
+            tunnel_domain: KNOWN_TUNNEL_DOMAINS[0].to_owned(),
+        }
+    }
+
+    #[test]
+    fn preconnects_from_outside_runtime() {
+        let runtime = Runtime::new().unwrap();
+        let (server, device) = runtime.block_on(async {
+            let server = MockTunnelServer::start().await.unwrap();
+            let store = Arc::new(EphemeralDeviceInfoStore::default());
+            let device =
+                CableKnownDevice::new(ClientPayloadHint::GetAssertion, &device_info(), store)
+                    .await
+                    .unwrap()
+                    .with_tunnel_connector(server.connector());
+            (server, device)
+        });
+
+        device.preconnect(runtime.handle());
+        let contact = runtime.block_on(server.next_contact()).unwrap();
+        assert_eq!(
+            contact.contact_id,
+            base64_url::encode(&device_info().contact_id)
+        );
+    }
+
+    #[tokio::test]
+    async fn channel_reuses_preconnected_tunnel() {
+        let server = MockTunnelServer::start().await.unwrap();
+        let device_info = device_info();
+        let store = Arc::new(EphemeralDeviceInfoStore::default());
+        let mut device =
+            CableKnownDevice::new(ClientPayloadHint::GetAssertion, &device_info, store)
+                .await
+                .unwrap()
+                .with_tunnel_connector(server.connector())
+                // The phone never advertises, so the channel fails after connecting.
+                .with_advert_scanner(Arc::new(MockAdvertScanner::new(vec![])));
+
+        device.preconnect(&Handle::current());
+        let contact = server.next_contact().await.unwrap();
+        assert_eq!(
+            contact.contact_id,
+            base64_url::encode(&device_info.contact_id)
+        );
+
+        let mut channel = device.channel().await.unwrap();
+        let mut ux_updates = channel.get_ux_update_receiver();
+        channel.announce_operation(ClientPayloadHint::GetAssertion);
+        loop {
+            match ux_updates.recv().await.unwrap() {
+                CableUxUpdate::CableUpdate(CableUpdate::Connecting) => {
+                    panic!("Connected again despite pre-connecting")
+                }
+                CableUxUpdate::CableUpdate(CableUpdate::Error(_)) => break,
+                _ => (),
+            }
+        }
+        let contacted_again = timeout(Duration::from_millis(100), server.next_contact()).await;
+        assert!(contacted_again.is_err());
+    }
 }
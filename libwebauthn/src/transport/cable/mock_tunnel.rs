@@ -0,0 +1,509 @@
+//! An in-process stand-in for caBLE tunnel servers, so hybrid flows can be tested without
+//! network access or a phone.
+//!
+//! Like the actual servers, [MockTunnelServer] relays messages between an authenticator which
+//! opened a new tunnel and the platform connecting to it. Known devices, which the actual
+//! servers wake up through push notifications, are handed to the test through
+//! [MockTunnelServer::next_contact].
+//!
+//! [PlaintextHandshake] skips the Noise handshake and encryption, so that tests can serve such
+//! a contact without the authenticator's private key.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+use futures::{SinkExt, StreamExt};
+use sha2::{Digest, Sha256};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio_tungstenite::tungstenite::handshake::client::{Request, Response};
+use tokio_tungstenite::tungstenite::handshake::server;
+use tokio_tungstenite::tungstenite::http::StatusCode;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::{accept_hdr_async, connect_async, MaybeTlsStream};
+use tracing::{debug, error, warn};
+
+use super::tunnel::{
+    TunnelCipher, TunnelConnector, TunnelHandshake, TunnelHandshakePeer, TunnelStream,
+};
+use crate::transport::error::TransportError;
+
+/// Routing ID assigned to every new tunnel.
+const ROUTING_ID: [u8; 3] = [0x12, 0x34, 0x56];
+
+/// A platform contacting a known device through the tunnel server.
+#[derive(Debug)]
+pub struct MockContact {
+    pub contact_id: String,
+    /// The CBOR-encoded client payload, sent in the `X-caBLE-Client-Payload` header.
+    pub client_payload: Vec<u8>,
+    /// The platform's end of the tunnel, to be served by the test as the authenticator.
+    pub ws_stream: TunnelStream,
+}
+
+#[derive(Debug)]
+pub struct MockTunnelServer {
+    addr: SocketAddr,
+    contacts: futures::lock::Mutex<mpsc::UnboundedReceiver<MockContact>>,
+    server: JoinHandle<()>,
+}
+
+impl MockTunnelServer {
+    /// Starts listening on a local port.
+    pub async fn start() -> Result<Self, TransportError> {
+        let listener = TcpListener::bind("127.0.0.1:0")
+            .await
+            .map_err(|e| TransportError::IoError(e.kind()))?;
+        let addr = listener
+            .local_addr()
+            .map_err(|e| TransportError::IoError(e.kind()))?;
+        let (contacts_sender, contacts) = mpsc::unbounded_channel();
+        let server = tokio::spawn(run(listener, contacts_sender));
+        debug!(?addr, "Started mock tunnel server");
+        Ok(Self {
+            addr,
+            contacts: futures::lock::Mutex::new(contacts),
+            server,
+        })
+    }
+
+    /// Connects to this server, whatever tunnel server domain is requested.
+    pub fn connector(&self) -> Arc<dyn TunnelConnector> {
+        Arc::new(MockTunnelConnector { addr: self.addr })
+    }
+
+    /// Waits for the next platform contacting a known device.
+    pub async fn next_contact(&self) -> Option<MockContact> {
+        self.contacts.lock().await.recv().await
+    }
+}
+
+impl Drop for MockTunnelServer {
+    fn drop(&mut self) {
+        self.server.abort();
+    }
+}
+
+#[derive(Debug)]
+struct MockTunnelConnector {
+    addr: SocketAddr,
+}
+
+#[async_trait]
+impl TunnelConnector for MockTunnelConnector {
+    async fn open(&self, mut request: Request) -> Result<(TunnelStream, Response), TransportError> {
+        let path = request.uri().path().to_owned();
+        *request.uri_mut() = format!("ws://{}{}", self.addr, path)
+            .parse()
+            .or(Err(TransportError::InvalidEndpoint))?;
+        connect_async(request).await.map_err(|e| {
+            error!(?e, "Failed to connect to mock tunnel server");
+            TransportError::ConnectionFailed
+        })
+    }
+}
+
+#[derive(Debug)]
+enum Route {
+    New {
+        tunnel_id: String,
+    },
+    Connect {
+        tunnel_id: String,
+    },
+    Contact {
+        contact_id: String,
+        client_payload: Vec<u8>,
+    },
+}
+
+impl Route {
+    fn parse(request: &server::Request) -> Option<Self> {
+        let segments: Vec<&str> = request.uri().path().split('/').skip(1).collect();
+        match segments.as_slice() {
+            ["cable", "new", tunnel_id] => Some(Route::New {
+                tunnel_id: tunnel_id.to_string(),
+            }),
+            ["cable", "connect", _routing_id, tunnel_id] => Some(Route::Connect {
+                tunnel_id: tunnel_id.to_string(),
+            }),
+            ["cable", "contact", contact_id] => {
+                let client_payload = request
+                    .headers()
+                    .get("X-caBLE-Client-Payload")
+                    .and_then(|payload| hex::decode(payload.as_bytes()).ok())?;
+                Some(Route::Contact {
+                    contact_id: contact_id.to_string(),
+                    client_payload,
+                })
+            }
+            _ => None,
+        }
+    }
+}
+
+async fn run(listener: TcpListener, contacts: mpsc::UnboundedSender<MockContact>) {
+    // Authenticators waiting for the platform, by tunnel ID.
+    let tunnels: Arc<Mutex<HashMap<String, TunnelStream>>> = Arc::default();
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                error!(?e, "Mock tunnel server failed to accept connection");
+                return;
+            }
+        };
+        tokio::spawn(handle(stream, tunnels.clone(), contacts.clone()));
+    }
+}
+
+async fn handle(
+    stream: TcpStream,
+    tunnels: Arc<Mutex<HashMap<String, TunnelStream>>>,
+    contacts: mpsc::UnboundedSender<MockContact>,
+) {
+    let mut route = None;
+    let router = Router { route: &mut route };
+    let ws_stream = match accept_hdr_async(MaybeTlsStream::Plain(stream), router).await {
+        Ok(ws_stream) => ws_stream,
+        Err(e) => {
+            warn!(?e, "Mock tunnel server failed to accept WebSocket");
+            return;
+        }
+    };
+
+    match route.expect("Accepted routes are known") {
+        Route::New { tunnel_id } => {
+            debug!(?tunnel_id, "Authenticator opened a new tunnel");
+            tunnels.lock().unwrap().insert(tunnel_id, ws_stream);
+        }
+        Route::Connect { tunnel_id } => {
+            let Some(authenticator) = tunnels.lock().unwrap().remove(&tunnel_id) else {
+                warn!(?tunnel_id, "Platform connected to an unknown tunnel");
+                return;
+            };
+            debug!(?tunnel_id, "Platform connected, relaying tunnel");
+            relay(authenticator, ws_stream).await;
+        }
+        Route::Contact {
+            contact_id,
+            client_payload,
+        } => {
+            debug!(?contact_id, "Platform contacted known device");
+            let _ = contacts.send(MockContact {
+                contact_id,
+                client_payload,
+                ws_stream,
+            });
+        }
+    }
+}
+
+/// Parses the route of the WebSocket request, answering unknown ones with 404.
+struct Router<'a> {
+    route: &'a mut Option<Route>,
+}
+
+impl server::Callback for Router<'_> {
+    fn on_request(
+        self,
+        request: &server::Request,
+        mut response: server::Response,
+    ) -> Result<server::Response, server::ErrorResponse> {
+        *self.route = Route::parse(request);
+        let Some(route) = self.route else {
+            warn!(uri = ?request.uri(), "Mock tunnel server rejecting unknown route");
+            let mut not_found = server::ErrorResponse::new(None);
+            *not_found.status_mut() = StatusCode::NOT_FOUND;
+            return Err(not_found);
+        };
+        let headers = response.headers_mut();
+        if let Some(protocol) = request.headers().get("Sec-WebSocket-Protocol") {
+            headers.insert("Sec-WebSocket-Protocol", protocol.clone());
+        }
+        if let Route::New { .. } = route {
+            let routing_id = hex::encode(ROUTING_ID).parse().expect("Valid header value");
+            headers.insert("X-caBLE-Routing-ID", routing_id);
+        }
+        Ok(response)
+    }
+}
+
+/// Forwards messages both ways, until either side goes away.
+async fn relay(authenticator: TunnelStream, platform: TunnelStream) {
+    let (authenticator_sink, authenticator_stream) = authenticator.split();
+    let (platform_sink, platform_stream) = platform.split();
+    tokio::select! {
+        _ = authenticator_stream.forward(platform_sink) => (),
+        _ = platform_stream.forward(authenticator_sink) => (),
+    }
+}
+
+/// A stand-in for the Noise handshake: the platform sends a hash of the PSK, which the
+/// authenticator checks, and frames are then sent unencrypted.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PlaintextHandshake;
+
+#[async_trait]
+impl TunnelHandshake for PlaintextHandshake {
+    async fn initiate(
+        &self,
+        ws_stream: &mut TunnelStream,
+        psk: &[u8; 32],
+        _peer: TunnelHandshakePeer<'_>,
+    ) -> Result<Box<dyn TunnelCipher>, TransportError> {
+        let cipher = PlaintextCipher::new(psk);
+        let message = Message::Binary(cipher.handshake_hash.to_vec().into());
+        ws_stream.send(message).await.map_err(|e| {
+            error!(?e, "Failed to send plaintext handshake");
+            TransportError::ConnectionFailed
+        })?;
+        Ok(Box::new(cipher))
+    }
+
+    async fn respond(
+        &self,
+        ws_stream: &mut TunnelStream,
+        psk: &[u8; 32],
+        _qr_public_key: &[u8],
+    ) -> Result<Box<dyn TunnelCipher>, TransportError> {
+        let cipher = PlaintextCipher::new(psk);
+        match ws_stream.next().await {
+            Some(Ok(Message::Binary(hash))) if hash[..] == cipher.handshake_hash => {
+                Ok(Box::new(cipher))
+            }
+            message => {
+                warn!(?message, "Platform sent an unexpected plaintext handshake");
+                Err(TransportError::ConnectionFailed)
+            }
+        }
+    }
+}
+
+pub(crate) struct PlaintextCipher {
+    handshake_hash: [u8; 32],
+}
+
+impl PlaintextCipher {
+    pub(crate) fn new(psk: &[u8; 32]) -> Self {
+        Self {
+            handshake_hash: Sha256::digest(psk).into(),
+        }
+    }
+}
+
+impl TunnelCipher for PlaintextCipher {
+    fn encrypt(&mut self, plaintext: &[u8]) -> Result<Vec<u8>, TransportError> {
+        Ok(plaintext.to_vec())
+    }
+
+    fn decrypt(&mut self, ciphertext: &[u8]) -> Result<Vec<u8>, TransportError> {
+        Ok(ciphertext.to_vec())
+    }
+
+    fn handshake_hash(&self) -> &[u8] {
+        &self.handshake_hash
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::sync::Arc;
+
+    use async_trait::async_trait;
+    use serde_bytes::ByteBuf;
+    use tokio::sync::mpsc;
+    use uuid::Uuid;
+
+    use super::{MockTunnelServer, PlaintextHandshake};
+    use crate::ops::webauthn::{MakeCredentialRequest, ResidentKeyRequirement};
+    use crate::proto::ctap2::cbor::{self, Value};
+    use crate::transport::cable::advertisement::{AdvertScanner, MockAdvertScanner};
+    use crate::transport::cable::authenticator::{
+        advert_plaintext, serve_qr_code, CableAdvertiser,
+    };
+    use crate::transport::cable::connection_stages::derive_psk;
+    use crate::transport::cable::crypto::{derive, encrypt_advert, KeyPurpose};
+    use crate::transport::cable::known_devices::{
+        CableKnownDevice, CableKnownDeviceInfo, ClientPayload, ClientPayloadHint,
+        EphemeralDeviceInfoStore,
+    };
+    use crate::transport::cable::qr_code_device::{CableQrCodeDevice, QrCodeOperationHint};
+    use crate::transport::cable::tunnel::{
+        self, CableTunnelConnectionType, NoiseHandshake, TunnelHandshake, KNOWN_TUNNEL_DOMAINS,
+    };
+    use crate::transport::error::TransportError;
+    use crate::transport::local::VirtualDevice;
+    use crate::transport::Device;
+    use crate::webauthn::error::Error;
+    use crate::webauthn::WebAuthn;
+
+    struct ForwardingAdvertiser(mpsc::UnboundedSender<[u8; 20]>);
+
+    #[async_trait]
+    impl CableAdvertiser for ForwardingAdvertiser {
+        async fn start_advertising(&self, service_data: &[u8; 20]) -> Result<(), TransportError> {
+            let _ = self.0.send(*service_data);
+            Ok(())
+        }
+
+        async fn stop_advertising(&self) {}
+    }
+
+    /// Finds the adverts sent by the test, which may only know them once the platform is
+    /// scanning.
+    #[derive(Debug)]
+    struct ReceivingAdvertScanner(futures::lock::Mutex<mpsc::UnboundedReceiver<[u8; 20]>>);
+
+    #[async_trait]
+    impl AdvertScanner for ReceivingAdvertScanner {
+        async fn find_service_data(
+            &self,
+            _uuids: &[Uuid],
+            accept: &(dyn for<'a> Fn(&'a [u8]) -> bool + Send + Sync),
+        ) -> Result<Vec<u8>, TransportError> {
+            let mut adverts = self.0.lock().await;
+            while let Some(advert) = adverts.recv().await {
+                if accept(&advert) {
+                    return Ok(advert.to_vec());
+                }
+            }
+            Err(TransportError::TransportUnavailable)
+        }
+    }
+
+    #[tokio::test]
+    async fn qr_code_device_registers_through_mock_tunnel() {
+        let server = MockTunnelServer::start().await.unwrap();
+        let store = Arc::new(EphemeralDeviceInfoStore::default());
+        let device = CableQrCodeDevice::new_persistent(QrCodeOperationHint::MakeCredential, store)
+            .with_tunnel_connector(server.connector());
+        let qr_code = device.qr_code.clone();
+        let (advert_sender, mut adverts) = mpsc::unbounded_channel();
+        let advertiser = ForwardingAdvertiser(advert_sender);
+
+        let mut authenticator = VirtualDevice::new_virtual();
+        let mut served = authenticator.channel().await.unwrap();
+        let connector = server.connector();
+        let authenticator = serve_qr_code(
+            &qr_code,
+            &advertiser,
+            connector.as_ref(),
+            &NoiseHandshake,
+            &mut served,
+        );
+        let platform = async {
+            let advert = adverts.recv().await.unwrap();
+            let scanner = MockAdvertScanner::new(vec![advert.to_vec()]);
+            let mut device = device.with_advert_scanner(Arc::new(scanner));
+            let mut channel = device.channel().await.unwrap();
+            let response = channel
+                .webauthn_make_credential(&MakeCredentialRequest::dummy())
+                .await
+                .unwrap();
+            assert!(response.authenticator_data.attested_credential.is_some());
+        };
+        let (result, ()) = tokio::join!(authenticator, platform);
+        assert!(matches!(
+            result,
+            Ok(()) | Err(Error::Transport(TransportError::ConnectionLost))
+        ));
+    }
+
+    #[tokio::test]
+    async fn known_device_contact_reaches_test() {
+        let server = MockTunnelServer::start().await.unwrap();
+        let client_payload = ClientPayload {
+            link_id: ByteBuf::from(vec![1; 8]),
+            client_nonce: ByteBuf::from(vec![2; 16]),
+            hint: ClientPayloadHint::GetAssertion,
+        };
+        let connection_type = CableTunnelConnectionType::KnownDevice {
+            contact_id: "contact".to_owned(),
+            authenticator_public_key: vec![],
+            client_payload: client_payload.clone(),
+        };
+
+        let connector = server.connector();
+        tunnel::connect(connector.as_ref(), "cable.ua5v.com", &connection_type)
+            .await
+            .unwrap();
+        let contact = server.next_contact().await.unwrap();
+        assert_eq!(contact.contact_id, "contact");
+        assert_eq!(
+            contact.client_payload,
+            cbor::to_vec(&client_payload).unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn known_device_registers_through_mock_tunnel() {
+        let server = MockTunnelServer::start().await.unwrap();
+        let device_info = CableKnownDeviceInfo {
+            contact_id: vec![1, 2, 3],
+            link_id: [4; 8],
+            link_secret: [5; 32],
+            public_key: [6; 65],
+            name: "Phone".to_owned(),
+            tunnel_domain: KNOWN_TUNNEL_DOMAINS[0].to_owned(),
+        };
+        let (advert_sender, adverts) = mpsc::unbounded_channel();
+        let scanner = ReceivingAdvertScanner(futures::lock::Mutex::new(adverts));
+        let store = Arc::new(EphemeralDeviceInfoStore::default());
+        // The hint is derived from the request, rather than the device's.
+        let mut device =
+            CableKnownDevice::new(ClientPayloadHint::GetAssertion, &device_info, store)
+                .await
+                .unwrap()
+                .with_tunnel_connector(server.connector())
+                .with_tunnel_handshake(Arc::new(PlaintextHandshake))
+                .with_advert_scanner(Arc::new(scanner));
+
+        let mut authenticator = VirtualDevice::new_virtual();
+        let mut served = authenticator.channel().await.unwrap();
+        let authenticator = async {
+            let mut contact = server.next_contact().await.unwrap();
+            let client_payload: BTreeMap<u8, Value> =
+                cbor::from_slice(&contact.client_payload).unwrap();
+            let Some(Value::Bytes(client_nonce)) = client_payload.get(&0x02) else {
+                panic!("Client payload without a nonce: {client_payload:?}");
+            };
+            assert_eq!(
+                client_payload.get(&0x03),
+                Some(&Value::Text("d.mc".to_owned()))
+            );
+            let eid_key = derive(
+                &device_info.link_secret,
+                Some(client_nonce),
+                KeyPurpose::EIDKey,
+            );
+            let plaintext = advert_plaintext(&[0; 3], 0);
+            advert_sender
+                .send(encrypt_advert(&eid_key, &plaintext))
+                .unwrap();
+
+            let psk = derive_psk(&device_info.link_secret, &plaintext);
+            let cipher = PlaintextHandshake
+                .respond(&mut contact.ws_stream, &psk, &[])
+                .await
+                .unwrap();
+            tunnel::serve(contact.ws_stream, cipher, &mut served).await
+        };
+        let platform = async {
+            let mut channel = device.channel().await.unwrap();
+            let mut request = MakeCredentialRequest::dummy();
+            request.resident_key = Some(ResidentKeyRequirement::Required);
+            let response = channel.webauthn_make_credential(&request).await.unwrap();
+            assert!(response.authenticator_data.attested_credential.is_some());
+        };
+        let (result, ()) = tokio::join!(authenticator, platform);
+        assert!(matches!(
+            result,
+            Ok(()) | Err(Error::Transport(TransportError::ConnectionLost))
+        ));
+    }
+}
@@ -4,14 +4,20 @@ mod crypto;
 mod digit_encode;
 
 pub mod advertisement;
+pub mod authenticator;
 pub mod channel;
 pub mod connection_stages;
+#[cfg(feature = "keyring")]
+pub mod keyring_store;
 pub mod known_devices;
+#[cfg(any(test, feature = "testing"))]
+pub mod mock_tunnel;
+pub mod proxy;
 pub mod qr_code_device;
 pub mod tunnel;
 
 use super::Transport;
-pub use digit_encode::digit_encode;
+pub use digit_encode::{digit_decode, digit_encode};
 
 pub struct Cable {}
 impl Transport for Cable {}
@@ -1,31 +1,39 @@
 use std::fmt::{Debug, Display};
+use std::str::FromStr;
 use std::sync::Arc;
-use std::time::SystemTime;
 
 use async_trait::async_trait;
 use p256::elliptic_curve::sec1::ToEncodedPoint;
-use p256::{NonZeroScalar, SecretKey};
-use rand::rngs::OsRng;
-use rand::RngCore;
-use serde::Serialize;
+use p256::{NonZeroScalar, PublicKey, SecretKey};
+use serde::{Deserialize, Deserializer, Serialize};
 use serde_bytes::ByteArray;
-use serde_indexed::SerializeIndexed;
+use serde_indexed::{DeserializeIndexed, SerializeIndexed};
 use tokio::sync::{broadcast, mpsc, watch};
 use tokio::task;
-use tracing::instrument;
+use tracing::{instrument, warn};
 
+use super::advertisement::{AdvertScanner, BtleplugScanner};
+use super::channel::{CableChannel, CableLinkingStatus, ConnectionState};
 use super::connection_stages::{
     connection_stage, handshake_stage, proximity_check_stage, ConnectionInput, HandshakeInput,
     MpscUxUpdateSender, ProximityCheckInput, TunnelConnectionInput, UxUpdateSender,
 };
-use super::known_devices::CableKnownDeviceInfoStore;
-use super::tunnel::{self, KNOWN_TUNNEL_DOMAINS};
-use super::{channel::CableChannel, channel::ConnectionState, Cable};
+use super::crypto::{derive, KeyPurpose};
+use super::known_devices::{CableKnownDeviceInfoStore, ClientPayloadHint};
+use super::tunnel::{
+    self, NoiseHandshake, TunnelConnector, TunnelDomains, TunnelHandshake, WssTunnelConnector,
+    KNOWN_TUNNEL_DOMAINS,
+};
+use super::Cable;
+use crate::metrics::MetricsRecorder;
+use crate::pin::PinUvAuthProtocols;
 use crate::proto::ctap2::cbor;
-use crate::transport::cable::digit_encode;
+use crate::sources::{self, CurrentRng};
+use crate::timeout::TimeoutPolicy;
+use crate::transport::cable::{digit_decode, digit_encode};
 use crate::transport::Device;
 use crate::webauthn::error::Error;
-use crate::webauthn::TransportError;
+use crate::webauthn::{CableStage, TransportError};
 
 #[derive(Debug, Clone, Copy, Serialize, PartialEq)]
 pub enum QrCodeOperationHint {
@@ -35,7 +43,55 @@ pub enum QrCodeOperationHint {
     MakeCredential,
 }
 
-#[derive(Debug, Clone, SerializeIndexed)]
+impl<'de> Deserialize<'de> for QrCodeOperationHint {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        // Unknown hints are treated as "ga".
+        Ok(match String::deserialize(deserializer)?.as_str() {
+            "mc" => QrCodeOperationHint::MakeCredential,
+            _ => QrCodeOperationHint::GetAssertionRequest,
+        })
+    }
+}
+
+/// QR codes don't tell discoverable credentials apart: both are hinted as "mc", see
+/// [CableQrCode::supports_non_discoverable_mc].
+impl From<ClientPayloadHint> for QrCodeOperationHint {
+    fn from(hint: ClientPayloadHint) -> Self {
+        match hint {
+            ClientPayloadHint::GetAssertion => QrCodeOperationHint::GetAssertionRequest,
+            ClientPayloadHint::MakeCredential | ClientPayloadHint::DiscoverableMakeCredential => {
+                QrCodeOperationHint::MakeCredential
+            }
+        }
+    }
+}
+
+/// An "mc" hint may be for a non-discoverable credential.
+impl From<QrCodeOperationHint> for ClientPayloadHint {
+    fn from(hint: QrCodeOperationHint) -> Self {
+        match hint {
+            QrCodeOperationHint::GetAssertionRequest => ClientPayloadHint::GetAssertion,
+            QrCodeOperationHint::MakeCredential => ClientPayloadHint::MakeCredential,
+        }
+    }
+}
+
+#[derive(thiserror::Error, Debug, PartialEq)]
+pub enum QrCodeParseError {
+    #[error("Missing FIDO:/ prefix")]
+    MissingPrefix,
+    #[error("Invalid digit encoding")]
+    InvalidDigits,
+    #[error("Malformed QR code payload")]
+    Malformed,
+    #[error("Invalid public key")]
+    InvalidPublicKey,
+}
+
+#[derive(Debug, Clone, SerializeIndexed, DeserializeIndexed)]
 pub struct CableQrCode {
     // Key 0: a 33-byte, P-256, X9.62, compressed public key.
     #[serde(index = 0x00)]
@@ -68,15 +124,85 @@ pub struct CableQrCode {
     #[serde(index = 0x05)]
     pub operation_hint: QrCodeOperationHint,
 
+    /// Key 6: (optional) true if an "mc" hint may be for a non-discoverable credential. Without
+    ///   it, authenticators prepare for creating a discoverable credential, i.e. a passkey.
     #[serde(skip_serializing_if = "Option::is_none")]
     #[serde(index = 0x06)]
     pub supports_non_discoverable_mc: Option<bool>,
 }
 
-impl ToString for CableQrCode {
-    fn to_string(&self) -> String {
-        let serialized = cbor::to_vec(&self).unwrap();
-        format!("FIDO:/{}", digit_encode(&serialized))
+impl CableQrCode {
+    /// Generates a QR code with a fresh key pair and QR secret. The private key is needed to
+    /// complete the handshake with the authenticator scanning the QR code. `hint` is best
+    /// derived from the request, e.g. `ClientPayloadHint::from(&request)`.
+    pub fn generate(
+        hint: impl Into<ClientPayloadHint>,
+        state_assisted: bool,
+    ) -> (Self, NonZeroScalar) {
+        let hint = hint.into();
+        let private_key_scalar = NonZeroScalar::random(&mut CurrentRng);
+        let private_key = SecretKey::from_bytes(&private_key_scalar.to_bytes()).unwrap();
+        let public_key: [u8; 33] = private_key
+            .public_key()
+            .as_affine()
+            .to_encoded_point(true)
+            .as_bytes()
+            .try_into()
+            .unwrap();
+        let qr_secret: [u8; 16] = sources::random();
+
+        let current_unix_time = sources::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .inspect_err(|err| warn!(?err, "System clock is before the Unix epoch"))
+            .ok()
+            .map(|t| t.as_secs());
+
+        let qr_code = CableQrCode {
+            public_key: ByteArray::from(public_key),
+            qr_secret: ByteArray::from(qr_secret),
+            known_tunnel_domains_count: KNOWN_TUNNEL_DOMAINS.len() as u8,
+            current_time: current_unix_time,
+            operation_hint: hint.into(),
+            state_assisted: Some(state_assisted),
+            supports_non_discoverable_mc: match hint {
+                ClientPayloadHint::MakeCredential => Some(true),
+                _ => None,
+            },
+        };
+        (qr_code, private_key_scalar)
+    }
+
+    /// Key identifying the BLE advert of the authenticator that scanned this QR code. The first
+    /// 32 bytes decrypt the advert with AES-256, the last 32 authenticate it with HMAC-SHA256.
+    pub fn eid_key(&self) -> [u8; 64] {
+        derive(self.qr_secret.as_ref(), None, KeyPurpose::EIDKey)
+    }
+}
+
+/// The `FIDO:/` URI to be rendered as QR code.
+impl Display for CableQrCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let serialized = cbor::to_vec(self).map_err(|_| std::fmt::Error)?;
+        write!(f, "FIDO:/{}", digit_encode(&serialized))
+    }
+}
+
+/// Parses a scanned `FIDO:/` URI, as displayed by browsers and [CableQrCode]'s `Display`.
+impl FromStr for CableQrCode {
+    type Err = QrCodeParseError;
+
+    fn from_str(uri: &str) -> Result<Self, Self::Err> {
+        // The scheme is case-insensitive, and QR codes in alphanumeric mode are upper-case.
+        let digits = match uri.get(..6) {
+            Some(prefix) if prefix.eq_ignore_ascii_case("FIDO:/") => &uri[6..],
+            _ => return Err(QrCodeParseError::MissingPrefix),
+        };
+        let serialized = digit_decode(digits).ok_or(QrCodeParseError::InvalidDigits)?;
+        let qr_code: CableQrCode =
+            cbor::from_slice(&serialized).map_err(|_| QrCodeParseError::Malformed)?;
+        PublicKey::from_sec1_bytes(qr_code.public_key.as_ref())
+            .map_err(|_| QrCodeParseError::InvalidPublicKey)?;
+        Ok(qr_code)
     }
 }
 
@@ -90,6 +216,13 @@ pub struct CableQrCodeDevice {
     pub private_key: NonZeroScalar,
     /// An optional reference to the store. This may be None, if no persistence is desired.
     pub(crate) store: Option<Arc<dyn CableKnownDeviceInfoStore>>,
+    pub(crate) advert_scanner: Arc<dyn AdvertScanner>,
+    /// Whether linking info sent by the authenticator may be persisted to the store.
+    pub(crate) persist_linking_info: bool,
+    pub(crate) tunnel_connector: Arc<dyn TunnelConnector>,
+    pub(crate) tunnel_handshake: Arc<dyn TunnelHandshake>,
+    pub(crate) tunnel_domains: TunnelDomains,
+    pub(crate) metrics_recorder: Option<Arc<dyn MetricsRecorder>>,
 }
 
 impl Debug for CableQrCodeDevice {
@@ -97,6 +230,12 @@ impl Debug for CableQrCodeDevice {
         f.debug_struct("CableQrCodeDevice")
             .field("qr_code", &self.qr_code)
             .field("store", &self.store)
+            .field("advert_scanner", &self.advert_scanner)
+            .field("persist_linking_info", &self.persist_linking_info)
+            .field("tunnel_connector", &self.tunnel_connector)
+            .field("tunnel_handshake", &self.tunnel_handshake)
+            .field("tunnel_domains", &self.tunnel_domains)
+            .field("metrics_recorder", &self.metrics_recorder)
             .finish()
     }
 }
@@ -105,57 +244,74 @@ impl CableQrCodeDevice {
     /// Generates a QR code, linking the provided known-device store. A device scanning
     /// this QR code may be persisted to the store after a successful connection.
     pub fn new_persistent(
-        hint: QrCodeOperationHint,
+        hint: impl Into<ClientPayloadHint>,
         store: Arc<dyn CableKnownDeviceInfoStore>,
     ) -> Self {
         Self::new(hint, true, Some(store))
     }
 
     fn new(
-        hint: QrCodeOperationHint,
+        hint: impl Into<ClientPayloadHint>,
         state_assisted: bool,
         store: Option<Arc<dyn CableKnownDeviceInfoStore>>,
     ) -> Self {
-        let private_key_scalar = NonZeroScalar::random(&mut OsRng);
-        let private_key = SecretKey::from_bytes(&private_key_scalar.to_bytes()).unwrap();
-        let public_key: [u8; 33] = private_key
-            .public_key()
-            .as_affine()
-            .to_encoded_point(true)
-            .as_bytes()
-            .try_into()
-            .unwrap();
-        let mut qr_secret = [0u8; 16];
-        OsRng::default().fill_bytes(&mut qr_secret);
-
-        let current_unix_time = SystemTime::now()
-            .duration_since(SystemTime::UNIX_EPOCH)
-            .ok()
-            .map(|t| t.as_secs());
-
+        let (qr_code, private_key) = CableQrCode::generate(hint, state_assisted);
         Self {
-            qr_code: CableQrCode {
-                public_key: ByteArray::from(public_key),
-                qr_secret: ByteArray::from(qr_secret),
-                known_tunnel_domains_count: KNOWN_TUNNEL_DOMAINS.len() as u8,
-                current_time: current_unix_time,
-                operation_hint: hint,
-                state_assisted: Some(state_assisted),
-                supports_non_discoverable_mc: match hint {
-                    QrCodeOperationHint::MakeCredential => Some(true),
-                    _ => None,
-                },
-            },
-            private_key: private_key_scalar,
+            qr_code,
+            private_key,
             store,
+            advert_scanner: Arc::new(BtleplugScanner),
+            persist_linking_info: true,
+            tunnel_connector: Arc::new(WssTunnelConnector),
+            tunnel_handshake: Arc::new(NoiseHandshake),
+            tunnel_domains: TunnelDomains::default(),
+            metrics_recorder: None,
         }
     }
+
+    /// Neither asks the authenticator for linking info, nor persists any it sends anyway, so
+    /// that the connection stays ephemeral even though a known-device store is set.
+    pub fn without_linking(mut self) -> Self {
+        self.qr_code.state_assisted = Some(false);
+        self.persist_linking_info = false;
+        self
+    }
+
+    /// Replaces the BLE stack used to scan for the authenticator's advert.
+    pub fn with_advert_scanner(mut self, advert_scanner: Arc<dyn AdvertScanner>) -> Self {
+        self.advert_scanner = advert_scanner;
+        self
+    }
+
+    /// Replaces how WebSocket connections to the tunnel server are opened.
+    pub fn with_tunnel_connector(mut self, tunnel_connector: Arc<dyn TunnelConnector>) -> Self {
+        self.tunnel_connector = tunnel_connector;
+        self
+    }
+
+    /// Replaces the handshake performed over the tunnel.
+    pub fn with_tunnel_handshake(mut self, tunnel_handshake: Arc<dyn TunnelHandshake>) -> Self {
+        self.tunnel_handshake = tunnel_handshake;
+        self
+    }
+
+    /// Replaces the tunnel server domains known in addition to the assigned ones.
+    pub fn with_tunnel_domains(mut self, tunnel_domains: TunnelDomains) -> Self {
+        self.tunnel_domains = tunnel_domains;
+        self
+    }
+
+    /// Reports tunnel connect times to `recorder`, which channels to this device also start with.
+    pub fn with_metrics_recorder(mut self, recorder: Arc<dyn MetricsRecorder>) -> Self {
+        self.metrics_recorder = Some(recorder);
+        self
+    }
 }
 
 impl CableQrCodeDevice {
     /// Generates a QR code, without any known-device store. A device scanning this QR code
     /// will not be persisted.
-    pub fn new_transient(hint: QrCodeOperationHint) -> Self {
+    pub fn new_transient(hint: impl Into<ClientPayloadHint>) -> Self {
         Self::new(hint, false, None)
     }
 
@@ -166,16 +322,23 @@ impl CableQrCodeDevice {
     ) -> Result<super::connection_stages::HandshakeOutput, TransportError> {
         // Stage 1: Proximity check
         let proximity_input = ProximityCheckInput::new_for_qr_code(qr_device);
-        let proximity_output = proximity_check_stage(proximity_input, ux_sender).await?;
+        let proximity_output = proximity_check_stage(proximity_input, ux_sender)
+            .await
+            .map_err(|e| e.at_cable_stage(CableStage::ProximityCheck))?;
 
         // Stage 2: Connection
-        let connection_input = ConnectionInput::new_for_qr_code(qr_device, &proximity_output)?;
-        let connection_output = connection_stage(connection_input, ux_sender).await?;
+        let connection_input = ConnectionInput::new_for_qr_code(qr_device, &proximity_output)
+            .map_err(|e| e.at_cable_stage(CableStage::Connection))?;
+        let connection_output = connection_stage(connection_input, ux_sender)
+            .await
+            .map_err(|e| e.at_cable_stage(CableStage::Connection))?;
 
         // Stage 3: Handshake
         let handshake_input =
             HandshakeInput::new_for_qr_code(qr_device, connection_output, proximity_output);
-        let handshake_output = handshake_stage(handshake_input, ux_sender).await?;
+        let handshake_output = handshake_stage(handshake_input, ux_sender)
+            .await
+            .map_err(|e| e.at_cable_stage(CableStage::Handshake))?;
 
         Ok(handshake_output)
     }
@@ -199,11 +362,15 @@ impl<'d> Device<'d, Cable, CableChannel> for CableQrCodeDevice {
         let (cbor_rx_send, cbor_rx_recv) = mpsc::channel(16);
         let (connection_state_sender, connection_state_receiver) =
             watch::channel(ConnectionState::Connecting);
+        let (linking_status_sender, linking_status_receiver) = watch::channel(CableLinkingStatus {
+            requested: self.qr_code.state_assisted == Some(true),
+            ..Default::default()
+        });
 
         let ux_update_sender_clone = ux_update_sender.clone();
         let qr_device = self.clone();
 
-        let handle_connection = task::spawn(async move {
+        let handle_connection = task::spawn(sources::inherit(async move {
             let ux_sender =
                 MpscUxUpdateSender::new(ux_update_sender_clone.clone(), connection_state_sender);
 
@@ -215,18 +382,20 @@ impl<'d> Device<'d, Cable, CableChannel> for CableQrCodeDevice {
                 }
             };
 
+            let store = qr_device.store.filter(|_| qr_device.persist_linking_info);
             let tunnel_input = TunnelConnectionInput::from_handshake_output(
                 handshake_output,
-                qr_device.store,
+                store,
                 cbor_tx_recv,
                 cbor_rx_send,
+                linking_status_sender,
             );
-            tunnel::connection(tunnel_input).await;
+            tunnel::connection(tunnel_input, &ux_sender).await;
 
             ux_sender
                 .set_connection_state(ConnectionState::Terminated)
                 .await;
-        });
+        }));
 
         Ok(CableChannel {
             handle_connection,
@@ -234,6 +403,16 @@ impl<'d> Device<'d, Cable, CableChannel> for CableQrCodeDevice {
             cbor_receiver: cbor_rx_recv,
             ux_update_sender,
             connection_state_receiver,
+            linking_status_receiver,
+            pending_hint: None,
+            pin_provider: None,
+            cancellation_token: None,
+            timeout_policy: TimeoutPolicy::default(),
+            audit_trail: None,
+            metrics_recorder: self.metrics_recorder.clone(),
+            pin_uv_auth_protocols: PinUvAuthProtocols::default(),
+            auth_token_data: None,
+            aaguid: Default::default(),
         })
     }
 
@@ -245,3 +424,110 @@ impl<'d> Device<'d, Cable, CableChannel> for CableQrCodeDevice {
 
 // TODO: unit tests
 // https://source.chromium.org/chromium/chromium/src/+/main:device/fido/cable/v2_handshake_unittest.cc
+
+#[cfg(test)]
+mod tests {
+    use p256::elliptic_curve::sec1::ToEncodedPoint;
+    use p256::PublicKey;
+
+    use std::sync::Arc;
+
+    use super::{CableQrCode, CableQrCodeDevice, QrCodeOperationHint, QrCodeParseError};
+    use crate::proto::ctap2::cbor;
+    use crate::transport::cable::crypto::{derive, KeyPurpose};
+    use crate::transport::cable::digit_encode;
+    use crate::transport::cable::known_devices::{ClientPayloadHint, EphemeralDeviceInfoStore};
+
+    #[test]
+    fn generated_qr_code_matches_its_keys() {
+        let (qr_code, private_key) =
+            CableQrCode::generate(QrCodeOperationHint::MakeCredential, true);
+        let public_key = PublicKey::from_secret_scalar(&private_key);
+        assert_eq!(
+            qr_code.public_key.as_ref(),
+            public_key.as_affine().to_encoded_point(true).as_bytes()
+        );
+        assert_eq!(
+            qr_code.eid_key(),
+            derive(qr_code.qr_secret.as_ref(), None, KeyPurpose::EIDKey)
+        );
+
+        let uri = qr_code.to_string();
+        let digits = uri.strip_prefix("FIDO:/").unwrap();
+        assert!(digits.chars().all(|c| c.is_ascii_digit()));
+    }
+
+    #[test]
+    fn parses_generated_qr_code() {
+        let (qr_code, _) = CableQrCode::generate(QrCodeOperationHint::MakeCredential, true);
+        let parsed: CableQrCode = qr_code.to_string().parse().unwrap();
+        assert_eq!(parsed.public_key, qr_code.public_key);
+        assert_eq!(parsed.qr_secret, qr_code.qr_secret);
+        assert_eq!(
+            parsed.known_tunnel_domains_count,
+            qr_code.known_tunnel_domains_count
+        );
+        assert_eq!(parsed.current_time, qr_code.current_time);
+        assert_eq!(parsed.state_assisted, Some(true));
+        assert_eq!(parsed.operation_hint, QrCodeOperationHint::MakeCredential);
+        assert_eq!(parsed.supports_non_discoverable_mc, Some(true));
+
+        let lower_case = qr_code.to_string().replace("FIDO", "fido");
+        assert!(lower_case.parse::<CableQrCode>().is_ok());
+    }
+
+    #[test]
+    fn discoverable_make_credential_hint() {
+        let (qr_code, _) =
+            CableQrCode::generate(ClientPayloadHint::DiscoverableMakeCredential, true);
+        let parsed: CableQrCode = qr_code.to_string().parse().unwrap();
+        assert_eq!(parsed.operation_hint, QrCodeOperationHint::MakeCredential);
+        assert_eq!(parsed.supports_non_discoverable_mc, None);
+    }
+
+    #[test]
+    fn without_linking_stops_requesting_linking_info() {
+        let store = Arc::new(EphemeralDeviceInfoStore::default());
+        let device = CableQrCodeDevice::new_persistent(QrCodeOperationHint::MakeCredential, store)
+            .without_linking();
+        assert!(!device.persist_linking_info);
+        let parsed: CableQrCode = device.qr_code.to_string().parse().unwrap();
+        assert_eq!(parsed.state_assisted, Some(false));
+    }
+
+    #[test]
+    fn rejects_invalid_qr_codes() {
+        let (qr_code, _) = CableQrCode::generate(QrCodeOperationHint::GetAssertionRequest, false);
+        let uri = qr_code.to_string();
+        assert_eq!(
+            uri[6..].parse::<CableQrCode>().unwrap_err(),
+            QrCodeParseError::MissingPrefix
+        );
+        assert_eq!(
+            uri[..uri.len() - 1].parse::<CableQrCode>().unwrap_err(),
+            QrCodeParseError::InvalidDigits
+        );
+
+        let mut serialized = cbor::to_vec(&qr_code).unwrap();
+        // Truncates the QR secret.
+        let secret = serialized
+            .windows(17)
+            .position(|w| w[0] == 0x50 && &w[1..] == qr_code.qr_secret.as_ref())
+            .unwrap();
+        serialized[secret] = 0x4f;
+        serialized.remove(secret + 1);
+        let uri = format!("FIDO:/{}", digit_encode(&serialized));
+        assert_eq!(
+            uri.parse::<CableQrCode>().unwrap_err(),
+            QrCodeParseError::Malformed
+        );
+
+        let mut invalid_key = qr_code.clone();
+        // Uncompressed tag, but only 33 bytes long.
+        invalid_key.public_key = [0x04; 33].into();
+        assert_eq!(
+            invalid_key.to_string().parse::<CableQrCode>().unwrap_err(),
+            QrCodeParseError::InvalidPublicKey
+        );
+    }
+}
@@ -1,34 +1,45 @@
 use std::collections::BTreeMap;
+use std::fmt::Debug;
 use std::sync::Arc;
+use std::time::Duration;
 
+use async_trait::async_trait;
 use futures::{SinkExt, StreamExt};
 use hmac::{Hmac, Mac};
+use p256::elliptic_curve::sec1::ToEncodedPoint;
 use p256::{ecdh, NonZeroScalar};
 use p256::{PublicKey, SecretKey};
 use serde::Deserialize;
 use serde_bytes::ByteBuf;
 use serde_cbor_2 as serde_cbor;
-use serde_indexed::DeserializeIndexed;
+use serde_indexed::{DeserializeIndexed, SerializeIndexed};
 use sha2::{Digest, Sha256};
 use snow::{Builder, TransportState};
 use tokio::net::TcpStream;
 use tokio::sync::mpsc::Sender;
+use tokio_tungstenite::tungstenite::handshake::client::{Request, Response};
 use tokio_tungstenite::tungstenite::http::StatusCode;
 use tokio_tungstenite::tungstenite::Message;
 use tokio_tungstenite::{connect_async, MaybeTlsStream, WebSocketStream};
 use tracing::{debug, error, trace, warn};
 use tungstenite::client::IntoClientRequest;
+use zeroize::{Zeroize, Zeroizing};
 
 use super::known_devices::ClientPayload;
 use super::known_devices::{CableKnownDeviceInfo, CableKnownDeviceInfoStore};
+use super::proxy::ProxyTunnelConnector;
 use crate::proto::ctap2::cbor::{self, CborRequest, CborResponse, Value};
 use crate::proto::ctap2::{Ctap2CommandCode, Ctap2GetInfoResponse};
-use crate::transport::cable::connection_stages::TunnelConnectionInput;
+use crate::proto::CtapError;
+use crate::sources;
+use crate::transport::cable::channel::{CableFeatures, CableUpdate, CableUxUpdate};
+use crate::transport::cable::connection_stages::{TunnelConnectionInput, UxUpdateSender};
 use crate::transport::cable::known_devices::CableKnownDeviceId;
 use crate::transport::error::TransportError;
-use crate::webauthn::error::Error;
+use crate::transport::Channel;
+use crate::webauthn::error::{Error, PlatformError};
 
-fn ensure_rustls_crypto_provider() {
+pub(crate) fn ensure_rustls_crypto_provider() {
     use std::sync::Once;
     static RUSTLS_INIT: Once = Once::new();
     RUSTLS_INIT.call_once(|| {
@@ -43,6 +54,8 @@ const TLDS: &[&str] = &[".com", ".org", ".net", ".info"];
 const P256_X962_LENGTH: usize = 65;
 const MAX_CBOR_SIZE: usize = 1024 * 1024;
 const PADDING_GRANULARITY: usize = 32;
+/// Timeout for requests forwarded to the local authenticator, which may wait for the user.
+const SERVE_TIMEOUT: Duration = Duration::from_secs(120);
 
 const CABLE_PROLOGUE_STATE_ASSISTED: &[u8] = &[0u8];
 const CABLE_PROLOGUE_QR_INITIATED: &[u8] = &[1u8];
@@ -69,6 +82,7 @@ impl CableTunnelMessage {
             0 => CableTunnelMessageType::Shutdown,
             1 => CableTunnelMessageType::Ctap,
             2 => CableTunnelMessageType::Update,
+            3 => CableTunnelMessageType::Json,
             _ => {
                 return Err(Error::Transport(TransportError::InvalidFraming));
             }
@@ -89,7 +103,7 @@ impl CableTunnelMessage {
     }
 }
 
-#[derive(Clone, Debug, DeserializeIndexed)]
+#[derive(Clone, Debug, SerializeIndexed, DeserializeIndexed)]
 struct CableInitialMessage {
     #[serde(skip_serializing_if = "Option::is_none")]
     #[serde(index = 0x00)]
@@ -100,7 +114,7 @@ struct CableInitialMessage {
 
     #[serde(skip_serializing_if = "Option::is_none")]
     #[serde(index = 0x03)]
-    pub _supported_features: Option<Vec<String>>,
+    pub supported_features: Option<Vec<String>>,
 }
 
 #[derive(Clone, Debug)]
@@ -121,14 +135,50 @@ pub(crate) struct CableLinkingInfo {
     pub handshake_signature: Vec<u8>,
 }
 
+impl Drop for CableLinkingInfo {
+    fn drop(&mut self) {
+        self.link_secret.zeroize();
+    }
+}
+
 #[repr(u8)]
 #[derive(Debug, Clone, Copy, Deserialize)]
 enum CableTunnelMessageType {
     Shutdown = 0,
     Ctap = 1,
     Update = 2,
+    /// JSON-encoded digital credential requests and responses, if both sides support `"dc"`.
+    Json = 3,
+}
+
+/// Tunnel server domains in addition to the assigned ones, e.g. those of an enterprise tunnel
+/// deployment whose authenticators advertise them. Set on a device with `with_tunnel_domains`.
+#[derive(Debug, Clone, Default)]
+pub struct TunnelDomains {
+    custom: Vec<(u16, String)>,
 }
 
+impl TunnelDomains {
+    /// Maps an encoded value to `domain`, instead of the assigned or hash-derived one. Custom
+    /// domains are also tried as fallbacks when contacting known devices.
+    pub fn with_custom(mut self, encoded: u16, domain: &str) -> Self {
+        self.custom.retain(|(e, _)| *e != encoded);
+        self.custom.push((encoded, domain.to_owned()));
+        self
+    }
+
+    /// Decodes the tunnel server domain advertised by an authenticator, see
+    /// [decode_tunnel_server_domain].
+    pub fn decode(&self, encoded: u16) -> Option<String> {
+        match self.custom.iter().find(|(e, _)| *e == encoded) {
+            Some((_, domain)) => Some(domain.clone()),
+            None => decode_tunnel_server_domain(encoded),
+        }
+    }
+}
+
+/// Decodes the tunnel server domain advertised by an authenticator. Values below 256 index the
+/// assigned domains, larger ones are mapped to a domain derived from their hash.
 pub fn decode_tunnel_server_domain(encoded: u16) -> Option<String> {
     if encoded < 256 {
         if encoded as usize >= KNOWN_TUNNEL_DOMAINS.len() {
@@ -200,12 +250,47 @@ impl std::fmt::Debug for CableTunnelConnectionType {
     }
 }
 
-pub(crate) async fn connect<'d>(
-    tunnel_domain: &str,
-    connection_type: &CableTunnelConnectionType,
-) -> Result<WebSocketStream<MaybeTlsStream<TcpStream>>, TransportError> {
+/// A WebSocket connection to a tunnel server.
+pub type TunnelStream = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+/// Opens WebSocket connections to tunnel servers. [WssTunnelConnector] connects to the actual
+/// servers, while the `testing` feature's `MockTunnelServer` provides an in-process one.
+#[async_trait]
+pub trait TunnelConnector: Debug + Send + Sync {
+    /// Opens the WebSocket for `request`, returning it with the server's handshake response.
+    async fn open(&self, request: Request) -> Result<(TunnelStream, Response), TransportError>;
+}
+
+/// Connects to tunnel servers over TLS, trusting the native root certificates. Honors the
+/// proxy set in the environment, see [ProxyTunnelConnector::from_env].
+#[derive(Debug, Clone, Copy, Default)]
+pub struct WssTunnelConnector;
+
+#[async_trait]
+impl TunnelConnector for WssTunnelConnector {
+    async fn open(&self, request: Request) -> Result<(TunnelStream, Response), TransportError> {
+        match ProxyTunnelConnector::from_env() {
+            Some(proxy) => proxy.open(request).await,
+            None => connect_direct(request).await,
+        }
+    }
+}
+
+pub(crate) async fn connect_direct(
+    request: Request,
+) -> Result<(TunnelStream, Response), TransportError> {
     ensure_rustls_crypto_provider();
+    connect_async(request).await.map_err(|e| {
+        error!(?e, "Failed to connect to tunnel server");
+        TransportError::ConnectionFailed
+    })
+}
 
+pub(crate) async fn connect(
+    connector: &dyn TunnelConnector,
+    tunnel_domain: &str,
+    connection_type: &CableTunnelConnectionType,
+) -> Result<TunnelStream, TransportError> {
     let connect_url = match connection_type {
         CableTunnelConnectionType::QrCode {
             routing_id,
@@ -242,13 +327,51 @@ pub(crate) async fn connect<'d>(
     }
     trace!(?request);
 
-    let (ws_stream, response) = match connect_async(request).await {
-        Ok((ws_stream, response)) => (ws_stream, response),
-        Err(e) => {
-            error!(?e, "Failed to connect to tunnel server");
-            return Err(TransportError::ConnectionFailed);
-        }
+    let (ws_stream, _) = open(connector, request).await?;
+    Ok(ws_stream)
+}
+
+/// Connects to the tunnel server as the authenticator, returning the routing ID the server
+/// assigned to the tunnel. The platform connects to the same tunnel using this routing ID,
+/// which it learns from the BLE advert.
+pub(crate) async fn connect_new(
+    connector: &dyn TunnelConnector,
+    tunnel_domain: &str,
+    tunnel_id: &str,
+) -> Result<(TunnelStream, [u8; 3]), TransportError> {
+    let connect_url = format!("wss://{}/cable/new/{}", tunnel_domain, tunnel_id);
+    debug!(?connect_url, "Connecting to tunnel server");
+    let mut request = connect_url
+        .into_client_request()
+        .or(Err(TransportError::InvalidEndpoint))?;
+    request.headers_mut().insert(
+        "Sec-WebSocket-Protocol",
+        "fido.cable"
+            .parse()
+            .or(Err(TransportError::InvalidEndpoint))?,
+    );
+    trace!(?request);
+
+    let (ws_stream, response) = open(connector, request).await?;
+    let routing_id = response
+        .headers()
+        .get("X-caBLE-Routing-ID")
+        .and_then(|routing_id| hex::decode(routing_id.as_bytes()).ok())
+        .and_then(|routing_id| <[u8; 3]>::try_from(routing_id).ok());
+    let Some(routing_id) = routing_id else {
+        error!(?response, "Tunnel server did not assign a valid routing ID");
+        return Err(TransportError::ConnectionFailed);
     };
+    debug!(?routing_id, "Tunnel server assigned routing ID");
+
+    Ok((ws_stream, routing_id))
+}
+
+async fn open(
+    connector: &dyn TunnelConnector,
+    request: Request,
+) -> Result<(TunnelStream, Response), TransportError> {
+    let (ws_stream, response) = connector.open(request).await?;
     debug!(?response, "Connected to tunnel server");
 
     if response.status() != StatusCode::SWITCHING_PROTOCOLS {
@@ -257,36 +380,182 @@ pub(crate) async fn connect<'d>(
     }
     debug!("Tunnel server returned success");
 
-    Ok(ws_stream)
+    Ok((ws_stream, response))
 }
 
-pub(crate) struct TunnelNoiseState {
-    pub transport_state: TransportState,
-    #[allow(dead_code)]
-    pub handshake_hash: Vec<u8>,
+/// Encrypts and decrypts the frames sent over a tunnel, once the handshake completed.
+pub trait TunnelCipher: Send {
+    fn encrypt(&mut self, plaintext: &[u8]) -> Result<Vec<u8>, TransportError>;
+
+    fn decrypt(&mut self, ciphertext: &[u8]) -> Result<Vec<u8>, TransportError>;
+
+    /// The hash of the handshake, which linking information sent over the tunnel is bound to.
+    fn handshake_hash(&self) -> &[u8];
 }
 
-pub(crate) async fn do_handshake(
-    ws_stream: &mut WebSocketStream<MaybeTlsStream<TcpStream>>,
-    psk: [u8; 32],
-    connection_type: &CableTunnelConnectionType,
+/// The keys the platform knows when initiating the handshake.
+#[derive(Clone, Copy)]
+pub enum TunnelHandshakePeer<'a> {
+    /// A QR-initiated connection, with the private key of the QR code's public key.
+    QrCode { private_key: &'a NonZeroScalar },
+    /// A known device, with the authenticator's public key from its linking information.
+    KnownDevice { authenticator_public_key: &'a [u8] },
+}
+
+impl std::fmt::Debug for TunnelHandshakePeer<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::QrCode { .. } => f
+                .debug_struct("QrCode")
+                .field("private_key", &"[REDACTED]")
+                .finish(),
+            Self::KnownDevice {
+                authenticator_public_key,
+            } => f
+                .debug_struct("KnownDevice")
+                .field("authenticator_public_key", authenticator_public_key)
+                .finish(),
+        }
+    }
+}
+
+impl<'a> From<&'a CableTunnelConnectionType> for TunnelHandshakePeer<'a> {
+    fn from(connection_type: &'a CableTunnelConnectionType) -> Self {
+        match connection_type {
+            CableTunnelConnectionType::QrCode { private_key, .. } => Self::QrCode { private_key },
+            CableTunnelConnectionType::KnownDevice {
+                authenticator_public_key,
+                ..
+            } => Self::KnownDevice {
+                authenticator_public_key,
+            },
+        }
+    }
+}
+
+/// Performs the handshake over a newly opened tunnel. [NoiseHandshake] performs the one
+/// specified for caBLE, while the `testing` feature's `PlaintextHandshake` skips it, for tests
+/// of the surrounding state machines.
+#[async_trait]
+pub trait TunnelHandshake: Debug + Send + Sync {
+    /// Performs the handshake as the platform.
+    async fn initiate(
+        &self,
+        ws_stream: &mut TunnelStream,
+        psk: &[u8; 32],
+        peer: TunnelHandshakePeer<'_>,
+    ) -> Result<Box<dyn TunnelCipher>, TransportError>;
+
+    /// Performs the handshake as the authenticator, for a QR-initiated connection.
+    /// `qr_public_key` is the platform's public key from the QR code.
+    async fn respond(
+        &self,
+        ws_stream: &mut TunnelStream,
+        psk: &[u8; 32],
+        qr_public_key: &[u8],
+    ) -> Result<Box<dyn TunnelCipher>, TransportError>;
+}
+
+/// The Noise handshakes specified for caBLE: `KNpsk0` for QR-initiated connections, and
+/// `NKpsk0` for known devices.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NoiseHandshake;
+
+#[async_trait]
+impl TunnelHandshake for NoiseHandshake {
+    async fn initiate(
+        &self,
+        ws_stream: &mut TunnelStream,
+        psk: &[u8; 32],
+        peer: TunnelHandshakePeer<'_>,
+    ) -> Result<Box<dyn TunnelCipher>, TransportError> {
+        Ok(Box::new(do_handshake(ws_stream, psk, peer).await?))
+    }
+
+    async fn respond(
+        &self,
+        ws_stream: &mut TunnelStream,
+        psk: &[u8; 32],
+        qr_public_key: &[u8],
+    ) -> Result<Box<dyn TunnelCipher>, TransportError> {
+        Ok(Box::new(
+            do_responder_handshake(ws_stream, psk, qr_public_key).await?,
+        ))
+    }
+}
+
+struct TunnelNoiseState {
+    transport_state: TransportState,
+    handshake_hash: Vec<u8>,
+}
+
+impl TunnelCipher for TunnelNoiseState {
+    fn encrypt(&mut self, plaintext: &[u8]) -> Result<Vec<u8>, TransportError> {
+        let mut ciphertext = vec![0u8; MAX_CBOR_SIZE + 1];
+        match self
+            .transport_state
+            .write_message(plaintext, &mut ciphertext)
+        {
+            Ok(size) => {
+                ciphertext.truncate(size);
+                Ok(ciphertext)
+            }
+            Err(e) => {
+                error!(?e, "Failed to encrypt frame");
+                Err(TransportError::ConnectionFailed)
+            }
+        }
+    }
+
+    fn decrypt(&mut self, ciphertext: &[u8]) -> Result<Vec<u8>, TransportError> {
+        let mut plaintext = vec![0u8; MAX_CBOR_SIZE];
+        match self
+            .transport_state
+            .read_message(ciphertext, &mut plaintext)
+        {
+            Ok(size) => {
+                plaintext.truncate(size);
+                Ok(plaintext)
+            }
+            Err(e) => {
+                error!(?e, "Failed to decrypt frame");
+                Err(TransportError::ConnectionFailed)
+            }
+        }
+    }
+
+    fn handshake_hash(&self) -> &[u8] {
+        &self.handshake_hash
+    }
+}
+
+fn noise_builder(params: &str) -> Result<Builder<'static>, snow::Error> {
+    Ok(Builder::with_resolver(
+        params.parse()?,
+        sources::noise_resolver(),
+    ))
+}
+
+async fn do_handshake(
+    ws_stream: &mut TunnelStream,
+    psk: &[u8; 32],
+    peer: TunnelHandshakePeer<'_>,
 ) -> Result<TunnelNoiseState, TransportError> {
-    let noise_handshake = match connection_type {
-        CableTunnelConnectionType::QrCode { private_key, .. } => {
+    let noise_handshake = match peer {
+        TunnelHandshakePeer::QrCode { private_key } => {
             let local_private_key = private_key.to_owned().to_bytes();
-            Builder::new("Noise_KNpsk0_P256_AESGCM_SHA256".parse()?)
+            noise_builder("Noise_KNpsk0_P256_AESGCM_SHA256")?
                 .prologue(CABLE_PROLOGUE_QR_INITIATED)?
                 .local_private_key(local_private_key.as_slice())?
-                .psk(0, &psk)?
+                .psk(0, psk)?
                 .build_initiator()
         }
-        CableTunnelConnectionType::KnownDevice {
+        TunnelHandshakePeer::KnownDevice {
             authenticator_public_key,
-            ..
-        } => Builder::new("Noise_NKpsk0_P256_AESGCM_SHA256".parse()?)
+        } => noise_builder("Noise_NKpsk0_P256_AESGCM_SHA256")?
             .prologue(CABLE_PROLOGUE_STATE_ASSISTED)?
-            .remote_public_key(&authenticator_public_key)?
-            .psk(0, &psk)?
+            .remote_public_key(authenticator_public_key)?
+            .psk(0, psk)?
             .build_initiator(),
     };
 
@@ -355,9 +624,13 @@ pub(crate) async fn do_handshake(
     }
 
     let mut payload = [0u8; 1024];
-    let payload_len = noise_handshake
-        .read_message(&response, &mut payload)
-        .unwrap();
+    let payload_len = match noise_handshake.read_message(&response, &mut payload) {
+        Ok(payload_len) => payload_len,
+        Err(e) => {
+            error!(?e, "Failed to read handshake response");
+            return Err(TransportError::ConnectionFailed);
+        }
+    };
 
     debug!(
         { handshake = ?payload[..payload_len] },
@@ -375,10 +648,183 @@ pub(crate) async fn do_handshake(
     })
 }
 
-pub(crate) async fn connection(mut input: TunnelConnectionInput) {
+/// Performs the handshake as the authenticator, i.e. the Noise responder, for a QR-initiated
+/// connection. `qr_public_key` is the platform's public key from the QR code.
+async fn do_responder_handshake(
+    ws_stream: &mut TunnelStream,
+    psk: &[u8; 32],
+    qr_public_key: &[u8],
+) -> Result<TunnelNoiseState, TransportError> {
+    let Ok(qr_public_key) = PublicKey::from_sec1_bytes(qr_public_key) else {
+        error!("Failed to parse QR code public key");
+        return Err(TransportError::InvalidKey);
+    };
+    let remote_public_key = qr_public_key.to_encoded_point(false);
+    let noise_handshake = noise_builder("Noise_KNpsk0_P256_AESGCM_SHA256")?
+        .prologue(CABLE_PROLOGUE_QR_INITIATED)?
+        .remote_public_key(remote_public_key.as_bytes())?
+        .psk(0, psk)?
+        .build_responder();
+    let mut noise_handshake = match noise_handshake {
+        Ok(handshake) => handshake,
+        Err(e) => {
+            error!(?e, "Failed to build Noise handshake");
+            return Err(TransportError::ConnectionFailed);
+        }
+    };
+
+    let initial_msg = match ws_stream.next().await {
+        Some(Ok(Message::Binary(initial_msg))) => {
+            debug!(
+                initial_msg_len = initial_msg.len(),
+                "Received initial handshake message"
+            );
+            trace!(?initial_msg);
+            initial_msg
+        }
+        Some(Ok(msg)) => {
+            error!(?msg, "Unexpected message type received");
+            return Err(TransportError::ConnectionFailed);
+        }
+        Some(Err(e)) => {
+            error!(?e, "Failed to read initial handshake message");
+            return Err(TransportError::ConnectionFailed);
+        }
+        None => {
+            error!("Connection was closed before handshake was complete");
+            return Err(TransportError::ConnectionFailed);
+        }
+    };
+
+    let mut payload = [0u8; 1024];
+    if let Err(e) = noise_handshake.read_message(&initial_msg, &mut payload) {
+        error!(?e, "Failed to read initial handshake message");
+        return Err(TransportError::ConnectionFailed);
+    }
+
+    let mut response_buffer = vec![0u8; 1024];
+    let response_len = match noise_handshake.write_message(&[], &mut response_buffer) {
+        Ok(msg_len) => msg_len,
+        Err(e) => {
+            error!(?e, "Failed to write handshake response");
+            return Err(TransportError::ConnectionFailed);
+        }
+    };
+    response_buffer.truncate(response_len);
+    trace!(handshake = ?response_buffer, "Sending handshake response");
+
+    if let Err(e) = ws_stream
+        .send(Message::Binary(response_buffer.into()))
+        .await
+    {
+        error!(?e, "Failed to send handshake response");
+        return Err(TransportError::ConnectionFailed);
+    }
+    debug!("Sent handshake response");
+
+    if !noise_handshake.is_handshake_finished() {
+        error!("Handshake did not complete");
+        return Err(TransportError::ConnectionFailed);
+    }
+
+    Ok(TunnelNoiseState {
+        handshake_hash: noise_handshake.get_handshake_hash().to_vec(),
+        transport_state: noise_handshake.into_transport_mode()?,
+    })
+}
+
+/// Runs the authenticator side of an established tunnel: sends the initial message with the
+/// getInfo response of `channel`, then forwards every CTAP request received from the platform
+/// to `channel`, until the platform shuts the tunnel down.
+pub(crate) async fn serve<C: Channel>(
+    mut ws_stream: TunnelStream,
+    mut cipher: Box<dyn TunnelCipher>,
+    channel: &mut C,
+) -> Result<(), Error> {
+    let get_info = CborRequest::new(Ctap2CommandCode::AuthenticatorGetInfo);
+    channel.cbor_send(&get_info, SERVE_TIMEOUT).await?;
+    let get_info = channel.cbor_recv(SERVE_TIMEOUT).await?;
+    let (CtapError::Ok, Some(info)) = (get_info.status_code, get_info.data) else {
+        error!("Local authenticator failed to respond to GetInfo");
+        return Err(Error::Platform(PlatformError::InvalidDeviceResponse));
+    };
+    let initial_message = CableInitialMessage {
+        _padding: None,
+        info: ByteBuf::from(info),
+        supported_features: None,
+    };
+    let initial_message = cbor::to_vec(&initial_message)?;
+    send_encrypted(&pad(&initial_message), &mut ws_stream, cipher.as_mut()).await?;
+    debug!("Sent initial message");
+
+    loop {
+        let message = match ws_stream.next().await {
+            Some(Ok(Message::Close(close_frame))) => {
+                debug!(?close_frame, "Platform closed the tunnel");
+                return Ok(());
+            }
+            Some(Ok(message)) => message,
+            Some(Err(e)) => {
+                error!(?e, "Failed to read encrypted CBOR message");
+                return Err(Error::Transport(TransportError::ConnectionLost));
+            }
+            None => {
+                debug!("Tunnel closed");
+                return Ok(());
+            }
+        };
+        let Some(encrypted_frame) = connection_recv_binary_frame(message).await? else {
+            continue;
+        };
+        let decrypted_frame = decrypt_frame(encrypted_frame, cipher.as_mut()).await?;
+        let cable_message = CableTunnelMessage::from_slice(&decrypted_frame)?;
+        match cable_message.message_type {
+            CableTunnelMessageType::Shutdown => {
+                debug!("Platform shut down the tunnel");
+                return Ok(());
+            }
+            CableTunnelMessageType::Update | CableTunnelMessageType::Json => {
+                // No optional features are announced, so the platform may only send CTAP.
+                error!(
+                    message_type = ?cable_message.message_type,
+                    "Platform sent an unsupported message, closing the tunnel"
+                );
+                return Err(Error::Transport(TransportError::InvalidFraming));
+            }
+            CableTunnelMessageType::Ctap => {
+                let response = serve_request(channel, &cable_message.payload).await?;
+                let frame = CableTunnelMessage::new(CableTunnelMessageType::Ctap, &pad(&response));
+                send_encrypted(&frame.to_vec(), &mut ws_stream, cipher.as_mut()).await?;
+            }
+        }
+    }
+}
+
+async fn serve_request<C: Channel>(channel: &mut C, request: &[u8]) -> Result<Vec<u8>, Error> {
+    let Some((&command, data)) = request.split_first() else {
+        return Err(Error::Transport(TransportError::InvalidFraming));
+    };
+    let Ok(command) = Ctap2CommandCode::try_from(command) else {
+        warn!(command, "Unknown CTAP2 command");
+        return Ok(vec![CtapError::InvalidCommand.into()]);
+    };
+    debug!(?command, "Forwarding CBOR request");
+    let request = CborRequest {
+        command,
+        encoded_data: data.to_vec(),
+    };
+    channel.cbor_send(&request, SERVE_TIMEOUT).await?;
+    let response = channel.cbor_recv(SERVE_TIMEOUT).await?;
+
+    let mut serialized = vec![response.status_code.into()];
+    serialized.extend(response.data.unwrap_or_default());
+    Ok(serialized)
+}
+
+pub(crate) async fn connection(mut input: TunnelConnectionInput, ux_sender: &dyn UxUpdateSender) {
     // Fetch the inital message
-    let get_info_response_serialized: Vec<u8> = match input.ws_stream.next().await {
-        Some(Ok(message)) => match connection_recv_initial(message, &mut input.noise_state).await {
+    let (get_info_response_serialized, features) = match input.ws_stream.next().await {
+        Some(Ok(message)) => match connection_recv_initial(message, input.cipher.as_mut()).await {
             Ok(initial) => initial,
             Err(e) => {
                 error!(?e, "Failed to process initial message");
@@ -394,7 +840,19 @@ pub(crate) async fn connection(mut input: TunnelConnectionInput) {
             return;
         }
     };
-    debug!(?get_info_response_serialized, "Received initial message");
+    debug!(
+        ?get_info_response_serialized,
+        ?features,
+        "Received initial message"
+    );
+    ux_sender
+        .send_update(CableUxUpdate::CableUpdate(
+            CableUpdate::SessionEstablished {
+                elapsed: ux_sender.elapsed(),
+                features,
+            },
+        ))
+        .await;
 
     loop {
         // Wait for a message on ws_stream, or a request to send on cbor_rx_send
@@ -408,7 +866,22 @@ pub(crate) async fn connection(mut input: TunnelConnectionInput) {
                     Ok(message) => {
                         debug!("Received WSS message");
                         trace!(?message);
-                        let _ = connection_recv(&input.connection_type, &input.tunnel_domain, &input.known_device_store, message, &input.cbor_rx_send, &mut input.noise_state).await;
+                        let received = connection_recv(&input.connection_type, &input.tunnel_domain, &input.known_device_store, message, &input.cbor_rx_send, input.cipher.as_mut()).await;
+                        match received {
+                            Ok(Some(stored)) => {
+                                input.linking_status.send_modify(|status| {
+                                    status.received = true;
+                                    status.stored = stored;
+                                });
+                                let status = *input.linking_status.borrow();
+                                ux_sender.send_update(CableUxUpdate::CableUpdate(CableUpdate::Linking(status))).await;
+                            }
+                            Ok(None) => (),
+                            Err(error) => {
+                                error!(?error, "Closing tunnel after an invalid message");
+                                return;
+                            }
+                        }
                     }
                 };
             }
@@ -418,11 +891,18 @@ pub(crate) async fn connection(mut input: TunnelConnectionInput) {
                     Ctap2CommandCode::AuthenticatorGetInfo => {
                         debug!("Responding to GetInfo request with cached response");
                         let response = CborResponse::new_success_from_slice(&get_info_response_serialized);
-                        let _ = input.cbor_rx_send.send(response).await;
+                        if input.cbor_rx_send.send(response).await.is_err() {
+                            debug!("Receiver dropped, closing connection");
+                            return;
+                        }
                     }
                     _ => {
                         debug!(?request.command, "Sending CBOR request");
-                        let _ = connection_send(request, &mut input.ws_stream, &mut input.noise_state).await;
+                        if let Err(error) = connection_send(request, &mut input.ws_stream, input.cipher.as_mut()).await {
+                            // Closing, so that the pending request fails rather than times out.
+                            error!(?error, "Closing tunnel after failing to send a request");
+                            return;
+                        }
                     }
                 }
             }
@@ -437,8 +917,8 @@ pub(crate) async fn connection(mut input: TunnelConnectionInput) {
 
 async fn connection_send(
     request: CborRequest,
-    ws_stream: &mut WebSocketStream<MaybeTlsStream<TcpStream>>,
-    noise_state: &mut TunnelNoiseState,
+    ws_stream: &mut TunnelStream,
+    cipher: &mut dyn TunnelCipher,
 ) -> Result<(), Error> {
     debug!("Sending CBOR request");
     trace!(?request);
@@ -455,30 +935,61 @@ async fn connection_send(
     }
     trace!(?cbor_request, cbor_request_len = cbor_request.len());
 
-    let extra_bytes = PADDING_GRANULARITY - (cbor_request.len() % PADDING_GRANULARITY);
-    let padded_len = cbor_request.len() + extra_bytes;
+    let frame = CableTunnelMessage::new(CableTunnelMessageType::Ctap, &pad(&cbor_request));
+    send_encrypted(&frame.to_vec(), ws_stream, cipher).await
+}
 
-    let mut padded_cbor_request = cbor_request.clone();
-    padded_cbor_request.resize(padded_len, 0u8);
-    padded_cbor_request[padded_len - 1] = (extra_bytes - 1) as u8;
+/// Pads `data` to a multiple of [PADDING_GRANULARITY]. The last byte holds the number of
+/// padding bytes before it.
+fn pad(data: &[u8]) -> Vec<u8> {
+    let extra_bytes = PADDING_GRANULARITY - (data.len() % PADDING_GRANULARITY);
+    let padded_len = data.len() + extra_bytes;
 
-    let frame = CableTunnelMessage::new(CableTunnelMessageType::Ctap, &padded_cbor_request);
-    let frame_serialized = frame.to_vec();
-    trace!(?frame_serialized);
+    let mut padded = data.to_vec();
+    padded.resize(padded_len, 0u8);
+    padded[padded_len - 1] = (extra_bytes - 1) as u8;
+    padded
+}
 
-    let mut encrypted_frame = vec![0u8; MAX_CBOR_SIZE + 1];
-    match noise_state
-        .transport_state
-        .write_message(&frame_serialized, &mut encrypted_frame)
-    {
-        Ok(size) => {
-            encrypted_frame.resize(size, 0u8);
+/// Inverse of [pad]. Padding granularity differs between protocol revisions, so any amount of
+/// padding is accepted.
+fn unpad(mut data: Vec<u8>) -> Result<Vec<u8>, Error> {
+    let Some(&padding_len) = data.last() else {
+        error!("Received empty frame");
+        return Err(Error::Transport(TransportError::InvalidFraming));
+    };
+    let Some(unpadded_len) = data.len().checked_sub(padding_len as usize + 1) else {
+        error!(padding_len, "Invalid padding");
+        return Err(Error::Transport(TransportError::InvalidFraming));
+    };
+    data.truncate(unpadded_len);
+    Ok(data)
+}
+
+/// Parses a decrypted frame received from the authenticator, without acting on it.
+pub(crate) fn parse_frame(frame: Vec<u8>) -> Result<(), Error> {
+    let message = CableTunnelMessage::from_slice(&unpad(frame)?)?;
+    match message.message_type {
+        CableTunnelMessageType::Ctap => {
+            CborResponse::try_from(&message.payload.to_vec())
+                .or(Err(TransportError::InvalidFraming))?;
         }
-        Err(e) => {
-            error!(?e, "Failed to encrypt frame");
-            return Err(Error::Transport(TransportError::ConnectionFailed));
+        CableTunnelMessageType::Update => {
+            connection_recv_update(&message.payload)?;
         }
+        CableTunnelMessageType::Shutdown | CableTunnelMessageType::Json => (),
     }
+    Ok(())
+}
+
+async fn send_encrypted(
+    frame_serialized: &[u8],
+    ws_stream: &mut TunnelStream,
+    cipher: &mut dyn TunnelCipher,
+) -> Result<(), Error> {
+    trace!(?frame_serialized);
+
+    let encrypted_frame = cipher.encrypt(frame_serialized)?;
 
     debug!("Sending encrypted frame");
     trace!(?encrypted_frame);
@@ -519,26 +1030,16 @@ async fn connection_recv_binary_frame(message: Message) -> Result<Option<Vec<u8>
 
 async fn decrypt_frame(
     encrypted_frame: Vec<u8>,
-    noise_state: &mut TunnelNoiseState,
+    cipher: &mut dyn TunnelCipher,
 ) -> Result<Vec<u8>, Error> {
-    let mut decrypted_frame = vec![0u8; MAX_CBOR_SIZE];
-    match noise_state
-        .transport_state
-        .read_message(&encrypted_frame, &mut decrypted_frame)
-    {
-        Ok(size) => {
-            debug!(decrypted_frame_len = size, "Decrypted CBOR response");
-            decrypted_frame.resize(size, 0u8);
-            trace!(?decrypted_frame);
-        }
-        Err(e) => {
-            error!(?e, "Failed to decrypt CBOR response");
-            return Err(Error::Transport(TransportError::ConnectionFailed));
-        }
-    }
+    let decrypted_frame = cipher.decrypt(&encrypted_frame)?;
+    debug!(
+        decrypted_frame_len = decrypted_frame.len(),
+        "Decrypted CBOR response"
+    );
+    trace!(?decrypted_frame);
 
-    let padding_len = decrypted_frame[decrypted_frame.len() - 1] as usize;
-    decrypted_frame.truncate(decrypted_frame.len() - (padding_len + 1));
+    let decrypted_frame = unpad(decrypted_frame)?;
     trace!(
         ?decrypted_frame,
         decrypted_frame_len = decrypted_frame.len(),
@@ -548,17 +1049,22 @@ async fn decrypt_frame(
     Ok(decrypted_frame)
 }
 
+/// Returns the authenticator's getInfo response, and the optional features it supports.
 async fn connection_recv_initial(
     message: Message,
-    noise_state: &mut TunnelNoiseState,
-) -> Result<Vec<u8>, Error> {
+    cipher: &mut dyn TunnelCipher,
+) -> Result<(Vec<u8>, CableFeatures), Error> {
     let Some(encrypted_frame) = connection_recv_binary_frame(message).await? else {
         return Err(Error::Transport(TransportError::ConnectionFailed));
     };
 
-    let decrypted_frame = decrypt_frame(encrypted_frame, noise_state).await?;
+    let decrypted_frame = decrypt_frame(encrypted_frame, cipher).await?;
+    parse_initial_message(&decrypted_frame)
+}
 
-    let initial_message: CableInitialMessage = match cbor::from_slice(&decrypted_frame) {
+/// Parses the decrypted and unpadded initial message, see [connection_recv_initial].
+pub(crate) fn parse_initial_message(frame: &[u8]) -> Result<(Vec<u8>, CableFeatures), Error> {
+    let initial_message: CableInitialMessage = match cbor::from_slice(frame) {
         Ok(initial_message) => initial_message,
         Err(e) => {
             error!(?e, "Failed to decode initial message");
@@ -574,10 +1080,16 @@ async fn connection_recv_initial(
         }
     };
 
-    Ok(initial_message.info.to_vec())
+    let features = CableFeatures::from_names(
+        initial_message
+            .supported_features
+            .as_deref()
+            .unwrap_or_default(),
+    );
+    Ok((initial_message.info.to_vec(), features))
 }
 
-async fn connection_recv_update(message: &[u8]) -> Result<Option<CableLinkingInfo>, Error> {
+pub(crate) fn connection_recv_update(message: &[u8]) -> Result<Option<CableLinkingInfo>, Error> {
     // TODO(#66): Android adds a 999-key to the end the message, which is not part of the standard.
     // For now, we parse the message to a map and manuually import fields.
 
@@ -640,19 +1152,21 @@ async fn connection_recv_update(message: &[u8]) -> Result<Option<CableLinkingInf
     Ok(Some(linking_info))
 }
 
+/// Handles a message from the authenticator. If it carried linking info, returns whether it was
+/// persisted to the known-device store.
 async fn connection_recv(
     connection_type: &CableTunnelConnectionType,
     tunnel_domain: &str,
     known_device_store: &Option<Arc<dyn CableKnownDeviceInfoStore>>,
     message: Message,
     cbor_rx_send: &Sender<CborResponse>,
-    noise_state: &mut TunnelNoiseState,
-) -> Result<(), Error> {
+    cipher: &mut dyn TunnelCipher,
+) -> Result<Option<bool>, Error> {
     let Some(encrypted_frame) = connection_recv_binary_frame(message).await? else {
-        return Ok(());
+        return Ok(None);
     };
 
-    let decrypted_frame = decrypt_frame(encrypted_frame, noise_state).await?;
+    let decrypted_frame = decrypt_frame(encrypted_frame, cipher).await?;
 
     // TODO handle the decrypted frame
     let cable_message: CableTunnelMessage = match CableTunnelMessage::from_slice(&decrypted_frame) {
@@ -670,6 +1184,11 @@ async fn connection_recv(
             error!("Received unexpected shutdown message");
             return Err(Error::Transport(TransportError::ConnectionFailed));
         }
+        CableTunnelMessageType::Json => {
+            // Authenticators only send JSON in response to JSON requests, which aren't supported.
+            error!("Received unsolicited JSON message");
+            return Err(Error::Transport(TransportError::InvalidFraming));
+        }
         CableTunnelMessageType::Ctap => {
             // Handle the CTAP message
             let cbor_response: CborResponse = (&cable_message.payload.to_vec())
@@ -686,16 +1205,16 @@ async fn connection_recv(
         CableTunnelMessageType::Update => {
             // Handle the update message
             let maybe_update_message: Option<CableLinkingInfo> =
-                connection_recv_update(&cable_message.payload).await?;
+                connection_recv_update(&cable_message.payload)?;
 
             let Some(linking_info) = maybe_update_message else {
                 warn!("Ignoring update message without linking info");
-                return Ok(());
+                return Ok(None);
             };
 
             let CableTunnelConnectionType::QrCode { private_key, .. } = connection_type else {
                 warn!("Ignoring update message for non-QR code connection");
-                return Ok(());
+                return Ok(Some(false));
             };
 
             debug!("Received update message with linking info");
@@ -708,12 +1227,13 @@ async fn connection_recv(
                         private_key,
                         tunnel_domain,
                         &linking_info,
-                        &noise_state,
+                        cipher.handshake_hash(),
                     ) {
                         Ok(known_device) => {
                             debug!(?device_id, "Updating known device");
                             trace!(?known_device);
                             store.put_known_device(&device_id, &known_device).await;
+                            return Ok(Some(true));
                         }
                         Err(e) => {
                             error!(
@@ -727,12 +1247,13 @@ async fn connection_recv(
                 }
                 None => {
                     warn!("Ignoring update message without a device store");
+                    return Ok(Some(false));
                 }
             };
         }
     };
 
-    Ok(())
+    Ok(None)
 }
 
 /// Validation requires a shared key computed on the QR code ephemeral identity key (private_key here).
@@ -743,7 +1264,7 @@ fn parse_known_device(
     private_key: &NonZeroScalar,
     tunnel_domain: &str,
     linking_info: &CableLinkingInfo,
-    noise_state: &TunnelNoiseState,
+    handshake_hash: &[u8],
 ) -> Result<CableKnownDeviceInfo, Error> {
     let known_device = CableKnownDeviceInfo::new(tunnel_domain, linking_info)?;
     let secret_key = SecretKey::from(private_key);
@@ -755,15 +1276,17 @@ fn parse_known_device(
         return Err(Error::Transport(TransportError::InvalidKey));
     };
 
-    let shared_secret: Vec<u8> = ecdh::diffie_hellman(
-        secret_key.to_nonzero_scalar(),
-        authenticator_public_key.as_affine(),
-    )
-    .raw_secret_bytes()
-    .to_vec();
+    let shared_secret = Zeroizing::new(
+        ecdh::diffie_hellman(
+            secret_key.to_nonzero_scalar(),
+            authenticator_public_key.as_affine(),
+        )
+        .raw_secret_bytes()
+        .to_vec(),
+    );
 
     let mut hmac = Hmac::<Sha256>::new_from_slice(&shared_secret).expect("Any key size is valid");
-    hmac.update(&noise_state.handshake_hash);
+    hmac.update(handshake_hash);
     let expected_mac = hmac.finalize().into_bytes().to_vec();
 
     if expected_mac != linking_info.handshake_signature {
@@ -778,7 +1301,76 @@ fn parse_known_device(
 
 #[cfg(test)]
 mod tests {
+    use tokio::sync::mpsc;
+
     use super::*;
+    use crate::transport::cable::known_devices::ClientPayloadHint;
+    use crate::transport::cable::mock_tunnel::PlaintextCipher;
+
+    #[test]
+    fn unpad_accepts_any_granularity() {
+        let data = vec![1, 2, 3];
+        assert_eq!(pad(&data).len(), PADDING_GRANULARITY);
+        assert_eq!(unpad(pad(&data)).unwrap(), data);
+        // Padded to 256 bytes, as by newer authenticators.
+        let mut padded = data.clone();
+        padded.resize(256, 0);
+        padded[255] = 252;
+        assert_eq!(unpad(padded).unwrap(), data);
+
+        assert!(unpad(vec![]).is_err());
+        assert!(unpad(vec![0, 2]).is_err());
+    }
+
+    #[test]
+    fn initial_message_announces_features() {
+        let initial_message = CableInitialMessage {
+            _padding: None,
+            info: ByteBuf::from(vec![0xa0]),
+            supported_features: Some(vec!["linking".to_owned(), "dc".to_owned()]),
+        };
+        let serialized = cbor::to_vec(&initial_message).unwrap();
+        let parsed: CableInitialMessage = cbor::from_slice(&serialized).unwrap();
+        let features = CableFeatures::from_names(parsed.supported_features.as_deref().unwrap());
+        assert!(features.linking);
+        assert!(features.digital_credentials);
+    }
+
+    #[test]
+    fn parses_json_messages() {
+        let message = CableTunnelMessage::from_slice(b"\x03{}").unwrap();
+        assert!(matches!(message.message_type, CableTunnelMessageType::Json));
+        assert!(CableTunnelMessage::from_slice(b"\x04{}").is_err());
+    }
+
+    #[tokio::test]
+    async fn unsolicited_json_message_is_rejected() {
+        let connection_type = CableTunnelConnectionType::KnownDevice {
+            contact_id: "contact".to_owned(),
+            authenticator_public_key: vec![],
+            client_payload: ClientPayload {
+                link_id: ByteBuf::from(vec![1; 8]),
+                client_nonce: ByteBuf::from(vec![2; 16]),
+                hint: ClientPayloadHint::GetAssertion,
+            },
+        };
+        let frame = CableTunnelMessage::new(CableTunnelMessageType::Json, b"{}").to_vec();
+        let (cbor_rx_send, _cbor_rx_recv) = mpsc::channel(1);
+        let result = connection_recv(
+            &connection_type,
+            "cable.ua5v.com",
+            &None,
+            Message::Binary(pad(&frame).into()),
+            &cbor_rx_send,
+            &mut PlaintextCipher::new(&[0; 32]),
+        )
+        .await;
+        assert_eq!(
+            result,
+            Err(Error::Transport(TransportError::InvalidFraming))
+        );
+    }
+
     #[test]
     fn decode_tunnel_server_domain_known() {
         assert_eq!(
@@ -791,5 +1383,24 @@ mod tests {
         );
     }
 
-    // TODO: test the non-known case
+    #[test]
+    fn decode_tunnel_server_domain_derived() {
+        assert_eq!(
+            decode_tunnel_server_domain(256).unwrap(),
+            "cable.qz2ekwmnd332c.info".to_string()
+        );
+        assert_eq!(
+            decode_tunnel_server_domain(0xffff).unwrap(),
+            "cable.lw6vahpb5pldb.net".to_string()
+        );
+        assert_eq!(decode_tunnel_server_domain(2), None);
+    }
+
+    #[test]
+    fn decode_tunnel_server_domain_custom() {
+        let domains = TunnelDomains::default().with_custom(200, "cable.example.com");
+        assert_eq!(domains.decode(200).unwrap(), "cable.example.com");
+        assert_eq!(domains.decode(0).unwrap(), "cable.ua5v.com");
+        assert_eq!(TunnelDomains::default().decode(200), None);
+    }
 }
@@ -1,6 +1,11 @@
 use std::fmt::{Debug, Display};
+use std::future::Future;
+use std::sync::{Arc, OnceLock};
 use std::time::Duration;
 
+use crate::audit::AuditTrail;
+use crate::metrics::MetricsRecorder;
+use crate::pin::{PinProvider, PinUvAuthProtocols};
 use crate::proto::ctap2::{
     Ctap2AuthTokenPermissionRole, Ctap2PinUvAuthProtocol, Ctap2UserVerificationOperation,
 };
@@ -8,16 +13,27 @@ use crate::proto::{
     ctap1::apdu::{ApduRequest, ApduResponse},
     ctap2::cbor::{CborRequest, CborResponse},
 };
-use crate::webauthn::error::Error;
+use crate::quirks::UsbId;
+use crate::redact::REDACTED;
+use crate::timeout::TimeoutPolicy;
+use crate::transport::cable::known_devices::ClientPayloadHint;
+use crate::webauthn::error::{Error, PlatformError};
 use crate::UvUpdate;
 
 use async_trait::async_trait;
 use cosey::PublicKey;
 use tokio::sync::broadcast;
-use tracing::{instrument, trace, warn};
+use tokio::sync::broadcast::error::RecvError;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, info, instrument, trace, warn};
+use uuid::Uuid;
+use zeroize::{Zeroize, ZeroizeOnDrop};
 
 use super::device::SupportedProtocols;
 
+/// Longest lock [Channel::lock_device] can take, as CTAPHID_LOCK allows at most 10 seconds.
+pub const MAX_DEVICE_LOCK_DURATION: Duration = Duration::from_secs(10);
+
 #[derive(Debug, Copy, Clone)]
 pub enum ChannelStatus {
     Ready, // Channels are created asynchrounously, and are always ready.
@@ -25,6 +41,76 @@ pub enum ChannelStatus {
     Closed,
 }
 
+/// The device behind a channel, as shown to the user. UIs driving several devices use it to
+/// tell them apart, e.g. "touch your YubiKey" vs "check your phone".
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeviceDescriptor {
+    /// Short name of the transport, see [Channel::transport_name].
+    pub transport: &'static str,
+    /// The channel's display name, e.g. the USB product name, or the phone's name.
+    pub name: String,
+    /// Known once the device answered getInfo.
+    pub aaguid: Option<Uuid>,
+}
+
+impl DeviceDescriptor {
+    /// Product name of the device, e.g. "YubiKey 5 Series with NFC", if its AAGUID is known.
+    #[cfg(feature = "aaguid-names")]
+    pub fn product_name(&self) -> Option<&'static str> {
+        crate::aaguid::product_name(self.aaguid.as_ref()?)
+    }
+}
+
+/// The AAGUID of a channel's device, learned from its getInfo response. Shared with the
+/// channel's [DeviceUxUpdateReceiver]s, so that it shows up in their later updates.
+#[derive(Debug, Clone, Default)]
+pub struct DeviceAaguid(Arc<OnceLock<Uuid>>);
+
+impl DeviceAaguid {
+    pub fn get(&self) -> Option<Uuid> {
+        self.0.get().copied()
+    }
+
+    /// Records the AAGUID reported in getInfo. Devices without one report all zeros.
+    pub(crate) fn set(&self, aaguid: &[u8]) {
+        match Uuid::from_slice(aaguid) {
+            Ok(aaguid) if !aaguid.is_nil() => match self.0.set(aaguid) {
+                Err(aaguid) if self.get() != Some(aaguid) => {
+                    warn!(%aaguid, "Device reported another AAGUID, keeping the first one")
+                }
+                _ => (),
+            },
+            Ok(_) => (),
+            Err(_) => warn!(len = aaguid.len(), "Invalid AAGUID in getInfo"),
+        }
+    }
+}
+
+/// A UX update, along with the device it comes from.
+#[derive(Debug, Clone)]
+pub struct DeviceUxUpdate<U> {
+    pub device: DeviceDescriptor,
+    pub update: U,
+}
+
+/// Receives a channel's UX updates as [DeviceUxUpdate]s, see
+/// [Channel::get_device_ux_update_receiver].
+#[derive(Debug)]
+pub struct DeviceUxUpdateReceiver<U> {
+    device: DeviceDescriptor,
+    aaguid: Option<DeviceAaguid>,
+    receiver: broadcast::Receiver<U>,
+}
+
+impl<U: Clone> DeviceUxUpdateReceiver<U> {
+    pub async fn recv(&mut self) -> Result<DeviceUxUpdate<U>, RecvError> {
+        let update = self.receiver.recv().await?;
+        let mut device = self.device.clone();
+        device.aaguid = self.aaguid.as_ref().and_then(DeviceAaguid::get);
+        Ok(DeviceUxUpdate { device, update })
+    }
+}
+
 #[async_trait]
 pub trait Channel: Send + Sync + Display + Ctap2AuthTokenStore {
     /// UX updates for this channel, must include UV updates.
@@ -36,6 +122,29 @@ pub trait Channel: Send + Sync + Display + Ctap2AuthTokenStore {
         self.get_ux_update_sender().subscribe()
     }
 
+    /// Same as [Channel::get_ux_update_receiver], with each update tagged with the device.
+    fn get_device_ux_update_receiver(&self) -> DeviceUxUpdateReceiver<Self::UxUpdate> {
+        DeviceUxUpdateReceiver {
+            device: self.device_descriptor(),
+            aaguid: self.device_aaguid().cloned(),
+            receiver: self.get_ux_update_receiver(),
+        }
+    }
+
+    fn device_descriptor(&self) -> DeviceDescriptor {
+        DeviceDescriptor {
+            transport: self.transport_name(),
+            name: self.to_string(),
+            aaguid: self.device_aaguid().and_then(DeviceAaguid::get),
+        }
+    }
+
+    /// Where the device's AAGUID is recorded once it answers getInfo. Channels without one
+    /// don't report AAGUIDs in their [DeviceDescriptor].
+    fn device_aaguid(&self) -> Option<&DeviceAaguid> {
+        None
+    }
+
     #[instrument(skip(self))]
     async fn send_ux_update(&mut self, state: Self::UxUpdate) {
         trace!("Sending UX update");
@@ -47,6 +156,89 @@ pub trait Channel: Send + Sync + Display + Ctap2AuthTokenStore {
         };
     }
 
+    /// The PIN provider installed on this channel, if any. Without one, PINs are requested
+    /// through `UvUpdate::PinRequired`.
+    fn get_pin_provider(&self) -> Option<Arc<dyn PinProvider>> {
+        None
+    }
+
+    /// Channels without a PIN provider slot keep requesting PINs through updates.
+    fn set_pin_provider(&mut self, _provider: Option<Arc<dyn PinProvider>>) {
+        warn!(
+            transport = self.transport_name(),
+            "PIN provider not supported by channel"
+        );
+    }
+
+    /// The PIN/UV auth protocol implementations used on this channel, the builtin ones unless
+    /// set with [Channel::set_pin_uv_auth_protocols].
+    fn get_pin_uv_auth_protocols(&self) -> PinUvAuthProtocols {
+        PinUvAuthProtocols::default()
+    }
+
+    /// Channels without a protocols slot keep using the builtin implementations.
+    fn set_pin_uv_auth_protocols(&mut self, _protocols: PinUvAuthProtocols) {
+        warn!(
+            transport = self.transport_name(),
+            "Custom PIN/UV auth protocols not supported by channel"
+        );
+    }
+
+    /// The recorder receiving metrics of the ceremonies run on this channel, if any.
+    fn get_metrics_recorder(&self) -> Option<Arc<dyn MetricsRecorder>> {
+        None
+    }
+
+    /// Channels without a recorder slot don't report metrics.
+    fn set_metrics_recorder(&mut self, _recorder: Option<Arc<dyn MetricsRecorder>>) {
+        warn!(
+            transport = self.transport_name(),
+            "Metrics recorder not supported by channel"
+        );
+    }
+
+    /// Where the high-level ceremonies on this channel are audited, if anywhere.
+    fn get_audit_trail(&self) -> Option<AuditTrail> {
+        None
+    }
+
+    /// Channels without an audit trail slot don't produce audit records.
+    fn set_audit_trail(&mut self, _trail: Option<AuditTrail>) {
+        warn!(
+            transport = self.transport_name(),
+            "Audit trail not supported by channel"
+        );
+    }
+
+    /// The token aborting operations on this channel, if any. Once it is cancelled, ongoing
+    /// and later operations fail with `PlatformError::Cancelled`: pending requests are aborted
+    /// (e.g. with CTAPHID_CANCEL, or by closing the caBLE tunnel), and `UvUpdate::Cancelled`
+    /// is sent so that UIs can dismiss their prompts.
+    fn get_cancellation_token(&self) -> Option<CancellationToken> {
+        None
+    }
+
+    /// Channels not supporting cancellation ignore the token.
+    fn set_cancellation_token(&mut self, _token: Option<CancellationToken>) {
+        warn!(
+            transport = self.transport_name(),
+            "Cancellation not supported by channel"
+        );
+    }
+
+    /// Per-request timeouts of the ceremonies run on this channel, within their own timeout.
+    fn get_timeout_policy(&self) -> TimeoutPolicy {
+        TimeoutPolicy::default()
+    }
+
+    /// Channels without a policy slot keep using the default policy.
+    fn set_timeout_policy(&mut self, _policy: TimeoutPolicy) {
+        warn!(
+            transport = self.transport_name(),
+            "Timeout policy not supported by channel"
+        );
+    }
+
     async fn supported_protocols(&self) -> Result<SupportedProtocols, Error>;
     async fn status(&self) -> ChannelStatus;
     async fn close(&mut self);
@@ -57,10 +249,82 @@ pub trait Channel: Send + Sync + Display + Ctap2AuthTokenStore {
     async fn cbor_send(&mut self, request: &CborRequest, timeout: Duration) -> Result<(), Error>;
     async fn cbor_recv(&mut self, timeout: Duration) -> Result<CborResponse, Error>;
 
+    /// Asks the device to only serve this channel for up to `duration`, so that multi-message
+    /// sequences like GetNextAssertion aren't interrupted by other applications. Transports
+    /// without such a mechanism do nothing.
+    async fn lock_device(&mut self, _duration: Duration) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Releases a lock taken with [Channel::lock_device] before it expires.
+    async fn unlock_device(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Short name of the channel's transport, e.g. `hid` or `cable`, as reported in metrics.
+    fn transport_name(&self) -> &'static str {
+        "unknown"
+    }
+
+    /// USB vendor and product ID of the device, used to look up its quirks.
+    fn usb_id(&self) -> Option<UsbId> {
+        None
+    }
+
     /// Allows channels to disable support for pre-flight requests
-    fn supports_preflight() -> bool {
+    fn supports_preflight(&self) -> bool {
         true
     }
+
+    /// Called by WebAuthn ceremonies before their first request, with the operation they are
+    /// about to perform. caBLE channels to known devices pass it on to the authenticator, which
+    /// prepares its UI for it. Other channels ignore it.
+    fn announce_operation(&mut self, _hint: ClientPayloadHint) {}
+}
+
+/// Fails if `token` was cancelled, so that no new request is sent to the device.
+pub(crate) fn ensure_not_cancelled<U>(
+    token: Option<&CancellationToken>,
+    ux_update_sender: &broadcast::Sender<U>,
+) -> Result<(), Error>
+where
+    U: From<UvUpdate>,
+{
+    match token {
+        Some(token) if token.is_cancelled() => Err(cancelled(ux_update_sender)),
+        _ => Ok(()),
+    }
+}
+
+/// Runs `future` until `token`, if any, is cancelled.
+pub(crate) async fn until_cancelled<T, U>(
+    token: Option<CancellationToken>,
+    ux_update_sender: &broadcast::Sender<U>,
+    future: impl Future<Output = Result<T, Error>>,
+) -> Result<T, Error>
+where
+    U: From<UvUpdate>,
+{
+    let Some(token) = token else {
+        return future.await;
+    };
+    tokio::select! {
+        biased;
+        _ = token.cancelled() => Err(cancelled(ux_update_sender)),
+        result = future => result,
+    }
+}
+
+/// Lets UIs dismiss their prompts for the cancelled operation.
+pub(crate) fn cancelled<U>(ux_update_sender: &broadcast::Sender<U>) -> Error
+where
+    U: From<UvUpdate>,
+{
+    info!("Operation cancelled");
+    if ux_update_sender.send(UvUpdate::Cancelled.into()).is_err() {
+        debug!("No receivers for UX update");
+    }
+    Error::Platform(PlatformError::Cancelled)
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -84,37 +348,125 @@ impl Ctap2AuthTokenPermission {
     }
 
     pub fn contains(&self, requested: &Ctap2AuthTokenPermission) -> bool {
+        self.mismatch(requested).is_none()
+    }
+
+    /// Why a token with these permissions can't be used for `requested`, if it can't.
+    pub fn mismatch(&self, requested: &Ctap2AuthTokenPermission) -> Option<AuthTokenMismatch> {
         if self.pin_uv_auth_protocol != requested.pin_uv_auth_protocol {
-            return false;
+            return Some(AuthTokenMismatch::Protocol);
         }
         if self.rpid != requested.rpid {
-            return false;
+            // Only mc and ga require an RP ID. For the other permissions, a token without one
+            // isn't restricted to any RP, e.g. for credential management.
+            let rpid_bound = self.rpid.is_some()
+                || requested.role.intersects(
+                    Ctap2AuthTokenPermissionRole::MAKE_CREDENTIAL
+                        | Ctap2AuthTokenPermissionRole::GET_ASSERTION,
+                );
+            if rpid_bound {
+                return Some(AuthTokenMismatch::RelyingParty {
+                    bound: self.rpid.clone(),
+                    requested: requested.rpid.clone(),
+                });
+            }
         }
-        self.role.contains(requested.role)
+        let missing = requested.role.difference(self.role);
+        (!missing.is_empty()).then_some(AuthTokenMismatch::Permissions(missing))
     }
 }
 
-#[derive(Debug, Clone)]
+/// Why a stored pinUvAuthToken can't be reused, so that the user has to be verified again.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AuthTokenMismatch {
+    /// The token was obtained with another PIN/UV auth protocol.
+    Protocol,
+    /// The token is bound to another RP than the operation's, or to one while the operation
+    /// isn't restricted to any.
+    RelyingParty {
+        bound: Option<String>,
+        requested: Option<String>,
+    },
+    /// The token lacks these permissions.
+    Permissions(Ctap2AuthTokenPermissionRole),
+}
+
+impl Display for AuthTokenMismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Protocol => write!(f, "token uses another PIN/UV auth protocol"),
+            Self::RelyingParty { bound, requested } => write!(
+                f,
+                "token is bound to {}, but the operation is for {}",
+                bound.as_deref().unwrap_or("no RP"),
+                requested.as_deref().unwrap_or("no RP")
+            ),
+            Self::Permissions(missing) => write!(f, "token lacks permissions {missing:?}"),
+        }
+    }
+}
+
+/// Wiped from memory when dropped.
+#[derive(Clone, Zeroize, ZeroizeOnDrop)]
 pub struct AuthTokenData {
     pub shared_secret: Vec<u8>,
+    #[zeroize(skip)]
     pub permission: Ctap2AuthTokenPermission,
     pub pin_uv_auth_token: Vec<u8>,
+    #[zeroize(skip)]
     pub protocol_version: Ctap2PinUvAuthProtocol,
+    #[zeroize(skip)]
     pub key_agreement: PublicKey,
+    #[zeroize(skip)]
     pub uv_operation: Ctap2UserVerificationOperation,
 }
 
+impl Debug for AuthTokenData {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AuthTokenData")
+            .field("shared_secret", &REDACTED)
+            .field("permission", &self.permission)
+            .field("pin_uv_auth_token", &REDACTED)
+            .field("protocol_version", &self.protocol_version)
+            .field("key_agreement", &self.key_agreement)
+            .field("uv_operation", &self.uv_operation)
+            .finish()
+    }
+}
+
+impl AuthTokenData {
+    /// Whether this token can be reused for an operation requiring `requested`.
+    pub fn covers(&self, requested: &Ctap2AuthTokenPermission) -> bool {
+        self.mismatch(requested).is_none()
+    }
+
+    /// Why this token can't be reused for an operation requiring `requested`, if it can't.
+    pub fn mismatch(&self, requested: &Ctap2AuthTokenPermission) -> Option<AuthTokenMismatch> {
+        if self.uv_operation == Ctap2UserVerificationOperation::GetPinToken {
+            // Legacy pinTokens are not scoped to permissions or an RP ID.
+            return (self.protocol_version != requested.pin_uv_auth_protocol)
+                .then_some(AuthTokenMismatch::Protocol);
+        }
+        self.permission.mismatch(requested)
+    }
+}
+
 #[async_trait]
 pub trait Ctap2AuthTokenStore {
     fn store_auth_data(&mut self, auth_token_data: AuthTokenData);
     fn get_auth_data(&self) -> Option<&AuthTokenData>;
     fn clear_uv_auth_token_store(&mut self);
     fn get_uv_auth_token(&self, requested_permission: &Ctap2AuthTokenPermission) -> Option<&[u8]> {
-        if let Some(stored_data) = self.get_auth_data() {
-            if stored_data.permission.contains(requested_permission) {
-                return Some(&stored_data.pin_uv_auth_token);
-            }
+        let stored_data = self.get_auth_data()?;
+        if stored_data.covers(requested_permission) {
+            debug!(?requested_permission, "Reusing stored pinUvAuthToken");
+            return Some(&stored_data.pin_uv_auth_token);
         }
+        trace!(
+            ?requested_permission,
+            stored_permission = ?stored_data.permission,
+            "Stored pinUvAuthToken does not cover requested permission"
+        );
         None
     }
     fn used_pin_for_auth(&self) -> bool {
@@ -126,3 +478,61 @@ pub trait Ctap2AuthTokenStore {
         false
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{AuthTokenMismatch, Ctap2AuthTokenPermission};
+    use crate::proto::ctap2::{Ctap2AuthTokenPermissionRole, Ctap2PinUvAuthProtocol};
+
+    fn permission(
+        role: Ctap2AuthTokenPermissionRole,
+        rpid: Option<&str>,
+    ) -> Ctap2AuthTokenPermission {
+        Ctap2AuthTokenPermission::new(Ctap2PinUvAuthProtocol::Two, role, rpid)
+    }
+
+    #[test]
+    fn permission_contains_requested() {
+        let cm = Ctap2AuthTokenPermissionRole::CREDENTIAL_MANAGEMENT;
+        let ga = Ctap2AuthTokenPermissionRole::GET_ASSERTION;
+
+        // Credential management tokens without an RP ID are valid for all RPs.
+        assert!(permission(cm, None).contains(&permission(cm, Some("example.org"))));
+        assert!(!permission(cm, Some("example.org")).contains(&permission(cm, None)));
+        assert!(!permission(cm, Some("example.org")).contains(&permission(cm, Some("example.com"))));
+
+        // mc and ga tokens are always bound to an RP ID.
+        assert!(permission(ga, Some("example.org")).contains(&permission(ga, Some("example.org"))));
+        assert!(!permission(ga, None).contains(&permission(ga, Some("example.org"))));
+        assert!(!permission(ga, Some("example.org")).contains(&permission(ga, Some("example.com"))));
+
+        assert!(permission(ga | cm, None).contains(&permission(cm, None)));
+        assert!(!permission(cm, None).contains(&permission(ga | cm, None)));
+        assert!(
+            !Ctap2AuthTokenPermission::new(Ctap2PinUvAuthProtocol::One, cm, None)
+                .contains(&permission(cm, None))
+        );
+    }
+
+    #[test]
+    fn permission_mismatch() {
+        let mc = Ctap2AuthTokenPermissionRole::MAKE_CREDENTIAL;
+        let ga = Ctap2AuthTokenPermissionRole::GET_ASSERTION;
+
+        assert_eq!(
+            permission(mc | ga, Some("example.org")).mismatch(&permission(ga, Some("example.com"))),
+            Some(AuthTokenMismatch::RelyingParty {
+                bound: Some("example.org".to_owned()),
+                requested: Some("example.com".to_owned()),
+            })
+        );
+        assert_eq!(
+            permission(ga, Some("example.org")).mismatch(&permission(mc | ga, Some("example.org"))),
+            Some(AuthTokenMismatch::Permissions(mc))
+        );
+        assert_eq!(
+            permission(mc | ga, Some("example.org")).mismatch(&permission(ga, Some("example.org"))),
+            None
+        );
+    }
+}
@@ -0,0 +1,297 @@
+use std::convert::TryFrom;
+use std::fmt::{Display, Formatter};
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use serde_bytes::ByteBuf;
+use tokio::net::UnixStream;
+use tokio::sync::{broadcast, Mutex};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, instrument, trace, Level};
+
+use crate::audit::AuditTrail;
+use crate::metrics::MetricsRecorder;
+use crate::pin::{PinProvider, PinUvAuthProtocols};
+use crate::proto::ctap1::apdu::{ApduRequest, ApduResponse};
+use crate::proto::ctap2::cbor::{CborRequest, CborResponse};
+use crate::timeout::TimeoutPolicy;
+use crate::transport::channel::{
+    ensure_not_cancelled, until_cancelled, AuthTokenData, Channel, ChannelStatus,
+    Ctap2AuthTokenStore,
+};
+use crate::transport::device::SupportedProtocols;
+use crate::transport::error::TransportError;
+use crate::transport::hid::framing::HidCommand;
+use crate::webauthn::error::Error;
+use crate::UvUpdate;
+
+use super::device::connect;
+use super::protocol::{
+    write_message, DaemonRequest, DaemonResponse, FrameReader, KEEPALIVE_STATUS_PROCESSING,
+    KEEPALIVE_STATUS_UPNEEDED,
+};
+use super::DaemonDevice;
+
+/// The connection to the daemon. Reads are cancel safe, and responses to requests abandoned
+/// by a cancelled or timed out read are skipped when they arrive.
+struct Connection {
+    stream: UnixStream,
+    reader: FrameReader,
+    /// Responses still to come, the last one answering the latest request.
+    pending: usize,
+}
+
+pub struct DaemonChannel<'d> {
+    status: ChannelStatus,
+    device: &'d DaemonDevice,
+    connection: Mutex<Connection>,
+    protocols: SupportedProtocols,
+    auth_token_data: Option<AuthTokenData>,
+    pin_provider: Option<Arc<dyn PinProvider>>,
+    cancellation_token: Option<CancellationToken>,
+    timeout_policy: TimeoutPolicy,
+    audit_trail: Option<AuditTrail>,
+    metrics_recorder: Option<Arc<dyn MetricsRecorder>>,
+    pin_uv_auth_protocols: PinUvAuthProtocols,
+    ux_update_sender: broadcast::Sender<UvUpdate>,
+}
+
+impl<'d> DaemonChannel<'d> {
+    pub async fn new(device: &'d DaemonDevice) -> Result<DaemonChannel<'d>, Error> {
+        let (ux_update_sender, _) = broadcast::channel(16);
+        let mut stream = connect(&device.socket_path).await?;
+        write_message(
+            &mut stream,
+            &DaemonRequest::Open {
+                device_id: device.id.clone(),
+            },
+        )
+        .await?;
+        let mut reader = FrameReader::new();
+        let protocols = match reader.read_message(&mut stream).await? {
+            DaemonResponse::Opened { u2f, fido2 } => SupportedProtocols { u2f, fido2 },
+            DaemonResponse::Failed(failure) => return Err(Error::Transport(failure.into())),
+            _ => return Err(Error::Transport(TransportError::InvalidFraming)),
+        };
+        Ok(Self {
+            status: ChannelStatus::Ready,
+            device,
+            connection: Mutex::new(Connection {
+                stream,
+                reader,
+                pending: 0,
+            }),
+            protocols,
+            auth_token_data: None,
+            pin_provider: None,
+            cancellation_token: None,
+            timeout_policy: TimeoutPolicy::default(),
+            audit_trail: None,
+            metrics_recorder: None,
+            pin_uv_auth_protocols: PinUvAuthProtocols::default(),
+            ux_update_sender,
+        })
+    }
+
+    async fn transact_send(
+        &self,
+        command: HidCommand,
+        payload: Vec<u8>,
+        timeout: Duration,
+    ) -> Result<(), Error> {
+        ensure_not_cancelled(self.cancellation_token.as_ref(), &self.ux_update_sender)?;
+        let request = DaemonRequest::Transact {
+            command: command.into(),
+            payload: ByteBuf::from(payload),
+            timeout_ms: timeout.as_millis() as u64,
+        };
+        let connection = &mut *self.connection.lock().await;
+        write_message(&mut connection.stream, &request).await?;
+        connection.pending += 1;
+        Ok(())
+    }
+
+    /// Waits for the response to the last `Transact` request, reporting keep-alives as updates.
+    async fn transact_recv(&self, timeout: Duration) -> Result<Vec<u8>, Error> {
+        let token = self.cancellation_token.clone();
+        let message = until_cancelled(token, &self.ux_update_sender, async {
+            let connection = &mut *self.connection.lock().await;
+            tokio::time::timeout(timeout, async {
+                loop {
+                    match connection
+                        .reader
+                        .read_message(&mut connection.stream)
+                        .await?
+                    {
+                        DaemonResponse::Keepalive { status } if connection.pending <= 1 => {
+                            self.keepalive(status)
+                        }
+                        DaemonResponse::Keepalive { .. } => (),
+                        response => {
+                            connection.pending = connection.pending.saturating_sub(1);
+                            if connection.pending == 0 {
+                                return Ok(response);
+                            }
+                            debug!("Skipping response to an abandoned request");
+                        }
+                    }
+                }
+            })
+            .await
+            .unwrap_or(Err(Error::Transport(TransportError::Timeout)))
+        })
+        .await?;
+        match message {
+            DaemonResponse::Transacted { payload } => Ok(payload.into_vec()),
+            DaemonResponse::Failed(failure) => Err(Error::Transport(failure.into())),
+            _ => Err(Error::Transport(TransportError::InvalidFraming)),
+        }
+    }
+
+    fn keepalive(&self, status: u8) {
+        debug!(?status, "Received keep-alive from daemon");
+        let update = match status {
+            KEEPALIVE_STATUS_PROCESSING => UvUpdate::Processing,
+            KEEPALIVE_STATUS_UPNEEDED => UvUpdate::PresenceRequired,
+            _ => return,
+        };
+        if self.ux_update_sender.send(update).is_err() {
+            debug!("No receivers for UX update");
+        }
+    }
+}
+
+impl Display for DaemonChannel<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        Display::fmt(&self.device, f)
+    }
+}
+
+#[async_trait]
+impl Channel for DaemonChannel<'_> {
+    type UxUpdate = UvUpdate;
+
+    fn transport_name(&self) -> &'static str {
+        "daemon"
+    }
+
+    async fn supported_protocols(&self) -> Result<SupportedProtocols, Error> {
+        Ok(self.protocols)
+    }
+
+    async fn status(&self) -> ChannelStatus {
+        self.status
+    }
+
+    async fn close(&mut self) {
+        self.status = ChannelStatus::Closed;
+    }
+
+    #[instrument(level = Level::DEBUG, skip_all)]
+    async fn apdu_send(&self, request: &ApduRequest, timeout: Duration) -> Result<(), Error> {
+        debug!("Sending APDU request to daemon");
+        trace!(?request);
+        let apdu_raw = request
+            .raw_long()
+            .map_err(|e| TransportError::IoError(e.kind()))?;
+        self.transact_send(HidCommand::Msg, apdu_raw, timeout).await
+    }
+
+    #[instrument(level = Level::DEBUG, skip_all)]
+    async fn apdu_recv(&self, timeout: Duration) -> Result<ApduResponse, Error> {
+        let payload = self.transact_recv(timeout).await?;
+        let apdu_response = ApduResponse::try_from(&payload)
+            .or(Err(Error::Transport(TransportError::InvalidFraming)))?;
+        debug!("Received APDU response from daemon");
+        trace!(?apdu_response);
+        Ok(apdu_response)
+    }
+
+    #[instrument(level = Level::DEBUG, skip_all)]
+    async fn cbor_send(&mut self, request: &CborRequest, timeout: Duration) -> Result<(), Error> {
+        debug!("Sending CBOR request to daemon");
+        trace!(?request);
+        self.transact_send(HidCommand::Cbor, request.ctap_hid_data(), timeout)
+            .await
+    }
+
+    #[instrument(level = Level::DEBUG, skip_all)]
+    async fn cbor_recv(&mut self, timeout: Duration) -> Result<CborResponse, Error> {
+        let payload = self.transact_recv(timeout).await?;
+        let cbor_response = CborResponse::try_from(&payload)
+            .or(Err(Error::Transport(TransportError::InvalidFraming)))?;
+        debug!(
+            { status = ?cbor_response.status_code },
+            "Received CBOR response from daemon"
+        );
+        trace!(?cbor_response);
+        Ok(cbor_response)
+    }
+
+    fn get_ux_update_sender(&self) -> &broadcast::Sender<UvUpdate> {
+        &self.ux_update_sender
+    }
+
+    fn get_pin_provider(&self) -> Option<Arc<dyn PinProvider>> {
+        self.pin_provider.clone()
+    }
+
+    fn set_pin_provider(&mut self, provider: Option<Arc<dyn PinProvider>>) {
+        self.pin_provider = provider;
+    }
+
+    fn get_cancellation_token(&self) -> Option<CancellationToken> {
+        self.cancellation_token.clone()
+    }
+
+    fn set_cancellation_token(&mut self, token: Option<CancellationToken>) {
+        self.cancellation_token = token;
+    }
+
+    fn get_timeout_policy(&self) -> TimeoutPolicy {
+        self.timeout_policy
+    }
+
+    fn set_timeout_policy(&mut self, policy: TimeoutPolicy) {
+        self.timeout_policy = policy;
+    }
+
+    fn get_audit_trail(&self) -> Option<AuditTrail> {
+        self.audit_trail.clone()
+    }
+
+    fn set_audit_trail(&mut self, trail: Option<AuditTrail>) {
+        self.audit_trail = trail;
+    }
+
+    fn get_metrics_recorder(&self) -> Option<Arc<dyn MetricsRecorder>> {
+        self.metrics_recorder.clone()
+    }
+
+    fn set_metrics_recorder(&mut self, recorder: Option<Arc<dyn MetricsRecorder>>) {
+        self.metrics_recorder = recorder;
+    }
+
+    fn get_pin_uv_auth_protocols(&self) -> PinUvAuthProtocols {
+        self.pin_uv_auth_protocols.clone()
+    }
+
+    fn set_pin_uv_auth_protocols(&mut self, protocols: PinUvAuthProtocols) {
+        self.pin_uv_auth_protocols = protocols;
+    }
+}
+
+impl Ctap2AuthTokenStore for DaemonChannel<'_> {
+    fn store_auth_data(&mut self, auth_token_data: AuthTokenData) {
+        self.auth_token_data = Some(auth_token_data);
+    }
+
+    fn get_auth_data(&self) -> Option<&AuthTokenData> {
+        self.auth_token_data.as_ref()
+    }
+
+    fn clear_uv_auth_token_store(&mut self) {
+        self.auth_token_data = None;
+    }
+}
@@ -0,0 +1,64 @@
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use async_trait::async_trait;
+use tokio::net::UnixStream;
+use tracing::{info, instrument};
+
+use crate::transport::device::Device;
+use crate::transport::error::TransportError;
+use crate::webauthn::error::Error;
+
+use super::channel::DaemonChannel;
+use super::protocol::{write_message, DaemonRequest, DaemonResponse, FrameReader};
+use super::Daemon;
+
+/// Lists the devices known to the daemon listening on `socket_path`.
+#[instrument(skip_all, fields(path = %socket_path.as_ref().display()))]
+pub async fn list_devices(socket_path: impl AsRef<Path>) -> Result<Vec<DaemonDevice>, Error> {
+    let mut stream = connect(socket_path.as_ref()).await?;
+    write_message(&mut stream, &DaemonRequest::ListDevices).await?;
+    let devices = match FrameReader::new().read_message(&mut stream).await? {
+        DaemonResponse::Devices(devices) => devices,
+        DaemonResponse::Failed(failure) => return Err(Error::Transport(failure.into())),
+        _ => return Err(Error::Transport(TransportError::InvalidFraming)),
+    };
+    info!(
+        { count = devices.len() },
+        "Listing devices shared by daemon"
+    );
+    Ok(devices
+        .into_iter()
+        .map(|info| DaemonDevice {
+            socket_path: socket_path.as_ref().to_owned(),
+            id: info.id,
+            name: info.name,
+        })
+        .collect())
+}
+
+pub(crate) async fn connect(socket_path: &Path) -> Result<UnixStream, Error> {
+    UnixStream::connect(socket_path)
+        .await
+        .or(Err(Error::Transport(TransportError::TransportUnavailable)))
+}
+
+#[derive(Debug, Clone)]
+pub struct DaemonDevice {
+    pub socket_path: PathBuf,
+    pub id: String,
+    pub name: String,
+}
+
+impl fmt::Display for DaemonDevice {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} (shared)", self.name)
+    }
+}
+
+#[async_trait]
+impl<'d> Device<'d, Daemon, DaemonChannel<'d>> for DaemonDevice {
+    async fn channel(&'d mut self) -> Result<DaemonChannel<'d>, Error> {
+        DaemonChannel::new(self).await
+    }
+}
@@ -0,0 +1,31 @@
+//! Sharing authenticators between processes.
+//!
+//! HID authenticators can only be used by one process at a time. The daemon in
+//! [`server`] owns the HID transport and forwards CTAPHID messages received over
+//! a Unix socket, serializing access per device. Applications then talk to the
+//! daemon through [`DaemonDevice`] and [`DaemonChannel`], which behave like any
+//! other transport.
+
+use std::fmt::Display;
+
+pub mod channel;
+pub mod device;
+pub mod protocol;
+pub mod server;
+
+pub use channel::DaemonChannel;
+pub use device::{list_devices, DaemonDevice};
+pub use server::run_daemon;
+
+use super::Transport;
+
+pub struct Daemon {}
+impl Transport for Daemon {}
+unsafe impl Send for Daemon {}
+unsafe impl Sync for Daemon {}
+
+impl Display for Daemon {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Daemon")
+    }
+}
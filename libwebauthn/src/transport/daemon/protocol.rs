@@ -0,0 +1,124 @@
+//! Wire protocol spoken between the daemon and its clients.
+//!
+//! Messages are framed as in [crate::transport::socket]. A client first either lists the
+//! devices, or opens one of them by ID. Once a device is opened, each `Transact` request is
+//! answered by any number of `Keepalive` responses, followed by exactly one other response.
+
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+
+use crate::transport::error::TransportError;
+use crate::webauthn::error::Error;
+
+pub(crate) use crate::transport::socket::decode_message;
+pub use crate::transport::socket::{write_message, FrameReader, MAX_MESSAGE_SIZE};
+
+/// `Keepalive` status: the device is processing the request.
+pub const KEEPALIVE_STATUS_PROCESSING: u8 = 0x01;
+/// `Keepalive` status: the device waits for the user's presence.
+pub const KEEPALIVE_STATUS_UPNEEDED: u8 = 0x02;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DaemonDeviceInfo {
+    /// Stable identifier of the device, e.g. the hidraw path.
+    pub id: String,
+    /// Human-readable description of the device.
+    pub name: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum DaemonRequest {
+    ListDevices,
+    Open {
+        device_id: String,
+    },
+    /// Sends a CTAPHID message with the given command to the opened device,
+    /// and waits for the response.
+    Transact {
+        command: u8,
+        payload: ByteBuf,
+        timeout_ms: u64,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum DaemonResponse {
+    Devices(Vec<DaemonDeviceInfo>),
+    Opened {
+        u2f: bool,
+        fido2: bool,
+    },
+    /// Sent while the device processes a `Transact` request, as CTAPHID_KEEPALIVE.
+    Keepalive {
+        status: u8,
+    },
+    Transacted {
+        payload: ByteBuf,
+    },
+    Failed(DaemonFailure),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum DaemonFailure {
+    UnknownDevice,
+    NotOpened,
+    InvalidRequest,
+    UnsupportedCommand,
+    Timeout,
+    ConnectionLost,
+    TransportUnavailable,
+    DeviceRemoved,
+}
+
+impl From<DaemonFailure> for TransportError {
+    fn from(failure: DaemonFailure) -> Self {
+        match failure {
+            DaemonFailure::UnknownDevice => TransportError::UnknownDevice,
+            DaemonFailure::NotOpened
+            | DaemonFailure::InvalidRequest
+            | DaemonFailure::UnsupportedCommand => TransportError::InvalidFraming,
+            DaemonFailure::Timeout => TransportError::Timeout,
+            DaemonFailure::ConnectionLost => TransportError::ConnectionLost,
+            DaemonFailure::TransportUnavailable => TransportError::TransportUnavailable,
+            DaemonFailure::DeviceRemoved => TransportError::DeviceRemoved,
+        }
+    }
+}
+
+impl From<&Error> for DaemonFailure {
+    fn from(error: &Error) -> Self {
+        match error {
+            Error::Transport(TransportError::UnknownDevice) => DaemonFailure::UnknownDevice,
+            Error::Transport(TransportError::Timeout) => DaemonFailure::Timeout,
+            Error::Transport(TransportError::TransportUnavailable) => {
+                DaemonFailure::TransportUnavailable
+            }
+            Error::Transport(TransportError::DeviceRemoved) => DaemonFailure::DeviceRemoved,
+            _ => DaemonFailure::ConnectionLost,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn message_roundtrip() {
+        let (mut client, mut server) = tokio::io::duplex(64);
+        let (mut client_reader, mut server_reader) = (FrameReader::new(), FrameReader::new());
+        let request = DaemonRequest::Transact {
+            command: 0x10,
+            payload: ByteBuf::from(vec![0x04]),
+            timeout_ms: 1000,
+        };
+        write_message(&mut client, &request).await.unwrap();
+        let received: DaemonRequest = server_reader.read_message(&mut server).await.unwrap();
+        assert_eq!(received, request);
+
+        let response = DaemonResponse::Failed(DaemonFailure::UnknownDevice);
+        write_message(&mut server, &response).await.unwrap();
+        let received: DaemonResponse = client_reader.read_message(&mut client).await.unwrap();
+        assert_eq!(received, response);
+    }
+}
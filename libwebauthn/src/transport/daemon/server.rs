@@ -0,0 +1,264 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use serde_bytes::ByteBuf;
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::Mutex as AsyncMutex;
+use tracing::{debug, info, instrument, warn};
+
+use crate::transport::channel::Channel;
+use crate::transport::error::TransportError;
+use crate::transport::hid::channel::HidChannel;
+use crate::transport::hid::device::HidBackendDevice;
+use crate::transport::hid::framing::{HidCommand, HidMessage};
+use crate::transport::hid::{list_devices, HidDevice};
+use crate::webauthn::error::Error;
+use crate::UvUpdate;
+
+use super::protocol::{
+    decode_message, write_message, DaemonDeviceInfo, DaemonFailure, DaemonRequest, DaemonResponse,
+    FrameReader, KEEPALIVE_STATUS_PROCESSING, KEEPALIVE_STATUS_UPNEEDED,
+};
+
+/// One lock per device ID, held while a client has the device open.
+type DeviceLocks = Arc<Mutex<HashMap<String, Arc<AsyncMutex<()>>>>>;
+
+/// Listens on `socket_path` and serves clients until an error occurs on the listener.
+#[instrument(skip_all, fields(path = %socket_path.as_ref().display()))]
+pub async fn run_daemon(socket_path: impl AsRef<Path>) -> Result<(), Error> {
+    let listener = UnixListener::bind(socket_path.as_ref())
+        .map_err(|e| Error::Transport(TransportError::IoError(e.kind())))?;
+    info!("Daemon listening for clients");
+
+    let locks = DeviceLocks::default();
+    loop {
+        let (stream, _) = listener
+            .accept()
+            .await
+            .map_err(|e| Error::Transport(TransportError::IoError(e.kind())))?;
+        let locks = Arc::clone(&locks);
+        tokio::spawn(async move {
+            if let Err(err) = serve_client(stream, locks).await {
+                debug!(?err, "Client session ended");
+            }
+        });
+    }
+}
+
+pub(crate) fn device_id(device: &HidDevice) -> String {
+    match &device.backend {
+        HidBackendDevice::HidApiDevice(info) => info.path().to_string_lossy().into_owned(),
+        #[cfg(feature = "virtual-hid-device")]
+        HidBackendDevice::VirtualDevice(_) => String::from("virtual"),
+    }
+}
+
+async fn serve_client(mut stream: UnixStream, locks: DeviceLocks) -> Result<(), Error> {
+    let mut reader = FrameReader::new();
+    let mut next = None;
+    loop {
+        let request = match next.take() {
+            Some(request) => request,
+            None => match read_request(&mut stream, &mut reader).await? {
+                Some(request) => request,
+                None => continue,
+            },
+        };
+        match request {
+            DaemonRequest::ListDevices => {
+                write_message(&mut stream, &device_list().await).await?;
+            }
+            DaemonRequest::Open { device_id: id } => match find_device(&id).await {
+                Ok(Some(device)) => {
+                    let lock = Arc::clone(locks.lock().unwrap().entry(id).or_default());
+                    next = serve_device(&mut stream, &mut reader, &device, &lock).await?;
+                }
+                Ok(None) => {
+                    let response = DaemonResponse::Failed(DaemonFailure::UnknownDevice);
+                    write_message(&mut stream, &response).await?;
+                }
+                Err(err) => {
+                    write_message(&mut stream, &DaemonResponse::Failed((&err).into())).await?;
+                }
+            },
+            DaemonRequest::Transact { .. } => {
+                let response = DaemonResponse::Failed(DaemonFailure::NotOpened);
+                write_message(&mut stream, &response).await?;
+            }
+        }
+    }
+}
+
+/// Reads the next request. Requests which can't be decoded are answered with a failure, and
+/// yield `None`; only errors on the connection itself end the session.
+async fn read_request(
+    stream: &mut UnixStream,
+    reader: &mut FrameReader,
+) -> Result<Option<DaemonRequest>, Error> {
+    let frame = reader.read_frame(stream).await?;
+    match decode_message(&frame) {
+        Ok(request) => Ok(Some(request)),
+        Err(err) => {
+            warn!(?err, "Failed to decode client request");
+            let response = DaemonResponse::Failed(DaemonFailure::InvalidRequest);
+            write_message(stream, &response).await?;
+            Ok(None)
+        }
+    }
+}
+
+async fn device_list() -> DaemonResponse {
+    match list_devices().await {
+        Ok(devices) => DaemonResponse::Devices(
+            devices
+                .iter()
+                .map(|device| DaemonDeviceInfo {
+                    id: device_id(device),
+                    name: device.to_string(),
+                })
+                .collect(),
+        ),
+        Err(err) => DaemonResponse::Failed((&err).into()),
+    }
+}
+
+async fn find_device(id: &str) -> Result<Option<HidDevice>, Error> {
+    Ok(list_devices()
+        .await?
+        .into_iter()
+        .find(|device| device_id(device) == id))
+}
+
+/// Serves the client once it opened `device`, with one HID channel for the whole session, so
+/// that state kept per CTAPHID channel, e.g. for getNextAssertion, survives between requests.
+/// Other clients opening the same device wait until the session ends.
+///
+/// Returns the client's next `Open` request, if it opens another device.
+async fn serve_device(
+    stream: &mut UnixStream,
+    reader: &mut FrameReader,
+    device: &HidDevice,
+    lock: &AsyncMutex<()>,
+) -> Result<Option<DaemonRequest>, Error> {
+    let _guard = lock.lock().await;
+    let opened = async {
+        let channel = HidChannel::new(device).await?;
+        let protocols = channel.supported_protocols().await?;
+        Ok::<_, Error>((channel, protocols))
+    }
+    .await;
+    let channel = match opened {
+        Ok((channel, protocols)) => {
+            let response = DaemonResponse::Opened {
+                u2f: protocols.u2f,
+                fido2: protocols.fido2,
+            };
+            write_message(stream, &response).await?;
+            channel
+        }
+        Err(err) => {
+            write_message(stream, &DaemonResponse::Failed((&err).into())).await?;
+            return Ok(None);
+        }
+    };
+    info!(%device, "Client opened device");
+
+    loop {
+        let Some(request) = read_request(stream, reader).await? else {
+            continue;
+        };
+        match request {
+            DaemonRequest::ListDevices => write_message(stream, &device_list().await).await?,
+            DaemonRequest::Open { .. } => {
+                info!(%device, "Client closed device");
+                return Ok(Some(request));
+            }
+            DaemonRequest::Transact {
+                command,
+                payload,
+                timeout_ms,
+            } => {
+                let timeout = Duration::from_millis(timeout_ms);
+                let response = transact(stream, &channel, command, &payload, timeout).await?;
+                write_message(stream, &response).await?;
+            }
+        }
+    }
+}
+
+/// Forwards a message to the device, and keep-alives to the client until the device answers.
+async fn transact(
+    stream: &mut UnixStream,
+    channel: &HidChannel<'_>,
+    command: u8,
+    payload: &[u8],
+    timeout: Duration,
+) -> Result<DaemonResponse, Error> {
+    let command = match HidCommand::try_from(command) {
+        Ok(command @ (HidCommand::Cbor | HidCommand::Msg)) => command,
+        _ => {
+            warn!(?command, "Refusing to forward unsupported HID command");
+            return Ok(DaemonResponse::Failed(DaemonFailure::UnsupportedCommand));
+        }
+    };
+    let mut updates = channel.get_ux_update_sender().subscribe();
+    let result = async {
+        channel
+            .hid_send(&HidMessage::new(channel.cid(), command, payload))
+            .await?;
+        let recv = channel.hid_recv(timeout);
+        tokio::pin!(recv);
+        loop {
+            tokio::select! {
+                response = &mut recv => break response,
+                Ok(update) = updates.recv() => {
+                    let status = match update {
+                        UvUpdate::Processing => KEEPALIVE_STATUS_PROCESSING,
+                        UvUpdate::PresenceRequired => KEEPALIVE_STATUS_UPNEEDED,
+                        _ => continue,
+                    };
+                    write_message(stream, &DaemonResponse::Keepalive { status }).await?;
+                }
+            }
+        }
+    }
+    .await;
+    Ok(match result {
+        Ok(response) if response.cmd == command => DaemonResponse::Transacted {
+            payload: ByteBuf::from(response.payload),
+        },
+        Ok(response) => {
+            warn!(?response.cmd, "Unexpected HID response command");
+            DaemonResponse::Failed(DaemonFailure::ConnectionLost)
+        }
+        Err(err) => DaemonResponse::Failed((&err).into()),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::socket::write_frame;
+
+    #[tokio::test]
+    async fn invalid_request_keeps_session() {
+        let (mut client, mut server) = UnixStream::pair().unwrap();
+        let (mut client_reader, mut server_reader) = (FrameReader::new(), FrameReader::new());
+        write_frame(&mut client, &[0xff]).await.unwrap();
+        write_message(&mut client, &DaemonRequest::ListDevices)
+            .await
+            .unwrap();
+
+        let request = read_request(&mut server, &mut server_reader).await.unwrap();
+        assert_eq!(request, None);
+        let response: DaemonResponse = client_reader.read_message(&mut client).await.unwrap();
+        assert_eq!(
+            response,
+            DaemonResponse::Failed(DaemonFailure::InvalidRequest)
+        );
+        let request = read_request(&mut server, &mut server_reader).await.unwrap();
+        assert_eq!(request, Some(DaemonRequest::ListDevices));
+    }
+}
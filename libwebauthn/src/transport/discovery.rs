@@ -0,0 +1,354 @@
+//! Watching for authenticators across transports.
+//!
+//! Each transport can list its devices once, e.g. [`crate::transport::hid::list_devices`].
+//! [`DeviceManager`] instead keeps watching all enabled transports concurrently, and reports
+//! devices appearing and disappearing as a single stream of [`DeviceEvent`]s. HID devices are
+//! listed again on hotplug events, other transports periodically.
+//! [`probe_devices`] then fetches the capabilities of many devices at once, e.g. for a picker.
+
+use std::collections::HashSet;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::stream::{self, BoxStream, Stream, StreamExt};
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::UnboundedReceiverStream;
+use tracing::{debug, info, instrument, warn};
+
+use crate::proto::ctap2::{Ctap2, Ctap2GetInfoResponse};
+pub use crate::transport::any::AnyDevice;
+use crate::transport::cable::known_devices::{
+    CableKnownDevice, CableKnownDeviceInfoStore, ClientPayloadHint,
+};
+use crate::transport::hid;
+use crate::transport::hid::device::HidBackendDevice;
+use crate::transport::hid::hotplug::HidHotplug;
+use crate::transport::{Channel, Device, Transport};
+use crate::webauthn::error::Error;
+
+const DEFAULT_POLL_INTERVAL: Duration = Duration::from_secs(1);
+/// Devices probed at once by [probe_devices], e.g. keys behind the same USB hub.
+pub const DEFAULT_PROBE_CONCURRENCY: usize = 8;
+
+#[derive(Debug)]
+pub struct DiscoveredDevice {
+    /// Identifies the device across events, unique among all transports.
+    pub id: String,
+    pub device: AnyDevice,
+}
+
+#[derive(Debug)]
+pub enum DeviceEvent {
+    Added(Box<DiscoveredDevice>),
+    /// The device with the given ID is no longer available.
+    Removed(String),
+}
+
+/// Watches HID devices, and optionally caBLE known devices, for changes.
+#[derive(Debug, Clone)]
+pub struct DeviceManager {
+    poll_interval: Duration,
+    hid: bool,
+    cable_store: Option<Arc<dyn CableKnownDeviceInfoStore>>,
+}
+
+impl Default for DeviceManager {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl DeviceManager {
+    pub fn new() -> Self {
+        Self {
+            poll_interval: DEFAULT_POLL_INTERVAL,
+            hid: true,
+            cable_store: None,
+        }
+    }
+
+    /// How often transports without change notifications are listed again. This includes HID
+    /// if hotplug events aren't available.
+    pub fn with_poll_interval(mut self, poll_interval: Duration) -> Self {
+        self.poll_interval = poll_interval;
+        self
+    }
+
+    pub fn with_hid(mut self, enabled: bool) -> Self {
+        self.hid = enabled;
+        self
+    }
+
+    /// Also reports the devices known to `store`.
+    pub fn with_cable_known_devices(mut self, store: Arc<dyn CableKnownDeviceInfoStore>) -> Self {
+        self.cable_store = Some(store);
+        self
+    }
+
+    /// Starts watching. Devices already present are reported as added first. Watching stops
+    /// once the stream is dropped.
+    #[instrument(skip_all)]
+    pub fn watch(&self) -> BoxStream<'static, DeviceEvent> {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        if self.hid {
+            info!("Watching HID devices");
+            tokio::spawn(watch_source(
+                sender.clone(),
+                hid_changes(self.poll_interval),
+                list_hid_devices,
+            ));
+        }
+        if let Some(store) = &self.cable_store {
+            info!("Watching caBLE known devices");
+            let store = Arc::clone(store);
+            tokio::spawn(watch_source(sender, poll(self.poll_interval), move || {
+                list_cable_known_devices(Arc::clone(&store))
+            }));
+        }
+        UnboundedReceiverStream::new(receiver).boxed()
+    }
+}
+
+/// Opens channels to `devices` and fetches their getInfo, at most `concurrency` at a time, with the
+/// short getInfo timeout. Yields each device's index in `devices` and its response as soon as
+/// it's known, so that slow or U2F-only devices don't hold up the others.
+pub fn probe_devices<'d, D, T, C>(
+    devices: &'d mut [D],
+    concurrency: usize,
+) -> impl Stream<Item = (usize, Result<Ctap2GetInfoResponse, Error>)> + 'd
+where
+    D: Device<'d, T, C>,
+    T: Transport,
+    C: Channel + 'd,
+{
+    stream::iter(devices.iter_mut().enumerate())
+        .map(|(index, device)| async move {
+            let info = match device.channel().await {
+                Ok(mut channel) => channel.ctap2_get_info().await,
+                Err(err) => Err(err),
+            };
+            debug!(index, ok = info.is_ok(), "Probed device");
+            (index, info)
+        })
+        .buffer_unordered(concurrency.max(1))
+}
+
+async fn list_hid_devices() -> Result<Vec<DiscoveredDevice>, Error> {
+    Ok(hid::list_devices()
+        .await?
+        .into_iter()
+        .map(|device| DiscoveredDevice {
+            id: match &device.backend {
+                HidBackendDevice::HidApiDevice(info) => {
+                    format!("hid:{}", info.path().to_string_lossy())
+                }
+                #[cfg(feature = "virtual-hid-device")]
+                HidBackendDevice::VirtualDevice(_) => String::from("hid:virtual"),
+            },
+            device: AnyDevice::Hid(device),
+        })
+        .collect())
+}
+
+async fn list_cable_known_devices(
+    store: Arc<dyn CableKnownDeviceInfoStore>,
+) -> Result<Vec<DiscoveredDevice>, Error> {
+    let mut devices = vec![];
+    for (id, info) in store.list_known_devices().await {
+        let device =
+            CableKnownDevice::new(ClientPayloadHint::GetAssertion, &info, Arc::clone(&store))
+                .await?;
+        devices.push(DiscoveredDevice {
+            id: format!("cable:{}", id),
+            device: AnyDevice::CableKnown(device),
+        });
+    }
+    Ok(devices)
+}
+
+/// Changes of HID devices, from hotplug events, or every `poll_interval` without them.
+fn hid_changes(poll_interval: Duration) -> BoxStream<'static, ()> {
+    match HidHotplug::new() {
+        Ok(hotplug) => hotplug.into_stream(),
+        Err(err) => {
+            warn!(?err, "HID hotplug events unavailable, polling instead");
+            poll(poll_interval)
+        }
+    }
+}
+
+fn poll(poll_interval: Duration) -> BoxStream<'static, ()> {
+    stream::unfold(
+        tokio::time::interval(poll_interval),
+        |mut interval| async move {
+            interval.tick().await;
+            Some(((), interval))
+        },
+    )
+    .boxed()
+}
+
+/// Lists devices once, then again on every item of `changes`, sending events for the
+/// differences. Stops once the event stream is dropped, or `changes` ends.
+async fn watch_source<F, Fut>(
+    sender: mpsc::UnboundedSender<DeviceEvent>,
+    changes: BoxStream<'static, ()>,
+    mut list: F,
+) where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<Vec<DiscoveredDevice>, Error>>,
+{
+    let mut known: HashSet<String> = HashSet::new();
+    let mut changes = stream::iter([()]).chain(changes);
+    loop {
+        tokio::select! {
+            change = changes.next() => {
+                if change.is_none() {
+                    warn!("Device change notifications ended, stopping");
+                    return;
+                }
+            }
+            _ = sender.closed() => {
+                debug!("Device event stream dropped, stopping");
+                return;
+            }
+        }
+        let devices = match list().await {
+            Ok(devices) => devices,
+            Err(err) => {
+                // Keeps reporting the last known devices, rather than removing them all.
+                debug!(?err, "Failed to list devices");
+                continue;
+            }
+        };
+        let current: HashSet<String> = devices.iter().map(|device| device.id.clone()).collect();
+        let removed = known
+            .difference(&current)
+            .inspect(|id| debug!(%id, "Device removed"))
+            .map(|id| DeviceEvent::Removed(id.clone()));
+        let added = devices
+            .into_iter()
+            .filter(|device| !known.contains(&device.id))
+            .inspect(|device| debug!(id = %device.id, "Device added"))
+            .map(|device| DeviceEvent::Added(Box::new(device)));
+        for event in removed.chain(added) {
+            if sender.send(event).is_err() {
+                debug!("Device event stream dropped, stopping");
+                return;
+            }
+        }
+        known = current;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use futures::StreamExt;
+    use tokio::sync::mpsc;
+    use tokio_stream::wrappers::UnboundedReceiverStream;
+
+    use super::{
+        list_cable_known_devices, probe_devices, watch_source, AnyDevice, DeviceEvent,
+        DeviceManager,
+    };
+    use crate::transport::cable::known_devices::{
+        CableKnownDeviceInfo, CableKnownDeviceInfoStore, EphemeralDeviceInfoStore,
+    };
+    use crate::transport::local::memory::VIRTUAL_AAGUID;
+    use crate::transport::local::{Capabilities, LocalDevice, MemoryCredentialStore};
+
+    #[tokio::test]
+    async fn probes_all_devices() {
+        let mut devices: Vec<_> = ["FIDO_2_0", "FIDO_2_1", "FIDO_2_1_PRE"]
+            .into_iter()
+            .map(|version| {
+                LocalDevice::new(version, VIRTUAL_AAGUID, MemoryCredentialStore::default())
+                    .with_capabilities(Capabilities::default().with_versions(&[version]))
+            })
+            .collect();
+
+        let mut probed: Vec<_> = probe_devices(&mut devices, 2).collect().await;
+        probed.sort_by_key(|(index, _)| *index);
+        let versions: Vec<_> = probed
+            .into_iter()
+            .map(|(_, info)| info.unwrap().versions)
+            .collect();
+        assert_eq!(
+            versions,
+            [["FIDO_2_0"], ["FIDO_2_1"], ["FIDO_2_1_PRE"]].map(|v| v.map(String::from).to_vec())
+        );
+    }
+
+    fn known_device_info() -> CableKnownDeviceInfo {
+        CableKnownDeviceInfo {
+            contact_id: vec![],
+            link_id: [0; 8],
+            link_secret: [0; 32],
+            public_key: [0; 65],
+            name: "Phone".to_owned(),
+            tunnel_domain: "cable.ua5v.com".to_owned(),
+        }
+    }
+
+    #[tokio::test]
+    async fn reports_cable_known_devices() {
+        let store = EphemeralDeviceInfoStore::new();
+        store
+            .put_known_device(&"phone".to_owned(), &known_device_info())
+            .await;
+
+        let mut events = DeviceManager::new()
+            .with_hid(false)
+            .with_poll_interval(Duration::from_millis(10))
+            .with_cable_known_devices(Arc::new(store.clone()))
+            .watch();
+
+        let Some(DeviceEvent::Added(added)) = events.next().await else {
+            panic!("expected the known device to be added");
+        };
+        assert_eq!(added.id, "cable:phone");
+        assert!(matches!(added.device, AnyDevice::CableKnown(_)));
+
+        store.delete_known_device(&"phone".to_owned()).await;
+        let Some(DeviceEvent::Removed(removed)) = events.next().await else {
+            panic!("expected the known device to be removed");
+        };
+        assert_eq!(removed, "cable:phone");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn devices_are_listed_on_changes() {
+        let store = Arc::new(EphemeralDeviceInfoStore::new());
+        let (change_sender, changes) = mpsc::unbounded_channel();
+        let (sender, mut events) = mpsc::unbounded_channel();
+        let list_store: Arc<dyn CableKnownDeviceInfoStore> = store.clone();
+        let watcher = tokio::spawn(watch_source(
+            sender,
+            UnboundedReceiverStream::new(changes).boxed(),
+            move || list_cable_known_devices(Arc::clone(&list_store)),
+        ));
+        // Lets the initial listing find no devices.
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        assert!(events.try_recv().is_err());
+
+        store
+            .put_known_device(&"phone".to_owned(), &known_device_info())
+            .await;
+        // Without a change notification, the device isn't listed again.
+        tokio::time::sleep(Duration::from_secs(60)).await;
+        assert!(events.try_recv().is_err());
+
+        change_sender.send(()).unwrap();
+        let Some(DeviceEvent::Added(added)) = events.recv().await else {
+            panic!("expected the known device to be added");
+        };
+        assert_eq!(added.id, "cable:phone");
+
+        drop(events);
+        watcher.await.unwrap();
+    }
+}
@@ -1,4 +1,13 @@
+use std::fmt::Display;
+
+use crate::webauthn::error::RecommendedAction;
+
+/// Errors of the transport carrying CTAP messages to and from the device.
+///
+/// New variants are added as more failures get their own context, e.g. `CableStageFailed`
+/// wraps the error of the caBLE stage it happened in; see [TransportError::root_cause].
 #[derive(thiserror::Error, Debug, PartialEq, Clone)]
+#[non_exhaustive]
 pub enum TransportError {
     #[error("connection failed")]
     ConnectionFailed,
@@ -22,6 +31,105 @@ pub enum TransportError {
     InvalidSignature,
     #[error("input/output error: {0}")]
     IoError(std::io::ErrorKind),
+    /// The device disappeared mid-operation, and did not come back.
+    #[error("device removed")]
+    DeviceRemoved,
+    /// The device disappeared mid-operation, but was plugged back in within the grace
+    /// period. The request was lost, and needs to be sent again.
+    #[error("device reconnected")]
+    DeviceReconnected,
+    /// The device answered a transport-level command, e.g. `CTAPHID_PING`, with another one.
+    #[error("unexpected response to {request}")]
+    UnexpectedResponse { request: &'static str },
+    /// Establishing a caBLE connection failed at `stage`.
+    #[error("caBLE {stage} failed: {source}")]
+    CableStageFailed {
+        stage: CableStage,
+        #[source]
+        source: Box<TransportError>,
+    },
+}
+
+impl TransportError {
+    /// A stable identifier of this error, for reporting. Context, such as the failed request,
+    /// is not part of it.
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::ConnectionFailed => "TRANSPORT_CONNECTION_FAILED",
+            Self::ConnectionLost => "TRANSPORT_CONNECTION_LOST",
+            Self::InvalidEndpoint => "TRANSPORT_INVALID_ENDPOINT",
+            Self::InvalidFraming => "TRANSPORT_INVALID_FRAMING",
+            Self::NegotiationFailed => "TRANSPORT_NEGOTIATION_FAILED",
+            Self::TransportUnavailable => "TRANSPORT_UNAVAILABLE",
+            Self::Timeout => "TRANSPORT_TIMEOUT",
+            Self::UnknownDevice => "TRANSPORT_UNKNOWN_DEVICE",
+            Self::InvalidKey => "TRANSPORT_INVALID_KEY",
+            Self::InvalidSignature => "TRANSPORT_INVALID_SIGNATURE",
+            Self::IoError(_) => "TRANSPORT_IO_ERROR",
+            Self::DeviceRemoved => "TRANSPORT_DEVICE_REMOVED",
+            Self::DeviceReconnected => "TRANSPORT_DEVICE_RECONNECTED",
+            Self::UnexpectedResponse { .. } => "TRANSPORT_UNEXPECTED_RESPONSE",
+            Self::CableStageFailed { .. } => "TRANSPORT_CABLE_STAGE_FAILED",
+        }
+    }
+
+    pub fn recommended_action(&self) -> RecommendedAction {
+        match self {
+            Self::ConnectionFailed
+            | Self::ConnectionLost
+            | Self::Timeout
+            | Self::DeviceReconnected => RecommendedAction::Retry,
+            Self::UnknownDevice
+            | Self::InvalidFraming
+            | Self::IoError(_)
+            | Self::DeviceRemoved
+            | Self::UnexpectedResponse { .. } => RecommendedAction::ReplugDevice,
+            Self::InvalidEndpoint
+            | Self::NegotiationFailed
+            | Self::TransportUnavailable
+            | Self::InvalidKey
+            | Self::InvalidSignature => RecommendedAction::Fatal,
+            Self::CableStageFailed { source, .. } => source.recommended_action(),
+        }
+    }
+
+    pub(crate) fn at_cable_stage(self, stage: CableStage) -> Self {
+        Self::CableStageFailed {
+            stage,
+            source: Box::new(self),
+        }
+    }
+
+    /// The underlying error, without the context of wrapping variants such as
+    /// `CableStageFailed`.
+    pub fn root_cause(&self) -> &TransportError {
+        match self {
+            Self::CableStageFailed { source, .. } => source.root_cause(),
+            error => error,
+        }
+    }
+}
+
+/// The stages of establishing a caBLE connection, in order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum CableStage {
+    /// Waiting for the authenticator's BLE advert.
+    ProximityCheck,
+    /// Connecting to the tunnel server.
+    Connection,
+    /// The Noise handshake with the authenticator.
+    Handshake,
+}
+
+impl Display for CableStage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::ProximityCheck => write!(f, "proximity check"),
+            Self::Connection => write!(f, "tunnel connection"),
+            Self::Handshake => write!(f, "handshake"),
+        }
+    }
 }
 
 impl From<snow::Error> for TransportError {
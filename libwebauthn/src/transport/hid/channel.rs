@@ -1,33 +1,46 @@
+use std::collections::HashMap;
 use std::convert::TryFrom;
+use std::ffi::CStr;
 use std::fmt::{Debug, Display, Formatter};
 use std::io::{Cursor as IOCursor, Seek, SeekFrom};
 use std::ops::DerefMut;
-use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, OnceLock, Weak};
 use std::time::Duration;
 
 use async_trait::async_trait;
 use byteorder::{BigEndian, ReadBytesExt};
 use hidapi::HidDevice as HidApiDevice;
-use rand::{thread_rng, Rng};
 use tokio::sync::broadcast;
 use tokio::sync::mpsc::error::TryRecvError;
 use tokio::sync::mpsc::{self, Receiver, Sender};
-use tokio::time::sleep;
+use tokio::sync::OwnedMutexGuard;
+use tokio::time::{sleep, Instant};
+use tokio_util::sync::CancellationToken;
 use tracing::{debug, info, instrument, trace, warn, Level};
 
 #[cfg(feature = "virtual-hid-device")]
 use tokio::net::UdpSocket;
 
+use crate::audit::AuditTrail;
+use crate::metrics::MetricsRecorder;
+use crate::pin::{PinProvider, PinUvAuthProtocols};
 use crate::proto::ctap1::apdu::{ApduRequest, ApduResponse};
 use crate::proto::ctap1::{Ctap1, Ctap1RegisterRequest};
 use crate::proto::ctap2::cbor::{CborRequest, CborResponse};
 use crate::proto::ctap2::{Ctap2, Ctap2MakeCredentialRequest};
 use crate::proto::CtapError;
-use crate::transport::channel::{AuthTokenData, Channel, ChannelStatus, Ctap2AuthTokenStore};
+use crate::quirks::UsbId;
+use crate::sources;
+use crate::timeout::TimeoutPolicy;
+use crate::transport::channel::{
+    self, ensure_not_cancelled, AuthTokenData, Channel, ChannelStatus, Ctap2AuthTokenStore,
+    DeviceAaguid, MAX_DEVICE_LOCK_DURATION,
+};
 use crate::transport::device::SupportedProtocols;
 use crate::transport::error::TransportError;
 use crate::transport::hid::framing::{
-    HidCommand, HidMessage, HidMessageParser, HidMessageParserState,
+    BorrowedHidMessage, HidCommand, HidMessage, HidMessageParser, HidMessageParserState,
 };
 use crate::webauthn::error::{Error, PlatformError};
 use crate::UvUpdate;
@@ -47,13 +60,34 @@ const REPORT_ID: u8 = 0x00;
 // by a CBOR command, so we want to ensure we wait some time after winking.
 const WINK_MIN_WAIT: Duration = Duration::from_secs(2);
 
+/// How long to wait for a device unplugged mid-operation to come back, by default.
+pub const DEFAULT_REMOVAL_GRACE_PERIOD: Duration = Duration::from_secs(5);
+const REMOVAL_POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+const KEEPALIVE_STATUS_PROCESSING: u8 = 0x01;
+const KEEPALIVE_STATUS_UPNEEDED: u8 = 0x02;
+
+const CANCEL_POLL_INTERVAL: Duration = Duration::from_millis(100);
+/// How long to wait for the device to answer a request aborted with CTAPHID_CANCEL.
+const CANCEL_RESPONSE_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// How long to wait for other channels to finish, and for the device to answer CTAPHID_LOCK.
+const LOCK_TIMEOUT: Duration = Duration::from_secs(1);
+
 pub type CancelHidOperation = ();
 enum OpenHidDevice {
-    HidApiDevice(Arc<Mutex<(HidApiDevice, mpsc::Receiver<CancelHidOperation>)>>),
+    HidApiDevice(Arc<Mutex<HidApiConnection>>),
     #[cfg(feature = "virtual-hid-device")]
     VirtualDevice,
 }
 
+struct HidApiConnection {
+    device: HidApiDevice,
+    cancel_rx: Receiver<CancelHidOperation>,
+    /// Outgoing reports are written here, and it is kept across requests.
+    report: Vec<u8>,
+}
+
 #[derive(Debug, Clone)]
 pub struct HidChannelHandle {
     tx: Sender<CancelHidOperation>,
@@ -70,9 +104,46 @@ pub struct HidChannel<'d> {
     device: &'d HidDevice,
     open_device: OpenHidDevice,
     init: InitResponse,
+    // Changes when the device is reconnected, see recover_connection_lost()
+    cid: AtomicU32,
+    removal_grace_period: Duration,
     auth_token_data: Option<AuthTokenData>,
+    aaguid: DeviceAaguid,
+    pin_provider: Option<Arc<dyn PinProvider>>,
+    cancellation_token: Option<CancellationToken>,
+    timeout_policy: TimeoutPolicy,
+    audit_trail: Option<AuditTrail>,
+    metrics_recorder: Option<Arc<dyn MetricsRecorder>>,
+    pin_uv_auth_protocols: PinUvAuthProtocols,
     ux_update_sender: broadcast::Sender<UvUpdate>,
     handle: HidChannelHandle,
+    // Shared by all channels to the same device, see begin_transaction()
+    transaction_lock: Arc<tokio::sync::Mutex<()>>,
+    transaction: Mutex<Option<OwnedMutexGuard<()>>>,
+    // Whether CTAPHID_LOCK is held, in which case the transaction lock is kept between requests
+    device_locked: AtomicBool,
+}
+
+/// Advisory locks, serializing the transactions of all channels in this process to a device.
+fn transaction_lock(device: &HidDevice) -> Arc<tokio::sync::Mutex<()>> {
+    type Locks = Mutex<HashMap<String, Weak<tokio::sync::Mutex<()>>>>;
+    static LOCKS: OnceLock<Locks> = OnceLock::new();
+    let key = match &device.backend {
+        HidBackendDevice::HidApiDevice(info) => info.path().to_string_lossy().into_owned(),
+        #[cfg(feature = "virtual-hid-device")]
+        HidBackendDevice::VirtualDevice(_) => String::from("virtual"),
+    };
+    let mut locks = LOCKS
+        .get_or_init(Default::default)
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    locks.retain(|_, lock| lock.strong_count() > 0);
+    if let Some(lock) = locks.get(&key).and_then(Weak::upgrade) {
+        return lock;
+    }
+    let lock = Arc::new(tokio::sync::Mutex::new(()));
+    locks.insert(key, Arc::downgrade(&lock));
+    lock
 }
 
 impl<'d> HidChannel<'d> {
@@ -87,24 +158,93 @@ impl<'d> HidChannel<'d> {
             open_device: match device.backend {
                 HidBackendDevice::HidApiDevice(_) => {
                     let hidapi_device = Self::hid_open(device)?;
-                    OpenHidDevice::HidApiDevice(Arc::new(Mutex::new((hidapi_device, handle_rx))))
+                    OpenHidDevice::HidApiDevice(Arc::new(Mutex::new(HidApiConnection {
+                        device: hidapi_device,
+                        cancel_rx: handle_rx,
+                        report: Vec::with_capacity(PACKET_SIZE + 1),
+                    })))
                 }
                 #[cfg(feature = "virtual-hid-device")]
                 HidBackendDevice::VirtualDevice(_) => OpenHidDevice::VirtualDevice,
             },
             init: InitResponse::default(),
+            cid: AtomicU32::default(),
+            removal_grace_period: DEFAULT_REMOVAL_GRACE_PERIOD,
             auth_token_data: None,
+            aaguid: DeviceAaguid::default(),
+            pin_provider: None,
+            cancellation_token: None,
+            timeout_policy: TimeoutPolicy::default(),
+            audit_trail: None,
+            metrics_recorder: None,
+            pin_uv_auth_protocols: PinUvAuthProtocols::default(),
             ux_update_sender,
             handle,
+            transaction_lock: transaction_lock(device),
+            transaction: Mutex::new(None),
+            device_locked: AtomicBool::new(false),
         };
         channel.init = channel.init(INIT_TIMEOUT).await?;
+        channel.cid.store(channel.init.cid, Ordering::Relaxed);
         Ok(channel)
     }
 
+    /// How long to wait for the device to be plugged back in, if it disappears mid-operation.
+    /// Set to zero to fail immediately with `TransportError::DeviceRemoved`.
+    pub fn set_removal_grace_period(&mut self, grace_period: Duration) {
+        self.removal_grace_period = grace_period;
+    }
+
     pub fn get_handle(&self) -> HidChannelHandle {
         self.handle.clone()
     }
 
+    /// Channel ID allocated by the device during CTAPHID_INIT.
+    pub fn cid(&self) -> u32 {
+        self.cid.load(Ordering::Relaxed)
+    }
+
+    /// Sends CTAPHID_PING with `payload`, and checks that the device echoes it back unchanged.
+    #[instrument(skip_all, fields(payload_len = payload.len()))]
+    pub async fn ping(&self, payload: &[u8], timeout: Duration) -> Result<(), Error> {
+        self.begin_transaction(timeout).await?;
+        let response = self
+            .transact(
+                &HidMessage::new(self.cid(), HidCommand::Ping, payload),
+                timeout,
+            )
+            .await;
+        self.end_transaction();
+        let response = response?;
+        if response.cmd != HidCommand::Ping {
+            warn!(?response.cmd, payload = ?response.payload, "Invalid response to PING request");
+            return Err(Error::Transport(TransportError::UnexpectedResponse {
+                request: "CTAPHID_PING",
+            }));
+        }
+        if response.payload != payload {
+            warn!("PING response does not echo the request payload");
+            return Err(Error::Transport(TransportError::InvalidFraming));
+        }
+        Ok(())
+    }
+
+    /// Measures the round-trip time of a CTAPHID_PING carrying `payload_len` random bytes.
+    #[instrument(skip_all)]
+    pub async fn ping_latency(
+        &self,
+        payload_len: usize,
+        timeout: Duration,
+    ) -> Result<Duration, Error> {
+        let mut payload = vec![0; payload_len];
+        sources::fill_bytes(&mut payload);
+        let start = Instant::now();
+        self.ping(&payload, timeout).await?;
+        let latency = start.elapsed();
+        debug!(?latency, payload_len, "PING round-trip complete");
+        Ok(latency)
+    }
+
     #[instrument(skip_all)]
     pub async fn wink(&mut self, timeout: Duration) -> Result<bool, Error> {
         if !self.init.caps.contains(Caps::WINK) {
@@ -112,7 +252,7 @@ impl<'d> HidChannel<'d> {
             return Ok(false);
         }
 
-        self.hid_send(&HidMessage::new(self.init.cid, HidCommand::Wink, &[]))
+        self.hid_send(&HidMessage::new(self.cid(), HidCommand::Wink, &[]))
             .await?;
         // Solokey does not seem to return an answer for wink and hangs here.
         if cfg!(not(feature = "virtual-hid-device")) {
@@ -164,8 +304,8 @@ impl<'d> HidChannel<'d> {
     }
 
     #[instrument(level = Level::DEBUG, skip_all)]
-    async fn init(&mut self, timeout: Duration) -> Result<InitResponse, Error> {
-        let nonce: [u8; 8] = thread_rng().gen();
+    async fn init(&self, timeout: Duration) -> Result<InitResponse, Error> {
+        let nonce: [u8; 8] = sources::random();
         let request = HidMessage::broadcast(HidCommand::Init, &nonce);
 
         self.hid_send(&request).await?;
@@ -173,7 +313,9 @@ impl<'d> HidChannel<'d> {
 
         if response.cmd != HidCommand::Init {
             warn!(?response.cmd, "Invalid response to INIT request");
-            return Err(Error::Transport(TransportError::InvalidEndpoint));
+            return Err(Error::Transport(TransportError::UnexpectedResponse {
+                request: "CTAPHID_INIT",
+            }));
         }
 
         if response.payload.len() < INIT_PAYLOAD_LEN {
@@ -216,9 +358,103 @@ impl<'d> HidChannel<'d> {
         }
     }
 
+    /// Maps a lost connection to either `DeviceRemoved` or `DeviceReconnected`, if the device was
+    /// unplugged and came back within the grace period. Other errors are returned as they are.
+    async fn recover_connection_lost<T>(&self, result: Result<T, Error>) -> Result<T, Error> {
+        let Err(Error::Transport(TransportError::ConnectionLost)) = result else {
+            return result;
+        };
+        let (info, open_device) = match (&self.device.backend, &self.open_device) {
+            (HidBackendDevice::HidApiDevice(info), OpenHidDevice::HidApiDevice(open_device)) => {
+                (info, open_device)
+            }
+            #[cfg(feature = "virtual-hid-device")]
+            _ => return result,
+        };
+        let hidapi = get_hidapi()?;
+        if hidapi.device_list().any(|d| d.path() == info.path()) {
+            // Still plugged in, so this is some other I/O failure.
+            return result;
+        }
+
+        warn!(grace_period = ?self.removal_grace_period, "Device removed mid-operation");
+        if self.ux_update_sender.send(UvUpdate::DeviceRemoved).is_err() {
+            debug!("No receivers for UX update");
+        }
+        let deadline = Instant::now() + self.removal_grace_period;
+        while Instant::now() < deadline {
+            sleep(REMOVAL_POLL_INTERVAL).await;
+            let Ok(hidapi) = get_hidapi() else {
+                continue;
+            };
+            let identity = DeviceIdentity::from(info);
+            let Some(reopened) = hidapi
+                .device_list()
+                .find(|d| identity.matches(&DeviceIdentity::from(*d)))
+                .and_then(|d| {
+                    d.open_device(&hidapi)
+                        .inspect_err(|err| debug!(?err, "Failed to reopen device"))
+                        .ok()
+                })
+            else {
+                continue;
+            };
+            match open_device.lock() {
+                Ok(mut guard) => guard.device = reopened,
+                Err(_) => return result,
+            }
+            let Ok(init) = self.init(INIT_TIMEOUT).await else {
+                continue;
+            };
+            self.cid.store(init.cid, Ordering::Relaxed);
+            info!(
+                { cid = init.cid },
+                "Device reconnected, operation needs to be retried"
+            );
+            return Err(Error::Transport(TransportError::DeviceReconnected));
+        }
+        warn!("Device did not come back within the grace period");
+        Err(Error::Transport(TransportError::DeviceRemoved))
+    }
+
+    /// Sends `msg` and receives the response, recovering from the device being re-plugged.
+    async fn transact(&self, msg: &HidMessage, timeout: Duration) -> Result<HidMessage, Error> {
+        let result = self.hid_send(msg).await;
+        self.recover_connection_lost(result).await?;
+        let result = self.hid_recv(timeout).await;
+        self.recover_connection_lost(result).await
+    }
+
+    /// Waits for other channels in this process to complete their requests to the device, so
+    /// that theirs can't interleave with ours. Ends with the response, see end_transaction().
+    async fn begin_transaction(&self, timeout: Duration) -> Result<(), Error> {
+        if self.transaction_guard().is_some() {
+            return Ok(());
+        }
+        let lock = Arc::clone(&self.transaction_lock);
+        let Ok(guard) = tokio::time::timeout(timeout, lock.lock_owned()).await else {
+            warn!("Timed out waiting for another channel to the same device");
+            return Err(Error::Transport(TransportError::Timeout));
+        };
+        *self.transaction_guard() = Some(guard);
+        Ok(())
+    }
+
+    fn end_transaction(&self) {
+        if !self.device_locked.load(Ordering::Relaxed) {
+            self.transaction_guard().take();
+        }
+    }
+
+    fn transaction_guard(&self) -> MutexGuard<'_, Option<OwnedMutexGuard<()>>> {
+        self.transaction
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
     #[instrument(level = Level::DEBUG, skip_all)]
     pub async fn hid_cancel(&self) -> Result<(), Error> {
-        self.hid_send(&HidMessage::new(self.init.cid, HidCommand::Cancel, &[]))
+        self.hid_send(&HidMessage::new(self.cid(), HidCommand::Cancel, &[]))
             .await
     }
 
@@ -264,23 +500,37 @@ impl<'d> HidChannel<'d> {
     }
     */
 
-    #[instrument(skip_all, fields(cmd = ?msg.cmd, payload_len = msg.payload.len()))]
     pub async fn hid_send(&self, msg: &HidMessage) -> Result<(), Error> {
+        self.hid_send_borrowed(BorrowedHidMessage {
+            cid: msg.cid,
+            cmd: msg.cmd,
+            payload: &[&msg.payload],
+        })
+        .await
+    }
+
+    #[instrument(skip_all, fields(cmd = ?msg.cmd, payload_len = msg.payload_len()))]
+    async fn hid_send_borrowed(&self, msg: BorrowedHidMessage<'_>) -> Result<(), Error> {
         match &self.open_device {
             OpenHidDevice::HidApiDevice(hidapi_device) => {
                 let Ok(mut guard) = hidapi_device.lock() else {
                     warn!("Poisoned lock on HID API device");
                     return Err(Error::Transport(TransportError::ConnectionLost));
                 };
-                let (device, cancel_rx) = guard.deref_mut();
-                let response = Self::hid_send_hidapi(device, cancel_rx, msg);
+                let connection = guard.deref_mut();
+                let response = Self::hid_send_hidapi(connection, msg);
                 if matches!(response, Err(Error::Platform(PlatformError::Cancelled))) {
                     // Using hid_send_hidapi directly, instead of hid_cancel, to avoid recursion
-                    let _ = Self::hid_send_hidapi(
-                        device,
-                        cancel_rx,
-                        &HidMessage::new(self.init.cid, HidCommand::Cancel, &[]),
-                    );
+                    if let Err(err) = Self::hid_send_hidapi(
+                        connection,
+                        BorrowedHidMessage {
+                            cid: self.cid(),
+                            cmd: HidCommand::Cancel,
+                            payload: &[],
+                        },
+                    ) {
+                        warn!(?err, "Failed to send CTAPHID_CANCEL");
+                    }
                 }
                 response
             }
@@ -290,56 +540,55 @@ impl<'d> HidChannel<'d> {
     }
 
     fn hid_send_hidapi(
-        device: &hidapi::HidDevice,
-        cancel_rx: &mut Receiver<CancelHidOperation>,
-        msg: &HidMessage,
+        connection: &mut HidApiConnection,
+        msg: BorrowedHidMessage<'_>,
     ) -> Result<(), Error> {
-        let packets = msg
-            .packets(PACKET_SIZE)
-            .or(Err(Error::Transport(TransportError::InvalidFraming)))?;
-        for (i, packet) in packets.iter().enumerate() {
+        let HidApiConnection {
+            device,
+            cancel_rx,
+            report,
+        } = connection;
+        let mut i = 0;
+        msg.write_reports(PACKET_SIZE, Some(REPORT_ID), report, |report| {
             if !matches!(cancel_rx.try_recv(), Err(TryRecvError::Empty)) {
                 return Err(Error::Platform(PlatformError::Cancelled));
             }
 
-            let mut report: Vec<u8> = vec![REPORT_ID];
-            report.extend(packet);
-            report.extend(vec![0; PACKET_SIZE - packet.len()]);
             debug!({ packet = i, len = report.len() }, "Sending packet as HID report",);
             trace!(?report);
+            i += 1;
             device
-                .write(&report)
+                .write(report)
                 .or(Err(Error::Transport(TransportError::ConnectionLost)))?;
-        }
-        Ok(())
+            Ok(())
+        })
     }
 
     #[cfg(feature = "virtual-hid-device")]
-    async fn hid_send_virtual(msg: &HidMessage) -> Result<(), Error> {
+    async fn hid_send_virtual(msg: BorrowedHidMessage<'_>) -> Result<(), Error> {
         // https://github.com/solokeys/python-fido2/commit/4964d98ca6d0cfc24cd49926521282b8e92c598d
         let socket = UdpSocket::bind("127.0.0.1:7112")
             .await
             .or(Err(Error::Transport(TransportError::TransportUnavailable)))?;
 
-        debug!({ cmd = ?msg.cmd, payload_len = msg.payload.len() }, "U2F HID request to UDP virtual device");
+        debug!({ cmd = ?msg.cmd, payload_len = msg.payload_len() }, "U2F HID request to UDP virtual device");
         trace!(?msg);
 
-        let packets = msg
-            .packets(PACKET_SIZE)
-            .or(Err(Error::Transport(TransportError::InvalidFraming)))?;
-        for (i, packet) in packets.iter().enumerate() {
-            let mut report: Vec<u8> = vec![];
-            report.extend(packet);
-            report.extend(vec![0; PACKET_SIZE - packet.len()]);
-
+        // Collected first, as reports can't be sent asynchronously while being written.
+        let mut reports = vec![];
+        msg.write_reports(PACKET_SIZE, None, &mut vec![], |report| {
+            reports.push(report.to_vec());
+            Ok(())
+        })?;
+        for (i, report) in reports.iter().enumerate() {
             debug!(
                 { packet = i, len = report.len() },
                 "Sending packet as HID report",
             );
-            trace!(?packet);
+            trace!(?report);
 
             socket
-                .send_to(&report, "127.0.0.1:8111")
+                .send_to(report, "127.0.0.1:8111")
                 .await
                 .or(Err(Error::Transport(TransportError::ConnectionLost)))?;
         }
@@ -347,12 +596,70 @@ impl<'d> HidChannel<'d> {
         Ok(())
     }
 
+    /// Receives the next message, skipping keep-alives. If the operation is cancelled or times
+    /// out, the pending request is aborted with CTAPHID_CANCEL.
     #[instrument(skip_all)]
     pub async fn hid_recv(&self, timeout: Duration) -> Result<HidMessage, Error> {
+        let deadline = Instant::now() + timeout;
+        let response = match self.cancellation_token.clone() {
+            // Cancels through the handle, so that the blocking read notices.
+            Some(token) => {
+                let handle = self.get_handle();
+                let cancel = async move {
+                    token.cancelled().await;
+                    handle.cancel_ongoing_operation().await;
+                    futures::future::pending::<()>().await
+                };
+                tokio::select! {
+                    response = self.hid_recv_until(deadline) => response,
+                    () = cancel => unreachable!(),
+                }
+            }
+            None => self.hid_recv_until(deadline).await,
+        };
+        if matches!(response, Err(Error::Platform(PlatformError::Cancelled)))
+            && self
+                .cancellation_token
+                .as_ref()
+                .is_some_and(|token| token.is_cancelled())
+        {
+            channel::cancelled(&self.ux_update_sender);
+        }
+        if matches!(
+            response,
+            Err(Error::Platform(PlatformError::Cancelled))
+                | Err(Error::Transport(TransportError::Timeout))
+        ) {
+            self.abort_pending_request().await;
+        }
+        response
+    }
+
+    /// Sends CTAPHID_CANCEL, and swallows the device's response to the aborted request, usually
+    /// CTAP2_ERR_KEEPALIVE_CANCEL, so it isn't mistaken for the response to the next one.
+    async fn abort_pending_request(&self) {
+        if let Err(err) = self.hid_cancel().await {
+            warn!(?err, "Failed to send CTAPHID_CANCEL");
+            return;
+        }
+        match self
+            .hid_recv_until(Instant::now() + CANCEL_RESPONSE_TIMEOUT)
+            .await
+        {
+            Ok(response) => {
+                debug!({ cmd = ?response.cmd, payload = ?response.payload }, "Discarded response to cancelled request")
+            }
+            Err(err) => debug!(?err, "No response to cancelled request"),
+        }
+    }
+
+    async fn hid_recv_until(&self, deadline: Instant) -> Result<HidMessage, Error> {
+        let mut processing = false;
         loop {
             let response = match &self.open_device {
                 OpenHidDevice::HidApiDevice(hidapi_device) => {
                     let device = Arc::clone(hidapi_device);
+                    let deadline = deadline.into_std();
                     // The HID device will block when waiting for a user to
                     // interact with the device, so mark the task as blocking to
                     // allow other tasks to complete.
@@ -363,28 +670,51 @@ impl<'d> HidChannel<'d> {
                             warn!("Poisoned lock on HID API device");
                             return Err(Error::Transport(TransportError::ConnectionLost));
                         };
-                        let (device, cancel_rx) = guard.deref_mut();
-                        Self::hid_recv_hidapi(device, cancel_rx, timeout)
+                        let HidApiConnection {
+                            device, cancel_rx, ..
+                        } = guard.deref_mut();
+                        Self::hid_recv_hidapi(device, cancel_rx, deadline)
                     })
                     .await
                     .expect("HID read not to panic.")
                 }
                 #[cfg(feature = "virtual-hid-device")]
-                OpenHidDevice::VirtualDevice => Self::hid_recv_virtual(timeout).await,
+                OpenHidDevice::VirtualDevice => {
+                    Self::hid_recv_virtual(deadline.saturating_duration_since(Instant::now())).await
+                }
             };
 
             match response {
                 Ok(HidMessage {
                     cmd: HidCommand::KeepAlive,
+                    payload,
                     ..
                 }) => {
-                    debug!("Ignoring HID keep-alive");
+                    let status = payload.first().copied();
+                    debug!(?status, "Received HID keep-alive");
+                    // Callers already prompt for presence before sending the request, so only
+                    // transitions from processing are reported.
+                    match status {
+                        Some(KEEPALIVE_STATUS_PROCESSING) if !processing => {
+                            processing = true;
+                            if self.ux_update_sender.send(UvUpdate::Processing).is_err() {
+                                debug!("No receivers for UX update");
+                            }
+                        }
+                        Some(KEEPALIVE_STATUS_UPNEEDED) if processing => {
+                            processing = false;
+                            if self
+                                .ux_update_sender
+                                .send(UvUpdate::PresenceRequired)
+                                .is_err()
+                            {
+                                debug!("No receivers for UX update");
+                            }
+                        }
+                        _ => (),
+                    }
                     continue;
                 }
-                Err(Error::Platform(PlatformError::Cancelled)) => {
-                    let _ = self.hid_cancel().await;
-                    break response;
-                }
                 _ => break response,
             }
         }
@@ -393,7 +723,7 @@ impl<'d> HidChannel<'d> {
     fn hid_recv_hidapi(
         device: &hidapi::HidDevice,
         cancel_rx: &mut Receiver<CancelHidOperation>,
-        timeout: Duration,
+        deadline: std::time::Instant,
     ) -> Result<HidMessage, Error> {
         let mut parser = HidMessageParser::new();
         loop {
@@ -401,14 +731,25 @@ impl<'d> HidChannel<'d> {
                 return Err(Error::Platform(PlatformError::Cancelled));
             }
 
+            let now = std::time::Instant::now();
+            if now >= deadline {
+                warn!("Timed out waiting for HID response");
+                return Err(Error::Transport(TransportError::Timeout));
+            }
+            // Reads in short slices, so cancellation is noticed while waiting.
+            let wait = (deadline - now).min(CANCEL_POLL_INTERVAL);
+
             let mut report = [0; PACKET_SIZE];
-            device
-                .read_timeout(&mut report, timeout.as_millis() as i32)
+            let len = device
+                .read_timeout(&mut report, wait.as_millis() as i32)
                 .or(Err(Error::Transport(TransportError::ConnectionLost)))?;
-            debug!({ len = report.len() }, "Received HID report");
+            if len == 0 {
+                continue;
+            }
+            debug!({ len }, "Received HID report");
             trace!(?report);
             if let HidMessageParserState::Done = parser
-                .update(&report)
+                .update(&report[..len])
                 .or(Err(Error::Transport(TransportError::InvalidFraming)))?
             {
                 break;
@@ -416,7 +757,7 @@ impl<'d> HidChannel<'d> {
         }
 
         let response = parser
-            .message()
+            .into_message()
             .or(Err(Error::Transport(TransportError::InvalidFraming)))?;
         debug!({ cmd = ?response.cmd, payload_len = response.payload.len() }, "Received U2F HID response");
         trace!(?response);
@@ -452,7 +793,7 @@ impl<'d> HidChannel<'d> {
         }
 
         let response = parser
-            .message()
+            .into_message()
             .or(Err(Error::Transport(TransportError::InvalidFraming)))?;
         debug!({ cmd = ?response.cmd }, "Parsed U2F HID response from UDP virtual device");
         trace!(?response);
@@ -488,6 +829,14 @@ impl Display for HidChannel<'_> {
 impl Channel for HidChannel<'_> {
     type UxUpdate = UvUpdate;
 
+    fn transport_name(&self) -> &'static str {
+        "hid"
+    }
+
+    fn device_aaguid(&self) -> Option<&DeviceAaguid> {
+        Some(&self.aaguid)
+    }
+
     async fn supported_protocols(&self) -> Result<SupportedProtocols, Error> {
         let cbor_supported = self.init.caps.contains(Caps::CBOR);
         let apdu_supported = !self.init.caps.contains(Caps::NO_MSG);
@@ -508,21 +857,31 @@ impl Channel for HidChannel<'_> {
     async fn apdu_send(
         &self,
         request: &ApduRequest,
-        _timeout: std::time::Duration,
+        timeout: std::time::Duration,
     ) -> Result<(), Error> {
-        let cid = self.init.cid;
+        ensure_not_cancelled(self.cancellation_token.as_ref(), &self.ux_update_sender)?;
+        let cid = self.cid();
         debug!({ cid }, "Sending APDU request");
         trace!(?request);
         let apdu_raw = request
             .raw_long()
             .map_err(|e| TransportError::IoError(e.kind()))?;
-        self.hid_send(&HidMessage::new(cid, HidCommand::Msg, &apdu_raw))
-            .await?;
-        Ok(())
+        self.begin_transaction(timeout).await?;
+        let result = self
+            .hid_send(&HidMessage::from_payload(cid, HidCommand::Msg, apdu_raw))
+            .await;
+        let result = self.recover_connection_lost(result).await;
+        if result.is_err() {
+            self.end_transaction();
+        }
+        result
     }
 
     async fn apdu_recv(&self, timeout: std::time::Duration) -> Result<ApduResponse, Error> {
-        let hid_response = self.hid_recv(timeout).await?;
+        let result = self.hid_recv(timeout).await;
+        let result = self.recover_connection_lost(result).await;
+        self.end_transaction();
+        let hid_response = result?;
         let apdu_response = ApduResponse::try_from(&hid_response.payload)
             .or(Err(Error::Transport(TransportError::InvalidFraming)))?;
         debug!("Received APDU response");
@@ -530,21 +889,31 @@ impl Channel for HidChannel<'_> {
         Ok(apdu_response)
     }
 
-    async fn cbor_send(&mut self, request: &CborRequest, _timeout: Duration) -> Result<(), Error> {
-        let cid = self.init.cid;
+    async fn cbor_send(&mut self, request: &CborRequest, timeout: Duration) -> Result<(), Error> {
+        ensure_not_cancelled(self.cancellation_token.as_ref(), &self.ux_update_sender)?;
+        let cid = self.cid();
         debug!({ cid }, "Sending CBOR request");
         trace!(?request);
-        self.hid_send(&HidMessage::new(
-            cid,
-            HidCommand::Cbor,
-            &request.ctap_hid_data(),
-        ))
-        .await?;
-        Ok(())
+        self.begin_transaction(timeout).await?;
+        let result = self
+            .hid_send_borrowed(BorrowedHidMessage {
+                cid,
+                cmd: HidCommand::Cbor,
+                payload: &[&[request.command as u8], &request.encoded_data],
+            })
+            .await;
+        let result = self.recover_connection_lost(result).await;
+        if result.is_err() {
+            self.end_transaction();
+        }
+        result
     }
 
     async fn cbor_recv(&mut self, timeout: Duration) -> Result<CborResponse, Error> {
-        let hid_response = self.hid_recv(timeout).await?;
+        let result = self.hid_recv(timeout).await;
+        let result = self.recover_connection_lost(result).await;
+        self.end_transaction();
+        let hid_response = result?;
         let cbor_response = CborResponse::try_from(&hid_response.payload)
             .or(Err(Error::Transport(TransportError::InvalidFraming)))?;
         debug!(
@@ -558,6 +927,160 @@ impl Channel for HidChannel<'_> {
     fn get_ux_update_sender(&self) -> &broadcast::Sender<UvUpdate> {
         &self.ux_update_sender
     }
+
+    fn get_pin_provider(&self) -> Option<Arc<dyn PinProvider>> {
+        self.pin_provider.clone()
+    }
+
+    fn set_pin_provider(&mut self, provider: Option<Arc<dyn PinProvider>>) {
+        self.pin_provider = provider;
+    }
+
+    fn get_cancellation_token(&self) -> Option<CancellationToken> {
+        self.cancellation_token.clone()
+    }
+
+    fn set_cancellation_token(&mut self, token: Option<CancellationToken>) {
+        self.cancellation_token = token;
+    }
+
+    fn get_timeout_policy(&self) -> TimeoutPolicy {
+        self.timeout_policy
+    }
+
+    fn set_timeout_policy(&mut self, policy: TimeoutPolicy) {
+        self.timeout_policy = policy;
+    }
+
+    fn get_audit_trail(&self) -> Option<AuditTrail> {
+        self.audit_trail.clone()
+    }
+
+    fn set_audit_trail(&mut self, trail: Option<AuditTrail>) {
+        self.audit_trail = trail;
+    }
+
+    fn get_metrics_recorder(&self) -> Option<Arc<dyn MetricsRecorder>> {
+        self.metrics_recorder.clone()
+    }
+
+    fn set_metrics_recorder(&mut self, recorder: Option<Arc<dyn MetricsRecorder>>) {
+        self.metrics_recorder = recorder;
+    }
+
+    fn get_pin_uv_auth_protocols(&self) -> PinUvAuthProtocols {
+        self.pin_uv_auth_protocols.clone()
+    }
+
+    fn set_pin_uv_auth_protocols(&mut self, protocols: PinUvAuthProtocols) {
+        self.pin_uv_auth_protocols = protocols;
+    }
+
+    /// Takes the CTAPHID_LOCK, for at most 10 seconds. Other channels in this process wait
+    /// for it to be released, other applications get CTAP1_ERR_CHANNEL_BUSY.
+    #[instrument(skip(self))]
+    async fn lock_device(&mut self, duration: Duration) -> Result<(), Error> {
+        let seconds = duration.min(MAX_DEVICE_LOCK_DURATION).as_secs().max(1) as u8;
+        self.begin_transaction(LOCK_TIMEOUT).await?;
+        let response = self
+            .transact(
+                &HidMessage::new(self.cid(), HidCommand::Lock, &[seconds]),
+                LOCK_TIMEOUT,
+            )
+            .await;
+        match response {
+            Ok(response) if response.cmd == HidCommand::Lock => {
+                debug!({ seconds }, "Device locked");
+                self.device_locked.store(true, Ordering::Relaxed);
+                Ok(())
+            }
+            Ok(response) => {
+                warn!(?response.cmd, payload = ?response.payload, "Invalid response to LOCK request");
+                self.end_transaction();
+                Err(Error::Transport(TransportError::UnexpectedResponse {
+                    request: "CTAPHID_LOCK",
+                }))
+            }
+            Err(err) => {
+                self.end_transaction();
+                Err(err)
+            }
+        }
+    }
+
+    #[instrument(skip(self))]
+    async fn unlock_device(&mut self) -> Result<(), Error> {
+        if !self.device_locked.swap(false, Ordering::Relaxed) {
+            return Ok(());
+        }
+        let response = self
+            .transact(
+                &HidMessage::new(self.cid(), HidCommand::Lock, &[0]),
+                LOCK_TIMEOUT,
+            )
+            .await;
+        self.end_transaction();
+        debug!("Device unlocked");
+        response.map(|_| ())
+    }
+
+    fn usb_id(&self) -> Option<UsbId> {
+        match &self.device.backend {
+            HidBackendDevice::HidApiDevice(info) => Some(UsbId {
+                vendor_id: info.vendor_id(),
+                product_id: info.product_id(),
+            }),
+            #[cfg(feature = "virtual-hid-device")]
+            HidBackendDevice::VirtualDevice(_) => None,
+        }
+    }
+}
+
+/// What identifies a HID device across re-enumeration, see [DeviceIdentity::matches].
+#[derive(Debug, PartialEq)]
+struct DeviceIdentity<'a> {
+    vendor_id: u16,
+    product_id: u16,
+    usage_page: u16,
+    usage: u16,
+    serial_number: Option<&'a str>,
+    path: &'a CStr,
+}
+
+impl<'a> From<&'a hidapi::DeviceInfo> for DeviceIdentity<'a> {
+    fn from(info: &'a hidapi::DeviceInfo) -> Self {
+        Self {
+            vendor_id: info.vendor_id(),
+            product_id: info.product_id(),
+            usage_page: info.usage_page(),
+            usage: info.usage(),
+            serial_number: info.serial_number().filter(|serial| !serial.is_empty()),
+            path: info.path(),
+        }
+    }
+}
+
+impl DeviceIdentity<'_> {
+    /// Whether `other` is the same device. The device node usually changes on
+    /// re-enumeration, so devices with a serial number are matched on it. Devices without
+    /// one can't be told apart from others of the same model, so they only match at the same
+    /// path.
+    fn matches(&self, other: &DeviceIdentity) -> bool {
+        if (self.vendor_id, self.product_id, self.usage_page, self.usage)
+            != (
+                other.vendor_id,
+                other.product_id,
+                other.usage_page,
+                other.usage,
+            )
+        {
+            return false;
+        }
+        match self.serial_number {
+            Some(serial_number) => other.serial_number == Some(serial_number),
+            None => other.serial_number.is_none() && other.path == self.path,
+        }
+    }
 }
 
 #[derive(Debug, Clone, Copy, Default)]
@@ -592,3 +1115,40 @@ impl Ctap2AuthTokenStore for HidChannel<'_> {
         self.auth_token_data = None;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::DeviceIdentity;
+
+    fn identity<'a>(
+        serial_number: Option<&'a str>,
+        path: &'a std::ffi::CStr,
+    ) -> DeviceIdentity<'a> {
+        DeviceIdentity {
+            vendor_id: 0x1050,
+            product_id: 0x0407,
+            usage_page: 0xf1d0,
+            usage: 0x01,
+            serial_number,
+            path,
+        }
+    }
+
+    #[test]
+    fn reconnected_device_matches_on_serial_or_path() {
+        let device = identity(Some("123"), c"/dev/hidraw1");
+        assert!(device.matches(&identity(Some("123"), c"/dev/hidraw2")));
+        assert!(!device.matches(&identity(Some("456"), c"/dev/hidraw1")));
+        assert!(!device.matches(&identity(None, c"/dev/hidraw1")));
+
+        let device = identity(None, c"/dev/hidraw1");
+        assert!(device.matches(&identity(None, c"/dev/hidraw1")));
+        assert!(!device.matches(&identity(None, c"/dev/hidraw2")));
+
+        let other_model = DeviceIdentity {
+            product_id: 0x0402,
+            ..identity(Some("123"), c"/dev/hidraw2")
+        };
+        assert!(!identity(Some("123"), c"/dev/hidraw1").matches(&other_model));
+    }
+}
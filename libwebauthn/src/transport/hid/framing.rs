@@ -1,14 +1,19 @@
 use std::convert::TryInto;
 use std::io::{Cursor as IOCursor, Error as IOError, ErrorKind as IOErrorKind};
 
-use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use byteorder::{BigEndian, ReadBytesExt};
 use num_enum::{IntoPrimitive, TryFromPrimitive};
 use tracing::{debug, error};
 
-const BROADCAST_CID: u32 = 0xFFFFFFFF;
+use crate::transport::error::TransportError;
+use crate::webauthn::error::Error;
+
+pub(crate) const BROADCAST_CID: u32 = 0xFFFFFFFF;
 const PACKET_INITIAL_HEADER_SIZE: usize = 7;
 const PACKET_INITIAL_CMD_MASK: u8 = 0x80;
 const PACKET_CONT_HEADER_SIZE: usize = 5;
+/// Sequence numbers of continuation packets range from 0 to 0x7F.
+const PACKET_MAX_CONT_COUNT: usize = 0x80;
 
 #[derive(Debug, IntoPrimitive, TryFromPrimitive, Copy, Clone, PartialEq)]
 #[repr(u8)]
@@ -45,53 +50,167 @@ impl HidMessage {
         Self::new(BROADCAST_CID, cmd, payload)
     }
 
+    /// Takes `payload`, e.g. an encoded request, without copying it.
+    pub fn from_payload(cid: u32, cmd: HidCommand, payload: Vec<u8>) -> Self {
+        Self { cid, cmd, payload }
+    }
+
+    pub fn packets(&self, packet_size: usize) -> Result<Vec<Vec<u8>>, IOError> {
+        self.borrowed(&[&self.payload]).packets(packet_size)
+    }
+
+    /// Frames the message into reports, see [BorrowedHidMessage::write_reports].
+    pub fn write_reports(
+        &self,
+        packet_size: usize,
+        report_id: Option<u8>,
+        report: &mut Vec<u8>,
+        write: impl FnMut(&[u8]) -> Result<(), Error>,
+    ) -> Result<(), Error> {
+        self.borrowed(&[&self.payload])
+            .write_reports(packet_size, report_id, report, write)
+    }
+
+    fn borrowed<'a>(&self, payload: &'a [&'a [u8]]) -> BorrowedHidMessage<'a> {
+        BorrowedHidMessage {
+            cid: self.cid,
+            cmd: self.cmd,
+            payload,
+        }
+    }
+}
+
+/// A message to send, whose payload is the concatenation of several parts, e.g. a command
+/// byte and an encoded request. The parts are framed where they are, without joining them.
+#[derive(Debug, Clone, Copy)]
+pub struct BorrowedHidMessage<'a> {
+    pub cid: u32,
+    pub cmd: HidCommand,
+    pub payload: &'a [&'a [u8]],
+}
+
+impl BorrowedHidMessage<'_> {
+    pub fn payload_len(&self) -> usize {
+        self.payload.iter().map(|part| part.len()).sum()
+    }
+
     pub fn packets(&self, packet_size: usize) -> Result<Vec<Vec<u8>>, IOError> {
+        self.check_size(packet_size)?;
+        let mut packets = vec![];
+        self.for_each_packet(packet_size, &[], false, &mut vec![], |packet| {
+            packets.push(packet.to_vec());
+            Ok::<_, IOError>(())
+        })?;
+        Ok(packets)
+    }
+
+    /// Frames the message into reports of `packet_size` bytes, zero-padded and preceded by
+    /// `report_id` if any, and passes them to `write` one at a time. All reports are written
+    /// to `report`, which is cleared between them.
+    pub fn write_reports(
+        &self,
+        packet_size: usize,
+        report_id: Option<u8>,
+        report: &mut Vec<u8>,
+        write: impl FnMut(&[u8]) -> Result<(), Error>,
+    ) -> Result<(), Error> {
+        self.check_size(packet_size)
+            .or(Err(Error::Transport(TransportError::InvalidFraming)))?;
+        self.for_each_packet(packet_size, report_id.as_slice(), true, report, write)
+    }
+
+    fn check_size(&self, packet_size: usize) -> Result<(), IOError> {
         if packet_size < PACKET_INITIAL_HEADER_SIZE + 1 {
             return Err(IOError::new(
                 IOErrorKind::InvalidData,
                 format!("Desired packet size is unsupported: {}", packet_size),
             ));
         }
+        let max_len = packet_size - PACKET_INITIAL_HEADER_SIZE
+            + PACKET_MAX_CONT_COUNT * (packet_size - PACKET_CONT_HEADER_SIZE);
+        if self.payload_len() > max_len {
+            return Err(IOError::new(
+                IOErrorKind::InvalidData,
+                format!("Payload is too large for packet size ({}), and would exceed maximum number of packets.", packet_size),
+            ));
+        }
+        Ok(())
+    }
 
-        let mut payload = self.payload.as_slice().into_iter().cloned().peekable();
-        let mut packets = vec![];
+    fn for_each_packet<E>(
+        &self,
+        packet_size: usize,
+        prefix: &[u8],
+        pad: bool,
+        packet: &mut Vec<u8>,
+        mut write: impl FnMut(&[u8]) -> Result<(), E>,
+    ) -> Result<(), E> {
+        let report_len = prefix.len() + packet_size;
+        packet.reserve(report_len);
+        let payload_len = self.payload_len();
+        let mut payload = PayloadReader::new(self.payload);
 
         // Initial fragment
-        let mut packet = vec![];
-        packet.write_u32::<BigEndian>(self.cid)?;
-        packet.write_u8(self.cmd as u8 | PACKET_INITIAL_CMD_MASK)?;
-        packet.write_u16::<BigEndian>(payload.len() as u16)?;
-        let mut chunk: Vec<u8> = payload
-            .by_ref()
-            .take(packet_size - PACKET_INITIAL_HEADER_SIZE)
-            .collect();
-        packet.append(&mut chunk);
-        packets.push(packet);
+        let initial_len = packet_size - PACKET_INITIAL_HEADER_SIZE;
+        packet.clear();
+        packet.extend_from_slice(prefix);
+        packet.extend_from_slice(&self.cid.to_be_bytes());
+        packet.push(self.cmd as u8 | PACKET_INITIAL_CMD_MASK);
+        packet.extend_from_slice(&(payload_len as u16).to_be_bytes());
+        payload.read_into(packet, initial_len);
+        if pad {
+            packet.resize(report_len, 0);
+        }
+        write(packet)?;
 
         // Sequence fragments
-        let mut seq: u8 = 0;
-        while payload.peek().is_some() {
-            let mut packet = vec![];
-            packet.write_u32::<BigEndian>(self.cid)?;
-            packet.write_u8(seq)?;
-
-            let mut chunk: Vec<u8> = payload
-                .by_ref()
-                .take(packet_size - PACKET_CONT_HEADER_SIZE)
-                .collect();
-            packet.append(&mut chunk);
-            packets.push(packet);
-            seq += 1;
-
-            if seq > 0x7F {
-                return Err(IOError::new(
-                    IOErrorKind::InvalidData,
-                    format!("Payload is too large for packet size ({}), and would exceed maximum number of packets.", packet_size),
-                ));
+        let cont_len = packet_size - PACKET_CONT_HEADER_SIZE;
+        let cont_count = payload_len.saturating_sub(initial_len).div_ceil(cont_len);
+        for seq in 0..cont_count {
+            packet.clear();
+            packet.extend_from_slice(prefix);
+            packet.extend_from_slice(&self.cid.to_be_bytes());
+            packet.push(seq as u8);
+            payload.read_into(packet, cont_len);
+            if pad {
+                packet.resize(report_len, 0);
             }
+            write(packet)?;
         }
+        Ok(())
+    }
+}
 
-        Ok(packets)
+/// Reads a payload split in parts, across part boundaries.
+struct PayloadReader<'a> {
+    parts: &'a [&'a [u8]],
+    current: &'a [u8],
+}
+
+impl<'a> PayloadReader<'a> {
+    fn new(parts: &'a [&'a [u8]]) -> Self {
+        Self {
+            parts,
+            current: &[],
+        }
+    }
+
+    /// Appends the next `len` bytes to `packet`, or as many as are left.
+    fn read_into(&mut self, packet: &mut Vec<u8>, mut len: usize) {
+        while len > 0 {
+            if self.current.is_empty() {
+                let Some((next, rest)) = self.parts.split_first() else {
+                    return;
+                };
+                self.current = next;
+                self.parts = rest;
+                continue;
+            }
+            let (chunk, rest) = self.current.split_at(len.min(self.current.len()));
+            packet.extend_from_slice(chunk);
+            self.current = rest;
+            len -= chunk.len();
+        }
     }
 }
 
@@ -101,18 +220,23 @@ pub enum HidMessageParserState {
     Done,
 }
 
+/// Reassembles a message from packets, accumulating the payload in a single buffer.
 #[derive(Debug)]
 pub struct HidMessageParser {
-    packets: Vec<Vec<u8>>,
+    header: Option<[u8; PACKET_INITIAL_HEADER_SIZE]>,
+    payload: Vec<u8>,
 }
 
 impl HidMessageParser {
     pub fn new() -> Self {
-        Self { packets: vec![] }
+        Self {
+            header: None,
+            payload: vec![],
+        }
     }
 
     pub fn update(&mut self, packet: &[u8]) -> Result<HidMessageParserState, IOError> {
-        if (self.packets.len() == 0 && packet.len() < PACKET_INITIAL_HEADER_SIZE)
+        if (self.header.is_none() && packet.len() < PACKET_INITIAL_HEADER_SIZE)
             || packet.len() < PACKET_CONT_HEADER_SIZE + 1
         {
             error!("Packet length in invalid");
@@ -123,8 +247,16 @@ impl HidMessageParser {
         }
         if packet.iter().all(|&b| b == 0) {
             debug!("Received unexpected packet of all zeroes, ignoring"); // ?!
+        } else if self.header.is_none() {
+            let mut header = [0; PACKET_INITIAL_HEADER_SIZE];
+            header.copy_from_slice(&packet[..PACKET_INITIAL_HEADER_SIZE]);
+            self.payload.reserve(expected_bytes(&header));
+            self.payload
+                .extend_from_slice(&packet[PACKET_INITIAL_HEADER_SIZE..]);
+            self.header = Some(header);
         } else {
-            self.packets.push(Vec::from(packet));
+            self.payload
+                .extend_from_slice(&packet[PACKET_CONT_HEADER_SIZE..]);
         }
         return if self.more_packets_needed() {
             Ok(HidMessageParserState::MorePacketsExpected)
@@ -134,43 +266,22 @@ impl HidMessageParser {
     }
 
     fn more_packets_needed(&self) -> bool {
-        if self.packets.is_empty() {
-            return true;
-        }
-
-        self.expected_bytes().unwrap() > self.payload_len()
-    }
-
-    fn expected_bytes(&self) -> Option<usize> {
-        if self.packets.is_empty() {
-            return None;
-        }
-
-        let mut cursor = IOCursor::new(vec![self.packets[0][5], self.packets[0][6]]);
-        Some(cursor.read_u16::<BigEndian>().unwrap() as usize)
-    }
-
-    fn payload_len(&self) -> usize {
-        if self.packets.is_empty() {
-            return 0;
-        }
-
-        let mut payload_len = self.packets[0].len() - PACKET_INITIAL_HEADER_SIZE;
-        for cont_packet in &self.packets[1..self.packets.len()] {
-            payload_len += cont_packet.len() - PACKET_CONT_HEADER_SIZE;
+        match &self.header {
+            Some(header) => expected_bytes(header) > self.payload.len(),
+            None => true,
         }
-        payload_len
     }
 
-    pub fn message(&self) -> Result<HidMessage, IOError> {
-        if self.more_packets_needed() {
+    /// The complete message, taking the reassembled payload without copying it.
+    pub fn into_message(mut self) -> Result<HidMessage, IOError> {
+        let Some(header) = self.header.filter(|_| !self.more_packets_needed()) else {
             return Err(IOError::new(
                 IOErrorKind::InvalidData,
                 "Message is not yet complete, more packets need to be ingested.",
             ));
-        }
+        };
 
-        let mut cursor = IOCursor::new(&self.packets[0]);
+        let mut cursor = IOCursor::new(&header);
         let cid = cursor.read_u32::<BigEndian>()?;
         let cmd = cursor.read_u8()? ^ PACKET_INITIAL_CMD_MASK;
         let Ok(cmd) = cmd.try_into() else {
@@ -180,23 +291,19 @@ impl HidMessageParser {
                 format!("Invalid HID message command: {:?}", cmd),
             ));
         };
-        let expected_size = cursor.read_u16::<BigEndian>()?;
-
-        let mut payload = vec![];
-        payload.extend(&self.packets[0][PACKET_INITIAL_HEADER_SIZE..]);
-        for cont_packet in &self.packets[1..] {
-            payload.extend_from_slice(&cont_packet[PACKET_CONT_HEADER_SIZE..]);
-        }
-
-        payload.truncate(expected_size as usize);
-        Ok(HidMessage::new(cid, cmd, &payload))
+        self.payload.truncate(expected_bytes(&header));
+        Ok(HidMessage::from_payload(cid, cmd, self.payload))
     }
 }
 
+fn expected_bytes(header: &[u8; PACKET_INITIAL_HEADER_SIZE]) -> usize {
+    u16::from_be_bytes([header[5], header[6]]) as usize
+}
+
 #[cfg(test)]
 mod tests {
     use crate::transport::hid::framing::{
-        HidCommand, HidMessage, HidMessageParser, HidMessageParserState,
+        BorrowedHidMessage, HidCommand, HidMessage, HidMessageParser, HidMessageParserState,
     };
     use std::io::ErrorKind as IOErrorKind;
 
@@ -236,6 +343,51 @@ mod tests {
         assert_eq!(msg.packets(8).unwrap(), expected)
     }
 
+    #[test]
+    fn write_reports_reuses_buffer() {
+        let msg = HidMessage::new(CHANNEL_ID, HidCommand::Cbor, &[0x0A; 200]);
+        let mut report = Vec::with_capacity(65);
+        let buffer = report.as_ptr();
+        let mut reports = vec![];
+        msg.write_reports(64, Some(0x00), &mut report, |report| {
+            reports.push(report.to_vec());
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(report.as_ptr(), buffer);
+
+        // 57 bytes in the initial packet, then 59 per continuation packet.
+        assert_eq!(reports.len(), 4);
+        assert!(reports.iter().all(|report| report.len() == 65));
+        assert_eq!(
+            reports[0][..8],
+            [0x00, 0xC0, 0xC1, 0xC2, 0xC3, 0x90, 0x00, 0xC8]
+        );
+        assert_eq!(reports[3][..6], [0x00, 0xC0, 0xC1, 0xC2, 0xC3, 0x02]);
+        assert_eq!(reports[3][6..31], [0x0A; 25]);
+        assert!(reports[3][31..].iter().all(|&b| b == 0));
+
+        let packets = msg.packets(64).unwrap();
+        for (packet, report) in packets.iter().zip(&reports) {
+            assert_eq!(packet[..], report[1..packet.len() + 1]);
+        }
+    }
+
+    #[test]
+    fn encode_borrowed_parts() {
+        let payload: Vec<u8> = (0..100).collect();
+        let msg = HidMessage::new(CHANNEL_ID, HidCommand::Cbor, &payload);
+        let (first, rest) = payload.split_at(1);
+        let (middle, last) = rest.split_at(60);
+        let borrowed = BorrowedHidMessage {
+            cid: CHANNEL_ID,
+            cmd: HidCommand::Cbor,
+            payload: &[first, &[], middle, last],
+        };
+        assert_eq!(borrowed.payload_len(), 100);
+        assert_eq!(borrowed.packets(64).unwrap(), msg.packets(64).unwrap());
+    }
+
     #[test]
     fn encode_too_large() {
         let msg = HidMessage::new(CHANNEL_ID, HidCommand::Msg, &[0x00; 0xFFFF]);
@@ -256,7 +408,7 @@ mod tests {
                 .unwrap(),
             HidMessageParserState::Done
         );
-        let msg = parser.message().unwrap();
+        let msg = parser.into_message().unwrap();
         assert_eq!(msg.cid, CHANNEL_ID);
         assert_eq!(msg.cmd, HidCommand::Msg);
         assert_eq!(msg.payload, vec![0x0A, 0x0B, 0x0C, 0x0D]);
@@ -284,7 +436,7 @@ mod tests {
             HidMessageParserState::Done
         );
 
-        let msg = parser.message().unwrap();
+        let msg = parser.into_message().unwrap();
         assert_eq!(msg.cid, CHANNEL_ID);
         assert_eq!(msg.cmd, HidCommand::Msg);
         assert_eq!(msg.payload, vec![0x0A, 0x0B, 0x0C, 0x0D, 0x0E]);
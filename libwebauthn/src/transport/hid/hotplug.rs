@@ -0,0 +1,132 @@
+//! Notifications of hidraw devices being plugged in or removed.
+//!
+//! Listens to the kernel's uevents, the same hotplug events udev is driven by, on a netlink
+//! socket. Only their subsystem is looked at: listing devices again is cheap, and hidapi
+//! already filters them by usage page.
+
+use std::io;
+use std::mem;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+
+use futures::stream::{self, BoxStream, StreamExt};
+use tokio::io::unix::AsyncFd;
+use tokio::io::Interest;
+use tracing::{debug, trace, warn};
+
+/// The multicast group of uevents sent by the kernel, rather than re-broadcast by udev.
+const KERNEL_UEVENT_GROUP: u32 = 1;
+const UEVENT_BUFFER_SIZE: usize = 8192;
+const HIDRAW_SUBSYSTEM: &[u8] = b"SUBSYSTEM=hidraw";
+
+/// A netlink socket receiving the kernel's uevents.
+#[derive(Debug)]
+pub struct HidHotplug {
+    socket: AsyncFd<OwnedFd>,
+}
+
+impl HidHotplug {
+    /// Starts listening. Fails e.g. in network namespaces without uevents, in which case
+    /// devices have to be listed periodically instead.
+    pub fn new() -> io::Result<Self> {
+        let fd = unsafe {
+            libc::socket(
+                libc::AF_NETLINK,
+                libc::SOCK_DGRAM | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC,
+                libc::NETLINK_KOBJECT_UEVENT,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let socket = unsafe { OwnedFd::from_raw_fd(fd) };
+
+        let mut address: libc::sockaddr_nl = unsafe { mem::zeroed() };
+        address.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+        address.nl_groups = KERNEL_UEVENT_GROUP;
+        let ret = unsafe {
+            libc::bind(
+                socket.as_raw_fd(),
+                &address as *const libc::sockaddr_nl as *const libc::sockaddr,
+                mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t,
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        debug!("Listening to hidraw hotplug events");
+        Ok(Self {
+            socket: AsyncFd::with_interest(socket, Interest::READABLE)?,
+        })
+    }
+
+    /// Waits until a hidraw device was added or removed.
+    pub async fn changed(&self) -> io::Result<()> {
+        let mut buffer = vec![0; UEVENT_BUFFER_SIZE];
+        loop {
+            let len = self
+                .socket
+                .async_io(Interest::READABLE, |socket| {
+                    let ret = unsafe {
+                        libc::recv(
+                            socket.as_raw_fd(),
+                            buffer.as_mut_ptr() as *mut libc::c_void,
+                            buffer.len(),
+                            0,
+                        )
+                    };
+                    if ret < 0 {
+                        Err(io::Error::last_os_error())
+                    } else {
+                        Ok(ret as usize)
+                    }
+                })
+                .await?;
+            trace!(len, "Received uevent");
+            if is_hidraw_event(&buffer[..len]) {
+                debug!("hidraw device added or removed");
+                return Ok(());
+            }
+        }
+    }
+
+    /// One item for every change, ending if the socket fails.
+    pub fn into_stream(self) -> BoxStream<'static, ()> {
+        stream::unfold(self, |hotplug| async move {
+            match hotplug.changed().await {
+                Ok(()) => Some(((), hotplug)),
+                Err(err) => {
+                    warn!(?err, "Failed to receive hotplug events");
+                    None
+                }
+            }
+        })
+        .boxed()
+    }
+}
+
+/// A uevent is a header, e.g. `add@/devices/...`, followed by NUL-separated `KEY=value` pairs.
+fn is_hidraw_event(uevent: &[u8]) -> bool {
+    uevent
+        .split(|&byte| byte == 0)
+        .skip(1)
+        .any(|field| field == HIDRAW_SUBSYSTEM)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::is_hidraw_event;
+
+    #[test]
+    fn hidraw_events_are_recognized() {
+        let added = b"add@/devices/virtual/misc/uhid/0003:1209:0001.0001/hidraw/hidraw3\0\
+            ACTION=add\0DEVPATH=/devices/virtual/misc/uhid/0003:1209:0001.0001/hidraw/hidraw3\0\
+            SUBSYSTEM=hidraw\0MAJOR=240\0MINOR=3\0DEVNAME=hidraw3\0SEQNUM=4242\0";
+        assert!(is_hidraw_event(added));
+
+        let input = b"add@/devices/virtual/misc/uhid/0003:1209:0001.0001/input/input7\0\
+            ACTION=add\0SUBSYSTEM=input\0SEQNUM=4243\0";
+        assert!(!is_hidraw_event(input));
+        // The header alone doesn't count, even if it names a hidraw path.
+        assert!(!is_hidraw_event(b"SUBSYSTEM=hidraw"));
+    }
+}
@@ -3,9 +3,13 @@ use std::fmt::Display;
 pub mod channel;
 pub mod device;
 pub mod framing;
+pub mod hotplug;
 pub mod init;
+pub mod selection;
+pub mod uhid;
 
 pub use device::{list_devices, HidDevice};
+pub use selection::select_device;
 
 use super::Transport;
 
@@ -0,0 +1,121 @@
+//! Letting the user pick one of several connected authenticators by touching it.
+
+use std::time::Duration;
+
+use futures::future::join_all;
+use futures::stream::{FuturesUnordered, StreamExt};
+use tracing::{debug, info, instrument, warn};
+
+use crate::transport::error::TransportError;
+use crate::transport::Device;
+use crate::webauthn::error::Error;
+
+use super::channel::HidChannel;
+use super::HidDevice;
+
+/// Opens channels to all `devices`, makes them blink, and returns the channel to the first one
+/// the user touches. The other devices are cancelled, so they stop blinking.
+///
+/// The actual request is then run on the returned channel. A single device is returned
+/// without waiting for a touch, as the request itself asks for one.
+#[instrument(skip_all, fields(devices = devices.len()))]
+pub async fn select_device(
+    devices: &mut [HidDevice],
+    timeout: Duration,
+) -> Result<HidChannel<'_>, Error> {
+    let channels: Vec<HidChannel> = join_all(devices.iter_mut().map(|device| device.channel()))
+        .await
+        .into_iter()
+        .filter_map(|channel| {
+            channel
+                .inspect_err(|err| warn!(?err, "Failed to open device, skipping"))
+                .ok()
+        })
+        .collect();
+    let mut channels = match channels.len() {
+        0 => return Err(Error::Transport(TransportError::TransportUnavailable)),
+        1 => return Ok(channels.into_iter().next().unwrap()),
+        _ => channels,
+    };
+
+    let handles: Vec<_> = channels
+        .iter()
+        .map(|channel| channel.get_handle())
+        .collect();
+    let mut pending: FuturesUnordered<_> = channels
+        .drain(..)
+        .enumerate()
+        .map(|(index, mut channel)| async move {
+            let touched = channel.blink_and_wait_for_user_presence(timeout).await;
+            (index, channel, touched)
+        })
+        .collect();
+
+    let mut selected = None;
+    // Keeps polling the cancelled devices, so their pending requests are wound down.
+    while let Some((index, channel, touched)) = pending.next().await {
+        match touched {
+            Ok(true) if selected.is_none() => {
+                info!(%channel, "Device selected by user");
+                for (other, handle) in handles.iter().enumerate() {
+                    if other != index {
+                        handle.cancel_ongoing_operation().await;
+                    }
+                }
+                selected = Some(channel);
+            }
+            touched => debug!(%channel, ?touched, "Device not selected"),
+        }
+    }
+    selected.ok_or(Error::Transport(TransportError::Timeout))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::transport::hid::device::HidBackendDevice;
+    use crate::transport::hid::list_devices;
+    use crate::transport::hid::uhid::UhidDevice;
+    use crate::transport::local::memory::VIRTUAL_AAGUID;
+    use crate::transport::local::{LocalDevice, MemoryCredentialStore};
+
+    use super::select_device;
+
+    #[tokio::test]
+    #[cfg_attr(
+        not(feature = "uhid-tests"),
+        ignore = "requires write access to /dev/uhid"
+    )]
+    async fn first_touched_device_is_selected() {
+        let slow = LocalDevice::new(
+            "Untouched",
+            VIRTUAL_AAGUID,
+            MemoryCredentialStore::default(),
+        );
+        let fast = LocalDevice::new("Touched", VIRTUAL_AAGUID, MemoryCredentialStore::default());
+        let _slow = UhidDevice::create(&slow, Duration::from_secs(30)).unwrap();
+        let _fast = UhidDevice::create(&fast, Duration::from_millis(300)).unwrap();
+        tokio::time::sleep(Duration::from_millis(500)).await;
+
+        let mut devices: Vec<_> = list_devices()
+            .await
+            .unwrap()
+            .into_iter()
+            .filter(|device| match &device.backend {
+                HidBackendDevice::HidApiDevice(info) => {
+                    matches!(info.product_string(), Some("Untouched" | "Touched"))
+                }
+                #[allow(unreachable_patterns)]
+                _ => false,
+            })
+            .collect();
+        assert_eq!(devices.len(), 2);
+
+        let channel = select_device(&mut devices, Duration::from_secs(10))
+            .await
+            .unwrap();
+        assert!(channel.to_string().contains("Touched"));
+        assert!(!channel.to_string().contains("Untouched"));
+    }
+}
@@ -0,0 +1,596 @@
+//! Virtual FIDO HID devices backed by Linux UHID.
+//!
+//! A [`UhidDevice`] exposes a [`LocalDevice`] as a real hidraw node, speaking CTAPHID on its
+//! behalf. This allows exercising the full hidapi, CTAPHID framing and CBOR path, e.g. in
+//! integration tests, without any physical authenticator. Creating one requires write access
+//! to `/dev/uhid`.
+
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::os::fd::AsRawFd;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use byteorder::{BigEndian, ByteOrder, LittleEndian};
+use tracing::{debug, error, info, trace, warn};
+
+use crate::proto::ctap2::cbor::{CborRequest, CborResponse};
+use crate::proto::ctap2::Ctap2CommandCode;
+use crate::proto::CtapError;
+use crate::transport::error::TransportError;
+use crate::transport::local::authenticator::Authenticator;
+use crate::transport::local::store::CredentialStore;
+use crate::transport::local::LocalDevice;
+use crate::webauthn::error::Error;
+
+use super::channel::Caps;
+use super::framing::{
+    HidCommand, HidMessage, HidMessageParser, HidMessageParserState, BROADCAST_CID,
+};
+
+const UHID_PATH: &str = "/dev/uhid";
+
+// From linux/uhid.h
+const UHID_DESTROY: u32 = 1;
+const UHID_OUTPUT: u32 = 6;
+const UHID_CREATE2: u32 = 11;
+const UHID_INPUT2: u32 = 12;
+const UHID_DATA_MAX: usize = 4096;
+const UHID_EVENT_SIZE: usize = 4380;
+const BUS_USB: u16 = 0x03;
+
+/// pid.codes test VID:PID, the device is found through its usage page anyway.
+const VENDOR_ID: u32 = 0x1209;
+const PRODUCT_ID: u32 = 0x0001;
+
+/// FIDO usage page, with 64-byte input and output reports.
+const REPORT_DESCRIPTOR: [u8; 34] = [
+    0x06, 0xD0, 0xF1, // Usage Page (FIDO Alliance)
+    0x09, 0x01, // Usage (CTAPHID)
+    0xA1, 0x01, // Collection (Application)
+    0x09, 0x20, //   Usage (Input Report Data)
+    0x15, 0x00, //   Logical Minimum (0)
+    0x26, 0xFF, 0x00, //   Logical Maximum (255)
+    0x75, 0x08, //   Report Size (8)
+    0x95, 0x40, //   Report Count (64)
+    0x81, 0x02, //   Input (Data, Var, Abs)
+    0x09, 0x21, //   Usage (Output Report Data)
+    0x15, 0x00, //   Logical Minimum (0)
+    0x26, 0xFF, 0x00, //   Logical Maximum (255)
+    0x75, 0x08, //   Report Size (8)
+    0x95, 0x40, //   Report Count (64)
+    0x91, 0x02, //   Output (Data, Var, Abs)
+    0xC0, // End Collection
+];
+
+const PACKET_SIZE: usize = 64;
+const KEEPALIVE_INTERVAL: Duration = Duration::from_millis(100);
+const KEEPALIVE_STATUS_UPNEEDED: u8 = 0x02;
+const CTAPHID_PROTOCOL_VERSION: u8 = 2;
+
+// CTAPHID_ERROR codes
+const ERR_INVALID_CMD: u8 = 0x01;
+const ERR_INVALID_PAR: u8 = 0x02;
+const ERR_INVALID_LEN: u8 = 0x03;
+const ERR_CHANNEL_BUSY: u8 = 0x06;
+const ERR_INVALID_CHANNEL: u8 = 0x0B;
+
+/// A [`LocalDevice`] exposed to the system as a hidraw device, for as long as this is alive.
+pub struct UhidDevice {
+    file: Arc<File>,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl UhidDevice {
+    /// Creates the hidraw device, named after `device`. Requests requiring user presence are
+    /// answered after `user_presence_delay`, sending keep-alives until then.
+    pub fn create<S: CredentialStore + 'static>(
+        device: &LocalDevice<S>,
+        user_presence_delay: Duration,
+    ) -> Result<Self, Error> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(UHID_PATH)
+            .map_err(|err| {
+                error!(?err, "Failed to open {}", UHID_PATH);
+                Error::Transport(TransportError::TransportUnavailable)
+            })?;
+        let file = Arc::new(file);
+        write_event(&file, &create_event(&device.to_string())).map_err(|err| {
+            error!(?err, "Failed to create UHID device");
+            Error::Transport(TransportError::TransportUnavailable)
+        })?;
+        info!(name = %device, "Created UHID device");
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let mut emulator = Emulator {
+            file: file.clone(),
+            authenticator: device.authenticator.clone(),
+            user_presence_delay,
+            next_cid: 1,
+            parser: None,
+            pending: None,
+            lock: None,
+        };
+        let thread = {
+            let stop = stop.clone();
+            std::thread::spawn(move || emulator.run(&stop))
+        };
+        Ok(Self {
+            file,
+            stop,
+            thread: Some(thread),
+        })
+    }
+}
+
+impl Drop for UhidDevice {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            if thread.join().is_err() {
+                error!("UHID emulator thread panicked");
+            }
+        }
+        let mut event = vec![0; UHID_EVENT_SIZE];
+        LittleEndian::write_u32(&mut event[0..4], UHID_DESTROY);
+        if let Err(err) = write_event(&self.file, &event) {
+            warn!(?err, "Failed to destroy UHID device");
+        }
+    }
+}
+
+/// A CBOR request waiting for simulated user presence.
+struct PendingRequest {
+    cid: u32,
+    request: CborRequest,
+    ready_at: Instant,
+}
+
+struct Emulator<S> {
+    file: Arc<File>,
+    authenticator: Arc<Mutex<Authenticator<S>>>,
+    user_presence_delay: Duration,
+    next_cid: u32,
+    parser: Option<(u32, HidMessageParser)>,
+    pending: Option<PendingRequest>,
+    /// Channel holding CTAPHID_LOCK, and when the lock expires.
+    lock: Option<(u32, Instant)>,
+}
+
+impl<S: CredentialStore> Emulator<S> {
+    fn run(&mut self, stop: &AtomicBool) {
+        let mut event = vec![0; UHID_EVENT_SIZE];
+        while !stop.load(Ordering::Relaxed) {
+            match poll_readable(&self.file, KEEPALIVE_INTERVAL) {
+                Ok(true) => match (&*self.file).read(&mut event) {
+                    Ok(len) if len >= 4 => self.handle_event(&event[..len]),
+                    Ok(_) => {}
+                    Err(err) => {
+                        error!(?err, "Failed to read UHID event");
+                        return;
+                    }
+                },
+                Ok(false) => {}
+                Err(err) => {
+                    error!(?err, "Failed to poll UHID device");
+                    return;
+                }
+            }
+            self.handle_pending();
+        }
+    }
+
+    fn handle_event(&mut self, event: &[u8]) {
+        let event_type = LittleEndian::read_u32(&event[0..4]);
+        if event_type != UHID_OUTPUT {
+            trace!(event_type, "Ignoring UHID event");
+            return;
+        }
+        if event.len() < 4 + UHID_DATA_MAX + 2 {
+            warn!("Truncated UHID output event");
+            return;
+        }
+        let data = &event[4..4 + UHID_DATA_MAX];
+        let size = LittleEndian::read_u16(&event[4 + UHID_DATA_MAX..]) as usize;
+        let mut report = &data[..size.min(UHID_DATA_MAX)];
+        // hidraw passes on the report number, which is always 0 for FIDO devices.
+        if report.len() == PACKET_SIZE + 1 {
+            report = &report[1..];
+        }
+        self.handle_packet(report);
+    }
+
+    fn handle_packet(&mut self, packet: &[u8]) {
+        if packet.len() < 5 {
+            warn!(len = packet.len(), "Ignoring short HID report");
+            return;
+        }
+        trace!(?packet, "Received HID report");
+        let cid = BigEndian::read_u32(&packet[0..4]);
+        let is_initial = packet[4] & 0x80 != 0;
+        if is_initial {
+            self.parser = Some((cid, HidMessageParser::new()));
+        }
+        let Some((_, parser)) = self
+            .parser
+            .as_mut()
+            .filter(|(parser_cid, _)| *parser_cid == cid)
+        else {
+            debug!(cid, "Ignoring unexpected continuation packet");
+            return;
+        };
+        match parser.update(packet) {
+            Ok(HidMessageParserState::MorePacketsExpected) => {}
+            Ok(HidMessageParserState::Done) => {
+                let Some((_, parser)) = self.parser.take() else {
+                    return;
+                };
+                match parser.into_message() {
+                    Ok(message) => self.handle_message(message),
+                    Err(err) => {
+                        warn!(?err, "Invalid HID message");
+                        self.send_error(cid, ERR_INVALID_CMD);
+                    }
+                }
+            }
+            Err(err) => {
+                warn!(?err, "Invalid HID packet");
+                self.parser = None;
+                self.send_error(cid, ERR_INVALID_LEN);
+            }
+        }
+    }
+
+    fn handle_message(&mut self, message: HidMessage) {
+        debug!(cid = message.cid, cmd = ?message.cmd, "Received HID message");
+        if message.cmd != HidCommand::Init && (message.cid == 0 || message.cid == BROADCAST_CID) {
+            return self.send_error(message.cid, ERR_INVALID_CHANNEL);
+        }
+        if let Some(pending) = &self.pending {
+            if pending.cid != message.cid {
+                return self.send_error(message.cid, ERR_CHANNEL_BUSY);
+            }
+        }
+        if let Some((cid, expires_at)) = self.lock {
+            if Instant::now() >= expires_at {
+                self.lock = None;
+            } else if cid != message.cid {
+                return self.send_error(message.cid, ERR_CHANNEL_BUSY);
+            }
+        }
+
+        match message.cmd {
+            HidCommand::Init => {
+                if message.payload.len() != 8 {
+                    return self.send_error(message.cid, ERR_INVALID_LEN);
+                }
+                let cid = if message.cid == BROADCAST_CID {
+                    let cid = self.next_cid;
+                    self.next_cid = self.next_cid.wrapping_add(1).max(1);
+                    cid
+                } else {
+                    // Resynchronizes an existing channel, aborting what it was doing.
+                    self.pending = None;
+                    message.cid
+                };
+                let mut payload = message.payload.clone();
+                payload.extend(cid.to_be_bytes());
+                payload.push(CTAPHID_PROTOCOL_VERSION);
+                payload.extend(version());
+                payload.push((Caps::WINK | Caps::CBOR | Caps::NO_MSG).bits());
+                self.send(&HidMessage::new(message.cid, HidCommand::Init, &payload));
+            }
+            HidCommand::Ping => self.send(&message),
+            HidCommand::Lock => {
+                let &[seconds] = message.payload.as_slice() else {
+                    return self.send_error(message.cid, ERR_INVALID_LEN);
+                };
+                if seconds > 10 {
+                    return self.send_error(message.cid, ERR_INVALID_PAR);
+                }
+                self.lock = (seconds > 0).then(|| {
+                    let expires_at = Instant::now() + Duration::from_secs(seconds.into());
+                    (message.cid, expires_at)
+                });
+                debug!(cid = message.cid, seconds, "Lock updated");
+                self.send(&HidMessage::new(message.cid, HidCommand::Lock, &[]));
+            }
+            HidCommand::Wink => self.send(&HidMessage::new(message.cid, HidCommand::Wink, &[])),
+            HidCommand::Cancel => {
+                if self.pending.take().is_some() {
+                    debug!(cid = message.cid, "Request cancelled");
+                    let status = [CtapError::KeepAliveCancel.into()];
+                    self.send(&HidMessage::new(message.cid, HidCommand::Cbor, &status));
+                }
+            }
+            HidCommand::Cbor => {
+                let Some((&command, data)) = message.payload.split_first() else {
+                    return self.send_error(message.cid, ERR_INVALID_LEN);
+                };
+                let Ok(command) = Ctap2CommandCode::try_from(command) else {
+                    warn!(command, "Unknown CTAP2 command");
+                    let status = [CtapError::InvalidCommand.into()];
+                    return self.send(&HidMessage::new(message.cid, HidCommand::Cbor, &status));
+                };
+                let request = CborRequest {
+                    command,
+                    encoded_data: data.to_vec(),
+                };
+                let pending = PendingRequest {
+                    cid: message.cid,
+                    request,
+                    ready_at: Instant::now() + self.user_presence_delay,
+                };
+                if requires_user_presence(command) && !self.user_presence_delay.is_zero() {
+                    debug!(?command, "Waiting for user presence");
+                    self.send_keepalive(message.cid);
+                    self.pending = Some(pending);
+                } else {
+                    self.complete(pending);
+                }
+            }
+            _ => self.send_error(message.cid, ERR_INVALID_CMD),
+        }
+    }
+
+    fn handle_pending(&mut self) {
+        let Some(pending) = self.pending.take() else {
+            return;
+        };
+        if Instant::now() >= pending.ready_at {
+            self.complete(pending);
+        } else {
+            self.send_keepalive(pending.cid);
+            self.pending = Some(pending);
+        }
+    }
+
+    fn complete(&mut self, pending: PendingRequest) {
+        let response: CborResponse = self.authenticator.lock().unwrap().process(&pending.request);
+        let mut payload = vec![response.status_code.into()];
+        payload.extend(response.data.unwrap_or_default());
+        self.send(&HidMessage::new(pending.cid, HidCommand::Cbor, &payload));
+    }
+
+    fn send_keepalive(&self, cid: u32) {
+        let status = [KEEPALIVE_STATUS_UPNEEDED];
+        self.send(&HidMessage::new(cid, HidCommand::KeepAlive, &status));
+    }
+
+    fn send_error(&self, cid: u32, code: u8) {
+        self.send(&HidMessage::new(cid, HidCommand::Error, &[code]));
+    }
+
+    fn send(&self, message: &HidMessage) {
+        debug!(cid = message.cid, cmd = ?message.cmd, "Sending HID message");
+        let packets = match message.packets(PACKET_SIZE) {
+            Ok(packets) => packets,
+            Err(err) => {
+                error!(?err, "Failed to fragment HID message");
+                return;
+            }
+        };
+        for packet in packets {
+            let mut event = vec![0; UHID_EVENT_SIZE];
+            LittleEndian::write_u32(&mut event[0..4], UHID_INPUT2);
+            LittleEndian::write_u16(&mut event[4..6], PACKET_SIZE as u16);
+            event[6..6 + packet.len()].copy_from_slice(&packet);
+            if let Err(err) = write_event(&self.file, &event) {
+                error!(?err, "Failed to write UHID input event");
+                return;
+            }
+        }
+    }
+}
+
+fn requires_user_presence(command: Ctap2CommandCode) -> bool {
+    matches!(
+        command,
+        Ctap2CommandCode::AuthenticatorMakeCredential
+            | Ctap2CommandCode::AuthenticatorGetAssertion
+            | Ctap2CommandCode::AuthenticatorSelection
+    )
+}
+
+/// Major, minor and build device version, from the crate version.
+fn version() -> [u8; 3] {
+    let parse = |part: &str| part.parse().unwrap_or(0);
+    [
+        parse(env!("CARGO_PKG_VERSION_MAJOR")),
+        parse(env!("CARGO_PKG_VERSION_MINOR")),
+        parse(env!("CARGO_PKG_VERSION_PATCH")),
+    ]
+}
+
+fn create_event(name: &str) -> Vec<u8> {
+    let mut event = vec![0; UHID_EVENT_SIZE];
+    LittleEndian::write_u32(&mut event[0..4], UHID_CREATE2);
+    let name = name.as_bytes();
+    let name_len = name.len().min(127);
+    event[4..4 + name_len].copy_from_slice(&name[..name_len]);
+    // phys and uniq are left empty.
+    LittleEndian::write_u16(&mut event[260..262], REPORT_DESCRIPTOR.len() as u16);
+    LittleEndian::write_u16(&mut event[262..264], BUS_USB);
+    LittleEndian::write_u32(&mut event[264..268], VENDOR_ID);
+    LittleEndian::write_u32(&mut event[268..272], PRODUCT_ID);
+    // version and country are left at 0.
+    event[280..280 + REPORT_DESCRIPTOR.len()].copy_from_slice(&REPORT_DESCRIPTOR);
+    event
+}
+
+fn write_event(file: &File, event: &[u8]) -> std::io::Result<()> {
+    (&*file).write_all(event)
+}
+
+fn poll_readable(file: &File, timeout: Duration) -> std::io::Result<bool> {
+    let mut fd = libc::pollfd {
+        fd: file.as_raw_fd(),
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    // SAFETY: fd points to a single, valid pollfd for the duration of the call.
+    let ret = unsafe { libc::poll(&mut fd, 1, timeout.as_millis() as libc::c_int) };
+    match ret {
+        -1 => {
+            let err = std::io::Error::last_os_error();
+            if err.kind() == std::io::ErrorKind::Interrupted {
+                Ok(false)
+            } else {
+                Err(err)
+            }
+        }
+        0 => Ok(false),
+        _ => Ok(fd.revents & libc::POLLIN != 0),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::UhidDevice;
+    use crate::ops::webauthn::{
+        GetAssertionRequest, MakeCredentialRequest, UserVerificationRequirement,
+    };
+    use crate::proto::ctap2::{Ctap2, Ctap2PublicKeyCredentialRpEntity};
+    use crate::transport::error::TransportError;
+    use crate::transport::hid::device::HidBackendDevice;
+    use crate::transport::hid::framing::{HidCommand, HidMessage};
+    use crate::transport::hid::{list_devices, HidDevice};
+    use crate::transport::local::VirtualDevice;
+    use crate::transport::{Channel, Device};
+    use crate::webauthn::{Error, PlatformError, WebAuthn};
+
+    async fn find_hidraw_device(name: &str) -> HidDevice {
+        for _ in 0..20 {
+            let hid_device =
+                list_devices()
+                    .await
+                    .unwrap()
+                    .into_iter()
+                    .find(|dev| match &dev.backend {
+                        HidBackendDevice::HidApiDevice(info) => info.product_string() == Some(name),
+                        #[allow(unreachable_patterns)]
+                        _ => false,
+                    });
+            if let Some(hid_device) = hid_device {
+                return hid_device;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        panic!("hidraw device did not appear");
+    }
+
+    #[tokio::test]
+    #[cfg_attr(
+        not(feature = "uhid-tests"),
+        ignore = "requires write access to /dev/uhid"
+    )]
+    async fn register_and_authenticate_over_hidraw() {
+        let device = VirtualDevice::new_virtual();
+        let _uhid = UhidDevice::create(&device, Duration::from_millis(300)).unwrap();
+
+        let mut hid_device = find_hidraw_device(&device.to_string()).await;
+        let mut channel = hid_device.channel().await.unwrap();
+
+        let mut request = MakeCredentialRequest::dummy();
+        request.relying_party = Ctap2PublicKeyCredentialRpEntity::new("example.org", "Example");
+        let response = channel.webauthn_make_credential(&request).await.unwrap();
+        let credential = response.authenticator_data.attested_credential.unwrap();
+
+        let request = GetAssertionRequest::builder()
+            .rp_id("example.org")
+            .client_data_hash(&[0; 32])
+            .allow_credential((&credential).into())
+            .user_verification(UserVerificationRequirement::Discouraged)
+            .timeout(Duration::from_secs(10))
+            .build()
+            .unwrap();
+        let response = channel.webauthn_get_assertion(&request).await.unwrap();
+        assert_eq!(response.assertions.len(), 1);
+        assert_eq!(device.credentials().len(), 1);
+    }
+
+    #[tokio::test]
+    #[cfg_attr(
+        not(feature = "uhid-tests"),
+        ignore = "requires write access to /dev/uhid"
+    )]
+    async fn cancel_aborts_pending_request() {
+        let device = VirtualDevice::new_virtual();
+        let _uhid = UhidDevice::create(&device, Duration::from_secs(10)).unwrap();
+        let mut hid_device = find_hidraw_device(&device.to_string()).await;
+        let mut channel = hid_device.channel().await.unwrap();
+        let handle = channel.get_handle();
+
+        let (result, _) = tokio::join!(channel.ctap2_selection(Duration::from_secs(10)), async {
+            tokio::time::sleep(Duration::from_millis(300)).await;
+            handle.cancel_ongoing_operation().await;
+        });
+        assert!(matches!(
+            result,
+            Err(Error::Platform(PlatformError::Cancelled))
+        ));
+
+        // The CTAP2_ERR_KEEPALIVE_CANCEL response was swallowed, and doesn't answer this one.
+        channel.ctap2_get_info().await.unwrap();
+    }
+
+    #[tokio::test]
+    #[cfg_attr(
+        not(feature = "uhid-tests"),
+        ignore = "requires write access to /dev/uhid"
+    )]
+    async fn ping_is_echoed() {
+        let device = VirtualDevice::new_virtual();
+        let _uhid = UhidDevice::create(&device, Duration::ZERO).unwrap();
+        let mut hid_device = find_hidraw_device(&device.to_string()).await;
+        let channel = hid_device.channel().await.unwrap();
+
+        // Spans several packets.
+        let payload: Vec<u8> = (0..200).map(|i| i as u8).collect();
+        channel
+            .ping(&payload, Duration::from_secs(1))
+            .await
+            .unwrap();
+        channel
+            .ping_latency(16, Duration::from_secs(1))
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    #[cfg_attr(
+        not(feature = "uhid-tests"),
+        ignore = "requires write access to /dev/uhid"
+    )]
+    async fn lock_excludes_other_channels() {
+        let device = VirtualDevice::new_virtual();
+        let _uhid = UhidDevice::create(&device, Duration::ZERO).unwrap();
+        let mut first_device = find_hidraw_device(&device.to_string()).await;
+        let mut second_device = find_hidraw_device(&device.to_string()).await;
+        let mut first = first_device.channel().await.unwrap();
+        let second = second_device.channel().await.unwrap();
+
+        first.lock_device(Duration::from_secs(5)).await.unwrap();
+        // Other channels in this process wait for the lock to be released...
+        assert_eq!(
+            second.ping(&[1], Duration::from_millis(200)).await,
+            Err(Error::Transport(TransportError::Timeout))
+        );
+        // ...while other applications are turned away by the device.
+        let ping = HidMessage::new(second.cid(), HidCommand::Ping, &[1]);
+        second.hid_send(&ping).await.unwrap();
+        let response = second.hid_recv(Duration::from_secs(1)).await.unwrap();
+        assert_eq!(response.cmd, HidCommand::Error);
+        first.ping(&[1], Duration::from_secs(1)).await.unwrap();
+
+        first.unlock_device().await.unwrap();
+        second.ping(&[1], Duration::from_secs(1)).await.unwrap();
+    }
+}
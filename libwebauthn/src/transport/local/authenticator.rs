@@ -0,0 +1,592 @@
+use std::collections::{BTreeMap, HashMap, VecDeque};
+
+use cosey::{P256PublicKey, PublicKey};
+use p256::ecdsa::signature::Signer;
+use p256::ecdsa::{DerSignature, SigningKey};
+use p256::elliptic_curve::sec1::ToEncodedPoint;
+use p256::SecretKey;
+use rand::rngs::OsRng;
+use rand::{thread_rng, Rng};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_bytes::ByteBuf;
+use serde_indexed::{DeserializeIndexed, SerializeIndexed};
+use sha2::{Digest, Sha256};
+use tracing::{debug, instrument, warn};
+
+use crate::fido::{AttestedCredentialData, AuthenticatorData, AuthenticatorDataFlags};
+use crate::proto::ctap2::cbor::{self, CborRequest, CborResponse};
+use crate::proto::ctap2::{
+    Ctap2AuthTokenPermissionRole, Ctap2COSEAlgorithmIdentifier, Ctap2CommandCode,
+    Ctap2CredentialType, Ctap2PublicKeyCredentialDescriptor, Ctap2PublicKeyCredentialRpEntity,
+    Ctap2PublicKeyCredentialType, Ctap2PublicKeyCredentialUserEntity,
+};
+use crate::proto::CtapError;
+use crate::webauthn::error::Error;
+
+use super::capabilities::Capabilities;
+use super::client_pin::{ClientPin, MIN_PIN_LENGTH};
+use super::credential_management::PendingEnumeration;
+use super::store::{CredentialStore, LocalCredential};
+
+const CREDENTIAL_ID_LENGTH: usize = 32;
+pub(super) const MAX_DISCOVERABLE_CREDENTIALS: usize = 256;
+
+#[derive(Debug, DeserializeIndexed)]
+struct MakeCredentialCommand {
+    #[serde(index = 0x01)]
+    client_data_hash: ByteBuf,
+
+    #[serde(index = 0x02)]
+    rp: Ctap2PublicKeyCredentialRpEntity,
+
+    #[serde(index = 0x03)]
+    user: Ctap2PublicKeyCredentialUserEntity,
+
+    #[serde(index = 0x04)]
+    pub_key_cred_params: Vec<Ctap2CredentialType>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(index = 0x05)]
+    exclude_list: Option<Vec<Ctap2PublicKeyCredentialDescriptor>>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(index = 0x07)]
+    options: Option<HashMap<String, bool>>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(index = 0x08)]
+    pin_uv_auth_param: Option<ByteBuf>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(index = 0x09)]
+    pin_uv_auth_protocol: Option<u32>,
+}
+
+#[derive(Debug, DeserializeIndexed)]
+struct GetAssertionCommand {
+    #[serde(index = 0x01)]
+    rp_id: String,
+
+    #[serde(index = 0x02)]
+    client_data_hash: ByteBuf,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(index = 0x03)]
+    allow_list: Option<Vec<Ctap2PublicKeyCredentialDescriptor>>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(index = 0x05)]
+    options: Option<HashMap<String, bool>>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(index = 0x06)]
+    pin_uv_auth_param: Option<ByteBuf>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(index = 0x07)]
+    pin_uv_auth_protocol: Option<u32>,
+}
+
+#[derive(Debug, SerializeIndexed)]
+struct GetInfoReply {
+    #[serde(index = 0x01)]
+    versions: Vec<String>,
+
+    #[serde(index = 0x03)]
+    aaguid: ByteBuf,
+
+    #[serde(index = 0x04)]
+    options: BTreeMap<String, bool>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(index = 0x05)]
+    max_msg_size: Option<u32>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(index = 0x06)]
+    pin_uv_auth_protocols: Option<Vec<u32>>,
+
+    #[serde(index = 0x08)]
+    max_credential_id_length: u32,
+
+    #[serde(index = 0x09)]
+    transports: Vec<String>,
+
+    #[serde(index = 0x0A)]
+    algorithms: Vec<Ctap2CredentialType>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(index = 0x0D)]
+    min_pin_length: Option<u32>,
+}
+
+#[derive(Debug, SerializeIndexed)]
+struct MakeCredentialReply {
+    #[serde(index = 0x01)]
+    format: String,
+
+    #[serde(index = 0x02)]
+    authenticator_data: ByteBuf,
+
+    #[serde(index = 0x03)]
+    attestation_statement: BTreeMap<String, cbor::Value>,
+}
+
+#[derive(Debug, SerializeIndexed)]
+struct GetAssertionReply {
+    #[serde(index = 0x01)]
+    credential: Ctap2PublicKeyCredentialDescriptor,
+
+    #[serde(index = 0x02)]
+    authenticator_data: ByteBuf,
+
+    #[serde(index = 0x03)]
+    signature: ByteBuf,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(index = 0x04)]
+    user: Option<Ctap2PublicKeyCredentialUserEntity>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(index = 0x05)]
+    credentials_count: Option<u32>,
+}
+
+/// Remaining credentials of a GetAssertion request, for GetNextAssertion.
+struct PendingAssertions {
+    rp_id_hash: [u8; 32],
+    client_data_hash: Vec<u8>,
+    flags: AuthenticatorDataFlags,
+    credentials: VecDeque<LocalCredential>,
+}
+
+/// CTAP2 authenticator logic, on top of a [CredentialStore]. Only supports ES256, and
+/// self-asserts user presence: the embedding application is in charge of user consent.
+/// User verification is only available with clientPin, if enabled.
+pub(crate) struct Authenticator<S> {
+    aaguid: [u8; 16],
+    pub(super) store: S,
+    pub(super) client_pin: Option<ClientPin>,
+    pub(super) capabilities: Capabilities,
+    pending_assertions: Option<PendingAssertions>,
+    pub(super) pending_enumeration: Option<PendingEnumeration>,
+}
+
+impl<S: CredentialStore> Authenticator<S> {
+    pub fn new(aaguid: [u8; 16], store: S) -> Self {
+        Self {
+            aaguid,
+            store,
+            client_pin: None,
+            capabilities: Capabilities::default(),
+            pending_assertions: None,
+            pending_enumeration: None,
+        }
+    }
+
+    /// Enables clientPin and credential management, which require a PIN to be set.
+    pub fn enable_client_pin(&mut self) -> &mut ClientPin {
+        self.client_pin.get_or_insert_with(ClientPin::new)
+    }
+
+    #[instrument(skip_all, fields(command = ?request.command))]
+    pub fn process(&mut self, request: &CborRequest) -> CborResponse {
+        // Platforms may check GetInfo in between, which doesn't interrupt enumerations.
+        if !matches!(
+            request.command,
+            Ctap2CommandCode::AuthenticatorGetInfo
+                | Ctap2CommandCode::AuthenticatorGetNextAssertion
+        ) {
+            self.pending_assertions = None;
+        }
+        if !matches!(
+            request.command,
+            Ctap2CommandCode::AuthenticatorGetInfo
+                | Ctap2CommandCode::AuthenticatorCredentialManagement
+        ) {
+            self.pending_enumeration = None;
+        }
+        let result = match self.rejection(request) {
+            Some(error) => Err(error),
+            None => self.dispatch(request),
+        };
+        match result {
+            Ok(data) => CborResponse::new_success_from_slice(&data),
+            Err(status_code) => {
+                debug!(?status_code, "Request failed");
+                CborResponse {
+                    status_code,
+                    data: None,
+                }
+            }
+        }
+    }
+
+    /// Errors configured in the capabilities, returned before processing `request`.
+    fn rejection(&mut self, request: &CborRequest) -> Option<CtapError> {
+        if let Some(max_msg_size) = self.capabilities.max_msg_size {
+            // The command byte counts towards the message size.
+            if request.encoded_data.len() + 1 > max_msg_size as usize {
+                return Some(CtapError::RequestTooLarge);
+            }
+        }
+        let error = self.capabilities.take_injected_error(request.command)?;
+        debug!(?error, "Injecting error");
+        Some(error)
+    }
+
+    fn dispatch(&mut self, request: &CborRequest) -> Result<Vec<u8>, CtapError> {
+        match request.command {
+            Ctap2CommandCode::AuthenticatorGetInfo => self.get_info(),
+            Ctap2CommandCode::AuthenticatorMakeCredential => {
+                parse(&request.encoded_data).and_then(|command| self.make_credential(command))
+            }
+            Ctap2CommandCode::AuthenticatorGetAssertion => {
+                parse(&request.encoded_data).and_then(|command| self.get_assertion(command))
+            }
+            Ctap2CommandCode::AuthenticatorGetNextAssertion => self.get_next_assertion(),
+            Ctap2CommandCode::AuthenticatorClientPin => match self.client_pin.as_mut() {
+                Some(client_pin) => parse(&request.encoded_data)
+                    .and_then(|command| client_pin.process(command))
+                    .and_then(|reply| encode(&reply)),
+                None => Err(CtapError::InvalidCommand),
+            },
+            Ctap2CommandCode::AuthenticatorCredentialManagement if self.client_pin.is_some() => {
+                parse(&request.encoded_data).and_then(|command| self.credential_management(command))
+            }
+            Ctap2CommandCode::AuthenticatorSelection => Ok(vec![]),
+            _ => Err(CtapError::InvalidCommand),
+        }
+    }
+
+    fn get_info(&self) -> Result<Vec<u8>, CtapError> {
+        let mut options: BTreeMap<String, bool> = [("rk", true), ("up", true), ("plat", true)]
+            .into_iter()
+            .map(|(option, enabled)| (option.to_string(), enabled))
+            .collect();
+        if let Some(client_pin) = &self.client_pin {
+            options.insert("clientPin".to_string(), client_pin.is_set());
+            for option in ["pinUvAuthToken", "credMgmt", "makeCredUvNotRqd"] {
+                options.insert(option.to_string(), true);
+            }
+        }
+        options.extend(self.capabilities.options.clone());
+        encode(&GetInfoReply {
+            versions: self.capabilities.versions.clone(),
+            aaguid: ByteBuf::from(self.aaguid),
+            options,
+            max_msg_size: self.capabilities.max_msg_size,
+            pin_uv_auth_protocols: self.client_pin.as_ref().map(ClientPin::protocols),
+            max_credential_id_length: CREDENTIAL_ID_LENGTH as u32,
+            transports: vec!["internal".to_string()],
+            algorithms: self.capabilities.algorithms.clone(),
+            min_pin_length: self.client_pin.as_ref().map(|_| MIN_PIN_LENGTH as u32),
+        })
+    }
+
+    fn make_credential(&mut self, command: MakeCredentialCommand) -> Result<Vec<u8>, CtapError> {
+        let es256 =
+            |params: &Ctap2CredentialType| params.algorithm == Ctap2COSEAlgorithmIdentifier::ES256;
+        if !self.capabilities.algorithms.iter().any(es256)
+            || !command.pub_key_cred_params.iter().any(es256)
+        {
+            return Err(CtapError::UnsupportedAlgorithm);
+        }
+        let options = command.options.unwrap_or_default();
+        // There is no built-in UV, and pinUvAuthParam takes precedence over the "uv" option.
+        let uv_requested = options.get("uv") == Some(&true) && command.pin_uv_auth_param.is_none();
+        if uv_requested || options.get("up") == Some(&false) {
+            return Err(CtapError::InvalidOption);
+        }
+        let discoverable = options.get("rk") == Some(&true);
+        let user_verified = self.verify_user(
+            command.pin_uv_auth_protocol,
+            command
+                .pin_uv_auth_param
+                .as_ref()
+                .map(|param| param.as_slice()),
+            &command.client_data_hash,
+            Ctap2AuthTokenPermissionRole::MAKE_CREDENTIAL,
+            &command.rp.id,
+        )?;
+        // Only non-discoverable credentials may be created without UV, once a PIN is set.
+        if !user_verified && discoverable && self.pin_is_set() {
+            return Err(CtapError::PINRequired);
+        }
+
+        let stored = self.credentials_for(&command.rp.id)?;
+        if let Some(exclude_list) = &command.exclude_list {
+            if stored
+                .iter()
+                .any(|cred| exclude_list.iter().any(|excluded| excluded.id == cred.id))
+            {
+                return Err(CtapError::CredentialExcluded);
+            }
+        }
+
+        if discoverable {
+            // A new discoverable credential replaces any existing one for the same account.
+            let replaced: Vec<&LocalCredential> = stored
+                .iter()
+                .filter(|cred| cred.discoverable && cred.user.id == command.user.id)
+                .collect();
+            let count = self
+                .store
+                .credentials()
+                .map_err(store_error)?
+                .iter()
+                .filter(|cred| cred.discoverable)
+                .count();
+            if replaced.is_empty() && count >= MAX_DISCOVERABLE_CREDENTIALS {
+                return Err(CtapError::KeyStoreFull);
+            }
+            for cred in replaced {
+                self.store.delete(&cred.id).map_err(store_error)?;
+            }
+        }
+
+        let private_key = SecretKey::random(&mut OsRng);
+        let public_key = private_key.public_key().to_encoded_point(false);
+        let credential_id: [u8; CREDENTIAL_ID_LENGTH] = thread_rng().gen();
+        let credential = LocalCredential {
+            id: ByteBuf::from(credential_id),
+            rp: command.rp,
+            user: command.user,
+            public_key: ByteBuf::from(public_key.as_bytes()),
+            discoverable,
+            sign_count: 0,
+        };
+        debug!(rp = %credential.rp.id, discoverable, "Creating credential");
+
+        let mut flags =
+            AuthenticatorDataFlags::USER_PRESENT | AuthenticatorDataFlags::ATTESTED_CREDENTIALS;
+        if user_verified {
+            flags |= AuthenticatorDataFlags::USER_VERIFIED;
+        }
+        let authenticator_data = AuthenticatorData::<()> {
+            rp_id_hash: Sha256::digest(credential.rp.id.as_bytes()).into(),
+            flags,
+            signature_count: credential.sign_count,
+            attested_credential: Some(AttestedCredentialData {
+                aaguid: self.aaguid,
+                credential_id: credential.id.to_vec(),
+                credential_public_key: cose_public_key(&public_key),
+            }),
+            extensions: None,
+        }
+        .to_response_bytes()
+        .map_err(store_error)?;
+
+        self.store
+            .insert(credential, &private_key)
+            .map_err(store_error)?;
+        encode(&MakeCredentialReply {
+            format: "none".to_string(),
+            authenticator_data: ByteBuf::from(authenticator_data),
+            attestation_statement: BTreeMap::new(),
+        })
+    }
+
+    fn get_assertion(&mut self, command: GetAssertionCommand) -> Result<Vec<u8>, CtapError> {
+        let options = command.options.unwrap_or_default();
+        if options.get("uv") == Some(&true) && command.pin_uv_auth_param.is_none() {
+            return Err(CtapError::InvalidOption);
+        }
+        let user_verified = self.verify_user(
+            command.pin_uv_auth_protocol,
+            command
+                .pin_uv_auth_param
+                .as_ref()
+                .map(|param| param.as_slice()),
+            &command.client_data_hash,
+            Ctap2AuthTokenPermissionRole::GET_ASSERTION,
+            &command.rp_id,
+        )?;
+        let mut flags = if options.get("up") == Some(&false) {
+            AuthenticatorDataFlags::empty()
+        } else {
+            AuthenticatorDataFlags::USER_PRESENT
+        };
+        if user_verified {
+            flags |= AuthenticatorDataFlags::USER_VERIFIED;
+        }
+
+        let allow_list = command.allow_list.unwrap_or_default();
+        let mut credentials: VecDeque<LocalCredential> = self
+            .credentials_for(&command.rp_id)?
+            .into_iter()
+            .filter(|cred| match allow_list.is_empty() {
+                true => cred.discoverable,
+                false => allow_list.iter().any(|allowed| allowed.id == cred.id),
+            })
+            // Most recently created first
+            .rev()
+            .collect();
+        let Some(credential) = credentials.pop_front() else {
+            return Err(CtapError::NoCredentials);
+        };
+
+        let mut pending = PendingAssertions {
+            rp_id_hash: Sha256::digest(command.rp_id.as_bytes()).into(),
+            client_data_hash: command.client_data_hash.into_vec(),
+            flags,
+            credentials: VecDeque::new(),
+        };
+        // With an allowList, any single credential will do.
+        let credentials_count = match allow_list.is_empty() && !credentials.is_empty() {
+            true => Some(credentials.len() as u32 + 1),
+            false => None,
+        };
+        let reply = self.assert(credential, &pending, credentials_count)?;
+        if credentials_count.is_some() {
+            pending.credentials = credentials;
+            self.pending_assertions = Some(pending);
+        }
+        Ok(reply)
+    }
+
+    fn get_next_assertion(&mut self) -> Result<Vec<u8>, CtapError> {
+        let Some(mut pending) = self.pending_assertions.take() else {
+            return Err(CtapError::NotAllowed);
+        };
+        let Some(credential) = pending.credentials.pop_front() else {
+            return Err(CtapError::NotAllowed);
+        };
+        let reply = self.assert(credential, &pending, None)?;
+        self.pending_assertions = Some(pending);
+        Ok(reply)
+    }
+
+    fn assert(
+        &mut self,
+        mut credential: LocalCredential,
+        pending: &PendingAssertions,
+        credentials_count: Option<u32>,
+    ) -> Result<Vec<u8>, CtapError> {
+        credential.sign_count = credential.sign_count.wrapping_add(1);
+        self.store.update(&credential).map_err(store_error)?;
+
+        let authenticator_data = AuthenticatorData::<()> {
+            rp_id_hash: pending.rp_id_hash,
+            flags: pending.flags.clone(),
+            signature_count: credential.sign_count,
+            attested_credential: None,
+            extensions: None,
+        }
+        .to_response_bytes()
+        .map_err(store_error)?;
+
+        let private_key = self
+            .store
+            .private_key(&credential.id)
+            .map_err(store_error)?;
+        let mut message = authenticator_data.clone();
+        message.extend(&pending.client_data_hash);
+        let signature: DerSignature = SigningKey::from(private_key).sign(&message);
+
+        // Without user verification, only the user ID may be disclosed.
+        let user = match pending
+            .flags
+            .contains(AuthenticatorDataFlags::USER_VERIFIED)
+        {
+            true => Some(credential.user.clone()),
+            false => credential
+                .discoverable
+                .then(|| Ctap2PublicKeyCredentialUserEntity {
+                    id: credential.user.id.clone(),
+                    name: None,
+                    display_name: None,
+                }),
+        };
+        encode(&GetAssertionReply {
+            credential: Ctap2PublicKeyCredentialDescriptor {
+                id: credential.id,
+                r#type: Ctap2PublicKeyCredentialType::PublicKey,
+                transports: None,
+            },
+            authenticator_data: ByteBuf::from(authenticator_data),
+            signature: ByteBuf::from(signature.as_bytes()),
+            user,
+            credentials_count,
+        })
+    }
+
+    fn pin_is_set(&self) -> bool {
+        self.client_pin.as_ref().is_some_and(ClientPin::is_set)
+    }
+
+    /// Verifies the pinUvAuthParam of MakeCredential or GetAssertion, returning whether the
+    /// user is verified.
+    fn verify_user(
+        &mut self,
+        protocol: Option<u32>,
+        pin_uv_auth_param: Option<&[u8]>,
+        client_data_hash: &[u8],
+        permission: Ctap2AuthTokenPermissionRole,
+        rp_id: &str,
+    ) -> Result<bool, CtapError> {
+        let Some(pin_uv_auth_param) = pin_uv_auth_param else {
+            return Ok(false);
+        };
+        let pin_is_set = self.pin_is_set();
+        let Some(client_pin) = self.client_pin.as_mut() else {
+            return Err(CtapError::PINNotSet);
+        };
+        // Platforms send an empty pinUvAuthParam to have the user select an authenticator.
+        if pin_uv_auth_param.is_empty() {
+            return Err(match pin_is_set {
+                true => CtapError::PINInvalid,
+                false => CtapError::PINNotSet,
+            });
+        }
+        client_pin.verify_token(
+            protocol,
+            pin_uv_auth_param,
+            client_data_hash,
+            permission,
+            Some(rp_id),
+        )?;
+        Ok(true)
+    }
+
+    fn credentials_for(&self, rp_id: &str) -> Result<Vec<LocalCredential>, CtapError> {
+        Ok(self
+            .store
+            .credentials()
+            .map_err(store_error)?
+            .into_iter()
+            .filter(|cred| cred.rp.id == rp_id)
+            .collect())
+    }
+}
+
+pub(super) fn cose_public_key(public_key: &p256::EncodedPoint) -> PublicKey {
+    let x: heapless::Vec<u8, 32> =
+        heapless::Vec::from_slice(public_key.x().expect("Not the identity point")).unwrap();
+    let y: heapless::Vec<u8, 32> =
+        heapless::Vec::from_slice(public_key.y().expect("Not identity nor compressed")).unwrap();
+    PublicKey::P256Key(P256PublicKey {
+        x: x.into(),
+        y: y.into(),
+    })
+}
+
+fn parse<T: DeserializeOwned>(data: &[u8]) -> Result<T, CtapError> {
+    cbor::from_slice(data).map_err(|e| {
+        warn!(%e, "Failed to parse request");
+        CtapError::InvalidCbor
+    })
+}
+
+fn encode<T: Serialize>(reply: &T) -> Result<Vec<u8>, CtapError> {
+    cbor::to_vec_canonical(reply).map_err(|e| {
+        warn!(%e, "Failed to encode response");
+        CtapError::Other
+    })
+}
+
+pub(super) fn store_error(error: Error) -> CtapError {
+    warn!(%error, "Credential store failure");
+    CtapError::Other
+}
@@ -0,0 +1,78 @@
+use std::collections::BTreeMap;
+
+use crate::proto::ctap2::{Ctap2CommandCode, Ctap2CredentialType};
+use crate::proto::CtapError;
+
+/// What a local authenticator supports and advertises in getInfo, so that tests can cover
+/// devices lacking features, or failing in specific ways.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Capabilities {
+    /// Advertised versions, e.g. `FIDO_2_1_PRE`.
+    pub versions: Vec<String>,
+    /// Whether clientPin is supported, and with it credential management.
+    pub client_pin: bool,
+    /// Options advertised in addition to, or instead of, the ones derived from the
+    /// authenticator's state. Advertising an option doesn't implement it: e.g. requests
+    /// asking for built-in UV still fail with `uv` set.
+    pub options: BTreeMap<String, bool>,
+    /// Requests longer than this fail with `CTAP2_ERR_REQUEST_TOO_LARGE`.
+    pub max_msg_size: Option<u32>,
+    /// Advertised algorithms. Only ES256 can actually be used, if advertised.
+    pub algorithms: Vec<Ctap2CredentialType>,
+    /// Errors returned instead of processing the next request of a command, in order.
+    pub injected_errors: Vec<(Ctap2CommandCode, CtapError)>,
+}
+
+impl Default for Capabilities {
+    fn default() -> Self {
+        Self {
+            versions: vec!["FIDO_2_0".to_string(), "FIDO_2_1".to_string()],
+            client_pin: true,
+            options: BTreeMap::new(),
+            max_msg_size: None,
+            algorithms: vec![Ctap2CredentialType::default()],
+            injected_errors: vec![],
+        }
+    }
+}
+
+impl Capabilities {
+    pub fn with_versions(mut self, versions: &[&str]) -> Self {
+        self.versions = versions.iter().map(|v| v.to_string()).collect();
+        self
+    }
+
+    pub fn without_client_pin(mut self) -> Self {
+        self.client_pin = false;
+        self
+    }
+
+    pub fn with_option(mut self, option: &str, enabled: bool) -> Self {
+        self.options.insert(option.to_owned(), enabled);
+        self
+    }
+
+    pub fn with_max_msg_size(mut self, max_msg_size: u32) -> Self {
+        self.max_msg_size = Some(max_msg_size);
+        self
+    }
+
+    pub fn with_algorithms(mut self, algorithms: &[Ctap2CredentialType]) -> Self {
+        self.algorithms = algorithms.to_vec();
+        self
+    }
+
+    /// Fails the next `command` request with `error`, once.
+    pub fn with_error(mut self, command: Ctap2CommandCode, error: CtapError) -> Self {
+        self.injected_errors.push((command, error));
+        self
+    }
+
+    pub(super) fn take_injected_error(&mut self, command: Ctap2CommandCode) -> Option<CtapError> {
+        let index = self
+            .injected_errors
+            .iter()
+            .position(|(injected, _)| *injected == command)?;
+        Some(self.injected_errors.remove(index).1)
+    }
+}
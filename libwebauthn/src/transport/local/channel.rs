@@ -0,0 +1,674 @@
+use std::fmt::{Display, Formatter};
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use tokio::sync::broadcast;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, error, trace};
+
+use crate::audit::AuditTrail;
+use crate::metrics::MetricsRecorder;
+use crate::pin::{PinProvider, PinUvAuthProtocols};
+use crate::proto::ctap1::apdu::{ApduRequest, ApduResponse};
+use crate::proto::ctap2::cbor::{CborRequest, CborResponse};
+use crate::proto::CtapError;
+use crate::timeout::TimeoutPolicy;
+use crate::transport::channel::{
+    ensure_not_cancelled, until_cancelled, AuthTokenData, Channel, ChannelStatus,
+    Ctap2AuthTokenStore, DeviceAaguid,
+};
+use crate::transport::device::SupportedProtocols;
+use crate::transport::error::TransportError;
+use crate::webauthn::error::Error;
+use crate::UvUpdate;
+
+use super::store::CredentialStore;
+use super::LocalDevice;
+
+pub struct LocalChannel<'d, S> {
+    status: ChannelStatus,
+    device: &'d LocalDevice<S>,
+    response: Option<CborResponse>,
+    auth_token_data: Option<AuthTokenData>,
+    aaguid: DeviceAaguid,
+    pin_provider: Option<Arc<dyn PinProvider>>,
+    cancellation_token: Option<CancellationToken>,
+    timeout_policy: TimeoutPolicy,
+    audit_trail: Option<AuditTrail>,
+    metrics_recorder: Option<Arc<dyn MetricsRecorder>>,
+    pin_uv_auth_protocols: PinUvAuthProtocols,
+    ux_update_sender: broadcast::Sender<UvUpdate>,
+}
+
+impl<'d, S> LocalChannel<'d, S> {
+    pub fn new(device: &'d LocalDevice<S>) -> Self {
+        let (ux_update_sender, _) = broadcast::channel(16);
+        Self {
+            status: ChannelStatus::Ready,
+            device,
+            response: None,
+            auth_token_data: None,
+            aaguid: DeviceAaguid::default(),
+            pin_provider: None,
+            cancellation_token: None,
+            timeout_policy: TimeoutPolicy::default(),
+            audit_trail: None,
+            metrics_recorder: None,
+            pin_uv_auth_protocols: PinUvAuthProtocols::default(),
+            ux_update_sender,
+        }
+    }
+}
+
+impl<S> Display for LocalChannel<'_, S> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        Display::fmt(&self.device, f)
+    }
+}
+
+#[async_trait]
+impl<S: CredentialStore + 'static> Channel for LocalChannel<'_, S> {
+    type UxUpdate = UvUpdate;
+
+    fn transport_name(&self) -> &'static str {
+        "local"
+    }
+
+    fn device_aaguid(&self) -> Option<&DeviceAaguid> {
+        Some(&self.aaguid)
+    }
+
+    async fn supported_protocols(&self) -> Result<SupportedProtocols, Error> {
+        Ok(SupportedProtocols::fido2_only())
+    }
+
+    async fn status(&self) -> ChannelStatus {
+        self.status
+    }
+
+    async fn close(&mut self) {
+        self.status = ChannelStatus::Closed;
+    }
+
+    async fn apdu_send(&self, _request: &ApduRequest, _timeout: Duration) -> Result<(), Error> {
+        error!("APDU send not supported by local authenticators");
+        Err(Error::Transport(TransportError::TransportUnavailable))
+    }
+
+    async fn apdu_recv(&self, _timeout: Duration) -> Result<ApduResponse, Error> {
+        error!("APDU recv not supported by local authenticators");
+        Err(Error::Transport(TransportError::TransportUnavailable))
+    }
+
+    async fn cbor_send(&mut self, request: &CborRequest, _timeout: Duration) -> Result<(), Error> {
+        ensure_not_cancelled(self.cancellation_token.as_ref(), &self.ux_update_sender)?;
+        debug!(command = ?request.command, "Sending CBOR request");
+        trace!(?request);
+        let authenticator = self.device.authenticator.clone();
+        let request = request.clone();
+        let token = self.cancellation_token.clone();
+        let worker_token = token.clone();
+        // Credential stores may block, e.g. while talking to the TPM. The worker outlives a
+        // cancelled operation, so it checks the token once it gets hold of the authenticator,
+        // rather than e.g. storing a credential nobody waits for anymore.
+        let processing = async {
+            tokio::task::spawn_blocking(move || {
+                let mut authenticator = authenticator.lock().unwrap();
+                if worker_token.is_some_and(|token| token.is_cancelled()) {
+                    debug!("Operation cancelled before processing the request");
+                    return CborResponse {
+                        status_code: CtapError::KeepAliveCancel,
+                        data: None,
+                    };
+                }
+                authenticator.process(&request)
+            })
+            .await
+            .or(Err(Error::Transport(TransportError::ConnectionLost)))
+        };
+        let response = until_cancelled(token, &self.ux_update_sender, processing).await?;
+        self.response = Some(response);
+        Ok(())
+    }
+
+    async fn cbor_recv(&mut self, _timeout: Duration) -> Result<CborResponse, Error> {
+        let response = self
+            .response
+            .take()
+            .ok_or(Error::Transport(TransportError::InvalidFraming))?;
+        trace!(?response);
+        Ok(response)
+    }
+
+    fn get_ux_update_sender(&self) -> &broadcast::Sender<UvUpdate> {
+        &self.ux_update_sender
+    }
+
+    fn get_pin_provider(&self) -> Option<Arc<dyn PinProvider>> {
+        self.pin_provider.clone()
+    }
+
+    fn set_pin_provider(&mut self, provider: Option<Arc<dyn PinProvider>>) {
+        self.pin_provider = provider;
+    }
+
+    fn get_cancellation_token(&self) -> Option<CancellationToken> {
+        self.cancellation_token.clone()
+    }
+
+    fn set_cancellation_token(&mut self, token: Option<CancellationToken>) {
+        self.cancellation_token = token;
+    }
+
+    fn get_timeout_policy(&self) -> TimeoutPolicy {
+        self.timeout_policy
+    }
+
+    fn set_timeout_policy(&mut self, policy: TimeoutPolicy) {
+        self.timeout_policy = policy;
+    }
+
+    fn get_audit_trail(&self) -> Option<AuditTrail> {
+        self.audit_trail.clone()
+    }
+
+    fn set_audit_trail(&mut self, trail: Option<AuditTrail>) {
+        self.audit_trail = trail;
+    }
+
+    fn get_metrics_recorder(&self) -> Option<Arc<dyn MetricsRecorder>> {
+        self.metrics_recorder.clone()
+    }
+
+    fn set_metrics_recorder(&mut self, recorder: Option<Arc<dyn MetricsRecorder>>) {
+        self.metrics_recorder = recorder;
+    }
+
+    fn get_pin_uv_auth_protocols(&self) -> PinUvAuthProtocols {
+        self.pin_uv_auth_protocols.clone()
+    }
+
+    fn set_pin_uv_auth_protocols(&mut self, protocols: PinUvAuthProtocols) {
+        self.pin_uv_auth_protocols = protocols;
+    }
+}
+
+impl<S> Ctap2AuthTokenStore for LocalChannel<'_, S> {
+    fn store_auth_data(&mut self, auth_token_data: AuthTokenData) {
+        self.auth_token_data = Some(auth_token_data);
+    }
+
+    fn get_auth_data(&self) -> Option<&AuthTokenData> {
+        self.auth_token_data.as_ref()
+    }
+
+    fn clear_uv_auth_token_store(&mut self) {
+        self.auth_token_data = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use async_trait::async_trait;
+    use futures::TryStreamExt;
+    use sha2::{Digest, Sha256};
+    use zeroize::Zeroizing;
+
+    use crate::management::CredentialManagement;
+    use crate::ops::webauthn::{
+        GetAssertionRequest, MakeCredentialRequest, ResidentKeyRequirement,
+        UserVerificationRequirement,
+    };
+    use crate::pin::{PinManagement, PinProvider, PinRequestContext};
+    use crate::proto::ctap2::cbor::CborRequest;
+    use crate::proto::ctap2::{
+        Ctap2, Ctap2AuthTokenPermissionRole, Ctap2CommandCode, Ctap2MakeCredentialRequest,
+        Ctap2PublicKeyCredentialRpEntity, Ctap2PublicKeyCredentialUserEntity,
+        PIN_UV_AUTH_PARAM_ALLOWANCE,
+    };
+    use crate::proto::CtapError;
+    use crate::transport::error::TransportError;
+    use crate::transport::local::{Capabilities, VirtualDevice};
+    use crate::transport::ux_stream::UxStream;
+    use crate::transport::{
+        AuthTokenMismatch, CancellationToken, Channel, Ctap2AuthTokenStore, Device,
+    };
+    use crate::webauthn::{Error, PlatformError, WebAuthn};
+    use crate::UvUpdate;
+
+    const TIMEOUT: Duration = Duration::from_secs(10);
+
+    struct FixedPin(&'static str);
+
+    #[async_trait]
+    impl PinProvider for FixedPin {
+        async fn provide_pin(&self, _context: &PinRequestContext) -> Option<Zeroizing<String>> {
+            Some(Zeroizing::new(self.0.to_owned()))
+        }
+    }
+
+    fn make_credential_request(user_id: &[u8]) -> MakeCredentialRequest {
+        let mut request = MakeCredentialRequest::dummy();
+        request.relying_party = Ctap2PublicKeyCredentialRpEntity::new("example.org", "Example");
+        request.user = Ctap2PublicKeyCredentialUserEntity::new(user_id, "user", "User");
+        request.resident_key = Some(ResidentKeyRequirement::Required);
+        request
+    }
+
+    fn get_assertion_request(
+        user_verification: UserVerificationRequirement,
+    ) -> GetAssertionRequest {
+        GetAssertionRequest::builder()
+            .rp_id("example.org")
+            .client_data_hash(&[0; 32])
+            .user_verification(user_verification)
+            .timeout(TIMEOUT)
+            .build()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn register_and_authenticate() {
+        let mut device = VirtualDevice::new_virtual();
+        let mut channel = device.channel().await.unwrap();
+
+        let response = channel
+            .webauthn_make_credential(&make_credential_request(b"user"))
+            .await
+            .unwrap();
+        let credential = response.authenticator_data.attested_credential.unwrap();
+
+        let request = get_assertion_request(UserVerificationRequirement::Discouraged);
+        for sign_count in 1..=2 {
+            let response = channel.webauthn_get_assertion(&request).await.unwrap();
+            assert_eq!(response.assertions.len(), 1);
+            let assertion = &response.assertions[0];
+            assert_eq!(
+                assertion.credential_id.as_ref().unwrap().id,
+                credential.credential_id
+            );
+            assert_eq!(assertion.authenticator_data.signature_count, sign_count);
+        }
+    }
+
+    #[tokio::test]
+    async fn user_verification_with_pin() {
+        let mut device = VirtualDevice::new_virtual().with_pin("1234");
+        let mut channel = device.channel().await.unwrap();
+        channel.set_pin_provider(Some(Arc::new(FixedPin("1234"))));
+
+        let response = channel
+            .webauthn_make_credential(&make_credential_request(b"user"))
+            .await
+            .unwrap();
+        assert!(response
+            .authenticator_data
+            .flags
+            .contains(crate::fido::AuthenticatorDataFlags::USER_VERIFIED));
+
+        let request = get_assertion_request(UserVerificationRequirement::Required);
+        let response = channel.webauthn_get_assertion(&request).await.unwrap();
+        let user = response.assertions[0].user.as_ref().unwrap();
+        assert_eq!(user.name.as_deref(), Some("user"));
+    }
+
+    #[tokio::test]
+    async fn token_with_additional_permissions() {
+        let mut device = VirtualDevice::new_virtual().with_pin("1234");
+        let mut channel = device.channel().await.unwrap();
+        channel.set_pin_provider(Some(Arc::new(FixedPin("1234"))));
+
+        let mut request = make_credential_request(b"user");
+        request.user_verification = UserVerificationRequirement::Required;
+        // The device has no fingerprint sensor, so bio enrollment is not requested.
+        request.additional_permissions = Ctap2AuthTokenPermissionRole::CREDENTIAL_MANAGEMENT
+            | Ctap2AuthTokenPermissionRole::BIO_ENROLLMENT;
+        channel.webauthn_make_credential(&request).await.unwrap();
+
+        let token = channel.get_auth_data().unwrap();
+        assert_eq!(
+            token.permission.role,
+            Ctap2AuthTokenPermissionRole::MAKE_CREDENTIAL
+                | Ctap2AuthTokenPermissionRole::GET_ASSERTION
+                | Ctap2AuthTokenPermissionRole::CREDENTIAL_MANAGEMENT
+        );
+    }
+
+    #[tokio::test]
+    async fn token_for_another_rp_is_not_reused() {
+        let mut device = VirtualDevice::new_virtual().with_pin("1234");
+        let mut channel = device.channel().await.unwrap();
+        channel.set_pin_provider(Some(Arc::new(FixedPin("1234"))));
+        let mut updates = channel.get_ux_update_receiver();
+
+        let mut request = make_credential_request(b"user");
+        request.user_verification = UserVerificationRequirement::Required;
+        channel.webauthn_make_credential(&request).await.unwrap();
+
+        let mut request = get_assertion_request(UserVerificationRequirement::Required);
+        request.relying_party_id = "example.com".to_owned();
+        let result = channel.webauthn_get_assertion(&request).await;
+        assert_eq!(result.unwrap_err(), Error::Ctap(CtapError::NoCredentials));
+
+        let mut mismatches = vec![];
+        while let Ok(update) = updates.try_recv() {
+            if let UvUpdate::AuthTokenMismatch(mismatch) = update {
+                mismatches.push(mismatch);
+            }
+        }
+        assert_eq!(
+            mismatches,
+            vec![AuthTokenMismatch::RelyingParty {
+                bound: Some("example.org".to_owned()),
+                requested: Some("example.com".to_owned()),
+            }]
+        );
+        let token = channel.get_auth_data().unwrap();
+        assert_eq!(token.permission.rpid.as_deref(), Some("example.com"));
+    }
+
+    #[tokio::test]
+    async fn wrong_pin_is_rejected() {
+        let mut device = VirtualDevice::new_virtual().with_pin("1234");
+        let mut channel = device.channel().await.unwrap();
+        channel.set_pin_provider(Some(Arc::new(FixedPin("4321"))));
+
+        let result = channel
+            .webauthn_make_credential(&make_credential_request(b"user"))
+            .await;
+        assert!(matches!(result, Err(Error::Ctap(CtapError::PINInvalid))));
+        let retries = channel.get_retries(TIMEOUT).await.unwrap();
+        assert_eq!(retries.pin_retries, Some(7));
+    }
+
+    #[tokio::test]
+    async fn set_and_change_pin() {
+        let mut device = VirtualDevice::new_virtual();
+        let mut channel = device.channel().await.unwrap();
+        channel
+            .change_pin(Zeroizing::new("1234".to_owned()), TIMEOUT)
+            .await
+            .unwrap();
+
+        channel.set_pin_provider(Some(Arc::new(FixedPin("1234"))));
+        channel
+            .change_pin(Zeroizing::new("5678".to_owned()), TIMEOUT)
+            .await
+            .unwrap();
+
+        channel.set_pin_provider(Some(Arc::new(FixedPin("5678"))));
+        channel
+            .webauthn_make_credential(&make_credential_request(b"user"))
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn manage_credentials() {
+        let mut device = VirtualDevice::new_virtual().with_pin("1234");
+        let mut channel = device.channel().await.unwrap();
+        channel.set_pin_provider(Some(Arc::new(FixedPin("1234"))));
+        for user_id in [b"alice", b"bobby"] {
+            channel
+                .webauthn_make_credential(&make_credential_request(user_id))
+                .await
+                .unwrap();
+        }
+
+        let metadata = channel.get_credential_metadata(TIMEOUT).await.unwrap();
+        assert_eq!(metadata.existing_resident_credentials_count, 2);
+        let rps: Vec<_> = channel.enumerate_rps(TIMEOUT).try_collect().await.unwrap();
+        assert_eq!(rps.len(), 1);
+        assert_eq!(rps[0].rp.id, "example.org");
+
+        let rp_id_hash = Sha256::digest(b"example.org");
+        let credentials: Vec<_> = channel
+            .enumerate_credentials(&rp_id_hash, TIMEOUT)
+            .try_collect()
+            .await
+            .unwrap();
+        assert_eq!(credentials.len(), 2);
+
+        channel
+            .delete_credential(&credentials[0].credential_id, TIMEOUT)
+            .await
+            .unwrap();
+        drop(channel);
+        assert_eq!(device.credentials().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn configured_capabilities() {
+        let capabilities = Capabilities::default()
+            .with_versions(&["FIDO_2_1_PRE"])
+            .without_client_pin()
+            .with_error(
+                Ctap2CommandCode::AuthenticatorGetAssertion,
+                CtapError::OperationDenied,
+            );
+        let mut device = VirtualDevice::new_virtual().with_capabilities(capabilities);
+        let mut channel = device.channel().await.unwrap();
+
+        let info = channel.ctap2_get_info().await.unwrap();
+        assert_eq!(info.versions, vec!["FIDO_2_1_PRE"]);
+        assert!(info.pin_auth_protos.is_none());
+
+        let request = get_assertion_request(UserVerificationRequirement::Required);
+        let result = channel.webauthn_get_assertion(&request).await;
+        assert_eq!(result.unwrap_err(), Error::Ctap(CtapError::PINNotSet));
+
+        channel
+            .webauthn_make_credential(&make_credential_request(b"user"))
+            .await
+            .unwrap();
+        let request = get_assertion_request(UserVerificationRequirement::Discouraged);
+        let result = channel.webauthn_get_assertion(&request).await;
+        assert_eq!(result.unwrap_err(), Error::Ctap(CtapError::OperationDenied));
+        assert!(channel.webauthn_get_assertion(&request).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn oversized_request_is_rejected() {
+        let capabilities = Capabilities::default().with_max_msg_size(256);
+        let mut device = VirtualDevice::new_virtual().with_capabilities(capabilities);
+        let mut channel = device.channel().await.unwrap();
+
+        let mut request = make_credential_request(b"user");
+        request.user = Ctap2PublicKeyCredentialUserEntity::new(b"user", "user", &"U".repeat(256));
+        let result = channel.webauthn_make_credential(&request).await;
+        assert!(matches!(
+            result.unwrap_err(),
+            Error::Platform(PlatformError::RequestTooLarge {
+                max_msg_size: 256,
+                ..
+            })
+        ));
+        channel
+            .webauthn_make_credential(&make_credential_request(b"user"))
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn oversized_request_is_rejected_before_user_verification() {
+        let capabilities = Capabilities::default().with_max_msg_size(256);
+        let mut device = VirtualDevice::new_virtual()
+            .with_pin("1234")
+            .with_capabilities(capabilities);
+        let mut channel = device.channel().await.unwrap();
+        let mut ux_updates = channel.get_ux_update_receiver();
+
+        // Fits on its own, but not with the pinUvAuthParam added after entering the PIN.
+        let mut request = make_credential_request(b"user");
+        let base_size = CborRequest::from(
+            &Ctap2MakeCredentialRequest::from_webauthn_request(
+                &request,
+                &channel.ctap2_get_info().await.unwrap(),
+            )
+            .unwrap(),
+        )
+        .encoded_data
+        .len();
+        let padding = 256 - base_size - PIN_UV_AUTH_PARAM_ALLOWANCE / 2;
+        request.user = Ctap2PublicKeyCredentialUserEntity::new(
+            b"user",
+            "user",
+            &format!("User{}", "U".repeat(padding)),
+        );
+        let result = channel.webauthn_make_credential(&request).await;
+        assert!(matches!(
+            result.unwrap_err(),
+            Error::Platform(PlatformError::RequestTooLarge {
+                max_msg_size: 256,
+                ..
+            })
+        ));
+        assert!(ux_updates.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn ceremony_fails_after_its_timeout() {
+        let mut device = VirtualDevice::new_virtual();
+        let mut channel = device.channel().await.unwrap();
+
+        let mut request = make_credential_request(b"user");
+        request.timeout = Duration::ZERO;
+        let result = channel.webauthn_make_credential(&request).await;
+        assert!(matches!(
+            result,
+            Err(Error::Transport(TransportError::Timeout))
+        ));
+        drop(channel);
+        assert!(device.credentials().is_empty());
+    }
+
+    #[tokio::test]
+    async fn user_action_timeout_is_reported() {
+        let capabilities = Capabilities::default().with_error(
+            Ctap2CommandCode::AuthenticatorMakeCredential,
+            CtapError::UserActionTimeout,
+        );
+        let mut device = VirtualDevice::new_virtual().with_capabilities(capabilities);
+        let mut channel = device.channel().await.unwrap();
+        let mut updates = channel.get_ux_update_receiver();
+
+        let result = channel
+            .webauthn_make_credential(&make_credential_request(b"user"))
+            .await;
+        assert_eq!(
+            result.unwrap_err(),
+            Error::Ctap(CtapError::UserActionTimeout)
+        );
+        let mut timed_out = false;
+        while let Ok(update) = updates.try_recv() {
+            timed_out |= matches!(update, UvUpdate::OperationTimeout);
+        }
+        assert!(timed_out);
+    }
+
+    #[tokio::test]
+    async fn updates_identify_device() {
+        let mut device = VirtualDevice::new_virtual();
+        let mut channel = device.channel().await.unwrap();
+        let mut updates = channel.get_device_ux_update_receiver();
+
+        // Learned after subscribing, but reported in later updates.
+        let info = channel.ctap2_get_info().await.unwrap();
+        channel.send_ux_update(UvUpdate::PresenceRequired).await;
+        let update = updates.recv().await.unwrap();
+        assert!(matches!(update.update, UvUpdate::PresenceRequired));
+        assert_eq!(update.device.transport, "local");
+        assert_eq!(update.device.name, channel.to_string());
+        assert_eq!(
+            update.device.aaguid.unwrap().as_bytes()[..],
+            info.aaguid[..]
+        );
+    }
+
+    #[tokio::test]
+    async fn ux_stream_tags_updates_of_each_channel() {
+        let mut first = VirtualDevice::new_virtual();
+        let mut second = VirtualDevice::new_virtual().with_pin("1234");
+        let mut first_channel = first.channel().await.unwrap();
+        let mut second_channel = second.channel().await.unwrap();
+        let mut stream = UxStream::<UvUpdate>::new();
+        let first_id = stream.add(&first_channel);
+        let second_id = stream.add(&second_channel);
+
+        first_channel.send_ux_update(UvUpdate::Processing).await;
+        let update = stream.recv().await.unwrap();
+        assert_eq!(update.channel, first_id);
+        assert_eq!(update.device.name, first_channel.to_string());
+
+        let request = make_credential_request(b"user");
+        let operation = second_channel.webauthn_make_credential(&request);
+        let user = async {
+            loop {
+                let update = stream.recv().await.unwrap();
+                if let UvUpdate::PinRequired(pin_request) = update.update {
+                    assert_eq!(update.channel, second_id);
+                    pin_request.send_pin("1234").unwrap();
+                    break;
+                }
+            }
+        };
+        let (result, ()) = tokio::join!(operation, user);
+        result.unwrap();
+
+        drop(first_channel);
+        drop(second_channel);
+        while stream.recv().await.is_some() {}
+    }
+
+    #[tokio::test]
+    async fn cancelled_operation_fails() {
+        let mut device = VirtualDevice::new_virtual();
+        let mut channel = device.channel().await.unwrap();
+        let token = CancellationToken::new();
+        channel.set_cancellation_token(Some(token.clone()));
+        let mut updates = channel.get_ux_update_receiver();
+
+        token.cancel();
+        let result = channel
+            .webauthn_make_credential(&make_credential_request(b"user"))
+            .await;
+        assert!(matches!(
+            result,
+            Err(Error::Platform(PlatformError::Cancelled))
+        ));
+        assert!(matches!(updates.try_recv(), Ok(UvUpdate::Cancelled)));
+        drop(channel);
+        assert!(device.credentials().is_empty());
+    }
+
+    #[tokio::test]
+    async fn cancelling_abandons_pin_prompt() {
+        let mut device = VirtualDevice::new_virtual().with_pin("1234");
+        let mut channel = device.channel().await.unwrap();
+        let token = CancellationToken::new();
+        channel.set_cancellation_token(Some(token.clone()));
+        let mut updates = channel.get_ux_update_receiver();
+
+        let request = make_credential_request(b"user");
+        let operation = channel.webauthn_make_credential(&request);
+        let user = async {
+            loop {
+                if let UvUpdate::PinRequired(_) = updates.recv().await.unwrap() {
+                    break;
+                }
+            }
+            token.cancel();
+            updates.recv().await.unwrap()
+        };
+        let (result, update) = tokio::join!(operation, user);
+        assert!(matches!(
+            result,
+            Err(Error::Platform(PlatformError::Cancelled))
+        ));
+        assert!(matches!(update, UvUpdate::Cancelled));
+    }
+}
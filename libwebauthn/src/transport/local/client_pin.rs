@@ -0,0 +1,398 @@
+//! clientPin of local authenticators: PIN/UV auth protocols one and two, and
+//! pinUvAuthTokens obtained with the PIN.
+
+use cosey::PublicKey;
+use num_traits::FromPrimitive;
+use rand::{thread_rng, Rng};
+use serde_bytes::ByteBuf;
+use serde_indexed::{DeserializeIndexed, SerializeIndexed};
+use tracing::{debug, info, warn};
+use zeroize::Zeroizing;
+
+use crate::pin::{pin_hash, PinUvAuthProtocol, PinUvAuthProtocolOne, PinUvAuthProtocolTwo};
+use crate::proto::ctap2::{
+    Ctap2AuthTokenPermissionRole, Ctap2PinUvAuthProtocol, Ctap2PinUvAuthProtocolCommand,
+};
+use crate::proto::CtapError;
+
+pub(crate) const MIN_PIN_LENGTH: usize = 4;
+const MAX_PIN_RETRIES: u32 = 8;
+/// Consecutive mismatches after which the authenticator needs a power cycle.
+const MAX_CONSECUTIVE_PIN_FAILURES: u32 = 3;
+const PADDED_PIN_LENGTH: usize = 64;
+
+#[derive(Debug, DeserializeIndexed)]
+pub(crate) struct ClientPinCommand {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(index = 0x01)]
+    protocol: Option<u32>,
+
+    #[serde(index = 0x02)]
+    subcommand: u32,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(index = 0x03)]
+    key_agreement: Option<PublicKey>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(index = 0x04)]
+    pin_uv_auth_param: Option<ByteBuf>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(index = 0x05)]
+    new_pin_encrypted: Option<ByteBuf>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(index = 0x06)]
+    pin_hash_encrypted: Option<ByteBuf>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(index = 0x09)]
+    permissions: Option<u32>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(index = 0x0A)]
+    permissions_rpid: Option<String>,
+}
+
+#[derive(Debug, Default, SerializeIndexed)]
+pub(crate) struct ClientPinReply {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(index = 0x01)]
+    key_agreement: Option<PublicKey>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(index = 0x02)]
+    pin_uv_auth_token: Option<ByteBuf>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(index = 0x03)]
+    pin_retries: Option<u32>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(index = 0x04)]
+    power_cycle_state: Option<bool>,
+}
+
+/// The pinUvAuthToken currently handed out, and what it may be used for.
+struct PinUvAuthToken {
+    token: Zeroizing<Vec<u8>>,
+    permissions: Ctap2AuthTokenPermissionRole,
+    rp_id: Option<String>,
+}
+
+pub(crate) struct ClientPin {
+    pin_hash: Option<Zeroizing<Vec<u8>>>,
+    retries: u32,
+    consecutive_failures: u32,
+    protocol_one: PinUvAuthProtocolOne,
+    protocol_two: PinUvAuthProtocolTwo,
+    token: Option<PinUvAuthToken>,
+}
+
+impl ClientPin {
+    pub fn new() -> Self {
+        Self {
+            pin_hash: None,
+            retries: MAX_PIN_RETRIES,
+            consecutive_failures: 0,
+            protocol_one: PinUvAuthProtocolOne::new(),
+            protocol_two: PinUvAuthProtocolTwo::new(),
+            token: None,
+        }
+    }
+
+    pub fn is_set(&self) -> bool {
+        self.pin_hash.is_some()
+    }
+
+    /// Sets the PIN directly, as if it had been set by a platform.
+    pub fn set(&mut self, pin: &str) {
+        self.pin_hash = Some(pin_hash(pin.as_bytes()));
+        self.retries = MAX_PIN_RETRIES;
+        self.consecutive_failures = 0;
+        self.token = None;
+    }
+
+    pub fn protocols(&self) -> Vec<u32> {
+        vec![
+            Ctap2PinUvAuthProtocol::Two as u32,
+            Ctap2PinUvAuthProtocol::One as u32,
+        ]
+    }
+
+    fn protocol(&self, protocol: Option<u32>) -> Result<&dyn PinUvAuthProtocol, CtapError> {
+        match protocol.map(Ctap2PinUvAuthProtocol::from_u32) {
+            None => Err(CtapError::MissingParameter),
+            Some(Some(Ctap2PinUvAuthProtocol::One)) => Ok(&self.protocol_one),
+            Some(Some(Ctap2PinUvAuthProtocol::Two)) => Ok(&self.protocol_two),
+            Some(None) => Err(CtapError::InvalidParameter),
+        }
+    }
+
+    pub fn process(&mut self, command: ClientPinCommand) -> Result<ClientPinReply, CtapError> {
+        let Some(subcommand) = Ctap2PinUvAuthProtocolCommand::from_u32(command.subcommand) else {
+            return Err(CtapError::InvalidSubcommand);
+        };
+        debug!(?subcommand, "Processing clientPin");
+        match subcommand {
+            Ctap2PinUvAuthProtocolCommand::GetPinRetries => Ok(ClientPinReply {
+                pin_retries: Some(self.retries),
+                power_cycle_state: Some(self.needs_power_cycle()),
+                ..Default::default()
+            }),
+            Ctap2PinUvAuthProtocolCommand::GetKeyAgreement => {
+                let key_agreement = match self.protocol(command.protocol)?.version() {
+                    Ctap2PinUvAuthProtocol::One => self.protocol_one.key_agreement(),
+                    Ctap2PinUvAuthProtocol::Two => self.protocol_two.key_agreement(),
+                };
+                Ok(ClientPinReply {
+                    key_agreement: Some(key_agreement),
+                    ..Default::default()
+                })
+            }
+            Ctap2PinUvAuthProtocolCommand::SetPin => self.set_pin(command),
+            Ctap2PinUvAuthProtocolCommand::ChangePin => self.change_pin(command),
+            Ctap2PinUvAuthProtocolCommand::GetPinToken => {
+                // Legacy tokens may be used for both MakeCredential and GetAssertion.
+                let permissions = Ctap2AuthTokenPermissionRole::MAKE_CREDENTIAL
+                    | Ctap2AuthTokenPermissionRole::GET_ASSERTION;
+                self.get_pin_token(command, permissions, None)
+            }
+            Ctap2PinUvAuthProtocolCommand::GetPinUvAuthTokenUsingPinWithPermissions => {
+                let Some(permissions) = command
+                    .permissions
+                    .filter(|&permissions| permissions != 0)
+                    .map(Ctap2AuthTokenPermissionRole::from_bits_retain)
+                else {
+                    return Err(CtapError::MissingParameter);
+                };
+                let supported = Ctap2AuthTokenPermissionRole::MAKE_CREDENTIAL
+                    | Ctap2AuthTokenPermissionRole::GET_ASSERTION
+                    | Ctap2AuthTokenPermissionRole::CREDENTIAL_MANAGEMENT;
+                if !supported.contains(permissions) {
+                    return Err(CtapError::UnauthorizedPermission);
+                }
+                let rp_id = command.permissions_rpid.clone();
+                self.get_pin_token(command, permissions, rp_id)
+            }
+            // There is no built-in user verification method.
+            Ctap2PinUvAuthProtocolCommand::GetPinUvAuthTokenUsingUvWithPermissions
+            | Ctap2PinUvAuthProtocolCommand::GetUvRetries => Err(CtapError::InvalidSubcommand),
+        }
+    }
+
+    fn needs_power_cycle(&self) -> bool {
+        self.consecutive_failures >= MAX_CONSECUTIVE_PIN_FAILURES
+    }
+
+    fn shared_secret(&self, command: &ClientPinCommand) -> Result<Zeroizing<Vec<u8>>, CtapError> {
+        let Some(key_agreement) = &command.key_agreement else {
+            return Err(CtapError::MissingParameter);
+        };
+        let (_, shared_secret) = self
+            .protocol(command.protocol)?
+            .encapsulate(key_agreement)
+            .or(Err(CtapError::InvalidParameter))?;
+        Ok(shared_secret)
+    }
+
+    fn verify_shared_secret_auth(
+        &self,
+        command: &ClientPinCommand,
+        shared_secret: &[u8],
+        message: &[u8],
+    ) -> Result<(), CtapError> {
+        let Some(pin_uv_auth_param) = &command.pin_uv_auth_param else {
+            return Err(CtapError::MissingParameter);
+        };
+        let expected = self
+            .protocol(command.protocol)?
+            .authenticate(shared_secret, message);
+        match constant_time_eq(&expected, pin_uv_auth_param) {
+            true => Ok(()),
+            false => Err(CtapError::PINAuthInvalid),
+        }
+    }
+
+    fn decrypt_new_pin(
+        &self,
+        command: &ClientPinCommand,
+        shared_secret: &[u8],
+    ) -> Result<Zeroizing<Vec<u8>>, CtapError> {
+        let Some(new_pin_encrypted) = &command.new_pin_encrypted else {
+            return Err(CtapError::MissingParameter);
+        };
+        let padded_pin = self
+            .protocol(command.protocol)?
+            .decrypt(shared_secret, new_pin_encrypted)
+            .or(Err(CtapError::InvalidParameter))?;
+        if padded_pin.len() != PADDED_PIN_LENGTH {
+            return Err(CtapError::InvalidParameter);
+        }
+        let pin_length = padded_pin
+            .iter()
+            .position(|&byte| byte == 0)
+            .unwrap_or(PADDED_PIN_LENGTH);
+        let Ok(pin) = std::str::from_utf8(&padded_pin[..pin_length]) else {
+            return Err(CtapError::PINPolicyViolation);
+        };
+        if pin.chars().count() < MIN_PIN_LENGTH || pin_length == PADDED_PIN_LENGTH {
+            return Err(CtapError::PINPolicyViolation);
+        }
+        Ok(Zeroizing::new(padded_pin[..pin_length].to_vec()))
+    }
+
+    fn set_pin(&mut self, command: ClientPinCommand) -> Result<ClientPinReply, CtapError> {
+        if self.is_set() {
+            return Err(CtapError::NotAllowed);
+        }
+        let shared_secret = self.shared_secret(&command)?;
+        let Some(new_pin_encrypted) = &command.new_pin_encrypted else {
+            return Err(CtapError::MissingParameter);
+        };
+        self.verify_shared_secret_auth(&command, &shared_secret, new_pin_encrypted)?;
+        let new_pin = self.decrypt_new_pin(&command, &shared_secret)?;
+        self.pin_hash = Some(pin_hash(&new_pin));
+        self.retries = MAX_PIN_RETRIES;
+        info!("PIN set");
+        Ok(ClientPinReply::default())
+    }
+
+    fn change_pin(&mut self, command: ClientPinCommand) -> Result<ClientPinReply, CtapError> {
+        if !self.is_set() {
+            return Err(CtapError::PINNotSet);
+        }
+        let shared_secret = self.shared_secret(&command)?;
+        let (Some(new_pin_encrypted), Some(pin_hash_encrypted)) =
+            (&command.new_pin_encrypted, &command.pin_hash_encrypted)
+        else {
+            return Err(CtapError::MissingParameter);
+        };
+        let message = [new_pin_encrypted.as_slice(), pin_hash_encrypted.as_slice()].concat();
+        self.verify_shared_secret_auth(&command, &shared_secret, &message)?;
+        self.verify_pin_hash(&command, &shared_secret)?;
+        let new_pin = self.decrypt_new_pin(&command, &shared_secret)?;
+        self.pin_hash = Some(pin_hash(&new_pin));
+        // Changing the PIN invalidates all tokens.
+        self.token = None;
+        info!("PIN changed");
+        Ok(ClientPinReply::default())
+    }
+
+    fn get_pin_token(
+        &mut self,
+        command: ClientPinCommand,
+        permissions: Ctap2AuthTokenPermissionRole,
+        rp_id: Option<String>,
+    ) -> Result<ClientPinReply, CtapError> {
+        if !self.is_set() {
+            return Err(CtapError::PINNotSet);
+        }
+        let shared_secret = self.shared_secret(&command)?;
+        self.verify_pin_hash(&command, &shared_secret)?;
+
+        let token: [u8; 32] = thread_rng().gen();
+        let encrypted_token = self
+            .protocol(command.protocol)?
+            .encrypt(&shared_secret, &token)
+            .or(Err(CtapError::Other))?;
+        debug!(?permissions, ?rp_id, "Issuing pinUvAuthToken");
+        self.token = Some(PinUvAuthToken {
+            token: Zeroizing::new(token.to_vec()),
+            permissions,
+            rp_id,
+        });
+        Ok(ClientPinReply {
+            pin_uv_auth_token: Some(ByteBuf::from(encrypted_token)),
+            ..Default::default()
+        })
+    }
+
+    /// Checks pinHashEnc against the PIN, counting down the retries.
+    fn verify_pin_hash(
+        &mut self,
+        command: &ClientPinCommand,
+        shared_secret: &[u8],
+    ) -> Result<(), CtapError> {
+        if self.retries == 0 {
+            return Err(CtapError::PINBlocked);
+        }
+        if self.needs_power_cycle() {
+            return Err(CtapError::PINAuthBlocked);
+        }
+        let Some(pin_hash_encrypted) = &command.pin_hash_encrypted else {
+            return Err(CtapError::MissingParameter);
+        };
+        self.retries -= 1;
+        let pin_hash = self
+            .protocol(command.protocol)?
+            .decrypt(shared_secret, pin_hash_encrypted)
+            .or(Err(CtapError::InvalidParameter))?;
+        if !constant_time_eq(
+            &pin_hash,
+            self.pin_hash
+                .as_ref()
+                .map_or(&[][..], |hash| hash.as_slice()),
+        ) {
+            warn!(retries = self.retries, "PIN mismatch");
+            // A new key agreement key prevents replaying the same pinHashEnc.
+            self.protocol_one = PinUvAuthProtocolOne::new();
+            self.protocol_two = PinUvAuthProtocolTwo::new();
+            self.consecutive_failures += 1;
+            return Err(if self.retries == 0 {
+                CtapError::PINBlocked
+            } else if self.needs_power_cycle() {
+                CtapError::PINAuthBlocked
+            } else {
+                CtapError::PINInvalid
+            });
+        }
+        self.retries = MAX_PIN_RETRIES;
+        self.consecutive_failures = 0;
+        Ok(())
+    }
+
+    /// Verifies the pinUvAuthParam of a request, authenticating `message` with the current
+    /// pinUvAuthToken. Tokens with MakeCredential or GetAssertion permission get bound to the
+    /// first RP they're used with.
+    pub fn verify_token(
+        &mut self,
+        protocol: Option<u32>,
+        pin_uv_auth_param: &[u8],
+        message: &[u8],
+        permission: Ctap2AuthTokenPermissionRole,
+        rp_id: Option<&str>,
+    ) -> Result<(), CtapError> {
+        let protocol = self.protocol(protocol)?;
+        let Some(token) = &self.token else {
+            return Err(CtapError::PINAuthInvalid);
+        };
+        let expected = protocol.authenticate(&token.token, message);
+        if !constant_time_eq(&expected, pin_uv_auth_param) {
+            return Err(CtapError::PINAuthInvalid);
+        }
+        if !token.permissions.contains(permission) {
+            return Err(CtapError::UnauthorizedPermission);
+        }
+        let token = self.token.as_mut().unwrap();
+        match (&token.rp_id, rp_id) {
+            (Some(bound), Some(rp_id)) if bound != rp_id => Err(CtapError::UnauthorizedPermission),
+            (Some(_), None) => Err(CtapError::UnauthorizedPermission),
+            (None, Some(rp_id))
+                if permission.intersects(
+                    Ctap2AuthTokenPermissionRole::MAKE_CREDENTIAL
+                        | Ctap2AuthTokenPermissionRole::GET_ASSERTION,
+                ) =>
+            {
+                token.rp_id = Some(rp_id.to_owned());
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
@@ -0,0 +1,302 @@
+//! authenticatorCredentialManagement of local authenticators, for discoverable credentials.
+
+use std::collections::VecDeque;
+
+use cosey::PublicKey;
+use num_traits::FromPrimitive;
+use serde_bytes::ByteBuf;
+use serde_indexed::{DeserializeIndexed, SerializeIndexed};
+use sha2::{Digest, Sha256};
+use tracing::{debug, info, warn};
+
+use crate::proto::ctap2::cbor;
+use crate::proto::ctap2::{
+    Ctap2AuthTokenPermissionRole, Ctap2CredentialManagementSubcommand,
+    Ctap2PublicKeyCredentialDescriptor, Ctap2PublicKeyCredentialRpEntity,
+    Ctap2PublicKeyCredentialType, Ctap2PublicKeyCredentialUserEntity,
+};
+use crate::proto::CtapError;
+
+use super::authenticator::{
+    cose_public_key, store_error, Authenticator, MAX_DISCOVERABLE_CREDENTIALS,
+};
+use super::store::{CredentialStore, LocalCredential};
+
+/// credProtect level userVerificationOptional, the only one we create credentials with.
+const CRED_PROTECT_UV_OPTIONAL: u64 = 0x01;
+
+#[derive(Debug, DeserializeIndexed)]
+pub(crate) struct CredentialManagementCommand {
+    #[serde(index = 0x01)]
+    subcommand: u32,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(index = 0x02)]
+    params: Option<CredentialManagementParams>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(index = 0x03)]
+    protocol: Option<u32>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(index = 0x04)]
+    pin_uv_auth_param: Option<ByteBuf>,
+}
+
+/// Re-encoded to verify pinUvAuthParam, so this matches the platform's encoding.
+#[derive(Debug, SerializeIndexed, DeserializeIndexed)]
+struct CredentialManagementParams {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(index = 0x01)]
+    rp_id_hash: Option<ByteBuf>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(index = 0x02)]
+    credential_id: Option<Ctap2PublicKeyCredentialDescriptor>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(index = 0x03)]
+    user: Option<Ctap2PublicKeyCredentialUserEntity>,
+}
+
+#[derive(Debug, Default, SerializeIndexed)]
+struct CredentialManagementReply {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(index = 0x01)]
+    existing_resident_credentials_count: Option<u64>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(index = 0x02)]
+    max_possible_remaining_resident_credentials_count: Option<u64>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(index = 0x03)]
+    rp: Option<Ctap2PublicKeyCredentialRpEntity>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(index = 0x04)]
+    rp_id_hash: Option<ByteBuf>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(index = 0x05)]
+    total_rps: Option<u64>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(index = 0x06)]
+    user: Option<Ctap2PublicKeyCredentialUserEntity>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(index = 0x07)]
+    credential_id: Option<Ctap2PublicKeyCredentialDescriptor>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(index = 0x08)]
+    public_key: Option<PublicKey>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(index = 0x09)]
+    total_credentials: Option<u64>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(index = 0x0A)]
+    cred_protect: Option<u64>,
+}
+
+/// Remaining RPs or credentials of an enumeration, for the GetNext subcommands.
+pub(crate) enum PendingEnumeration {
+    Rps(VecDeque<Ctap2PublicKeyCredentialRpEntity>),
+    Credentials(VecDeque<LocalCredential>),
+}
+
+impl<S: CredentialStore> Authenticator<S> {
+    pub(crate) fn credential_management(
+        &mut self,
+        command: CredentialManagementCommand,
+    ) -> Result<Vec<u8>, CtapError> {
+        let Some(subcommand) = Ctap2CredentialManagementSubcommand::from_u32(command.subcommand)
+        else {
+            return Err(CtapError::InvalidSubcommand);
+        };
+        debug!(?subcommand, "Processing credential management");
+        let pending = self.pending_enumeration.take();
+        let reply = match subcommand {
+            Ctap2CredentialManagementSubcommand::GetCredsMetadata => {
+                self.verify_credential_management(&command, None)?;
+                let count = self.discoverable_credentials()?.len();
+                CredentialManagementReply {
+                    existing_resident_credentials_count: Some(count as u64),
+                    max_possible_remaining_resident_credentials_count: Some(
+                        MAX_DISCOVERABLE_CREDENTIALS.saturating_sub(count) as u64,
+                    ),
+                    ..Default::default()
+                }
+            }
+            Ctap2CredentialManagementSubcommand::EnumerateRPsBegin => {
+                self.verify_credential_management(&command, None)?;
+                let mut rps: VecDeque<Ctap2PublicKeyCredentialRpEntity> = VecDeque::new();
+                for credential in self.discoverable_credentials()? {
+                    if !rps.iter().any(|rp| rp.id == credential.rp.id) {
+                        rps.push_back(credential.rp);
+                    }
+                }
+                let total_rps = rps.len() as u64;
+                let Some(rp) = rps.pop_front() else {
+                    return Err(CtapError::NoCredentials);
+                };
+                self.pending_enumeration = Some(PendingEnumeration::Rps(rps));
+                CredentialManagementReply {
+                    total_rps: Some(total_rps),
+                    ..rp_reply(rp)
+                }
+            }
+            Ctap2CredentialManagementSubcommand::EnumerateRPsGetNextRP => {
+                let Some(PendingEnumeration::Rps(mut rps)) = pending else {
+                    return Err(CtapError::NotAllowed);
+                };
+                let Some(rp) = rps.pop_front() else {
+                    return Err(CtapError::NotAllowed);
+                };
+                self.pending_enumeration = Some(PendingEnumeration::Rps(rps));
+                rp_reply(rp)
+            }
+            Ctap2CredentialManagementSubcommand::EnumerateCredentialsBegin => {
+                let Some(rp_id_hash) = command
+                    .params
+                    .as_ref()
+                    .and_then(|params| params.rp_id_hash.clone())
+                else {
+                    return Err(CtapError::MissingParameter);
+                };
+                self.verify_credential_management(&command, None)?;
+                let mut credentials: VecDeque<LocalCredential> = self
+                    .discoverable_credentials()?
+                    .into_iter()
+                    .filter(|cred| Sha256::digest(cred.rp.id.as_bytes()).as_slice() == *rp_id_hash)
+                    .collect();
+                let total_credentials = credentials.len() as u64;
+                let Some(credential) = credentials.pop_front() else {
+                    return Err(CtapError::NoCredentials);
+                };
+                self.pending_enumeration = Some(PendingEnumeration::Credentials(credentials));
+                CredentialManagementReply {
+                    total_credentials: Some(total_credentials),
+                    ..credential_reply(credential)
+                }
+            }
+            Ctap2CredentialManagementSubcommand::EnumerateCredentialsGetNextCredential => {
+                let Some(PendingEnumeration::Credentials(mut credentials)) = pending else {
+                    return Err(CtapError::NotAllowed);
+                };
+                let Some(credential) = credentials.pop_front() else {
+                    return Err(CtapError::NotAllowed);
+                };
+                self.pending_enumeration = Some(PendingEnumeration::Credentials(credentials));
+                credential_reply(credential)
+            }
+            Ctap2CredentialManagementSubcommand::DeleteCredential => {
+                let credential = self.find_credential(&command)?;
+                self.verify_credential_management(&command, Some(&credential.rp.id))?;
+                self.store.delete(&credential.id).map_err(store_error)?;
+                info!(rp = %credential.rp.id, "Deleted credential");
+                CredentialManagementReply::default()
+            }
+            Ctap2CredentialManagementSubcommand::UpdateUserInformation => {
+                let mut credential = self.find_credential(&command)?;
+                let Some(user) = command
+                    .params
+                    .as_ref()
+                    .and_then(|params| params.user.clone())
+                else {
+                    return Err(CtapError::MissingParameter);
+                };
+                self.verify_credential_management(&command, Some(&credential.rp.id))?;
+                if user.id != credential.user.id {
+                    return Err(CtapError::InvalidParameter);
+                }
+                credential.user = user;
+                self.store.update(&credential).map_err(store_error)?;
+                info!(rp = %credential.rp.id, "Updated user information");
+                CredentialManagementReply::default()
+            }
+        };
+        cbor::to_vec_canonical(&reply).or(Err(CtapError::Other))
+    }
+
+    /// Checks the pinUvAuthParam over subCommand || subCommandParams.
+    fn verify_credential_management(
+        &mut self,
+        command: &CredentialManagementCommand,
+        rp_id: Option<&str>,
+    ) -> Result<(), CtapError> {
+        let Some(client_pin) = self.client_pin.as_mut() else {
+            return Err(CtapError::InvalidCommand);
+        };
+        let Some(pin_uv_auth_param) = &command.pin_uv_auth_param else {
+            return Err(CtapError::PINRequired);
+        };
+        let mut message = vec![command.subcommand as u8];
+        if let Some(params) = &command.params {
+            message.extend(cbor::to_vec_canonical(params).or(Err(CtapError::InvalidCbor))?);
+        }
+        client_pin.verify_token(
+            command.protocol,
+            pin_uv_auth_param,
+            &message,
+            Ctap2AuthTokenPermissionRole::CREDENTIAL_MANAGEMENT,
+            rp_id,
+        )
+    }
+
+    fn discoverable_credentials(&self) -> Result<Vec<LocalCredential>, CtapError> {
+        Ok(self
+            .store
+            .credentials()
+            .map_err(store_error)?
+            .into_iter()
+            .filter(|cred| cred.discoverable)
+            .collect())
+    }
+
+    fn find_credential(
+        &self,
+        command: &CredentialManagementCommand,
+    ) -> Result<LocalCredential, CtapError> {
+        let Some(descriptor) = command
+            .params
+            .as_ref()
+            .and_then(|params| params.credential_id.as_ref())
+        else {
+            return Err(CtapError::MissingParameter);
+        };
+        self.discoverable_credentials()?
+            .into_iter()
+            .find(|cred| cred.id == descriptor.id)
+            .ok_or(CtapError::NoCredentials)
+    }
+}
+
+fn rp_reply(rp: Ctap2PublicKeyCredentialRpEntity) -> CredentialManagementReply {
+    CredentialManagementReply {
+        rp_id_hash: Some(ByteBuf::from(Sha256::digest(rp.id.as_bytes()).to_vec())),
+        rp: Some(rp),
+        ..Default::default()
+    }
+}
+
+fn credential_reply(credential: LocalCredential) -> CredentialManagementReply {
+    let public_key = p256::EncodedPoint::from_bytes(&credential.public_key)
+        .inspect_err(|err| warn!(?err, "Stored credential has an invalid public key"))
+        .ok()
+        .map(|point| cose_public_key(&point));
+    CredentialManagementReply {
+        user: Some(credential.user),
+        credential_id: Some(Ctap2PublicKeyCredentialDescriptor {
+            id: credential.id,
+            r#type: Ctap2PublicKeyCredentialType::PublicKey,
+            transports: None,
+        }),
+        public_key,
+        cred_protect: Some(CRED_PROTECT_UV_OPTIONAL),
+        ..Default::default()
+    }
+}
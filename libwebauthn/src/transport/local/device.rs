@@ -0,0 +1,64 @@
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+
+use crate::proto::ctap2::Ctap2CommandCode;
+use crate::proto::CtapError;
+use crate::transport::device::Device;
+use crate::webauthn::error::Error;
+
+use super::authenticator::Authenticator;
+use super::capabilities::Capabilities;
+use super::channel::LocalChannel;
+use super::store::CredentialStore;
+use super::Local;
+
+/// An authenticator running in this process, keeping its credentials in `S`.
+pub struct LocalDevice<S> {
+    name: String,
+    pub(crate) authenticator: Arc<Mutex<Authenticator<S>>>,
+}
+
+impl<S: CredentialStore> LocalDevice<S> {
+    pub fn new(name: &str, aaguid: [u8; 16], store: S) -> Self {
+        Self {
+            name: name.to_string(),
+            authenticator: Arc::new(Mutex::new(Authenticator::new(aaguid, store))),
+        }
+    }
+
+    /// Replaces what the authenticator supports. Disables clientPin if not supported.
+    pub fn with_capabilities(self, capabilities: Capabilities) -> Self {
+        {
+            let mut authenticator = self.authenticator.lock().unwrap();
+            if !capabilities.client_pin {
+                authenticator.client_pin = None;
+            }
+            authenticator.capabilities = capabilities;
+        }
+        self
+    }
+
+    /// Fails the next `command` request with `error`, once.
+    pub fn inject_error(&self, command: Ctap2CommandCode, error: CtapError) {
+        let mut authenticator = self.authenticator.lock().unwrap();
+        authenticator
+            .capabilities
+            .injected_errors
+            .push((command, error));
+    }
+}
+
+impl<S> fmt::Display for LocalDevice<S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name)
+    }
+}
+
+#[async_trait]
+impl<'d, S: CredentialStore + 'static> Device<'d, Local, LocalChannel<'d, S>> for LocalDevice<S> {
+    async fn channel(&'d mut self) -> Result<LocalChannel<'d, S>, Error> {
+        Ok(LocalChannel::new(self))
+    }
+}
@@ -0,0 +1,96 @@
+use p256::SecretKey;
+
+use crate::webauthn::error::{Error, PlatformError};
+
+use super::store::{CredentialStore, LocalCredential};
+use super::LocalDevice;
+
+pub const VIRTUAL_AAGUID: [u8; 16] = [
+    0x4c, 0x69, 0x62, 0x57, 0x65, 0x62, 0x41, 0x75, 0x74, 0x68, 0x6e, 0x56, 0x69, 0x72, 0x74, 0x00,
+];
+
+/// An authenticator keeping everything in memory, with clientPin and credential management.
+/// Meant for integration tests, which can drive it like any other device.
+pub type VirtualDevice = LocalDevice<MemoryCredentialStore>;
+
+impl VirtualDevice {
+    /// A virtual authenticator without credentials, nor a PIN.
+    pub fn new_virtual() -> Self {
+        let device = LocalDevice::new("Virtual", VIRTUAL_AAGUID, MemoryCredentialStore::default());
+        device.authenticator.lock().unwrap().enable_client_pin();
+        device
+    }
+
+    /// Sets the PIN, as if it had been set by a platform.
+    pub fn with_pin(self, pin: &str) -> Self {
+        self.authenticator
+            .lock()
+            .unwrap()
+            .enable_client_pin()
+            .set(pin);
+        self
+    }
+
+    /// All credentials currently stored, oldest first.
+    pub fn credentials(&self) -> Vec<LocalCredential> {
+        self.authenticator
+            .lock()
+            .unwrap()
+            .store
+            .credentials
+            .iter()
+            .map(|(credential, _)| credential.clone())
+            .collect()
+    }
+}
+
+/// Keeps credentials and their private keys in memory, so they are lost when dropped.
+#[derive(Default)]
+pub struct MemoryCredentialStore {
+    credentials: Vec<(LocalCredential, SecretKey)>,
+}
+
+impl CredentialStore for MemoryCredentialStore {
+    fn credentials(&self) -> Result<Vec<LocalCredential>, Error> {
+        Ok(self
+            .credentials
+            .iter()
+            .map(|(credential, _)| credential.clone())
+            .collect())
+    }
+
+    fn insert(
+        &mut self,
+        credential: LocalCredential,
+        private_key: &SecretKey,
+    ) -> Result<(), Error> {
+        self.credentials.push((credential, private_key.clone()));
+        Ok(())
+    }
+
+    fn update(&mut self, credential: &LocalCredential) -> Result<(), Error> {
+        let Some((stored, _)) = self
+            .credentials
+            .iter_mut()
+            .find(|(stored, _)| stored.id == credential.id)
+        else {
+            return Err(Error::Platform(PlatformError::CredentialNotFound));
+        };
+        *stored = credential.clone();
+        Ok(())
+    }
+
+    fn delete(&mut self, credential_id: &[u8]) -> Result<(), Error> {
+        self.credentials
+            .retain(|(credential, _)| credential.id.as_slice() != credential_id);
+        Ok(())
+    }
+
+    fn private_key(&mut self, credential_id: &[u8]) -> Result<SecretKey, Error> {
+        self.credentials
+            .iter()
+            .find(|(credential, _)| credential.id.as_slice() == credential_id)
+            .map(|(_, private_key)| private_key.clone())
+            .ok_or(Error::Platform(PlatformError::CredentialNotFound))
+    }
+}
@@ -0,0 +1,32 @@
+//! Authenticators implemented in this process, speaking CTAP2 to a [store::CredentialStore]
+//! rather than to a physical device.
+
+use std::fmt::Display;
+
+pub(crate) mod authenticator;
+pub mod capabilities;
+pub mod channel;
+mod client_pin;
+mod credential_management;
+pub mod device;
+pub mod memory;
+pub mod store;
+
+pub use capabilities::Capabilities;
+pub use channel::LocalChannel;
+pub use device::LocalDevice;
+pub use memory::{MemoryCredentialStore, VirtualDevice};
+pub use store::{CredentialStore, LocalCredential};
+
+use super::Transport;
+
+pub struct Local {}
+impl Transport for Local {}
+unsafe impl Send for Local {}
+unsafe impl Sync for Local {}
+
+impl Display for Local {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Local")
+    }
+}
@@ -0,0 +1,64 @@
+use p256::SecretKey;
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+
+use crate::proto::ctap2::{Ctap2PublicKeyCredentialRpEntity, Ctap2PublicKeyCredentialUserEntity};
+use crate::webauthn::error::Error;
+
+/// A credential created by a local authenticator. The private key is kept by the
+/// [CredentialStore], and only handed out for signing.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LocalCredential {
+    pub id: ByteBuf,
+    pub rp: Ctap2PublicKeyCredentialRpEntity,
+    pub user: Ctap2PublicKeyCredentialUserEntity,
+    /// SEC1-encoded P-256 public key
+    pub public_key: ByteBuf,
+    pub discoverable: bool,
+    pub sign_count: u32,
+}
+
+/// Storage backend of a local authenticator. Implementations may block, e.g. on
+/// hardware, as they are only called from blocking tasks.
+pub trait CredentialStore: Send {
+    /// All stored credentials, oldest first.
+    fn credentials(&self) -> Result<Vec<LocalCredential>, Error>;
+
+    fn insert(&mut self, credential: LocalCredential, private_key: &SecretKey)
+        -> Result<(), Error>;
+
+    /// Replaces the stored credential with the same ID, e.g. to persist the signature counter.
+    fn update(&mut self, credential: &LocalCredential) -> Result<(), Error>;
+
+    fn delete(&mut self, credential_id: &[u8]) -> Result<(), Error>;
+
+    fn private_key(&mut self, credential_id: &[u8]) -> Result<SecretKey, Error>;
+}
+
+/// Allows keeping local devices with different stores together, e.g. in an
+/// [`AnyDevice`](crate::transport::any::AnyDevice).
+impl<S: CredentialStore + ?Sized> CredentialStore for Box<S> {
+    fn credentials(&self) -> Result<Vec<LocalCredential>, Error> {
+        (**self).credentials()
+    }
+
+    fn insert(
+        &mut self,
+        credential: LocalCredential,
+        private_key: &SecretKey,
+    ) -> Result<(), Error> {
+        (**self).insert(credential, private_key)
+    }
+
+    fn update(&mut self, credential: &LocalCredential) -> Result<(), Error> {
+        (**self).update(credential)
+    }
+
+    fn delete(&mut self, credential_id: &[u8]) -> Result<(), Error> {
+        (**self).delete(credential_id)
+    }
+
+    fn private_key(&mut self, credential_id: &[u8]) -> Result<SecretKey, Error> {
+        (**self).private_key(credential_id)
+    }
+}
@@ -1,14 +1,32 @@
 pub(crate) mod error;
 
+pub mod any;
 pub mod ble;
 pub mod cable;
+#[cfg(feature = "daemon")]
+pub mod daemon;
 pub mod device;
+pub mod discovery;
 pub mod hid;
+pub mod local;
+pub mod recording;
+#[cfg(feature = "remote")]
+pub mod remote;
+#[cfg(any(feature = "daemon", feature = "remote"))]
+pub mod socket;
+#[cfg(feature = "tpm")]
+pub mod tpm;
+pub mod ux_stream;
 
 mod channel;
 mod transport;
 
-pub(crate) use channel::{AuthTokenData, Ctap2AuthTokenPermission};
-pub use channel::{Channel, Ctap2AuthTokenStore};
+pub(crate) use channel::until_cancelled;
+pub use channel::{
+    AuthTokenData, AuthTokenMismatch, Channel, ChannelStatus, Ctap2AuthTokenPermission,
+    Ctap2AuthTokenStore, DeviceAaguid, DeviceDescriptor, DeviceUxUpdate, DeviceUxUpdateReceiver,
+    MAX_DEVICE_LOCK_DURATION,
+};
 pub use device::Device;
+pub use tokio_util::sync::CancellationToken;
 pub use transport::Transport;
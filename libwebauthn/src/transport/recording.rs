@@ -0,0 +1,527 @@
+//! Recording and replaying the traffic of a channel, for regression tests.
+//!
+//! [RecordingChannel] wraps any channel, and records the CBOR and APDU requests sent through
+//! it along with the device's responses. The [Recording] can be saved as JSON, e.g. attached
+//! to a bug report, and served by a [ReplayChannel] to reproduce the ceremony without the
+//! device.
+//!
+//! Exchanges are recorded above the transport's framing, so HID reports or caBLE tunnel
+//! messages aren't part of a recording. Replays only check that requests are sent in the
+//! recorded order, and with the recorded commands: request payloads containing fresh
+//! randomness, such as PIN protocol key agreements, are expected to differ from the recorded
+//! ones. Requests failing at the transport, e.g. with a timeout, aren't recorded.
+
+use std::collections::VecDeque;
+use std::fmt::{Display, Formatter};
+use std::io::{Read, Write};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, error, warn};
+
+use crate::audit::AuditTrail;
+use crate::metrics::MetricsRecorder;
+use crate::pin::{PinProvider, PinUvAuthProtocols};
+use crate::proto::ctap1::apdu::{ApduRequest, ApduResponse};
+use crate::proto::ctap2::cbor::{CborRequest, CborResponse};
+use crate::quirks::UsbId;
+use crate::timeout::TimeoutPolicy;
+use crate::transport::cable::known_devices::ClientPayloadHint;
+use crate::transport::channel::{
+    AuthTokenData, Channel, ChannelStatus, Ctap2AuthTokenStore, DeviceAaguid,
+};
+use crate::transport::device::SupportedProtocols;
+use crate::transport::error::TransportError;
+use crate::webauthn::error::Error;
+use crate::UvUpdate;
+
+/// The traffic of a channel, in the order it was exchanged.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Recording {
+    pub u2f: bool,
+    pub fido2: bool,
+    pub supports_preflight: bool,
+    pub exchanges: Vec<Exchange>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Exchange {
+    Cbor {
+        command: u8,
+        #[serde(with = "hex_bytes")]
+        request: Vec<u8>,
+        status: u8,
+        #[serde(with = "hex_bytes")]
+        response: Vec<u8>,
+    },
+    /// Requests and responses in their long-form encoding, including the status words.
+    Apdu {
+        #[serde(with = "hex_bytes")]
+        request: Vec<u8>,
+        #[serde(with = "hex_bytes")]
+        response: Vec<u8>,
+    },
+}
+
+impl Recording {
+    pub fn to_writer(&self, writer: impl Write) -> Result<(), serde_json::Error> {
+        serde_json::to_writer_pretty(writer, self)
+    }
+
+    pub fn from_reader(reader: impl Read) -> Result<Self, serde_json::Error> {
+        serde_json::from_reader(reader)
+    }
+}
+
+mod hex_bytes {
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&hex::encode(bytes))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        hex::decode(String::deserialize(deserializer)?).map_err(D::Error::custom)
+    }
+}
+
+/// Records the traffic of the wrapped channel.
+pub struct RecordingChannel<C> {
+    inner: C,
+    recording: Arc<Mutex<Recording>>,
+    pending_cbor: Option<CborRequest>,
+    pending_apdu: Mutex<Option<Vec<u8>>>,
+}
+
+impl<C: Channel> RecordingChannel<C> {
+    pub async fn new(inner: C) -> Result<Self, Error> {
+        let protocols = inner.supported_protocols().await?;
+        let recording = Recording {
+            u2f: protocols.u2f,
+            fido2: protocols.fido2,
+            supports_preflight: inner.supports_preflight(),
+            exchanges: vec![],
+        };
+        Ok(Self {
+            inner,
+            recording: Arc::new(Mutex::new(recording)),
+            pending_cbor: None,
+            pending_apdu: Mutex::new(None),
+        })
+    }
+
+    /// The traffic recorded so far.
+    pub fn recording(&self) -> Recording {
+        self.recording.lock().unwrap().clone()
+    }
+
+    pub fn into_inner(self) -> C {
+        self.inner
+    }
+
+    fn push(&self, exchange: Exchange) {
+        self.recording.lock().unwrap().exchanges.push(exchange);
+    }
+}
+
+impl<C: Display> Display for RecordingChannel<C> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} (recording)", self.inner)
+    }
+}
+
+#[async_trait]
+impl<C: Channel> Channel for RecordingChannel<C> {
+    type UxUpdate = C::UxUpdate;
+
+    fn get_ux_update_sender(&self) -> &broadcast::Sender<Self::UxUpdate> {
+        self.inner.get_ux_update_sender()
+    }
+
+    fn get_pin_provider(&self) -> Option<Arc<dyn PinProvider>> {
+        self.inner.get_pin_provider()
+    }
+
+    fn set_pin_provider(&mut self, provider: Option<Arc<dyn PinProvider>>) {
+        self.inner.set_pin_provider(provider)
+    }
+
+    fn get_cancellation_token(&self) -> Option<CancellationToken> {
+        self.inner.get_cancellation_token()
+    }
+
+    fn set_cancellation_token(&mut self, token: Option<CancellationToken>) {
+        self.inner.set_cancellation_token(token)
+    }
+
+    fn get_timeout_policy(&self) -> TimeoutPolicy {
+        self.inner.get_timeout_policy()
+    }
+
+    fn set_timeout_policy(&mut self, policy: TimeoutPolicy) {
+        self.inner.set_timeout_policy(policy)
+    }
+
+    fn get_audit_trail(&self) -> Option<AuditTrail> {
+        self.inner.get_audit_trail()
+    }
+
+    fn set_audit_trail(&mut self, trail: Option<AuditTrail>) {
+        self.inner.set_audit_trail(trail)
+    }
+
+    fn get_metrics_recorder(&self) -> Option<Arc<dyn MetricsRecorder>> {
+        self.inner.get_metrics_recorder()
+    }
+
+    fn set_metrics_recorder(&mut self, recorder: Option<Arc<dyn MetricsRecorder>>) {
+        self.inner.set_metrics_recorder(recorder)
+    }
+
+    fn get_pin_uv_auth_protocols(&self) -> PinUvAuthProtocols {
+        self.inner.get_pin_uv_auth_protocols()
+    }
+
+    fn set_pin_uv_auth_protocols(&mut self, protocols: PinUvAuthProtocols) {
+        self.inner.set_pin_uv_auth_protocols(protocols)
+    }
+
+    async fn supported_protocols(&self) -> Result<SupportedProtocols, Error> {
+        self.inner.supported_protocols().await
+    }
+
+    async fn status(&self) -> ChannelStatus {
+        self.inner.status().await
+    }
+
+    async fn close(&mut self) {
+        self.inner.close().await
+    }
+
+    async fn apdu_send(&self, request: &ApduRequest, timeout: Duration) -> Result<(), Error> {
+        self.inner.apdu_send(request, timeout).await?;
+        *self.pending_apdu.lock().unwrap() = request
+            .raw_long()
+            .inspect_err(|err| warn!(?err, "Failed to encode APDU for the recording"))
+            .ok();
+        Ok(())
+    }
+
+    async fn apdu_recv(&self, timeout: Duration) -> Result<ApduResponse, Error> {
+        let response = self.inner.apdu_recv(timeout).await?;
+        if let Some(request) = self.pending_apdu.lock().unwrap().take() {
+            self.push(Exchange::Apdu {
+                request,
+                response: response.to_bytes(),
+            });
+        }
+        Ok(response)
+    }
+
+    async fn cbor_send(&mut self, request: &CborRequest, timeout: Duration) -> Result<(), Error> {
+        self.inner.cbor_send(request, timeout).await?;
+        self.pending_cbor = Some(request.clone());
+        Ok(())
+    }
+
+    async fn cbor_recv(&mut self, timeout: Duration) -> Result<CborResponse, Error> {
+        let response = self.inner.cbor_recv(timeout).await?;
+        if let Some(request) = self.pending_cbor.take() {
+            self.push(Exchange::Cbor {
+                command: request.command as u8,
+                request: request.encoded_data,
+                status: response.status_code.into(),
+                response: response.data.clone().unwrap_or_default(),
+            });
+        }
+        Ok(response)
+    }
+
+    async fn lock_device(&mut self, duration: Duration) -> Result<(), Error> {
+        self.inner.lock_device(duration).await
+    }
+
+    async fn unlock_device(&mut self) -> Result<(), Error> {
+        self.inner.unlock_device().await
+    }
+
+    fn transport_name(&self) -> &'static str {
+        self.inner.transport_name()
+    }
+
+    fn device_aaguid(&self) -> Option<&DeviceAaguid> {
+        self.inner.device_aaguid()
+    }
+
+    fn usb_id(&self) -> Option<UsbId> {
+        self.inner.usb_id()
+    }
+
+    fn supports_preflight(&self) -> bool {
+        self.inner.supports_preflight()
+    }
+
+    fn announce_operation(&mut self, hint: ClientPayloadHint) {
+        self.inner.announce_operation(hint)
+    }
+}
+
+impl<C: Channel> Ctap2AuthTokenStore for RecordingChannel<C> {
+    fn store_auth_data(&mut self, auth_token_data: AuthTokenData) {
+        self.inner.store_auth_data(auth_token_data)
+    }
+
+    fn get_auth_data(&self) -> Option<&AuthTokenData> {
+        self.inner.get_auth_data()
+    }
+
+    fn clear_uv_auth_token_store(&mut self) {
+        self.inner.clear_uv_auth_token_store()
+    }
+}
+
+/// Serves the responses of a [Recording], in order.
+pub struct ReplayChannel {
+    recording: Recording,
+    exchanges: Mutex<VecDeque<Exchange>>,
+    cbor_response: Option<CborResponse>,
+    apdu_response: Mutex<Option<ApduResponse>>,
+    auth_token_data: Option<AuthTokenData>,
+    pin_provider: Option<Arc<dyn PinProvider>>,
+    cancellation_token: Option<CancellationToken>,
+    timeout_policy: TimeoutPolicy,
+    audit_trail: Option<AuditTrail>,
+    metrics_recorder: Option<Arc<dyn MetricsRecorder>>,
+    pin_uv_auth_protocols: PinUvAuthProtocols,
+    ux_update_sender: broadcast::Sender<UvUpdate>,
+}
+
+impl ReplayChannel {
+    pub fn new(recording: Recording) -> Self {
+        let (ux_update_sender, _) = broadcast::channel(16);
+        Self {
+            exchanges: Mutex::new(recording.exchanges.iter().cloned().collect()),
+            recording,
+            cbor_response: None,
+            apdu_response: Mutex::new(None),
+            auth_token_data: None,
+            pin_provider: None,
+            cancellation_token: None,
+            timeout_policy: TimeoutPolicy::default(),
+            audit_trail: None,
+            metrics_recorder: None,
+            pin_uv_auth_protocols: PinUvAuthProtocols::default(),
+            ux_update_sender,
+        }
+    }
+
+    /// Whether all recorded exchanges were replayed.
+    pub fn is_exhausted(&self) -> bool {
+        self.exchanges.lock().unwrap().is_empty()
+    }
+
+    fn next_exchange(&self, request: &'static str) -> Result<Exchange, Error> {
+        match self.exchanges.lock().unwrap().pop_front() {
+            Some(exchange) => Ok(exchange),
+            None => {
+                warn!(request, "Recording exhausted");
+                Err(Error::Transport(TransportError::ConnectionLost))
+            }
+        }
+    }
+}
+
+impl Display for ReplayChannel {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Replay")
+    }
+}
+
+#[async_trait]
+impl Channel for ReplayChannel {
+    type UxUpdate = UvUpdate;
+
+    fn get_ux_update_sender(&self) -> &broadcast::Sender<UvUpdate> {
+        &self.ux_update_sender
+    }
+
+    fn get_pin_provider(&self) -> Option<Arc<dyn PinProvider>> {
+        self.pin_provider.clone()
+    }
+
+    fn set_pin_provider(&mut self, provider: Option<Arc<dyn PinProvider>>) {
+        self.pin_provider = provider;
+    }
+
+    fn get_cancellation_token(&self) -> Option<CancellationToken> {
+        self.cancellation_token.clone()
+    }
+
+    fn set_cancellation_token(&mut self, token: Option<CancellationToken>) {
+        self.cancellation_token = token;
+    }
+
+    fn get_timeout_policy(&self) -> TimeoutPolicy {
+        self.timeout_policy
+    }
+
+    fn set_timeout_policy(&mut self, policy: TimeoutPolicy) {
+        self.timeout_policy = policy;
+    }
+
+    fn get_audit_trail(&self) -> Option<AuditTrail> {
+        self.audit_trail.clone()
+    }
+
+    fn set_audit_trail(&mut self, trail: Option<AuditTrail>) {
+        self.audit_trail = trail;
+    }
+
+    fn get_metrics_recorder(&self) -> Option<Arc<dyn MetricsRecorder>> {
+        self.metrics_recorder.clone()
+    }
+
+    fn set_metrics_recorder(&mut self, recorder: Option<Arc<dyn MetricsRecorder>>) {
+        self.metrics_recorder = recorder;
+    }
+
+    fn get_pin_uv_auth_protocols(&self) -> PinUvAuthProtocols {
+        self.pin_uv_auth_protocols.clone()
+    }
+
+    fn set_pin_uv_auth_protocols(&mut self, protocols: PinUvAuthProtocols) {
+        self.pin_uv_auth_protocols = protocols;
+    }
+
+    async fn supported_protocols(&self) -> Result<SupportedProtocols, Error> {
+        Ok(SupportedProtocols {
+            u2f: self.recording.u2f,
+            fido2: self.recording.fido2,
+        })
+    }
+
+    async fn status(&self) -> ChannelStatus {
+        ChannelStatus::Ready
+    }
+
+    async fn close(&mut self) {}
+
+    async fn apdu_send(&self, _request: &ApduRequest, _timeout: Duration) -> Result<(), Error> {
+        let Exchange::Apdu { response, .. } = self.next_exchange("APDU")? else {
+            error!("Recording expected a CBOR request, not an APDU");
+            return Err(Error::Transport(TransportError::UnexpectedResponse {
+                request: "APDU",
+            }));
+        };
+        let response = ApduResponse::try_from(&response)
+            .or(Err(Error::Transport(TransportError::InvalidFraming)))?;
+        *self.apdu_response.lock().unwrap() = Some(response);
+        Ok(())
+    }
+
+    async fn apdu_recv(&self, _timeout: Duration) -> Result<ApduResponse, Error> {
+        self.apdu_response
+            .lock()
+            .unwrap()
+            .take()
+            .ok_or(Error::Transport(TransportError::InvalidFraming))
+    }
+
+    async fn cbor_send(&mut self, request: &CborRequest, _timeout: Duration) -> Result<(), Error> {
+        let Exchange::Cbor {
+            command,
+            status,
+            response,
+            ..
+        } = self.next_exchange("CBOR")?
+        else {
+            error!("Recording expected an APDU, not a CBOR request");
+            return Err(Error::Transport(TransportError::UnexpectedResponse {
+                request: "CBOR",
+            }));
+        };
+        if command != request.command as u8 {
+            error!(
+                recorded = command,
+                sent = ?request.command,
+                "Replayed request diverged from the recording"
+            );
+            return Err(Error::Transport(TransportError::UnexpectedResponse {
+                request: "CBOR",
+            }));
+        }
+        debug!(command = ?request.command, "Replaying CBOR response");
+        let mut packet = vec![status];
+        packet.extend(response);
+        let response = CborResponse::try_from(&packet)
+            .or(Err(Error::Transport(TransportError::InvalidFraming)))?;
+        self.cbor_response = Some(response);
+        Ok(())
+    }
+
+    async fn cbor_recv(&mut self, _timeout: Duration) -> Result<CborResponse, Error> {
+        self.cbor_response
+            .take()
+            .ok_or(Error::Transport(TransportError::InvalidFraming))
+    }
+
+    fn transport_name(&self) -> &'static str {
+        "replay"
+    }
+
+    fn supports_preflight(&self) -> bool {
+        self.recording.supports_preflight
+    }
+}
+
+impl Ctap2AuthTokenStore for ReplayChannel {
+    fn store_auth_data(&mut self, auth_token_data: AuthTokenData) {
+        self.auth_token_data = Some(auth_token_data);
+    }
+
+    fn get_auth_data(&self) -> Option<&AuthTokenData> {
+        self.auth_token_data.as_ref()
+    }
+
+    fn clear_uv_auth_token_store(&mut self) {
+        self.auth_token_data = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Recording, RecordingChannel, ReplayChannel};
+    use crate::ops::webauthn::MakeCredentialRequest;
+    use crate::proto::ctap2::Ctap2PublicKeyCredentialRpEntity;
+    use crate::transport::local::VirtualDevice;
+    use crate::transport::Device;
+    use crate::webauthn::WebAuthn;
+
+    #[tokio::test]
+    async fn replays_recorded_make_credential() {
+        let mut request = MakeCredentialRequest::dummy();
+        request.relying_party = Ctap2PublicKeyCredentialRpEntity::new("example.org", "Example");
+
+        let mut device = VirtualDevice::new_virtual();
+        let channel = device.channel().await.unwrap();
+        let mut channel = RecordingChannel::new(channel).await.unwrap();
+        let response = channel.webauthn_make_credential(&request).await.unwrap();
+        let recorded = response.authenticator_data.attested_credential.unwrap();
+
+        let mut json = vec![];
+        channel.recording().to_writer(&mut json).unwrap();
+        let recording = Recording::from_reader(json.as_slice()).unwrap();
+        assert_eq!(recording, channel.recording());
+
+        let mut replay = ReplayChannel::new(recording);
+        let response = replay.webauthn_make_credential(&request).await.unwrap();
+        let replayed = response.authenticator_data.attested_credential.unwrap();
+        assert!(replay.is_exhausted());
+        assert_eq!(replayed.credential_id, recorded.credential_id);
+    }
+}
@@ -0,0 +1,307 @@
+use std::fmt::{Display, Formatter};
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use serde_bytes::ByteBuf;
+use tokio::sync::{broadcast, Mutex};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, error, info, instrument, trace, Level};
+
+use crate::audit::AuditTrail;
+use crate::metrics::MetricsRecorder;
+use crate::pin::{PinProvider, PinUvAuthProtocols};
+use crate::proto::ctap1::apdu::{ApduRequest, ApduResponse};
+use crate::proto::ctap2::cbor::{CborRequest, CborResponse};
+use crate::timeout::TimeoutPolicy;
+use crate::transport::channel::{
+    ensure_not_cancelled, until_cancelled, AuthTokenData, Channel, ChannelStatus,
+    Ctap2AuthTokenStore,
+};
+use crate::transport::device::SupportedProtocols;
+use crate::transport::error::TransportError;
+use crate::webauthn::error::Error;
+use crate::UvUpdate;
+
+use super::device::{connect, RemoteStream};
+use super::protocol::{RemoteRequest, RemoteResponse, SecureStream};
+use super::RemoteDevice;
+
+/// The connection to the server. Reads are cancel safe, and responses to requests abandoned
+/// by a cancelled or timed out read are skipped when they arrive.
+struct Connection {
+    stream: SecureStream<Box<dyn RemoteStream>>,
+    /// Responses still to come, the last one answering the latest request.
+    pending: usize,
+}
+
+pub struct RemoteChannel<'d> {
+    status: ChannelStatus,
+    device: &'d RemoteDevice,
+    name: String,
+    connection: Mutex<Connection>,
+    protocols: SupportedProtocols,
+    auth_token_data: Option<AuthTokenData>,
+    pin_provider: Option<Arc<dyn PinProvider>>,
+    cancellation_token: Option<CancellationToken>,
+    timeout_policy: TimeoutPolicy,
+    audit_trail: Option<AuditTrail>,
+    metrics_recorder: Option<Arc<dyn MetricsRecorder>>,
+    pin_uv_auth_protocols: PinUvAuthProtocols,
+    ux_update_sender: broadcast::Sender<UvUpdate>,
+}
+
+impl<'d> RemoteChannel<'d> {
+    pub async fn new(device: &'d RemoteDevice) -> Result<RemoteChannel<'d>, Error> {
+        let (ux_update_sender, _) = broadcast::channel(16);
+        let mut stream = connect(&device.address, &device.psk).await?;
+        stream.write_message(&RemoteRequest::Hello).await?;
+        let (name, protocols) = match stream.read_message().await? {
+            RemoteResponse::Hello { name, fido2 } => {
+                (name, SupportedProtocols { u2f: false, fido2 })
+            }
+            RemoteResponse::Failed(failure) => return Err(Error::Transport(failure.into())),
+            _ => return Err(Error::Transport(TransportError::InvalidFraming)),
+        };
+        info!(%device, %name, "Connected to remote authenticator");
+        Ok(Self {
+            status: ChannelStatus::Ready,
+            device,
+            name,
+            connection: Mutex::new(Connection { stream, pending: 0 }),
+            protocols,
+            auth_token_data: None,
+            pin_provider: None,
+            cancellation_token: None,
+            timeout_policy: TimeoutPolicy::default(),
+            audit_trail: None,
+            metrics_recorder: None,
+            pin_uv_auth_protocols: PinUvAuthProtocols::default(),
+            ux_update_sender,
+        })
+    }
+
+    /// Name of the authenticator, as reported by the server.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+impl Display for RemoteChannel<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} ({})", self.name, self.device)
+    }
+}
+
+#[async_trait]
+impl Channel for RemoteChannel<'_> {
+    type UxUpdate = UvUpdate;
+
+    fn transport_name(&self) -> &'static str {
+        "remote"
+    }
+
+    async fn supported_protocols(&self) -> Result<SupportedProtocols, Error> {
+        Ok(self.protocols)
+    }
+
+    async fn status(&self) -> ChannelStatus {
+        self.status
+    }
+
+    async fn close(&mut self) {
+        self.status = ChannelStatus::Closed;
+    }
+
+    async fn apdu_send(&self, _request: &ApduRequest, _timeout: Duration) -> Result<(), Error> {
+        error!("APDU send not supported by remote authenticators");
+        Err(Error::Transport(TransportError::TransportUnavailable))
+    }
+
+    async fn apdu_recv(&self, _timeout: Duration) -> Result<ApduResponse, Error> {
+        error!("APDU recv not supported by remote authenticators");
+        Err(Error::Transport(TransportError::TransportUnavailable))
+    }
+
+    #[instrument(level = Level::DEBUG, skip_all)]
+    async fn cbor_send(&mut self, request: &CborRequest, timeout: Duration) -> Result<(), Error> {
+        ensure_not_cancelled(self.cancellation_token.as_ref(), &self.ux_update_sender)?;
+        debug!(command = ?request.command, "Sending CBOR request to remote authenticator");
+        trace!(?request);
+        let request = RemoteRequest::Cbor {
+            command: request.command.into(),
+            data: ByteBuf::from(request.encoded_data.clone()),
+            timeout_ms: timeout.as_millis() as u64,
+        };
+        let connection = &mut *self.connection.lock().await;
+        connection.stream.write_message(&request).await?;
+        connection.pending += 1;
+        Ok(())
+    }
+
+    #[instrument(level = Level::DEBUG, skip_all)]
+    async fn cbor_recv(&mut self, timeout: Duration) -> Result<CborResponse, Error> {
+        let token = self.cancellation_token.clone();
+        let message = until_cancelled(token, &self.ux_update_sender, async {
+            let connection = &mut *self.connection.lock().await;
+            let read = async {
+                loop {
+                    let message = connection.stream.read_message().await?;
+                    connection.pending = connection.pending.saturating_sub(1);
+                    if connection.pending == 0 {
+                        return Ok(message);
+                    }
+                    debug!("Skipping response to an abandoned request");
+                }
+            };
+            match tokio::time::timeout(timeout, read).await {
+                Ok(message) => message,
+                Err(_) => Err(Error::Transport(TransportError::Timeout)),
+            }
+        })
+        .await?;
+        let (status, data) = match message {
+            RemoteResponse::Cbor { status, data } => (status, data),
+            RemoteResponse::Failed(failure) => return Err(Error::Transport(failure.into())),
+            _ => return Err(Error::Transport(TransportError::InvalidFraming)),
+        };
+        let Ok(status_code) = status.try_into() else {
+            error!(?status, "Invalid CTAP error code");
+            return Err(Error::Transport(TransportError::InvalidFraming));
+        };
+        let cbor_response = CborResponse {
+            status_code,
+            data: (!data.is_empty()).then(|| data.into_vec()),
+        };
+        debug!(
+            { status = ?cbor_response.status_code },
+            "Received CBOR response from remote authenticator"
+        );
+        trace!(?cbor_response);
+        Ok(cbor_response)
+    }
+
+    fn get_ux_update_sender(&self) -> &broadcast::Sender<UvUpdate> {
+        &self.ux_update_sender
+    }
+
+    fn get_pin_provider(&self) -> Option<Arc<dyn PinProvider>> {
+        self.pin_provider.clone()
+    }
+
+    fn set_pin_provider(&mut self, provider: Option<Arc<dyn PinProvider>>) {
+        self.pin_provider = provider;
+    }
+
+    fn get_cancellation_token(&self) -> Option<CancellationToken> {
+        self.cancellation_token.clone()
+    }
+
+    fn set_cancellation_token(&mut self, token: Option<CancellationToken>) {
+        self.cancellation_token = token;
+    }
+
+    fn get_timeout_policy(&self) -> TimeoutPolicy {
+        self.timeout_policy
+    }
+
+    fn set_timeout_policy(&mut self, policy: TimeoutPolicy) {
+        self.timeout_policy = policy;
+    }
+
+    fn get_audit_trail(&self) -> Option<AuditTrail> {
+        self.audit_trail.clone()
+    }
+
+    fn set_audit_trail(&mut self, trail: Option<AuditTrail>) {
+        self.audit_trail = trail;
+    }
+
+    fn get_metrics_recorder(&self) -> Option<Arc<dyn MetricsRecorder>> {
+        self.metrics_recorder.clone()
+    }
+
+    fn set_metrics_recorder(&mut self, recorder: Option<Arc<dyn MetricsRecorder>>) {
+        self.metrics_recorder = recorder;
+    }
+
+    fn get_pin_uv_auth_protocols(&self) -> PinUvAuthProtocols {
+        self.pin_uv_auth_protocols.clone()
+    }
+
+    fn set_pin_uv_auth_protocols(&mut self, protocols: PinUvAuthProtocols) {
+        self.pin_uv_auth_protocols = protocols;
+    }
+}
+
+impl Ctap2AuthTokenStore for RemoteChannel<'_> {
+    fn store_auth_data(&mut self, auth_token_data: AuthTokenData) {
+        self.auth_token_data = Some(auth_token_data);
+    }
+
+    fn get_auth_data(&self) -> Option<&AuthTokenData> {
+        self.auth_token_data.as_ref()
+    }
+
+    fn clear_uv_auth_token_store(&mut self) {
+        self.auth_token_data = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_bytes::ByteBuf;
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::proto::ctap2::Ctap2CommandCode;
+    use crate::transport::remote::RemotePsk;
+    use crate::transport::Device;
+
+    const TIMEOUT: Duration = Duration::from_secs(10);
+
+    #[tokio::test]
+    async fn response_to_abandoned_request_is_skipped() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let psk = RemotePsk::generate();
+        let address = listener.local_addr().unwrap().to_string();
+        let mut remote = RemoteDevice::tcp(&address, psk.clone());
+
+        let server = async {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut stream = SecureStream::accept(stream, &psk).await.unwrap();
+            let _: RemoteRequest = stream.read_message().await.unwrap();
+            let hello = RemoteResponse::Hello {
+                name: String::from("slow"),
+                fido2: true,
+            };
+            stream.write_message(&hello).await.unwrap();
+            for answer in 1..=2 {
+                let _: RemoteRequest = stream.read_message().await.unwrap();
+                if answer == 1 {
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                }
+                let response = RemoteResponse::Cbor {
+                    status: 0,
+                    data: ByteBuf::from(vec![answer]),
+                };
+                stream.write_message(&response).await.unwrap();
+            }
+        };
+        let client = async {
+            let mut channel = remote.channel().await.unwrap();
+            let request = CborRequest::new(Ctap2CommandCode::AuthenticatorGetInfo);
+            channel.cbor_send(&request, TIMEOUT).await.unwrap();
+            let result = channel.cbor_recv(Duration::from_millis(10)).await;
+            assert_eq!(
+                result.unwrap_err(),
+                Error::Transport(TransportError::Timeout)
+            );
+
+            channel.cbor_send(&request, TIMEOUT).await.unwrap();
+            let response = channel.cbor_recv(TIMEOUT).await.unwrap();
+            assert_eq!(response.data, Some(vec![2]));
+        };
+        tokio::join!(server, client);
+    }
+}
@@ -0,0 +1,97 @@
+use std::fmt;
+use std::path::PathBuf;
+
+use async_trait::async_trait;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpStream, UnixStream};
+use tracing::{debug, instrument, warn};
+
+use crate::transport::device::Device;
+use crate::transport::error::TransportError;
+use crate::webauthn::error::Error;
+
+use super::channel::RemoteChannel;
+use super::protocol::{RemotePsk, SecureStream};
+use super::Remote;
+
+/// Where a remote authenticator is being served.
+#[derive(Debug, Clone, PartialEq)]
+pub enum RemoteAddress {
+    /// A `host:port` TCP address.
+    Tcp(String),
+    Unix(PathBuf),
+}
+
+impl fmt::Display for RemoteAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RemoteAddress::Tcp(address) => write!(f, "tcp:{}", address),
+            RemoteAddress::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+pub(crate) trait RemoteStream: AsyncRead + AsyncWrite + Unpin + Send + Sync {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send + Sync> RemoteStream for T {}
+
+#[instrument(skip_all, fields(%address))]
+pub(crate) async fn connect(
+    address: &RemoteAddress,
+    psk: &RemotePsk,
+) -> Result<SecureStream<Box<dyn RemoteStream>>, Error> {
+    debug!("Connecting to remote authenticator");
+    let stream: Box<dyn RemoteStream> = match address {
+        RemoteAddress::Tcp(address) => {
+            let stream = TcpStream::connect(address)
+                .await
+                .or(Err(Error::Transport(TransportError::ConnectionFailed)))?;
+            // Requests and responses are small, and latency matters more than throughput.
+            if let Err(err) = stream.set_nodelay(true) {
+                warn!(?err, "Failed to disable Nagle's algorithm");
+            }
+            Box::new(stream)
+        }
+        RemoteAddress::Unix(path) => Box::new(
+            UnixStream::connect(path)
+                .await
+                .or(Err(Error::Transport(TransportError::ConnectionFailed)))?,
+        ),
+    };
+    SecureStream::connect(stream, psk).await
+}
+
+/// An authenticator served by [`super::serve_channel`] at `address`, with the key `psk`.
+#[derive(Debug, Clone)]
+pub struct RemoteDevice {
+    pub address: RemoteAddress,
+    pub psk: RemotePsk,
+}
+
+impl RemoteDevice {
+    pub fn tcp(address: &str, psk: RemotePsk) -> Self {
+        Self {
+            address: RemoteAddress::Tcp(address.to_owned()),
+            psk,
+        }
+    }
+
+    pub fn unix(path: impl Into<PathBuf>, psk: RemotePsk) -> Self {
+        Self {
+            address: RemoteAddress::Unix(path.into()),
+            psk,
+        }
+    }
+}
+
+impl fmt::Display for RemoteDevice {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Remote authenticator at {}", self.address)
+    }
+}
+
+#[async_trait]
+impl<'d> Device<'d, Remote, RemoteChannel<'d>> for RemoteDevice {
+    async fn channel(&'d mut self) -> Result<RemoteChannel<'d>, Error> {
+        RemoteChannel::new(self).await
+    }
+}
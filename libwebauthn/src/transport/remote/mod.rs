@@ -0,0 +1,32 @@
+//! Proxying CTAP2 over a socket.
+//!
+//! A privileged helper, or a VM host, owns the authenticator and forwards the CBOR
+//! requests it receives on a TCP or Unix socket to it using [`serve_channel`].
+//! Sandboxed clients then talk to it through [`RemoteDevice`] and [`RemoteChannel`],
+//! which behave like any other transport. Both ends authenticate each other with a
+//! [`RemotePsk`], and the connection is encrypted.
+
+use std::fmt::Display;
+
+pub mod channel;
+pub mod device;
+pub mod protocol;
+pub mod server;
+
+pub use channel::RemoteChannel;
+pub use device::{RemoteAddress, RemoteDevice};
+pub use protocol::RemotePsk;
+pub use server::serve_channel;
+
+use super::Transport;
+
+pub struct Remote {}
+impl Transport for Remote {}
+unsafe impl Send for Remote {}
+unsafe impl Sync for Remote {}
+
+impl Display for Remote {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Remote")
+    }
+}
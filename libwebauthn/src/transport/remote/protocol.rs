@@ -0,0 +1,291 @@
+//! Wire protocol spoken between a remote channel and the server it connects to.
+//!
+//! Messages are framed as in [crate::transport::socket]. A connection starts with a
+//! `Noise_NNpsk0_25519_ChaChaPoly_SHA256` handshake, so that only peers knowing the
+//! [RemotePsk] can connect, and everything after it is encrypted and bound to the handshake.
+//! The client then sends `Hello`, then any number of `Cbor` requests, each answered by
+//! exactly one response.
+
+use std::fmt;
+
+use rand::rngs::OsRng;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+use snow::{Builder, HandshakeState, TransportState};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tracing::{debug, warn};
+use zeroize::{Zeroize, ZeroizeOnDrop};
+
+use crate::redact::REDACTED;
+use crate::sources;
+use crate::transport::error::TransportError;
+use crate::transport::socket::{decode_message, write_frame, FrameReader};
+use crate::webauthn::error::Error;
+
+pub use crate::transport::socket::MAX_MESSAGE_SIZE;
+
+const NOISE_PARAMS: &str = "Noise_NNpsk0_25519_ChaChaPoly_SHA256";
+const PROLOGUE: &[u8] = b"libwebauthn remote v1";
+const MAX_NOISE_MESSAGE: usize = 65535;
+const NOISE_TAG_SIZE: usize = 16;
+
+/// The key both ends of a remote connection must know. Distribute it out of band, e.g. in
+/// a file only the serving helper and its clients can read.
+#[derive(Clone, PartialEq, Eq, Zeroize, ZeroizeOnDrop)]
+pub struct RemotePsk([u8; 32]);
+
+impl RemotePsk {
+    pub fn new(key: [u8; 32]) -> Self {
+        Self(key)
+    }
+
+    /// A new random key, from the operating system's RNG.
+    pub fn generate() -> Self {
+        let mut key = [0; 32];
+        OsRng.fill_bytes(&mut key);
+        Self(key)
+    }
+
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.0
+    }
+}
+
+impl fmt::Debug for RemotePsk {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("RemotePsk").field(&REDACTED).finish()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum RemoteRequest {
+    Hello,
+    /// Sends a CBOR request to the served authenticator, and waits for its response.
+    Cbor {
+        command: u8,
+        data: ByteBuf,
+        timeout_ms: u64,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum RemoteResponse {
+    Hello { name: String, fido2: bool },
+    Cbor { status: u8, data: ByteBuf },
+    Failed(RemoteFailure),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum RemoteFailure {
+    UnsupportedCommand,
+    Timeout,
+    ConnectionLost,
+    TransportUnavailable,
+    DeviceRemoved,
+}
+
+impl From<RemoteFailure> for TransportError {
+    fn from(failure: RemoteFailure) -> Self {
+        match failure {
+            RemoteFailure::UnsupportedCommand => TransportError::InvalidFraming,
+            RemoteFailure::Timeout => TransportError::Timeout,
+            RemoteFailure::ConnectionLost => TransportError::ConnectionLost,
+            RemoteFailure::TransportUnavailable => TransportError::TransportUnavailable,
+            RemoteFailure::DeviceRemoved => TransportError::DeviceRemoved,
+        }
+    }
+}
+
+impl From<&Error> for RemoteFailure {
+    fn from(error: &Error) -> Self {
+        match error {
+            Error::Transport(TransportError::Timeout) => RemoteFailure::Timeout,
+            Error::Transport(TransportError::TransportUnavailable) => {
+                RemoteFailure::TransportUnavailable
+            }
+            Error::Transport(TransportError::DeviceRemoved) => RemoteFailure::DeviceRemoved,
+            _ => RemoteFailure::ConnectionLost,
+        }
+    }
+}
+
+/// A stream after the Noise handshake. Messages larger than a Noise message are split into
+/// several, which are sent as a single frame.
+pub struct SecureStream<S> {
+    stream: S,
+    reader: FrameReader,
+    noise: TransportState,
+}
+
+impl<S> SecureStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    /// Performs the handshake as the client.
+    pub async fn connect(mut stream: S, psk: &RemotePsk) -> Result<Self, Error> {
+        let mut reader = FrameReader::new();
+        let mut handshake = handshake_state(psk, true)?;
+        let mut message = vec![0; MAX_NOISE_MESSAGE];
+        let len = handshake
+            .write_message(&[], &mut message)
+            .map_err(noise_error)?;
+        write_frame(&mut stream, &message[..len]).await?;
+        let response = reader.read_frame(&mut stream).await?;
+        handshake
+            .read_message(&response, &mut message)
+            .map_err(|err| {
+                warn!(
+                    ?err,
+                    "Remote handshake failed, the server may use another key"
+                );
+                Error::Transport(TransportError::ConnectionFailed)
+            })?;
+        Self::established(stream, reader, handshake)
+    }
+
+    /// Performs the handshake as the server.
+    pub async fn accept(mut stream: S, psk: &RemotePsk) -> Result<Self, Error> {
+        let mut reader = FrameReader::new();
+        let mut handshake = handshake_state(psk, false)?;
+        let mut message = vec![0; MAX_NOISE_MESSAGE];
+        let initial = reader.read_frame(&mut stream).await?;
+        handshake
+            .read_message(&initial, &mut message)
+            .map_err(noise_error)?;
+        let len = handshake
+            .write_message(&[], &mut message)
+            .map_err(noise_error)?;
+        write_frame(&mut stream, &message[..len]).await?;
+        Self::established(stream, reader, handshake)
+    }
+
+    fn established(
+        stream: S,
+        reader: FrameReader,
+        handshake: HandshakeState,
+    ) -> Result<Self, Error> {
+        let noise = handshake.into_transport_mode().map_err(noise_error)?;
+        debug!("Remote handshake complete");
+        Ok(Self {
+            stream,
+            reader,
+            noise,
+        })
+    }
+
+    pub async fn write_message<T: Serialize>(&mut self, message: &T) -> Result<(), Error> {
+        let plaintext = crate::proto::ctap2::cbor::to_vec(message)?;
+        let max_plaintext = MAX_NOISE_MESSAGE - NOISE_TAG_SIZE;
+        let mut frame = Vec::with_capacity(plaintext.len() + NOISE_TAG_SIZE);
+        let mut ciphertext = vec![0; MAX_NOISE_MESSAGE];
+        for chunk in plaintext.chunks(max_plaintext) {
+            let len = self
+                .noise
+                .write_message(chunk, &mut ciphertext)
+                .map_err(noise_error)?;
+            frame.extend_from_slice(&ciphertext[..len]);
+        }
+        write_frame(&mut self.stream, &frame).await
+    }
+
+    /// Reads the next message. Cancel safe, as [FrameReader::read_frame].
+    pub async fn read_message<T>(&mut self) -> Result<T, Error>
+    where
+        T: for<'de> Deserialize<'de>,
+    {
+        let frame = self.reader.read_frame(&mut self.stream).await?;
+        let mut plaintext = Vec::with_capacity(frame.len());
+        let mut chunk = vec![0; MAX_NOISE_MESSAGE];
+        for ciphertext in frame.chunks(MAX_NOISE_MESSAGE) {
+            let len = self
+                .noise
+                .read_message(ciphertext, &mut chunk)
+                .map_err(|err| {
+                    warn!(?err, "Failed to decrypt remote message");
+                    Error::Transport(TransportError::InvalidFraming)
+                })?;
+            plaintext.extend_from_slice(&chunk[..len]);
+        }
+        decode_message(&plaintext)
+    }
+}
+
+fn handshake_state(psk: &RemotePsk, initiator: bool) -> Result<HandshakeState, Error> {
+    let params = NOISE_PARAMS.parse().map_err(noise_error)?;
+    let builder = Builder::with_resolver(params, sources::noise_resolver())
+        .prologue(PROLOGUE)
+        .and_then(|builder| builder.psk(0, psk.as_bytes()))
+        .map_err(noise_error)?;
+    if initiator {
+        builder.build_initiator()
+    } else {
+        builder.build_responder()
+    }
+    .map_err(noise_error)
+}
+
+fn noise_error(err: snow::Error) -> Error {
+    warn!(?err, "Remote handshake failed");
+    Error::Transport(TransportError::ConnectionFailed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    type Connected = Result<SecureStream<tokio::io::DuplexStream>, Error>;
+
+    async fn connect(client_psk: &RemotePsk, server_psk: &RemotePsk) -> (Connected, Connected) {
+        let (client, server) = tokio::io::duplex(1024);
+        tokio::join!(
+            SecureStream::connect(client, client_psk),
+            SecureStream::accept(server, server_psk)
+        )
+    }
+
+    #[tokio::test]
+    async fn message_roundtrip() {
+        let psk = RemotePsk::generate();
+        let (client, server) = connect(&psk, &psk).await;
+        let (mut client, mut server) = (client.unwrap(), server.unwrap());
+        let request = RemoteRequest::Cbor {
+            command: 0x04,
+            data: ByteBuf::from(vec![0xab; 100_000]),
+            timeout_ms: 1000,
+        };
+        let (sent, received) = tokio::join!(
+            client.write_message(&request),
+            server.read_message::<RemoteRequest>()
+        );
+        sent.unwrap();
+        assert_eq!(received.unwrap(), request);
+
+        let response = RemoteResponse::Failed(RemoteFailure::UnsupportedCommand);
+        server.write_message(&response).await.unwrap();
+        let received: RemoteResponse = client.read_message().await.unwrap();
+        assert_eq!(received, response);
+    }
+
+    #[tokio::test]
+    async fn wrong_key_is_rejected() {
+        let (client, server) = connect(&RemotePsk::generate(), &RemotePsk::generate()).await;
+        // The server drops the connection without answering.
+        assert_eq!(
+            server.err(),
+            Some(Error::Transport(TransportError::ConnectionFailed))
+        );
+        assert_eq!(
+            client.err(),
+            Some(Error::Transport(TransportError::ConnectionLost))
+        );
+    }
+
+    #[test]
+    fn psk_is_redacted() {
+        assert_eq!(
+            format!("{:?}", RemotePsk::new([0xab; 32])),
+            "RemotePsk(\"<redacted>\")"
+        );
+    }
+}
@@ -0,0 +1,133 @@
+use std::time::Duration;
+
+use serde_bytes::ByteBuf;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tracing::{debug, info, instrument, warn};
+
+use crate::proto::ctap2::cbor::CborRequest;
+use crate::proto::ctap2::Ctap2CommandCode;
+use crate::transport::channel::Channel;
+use crate::transport::error::TransportError;
+use crate::webauthn::error::Error;
+
+use super::protocol::{RemoteFailure, RemotePsk, RemoteRequest, RemoteResponse, SecureStream};
+
+/// Serves a single client connected through `stream`, forwarding its CBOR requests to
+/// `channel`, until the client disconnects. Clients which don't know `psk` are refused.
+///
+/// Accepting connections is left to the caller, e.g. from a `TcpListener` or `UnixListener`.
+#[instrument(skip_all, fields(%channel))]
+pub async fn serve_channel<C, S>(channel: &mut C, stream: S, psk: &RemotePsk) -> Result<(), Error>
+where
+    C: Channel,
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut stream = SecureStream::accept(stream, psk).await?;
+    loop {
+        let request: RemoteRequest = match stream.read_message().await {
+            Ok(request) => request,
+            Err(Error::Transport(TransportError::ConnectionLost)) => {
+                info!("Remote client disconnected");
+                return Ok(());
+            }
+            Err(err) => return Err(err),
+        };
+        let response = match request {
+            RemoteRequest::Hello => match channel.supported_protocols().await {
+                Ok(protocols) => RemoteResponse::Hello {
+                    name: channel.to_string(),
+                    fido2: protocols.fido2,
+                },
+                Err(err) => RemoteResponse::Failed((&err).into()),
+            },
+            RemoteRequest::Cbor {
+                command,
+                data,
+                timeout_ms,
+            } => forward(channel, command, data, Duration::from_millis(timeout_ms)).await,
+        };
+        stream.write_message(&response).await?;
+    }
+}
+
+async fn forward<C: Channel>(
+    channel: &mut C,
+    command: u8,
+    data: ByteBuf,
+    timeout: Duration,
+) -> RemoteResponse {
+    let Ok(command) = Ctap2CommandCode::try_from(command) else {
+        warn!(?command, "Refusing to forward unknown CTAP2 command");
+        return RemoteResponse::Failed(RemoteFailure::UnsupportedCommand);
+    };
+    debug!(?command, "Forwarding CBOR request");
+    let request = CborRequest {
+        command,
+        encoded_data: data.into_vec(),
+    };
+    let result = async {
+        channel.cbor_send(&request, timeout).await?;
+        channel.cbor_recv(timeout).await
+    }
+    .await;
+    match result {
+        Ok(response) => RemoteResponse::Cbor {
+            status: response.status_code.into(),
+            data: ByteBuf::from(response.data.unwrap_or_default()),
+        },
+        Err(err) => RemoteResponse::Failed((&err).into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::net::TcpListener;
+
+    use super::serve_channel;
+    use crate::ops::webauthn::{
+        GetAssertionRequest, MakeCredentialRequest, UserVerificationRequirement,
+    };
+    use crate::proto::ctap2::Ctap2PublicKeyCredentialRpEntity;
+    use crate::transport::local::VirtualDevice;
+    use crate::transport::remote::{RemoteDevice, RemotePsk};
+    use crate::transport::{Channel, Device};
+    use crate::webauthn::WebAuthn;
+
+    #[tokio::test]
+    async fn register_and_authenticate_remotely() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let psk = RemotePsk::generate();
+        let address = listener.local_addr().unwrap().to_string();
+        let mut remote = RemoteDevice::tcp(&address, psk.clone());
+        let mut device = VirtualDevice::new_virtual();
+        let mut served = device.channel().await.unwrap();
+
+        let server = async {
+            let (stream, _) = listener.accept().await.unwrap();
+            serve_channel(&mut served, stream, &psk).await.unwrap();
+        };
+        let client = async {
+            let mut channel = remote.channel().await.unwrap();
+            assert!(channel.supported_protocols().await.unwrap().fido2);
+
+            let mut request = MakeCredentialRequest::dummy();
+            request.relying_party = Ctap2PublicKeyCredentialRpEntity::new("example.org", "Example");
+            let response = channel.webauthn_make_credential(&request).await.unwrap();
+            let credential = response.authenticator_data.attested_credential.unwrap();
+
+            let request = GetAssertionRequest::builder()
+                .rp_id("example.org")
+                .client_data_hash(&[0; 32])
+                .allow_credential((&credential).into())
+                .user_verification(UserVerificationRequirement::Discouraged)
+                .timeout(Duration::from_secs(10))
+                .build()
+                .unwrap();
+            let response = channel.webauthn_get_assertion(&request).await.unwrap();
+            assert_eq!(response.assertions.len(), 1);
+        };
+        tokio::join!(server, client);
+    }
+}
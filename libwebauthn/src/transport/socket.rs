@@ -0,0 +1,173 @@
+//! Length-prefixed messages over a byte stream, shared by the daemon and remote transports.
+//!
+//! Every frame is prefixed with its length as a big-endian u32, and messages are encoded as
+//! CBOR. A [FrameReader] keeps partially received frames across reads, so that a read which
+//! is cancelled or times out midway doesn't leave the stream in the middle of a frame.
+
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tracing::{trace, warn};
+
+use crate::proto::ctap2::cbor;
+use crate::transport::error::TransportError;
+use crate::webauthn::error::Error;
+
+/// Upper bound for a single frame, well above any authenticator's maxMsgSize.
+pub const MAX_MESSAGE_SIZE: u32 = 1 << 20;
+
+const LENGTH_SIZE: usize = 4;
+
+fn io_error(error: std::io::Error) -> Error {
+    match error.kind() {
+        std::io::ErrorKind::UnexpectedEof => Error::Transport(TransportError::ConnectionLost),
+        kind => Error::Transport(TransportError::IoError(kind)),
+    }
+}
+
+pub async fn write_frame<W>(writer: &mut W, frame: &[u8]) -> Result<(), Error>
+where
+    W: AsyncWrite + Unpin,
+{
+    let len = match u32::try_from(frame.len()) {
+        Ok(len) if len <= MAX_MESSAGE_SIZE => len,
+        _ => {
+            warn!(len = frame.len(), "Frame exceeds maximum size");
+            return Err(Error::Transport(TransportError::InvalidFraming));
+        }
+    };
+    trace!({ len }, "Writing frame");
+    let mut buffer = Vec::with_capacity(LENGTH_SIZE + frame.len());
+    buffer.extend_from_slice(&len.to_be_bytes());
+    buffer.extend_from_slice(frame);
+    writer.write_all(&buffer).await.map_err(io_error)?;
+    writer.flush().await.map_err(io_error)
+}
+
+pub async fn write_message<W, T>(writer: &mut W, message: &T) -> Result<(), Error>
+where
+    W: AsyncWrite + Unpin,
+    T: Serialize,
+{
+    write_frame(writer, &cbor::to_vec(message)?).await
+}
+
+pub(crate) fn decode_message<T>(frame: &[u8]) -> Result<T, Error>
+where
+    T: for<'de> Deserialize<'de>,
+{
+    cbor::from_slice(frame).or(Err(Error::Transport(TransportError::InvalidFraming)))
+}
+
+/// Reads frames from a stream, keeping partially received ones across cancelled reads.
+#[derive(Debug, Default)]
+pub struct FrameReader {
+    buffer: Vec<u8>,
+}
+
+impl FrameReader {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Reads the next frame. Cancel safe: dropping the future before it completes loses no
+    /// data, and the next call picks up where this one stopped.
+    pub async fn read_frame<R>(&mut self, reader: &mut R) -> Result<Vec<u8>, Error>
+    where
+        R: AsyncRead + Unpin,
+    {
+        loop {
+            if let Some(frame) = self.take_frame()? {
+                return Ok(frame);
+            }
+            let read = reader.read_buf(&mut self.buffer).await.map_err(io_error)?;
+            if read == 0 {
+                return Err(Error::Transport(TransportError::ConnectionLost));
+            }
+        }
+    }
+
+    pub async fn read_message<R, T>(&mut self, reader: &mut R) -> Result<T, Error>
+    where
+        R: AsyncRead + Unpin,
+        T: for<'de> Deserialize<'de>,
+    {
+        decode_message(&self.read_frame(reader).await?)
+    }
+
+    fn take_frame(&mut self) -> Result<Option<Vec<u8>>, Error> {
+        let Some(header) = self.buffer.first_chunk::<LENGTH_SIZE>() else {
+            return Ok(None);
+        };
+        let len = u32::from_be_bytes(*header);
+        if len > MAX_MESSAGE_SIZE {
+            warn!({ len }, "Frame exceeds maximum size");
+            return Err(Error::Transport(TransportError::InvalidFraming));
+        }
+        let end = LENGTH_SIZE + len as usize;
+        if self.buffer.len() < end {
+            return Ok(None);
+        }
+        trace!({ len }, "Read frame");
+        let frame = self.buffer[LENGTH_SIZE..end].to_vec();
+        self.buffer.drain(..end);
+        Ok(Some(frame))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::io::AsyncWriteExt;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn message_roundtrip() {
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        let message = vec![String::from("hello"); 16];
+        write_message(&mut client, &message).await.unwrap();
+        let received: Vec<String> = FrameReader::new().read_message(&mut server).await.unwrap();
+        assert_eq!(received, message);
+    }
+
+    #[tokio::test]
+    async fn oversized_frame_is_rejected() {
+        let (mut client, mut server) = tokio::io::duplex(64);
+        client.write_u32(MAX_MESSAGE_SIZE + 1).await.unwrap();
+        assert_eq!(
+            FrameReader::new()
+                .read_frame(&mut server)
+                .await
+                .unwrap_err(),
+            Error::Transport(TransportError::InvalidFraming)
+        );
+    }
+
+    #[tokio::test]
+    async fn closed_connection_is_reported() {
+        let (client, mut server) = tokio::io::duplex(64);
+        drop(client);
+        assert_eq!(
+            FrameReader::new()
+                .read_frame(&mut server)
+                .await
+                .unwrap_err(),
+            Error::Transport(TransportError::ConnectionLost)
+        );
+    }
+
+    #[tokio::test]
+    async fn cancelled_read_keeps_partial_frame() {
+        let (mut client, mut server) = tokio::io::duplex(64);
+        let mut reader = FrameReader::new();
+        client.write_all(&[0, 0, 0, 3, 1]).await.unwrap();
+        let cancelled =
+            tokio::time::timeout(Duration::from_millis(10), reader.read_frame(&mut server)).await;
+        assert!(cancelled.is_err());
+
+        client.write_all(&[2, 3, 0, 0, 0, 0]).await.unwrap();
+        assert_eq!(reader.read_frame(&mut server).await.unwrap(), [1, 2, 3]);
+        assert!(reader.read_frame(&mut server).await.unwrap().is_empty());
+    }
+}
@@ -0,0 +1,460 @@
+//! The few TPM 2.0 commands needed to seal and unseal data, marshalled as specified in
+//! TPM 2.0 Part 3, and sent to the kernel's TPM device.
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, Cursor, Read, Write};
+use std::path::Path;
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use tracing::{trace, warn};
+use zeroize::Zeroizing;
+
+const TPM_ST_NO_SESSIONS: u16 = 0x8001;
+const TPM_ST_SESSIONS: u16 = 0x8002;
+
+const TPM_CC_CREATE_PRIMARY: u32 = 0x0000_0131;
+const TPM_CC_CREATE: u32 = 0x0000_0153;
+const TPM_CC_LOAD: u32 = 0x0000_0157;
+const TPM_CC_UNSEAL: u32 = 0x0000_015E;
+const TPM_CC_FLUSH_CONTEXT: u32 = 0x0000_0165;
+
+const TPM_RH_OWNER: u32 = 0x4000_0001;
+/// Password authorization, with an empty password.
+const TPM_RS_PW: u32 = 0x4000_0009;
+
+const TPM_ALG_AES: u16 = 0x0006;
+const TPM_ALG_KEYEDHASH: u16 = 0x0008;
+const TPM_ALG_SHA256: u16 = 0x000B;
+const TPM_ALG_NULL: u16 = 0x0010;
+const TPM_ALG_ECC: u16 = 0x0023;
+const TPM_ALG_CFB: u16 = 0x0043;
+const TPM_ECC_NIST_P256: u16 = 0x0003;
+
+const FIXED_TPM: u32 = 1 << 1;
+const FIXED_PARENT: u32 = 1 << 4;
+const SENSITIVE_DATA_ORIGIN: u32 = 1 << 5;
+const USER_WITH_AUTH: u32 = 1 << 6;
+const NO_DA: u32 = 1 << 10;
+const RESTRICTED: u32 = 1 << 16;
+const DECRYPT: u32 = 1 << 17;
+
+const HEADER_LEN: usize = 10;
+const MAX_RESPONSE_LEN: usize = 4096;
+
+pub type TpmHandle = u32;
+
+/// Exchanges marshalled commands and responses with a TPM.
+pub trait TpmTransport: Send {
+    fn transmit(&mut self, command: &[u8]) -> io::Result<Zeroizing<Vec<u8>>>;
+}
+
+/// A TPM character device, e.g. the kernel's resource manager at `/dev/tpmrm0`.
+#[derive(Debug)]
+pub struct TpmCharDevice {
+    file: File,
+}
+
+impl TpmCharDevice {
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+        Ok(Self { file })
+    }
+}
+
+impl TpmTransport for TpmCharDevice {
+    fn transmit(&mut self, command: &[u8]) -> io::Result<Zeroizing<Vec<u8>>> {
+        // The device takes one whole command per write, and returns the whole response in
+        // the following read.
+        self.file.write_all(command)?;
+        let mut response = Zeroizing::new(vec![0; MAX_RESPONSE_LEN]);
+        let len = self.file.read(&mut response)?;
+        response.truncate(len);
+        Ok(response)
+    }
+}
+
+/// Why a TPM command failed.
+#[derive(thiserror::Error, Debug)]
+pub enum TpmError {
+    #[error("TPM I/O error: {0}")]
+    Io(#[from] io::Error),
+    #[error("TPM response code {0:#x}")]
+    ResponseCode(u32),
+    #[error("malformed TPM response")]
+    MalformedResponse,
+}
+
+/// A sealed object, as returned by `TPM2_Create`, and loaded again with `TPM2_Load`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SealedObject {
+    /// Contents of the TPM2B_PUBLIC
+    pub public: Vec<u8>,
+    /// Contents of the TPM2B_PRIVATE, encrypted with the parent key
+    pub private: Vec<u8>,
+}
+
+/// Creates the storage primary key of the owner hierarchy. It is derived from the
+/// hierarchy's seed, so it is the same key every time, and doesn't need to be persisted.
+pub fn create_primary(tpm: &mut dyn TpmTransport) -> Result<TpmHandle, TpmError> {
+    let mut params = vec![];
+    // inSensitive: no userAuth, nor data
+    write_tpm2b(&mut params, &sensitive_create(&[]));
+    write_tpm2b(&mut params, &storage_primary_public());
+    // outsideInfo, creationPCR
+    write_tpm2b(&mut params, &[]);
+    params.write_u32::<BigEndian>(0).unwrap();
+    let response = execute(tpm, TPM_CC_CREATE_PRIMARY, Some(TPM_RH_OWNER), &params)?;
+    let mut cursor = Cursor::new(response.as_slice());
+    read_u32(&mut cursor)
+}
+
+/// Seals `secret` under `parent`, so that it can only be unsealed by this TPM.
+pub fn create_sealed(
+    tpm: &mut dyn TpmTransport,
+    parent: TpmHandle,
+    secret: &[u8],
+) -> Result<SealedObject, TpmError> {
+    let mut params = Zeroizing::new(vec![]);
+    write_tpm2b(&mut params, &sensitive_create(secret));
+    write_tpm2b(&mut params, &sealed_data_public());
+    write_tpm2b(&mut params, &[]);
+    params.write_u32::<BigEndian>(0).unwrap();
+    let response = execute(tpm, TPM_CC_CREATE, Some(parent), &params)?;
+    let mut cursor = Cursor::new(response.as_slice());
+    let _parameter_size = read_u32(&mut cursor)?;
+    let private = read_tpm2b(&mut cursor)?;
+    let public = read_tpm2b(&mut cursor)?;
+    Ok(SealedObject { public, private })
+}
+
+pub fn load(
+    tpm: &mut dyn TpmTransport,
+    parent: TpmHandle,
+    object: &SealedObject,
+) -> Result<TpmHandle, TpmError> {
+    let mut params = vec![];
+    write_tpm2b(&mut params, &object.private);
+    write_tpm2b(&mut params, &object.public);
+    let response = execute(tpm, TPM_CC_LOAD, Some(parent), &params)?;
+    let mut cursor = Cursor::new(response.as_slice());
+    read_u32(&mut cursor)
+}
+
+pub fn unseal(tpm: &mut dyn TpmTransport, item: TpmHandle) -> Result<Zeroizing<Vec<u8>>, TpmError> {
+    let response = execute(tpm, TPM_CC_UNSEAL, Some(item), &[])?;
+    let mut cursor = Cursor::new(response.as_slice());
+    let _parameter_size = read_u32(&mut cursor)?;
+    Ok(Zeroizing::new(read_tpm2b(&mut cursor)?))
+}
+
+pub fn flush_context(tpm: &mut dyn TpmTransport, handle: TpmHandle) -> Result<(), TpmError> {
+    execute(tpm, TPM_CC_FLUSH_CONTEXT, None, &handle.to_be_bytes())?;
+    Ok(())
+}
+
+/// Sends a command, authorized with an empty password if it has a handle, and returns the
+/// response following its header.
+fn execute(
+    tpm: &mut dyn TpmTransport,
+    command_code: u32,
+    handle: Option<TpmHandle>,
+    params: &[u8],
+) -> Result<Zeroizing<Vec<u8>>, TpmError> {
+    let mut command = Zeroizing::new(Vec::with_capacity(HEADER_LEN + 13 + params.len()));
+    let tag = match handle {
+        Some(_) => TPM_ST_SESSIONS,
+        None => TPM_ST_NO_SESSIONS,
+    };
+    command.write_u16::<BigEndian>(tag).unwrap();
+    // commandSize, filled in below
+    command.write_u32::<BigEndian>(0).unwrap();
+    command.write_u32::<BigEndian>(command_code).unwrap();
+    if let Some(handle) = handle {
+        command.write_u32::<BigEndian>(handle).unwrap();
+        let mut auth = vec![];
+        auth.write_u32::<BigEndian>(TPM_RS_PW).unwrap();
+        // nonce, sessionAttributes, hmac
+        write_tpm2b(&mut auth, &[]);
+        auth.write_u8(0).unwrap();
+        write_tpm2b(&mut auth, &[]);
+        command.write_u32::<BigEndian>(auth.len() as u32).unwrap();
+        command.extend_from_slice(&auth);
+    }
+    command.extend_from_slice(params);
+    let size = command.len() as u32;
+    command[2..6].copy_from_slice(&size.to_be_bytes());
+
+    trace!(command_code, size, "Sending TPM command");
+    let mut response = tpm.transmit(&command)?;
+    let mut cursor = Cursor::new(response.as_slice());
+    let _tag = read_u16(&mut cursor)?;
+    let response_size = read_u32(&mut cursor)? as usize;
+    let response_code = read_u32(&mut cursor)?;
+    if response_code != 0 {
+        warn!(command_code, response_code, "TPM command failed");
+        return Err(TpmError::ResponseCode(response_code));
+    }
+    if response_size != response.len() {
+        return Err(TpmError::MalformedResponse);
+    }
+    response.drain(..HEADER_LEN);
+    Ok(response)
+}
+
+fn sensitive_create(data: &[u8]) -> Zeroizing<Vec<u8>> {
+    let mut sensitive = Zeroizing::new(vec![]);
+    // userAuth
+    write_tpm2b(&mut sensitive, &[]);
+    write_tpm2b(&mut sensitive, data);
+    sensitive
+}
+
+/// TPMT_PUBLIC of an ECC P-256 storage key, as in the TCG's SRK template.
+fn storage_primary_public() -> Vec<u8> {
+    let mut public = vec![];
+    public.write_u16::<BigEndian>(TPM_ALG_ECC).unwrap();
+    public.write_u16::<BigEndian>(TPM_ALG_SHA256).unwrap();
+    let attributes = FIXED_TPM
+        | FIXED_PARENT
+        | SENSITIVE_DATA_ORIGIN
+        | USER_WITH_AUTH
+        | NO_DA
+        | RESTRICTED
+        | DECRYPT;
+    public.write_u32::<BigEndian>(attributes).unwrap();
+    // authPolicy
+    write_tpm2b(&mut public, &[]);
+    // TPMS_ECC_PARMS: AES-128-CFB for children, no signing scheme, P-256, no KDF
+    public.write_u16::<BigEndian>(TPM_ALG_AES).unwrap();
+    public.write_u16::<BigEndian>(128).unwrap();
+    public.write_u16::<BigEndian>(TPM_ALG_CFB).unwrap();
+    public.write_u16::<BigEndian>(TPM_ALG_NULL).unwrap();
+    public.write_u16::<BigEndian>(TPM_ECC_NIST_P256).unwrap();
+    public.write_u16::<BigEndian>(TPM_ALG_NULL).unwrap();
+    // unique: empty x and y
+    write_tpm2b(&mut public, &[]);
+    write_tpm2b(&mut public, &[]);
+    public
+}
+
+/// TPMT_PUBLIC of a sealed data object, usable without a password, but only below the
+/// parent it was created under.
+fn sealed_data_public() -> Vec<u8> {
+    let mut public = vec![];
+    public.write_u16::<BigEndian>(TPM_ALG_KEYEDHASH).unwrap();
+    public.write_u16::<BigEndian>(TPM_ALG_SHA256).unwrap();
+    public
+        .write_u32::<BigEndian>(FIXED_TPM | FIXED_PARENT | USER_WITH_AUTH | NO_DA)
+        .unwrap();
+    write_tpm2b(&mut public, &[]);
+    // TPMS_KEYEDHASH_PARMS: no scheme, as it only holds data
+    public.write_u16::<BigEndian>(TPM_ALG_NULL).unwrap();
+    write_tpm2b(&mut public, &[]);
+    public
+}
+
+fn write_tpm2b(buffer: &mut Vec<u8>, data: &[u8]) {
+    buffer.write_u16::<BigEndian>(data.len() as u16).unwrap();
+    buffer.extend_from_slice(data);
+}
+
+fn read_u16(cursor: &mut Cursor<&[u8]>) -> Result<u16, TpmError> {
+    cursor
+        .read_u16::<BigEndian>()
+        .or(Err(TpmError::MalformedResponse))
+}
+
+fn read_u32(cursor: &mut Cursor<&[u8]>) -> Result<u32, TpmError> {
+    cursor
+        .read_u32::<BigEndian>()
+        .or(Err(TpmError::MalformedResponse))
+}
+
+fn read_tpm2b(cursor: &mut Cursor<&[u8]>) -> Result<Vec<u8>, TpmError> {
+    let len = read_u16(cursor)? as usize;
+    let mut data = vec![0; len];
+    cursor
+        .read_exact(&mut data)
+        .or(Err(TpmError::MalformedResponse))?;
+    Ok(data)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::collections::HashMap;
+    use std::io::{Cursor, Read};
+
+    use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+    use zeroize::Zeroizing;
+
+    use super::*;
+
+    const TPM_RC_HANDLE: u32 = 0x08B;
+
+    /// Answers the commands above like a TPM would, "encrypting" sealed data by reversing it.
+    #[derive(Default)]
+    pub(crate) struct FakeTpm {
+        next_handle: TpmHandle,
+        loaded: HashMap<TpmHandle, Vec<u8>>,
+        pub(crate) commands: Vec<u32>,
+    }
+
+    impl FakeTpm {
+        fn respond(&mut self, command: &[u8]) -> Result<Vec<u8>, u32> {
+            let mut cursor = Cursor::new(command);
+            let tag = cursor.read_u16::<BigEndian>().unwrap();
+            let size = cursor.read_u32::<BigEndian>().unwrap();
+            assert_eq!(size as usize, command.len());
+            let command_code = cursor.read_u32::<BigEndian>().unwrap();
+            self.commands.push(command_code);
+            if command_code == TPM_CC_FLUSH_CONTEXT {
+                assert_eq!(tag, TPM_ST_NO_SESSIONS);
+                let handle = cursor.read_u32::<BigEndian>().unwrap();
+                return match self.loaded.remove(&handle) {
+                    Some(_) => Ok(vec![]),
+                    None => Err(TPM_RC_HANDLE),
+                };
+            }
+            assert_eq!(tag, TPM_ST_SESSIONS);
+            let handle = cursor.read_u32::<BigEndian>().unwrap();
+            let auth_size = cursor.read_u32::<BigEndian>().unwrap();
+            assert_eq!(auth_size, 9);
+            assert_eq!(cursor.read_u32::<BigEndian>().unwrap(), TPM_RS_PW);
+            cursor.set_position(cursor.position() + 5);
+
+            let mut response = vec![];
+            match command_code {
+                TPM_CC_CREATE_PRIMARY => {
+                    assert_eq!(handle, TPM_RH_OWNER);
+                    let _sensitive = read_tpm2b(&mut cursor).unwrap();
+                    assert_eq!(read_tpm2b(&mut cursor).unwrap(), storage_primary_public());
+                    let _outside_info = read_tpm2b(&mut cursor).unwrap();
+                    assert_eq!(cursor.read_u32::<BigEndian>().unwrap(), 0);
+                    let primary = self.insert(vec![]);
+                    response.write_u32::<BigEndian>(primary).unwrap();
+                    response.write_u32::<BigEndian>(0).unwrap();
+                }
+                TPM_CC_CREATE => {
+                    self.loaded.get(&handle).ok_or(TPM_RC_HANDLE)?;
+                    let sensitive = read_tpm2b(&mut cursor).unwrap();
+                    let mut sensitive = Cursor::new(sensitive.as_slice());
+                    let _user_auth = read_tpm2b(&mut sensitive).unwrap();
+                    let mut data = read_tpm2b(&mut sensitive).unwrap();
+                    let public = read_tpm2b(&mut cursor).unwrap();
+                    assert_eq!(public, sealed_data_public());
+                    let _outside_info = read_tpm2b(&mut cursor).unwrap();
+                    assert_eq!(cursor.read_u32::<BigEndian>().unwrap(), 0);
+                    data.reverse();
+                    let mut params = vec![];
+                    write_tpm2b(&mut params, &data);
+                    write_tpm2b(&mut params, &public);
+                    response
+                        .write_u32::<BigEndian>(params.len() as u32)
+                        .unwrap();
+                    response.extend(params);
+                }
+                TPM_CC_LOAD => {
+                    self.loaded.get(&handle).ok_or(TPM_RC_HANDLE)?;
+                    let mut data = read_tpm2b(&mut cursor).unwrap();
+                    let public = read_tpm2b(&mut cursor).unwrap();
+                    assert_eq!(public, sealed_data_public());
+                    data.reverse();
+                    let object = self.insert(data);
+                    response.write_u32::<BigEndian>(object).unwrap();
+                    response.write_u32::<BigEndian>(2).unwrap();
+                    write_tpm2b(&mut response, &[]);
+                }
+                TPM_CC_UNSEAL => {
+                    let data = self.loaded.get(&handle).ok_or(TPM_RC_HANDLE)?;
+                    let mut params = vec![];
+                    write_tpm2b(&mut params, data);
+                    response
+                        .write_u32::<BigEndian>(params.len() as u32)
+                        .unwrap();
+                    response.extend(params);
+                }
+                _ => panic!("Unexpected command {command_code:#x}"),
+            }
+            assert_eq!(cursor.read(&mut [0; 1]).unwrap(), 0, "Trailing bytes");
+            Ok(response)
+        }
+
+        fn insert(&mut self, data: Vec<u8>) -> TpmHandle {
+            self.next_handle += 1;
+            let handle = 0x8000_0000 + self.next_handle;
+            self.loaded.insert(handle, data);
+            handle
+        }
+
+        pub(crate) fn loaded_objects(&self) -> usize {
+            self.loaded.len()
+        }
+    }
+
+    impl TpmTransport for FakeTpm {
+        fn transmit(&mut self, command: &[u8]) -> io::Result<Zeroizing<Vec<u8>>> {
+            let (response_code, body) = match self.respond(command) {
+                Ok(body) => (0, body),
+                Err(response_code) => (response_code, vec![]),
+            };
+            let mut response = vec![];
+            response.write_u16::<BigEndian>(TPM_ST_SESSIONS).unwrap();
+            response
+                .write_u32::<BigEndian>((HEADER_LEN + body.len()) as u32)
+                .unwrap();
+            response.write_u32::<BigEndian>(response_code).unwrap();
+            response.extend(body);
+            Ok(Zeroizing::new(response))
+        }
+    }
+
+    #[test]
+    fn flush_context_is_marshalled() {
+        struct Expect(Vec<u8>);
+        impl TpmTransport for Expect {
+            fn transmit(&mut self, command: &[u8]) -> io::Result<Zeroizing<Vec<u8>>> {
+                assert_eq!(command, self.0);
+                Ok(Zeroizing::new(vec![0x80, 0x01, 0, 0, 0, 10, 0, 0, 0, 0]))
+            }
+        }
+        let mut tpm = Expect(vec![
+            0x80, 0x01, 0, 0, 0, 14, 0, 0, 0x01, 0x65, 0x80, 0, 0, 0x01,
+        ]);
+        flush_context(&mut tpm, 0x8000_0001).unwrap();
+    }
+
+    #[test]
+    fn storage_primary_matches_srk_template() {
+        assert_eq!(
+            storage_primary_public(),
+            vec![
+                0x00, 0x23, 0x00, 0x0B, 0x00, 0x03, 0x04, 0x72, 0x00, 0x00, 0x00, 0x06, 0x00, 0x80,
+                0x00, 0x43, 0x00, 0x10, 0x00, 0x03, 0x00, 0x10, 0x00, 0x00, 0x00, 0x00,
+            ]
+        );
+    }
+
+    #[test]
+    fn sealed_data_round_trips() {
+        let mut tpm = FakeTpm::default();
+        let primary = create_primary(&mut tpm).unwrap();
+        let sealed = create_sealed(&mut tpm, primary, b"secret").unwrap();
+        assert_eq!(sealed.private, b"terces");
+        assert_eq!(sealed.public, sealed_data_public());
+
+        let object = load(&mut tpm, primary, &sealed).unwrap();
+        assert_eq!(unseal(&mut tpm, object).unwrap().as_slice(), b"secret");
+        flush_context(&mut tpm, object).unwrap();
+        flush_context(&mut tpm, primary).unwrap();
+        assert_eq!(tpm.loaded_objects(), 0);
+    }
+
+    #[test]
+    fn response_codes_are_errors() {
+        let mut tpm = FakeTpm::default();
+        assert!(matches!(
+            unseal(&mut tpm, 0x8000_0042),
+            Err(TpmError::ResponseCode(TPM_RC_HANDLE))
+        ));
+    }
+}
@@ -0,0 +1,306 @@
+//! Platform authenticator keeping the private keys of its credentials sealed to the
+//! system TPM, so that they can't be used on another machine.
+
+use std::fs;
+use std::io::{ErrorKind as IOErrorKind, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+
+use p256::SecretKey;
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+use tracing::{debug, info, instrument, warn};
+use zeroize::Zeroizing;
+
+use crate::proto::ctap2::cbor;
+use crate::transport::error::TransportError;
+use crate::transport::local::{CredentialStore, LocalCredential, LocalDevice};
+use crate::webauthn::error::{Error, PlatformError};
+
+pub mod command;
+
+use command::{SealedObject, TpmCharDevice, TpmError, TpmHandle, TpmTransport};
+
+pub const TPM_AAGUID: [u8; 16] = [
+    0xcb, 0xf8, 0xff, 0x68, 0x1f, 0x1e, 0x4a, 0x86, 0x8a, 0xcb, 0x68, 0xa1, 0xbb, 0xdd, 0xe1, 0xab,
+];
+
+const DEFAULT_TPM_DEVICE: &str = "/dev/tpmrm0";
+
+pub type TpmDevice = LocalDevice<TpmCredentialStore>;
+
+impl TpmDevice {
+    /// Opens the TPM-backed platform authenticator, storing credentials at `path`.
+    pub fn open(path: impl Into<PathBuf>) -> Result<Self, Error> {
+        let store = TpmCredentialStore::open(path)?;
+        Ok(LocalDevice::new("TPM", TPM_AAGUID, store))
+    }
+}
+
+/// Whether the kernel's TPM resource manager exists. Doesn't talk to the TPM, so
+/// [TpmDevice::open] may still fail.
+pub fn is_available() -> bool {
+    Path::new(DEFAULT_TPM_DEVICE).exists()
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct SealedCredential {
+    credential: LocalCredential,
+    /// TPM2B_PUBLIC of the sealed object, without its size
+    public: ByteBuf,
+    /// TPM2B_PRIVATE of the sealed object, without its size. Only usable with this TPM.
+    private: ByteBuf,
+}
+
+/// Credentials are kept in a file, with each private key sealed to the storage primary
+/// key of the TPM's owner hierarchy. Private keys are only unsealed to sign, and not kept.
+pub struct TpmCredentialStore {
+    path: PathBuf,
+    tpm: Box<dyn TpmTransport>,
+    credentials: Vec<SealedCredential>,
+}
+
+impl TpmCredentialStore {
+    /// Opens the store at `path`, sealing to the TPM behind the kernel's resource manager.
+    pub fn open(path: impl Into<PathBuf>) -> Result<Self, Error> {
+        let tpm = TpmCharDevice::open(Path::new(DEFAULT_TPM_DEVICE)).map_err(|e| {
+            warn!(%e, "Failed to open TPM");
+            Error::Transport(TransportError::TransportUnavailable)
+        })?;
+        Self::open_with(path, Box::new(tpm))
+    }
+
+    /// Opens the store at `path`, sealing to `tpm`, e.g. a TPM simulator.
+    #[instrument(skip_all)]
+    pub fn open_with(path: impl Into<PathBuf>, tpm: Box<dyn TpmTransport>) -> Result<Self, Error> {
+        let path = path.into();
+        let credentials = match fs::read(&path) {
+            Ok(data) => cbor::from_slice(&data)?,
+            Err(e) if e.kind() == IOErrorKind::NotFound => vec![],
+            Err(e) => return Err(Error::Transport(TransportError::IoError(e.kind()))),
+        };
+        let mut store = Self {
+            path,
+            tpm,
+            credentials,
+        };
+        // Fail early if the TPM is unusable.
+        store.with_primary_key(|_, _| Ok(()))?;
+        info!(count = store.credentials.len(), path = ?store.path, "Opened TPM credential store");
+        Ok(store)
+    }
+
+    /// Runs `f` with the storage primary key, which is flushed again afterwards.
+    fn with_primary_key<T>(
+        &mut self,
+        f: impl FnOnce(&mut dyn TpmTransport, TpmHandle) -> Result<T, TpmError>,
+    ) -> Result<T, Error> {
+        let tpm = self.tpm.as_mut();
+        let primary = command::create_primary(tpm).map_err(tpm_error)?;
+        let result = f(tpm, primary);
+        if let Err(error) = command::flush_context(tpm, primary) {
+            warn!(%error, "Failed to flush the storage primary key");
+        }
+        result.map_err(tpm_error)
+    }
+
+    fn seal(&mut self, secret: &[u8]) -> Result<SealedObject, Error> {
+        self.with_primary_key(|tpm, primary| command::create_sealed(tpm, primary, secret))
+    }
+
+    fn unseal(&mut self, sealed: &SealedObject) -> Result<Zeroizing<Vec<u8>>, Error> {
+        self.with_primary_key(|tpm, primary| {
+            let object = command::load(tpm, primary, sealed)?;
+            let secret = command::unseal(tpm, object);
+            command::flush_context(tpm, object)?;
+            secret
+        })
+    }
+
+    fn save(&self) -> Result<(), Error> {
+        let data = cbor::to_vec(&self.credentials)?;
+        write_private_file(&self.path, &data)
+            .map_err(|e| Error::Transport(TransportError::IoError(e.kind())))
+    }
+}
+
+impl CredentialStore for TpmCredentialStore {
+    fn credentials(&self) -> Result<Vec<LocalCredential>, Error> {
+        Ok(self
+            .credentials
+            .iter()
+            .map(|sealed| sealed.credential.clone())
+            .collect())
+    }
+
+    fn insert(
+        &mut self,
+        credential: LocalCredential,
+        private_key: &SecretKey,
+    ) -> Result<(), Error> {
+        let secret = Zeroizing::new(private_key.to_bytes());
+        let sealed = self.seal(&secret)?;
+        debug!("Sealed credential private key");
+        self.credentials.push(SealedCredential {
+            credential,
+            public: ByteBuf::from(sealed.public),
+            private: ByteBuf::from(sealed.private),
+        });
+        self.save()
+    }
+
+    fn update(&mut self, credential: &LocalCredential) -> Result<(), Error> {
+        let Some(sealed) = self
+            .credentials
+            .iter_mut()
+            .find(|sealed| sealed.credential.id == credential.id)
+        else {
+            return Err(Error::Platform(PlatformError::CredentialNotFound));
+        };
+        sealed.credential = credential.clone();
+        self.save()
+    }
+
+    fn delete(&mut self, credential_id: &[u8]) -> Result<(), Error> {
+        self.credentials
+            .retain(|sealed| sealed.credential.id.as_slice() != credential_id);
+        self.save()
+    }
+
+    fn private_key(&mut self, credential_id: &[u8]) -> Result<SecretKey, Error> {
+        let Some(sealed) = self
+            .credentials
+            .iter()
+            .find(|sealed| sealed.credential.id.as_slice() == credential_id)
+        else {
+            return Err(Error::Platform(PlatformError::CredentialNotFound));
+        };
+        let sealed = SealedObject {
+            public: sealed.public.to_vec(),
+            private: sealed.private.to_vec(),
+        };
+        let secret = self.unseal(&sealed)?;
+        SecretKey::from_slice(&secret)
+            .or(Err(Error::Platform(PlatformError::InvalidDeviceResponse)))
+    }
+}
+
+/// Replaces `path` atomically, with a file only readable by the current user.
+fn write_private_file(path: &Path, data: &[u8]) -> std::io::Result<()> {
+    let tmp_path = path.with_extension("tmp");
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&tmp_path)?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    fs::rename(&tmp_path, path)
+}
+
+fn tpm_error(error: TpmError) -> Error {
+    warn!(%error, "TPM operation failed");
+    Error::Transport(TransportError::ConnectionFailed)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::sync::{Arc, Mutex};
+
+    use rand::rngs::OsRng;
+    use serde_bytes::ByteBuf;
+    use zeroize::Zeroizing;
+
+    use super::command::tests::FakeTpm;
+    use super::*;
+    use crate::proto::ctap2::{
+        Ctap2PublicKeyCredentialRpEntity, Ctap2PublicKeyCredentialUserEntity,
+    };
+
+    #[derive(Clone, Default)]
+    struct SharedTpm(Arc<Mutex<FakeTpm>>);
+
+    impl TpmTransport for SharedTpm {
+        fn transmit(&mut self, command: &[u8]) -> io::Result<Zeroizing<Vec<u8>>> {
+            self.0.lock().unwrap().transmit(command)
+        }
+    }
+
+    fn store_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("libwebauthn-{}-{name}", std::process::id()));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    fn credential() -> LocalCredential {
+        LocalCredential {
+            id: ByteBuf::from([0x42; 16]),
+            rp: Ctap2PublicKeyCredentialRpEntity::dummy(),
+            user: Ctap2PublicKeyCredentialUserEntity::dummy(),
+            public_key: ByteBuf::from([0x04; 65]),
+            discoverable: true,
+            sign_count: 0,
+        }
+    }
+
+    #[test]
+    fn private_keys_are_sealed() {
+        let path = store_path("sealed");
+        let tpm = SharedTpm::default();
+        let private_key = SecretKey::random(&mut OsRng);
+        let mut store = TpmCredentialStore::open_with(&path, Box::new(tpm.clone())).unwrap();
+        store.insert(credential(), &private_key).unwrap();
+
+        let data = fs::read(&path).unwrap();
+        let secret = private_key.to_bytes();
+        assert!(!data
+            .windows(secret.len())
+            .any(|window| window == secret.as_slice()));
+        assert_eq!(
+            store.private_key(&credential().id).unwrap().to_bytes(),
+            secret
+        );
+        assert_eq!(tpm.0.lock().unwrap().loaded_objects(), 0);
+
+        // The fake TPM "encrypts" without state, so another instance stands in for a reboot.
+        let mut reopened =
+            TpmCredentialStore::open_with(&path, Box::new(FakeTpm::default())).unwrap();
+        assert_eq!(reopened.credentials().unwrap(), vec![credential()]);
+        assert_eq!(
+            reopened.private_key(&credential().id).unwrap().to_bytes(),
+            secret
+        );
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn unknown_credentials_are_not_unsealed() {
+        let path = store_path("unknown");
+        let tpm = SharedTpm::default();
+        let mut store = TpmCredentialStore::open_with(&path, Box::new(tpm.clone())).unwrap();
+        assert!(matches!(
+            store.private_key(&[0x42; 16]),
+            Err(Error::Platform(PlatformError::CredentialNotFound))
+        ));
+        // Only the primary key was created and flushed, when opening the store.
+        assert_eq!(tpm.0.lock().unwrap().commands.len(), 2);
+    }
+
+    #[test]
+    fn unusable_tpms_fail_to_open() {
+        struct Broken;
+        impl TpmTransport for Broken {
+            fn transmit(&mut self, _: &[u8]) -> io::Result<Zeroizing<Vec<u8>>> {
+                Err(io::ErrorKind::BrokenPipe.into())
+            }
+        }
+        let path = store_path("broken");
+        assert!(matches!(
+            TpmCredentialStore::open_with(&path, Box::new(Broken)),
+            Err(Error::Transport(TransportError::ConnectionFailed))
+        ));
+        assert!(!path.exists());
+    }
+}
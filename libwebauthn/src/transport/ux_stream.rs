@@ -0,0 +1,142 @@
+//! UX updates of several channels, merged into a single stream.
+//!
+//! An orchestrator running a ceremony on several channels at once, e.g. on a security key and
+//! on a phone, would otherwise have to poll each channel's receiver. A [UxStream] tags every
+//! update with the [ChannelId] it was added under, and the [DeviceDescriptor] of its device.
+//! Replies go to the channel which asked: a `UvUpdate::PinRequired` is answered through its
+//! own `send_pin`, and the accounts to select from are in the response of the channel whose
+//! id the orchestrator kept.
+
+use std::collections::HashMap;
+use std::fmt;
+
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tracing::{debug, warn};
+
+use super::channel::{Channel, DeviceDescriptor, DeviceUxUpdate};
+
+/// Identifies a channel within a [UxStream].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ChannelId(usize);
+
+impl fmt::Display for ChannelId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "#{}", self.0)
+    }
+}
+
+/// A UX update, along with the channel and device it comes from.
+#[derive(Debug, Clone)]
+pub struct ChannelUxUpdate<U> {
+    pub channel: ChannelId,
+    pub device: DeviceDescriptor,
+    pub update: U,
+}
+
+/// The UX updates of several channels, see the [module documentation](self).
+///
+/// Channels with different update types are merged into a common type `U`, e.g.
+/// [AnyUxUpdate](super::any::AnyUxUpdate) for HID and caBLE channels.
+pub struct UxStream<U> {
+    // Only forwarders hold the sender, so that the stream ends once all of them are done.
+    sender: mpsc::WeakUnboundedSender<ChannelUxUpdate<U>>,
+    receiver: mpsc::UnboundedReceiver<ChannelUxUpdate<U>>,
+    forwarders: HashMap<ChannelId, JoinHandle<()>>,
+    next_id: usize,
+}
+
+impl<U> fmt::Debug for UxStream<U> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("UxStream")
+            .field("channels", &self.forwarders.len())
+            .finish()
+    }
+}
+
+impl<U: Send + 'static> Default for UxStream<U> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<U: Send + 'static> UxStream<U> {
+    pub fn new() -> Self {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        Self {
+            sender: sender.downgrade(),
+            receiver,
+            forwarders: HashMap::new(),
+            next_id: 0,
+        }
+    }
+
+    /// Adds the updates `channel` sends from now on. They are tagged with the returned id.
+    pub fn add<C>(&mut self, channel: &C) -> ChannelId
+    where
+        C: Channel,
+        C::UxUpdate: Clone + Into<U> + 'static,
+    {
+        let id = ChannelId(self.next_id);
+        self.next_id += 1;
+        let sender = self.sender();
+        let mut receiver = channel.get_device_ux_update_receiver();
+        let forwarder = tokio::spawn(async move {
+            loop {
+                match receiver.recv().await {
+                    Ok(DeviceUxUpdate { device, update }) => {
+                        let update = ChannelUxUpdate {
+                            channel: id,
+                            device,
+                            update: update.into(),
+                        };
+                        if sender.send(update).is_err() {
+                            return;
+                        }
+                    }
+                    Err(RecvError::Lagged(skipped)) => {
+                        warn!(%id, skipped, "Dropped UX updates")
+                    }
+                    Err(RecvError::Closed) => return,
+                }
+            }
+        });
+        debug!(%id, device = %channel, "Added channel to UX stream");
+        self.forwarders.insert(id, forwarder);
+        id
+    }
+
+    /// Stops adding the updates of `channel`, e.g. once the user picked another device.
+    /// Updates already sent remain in the stream.
+    pub fn remove(&mut self, channel: ChannelId) {
+        if let Some(forwarder) = self.forwarders.remove(&channel) {
+            forwarder.abort();
+        }
+    }
+
+    /// The next update of any channel. Returns `None` once all channels were closed or
+    /// removed, and their updates received.
+    pub async fn recv(&mut self) -> Option<ChannelUxUpdate<U>> {
+        self.receiver.recv().await
+    }
+
+    fn sender(&mut self) -> mpsc::UnboundedSender<ChannelUxUpdate<U>> {
+        if let Some(sender) = self.sender.upgrade() {
+            return sender;
+        }
+        // All channels were done, and the stream ended: start over.
+        let (sender, receiver) = mpsc::unbounded_channel();
+        self.sender = sender.downgrade();
+        self.receiver = receiver;
+        sender
+    }
+}
+
+impl<U> Drop for UxStream<U> {
+    fn drop(&mut self) {
+        for forwarder in self.forwarders.values() {
+            forwarder.abort();
+        }
+    }
+}
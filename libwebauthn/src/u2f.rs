@@ -1,18 +1,30 @@
 use async_trait::async_trait;
-use tracing::{instrument, warn};
+use tracing::{debug, field::Empty, instrument, warn};
 
+use crate::correlation::CorrelationId;
 use crate::fido::FidoProtocol;
-use crate::ops::u2f::{RegisterRequest, SignRequest};
-use crate::ops::u2f::{RegisterResponse, SignResponse};
+use crate::ops::u2f::{AuthenticateRequest, RegisterRequest, SignRequest};
+use crate::ops::u2f::{AuthenticateResponse, RegisterResponse, SignResponse};
 use crate::proto::ctap1::Ctap1;
 use crate::transport::{error::TransportError, Channel};
-use crate::webauthn::error::Error;
+use crate::webauthn::error::{CtapError, Error};
+use crate::UvUpdate;
 
 #[async_trait]
 pub trait U2F {
     async fn u2f_negotiate_protocol(&mut self) -> Result<FidoProtocol, Error>;
+    /// Registers a new key handle, sending `UvUpdate::PresenceRequired` and waiting for the
+    /// user's touch. Fails with `CtapError::CredentialExcluded` if one of the request's
+    /// registered keys is already on the device.
     async fn u2f_register(&mut self, op: &RegisterRequest) -> Result<RegisterResponse, Error>;
     async fn u2f_sign(&mut self, op: &SignRequest) -> Result<SignResponse, Error>;
+    /// Signs with the first of the request's registered keys the device knows, sending
+    /// `UvUpdate::PresenceRequired` and waiting for the user's touch. Fails with
+    /// `CtapError::NoCredentials` if it knows none of them.
+    async fn u2f_authenticate(
+        &mut self,
+        op: &AuthenticateRequest,
+    ) -> Result<AuthenticateResponse, Error>;
 }
 
 #[async_trait]
@@ -33,21 +45,74 @@ where
         Ok(selected)
     }
 
-    #[instrument(skip_all, fields(dev = %self))]
+    #[instrument(skip_all, err(level = "warn"), fields(dev = %self, correlation_id = Empty))]
     async fn u2f_register(&mut self, op: &RegisterRequest) -> Result<RegisterResponse, Error> {
-        let protocol = self.u2f_negotiate_protocol().await?;
-        match protocol {
-            FidoProtocol::U2F => self.ctap1_register(op).await,
-            _ => Err(Error::Transport(TransportError::NegotiationFailed)),
-        }
+        let correlation_id = CorrelationId::for_operation();
+        correlation_id
+            .scope(async {
+                let protocol = self.u2f_negotiate_protocol().await?;
+                match protocol {
+                    FidoProtocol::U2F => {
+                        self.send_ux_update(UvUpdate::PresenceRequired.into()).await;
+                        self.ctap1_register(op).await
+                    }
+                    _ => Err(Error::Transport(TransportError::NegotiationFailed)),
+                }
+            })
+            .await
     }
 
-    #[instrument(skip_all, fields(dev = %self))]
+    #[instrument(skip_all, err(level = "warn"), fields(dev = %self, correlation_id = Empty))]
     async fn u2f_sign(&mut self, op: &SignRequest) -> Result<SignResponse, Error> {
-        let protocol = self.u2f_negotiate_protocol().await?;
-        match protocol {
-            FidoProtocol::U2F => self.ctap1_sign(op).await,
-            _ => Err(Error::Transport(TransportError::NegotiationFailed)),
-        }
+        let correlation_id = CorrelationId::for_operation();
+        correlation_id
+            .scope(async {
+                let protocol = self.u2f_negotiate_protocol().await?;
+                match protocol {
+                    FidoProtocol::U2F => {
+                        if op.require_user_presence {
+                            self.send_ux_update(UvUpdate::PresenceRequired.into()).await;
+                        }
+                        self.ctap1_sign(op).await
+                    }
+                    _ => Err(Error::Transport(TransportError::NegotiationFailed)),
+                }
+            })
+            .await
+    }
+
+    #[instrument(skip_all, err(level = "warn"), fields(dev = %self, correlation_id = Empty))]
+    async fn u2f_authenticate(
+        &mut self,
+        op: &AuthenticateRequest,
+    ) -> Result<AuthenticateResponse, Error> {
+        let correlation_id = CorrelationId::for_operation();
+        correlation_id
+            .scope(async {
+                let protocol = self.u2f_negotiate_protocol().await?;
+                if protocol != FidoProtocol::U2F {
+                    return Err(Error::Transport(TransportError::NegotiationFailed));
+                }
+                // Unknown key handles are rejected right away, so one touch is enough.
+                self.send_ux_update(UvUpdate::PresenceRequired.into()).await;
+                for sign_request in op.sign_requests() {
+                    match self.ctap1_sign(&sign_request).await {
+                        Ok(response) => {
+                            return Ok(AuthenticateResponse {
+                                key_handle: sign_request.key_handle,
+                                app_id_hash: sign_request.app_id_hash,
+                                response,
+                            })
+                        }
+                        Err(Error::Ctap(CtapError::NoCredentials)) => {
+                            debug!("Key handle unknown to the device, trying the next one");
+                        }
+                        Err(err) => return Err(err),
+                    }
+                }
+                warn!("None of the registered keys are known to the device");
+                Err(Error::Ctap(CtapError::NoCredentials))
+            })
+            .await
     }
 }
@@ -0,0 +1,430 @@
+//! Server-side verification of WebAuthn assertions.
+//!
+//! Implements the relying party checks of
+//! <https://www.w3.org/TR/webauthn-3/#sctn-verifying-assertion> against a credential
+//! public key stored at registration time, so that small relying parties built on
+//! this crate don't need a second WebAuthn implementation.
+
+use cosey::PublicKey;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use tracing::{debug, instrument, warn};
+
+use crate::fido::AuthenticatorDataFlags;
+use crate::ops::webauthn::SignCountCheck;
+
+pub mod attestation;
+pub(crate) mod certificate;
+pub(crate) mod jws;
+
+const AUTHENTICATOR_DATA_MIN_LEN: usize = 37;
+
+#[derive(thiserror::Error, Debug, PartialEq)]
+pub enum VerificationError {
+    #[error("malformed client data")]
+    MalformedClientData,
+    #[error("unexpected client data type: {0}")]
+    UnexpectedType(String),
+    #[error("challenge mismatch")]
+    ChallengeMismatch,
+    #[error("unexpected origin: {0}")]
+    UnexpectedOrigin(String),
+    #[error("malformed authenticator data")]
+    MalformedAuthenticatorData,
+    #[error("relying party ID hash mismatch")]
+    RpIdHashMismatch,
+    #[error("user presence flag not set")]
+    UserNotPresent,
+    #[error("user verification flag not set")]
+    UserNotVerified,
+    #[error("signature counter did not increase (stored {stored}, received {received})")]
+    SignCountRegression { stored: u32, received: u32 },
+    #[error("unsupported public key type")]
+    UnsupportedPublicKey,
+    #[error("invalid public key")]
+    InvalidPublicKey,
+    #[error("invalid signature")]
+    InvalidSignature,
+    #[error("unsupported signature algorithm")]
+    UnsupportedAlgorithm,
+    #[error("unexpected attestation statement format")]
+    UnexpectedAttestationFormat,
+    #[error("malformed attestation certificate")]
+    MalformedCertificate,
+    #[error("attestation certificate chain does not verify")]
+    InvalidCertificateChain,
+    #[error("attestation certificate does not match the credential public key")]
+    CredentialKeyMismatch,
+    #[error("missing Android key attestation extension")]
+    MissingKeyAttestation,
+    #[error("malformed Android key attestation extension")]
+    MalformedKeyAttestation,
+    #[error("key attestation rejected: {0}")]
+    KeyAttestationPolicy(&'static str),
+    #[error("malformed SafetyNet response")]
+    MalformedSafetyNetResponse,
+    #[error("attestation certificate issued to an unexpected hostname")]
+    UnexpectedCertificateHostname,
+    #[error("SafetyNet response timestamp outside the accepted window")]
+    StaleSafetyNetResponse,
+}
+
+/// What the relying party expects the assertion to look like.
+#[derive(Debug, Clone)]
+pub struct ExpectedAssertion<'a> {
+    /// The RP ID the credential is scoped to.
+    pub rp_id: &'a str,
+    /// The raw challenge sent to the client.
+    pub challenge: &'a [u8],
+    /// Origins the RP accepts, e.g. `https://login.example.org`.
+    pub origins: &'a [&'a str],
+    /// Whether the UV flag must be set.
+    pub require_user_verification: bool,
+    /// The signature counter stored for this credential, 0 if the authenticator doesn't use counters.
+    pub stored_sign_count: u32,
+}
+
+/// Result of a successful verification. The RP should store `sign_count` for the next ceremony.
+#[derive(Debug, Clone)]
+pub struct VerifiedAssertion {
+    pub flags: AuthenticatorDataFlags,
+    pub sign_count: u32,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct CollectedClientData {
+    #[serde(rename = "type")]
+    r#type: String,
+    challenge: String,
+    origin: String,
+}
+
+/// Verifies an assertion, as returned by the client, against the stored COSE public key.
+#[instrument(skip_all, fields(rp_id = expected.rp_id))]
+pub fn verify_assertion(
+    public_key: &PublicKey,
+    expected: &ExpectedAssertion,
+    client_data_json: &[u8],
+    authenticator_data: &[u8],
+    signature: &[u8],
+) -> Result<VerifiedAssertion, VerificationError> {
+    let client_data: CollectedClientData =
+        serde_json::from_slice(client_data_json).map_err(|e| {
+            warn!(%e, "Failed to parse client data JSON");
+            VerificationError::MalformedClientData
+        })?;
+    if client_data.r#type != "webauthn.get" {
+        return Err(VerificationError::UnexpectedType(client_data.r#type));
+    }
+    if client_data.challenge != base64_url::encode(expected.challenge) {
+        return Err(VerificationError::ChallengeMismatch);
+    }
+    if !expected.origins.contains(&client_data.origin.as_str()) {
+        return Err(VerificationError::UnexpectedOrigin(client_data.origin));
+    }
+
+    if authenticator_data.len() < AUTHENTICATOR_DATA_MIN_LEN {
+        return Err(VerificationError::MalformedAuthenticatorData);
+    }
+    let rp_id_hash = Sha256::digest(expected.rp_id.as_bytes());
+    if authenticator_data[0..32] != rp_id_hash[..] {
+        return Err(VerificationError::RpIdHashMismatch);
+    }
+    let flags = AuthenticatorDataFlags::from_bits_truncate(authenticator_data[32]);
+    if !flags.contains(AuthenticatorDataFlags::USER_PRESENT) {
+        return Err(VerificationError::UserNotPresent);
+    }
+    if expected.require_user_verification && !flags.contains(AuthenticatorDataFlags::USER_VERIFIED)
+    {
+        return Err(VerificationError::UserNotVerified);
+    }
+
+    // Only trust the counter once the signature covering it has been checked.
+    let client_data_hash = Sha256::digest(client_data_json);
+    let mut signed_data = authenticator_data.to_vec();
+    signed_data.extend_from_slice(&client_data_hash);
+    verify_signature(public_key, &signed_data, signature)?;
+
+    let sign_count = u32::from_be_bytes(authenticator_data[33..37].try_into().unwrap());
+    if SignCountCheck::new(expected.stored_sign_count, sign_count) == SignCountCheck::Regressed {
+        warn!(
+            { stored = expected.stored_sign_count, received = sign_count },
+            "Signature counter did not increase, the authenticator may be cloned"
+        );
+        return Err(VerificationError::SignCountRegression {
+            stored: expected.stored_sign_count,
+            received: sign_count,
+        });
+    }
+
+    debug!(?flags, sign_count, "Assertion verified");
+    Ok(VerifiedAssertion { flags, sign_count })
+}
+
+fn verify_signature(
+    public_key: &PublicKey,
+    signed_data: &[u8],
+    signature: &[u8],
+) -> Result<(), VerificationError> {
+    match public_key {
+        PublicKey::P256Key(key) => {
+            use p256::ecdsa::signature::Verifier;
+            use p256::ecdsa::{Signature, VerifyingKey};
+
+            let point = p256::EncodedPoint::from_affine_coordinates(
+                key.x.as_slice().into(),
+                key.y.as_slice().into(),
+                false,
+            );
+            let verifying_key = VerifyingKey::from_encoded_point(&point)
+                .or(Err(VerificationError::InvalidPublicKey))?;
+            // WebAuthn ES256 signatures are ASN.1 DER encoded
+            let signature =
+                Signature::from_der(signature).or(Err(VerificationError::InvalidSignature))?;
+            verifying_key
+                .verify(signed_data, &signature)
+                .or(Err(VerificationError::InvalidSignature))
+        }
+        PublicKey::Ed25519Key(key) => {
+            use ed25519_dalek::{Signature, Verifier, VerifyingKey};
+
+            let key_bytes: &[u8; 32] = key
+                .x
+                .as_slice()
+                .try_into()
+                .or(Err(VerificationError::InvalidPublicKey))?;
+            let verifying_key =
+                VerifyingKey::from_bytes(key_bytes).or(Err(VerificationError::InvalidPublicKey))?;
+            let signature =
+                Signature::from_slice(signature).or(Err(VerificationError::InvalidSignature))?;
+            verifying_key
+                .verify(signed_data, &signature)
+                .or(Err(VerificationError::InvalidSignature))
+        }
+        PublicKey::EcdhEsHkdf256Key(_) | PublicKey::TotpKey(_) => {
+            Err(VerificationError::UnsupportedPublicKey)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use cosey::{Ed25519PublicKey, P256PublicKey};
+    use p256::ecdsa::signature::Signer;
+    use p256::ecdsa::{DerSignature, SigningKey};
+
+    const RP_ID: &str = "example.org";
+    const ORIGIN: &str = "https://example.org";
+    const CHALLENGE: &[u8] = &[1, 2, 3, 4];
+
+    fn expected(stored_sign_count: u32) -> ExpectedAssertion<'static> {
+        ExpectedAssertion {
+            rp_id: RP_ID,
+            challenge: CHALLENGE,
+            origins: &[ORIGIN],
+            require_user_verification: false,
+            stored_sign_count,
+        }
+    }
+
+    fn client_data(r#type: &str, challenge: &[u8], origin: &str) -> Vec<u8> {
+        format!(
+            r#"{{"type":"{}","challenge":"{}","origin":"{}","crossOrigin":false}}"#,
+            r#type,
+            base64_url::encode(challenge),
+            origin
+        )
+        .into_bytes()
+    }
+
+    fn authenticator_data(flags: u8, sign_count: u32) -> Vec<u8> {
+        let mut data = Sha256::digest(RP_ID.as_bytes()).to_vec();
+        data.push(flags);
+        data.extend(sign_count.to_be_bytes());
+        data
+    }
+
+    fn p256_key() -> (SigningKey, PublicKey) {
+        let signing_key = SigningKey::from_slice(&[0x42; 32]).unwrap();
+        let point = signing_key.verifying_key().to_encoded_point(false);
+        let public_key = PublicKey::P256Key(P256PublicKey {
+            x: cosey::Bytes::from_slice(point.x().unwrap()).unwrap(),
+            y: cosey::Bytes::from_slice(point.y().unwrap()).unwrap(),
+        });
+        (signing_key, public_key)
+    }
+
+    fn sign_p256(key: &SigningKey, auth_data: &[u8], client_data: &[u8]) -> Vec<u8> {
+        let mut signed_data = auth_data.to_vec();
+        signed_data.extend(Sha256::digest(client_data));
+        let signature: DerSignature = key.sign(&signed_data);
+        signature.as_bytes().to_vec()
+    }
+
+    #[test]
+    fn verify_valid_p256_assertion() {
+        let (signing_key, public_key) = p256_key();
+        let client_data = client_data("webauthn.get", CHALLENGE, ORIGIN);
+        let auth_data = authenticator_data(0x05, 8);
+        let signature = sign_p256(&signing_key, &auth_data, &client_data);
+
+        let verified = verify_assertion(
+            &public_key,
+            &expected(7),
+            &client_data,
+            &auth_data,
+            &signature,
+        )
+        .unwrap();
+        assert_eq!(verified.sign_count, 8);
+        assert!(verified
+            .flags
+            .contains(AuthenticatorDataFlags::USER_VERIFIED));
+    }
+
+    #[test]
+    fn verify_valid_ed25519_assertion() {
+        use ed25519_dalek::Signer;
+
+        let signing_key = ed25519_dalek::SigningKey::from_bytes(&[0x24; 32]);
+        let public_key = PublicKey::Ed25519Key(Ed25519PublicKey {
+            x: cosey::Bytes::from_slice(signing_key.verifying_key().as_bytes()).unwrap(),
+        });
+        let client_data = client_data("webauthn.get", CHALLENGE, ORIGIN);
+        let auth_data = authenticator_data(0x01, 0);
+        let mut signed_data = auth_data.clone();
+        signed_data.extend(Sha256::digest(&client_data));
+        let signature = signing_key.sign(&signed_data).to_bytes();
+
+        assert!(verify_assertion(
+            &public_key,
+            &expected(0),
+            &client_data,
+            &auth_data,
+            &signature
+        )
+        .is_ok());
+    }
+
+    #[test]
+    fn reject_tampered_signature() {
+        let (signing_key, public_key) = p256_key();
+        let client_data = client_data("webauthn.get", CHALLENGE, ORIGIN);
+        let auth_data = authenticator_data(0x01, 1);
+        let signature = sign_p256(&signing_key, &authenticator_data(0x05, 1), &client_data);
+
+        assert_eq!(
+            verify_assertion(
+                &public_key,
+                &expected(0),
+                &client_data,
+                &auth_data,
+                &signature
+            )
+            .unwrap_err(),
+            VerificationError::InvalidSignature
+        );
+    }
+
+    #[test]
+    fn reject_client_data_mismatches() {
+        let (signing_key, public_key) = p256_key();
+        let auth_data = authenticator_data(0x01, 1);
+        let cases = [
+            (
+                client_data("webauthn.create", CHALLENGE, ORIGIN),
+                VerificationError::UnexpectedType("webauthn.create".to_owned()),
+            ),
+            (
+                client_data("webauthn.get", &[9, 9], ORIGIN),
+                VerificationError::ChallengeMismatch,
+            ),
+            (
+                client_data("webauthn.get", CHALLENGE, "https://evil.example"),
+                VerificationError::UnexpectedOrigin("https://evil.example".to_owned()),
+            ),
+        ];
+        for (client_data, error) in cases {
+            let signature = sign_p256(&signing_key, &auth_data, &client_data);
+            assert_eq!(
+                verify_assertion(
+                    &public_key,
+                    &expected(0),
+                    &client_data,
+                    &auth_data,
+                    &signature
+                )
+                .unwrap_err(),
+                error
+            );
+        }
+    }
+
+    #[test]
+    fn reject_flags_and_counter() {
+        let (signing_key, public_key) = p256_key();
+        let client_data = client_data("webauthn.get", CHALLENGE, ORIGIN);
+
+        let auth_data = authenticator_data(0x00, 1);
+        let signature = sign_p256(&signing_key, &auth_data, &client_data);
+        assert_eq!(
+            verify_assertion(
+                &public_key,
+                &expected(0),
+                &client_data,
+                &auth_data,
+                &signature
+            )
+            .unwrap_err(),
+            VerificationError::UserNotPresent
+        );
+
+        let auth_data = authenticator_data(0x01, 1);
+        let signature = sign_p256(&signing_key, &auth_data, &client_data);
+        let mut uv_required = expected(0);
+        uv_required.require_user_verification = true;
+        assert_eq!(
+            verify_assertion(
+                &public_key,
+                &uv_required,
+                &client_data,
+                &auth_data,
+                &signature
+            )
+            .unwrap_err(),
+            VerificationError::UserNotVerified
+        );
+        assert_eq!(
+            verify_assertion(
+                &public_key,
+                &expected(5),
+                &client_data,
+                &auth_data,
+                &signature
+            )
+            .unwrap_err(),
+            VerificationError::SignCountRegression {
+                stored: 5,
+                received: 1
+            }
+        );
+    }
+
+    #[test]
+    fn reject_wrong_rp_id() {
+        let (signing_key, public_key) = p256_key();
+        let client_data = client_data("webauthn.get", CHALLENGE, ORIGIN);
+        let auth_data = authenticator_data(0x01, 1);
+        let signature = sign_p256(&signing_key, &auth_data, &client_data);
+        let mut other_rp = expected(0);
+        other_rp.rp_id = "other.example";
+        assert_eq!(
+            verify_assertion(&public_key, &other_rp, &client_data, &auth_data, &signature)
+                .unwrap_err(),
+            VerificationError::RpIdHashMismatch
+        );
+    }
+}
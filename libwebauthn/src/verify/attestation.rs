@@ -0,0 +1,522 @@
+//! Verification of Android attestation statements.
+//!
+//! Covers the `android-key` (<https://www.w3.org/TR/webauthn-3/#sctn-android-key-attestation>)
+//! and `android-safetynet` (<https://www.w3.org/TR/webauthn-3/#sctn-android-safetynet-attestation>)
+//! formats. Both verifiers check the certificate chain is internally consistent and return it,
+//! anchoring it to the Google attestation roots is left to the relying party's trust policy.
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use base64_url::base64::engine::general_purpose::STANDARD;
+use base64_url::base64::Engine;
+use cosey::PublicKey;
+use ring::signature;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use tracing::{debug, instrument, warn};
+use x509_parser::asn1_rs::{Any, Class, FromDer};
+
+use super::certificate::{certificate_has_hostname, parse_chain, verify_with_certificate};
+use super::jws::Jws;
+use super::VerificationError;
+use crate::proto::ctap2::{Ctap2AttestationStatement, Ctap2COSEAlgorithmIdentifier};
+
+const KEY_ATTESTATION_OID: &str = "1.3.6.1.4.1.11129.2.1.17";
+const SAFETYNET_HOSTNAME: &str = "attest.android.com";
+
+// AuthorizationList tags, https://source.android.com/docs/security/features/keystore/attestation#schema
+const TAG_PURPOSE: u32 = 1;
+const TAG_ALL_APPLICATIONS: u32 = 600;
+const TAG_ORIGIN: u32 = 702;
+const TAG_ROOT_OF_TRUST: u32 = 704;
+const TAG_OS_VERSION: u32 = 705;
+const TAG_OS_PATCH_LEVEL: u32 = 706;
+
+const KM_PURPOSE_SIGN: u32 = 2;
+const KM_ORIGIN_GENERATED: u32 = 0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AndroidSecurityLevel {
+    Software,
+    TrustedEnvironment,
+    StrongBox,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VerifiedBootState {
+    Verified,
+    SelfSigned,
+    Unverified,
+    Failed,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AndroidRootOfTrust {
+    pub device_locked: bool,
+    pub verified_boot_state: VerifiedBootState,
+}
+
+/// Device integrity information extracted from a verified `android-key` statement.
+#[derive(Debug, Clone)]
+pub struct AndroidKeyAttestation {
+    pub attestation_version: u32,
+    pub attestation_security_level: AndroidSecurityLevel,
+    pub keymint_version: u32,
+    pub keymint_security_level: AndroidSecurityLevel,
+    /// Whether the key origin and signing purpose are enforced by secure hardware,
+    /// rather than only by the Android OS.
+    pub hardware_enforced: bool,
+    pub root_of_trust: Option<AndroidRootOfTrust>,
+    pub os_version: Option<u32>,
+    pub os_patch_level: Option<u32>,
+    /// The DER attestation certificate chain, leaf first.
+    pub certificates: Vec<Vec<u8>>,
+}
+
+/// Device integrity information extracted from a verified `android-safetynet` statement.
+#[derive(Debug, Clone)]
+pub struct SafetyNetAttestation {
+    pub version: String,
+    pub timestamp: SystemTime,
+    pub cts_profile_match: bool,
+    pub basic_integrity: bool,
+    pub apk_package_name: Option<String>,
+    pub apk_certificate_digests: Vec<String>,
+    pub evaluation_type: Option<String>,
+    /// The DER certificate chain of the JWS, leaf first.
+    pub certificates: Vec<Vec<u8>>,
+}
+
+#[derive(Debug, Default)]
+struct AuthorizationList {
+    purposes: Vec<u32>,
+    all_applications: bool,
+    origin: Option<u32>,
+    root_of_trust: Option<AndroidRootOfTrust>,
+    os_version: Option<u32>,
+    os_patch_level: Option<u32>,
+}
+
+#[derive(Debug)]
+struct KeyDescription {
+    attestation_version: u32,
+    attestation_security_level: AndroidSecurityLevel,
+    keymint_version: u32,
+    keymint_security_level: AndroidSecurityLevel,
+    attestation_challenge: Vec<u8>,
+    software_enforced: AuthorizationList,
+    hardware_enforced: AuthorizationList,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SafetyNetPayload {
+    nonce: String,
+    timestamp_ms: u64,
+    #[serde(default)]
+    cts_profile_match: bool,
+    #[serde(default)]
+    basic_integrity: bool,
+    apk_package_name: Option<String>,
+    #[serde(default)]
+    apk_certificate_digest_sha256: Vec<String>,
+    evaluation_type: Option<String>,
+}
+
+/// Verifies an `android-key` attestation statement for a newly created credential.
+#[instrument(skip_all)]
+pub fn verify_android_key(
+    statement: &Ctap2AttestationStatement,
+    authenticator_data: &[u8],
+    client_data_hash: &[u8],
+    credential_public_key: &PublicKey,
+) -> Result<AndroidKeyAttestation, VerificationError> {
+    let Ctap2AttestationStatement::PackedOrAndroid(statement) = statement else {
+        return Err(VerificationError::UnexpectedAttestationFormat);
+    };
+    let certificates: Vec<Vec<u8>> = statement.certificates.iter().map(|c| c.to_vec()).collect();
+    let chain = parse_chain(&certificates)?;
+    let leaf = &chain[0];
+
+    if statement.algorithm != Ctap2COSEAlgorithmIdentifier::ES256 {
+        return Err(VerificationError::UnsupportedAlgorithm);
+    }
+    let mut signed_data = authenticator_data.to_vec();
+    signed_data.extend_from_slice(client_data_hash);
+    verify_with_certificate(
+        leaf,
+        &signature::ECDSA_P256_SHA256_ASN1,
+        &signed_data,
+        &statement.signature,
+    )?;
+
+    let PublicKey::P256Key(key) = credential_public_key else {
+        return Err(VerificationError::UnsupportedPublicKey);
+    };
+    let mut credential_point = vec![0x04];
+    credential_point.extend_from_slice(&key.x);
+    credential_point.extend_from_slice(&key.y);
+    if leaf.public_key().subject_public_key.data.as_ref() != credential_point.as_slice() {
+        return Err(VerificationError::CredentialKeyMismatch);
+    }
+
+    let extension = leaf
+        .iter_extensions()
+        .find(|e| e.oid.to_id_string() == KEY_ATTESTATION_OID)
+        .ok_or(VerificationError::MissingKeyAttestation)?;
+    let description = parse_key_description(extension.value)?;
+    if description.attestation_challenge != client_data_hash {
+        return Err(VerificationError::ChallengeMismatch);
+    }
+
+    // The key must be scoped to the RP, be generated on the device, and be usable for signing.
+    let software = &description.software_enforced;
+    let hardware = &description.hardware_enforced;
+    if software.all_applications || hardware.all_applications {
+        return Err(VerificationError::KeyAttestationPolicy(
+            "key bound to all applications",
+        ));
+    }
+    let hardware_enforced = hardware.origin == Some(KM_ORIGIN_GENERATED)
+        && hardware.purposes.contains(&KM_PURPOSE_SIGN);
+    let software_enforced = software.origin == Some(KM_ORIGIN_GENERATED)
+        && software.purposes.contains(&KM_PURPOSE_SIGN);
+    if !hardware_enforced && !software_enforced {
+        return Err(VerificationError::KeyAttestationPolicy(
+            "key not generated on device for signing",
+        ));
+    }
+
+    debug!(
+        security_level = ?description.attestation_security_level,
+        hardware_enforced,
+        "Android key attestation verified"
+    );
+    Ok(AndroidKeyAttestation {
+        attestation_version: description.attestation_version,
+        attestation_security_level: description.attestation_security_level,
+        keymint_version: description.keymint_version,
+        keymint_security_level: description.keymint_security_level,
+        hardware_enforced,
+        root_of_trust: hardware.root_of_trust.or(software.root_of_trust),
+        os_version: hardware.os_version.or(software.os_version),
+        os_patch_level: hardware.os_patch_level.or(software.os_patch_level),
+        certificates,
+    })
+}
+
+/// Verifies an `android-safetynet` attestation statement. Responses whose timestamp is
+/// further than `max_age` from the current time are rejected.
+#[instrument(skip_all)]
+pub fn verify_android_safetynet(
+    statement: &Ctap2AttestationStatement,
+    authenticator_data: &[u8],
+    client_data_hash: &[u8],
+    max_age: Duration,
+) -> Result<SafetyNetAttestation, VerificationError> {
+    let Ctap2AttestationStatement::AndroidSafetyNet(statement) = statement else {
+        return Err(VerificationError::UnexpectedAttestationFormat);
+    };
+    let jws = std::str::from_utf8(&statement.response)
+        .ok()
+        .and_then(Jws::parse)
+        .ok_or(VerificationError::MalformedSafetyNetResponse)?;
+    let certificates = jws
+        .certificates()
+        .ok_or(VerificationError::MalformedSafetyNetResponse)?;
+    let chain = parse_chain(&certificates)?;
+    let leaf = &chain[0];
+    jws.verify(leaf)?;
+    if !certificate_has_hostname(leaf, SAFETYNET_HOSTNAME) {
+        return Err(VerificationError::UnexpectedCertificateHostname);
+    }
+
+    let payload: SafetyNetPayload = serde_json::from_slice(&jws.payload).map_err(|e| {
+        warn!(%e, "Failed to parse SafetyNet payload");
+        VerificationError::MalformedSafetyNetResponse
+    })?;
+    let mut signed_data = authenticator_data.to_vec();
+    signed_data.extend_from_slice(client_data_hash);
+    let expected_nonce = STANDARD.encode(Sha256::digest(&signed_data));
+    let timestamp = check_safetynet_payload(&payload, &expected_nonce, SystemTime::now(), max_age)?;
+
+    debug!(
+        basic_integrity = payload.basic_integrity,
+        evaluation_type = ?payload.evaluation_type,
+        "SafetyNet attestation verified"
+    );
+    Ok(SafetyNetAttestation {
+        version: statement.version.clone(),
+        timestamp,
+        cts_profile_match: payload.cts_profile_match,
+        basic_integrity: payload.basic_integrity,
+        apk_package_name: payload.apk_package_name,
+        apk_certificate_digests: payload.apk_certificate_digest_sha256,
+        evaluation_type: payload.evaluation_type,
+        certificates,
+    })
+}
+
+fn check_safetynet_payload(
+    payload: &SafetyNetPayload,
+    expected_nonce: &str,
+    now: SystemTime,
+    max_age: Duration,
+) -> Result<SystemTime, VerificationError> {
+    if payload.nonce != expected_nonce {
+        return Err(VerificationError::ChallengeMismatch);
+    }
+    let timestamp = UNIX_EPOCH + Duration::from_millis(payload.timestamp_ms);
+    let age = now
+        .duration_since(timestamp)
+        .unwrap_or_else(|e| e.duration());
+    if age > max_age {
+        warn!(
+            ?age,
+            "SafetyNet response is outside the accepted time window"
+        );
+        return Err(VerificationError::StaleSafetyNetResponse);
+    }
+    if !payload.cts_profile_match {
+        return Err(VerificationError::KeyAttestationPolicy(
+            "device failed the CTS profile check",
+        ));
+    }
+    Ok(timestamp)
+}
+
+fn parse_key_description(der: &[u8]) -> Result<KeyDescription, VerificationError> {
+    let (_, sequence) = Any::from_der(der).or(Err(VerificationError::MalformedKeyAttestation))?;
+    let mut fields = DerFields(sequence.data);
+    let attestation_version = fields.next()?.as_u32().or(Err(malformed()))?;
+    let attestation_security_level = security_level(fields.next()?)?;
+    let keymint_version = fields.next()?.as_u32().or(Err(malformed()))?;
+    let keymint_security_level = security_level(fields.next()?)?;
+    let attestation_challenge = fields.next()?.data.to_vec();
+    let _unique_id = fields.next()?;
+    Ok(KeyDescription {
+        attestation_version,
+        attestation_security_level,
+        keymint_version,
+        keymint_security_level,
+        attestation_challenge,
+        software_enforced: authorization_list(fields.next()?)?,
+        hardware_enforced: authorization_list(fields.next()?)?,
+    })
+}
+
+fn authorization_list(list: Any) -> Result<AuthorizationList, VerificationError> {
+    let mut parsed = AuthorizationList::default();
+    let mut entries = DerFields(list.data);
+    while let Some(entry) = entries.next_optional()? {
+        if entry.header.class() != Class::ContextSpecific {
+            return Err(malformed());
+        }
+        let (_, value) = Any::from_der(entry.data).or(Err(malformed()))?;
+        match entry.header.tag().0 {
+            TAG_PURPOSE => {
+                let mut purposes = DerFields(value.data);
+                while let Some(purpose) = purposes.next_optional()? {
+                    parsed.purposes.push(purpose.as_u32().or(Err(malformed()))?);
+                }
+            }
+            TAG_ALL_APPLICATIONS => parsed.all_applications = true,
+            TAG_ORIGIN => parsed.origin = Some(value.as_u32().or(Err(malformed()))?),
+            TAG_ROOT_OF_TRUST => {
+                let mut fields = DerFields(value.data);
+                let _verified_boot_key = fields.next()?;
+                let device_locked = fields.next()?.as_bool().or(Err(malformed()))?;
+                let verified_boot_state = match fields.next()?.as_enumerated() {
+                    Ok(state) if state.0 == 0 => VerifiedBootState::Verified,
+                    Ok(state) if state.0 == 1 => VerifiedBootState::SelfSigned,
+                    Ok(state) if state.0 == 2 => VerifiedBootState::Unverified,
+                    Ok(state) if state.0 == 3 => VerifiedBootState::Failed,
+                    _ => return Err(malformed()),
+                };
+                parsed.root_of_trust = Some(AndroidRootOfTrust {
+                    device_locked,
+                    verified_boot_state,
+                });
+            }
+            TAG_OS_VERSION => parsed.os_version = value.as_u32().ok(),
+            TAG_OS_PATCH_LEVEL => parsed.os_patch_level = value.as_u32().ok(),
+            _ => {}
+        }
+    }
+    Ok(parsed)
+}
+
+fn security_level(value: Any) -> Result<AndroidSecurityLevel, VerificationError> {
+    match value.as_enumerated().or(Err(malformed()))?.0 {
+        0 => Ok(AndroidSecurityLevel::Software),
+        1 => Ok(AndroidSecurityLevel::TrustedEnvironment),
+        2 => Ok(AndroidSecurityLevel::StrongBox),
+        _ => Err(malformed()),
+    }
+}
+
+fn malformed() -> VerificationError {
+    VerificationError::MalformedKeyAttestation
+}
+
+/// Iterates over the DER elements of a constructed value's content.
+struct DerFields<'a>(&'a [u8]);
+
+impl<'a> DerFields<'a> {
+    fn next(&mut self) -> Result<Any<'a>, VerificationError> {
+        self.next_optional()?.ok_or_else(malformed)
+    }
+
+    fn next_optional(&mut self) -> Result<Option<Any<'a>>, VerificationError> {
+        if self.0.is_empty() {
+            return Ok(None);
+        }
+        let (rest, value) = Any::from_der(self.0).or(Err(malformed()))?;
+        self.0 = rest;
+        Ok(Some(value))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde_bytes::ByteBuf;
+
+    use crate::proto::ctap2::SafetyNetAttestationStmt;
+
+    fn tlv(tag: &[u8], content: &[u8]) -> Vec<u8> {
+        let mut out = tag.to_vec();
+        if content.len() < 0x80 {
+            out.push(content.len() as u8);
+        } else {
+            out.push(0x81);
+            out.push(content.len() as u8);
+        }
+        out.extend_from_slice(content);
+        out
+    }
+
+    fn key_description(challenge: &[u8], hardware_enforced: &[u8]) -> Vec<u8> {
+        let purpose = tlv(
+            &[0xa1],
+            &tlv(&[0x31], &tlv(&[0x02], &[KM_PURPOSE_SIGN as u8])),
+        );
+        let origin = tlv(&[0xbf, 0x85, 0x3e], &tlv(&[0x02], &[0x00]));
+        let root_of_trust = tlv(
+            &[0xbf, 0x85, 0x40],
+            &tlv(
+                &[0x30],
+                &[
+                    tlv(&[0x04], &[0xaa; 32]),
+                    tlv(&[0x01], &[0xff]),
+                    tlv(&[0x0a], &[0x00]),
+                ]
+                .concat(),
+            ),
+        );
+        let hardware = if hardware_enforced.is_empty() {
+            [purpose, origin, root_of_trust].concat()
+        } else {
+            hardware_enforced.to_vec()
+        };
+        tlv(
+            &[0x30],
+            &[
+                tlv(&[0x02], &[0x64]),
+                tlv(&[0x0a], &[0x01]),
+                tlv(&[0x02], &[0x64]),
+                tlv(&[0x0a], &[0x02]),
+                tlv(&[0x04], challenge),
+                tlv(&[0x04], &[]),
+                tlv(&[0x30], &[]),
+                tlv(&[0x30], &hardware),
+            ]
+            .concat(),
+        )
+    }
+
+    #[test]
+    fn parse_key_description_fields() {
+        let challenge = [0x11; 32];
+        let description = parse_key_description(&key_description(&challenge, &[])).unwrap();
+        assert_eq!(description.attestation_version, 100);
+        assert_eq!(
+            description.attestation_security_level,
+            AndroidSecurityLevel::TrustedEnvironment
+        );
+        assert_eq!(
+            description.keymint_security_level,
+            AndroidSecurityLevel::StrongBox
+        );
+        assert_eq!(description.attestation_challenge, challenge);
+        assert_eq!(
+            description.hardware_enforced.purposes,
+            vec![KM_PURPOSE_SIGN]
+        );
+        assert_eq!(
+            description.hardware_enforced.origin,
+            Some(KM_ORIGIN_GENERATED)
+        );
+        assert_eq!(
+            description.hardware_enforced.root_of_trust,
+            Some(AndroidRootOfTrust {
+                device_locked: true,
+                verified_boot_state: VerifiedBootState::Verified,
+            })
+        );
+        assert!(!description.hardware_enforced.all_applications);
+        assert!(description.software_enforced.purposes.is_empty());
+    }
+
+    #[test]
+    fn parse_key_description_all_applications() {
+        let all_applications = tlv(&[0xbf, 0x84, 0x58], &tlv(&[0x05], &[]));
+        let description =
+            parse_key_description(&key_description(&[0x11; 32], &all_applications)).unwrap();
+        assert!(description.hardware_enforced.all_applications);
+        assert!(parse_key_description(&[0x30, 0x03, 0x02, 0x01]).is_err());
+    }
+
+    #[test]
+    fn check_safetynet_payload_nonce_and_age() {
+        let now = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let payload = SafetyNetPayload {
+            nonce: "bm9uY2U=".to_owned(),
+            timestamp_ms: 1_700_000_000_000 - 30_000,
+            cts_profile_match: true,
+            basic_integrity: true,
+            apk_package_name: Some("com.google.android.gms".to_owned()),
+            apk_certificate_digest_sha256: vec![],
+            evaluation_type: Some("BASIC,HARDWARE_BACKED".to_owned()),
+        };
+        let max_age = Duration::from_secs(60);
+        assert!(check_safetynet_payload(&payload, "bm9uY2U=", now, max_age).is_ok());
+        assert_eq!(
+            check_safetynet_payload(&payload, "b3RoZXI=", now, max_age).unwrap_err(),
+            VerificationError::ChallengeMismatch
+        );
+        assert_eq!(
+            check_safetynet_payload(&payload, "bm9uY2U=", now, Duration::from_secs(10))
+                .unwrap_err(),
+            VerificationError::StaleSafetyNetResponse
+        );
+    }
+
+    #[test]
+    fn reject_malformed_safetynet_response() {
+        let statement = Ctap2AttestationStatement::AndroidSafetyNet(SafetyNetAttestationStmt {
+            version: "12345".to_owned(),
+            response: ByteBuf::from(b"not-a-jws".to_vec()),
+        });
+        assert_eq!(
+            verify_android_safetynet(&statement, &[], &[], Duration::from_secs(60)).unwrap_err(),
+            VerificationError::MalformedSafetyNetResponse
+        );
+        let statement = Ctap2AttestationStatement::None(Default::default());
+        assert_eq!(
+            verify_android_safetynet(&statement, &[], &[], Duration::from_secs(60)).unwrap_err(),
+            VerificationError::UnexpectedAttestationFormat
+        );
+    }
+}
@@ -0,0 +1,61 @@
+//! X.509 helpers shared by the attestation and metadata verifiers.
+
+use ring::signature::{UnparsedPublicKey, VerificationAlgorithm};
+use tracing::warn;
+use x509_parser::extensions::GeneralName;
+use x509_parser::prelude::{FromDer, X509Certificate};
+
+use super::VerificationError;
+
+/// Parses a leaf-first certificate chain and checks each certificate is signed by the next one.
+pub(crate) fn parse_chain(
+    certificates: &[Vec<u8>],
+) -> Result<Vec<X509Certificate<'_>>, VerificationError> {
+    let chain = certificates
+        .iter()
+        .map(|der| X509Certificate::from_der(der).map(|(_, cert)| cert))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| {
+            warn!(%e, "Failed to parse attestation certificate");
+            VerificationError::MalformedCertificate
+        })?;
+    if chain.is_empty() {
+        return Err(VerificationError::MalformedCertificate);
+    }
+    for pair in chain.windows(2) {
+        pair[0]
+            .verify_signature(Some(pair[1].public_key()))
+            .or(Err(VerificationError::InvalidCertificateChain))?;
+    }
+    Ok(chain)
+}
+
+pub(crate) fn verify_with_certificate(
+    certificate: &X509Certificate,
+    algorithm: &'static dyn VerificationAlgorithm,
+    signed_data: &[u8],
+    signature: &[u8],
+) -> Result<(), VerificationError> {
+    let key = &certificate.public_key().subject_public_key.data;
+    UnparsedPublicKey::new(algorithm, key.as_ref())
+        .verify(signed_data, signature)
+        .or(Err(VerificationError::InvalidSignature))
+}
+
+pub(crate) fn certificate_has_hostname(certificate: &X509Certificate, hostname: &str) -> bool {
+    let in_alt_names = certificate
+        .subject_alternative_name()
+        .ok()
+        .flatten()
+        .is_some_and(|san| {
+            san.value
+                .general_names
+                .iter()
+                .any(|name| matches!(name, GeneralName::DNSName(dns) if *dns == hostname))
+        });
+    in_alt_names
+        || certificate
+            .subject()
+            .iter_common_name()
+            .any(|cn| cn.as_str() == Ok(hostname))
+}
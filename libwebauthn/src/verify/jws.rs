@@ -0,0 +1,87 @@
+//! Compact JWS (RFC 7515) as used by SafetyNet responses and the FIDO metadata service.
+
+use base64_url::base64::engine::general_purpose::STANDARD;
+use base64_url::base64::Engine;
+use ring::signature::{self, VerificationAlgorithm};
+use serde::Deserialize;
+use x509_parser::prelude::X509Certificate;
+
+use super::certificate::verify_with_certificate;
+use super::VerificationError;
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct JwsHeader {
+    pub alg: String,
+    #[serde(default)]
+    pub x5c: Vec<String>,
+}
+
+#[derive(Debug)]
+pub(crate) struct Jws<'a> {
+    pub header: JwsHeader,
+    pub payload: Vec<u8>,
+    signing_input: &'a str,
+    signature: Vec<u8>,
+}
+
+impl<'a> Jws<'a> {
+    pub fn parse(jws: &'a str) -> Option<Self> {
+        let (signing_input, signature) = jws.rsplit_once('.')?;
+        let (header, payload) = signing_input.split_once('.')?;
+        if payload.contains('.') {
+            return None;
+        }
+        let header = serde_json::from_slice(&base64_url::decode(header).ok()?).ok()?;
+        Some(Self {
+            header,
+            payload: base64_url::decode(payload).ok()?,
+            signing_input,
+            signature: base64_url::decode(signature).ok()?,
+        })
+    }
+
+    /// The DER certificates from the `x5c` header, leaf first.
+    pub fn certificates(&self) -> Option<Vec<Vec<u8>>> {
+        self.header
+            .x5c
+            .iter()
+            .map(|c| STANDARD.decode(c).ok())
+            .collect()
+    }
+
+    pub fn verify(&self, signer: &X509Certificate) -> Result<(), VerificationError> {
+        let algorithm: &dyn VerificationAlgorithm = match self.header.alg.as_str() {
+            "RS256" => &signature::RSA_PKCS1_2048_8192_SHA256,
+            "ES256" => &signature::ECDSA_P256_SHA256_FIXED,
+            _ => return Err(VerificationError::UnsupportedAlgorithm),
+        };
+        verify_with_certificate(
+            signer,
+            algorithm,
+            self.signing_input.as_bytes(),
+            &self.signature,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Jws;
+
+    #[test]
+    fn parse_compact_jws() {
+        let header = base64_url::encode(r#"{"alg":"ES256","x5c":["AQID"]}"#);
+        let payload = base64_url::encode("{}");
+        let signature = base64_url::encode(&[9, 9]);
+        let compact = format!("{header}.{payload}.{signature}");
+
+        let jws = Jws::parse(&compact).unwrap();
+        assert_eq!(jws.header.alg, "ES256");
+        assert_eq!(jws.payload, b"{}");
+        assert_eq!(jws.certificates(), Some(vec![vec![1, 2, 3]]));
+        assert_eq!(jws.signing_input, format!("{header}.{payload}"));
+
+        assert!(Jws::parse("not-a-jws").is_none());
+        assert!(Jws::parse(&format!("{compact}.extra")).is_none());
+    }
+}
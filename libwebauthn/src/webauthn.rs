@@ -1,24 +1,36 @@
 pub mod error;
 pub mod pin_uv_auth_token;
+pub mod replay;
+
+use std::time::Instant;
 
 use async_trait::async_trait;
-use tracing::{debug, error, info, instrument, trace, warn};
+use tracing::{debug, error, field::Empty, info, instrument, trace, warn};
 
+use crate::audit::{self, AuditUvMethod};
+use crate::correlation::CorrelationId;
 use crate::fido::FidoProtocol;
+use crate::metrics::{self, Operation};
 use crate::ops::u2f::{RegisterRequest, SignRequest, UpgradableResponse};
-use crate::ops::webauthn::{DowngradableRequest, GetAssertionRequest, GetAssertionResponse};
+use crate::ops::webauthn::{
+    Ctap1Fallback, DowngradableRequest, GetAssertionHmacOrPrfInput, GetAssertionRequest,
+    GetAssertionResponse,
+};
 use crate::ops::webauthn::{MakeCredentialRequest, MakeCredentialResponse};
 use crate::proto::ctap1::Ctap1;
-use crate::proto::ctap2::preflight::ctap2_preflight;
+use crate::proto::ctap2::preflight::{ctap2_preflight, ctap2_probe};
 use crate::proto::ctap2::{
-    Ctap2, Ctap2ClientPinRequest, Ctap2GetAssertionRequest, Ctap2MakeCredentialRequest,
+    check_max_msg_size, Ctap2, Ctap2ClientPinRequest, Ctap2GetAssertionRequest,
+    Ctap2MakeCredentialRequest, HMAC_SECRET_ALLOWANCE, PIN_UV_AUTH_PARAM_ALLOWANCE,
 };
-pub use crate::transport::error::TransportError;
-use crate::transport::Channel;
+use crate::session_gate;
+use crate::timeout::OperationDeadline;
+pub use crate::transport::error::{CableStage, TransportError};
+use crate::transport::{Channel, MAX_DEVICE_LOCK_DURATION};
 pub use crate::webauthn::error::{CtapError, Error, PlatformError};
 use crate::UvUpdate;
 
-use pin_uv_auth_token::{user_verification, UsedPinUvAuthToken};
+use pin_uv_auth_token::{check_always_uv, user_verification, UsedPinUvAuthToken};
 
 macro_rules! handle_errors {
     ($channel: expr, $resp: expr, $uv_auth_used: expr, $timeout: expr) => {
@@ -30,6 +42,12 @@ macro_rules! handle_errors {
                 $channel.clear_uv_auth_token_store();
                 continue;
             }
+            Err(Error::Transport(crate::transport::error::TransportError::DeviceReconnected)) => {
+                // The device was power-cycled, so any token we hold is gone too.
+                info!("Device reconnected: Clearing auth token storage and trying again.");
+                $channel.clear_uv_auth_token_store();
+                continue;
+            }
             Err(Error::Ctap(CtapError::UVInvalid)) => {
                 let attempts_left = $channel
                     .ctap2_client_pin(&Ctap2ClientPinRequest::new_get_uv_retries(), $timeout)
@@ -77,7 +95,29 @@ pub trait WebAuthn {
         &mut self,
         op: &GetAssertionRequest,
     ) -> Result<GetAssertionResponse, Error>;
-    async fn _negotiate_protocol(&mut self, allow_u2f: bool) -> Result<FidoProtocol, Error>;
+    async fn _negotiate_protocol(&mut self, fallback: Ctap1Fallback)
+        -> Result<FidoProtocol, Error>;
+}
+
+/// The fallback policy of `op`, or [Ctap1Fallback::Never] if U2F can't fulfill it.
+fn ctap1_fallback<T>(op: &impl DowngradableRequest<T>, fallback: Ctap1Fallback) -> Ctap1Fallback {
+    if op.is_downgradable() {
+        fallback
+    } else {
+        Ctap1Fallback::Never
+    }
+}
+
+/// Whether to retry over U2F after FIDO2 failed because the PIN is blocked.
+async fn falls_back_on_pin_blocked<C: Channel>(channel: &C, fallback: Ctap1Fallback) -> bool {
+    if fallback != Ctap1Fallback::WhenRequired {
+        return false;
+    }
+    let u2f = matches!(channel.supported_protocols().await, Ok(supported) if supported.u2f);
+    if u2f {
+        warn!("PIN is blocked, falling back from FIDO2 to FIDO U2F");
+    }
+    u2f
 }
 
 #[async_trait]
@@ -85,37 +125,76 @@ impl<C> WebAuthn for C
 where
     C: Channel,
 {
-    #[instrument(skip_all, fields(dev = % self))]
+    #[instrument(skip_all, err(level = "warn"), fields(dev = % self, correlation_id = Empty))]
     async fn webauthn_make_credential(
         &mut self,
         op: &MakeCredentialRequest,
     ) -> Result<MakeCredentialResponse, Error> {
-        trace!(?op, "WebAuthn MakeCredential request");
-        let protocol = self._negotiate_protocol(op.is_downgradable()).await?;
-        match protocol {
-            FidoProtocol::FIDO2 => self._webauthn_make_credential_fido2(op).await,
-            FidoProtocol::U2F => self._webauthn_make_credential_u2f(op).await,
+        let correlation_id = CorrelationId::for_operation();
+        let started = Instant::now();
+        let result = correlation_id
+            .scope(async {
+                trace!(?op, "WebAuthn MakeCredential request");
+                self.announce_operation(op.into());
+                session_gate::wait_for_open(self, op.timeout).await?;
+                let fallback = ctap1_fallback(op, op.ctap1_fallback);
+                let protocol = self._negotiate_protocol(fallback).await?;
+                match protocol {
+                    FidoProtocol::FIDO2 => match self._webauthn_make_credential_fido2(op).await {
+                        Err(Error::Ctap(CtapError::PINBlocked))
+                            if falls_back_on_pin_blocked(self, fallback).await =>
+                        {
+                            self._webauthn_make_credential_u2f(op).await
+                        }
+                        result => result,
+                    },
+                    FidoProtocol::U2F => self._webauthn_make_credential_u2f(op).await,
+                }
+            })
+            .await;
+        if let Some(recorder) = self.get_metrics_recorder() {
+            metrics::record_operation(
+                recorder.as_ref(),
+                Operation::MakeCredential,
+                self.transport_name(),
+                started,
+                &result,
+            );
         }
+        audit::record_make_credential(self, correlation_id, op, &result);
+        result
     }
 
     async fn _webauthn_make_credential_fido2(
         &mut self,
         op: &MakeCredentialRequest,
     ) -> Result<MakeCredentialResponse, Error> {
+        let deadline = OperationDeadline::new(self.get_timeout_policy(), op.timeout);
         let get_info_response = self.ctap2_get_info().await?;
         let mut ctap2_request =
             Ctap2MakeCredentialRequest::from_webauthn_request(op, &get_info_response)?;
-        if Self::supports_preflight() {
+        if self.supports_preflight() {
             if let Some(exclude_list) = &op.exclude {
                 let filtered_exclude_list =
                     ctap2_preflight(self, exclude_list, &op.hash, &op.relying_party.id).await;
                 ctap2_request.exclude = Some(filtered_exclude_list);
             }
         }
+        check_max_msg_size(
+            &(&ctap2_request).into(),
+            PIN_UV_AUTH_PARAM_ALLOWANCE,
+            &get_info_response,
+        )?;
+        let always_uv_enforced = check_always_uv(
+            self,
+            op.user_verification,
+            op.always_uv_policy,
+            &get_info_response,
+        )
+        .await?;
+        let mut uv_auth_used;
         let response = loop {
-            let uv_auth_used =
-                user_verification(self, op.user_verification, &mut ctap2_request, op.timeout)
-                    .await?;
+            uv_auth_used = user_verification(self, op.into(), &mut ctap2_request, deadline).await?;
 
             // We've already sent out this update, in case we used builtin UV
             // but if we used PIN, we need to touch the device now.
@@ -124,12 +203,18 @@ where
             }
             handle_errors!(
                 self,
-                self.ctap2_make_credential(&ctap2_request, op.timeout).await,
+                self.ctap2_make_credential(&ctap2_request, deadline).await,
                 uv_auth_used,
-                op.timeout
+                deadline
             )
         }?;
-        let make_cred = response.into_make_credential_output(op, Some(&get_info_response));
+        let mut make_cred = response.into_make_credential_output(op, Some(&get_info_response));
+        make_cred.always_uv_enforced = always_uv_enforced;
+        make_cred.uv_method = AuditUvMethod::from_ceremony(
+            &make_cred.authenticator_data,
+            uv_auth_used,
+            self.get_auth_data(),
+        );
         Ok(make_cred)
     }
 
@@ -145,30 +230,68 @@ where
             .try_upgrade(op)
     }
 
-    #[instrument(skip_all, fields(dev = % self))]
+    #[instrument(skip_all, err(level = "warn"), fields(dev = % self, correlation_id = Empty))]
     async fn webauthn_get_assertion(
         &mut self,
         op: &GetAssertionRequest,
     ) -> Result<GetAssertionResponse, Error> {
-        trace!(?op, "WebAuthn GetAssertion request");
-        let protocol = self._negotiate_protocol(op.is_downgradable()).await?;
-        match protocol {
-            FidoProtocol::FIDO2 => self._webauthn_get_assertion_fido2(op).await,
-            FidoProtocol::U2F => self._webauthn_get_assertion_u2f(op).await,
+        let correlation_id = CorrelationId::for_operation();
+        let started = Instant::now();
+        let result = correlation_id
+            .scope(async {
+                trace!(?op, "WebAuthn GetAssertion request");
+                self.announce_operation(op.into());
+                session_gate::wait_for_open(self, op.timeout).await?;
+                let fallback = ctap1_fallback(op, op.ctap1_fallback);
+                let protocol = self._negotiate_protocol(fallback).await?;
+                match protocol {
+                    FidoProtocol::FIDO2 => match self._webauthn_get_assertion_fido2(op).await {
+                        Err(Error::Ctap(CtapError::PINBlocked))
+                            if falls_back_on_pin_blocked(self, fallback).await =>
+                        {
+                            self._webauthn_get_assertion_u2f(op).await
+                        }
+                        result => result,
+                    },
+                    FidoProtocol::U2F => self._webauthn_get_assertion_u2f(op).await,
+                }
+            })
+            .await;
+        if let Some(recorder) = self.get_metrics_recorder() {
+            metrics::record_operation(
+                recorder.as_ref(),
+                Operation::GetAssertion,
+                self.transport_name(),
+                started,
+                &result,
+            );
         }
+        audit::record_get_assertion(self, correlation_id, op, &result);
+        result
     }
 
     async fn _webauthn_get_assertion_fido2(
         &mut self,
         op: &GetAssertionRequest,
     ) -> Result<GetAssertionResponse, Error> {
+        let deadline = OperationDeadline::new(self.get_timeout_policy(), op.timeout);
         let get_info_response = self.ctap2_get_info().await?;
         let mut ctap2_request =
             Ctap2GetAssertionRequest::from_webauthn_request(op, &get_info_response)?;
 
-        if Self::supports_preflight() {
-            let filtered_allow_list =
+        if self.supports_preflight() {
+            let mut filtered_allow_list =
                 ctap2_preflight(self, &op.allow, &op.hash, &op.relying_party_id).await;
+            if let Some(app_id) = op.app_id() {
+                if filtered_allow_list.is_empty() && !op.allow.is_empty() {
+                    // Credentials registered over U2F are only found under the AppID.
+                    filtered_allow_list = ctap2_preflight(self, &op.allow, &op.hash, app_id).await;
+                    if !filtered_allow_list.is_empty() {
+                        debug!("Found credentials under the AppID, asserting with it");
+                        ctap2_request.relying_party_id = app_id.to_owned();
+                    }
+                }
+            }
             if filtered_allow_list.is_empty() && !op.allow.is_empty() {
                 // We filtered out everything in preflight, meaning none of the allowed
                 // credentials are present on this device. So we error out here
@@ -177,44 +300,94 @@ where
                 warn!("Preflight removed all credentials from the allow-list. Sending dummy request and erroring out.");
                 let dummy_request: Ctap2MakeCredentialRequest = Ctap2MakeCredentialRequest::dummy();
                 self.send_ux_update(UvUpdate::PresenceRequired.into()).await;
-                let _ = self.ctap2_make_credential(&dummy_request, op.timeout).await;
+                if let Err(err) = self.ctap2_make_credential(&dummy_request, deadline).await {
+                    debug!(?err, "Dummy request failed");
+                }
                 return Err(Error::Ctap(CtapError::NoCredentials));
             }
             ctap2_request.allow = filtered_allow_list;
+        } else if let Some(app_id) = op.app_id() {
+            // Without pre-flight, a single silent request tells whether the credentials were
+            // registered over U2F, before the user is asked for anything.
+            if !op.allow.is_empty() && ctap2_probe(self, &op.allow, &op.hash, app_id).await {
+                debug!("Found credentials under the AppID, asserting with it");
+                ctap2_request.relying_party_id = app_id.to_owned();
+            }
         }
 
+        let mut allowance = PIN_UV_AUTH_PARAM_ALLOWANCE;
+        if ctap2_request.extensions.as_ref().is_some_and(|extensions| {
+            !matches!(extensions.hmac_or_prf, GetAssertionHmacOrPrfInput::None)
+        }) {
+            allowance += HMAC_SECRET_ALLOWANCE;
+        }
+        check_max_msg_size(&(&ctap2_request).into(), allowance, &get_info_response)?;
+        let always_uv_enforced = check_always_uv(
+            self,
+            op.user_verification,
+            op.always_uv_policy,
+            &get_info_response,
+        )
+        .await?;
+        let mut uv_auth_used;
         let response = loop {
-            let uv_auth_used =
-                user_verification(self, op.user_verification, &mut ctap2_request, op.timeout)
-                    .await?;
+            uv_auth_used = user_verification(self, op.into(), &mut ctap2_request, deadline).await?;
 
-            // We've already sent out this update, in case we used builtin UV
-            // but if we used PIN, we need to touch the device now.
-            if self.used_pin_for_auth() {
-                self.send_ux_update(UvUpdate::PresenceRequired.into()).await;
-            }
             if let Some(auth_data) = self.get_auth_data() {
                 if let Some(e) = ctap2_request.extensions.as_mut() {
                     e.calculate_hmac(&op.allow, auth_data)?;
                 }
             }
+            // We've already sent out this update, in case we used builtin UV
+            // but if we used PIN, we need to touch the device now.
+            if self.used_pin_for_auth() {
+                self.send_ux_update(UvUpdate::PresenceRequired.into()).await;
+            }
 
             handle_errors!(
                 self,
-                self.ctap2_get_assertion(&ctap2_request, op.timeout).await,
+                self.ctap2_get_assertion(&ctap2_request, deadline).await,
                 uv_auth_used,
-                op.timeout
+                deadline
             )
         }?;
         let count = response.credentials_count.unwrap_or(1);
+        let uv_method = AuditUvMethod::from_ceremony(
+            &response.authenticator_data,
+            uv_auth_used,
+            self.get_auth_data(),
+        );
         let mut assertions = vec![response.into_assertion_output(op, self.get_auth_data())];
-        for i in 1..count {
-            debug!({ i }, "Fetching additional credential");
-            // GetNextAssertion doesn't use PinUVAuthToken, so we don't need to check uv_auth_used here
-            let response = self.ctap2_get_next_assertion(op.timeout).await?;
-            assertions.push(response.into_assertion_output(op, self.get_auth_data()));
+        if count > 1 {
+            // Any other request in between would discard the remaining credentials.
+            if let Err(err) = self.lock_device(MAX_DEVICE_LOCK_DURATION).await {
+                debug!(
+                    ?err,
+                    "Failed to lock device, fetching credentials without a lock"
+                );
+            }
+        }
+        let result = async {
+            for i in 1..count {
+                debug!({ i }, "Fetching additional credential");
+                // GetNextAssertion doesn't use PinUVAuthToken, so we don't need to check uv_auth_used here
+                let response = self.ctap2_get_next_assertion(deadline).await?;
+                assertions.push(response.into_assertion_output(op, self.get_auth_data()));
+            }
+            Ok::<(), Error>(())
+        }
+        .await;
+        if let Err(err) = self.unlock_device().await {
+            debug!(?err, "Failed to unlock device");
         }
-        Ok(assertions.as_slice().into())
+        result?;
+        for assertion in assertions.iter_mut() {
+            assertion.set_app_id_output(op);
+        }
+        let mut response: GetAssertionResponse = assertions.as_slice().into();
+        response.always_uv_enforced = always_uv_enforced;
+        response.uv_method = uv_method;
+        Ok(response)
     }
 
     async fn _webauthn_get_assertion_u2f(
@@ -228,7 +401,11 @@ where
             match self.ctap1_sign(&sign_request).await {
                 Ok(response) => {
                     debug!("Found successful candidate in allowList");
-                    return response.try_upgrade(&sign_request);
+                    let mut response = response.try_upgrade(&sign_request)?;
+                    for assertion in response.assertions.iter_mut() {
+                        assertion.set_app_id_output(op);
+                    }
+                    return Ok(response);
                 }
                 Err(Error::Ctap(CtapError::NoCredentials)) => {
                     debug!("No credentials found, trying with the next.");
@@ -247,22 +424,30 @@ where
     }
 
     #[instrument(skip_all)]
-    async fn _negotiate_protocol(&mut self, allow_u2f: bool) -> Result<FidoProtocol, Error> {
+    async fn _negotiate_protocol(
+        &mut self,
+        fallback: Ctap1Fallback,
+    ) -> Result<FidoProtocol, Error> {
         let supported = self.supported_protocols().await?;
         if !supported.u2f && !supported.fido2 {
             return Err(Error::Transport(TransportError::NegotiationFailed));
         }
 
-        if !allow_u2f && !supported.fido2 {
+        if fallback == Ctap1Fallback::Never && !supported.fido2 {
             return Err(Error::Transport(TransportError::NegotiationFailed));
         }
 
-        let fido_protocol = if supported.fido2 {
-            FidoProtocol::FIDO2
-        } else {
+        let use_u2f = match fallback {
+            Ctap1Fallback::Never => false,
+            Ctap1Fallback::WhenRequired => !supported.fido2,
+            Ctap1Fallback::Always => supported.u2f,
+        };
+        let fido_protocol = if use_u2f {
             // Ensure CTAP1 version is reported correctly.
             self.ctap1_version().await?;
             FidoProtocol::U2F
+        } else {
+            FidoProtocol::FIDO2
         };
 
         if fido_protocol == FidoProtocol::U2F {
@@ -273,3 +458,111 @@ where
         Ok(fido_protocol)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use serde_bytes::ByteBuf;
+    use sha2::{Digest, Sha256};
+
+    use crate::fido::{AuthenticatorData, AuthenticatorDataFlags};
+    use crate::ops::webauthn::{
+        GetAssertionRequest, GetAssertionRequestExtensions, UserVerificationRequirement,
+    };
+    use crate::proto::ctap2::cbor::{self, Value};
+    use crate::proto::ctap2::{
+        Ctap2CommandCode, Ctap2GetAssertionResponse, Ctap2GetInfoResponse,
+        Ctap2PublicKeyCredentialDescriptor, Ctap2PublicKeyCredentialType,
+    };
+    use crate::testing::MockChannel;
+    use crate::webauthn::WebAuthn;
+
+    const APP_ID: &str = "https://example.org/appid.json";
+
+    fn assertion_under(rp_id: &str) -> Ctap2GetAssertionResponse {
+        Ctap2GetAssertionResponse {
+            credential_id: None,
+            authenticator_data: AuthenticatorData {
+                rp_id_hash: Sha256::digest(rp_id).into(),
+                flags: AuthenticatorDataFlags::USER_PRESENT,
+                signature_count: 1,
+                attested_credential: None,
+                extensions: None,
+            },
+            signature: ByteBuf::from(vec![0; 64]),
+            user: None,
+            credentials_count: None,
+            user_selected: None,
+            large_blob_key: None,
+            enterprise_attestation: None,
+            attestation_statement: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn app_id_is_probed_without_preflight() {
+        let info = Ctap2GetInfoResponse {
+            versions: vec!["FIDO_2_0".to_owned()],
+            ..Default::default()
+        };
+        let mut channel = MockChannel::new();
+        channel
+            .without_preflight()
+            .expect(Ctap2CommandCode::AuthenticatorGetInfo, &info)
+            .expect(
+                Ctap2CommandCode::AuthenticatorGetAssertion,
+                &assertion_under(APP_ID),
+            )
+            .expect(Ctap2CommandCode::AuthenticatorGetInfo, &info)
+            .expect(
+                Ctap2CommandCode::AuthenticatorGetAssertion,
+                &assertion_under(APP_ID),
+            );
+        let request = GetAssertionRequest::builder()
+            .rp_id("example.org")
+            .client_data_hash(&[1; 32])
+            .allow_credential(Ctap2PublicKeyCredentialDescriptor {
+                r#type: Ctap2PublicKeyCredentialType::PublicKey,
+                id: ByteBuf::from(b"u2f".to_vec()),
+                transports: None,
+            })
+            .extensions(GetAssertionRequestExtensions {
+                app_id: Some(APP_ID.to_owned()),
+                ..Default::default()
+            })
+            .user_verification(UserVerificationRequirement::Discouraged)
+            .build()
+            .unwrap();
+
+        let response = channel.webauthn_get_assertion(&request).await.unwrap();
+        channel.assert_done();
+        let assertion = &response.assertions[0];
+        assert_eq!(
+            assertion
+                .unsigned_extensions_output
+                .as_ref()
+                .unwrap()
+                .app_id,
+            Some(true)
+        );
+
+        let get_assertions: Vec<BTreeMap<u8, Value>> = channel
+            .requests()
+            .iter()
+            .filter(|r| r.command == Ctap2CommandCode::AuthenticatorGetAssertion)
+            .map(|r| cbor::from_slice(&r.encoded_data).unwrap())
+            .collect();
+        // The probe doesn't ask for the user's presence, the assertion then uses the AppID.
+        let rp_id = Value::Text(APP_ID.to_owned());
+        let Some(Value::Map(probe_options)) = get_assertions[0].get(&0x05) else {
+            panic!("Probe without options: {:?}", get_assertions[0]);
+        };
+        assert_eq!(
+            probe_options.get(&Value::Text("up".to_owned())),
+            Some(&Value::Bool(false))
+        );
+        assert_eq!(get_assertions[0].get(&0x01), Some(&rp_id));
+        assert_eq!(get_assertions[1].get(&0x01), Some(&rp_id));
+    }
+}
@@ -1,3 +1,4 @@
+use crate::pin::PinPolicyViolation;
 pub use crate::proto::CtapError;
 use crate::{proto::ctap2::cbor::CborError, webauthn::TransportError};
 
@@ -11,16 +12,76 @@ pub enum Error {
     Platform(#[from] PlatformError),
 }
 
+/// How to recover from an error, see [Error::recommended_action].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecommendedAction {
+    /// Send the request again, e.g. after the device was busy or the connection dropped.
+    Retry,
+    /// Send the request again after the user acted, e.g. entered the correct PIN or touched
+    /// the device in time.
+    RetryAfterUserAction,
+    /// Ask the user to unplug and replug the device, or to power-cycle it, before retrying.
+    ReplugDevice,
+    /// Retrying won't help.
+    Fatal,
+}
+
+impl Error {
+    /// Classifies the error for retry loops, generalizing
+    /// [CtapError::is_retryable_user_error].
+    pub fn recommended_action(&self) -> RecommendedAction {
+        match self {
+            Error::Transport(error) => error.recommended_action(),
+            Error::Ctap(error) => error.recommended_action(),
+            Error::Platform(error) => error.recommended_action(),
+        }
+    }
+
+    /// The WebAuthn error to report to web content, e.g. `NotAllowedError`.
+    pub fn webauthn_category(&self) -> WebAuthnErrorCategory {
+        self.into()
+    }
+
+    /// A stable identifier of this error, for reporting: the specification's name for CTAP
+    /// errors, e.g. `CTAP2_ERR_PIN_INVALID`, otherwise e.g. `TRANSPORT_TIMEOUT`.
+    pub fn code(&self) -> &'static str {
+        match self {
+            Error::Transport(error) => error.code(),
+            Error::Ctap(error) => error.code(),
+            Error::Platform(error) => error.code(),
+        }
+    }
+}
+
 impl From<CborError> for Error {
     fn from(error: CborError) -> Self {
         Error::Platform(PlatformError::CborError(error))
     }
 }
 
+/// Errors raised by the library itself, rather than by the device or the transport.
+///
+/// New variants are added as more failures get their own context, e.g. `InvalidResponse` and
+/// `MissingResponseField` replaced some uses of `InvalidDeviceResponse`. Match on
+/// [Error::code] or [Error::recommended_action] where a catch-all isn't enough.
 #[derive(thiserror::Error, Debug, PartialEq)]
+#[non_exhaustive]
 pub enum PlatformError {
+    /// The new PIN doesn't meet the device's PIN policy; `policy_url` describes it, if the
+    /// device has one.
+    #[error("{violation}")]
+    PinPolicyViolation {
+        violation: PinPolicyViolation,
+        policy_url: Option<String>,
+    },
+    /// No longer returned: PINs are checked against the device's policy, and too short PINs
+    /// fail with [PlatformError::PinPolicyViolation] and [PinPolicyViolation::TooShort].
+    #[deprecated(note = "match PinPolicyViolation { violation: TooShort { .. }, .. } instead")]
     #[error("pin too short")]
     PinTooShort,
+    /// No longer returned: too long PINs fail with [PlatformError::PinPolicyViolation] and
+    /// [PinPolicyViolation::TooLong].
+    #[deprecated(note = "match PinPolicyViolation { violation: TooLong { .. }, .. } instead")]
     #[error("pin too long")]
     PinTooLong,
     #[error("pin not supported")]
@@ -29,12 +90,358 @@ pub enum PlatformError {
     NoUvAvailable,
     #[error("invalid device response")]
     InvalidDeviceResponse,
+    /// The device's response to a request could not be parsed.
+    #[error("invalid {response} from device: {source}")]
+    InvalidResponse {
+        response: &'static str,
+        #[source]
+        source: CborError,
+    },
+    /// The device's response lacks a field required by the specification.
+    #[error("device response is missing {0}")]
+    MissingResponseField(&'static str),
     #[error("operation not supported")]
     NotSupported,
     #[error("syntax error")]
     SyntaxError,
+    /// The origin isn't a secure context, or may not use the relying party ID.
+    #[error("origin not allowed for the relying party")]
+    SecurityError,
     #[error("cbor serialization error: {0}")]
     CborError(#[from] CborError),
     #[error("cancelled by user")]
     Cancelled,
+    #[error("client data hash was already used")]
+    ReplayedRequest,
+    #[error("challenge is no longer fresh")]
+    StaleChallenge,
+    #[error("device enforces user verification, which the relying party discouraged")]
+    AlwaysUvEnforced,
+    #[error("friendly name too long, the device accepts at most {0} bytes")]
+    FriendlyNameTooLong(usize),
+    #[error("session is locked")]
+    SessionLocked,
+    #[error("no matching credential stored on the device")]
+    CredentialNotFound,
+    #[error("device's secret doesn't match the enrolled one")]
+    SecretMismatch,
+    /// The encoded request is larger than the device accepts.
+    #[error("request too large, {size} bytes while the device accepts at most {max_msg_size}")]
+    RequestTooLarge { size: usize, max_msg_size: u32 },
+    /// A large blob's origSize exceeds the limit it is read with, or the serialized large-blob
+    /// array exceeds the device's maxSerializedLargeBlobArray.
+    #[error("large blob of {0} bytes exceeds the size limit")]
+    LargeBlobTooLarge(u64),
+    /// More RP IDs to read the minimum PIN length than maxRPIDsForSetMinPINLength. Devices
+    /// which don't allow adding any after setup have a maximum of 0.
+    #[error("{count} RP IDs for setMinPINLength, while the device accepts at most {max}")]
+    TooManyMinPinLengthRpIds { count: usize, max: u32 },
+}
+
+#[allow(deprecated)]
+impl PlatformError {
+    pub fn recommended_action(&self) -> RecommendedAction {
+        match self {
+            Self::SessionLocked => RecommendedAction::Retry,
+            Self::PinPolicyViolation { .. } | Self::PinTooShort | Self::PinTooLong => {
+                RecommendedAction::RetryAfterUserAction
+            }
+            Self::PinNotSupported
+            | Self::NoUvAvailable
+            | Self::InvalidDeviceResponse
+            | Self::InvalidResponse { .. }
+            | Self::MissingResponseField(_)
+            | Self::NotSupported
+            | Self::SyntaxError
+            | Self::SecurityError
+            | Self::CborError(_)
+            | Self::Cancelled
+            | Self::ReplayedRequest
+            | Self::StaleChallenge
+            | Self::AlwaysUvEnforced
+            | Self::FriendlyNameTooLong(_)
+            | Self::CredentialNotFound
+            | Self::SecretMismatch
+            | Self::RequestTooLarge { .. }
+            | Self::LargeBlobTooLarge(_)
+            | Self::TooManyMinPinLengthRpIds { .. } => RecommendedAction::Fatal,
+        }
+    }
+
+    /// A stable identifier of this error, for reporting. Context, such as the missing field,
+    /// is not part of it.
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::PinPolicyViolation { .. } => "PLATFORM_PIN_POLICY_VIOLATION",
+            Self::PinTooShort => "PLATFORM_PIN_TOO_SHORT",
+            Self::PinTooLong => "PLATFORM_PIN_TOO_LONG",
+            Self::PinNotSupported => "PLATFORM_PIN_NOT_SUPPORTED",
+            Self::NoUvAvailable => "PLATFORM_NO_UV_AVAILABLE",
+            Self::InvalidDeviceResponse => "PLATFORM_INVALID_DEVICE_RESPONSE",
+            Self::InvalidResponse { .. } => "PLATFORM_INVALID_RESPONSE",
+            Self::MissingResponseField(_) => "PLATFORM_MISSING_RESPONSE_FIELD",
+            Self::NotSupported => "PLATFORM_NOT_SUPPORTED",
+            Self::SyntaxError => "PLATFORM_SYNTAX_ERROR",
+            Self::SecurityError => "PLATFORM_SECURITY_ERROR",
+            Self::CborError(_) => "PLATFORM_CBOR_ERROR",
+            Self::Cancelled => "PLATFORM_CANCELLED",
+            Self::ReplayedRequest => "PLATFORM_REPLAYED_REQUEST",
+            Self::StaleChallenge => "PLATFORM_STALE_CHALLENGE",
+            Self::AlwaysUvEnforced => "PLATFORM_ALWAYS_UV_ENFORCED",
+            Self::FriendlyNameTooLong(_) => "PLATFORM_FRIENDLY_NAME_TOO_LONG",
+            Self::SessionLocked => "PLATFORM_SESSION_LOCKED",
+            Self::CredentialNotFound => "PLATFORM_CREDENTIAL_NOT_FOUND",
+            Self::SecretMismatch => "PLATFORM_SECRET_MISMATCH",
+            Self::RequestTooLarge { .. } => "PLATFORM_REQUEST_TOO_LARGE",
+            Self::LargeBlobTooLarge(_) => "PLATFORM_LARGE_BLOB_TOO_LARGE",
+            Self::TooManyMinPinLengthRpIds { .. } => "PLATFORM_TOO_MANY_MIN_PIN_LENGTH_RPIDS",
+        }
+    }
+}
+
+/// The errors of the WebAuthn API, named after the DOMException they are reported as to
+/// web content, see [WebAuthnErrorCategory::name].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WebAuthnErrorCategory {
+    /// The ceremony failed, timed out, or the user declined, without telling why, as
+    /// WebAuthn avoids leaking information to relying parties.
+    NotAllowedError,
+    /// An excluded credential is already stored on the authenticator.
+    InvalidStateError,
+    /// The authenticator can't satisfy a requirement of the request, e.g. resident keys
+    /// or user verification.
+    ConstraintError,
+    /// None of the requested algorithms or options is supported.
+    NotSupportedError,
+    /// The ceremony was aborted by the caller.
+    AbortError,
+    /// The relying party ID or the origin was rejected.
+    SecurityError,
+    /// The request's options are malformed.
+    TypeError,
+    /// The authenticator's response was malformed or incomplete. Reported to web content as
+    /// `UnknownError`, but kept apart so that embedders can tell faulty devices from other
+    /// failures.
+    InvalidResponse,
+    /// Any other failure.
+    UnknownError,
+}
+
+impl WebAuthnErrorCategory {
+    /// The name of the DOMException, e.g. `NotAllowedError`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::NotAllowedError => "NotAllowedError",
+            Self::InvalidStateError => "InvalidStateError",
+            Self::ConstraintError => "ConstraintError",
+            Self::NotSupportedError => "NotSupportedError",
+            Self::AbortError => "AbortError",
+            Self::SecurityError => "SecurityError",
+            Self::TypeError => "TypeError",
+            Self::InvalidResponse | Self::UnknownError => "UnknownError",
+        }
+    }
+}
+
+impl std::fmt::Display for WebAuthnErrorCategory {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+impl From<CtapError> for WebAuthnErrorCategory {
+    fn from(error: CtapError) -> Self {
+        match error {
+            CtapError::CredentialExcluded => Self::InvalidStateError,
+            CtapError::UnsupportedAlgorithm => Self::NotSupportedError,
+            CtapError::KeyStoreFull | CtapError::UnsupportedOption => Self::ConstraintError,
+            CtapError::InvalidCommand
+            | CtapError::InvalidParameter
+            | CtapError::InvalidLength
+            | CtapError::InvalidSeq
+            | CtapError::InvalidChannel
+            | CtapError::InvalidCborType
+            | CtapError::InvalidCbor
+            | CtapError::MissingParameter
+            | CtapError::LimitExceeded
+            | CtapError::UnsupportedExtension
+            | CtapError::InvalidOption
+            | CtapError::RequestTooLarge
+            | CtapError::IntegrityFailure
+            | CtapError::InvalidSubcommand
+            | CtapError::Other => Self::UnknownError,
+            // Timeouts, refusals and failed user verification.
+            _ => Self::NotAllowedError,
+        }
+    }
+}
+
+impl From<&TransportError> for WebAuthnErrorCategory {
+    fn from(error: &TransportError) -> Self {
+        match error.root_cause() {
+            TransportError::Timeout
+            | TransportError::ConnectionLost
+            | TransportError::DeviceRemoved
+            | TransportError::DeviceReconnected => Self::NotAllowedError,
+            TransportError::InvalidFraming | TransportError::UnexpectedResponse { .. } => {
+                Self::InvalidResponse
+            }
+            _ => Self::UnknownError,
+        }
+    }
+}
+
+impl From<&PlatformError> for WebAuthnErrorCategory {
+    #[allow(deprecated)]
+    fn from(error: &PlatformError) -> Self {
+        match error {
+            PlatformError::Cancelled => Self::AbortError,
+            PlatformError::SyntaxError | PlatformError::FriendlyNameTooLong(_) => Self::TypeError,
+            PlatformError::SecurityError => Self::SecurityError,
+            PlatformError::NotSupported => Self::NotSupportedError,
+            PlatformError::PinNotSupported
+            | PlatformError::NoUvAvailable
+            | PlatformError::TooManyMinPinLengthRpIds { .. } => Self::ConstraintError,
+            PlatformError::InvalidDeviceResponse
+            | PlatformError::InvalidResponse { .. }
+            | PlatformError::MissingResponseField(_) => Self::InvalidResponse,
+            PlatformError::CborError(_)
+            | PlatformError::RequestTooLarge { .. }
+            | PlatformError::LargeBlobTooLarge(_) => Self::UnknownError,
+            PlatformError::PinPolicyViolation { .. }
+            | PlatformError::PinTooShort
+            | PlatformError::PinTooLong
+            | PlatformError::ReplayedRequest
+            | PlatformError::StaleChallenge
+            | PlatformError::AlwaysUvEnforced
+            | PlatformError::SessionLocked
+            | PlatformError::CredentialNotFound
+            | PlatformError::SecretMismatch => Self::NotAllowedError,
+        }
+    }
+}
+
+impl From<&Error> for WebAuthnErrorCategory {
+    fn from(error: &Error) -> Self {
+        match error {
+            Error::Transport(error) => error.into(),
+            Error::Ctap(error) => (*error).into(),
+            Error::Platform(error) => error.into(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::error::Error as _;
+
+    use super::{CtapError, Error, PlatformError, RecommendedAction, WebAuthnErrorCategory};
+    use crate::transport::error::{CableStage, TransportError};
+
+    #[test]
+    fn errors_have_stable_codes() {
+        assert_eq!(
+            Error::Ctap(CtapError::PINInvalid).code(),
+            "CTAP2_ERR_PIN_INVALID"
+        );
+        assert_eq!(
+            Error::Transport(TransportError::Timeout).code(),
+            "TRANSPORT_TIMEOUT"
+        );
+        assert_eq!(
+            Error::Platform(PlatformError::MissingResponseField("data")).code(),
+            "PLATFORM_MISSING_RESPONSE_FIELD"
+        );
+    }
+
+    #[test]
+    fn context_is_kept_in_source_chain() {
+        let error = Error::Transport(TransportError::Timeout.at_cable_stage(CableStage::Handshake));
+        assert_eq!(
+            error.to_string(),
+            "Transport error: caBLE handshake failed: timeout"
+        );
+        let stage_error = error.source().unwrap();
+        assert_eq!(stage_error.source().unwrap().to_string(), "timeout");
+
+        let Error::Transport(transport_error) = &error else {
+            panic!("not a transport error");
+        };
+        assert_eq!(transport_error.root_cause(), &TransportError::Timeout);
+    }
+
+    #[test]
+    fn errors_recommend_an_action() {
+        let action = |error: Error| error.recommended_action();
+        assert_eq!(
+            action(Error::Ctap(CtapError::PINInvalid)),
+            RecommendedAction::RetryAfterUserAction
+        );
+        assert_eq!(
+            action(Error::Ctap(CtapError::ChannelBusy)),
+            RecommendedAction::Retry
+        );
+        assert_eq!(
+            action(Error::Ctap(CtapError::PINAuthBlocked)),
+            RecommendedAction::ReplugDevice
+        );
+        assert_eq!(
+            action(Error::Ctap(CtapError::PINBlocked)),
+            RecommendedAction::Fatal
+        );
+        assert_eq!(
+            action(Error::Transport(TransportError::DeviceRemoved)),
+            RecommendedAction::ReplugDevice
+        );
+        assert_eq!(
+            action(Error::Transport(
+                TransportError::ConnectionFailed.at_cable_stage(CableStage::Connection)
+            )),
+            RecommendedAction::Retry
+        );
+        assert_eq!(
+            action(Error::Platform(PlatformError::Cancelled)),
+            RecommendedAction::Fatal
+        );
+    }
+
+    #[test]
+    fn errors_map_to_webauthn_categories() {
+        let category = |error: Error| error.webauthn_category().name();
+        assert_eq!(
+            category(Error::Ctap(CtapError::CredentialExcluded)),
+            "InvalidStateError"
+        );
+        assert_eq!(
+            category(Error::Ctap(CtapError::PINInvalid)),
+            "NotAllowedError"
+        );
+        assert_eq!(
+            category(Error::Ctap(CtapError::KeyStoreFull)),
+            "ConstraintError"
+        );
+        assert_eq!(
+            category(Error::Ctap(CtapError::InvalidCbor)),
+            "UnknownError"
+        );
+        assert_eq!(
+            category(Error::Transport(TransportError::Timeout)),
+            "NotAllowedError"
+        );
+        assert_eq!(
+            category(Error::Platform(PlatformError::Cancelled)),
+            "AbortError"
+        );
+        assert_eq!(
+            category(Error::Platform(PlatformError::SyntaxError)),
+            "TypeError"
+        );
+
+        let error = Error::Platform(PlatformError::MissingResponseField("authData"));
+        assert_eq!(
+            error.webauthn_category(),
+            WebAuthnErrorCategory::InvalidResponse
+        );
+        assert_eq!(category(error), "UnknownError");
+    }
 }
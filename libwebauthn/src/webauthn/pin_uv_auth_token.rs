@@ -1,20 +1,30 @@
 use std::sync::Arc;
-use std::time::Duration;
 
 use tracing::{debug, error, info, instrument, warn};
 
 use cosey::PublicKey;
-
-use crate::ops::webauthn::UserVerificationRequirement;
+use num_traits::FromPrimitive;
+use zeroize::Zeroizing;
+
+use crate::correlation::CorrelationId;
+use crate::metrics::UvAttemptMethod;
+use crate::ops::webauthn::{
+    AlwaysUvPolicy, GetAssertionRequest, MakeCredentialRequest, UserVerificationRequirement,
+    UvMethodPreference,
+};
 use crate::pin::{
-    pin_hash, PinRequestReason, PinUvAuthProtocol, PinUvAuthProtocolOne, PinUvAuthProtocolTwo,
+    pin_hash, PinRequestContext, PinRequestReason, PinUvAuthProtocol, PinUvAuthProtocols,
 };
 use crate::proto::ctap2::{
-    Ctap2, Ctap2ClientPinRequest, Ctap2GetInfoResponse, Ctap2PinUvAuthProtocol,
-    Ctap2UserVerifiableRequest, Ctap2UserVerificationOperation,
+    Ctap2, Ctap2AuthTokenPermissionRole, Ctap2ClientPinRequest, Ctap2GetInfoResponse,
+    Ctap2PinUvAuthProtocol, Ctap2UserVerifiableRequest, Ctap2UserVerificationOperation,
 };
+use crate::session_gate;
+use crate::timeout::OperationDeadline;
 pub use crate::transport::error::TransportError;
-use crate::transport::{AuthTokenData, Channel, Ctap2AuthTokenPermission};
+use crate::transport::{
+    until_cancelled, AuthTokenData, AuthTokenMismatch, Channel, Ctap2AuthTokenPermission,
+};
 pub use crate::webauthn::error::{CtapError, Error, PlatformError};
 use crate::{PinRequiredUpdate, UvUpdate};
 
@@ -29,35 +39,119 @@ pub(crate) enum UsedPinUvAuthToken {
 
 pub(crate) async fn select_uv_proto(
     get_info_response: &Ctap2GetInfoResponse,
+    protocols: &PinUvAuthProtocols,
 ) -> Option<Box<dyn PinUvAuthProtocol>> {
     for &protocol in get_info_response.pin_auth_protos.iter().flatten() {
-        match protocol {
-            1 => return Some(Box::new(PinUvAuthProtocolOne::new())),
-            2 => return Some(Box::new(PinUvAuthProtocolTwo::new())),
-            _ => (),
-        };
+        if let Some(protocol) = Ctap2PinUvAuthProtocol::from_u32(protocol) {
+            return Some(protocols.create(protocol));
+        }
     }
 
     warn!(?get_info_response.pin_auth_protos, "No supported PIN/UV auth protocols found");
     None
 }
 
+/// Applies `policy` if the RP discouraged UV, but the device has alwaysUv enabled.
+/// Returns whether UV is going to be enforced against the RP's preference.
 #[instrument(skip_all)]
-pub(crate) async fn user_verification<R, C>(
+pub(crate) async fn check_always_uv<C>(
     channel: &mut C,
     user_verification: UserVerificationRequirement,
+    policy: AlwaysUvPolicy,
+    get_info_response: &Ctap2GetInfoResponse,
+) -> Result<bool, Error>
+where
+    C: Channel,
+{
+    if user_verification.is_preferred() || !get_info_response.option_enabled("alwaysUv") {
+        return Ok(false);
+    }
+    match policy {
+        AlwaysUvPolicy::Proceed => {
+            debug!("UV discouraged by RP, but enforced by device alwaysUv. Proceeding.");
+        }
+        AlwaysUvPolicy::Warn => {
+            warn!("UV discouraged by RP, but enforced by device alwaysUv.");
+            channel
+                .send_ux_update(UvUpdate::AlwaysUvEnforced.into())
+                .await;
+        }
+        AlwaysUvPolicy::Abort => {
+            warn!("UV discouraged by RP, but enforced by device alwaysUv. Aborting.");
+            return Err(Error::Platform(PlatformError::AlwaysUvEnforced));
+        }
+    }
+    Ok(true)
+}
+
+/// How to verify the user for a request, and which pinUvAuthToken to obtain for it.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct UvOptions {
+    pub user_verification: UserVerificationRequirement,
+    /// Which user verification method to attempt first
+    pub uv_preference: UvMethodPreference,
+    /// Built-in UV failures to allow before falling back to PIN, overriding the
+    /// authenticator's `preferredPlatformUvAttempts`
+    pub platform_uv_attempts: Option<u32>,
+    /// Permissions to request for a new pinUvAuthToken besides the request's own, as far as
+    /// the device supports them, so that it can be reused for other operations
+    pub additional_permissions: Ctap2AuthTokenPermissionRole,
+}
+
+impl UvOptions {
+    /// With the default method preference, and without additional permissions.
+    pub(crate) fn new(user_verification: UserVerificationRequirement) -> Self {
+        Self {
+            user_verification,
+            uv_preference: UvMethodPreference::default(),
+            platform_uv_attempts: None,
+            additional_permissions: Ctap2AuthTokenPermissionRole::empty(),
+        }
+    }
+}
+
+impl From<&MakeCredentialRequest> for UvOptions {
+    fn from(request: &MakeCredentialRequest) -> Self {
+        Self {
+            user_verification: request.user_verification,
+            uv_preference: request.uv_method_preference,
+            platform_uv_attempts: request.platform_uv_attempts,
+            additional_permissions: request.additional_permissions,
+        }
+    }
+}
+
+impl From<&GetAssertionRequest> for UvOptions {
+    fn from(request: &GetAssertionRequest) -> Self {
+        Self {
+            user_verification: request.user_verification,
+            uv_preference: request.uv_method_preference,
+            platform_uv_attempts: request.platform_uv_attempts,
+            additional_permissions: request.additional_permissions,
+        }
+    }
+}
+
+#[instrument(skip_all)]
+pub(crate) async fn user_verification<R, C>(
+    channel: &mut C,
+    options: UvOptions,
     ctap2_request: &mut R,
-    timeout: Duration,
+    timeout: impl Into<OperationDeadline>,
 ) -> Result<UsedPinUvAuthToken, Error>
 where
     C: Channel,
     R: Ctap2UserVerifiableRequest,
 {
+    let timeout = timeout.into();
     let get_info_response = channel.ctap2_get_info().await?;
     ctap2_request.handle_legacy_preview(&get_info_response);
-    let maybe_uv_proto = select_uv_proto(&get_info_response).await;
+    let maybe_uv_proto =
+        select_uv_proto(&get_info_response, &channel.get_pin_uv_auth_protocols()).await;
 
+    let mut mismatch = None;
     if let Some(uv_proto) = maybe_uv_proto {
+        // The cached token only needs the permissions of this request.
         let token_identifier = Ctap2AuthTokenPermission::new(
             uv_proto.version(),
             ctap2_request.permissions(),
@@ -67,23 +161,76 @@ where
             ctap2_request.calculate_and_set_uv_auth(&uv_proto, uv_auth_token);
             return Ok(UsedPinUvAuthToken::FromStorage);
         }
+        mismatch = channel
+            .get_auth_data()
+            .and_then(|stored| stored.mismatch(&token_identifier));
     }
 
-    user_verification_helper(channel, user_verification, ctap2_request, timeout).await
+    user_verification_helper(
+        channel,
+        &options,
+        mismatch,
+        &get_info_response,
+        ctap2_request,
+        timeout,
+    )
+    .await
+}
+
+/// The subset of `requested` the device can grant, according to its options. Requesting
+/// any other permission would fail to obtain a token at all.
+fn supported_permissions(
+    info: &Ctap2GetInfoResponse,
+    requested: Ctap2AuthTokenPermissionRole,
+) -> Ctap2AuthTokenPermissionRole {
+    let mut supported =
+        Ctap2AuthTokenPermissionRole::MAKE_CREDENTIAL | Ctap2AuthTokenPermissionRole::GET_ASSERTION;
+    for (permission, option) in [
+        (
+            Ctap2AuthTokenPermissionRole::CREDENTIAL_MANAGEMENT,
+            "credMgmt",
+        ),
+        (Ctap2AuthTokenPermissionRole::LARGE_BLOB_WRITE, "largeBlobs"),
+        (
+            Ctap2AuthTokenPermissionRole::AUTHENTICATOR_CONFIGURATION,
+            "authnrCfg",
+        ),
+    ] {
+        if info.option_enabled(option) {
+            supported |= permission;
+        }
+    }
+    // bioEnroll is false, rather than absent, if no fingerprint is enrolled yet.
+    if info
+        .options
+        .as_ref()
+        .is_some_and(|options| options.contains_key("bioEnroll"))
+    {
+        supported |= Ctap2AuthTokenPermissionRole::BIO_ENROLLMENT;
+    }
+    let unsupported = requested.difference(supported);
+    if !unsupported.is_empty() {
+        warn!(?unsupported, "Device lacks requested token permissions");
+    }
+    requested & supported
 }
 
 #[instrument(skip_all)]
 async fn user_verification_helper<R, C>(
     channel: &mut C,
-    user_verification: UserVerificationRequirement,
+    options: &UvOptions,
+    mut mismatch: Option<AuthTokenMismatch>,
+    get_info_response: &Ctap2GetInfoResponse,
     ctap2_request: &mut R,
-    timeout: Duration,
+    timeout: OperationDeadline,
 ) -> Result<UsedPinUvAuthToken, Error>
 where
     C: Channel,
     R: Ctap2UserVerifiableRequest,
 {
-    let get_info_response = channel.ctap2_get_info().await?;
+    let user_verification = options.user_verification;
+    let permissions = ctap2_request.permissions()
+        | supported_permissions(get_info_response, options.additional_permissions);
 
     let rp_uv_preferred = user_verification.is_preferred();
     let dev_uv_protected = get_info_response.is_uv_protected();
@@ -107,12 +254,18 @@ where
         return Ok(UsedPinUvAuthToken::None);
     }
 
-    let skip_uv = !ctap2_request.can_use_uv(&get_info_response);
+    let skip_uv = !ctap2_request.can_use_uv(get_info_response);
+
+    let max_uv_attempts = options
+        .platform_uv_attempts
+        .unwrap_or_else(|| get_info_response.platform_uv_attempts());
+    debug!(%max_uv_attempts, "Built-in UV attempts before falling back to PIN");
 
     let mut uv_blocked = false;
+    let mut uv_failures = 0;
     let (uv_proto, token_response, shared_secret, public_key, uv_operation) = loop {
         let uv_operation = get_info_response
-            .uv_operation(uv_blocked || skip_uv)
+            .uv_operation_with_preference(uv_blocked || skip_uv, options.uv_preference)
             .ok_or({
                 if uv_blocked {
                     Error::Ctap(CtapError::UvBlocked)
@@ -126,11 +279,21 @@ where
             return Ok(UsedPinUvAuthToken::LegacyUV);
         }
 
-        let Some(uv_proto) = select_uv_proto(&get_info_response).await else {
+        let Some(uv_proto) =
+            select_uv_proto(get_info_response, &channel.get_pin_uv_auth_protocols()).await
+        else {
             error!("No supported PIN/UV auth protocols found");
             return Err(Error::Ctap(CtapError::Other));
         };
 
+        // Explains why the user is prompted again, despite a stored token.
+        if let Some(mismatch) = mismatch.take() {
+            info!(%mismatch, "Stored pinUvAuthToken can't be reused, obtaining a new one");
+            channel
+                .send_ux_update(UvUpdate::AuthTokenMismatch(mismatch).into())
+                .await;
+        }
+
         // For operations that include a PIN, we want to fetch one before obtaining a shared secret.
         // This prevents the shared secret from expiring whilst we wait for the user to enter a PIN.
         let pin = match uv_operation {
@@ -148,7 +311,7 @@ where
                 Some(
                     obtain_pin(
                         channel,
-                        &get_info_response,
+                        get_info_response,
                         uv_proto.version(),
                         reason,
                         timeout,
@@ -161,6 +324,12 @@ where
             }
         };
 
+        let method = if pin.is_some() {
+            UvAttemptMethod::Pin
+        } else {
+            UvAttemptMethod::BuiltInUv
+        };
+
         // In preparation for obtaining pinUvAuthToken, the platform:
         // * Obtains a shared secret.
         let (public_key, shared_secret) = obtain_shared_secret(channel, &uv_proto, timeout).await?;
@@ -181,7 +350,7 @@ where
                     uv_proto.version(),
                     public_key.clone(),
                     &uv_proto.encrypt(&shared_secret, &pin_hash(&pin.unwrap()))?,
-                    ctap2_request.permissions(),
+                    permissions,
                     ctap2_request.permissions_rpid(),
                 )
             }
@@ -192,13 +361,27 @@ where
                 Ctap2ClientPinRequest::new_get_uv_token_with_perm(
                     uv_proto.version(),
                     public_key.clone(),
-                    ctap2_request.permissions(),
+                    permissions,
                     ctap2_request.permissions_rpid(),
                 )
             }
         };
 
-        match channel.ctap2_client_pin(&token_request, timeout).await {
+        let token_result = channel.ctap2_client_pin(&token_request, timeout).await;
+        if let Some(recorder) = channel.get_metrics_recorder() {
+            match &token_result {
+                Ok(_) => recorder.record_uv_attempt(method, true),
+                Err(Error::Ctap(
+                    CtapError::PINInvalid
+                    | CtapError::PINBlocked
+                    | CtapError::PINAuthBlocked
+                    | CtapError::UVInvalid
+                    | CtapError::UvBlocked,
+                )) => recorder.record_uv_attempt(method, false),
+                Err(_) => (),
+            }
+        }
+        match token_result {
             Ok(t) => {
                 break (uv_proto, t, shared_secret, public_key, uv_operation);
             }
@@ -228,6 +411,18 @@ where
                         continue;
                     }
                 }
+                uv_failures += 1;
+                if uv_failures < max_uv_attempts {
+                    debug!(%uv_failures, "UV failed, retrying built-in UV");
+                    continue;
+                }
+                if options.uv_preference != UvMethodPreference::BioOnly
+                    && get_info_response.option_enabled("clientPin")
+                {
+                    warn!(%uv_failures, "UV failed too many times. Falling back to PIN.");
+                    uv_blocked = true;
+                    continue;
+                }
                 return Err(Error::Ctap(CtapError::UVInvalid));
             }
             Err(x) => {
@@ -236,16 +431,16 @@ where
         }
     };
 
-    let Some(encrypted_pin_uv_auth_token) = token_response.pin_uv_auth_token else {
+    let Some(encrypted_pin_uv_auth_token) = &token_response.pin_uv_auth_token else {
         error!("Client PIN response did not include a PIN UV auth token");
         return Err(Error::Ctap(CtapError::Other));
     };
 
-    let uv_auth_token = uv_proto.decrypt(&shared_secret, &encrypted_pin_uv_auth_token)?;
+    let uv_auth_token = uv_proto.decrypt(&shared_secret, encrypted_pin_uv_auth_token)?;
 
     let token_identifier = Ctap2AuthTokenPermission::new(
         uv_proto.version(),
-        ctap2_request.permissions(),
+        permissions,
         ctap2_request.permissions_rpid(),
     );
 
@@ -253,7 +448,7 @@ where
     let auth_token_data = AuthTokenData {
         shared_secret: shared_secret.to_vec(),
         permission: token_identifier,
-        pin_uv_auth_token: uv_auth_token.clone(),
+        pin_uv_auth_token: uv_auth_token.to_vec(),
         protocol_version: uv_proto.version(),
         key_agreement: public_key,
         uv_operation,
@@ -271,8 +466,8 @@ where
 pub(crate) async fn obtain_shared_secret<C>(
     channel: &mut C,
     pin_proto: &Box<dyn PinUvAuthProtocol>,
-    timeout: Duration,
-) -> Result<(PublicKey, Vec<u8>), Error>
+    timeout: impl Into<OperationDeadline> + Send,
+) -> Result<(PublicKey, Zeroizing<Vec<u8>>), Error>
 where
     C: Channel,
 {
@@ -280,11 +475,11 @@ where
     let client_pin_response = channel
         .ctap2_client_pin(&client_pin_request, timeout)
         .await?;
-    let Some(public_key) = client_pin_response.key_agreement else {
+    let Some(public_key) = &client_pin_response.key_agreement else {
         error!("Missing public key from Client PIN response");
         return Err(Error::Ctap(CtapError::Other));
     };
-    pin_proto.encapsulate(&public_key)
+    pin_proto.encapsulate(public_key)
 }
 
 pub(crate) async fn obtain_pin<C>(
@@ -292,11 +487,12 @@ pub(crate) async fn obtain_pin<C>(
     info: &Ctap2GetInfoResponse,
     pin_proto: Ctap2PinUvAuthProtocol,
     reason: PinRequestReason,
-    timeout: Duration,
-) -> Result<Vec<u8>, Error>
+    timeout: impl Into<OperationDeadline>,
+) -> Result<Zeroizing<Vec<u8>>, Error>
 where
     C: Channel,
 {
+    let timeout = timeout.into();
     // FIDO 2.0 requires PIN protocol, 2.1 does not anymore
     let pin_protocol = if info.supports_fido_2_1() {
         None
@@ -304,6 +500,8 @@ where
         Some(pin_proto)
     };
 
+    session_gate::wait_for_open(channel, timeout.remaining()).await?;
+
     let attempts_left = channel
         .ctap2_client_pin(
             &Ctap2ClientPinRequest::new_get_pin_retries(pin_protocol),
@@ -314,23 +512,68 @@ where
         .ok() // It's optional, so soft-error here
         .flatten();
 
-    let (tx, rx) = tokio::sync::oneshot::channel();
-    channel
-        .send_ux_update(
-            UvUpdate::PinRequired(PinRequiredUpdate {
-                reply_to: Arc::new(tx),
+    // The prompt is abandoned if the operation is cancelled meanwhile.
+    let token = channel.get_cancellation_token();
+    let pin = match channel.get_pin_provider() {
+        Some(provider) => {
+            let context = PinRequestContext {
                 reason,
                 attempts_left,
-            })
-            .into(),
-        )
-        .await;
-    let pin = match rx.await {
-        Ok(pin) => pin,
-        Err(_) => {
-            info!("User cancelled operation: no PIN provided");
-            return Err(Error::Ctap(CtapError::PINRequired));
+                correlation_id: CorrelationId::current(),
+            };
+            let provided = async { Ok(provider.provide_pin(&context).await) };
+            until_cancelled(token, channel.get_ux_update_sender(), provided).await?
+        }
+        None => {
+            let (tx, rx) = tokio::sync::oneshot::channel();
+            channel
+                .send_ux_update(
+                    UvUpdate::PinRequired(PinRequiredUpdate {
+                        reply_to: Arc::new(tx),
+                        reason,
+                        attempts_left,
+                        correlation_id: CorrelationId::current(),
+                    })
+                    .into(),
+                )
+                .await;
+            let replied = async { Ok(rx.await.ok()) };
+            until_cancelled(token, channel.get_ux_update_sender(), replied).await?
         }
     };
-    Ok(pin.as_bytes().to_owned())
+    let Some(pin) = pin else {
+        info!("User cancelled operation: no PIN provided");
+        return Err(Error::Ctap(CtapError::PINRequired));
+    };
+    Ok(Zeroizing::new(pin.as_bytes().to_owned()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::supported_permissions;
+    use crate::proto::ctap2::{Ctap2AuthTokenPermissionRole, Ctap2GetInfoResponse};
+
+    #[test]
+    fn permissions_follow_device_options() {
+        let options = [
+            ("largeBlobs", false),
+            ("credMgmt", true),
+            ("bioEnroll", false),
+        ];
+        let info = Ctap2GetInfoResponse {
+            options: Some(HashMap::from(options.map(|(k, v)| (k.to_owned(), v)))),
+            ..Default::default()
+        };
+        let requested = Ctap2AuthTokenPermissionRole::LARGE_BLOB_WRITE
+            | Ctap2AuthTokenPermissionRole::CREDENTIAL_MANAGEMENT
+            | Ctap2AuthTokenPermissionRole::BIO_ENROLLMENT;
+        // Bio enrollment is supported before any fingerprint is enrolled, large blobs are not.
+        assert_eq!(
+            supported_permissions(&info, requested),
+            Ctap2AuthTokenPermissionRole::CREDENTIAL_MANAGEMENT
+                | Ctap2AuthTokenPermissionRole::BIO_ENROLLMENT
+        );
+    }
 }
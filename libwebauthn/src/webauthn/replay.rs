@@ -0,0 +1,165 @@
+//! Guardrails against building replayable flows on top of the low-level API.
+//!
+//! The platform never generates challenges itself: the `hash` of a request is
+//! whatever the embedder passes in. [`Challenge`] helps issuing fresh, random
+//! challenges, and [`ReplayGuard`] refuses to send the same client data hash to an
+//! authenticator twice within its retention window.
+
+use std::collections::HashMap;
+use std::sync::{Mutex, PoisonError};
+use std::time::Duration;
+
+use tokio::time::Instant;
+use tracing::{debug, warn};
+
+use crate::ops::webauthn::{GetAssertionRequest, MakeCredentialRequest};
+use crate::sources;
+use crate::webauthn::error::{Error, PlatformError};
+
+const CHALLENGE_LEN: usize = 32;
+
+/// A random per-operation nonce, remembering when it was issued.
+#[derive(Debug, Clone)]
+pub struct Challenge {
+    bytes: [u8; CHALLENGE_LEN],
+    issued_at: Instant,
+}
+
+impl Challenge {
+    pub fn new() -> Self {
+        Self {
+            bytes: sources::random(),
+            issued_at: Instant::now(),
+        }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    pub fn is_fresh(&self, max_age: Duration) -> bool {
+        self.issued_at.elapsed() <= max_age
+    }
+
+    /// Fails with `StaleChallenge` if the challenge was issued longer than `max_age` ago.
+    pub fn check_fresh(&self, max_age: Duration) -> Result<(), Error> {
+        if !self.is_fresh(max_age) {
+            warn!("Refusing to use a stale challenge");
+            return Err(Error::Platform(PlatformError::StaleChallenge));
+        }
+        Ok(())
+    }
+}
+
+impl Default for Challenge {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Remembers the client data hashes of past operations for `retention`,
+/// and rejects any operation reusing one of them. Expired hashes are evicted on every check,
+/// so the guard only holds the hashes used within the last `retention`.
+#[derive(Debug)]
+pub struct ReplayGuard {
+    retention: Duration,
+    seen: Mutex<HashMap<Vec<u8>, Instant>>,
+}
+
+impl ReplayGuard {
+    pub fn new(retention: Duration) -> Self {
+        Self {
+            retention,
+            seen: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Records `client_data_hash` as used, failing with `ReplayedRequest` if it already was.
+    pub fn check_client_data_hash(&self, client_data_hash: &[u8]) -> Result<(), Error> {
+        let now = Instant::now();
+        let mut seen = self.seen.lock().unwrap_or_else(PoisonError::into_inner);
+        seen.retain(|_, used_at| now.duration_since(*used_at) < self.retention);
+        if seen.contains_key(client_data_hash) {
+            warn!("Refusing to reuse client data hash of a previous operation");
+            return Err(Error::Platform(PlatformError::ReplayedRequest));
+        }
+        seen.insert(client_data_hash.to_vec(), now);
+        debug!({ tracked = seen.len() }, "Recorded client data hash");
+        Ok(())
+    }
+
+    pub fn check_make_credential(&self, op: &MakeCredentialRequest) -> Result<(), Error> {
+        self.check_client_data_hash(&op.hash)
+    }
+
+    pub fn check_get_assertion(&self, op: &GetAssertionRequest) -> Result<(), Error> {
+        self.check_client_data_hash(&op.hash)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn challenges_are_unique() {
+        assert_ne!(Challenge::new().as_bytes(), Challenge::new().as_bytes());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn challenge_freshness() {
+        let challenge = Challenge::new();
+        tokio::time::advance(Duration::from_secs(60)).await;
+        assert!(challenge.check_fresh(Duration::from_secs(60)).is_ok());
+        tokio::time::advance(Duration::from_secs(1)).await;
+        assert_eq!(
+            challenge.check_fresh(Duration::from_secs(60)),
+            Err(Error::Platform(PlatformError::StaleChallenge))
+        );
+    }
+
+    #[test]
+    fn reused_hash_is_rejected() {
+        let guard = ReplayGuard::new(Duration::from_secs(60));
+        let op = MakeCredentialRequest::dummy();
+        assert!(guard.check_make_credential(&op).is_ok());
+        assert_eq!(
+            guard.check_make_credential(&op),
+            Err(Error::Platform(PlatformError::ReplayedRequest))
+        );
+        assert!(guard.check_client_data_hash(&[1; 32]).is_ok());
+    }
+
+    #[test]
+    fn poisoned_guard_keeps_rejecting_reused_hashes() {
+        let guard = ReplayGuard::new(Duration::from_secs(60));
+        assert!(guard.check_client_data_hash(&[1; 32]).is_ok());
+        let _ = std::panic::catch_unwind(|| {
+            let _seen = guard.seen.lock().unwrap();
+            panic!("poisoning the guard");
+        });
+        assert!(guard.seen.is_poisoned());
+        assert_eq!(
+            guard.check_client_data_hash(&[1; 32]),
+            Err(Error::Platform(PlatformError::ReplayedRequest))
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn expired_hashes_are_evicted() {
+        let guard = ReplayGuard::new(Duration::from_secs(60));
+        assert!(guard.check_client_data_hash(&[1; 32]).is_ok());
+        tokio::time::advance(Duration::from_secs(30)).await;
+        assert!(guard.check_client_data_hash(&[2; 32]).is_ok());
+        assert_eq!(
+            guard.check_client_data_hash(&[1; 32]),
+            Err(Error::Platform(PlatformError::ReplayedRequest))
+        );
+
+        tokio::time::advance(Duration::from_secs(30)).await;
+        assert!(guard.check_client_data_hash(&[3; 32]).is_ok());
+        let seen = guard.seen.lock().unwrap();
+        assert!(!seen.contains_key([1; 32].as_slice()));
+        assert_eq!(seen.len(), 2);
+    }
+}